@@ -304,6 +304,14 @@ mod tests {
             if f == "index.json" {
                 cmp.insert("timestamp".to_string(), actual["timestamp"].clone());
             }
+            if f == "about.json" {
+                // the tools version changes with every release of rattler-build
+                assert!(actual["tools_version"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("rattler-build "));
+                cmp.insert("tools_version".to_string(), actual["tools_version"].clone());
+            }
             if f == "paths.json" {
                 let act_arr = actual["paths"].as_array().unwrap();
                 let cmp_arr = cmp["paths"].as_array().unwrap();
@@ -447,4 +455,59 @@ mod tests {
         assert!(rattler_build.is_ok());
         assert!(rattler_build.unwrap().status.success());
     }
+
+    #[test]
+    fn test_continue_on_failure() {
+        let tmp = tmp("test_continue_on_failure");
+        let rs = recipes().join("continue-on-failure").display().to_string();
+        let od = tmp.as_dir().display().to_string();
+        let rattler_build = rattler().with_args([
+            "build",
+            "--recipe",
+            rs.as_str(),
+            "--output-dir",
+            od.as_str(),
+            "--continue-on-failure",
+        ]);
+        assert!(rattler_build.is_ok());
+        // the failure of `cof-b` is reported at the end
+        assert!(rattler_build.unwrap().status.code().unwrap() == 1);
+
+        // `cof-a` is still built, `cof-c` depends on `cof-b` and is never attempted
+        get_package(tmp.as_dir(), "cof-a".to_string());
+        let path = std::env::current_dir().unwrap();
+        _ = std::env::set_current_dir(tmp.as_dir());
+        let cof_c = glob::glob("**/cof-c*.tar.bz2").unwrap().count();
+        _ = std::env::set_current_dir(path);
+        assert_eq!(cof_c, 0);
+    }
+
+    #[cfg(feature = "container-tests")]
+    #[test]
+    fn test_container_build() {
+        let image = std::env::var("RATTLER_BUILD_TEST_CONTAINER_IMAGE")
+            .unwrap_or_else(|_| "docker.io/library/debian:bookworm-slim".to_string());
+        let tmp = tmp("test_container_build");
+        let rs = recipes().join("container").display().to_string();
+        let od = tmp.as_dir().display().to_string();
+        let rattler_build = rattler().with_args([
+            "build",
+            "--recipe",
+            rs.as_str(),
+            "--output-dir",
+            od.as_str(),
+            "--container-image",
+            image.as_str(),
+        ]);
+        assert!(rattler_build.is_ok());
+        assert!(rattler_build.unwrap().status.success());
+
+        let pkg = get_extracted_package(tmp.as_dir(), "container-hello");
+        // the build script ran in the image
+        let os_release =
+            std::fs::read_to_string(pkg.join("share/container-hello/os-release")).unwrap();
+        if image.contains("debian") {
+            assert!(os_release.contains("ID=debian"));
+        }
+    }
 }
@@ -0,0 +1,233 @@
+//! Limiting the download bandwidth of a build.
+//!
+//! A single [`BandwidthLimiter`] is shared by all downloads of a build (sources and packages),
+//! so the limit applies to their sum. It is a token bucket: every downloaded chunk takes tokens
+//! out of the bucket, and a download waits when the bucket is empty until enough tokens have
+//! been refilled. Only the pace at which chunks are read changes, never their content.
+
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{Stream, StreamExt};
+use indicatif::HumanBytes;
+
+/// The environment variable that sets the download rate limit, e.g. `500K` or `10M` (bytes per
+/// second, with an optional binary `K`, `M` or `G` suffix)
+pub const RATE_LIMIT_ENV: &str = "RATTLER_BUILD_DOWNLOAD_RATE_LIMIT";
+
+/// The bucket holds at most this much time worth of tokens. This is the largest burst the
+/// downloads can make after being idle.
+const BURST: Duration = Duration::from_millis(250);
+
+/// The interval over which the current rate is measured
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Error when parsing a rate limit
+#[derive(Debug, thiserror::Error)]
+#[error("invalid download rate limit `{0}` (expected bytes per second, e.g. `500K` or `10M`)")]
+pub struct ParseRateError(String);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    window_start: Instant,
+    window_bytes: u64,
+    current_rate: u64,
+}
+
+/// A download rate limit that is shared by all its clones
+#[derive(Debug, Clone)]
+pub struct BandwidthLimiter {
+    bytes_per_second: NonZeroU64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl BandwidthLimiter {
+    /// Create a new limiter that allows `bytes_per_second` over all downloads
+    pub fn new(bytes_per_second: NonZeroU64) -> Self {
+        let now = Instant::now();
+        Self {
+            bytes_per_second,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_second.get() as f64 * BURST.as_secs_f64(),
+                last_refill: now,
+                window_start: now,
+                window_bytes: 0,
+                current_rate: 0,
+            })),
+        }
+    }
+
+    /// Parse a rate limit like `1048576`, `500K`, `10M` or `1G`
+    pub fn parse(value: &str) -> Result<Self, ParseRateError> {
+        let error = || ParseRateError(value.to_string());
+        let trimmed = value.trim();
+        let (number, multiplier) = match trimmed.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&trimmed[..i], 1024),
+            Some((i, 'm' | 'M')) => (&trimmed[..i], 1024 * 1024),
+            Some((i, 'g' | 'G')) => (&trimmed[..i], 1024 * 1024 * 1024),
+            _ => (trimmed, 1),
+        };
+        let bytes = number
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .and_then(NonZeroU64::new)
+            .ok_or_else(error)?;
+        Ok(Self::new(bytes))
+    }
+
+    /// The limit from the [`RATE_LIMIT_ENV`] environment variable, if it is set. Invalid values
+    /// are ignored with a warning.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var(RATE_LIMIT_ENV).ok()?;
+        match Self::parse(&value) {
+            Ok(limiter) => Some(limiter),
+            Err(e) => {
+                tracing::warn!("Ignoring {}: {}", RATE_LIMIT_ENV, e);
+                None
+            }
+        }
+    }
+
+    /// The allowed number of bytes per second
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second.get()
+    }
+
+    /// The measured rate over all downloads, in bytes per second
+    pub fn current_rate(&self) -> u64 {
+        self.lock().current_rate
+    }
+
+    /// A message for progress bars with the current and the allowed rate
+    pub fn rate_message(&self) -> String {
+        format!(
+            "{}/s (limit {}/s)",
+            HumanBytes(self.current_rate()),
+            HumanBytes(self.bytes_per_second())
+        )
+    }
+
+    /// Take `bytes` tokens out of the bucket, waiting until they are available. The tokens are
+    /// taken right away (the bucket may go into debt), so that concurrent downloads are served
+    /// in the order in which they asked.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.lock();
+            let now = Instant::now();
+            let rate = self.bytes_per_second.get() as f64;
+
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate * BURST.as_secs_f64());
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+
+            bucket.window_bytes += bytes as u64;
+            let window = now.duration_since(bucket.window_start);
+            if window >= RATE_WINDOW {
+                bucket.current_rate = (bucket.window_bytes as f64 / window.as_secs_f64()) as u64;
+                bucket.window_start = now;
+                bucket.window_bytes = 0;
+            }
+
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Throttle a stream of downloaded chunks (e.g. [`reqwest::Response::bytes_stream`])
+    pub fn throttle<S, B, E>(&self, stream: S) -> impl Stream<Item = Result<B, E>>
+    where
+        S: Stream<Item = Result<B, E>>,
+        B: AsRef<[u8]>,
+    {
+        let limiter = self.clone();
+        stream.then(move |chunk| {
+            let limiter = limiter.clone();
+            async move {
+                if let Ok(bytes) = &chunk {
+                    limiter.acquire(bytes.as_ref().len()).await;
+                }
+                chunk
+            }
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A limiter is serialized as its rate in bytes per second
+impl serde::Serialize for BandwidthLimiter {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.bytes_per_second())
+    }
+}
+
+/// A limiter is deserialized from a number of bytes per second or a rate like `10M`
+impl<'de> serde::Deserialize<'de> for BandwidthLimiter {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Rate {
+            Bytes(u64),
+            Text(String),
+        }
+
+        let rate = match Rate::deserialize(deserializer)? {
+            Rate::Bytes(bytes) => bytes.to_string(),
+            Rate::Text(text) => text,
+        };
+        Self::parse(&rate).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures::StreamExt;
+
+    use super::BandwidthLimiter;
+
+    #[test]
+    fn parse() {
+        let rate = |s: &str| BandwidthLimiter::parse(s).map(|l| l.bytes_per_second());
+        assert_eq!(rate("1000").unwrap(), 1000);
+        assert_eq!(rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(rate(" 10m ").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(rate("1G").unwrap(), 1024 * 1024 * 1024);
+        assert!(rate("0").is_err());
+        assert!(rate("fast").is_err());
+        assert!(rate("10T").is_err());
+    }
+
+    #[tokio::test]
+    async fn shared_limit() {
+        let limiter = BandwidthLimiter::parse("100K").unwrap();
+        let chunks = || futures::stream::iter((0..10).map(|_| Ok::<_, ()>(vec![0u8; 10 * 1024])));
+
+        // two downloads of 100 KiB each share the limit of 100 KiB/s
+        let start = Instant::now();
+        let (a, b) = tokio::join!(
+            limiter.throttle(chunks()).collect::<Vec<_>>(),
+            limiter.throttle(chunks()).collect::<Vec<_>>()
+        );
+        let elapsed = start.elapsed();
+
+        assert_eq!(a.len() + b.len(), 20);
+        // minus the initial burst of 25 KiB
+        assert!(elapsed >= Duration::from_millis(1700), "{elapsed:?}");
+        assert!(limiter.current_rate() > 0);
+    }
+}
@@ -1,29 +1,52 @@
 //! The build module contains the code for running the build process for a given [`Output`]
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::ffi::OsString;
 
-use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
 
 use fs_err as fs;
 use fs_err::File;
-use std::borrow::Cow;
+use std::future::Future;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
 use miette::IntoDiagnostic;
+use rattler::package_cache::CacheKey;
+use rattler_conda_types::package::{PackageFile, PathsJson};
+use rattler_conda_types::{Platform, RepoDataRecord};
 use rattler_shell::shell;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
 
-use crate::env_vars::write_env_script;
+use crate::build_report::{BuildReport, PhaseTimings, TestStatus};
+use crate::channel_query::ChannelQuery;
+use crate::ci_log::{BuildPhase, LogGroup};
+use crate::env_vars::{script_secrets, write_env_script};
+use crate::log_stream::{LogForwarder, LogLine, LogStream};
 use crate::metadata::{Directories, Output};
+use crate::network::NetworkGuard;
+use crate::output_files::select_files;
+use crate::package_inspect::PackageInspector;
 use crate::packaging::{package_conda, record_files};
-use crate::recipe::parser::ScriptContent;
+use crate::recipe::parser::{ScriptContent, SourceFetch};
+use crate::render::integrity::check_prefix;
+use crate::render::lock::write_lock_file;
 use crate::render::resolved_dependencies::{install_environments, resolve_dependencies};
-use crate::source::fetch_sources;
+use crate::skip_existing::{find_existing, SkipExisting};
+use crate::source::{copy_dir::CopyDir, fetch_sources};
 use crate::test::TestConfiguration;
-use crate::{index, test, tool_configuration};
+use crate::tools::{Tool, ToolNotFound, Tools};
+use crate::{container, index, test, tool_configuration};
+
+/// The start of the comment with the hash of the inputs of the build scripts
+const INPUTS_HEADER: &str = "rattler-build inputs:";
 
 const BASH_PREAMBLE: &str = r#"
 ## Start of bash preamble
@@ -35,30 +58,572 @@ set -x
 ## End of preamble
 "#;
 
-/// Create a conda build script and return the path to it
+/// The flavor of the build script that is written for an output.
+///
+/// The flavor is decided by the platform the package is built _for_, so that a Windows package
+/// gets a `cmd.exe` script (and a unix package a bash script), regardless of the machine that
+/// rattler-build is running on. The interpreter or the script file of the recipe can select
+/// another flavor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptFlavor {
+    /// A bash script (`conda_build.sh`)
+    Bash,
+    /// A `cmd.exe` batch script (`conda_build.bat`)
+    CmdExe,
+    /// A PowerShell script (`conda_build.ps1`)
+    PowerShell,
+}
+
+impl ScriptFlavor {
+    /// Select the script flavor for the given target and build platform. `noarch` packages
+    /// don't have a target family, so the script is written for the build platform instead.
+    pub fn from_platforms(target_platform: &Platform, build_platform: &Platform) -> Self {
+        let platform = match target_platform {
+            Platform::NoArch => build_platform,
+            platform => platform,
+        };
+        if platform.is_windows() {
+            ScriptFlavor::CmdExe
+        } else {
+            ScriptFlavor::Bash
+        }
+    }
+
+    /// The file extension of scripts with this flavor (without the leading dot)
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ScriptFlavor::Bash => "sh",
+            ScriptFlavor::CmdExe => "bat",
+            ScriptFlavor::PowerShell => "ps1",
+        }
+    }
+
+    /// The flavor of a script file with the `extension`
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "sh" => Some(ScriptFlavor::Bash),
+            "bat" | "cmd" => Some(ScriptFlavor::CmdExe),
+            "ps1" => Some(ScriptFlavor::PowerShell),
+            _ => None,
+        }
+    }
+
+    /// The shell that writes the activation and environment scripts of this flavor
+    pub fn shell(&self) -> shell::ShellEnum {
+        match self {
+            ScriptFlavor::Bash => shell::Bash.into(),
+            ScriptFlavor::CmdExe => shell::CmdExe.into(),
+            ScriptFlavor::PowerShell => shell::PowerShell::default().into(),
+        }
+    }
+
+    /// The preamble that sources the build environment script at `env_script_path`
+    fn preamble(&self, env_script_path: &Path) -> String {
+        match self {
+            ScriptFlavor::Bash => {
+                BASH_PREAMBLE.replace("((script_path))", &env_script_path.to_string_lossy())
+            }
+            ScriptFlavor::CmdExe => format!(
+                "IF \"%CONDA_BUILD%\" == \"\" (\n    call {}\n)",
+                env_script_path.to_string_lossy()
+            ),
+            // a failing cmdlet stops the script (a failing program is checked after every
+            // statement, see `powershell_exit_checks`)
+            ScriptFlavor::PowerShell => format!(
+                "if (-not $env:CONDA_BUILD) {{\n    . \"{}\"\n}}\n$ErrorActionPreference = \"Stop\"",
+                env_script_path.to_string_lossy()
+            ),
+        }
+    }
+
+    /// The comment with the hash of the inputs that a script was written for
+    fn inputs_header(&self, hash: &str) -> String {
+        match self {
+            ScriptFlavor::Bash | ScriptFlavor::PowerShell => format!("# {INPUTS_HEADER} {hash}"),
+            ScriptFlavor::CmdExe => format!(":: {INPUTS_HEADER} {hash}"),
+        }
+    }
+
+    /// Add the inputs header to `script`, after its shebang (if any)
+    fn with_inputs_header(&self, script: &str, hash: &str) -> String {
+        let header = self.inputs_header(hash);
+        match script.split_once('\n') {
+            Some((shebang, rest)) if shebang.starts_with("#!") => {
+                format!("{shebang}\n{header}\n{rest}")
+            }
+            _ => format!("{header}\n{script}"),
+        }
+    }
+
+    /// Render the full build script (preamble + recipe script) for this flavor
+    pub fn render_script(&self, env_script_path: &Path, script_content: &str) -> String {
+        match self {
+            ScriptFlavor::PowerShell => format!(
+                "{}\n{}",
+                self.preamble(env_script_path),
+                powershell_exit_checks(script_content)
+            ),
+            _ => format!("{}\n{}", self.preamble(env_script_path), script_content),
+        }
+    }
+
+    /// Returns the interpreter and arguments that are used to execute `script` on the build
+    /// platform. When the script flavor does not match the build platform we rely on a
+    /// compatibility layer being available in the `PATH` (`bash` from MSYS2 / git-bash on
+    /// Windows, `wine` to run batch scripts on unix).
+    pub fn interpreter(&self, build_platform: &Platform, script: &Path) -> (String, Vec<OsString>) {
+        let script = script.as_os_str().to_owned();
+        match (self, build_platform.is_windows()) {
+            (ScriptFlavor::Bash, false) => {
+                ("/bin/bash".to_string(), vec![OsString::from("-e"), script])
+            }
+            (ScriptFlavor::Bash, true) => ("bash".to_string(), vec![OsString::from("-e"), script]),
+            (ScriptFlavor::CmdExe, true) => (
+                "cmd.exe".to_string(),
+                vec![OsString::from("/d"), OsString::from("/c"), script],
+            ),
+            (ScriptFlavor::CmdExe, false) => (
+                "wine".to_string(),
+                vec![
+                    OsString::from("cmd.exe"),
+                    OsString::from("/d"),
+                    OsString::from("/c"),
+                    script,
+                ],
+            ),
+            (ScriptFlavor::PowerShell, true) => (
+                "powershell.exe".to_string(),
+                vec![
+                    OsString::from("-NoProfile"),
+                    OsString::from("-NonInteractive"),
+                    OsString::from("-ExecutionPolicy"),
+                    OsString::from("Bypass"),
+                    OsString::from("-File"),
+                    script,
+                ],
+            ),
+            (ScriptFlavor::PowerShell, false) => (
+                "pwsh".to_string(),
+                vec![
+                    OsString::from("-NoProfile"),
+                    OsString::from("-NonInteractive"),
+                    OsString::from("-File"),
+                    script,
+                ],
+            ),
+        }
+    }
+}
+
+/// The line that stops a PowerShell script when the last program failed
+const POWERSHELL_EXIT_CHECK: &str = "if ($LASTEXITCODE) { exit $LASTEXITCODE }";
+
+/// Stop the PowerShell `script` when a program fails. Windows PowerShell (5.1) continues after a
+/// program that exits with an error, even with `$ErrorActionPreference = "Stop"`. The exit code
+/// is checked after every statement at the top level of the script, i.e. not within blocks,
+/// parentheses, here-strings or lines that are continued on the next line (or by `else`,
+/// `catch`, ...).
+fn powershell_exit_checks(script: &str) -> String {
+    let lines = script.lines().collect::<Vec<_>>();
+    let mut checked = String::with_capacity(script.len());
+    let mut depth = 0usize;
+    let mut here_string: Option<&str> = None;
+    for (index, line) in lines.iter().enumerate() {
+        checked.push_str(line);
+        checked.push('\n');
+
+        let trimmed = line.trim();
+        if let Some(end) = here_string {
+            if trimmed.starts_with(end) {
+                here_string = None;
+            } else {
+                continue;
+            }
+        }
+        if trimmed.ends_with("@\"") {
+            here_string = Some("\"@");
+            continue;
+        } else if trimmed.ends_with("@'") {
+            here_string = Some("'@");
+            continue;
+        }
+
+        let mut quote = None;
+        for c in trimmed.chars() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '\'' | '"') => quote = Some(c),
+                (None, '#') => break,
+                (None, '{' | '(') => depth += 1,
+                (None, '}' | ')') => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+
+        let next = lines[index + 1..]
+            .iter()
+            .map(|line| line.trim().to_lowercase())
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .unwrap_or_default();
+        let continued = ['`', '|', ','].iter().any(|c| trimmed.ends_with(*c))
+            || (trimmed.ends_with('}')
+                && ["elseif", "else", "catch", "finally", "until", "while"]
+                    .iter()
+                    .any(|keyword| {
+                        next.strip_prefix(keyword).map_or(false, |rest| {
+                            !rest.starts_with(|c: char| c.is_alphanumeric() || c == '-')
+                        })
+                    }));
+        if depth == 0 && !continued && !trimmed.is_empty() && !trimmed.starts_with('#') {
+            checked.push_str(POWERSHELL_EXIT_CHECK);
+            checked.push('\n');
+        }
+    }
+    checked
+}
+
+/// Find a bash that runs bash scripts on Windows: the `m2-bash` of the build prefix or a bash in
+/// the `PATH` (except the one of WSL, which runs the script in a Linux VM), and then the ones of
+/// Git for Windows and MSYS2
+fn find_bash(tools: &Tools) -> Result<PathBuf, ToolNotFound> {
+    tools
+        .find_accepted(Tool::Bash, "run the bash build script", |path| {
+            !path
+                .to_string_lossy()
+                .to_lowercase()
+                .contains("windows\\system32")
+        })
+        .or_else(|err| {
+            let program_files = std::env::var_os("ProgramFiles")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("C:\\Program Files"));
+            [
+                program_files.join("Git/bin/bash.exe"),
+                PathBuf::from("C:\\msys64\\usr\\bin\\bash.exe"),
+            ]
+            .into_iter()
+            .find(|path| path.is_file())
+            .ok_or(err)
+        })
+}
+
+/// The script file `stem.<extension>` of the recipe, or the first of the `fallbacks` flavors
+/// that the recipe has a script for. Returns the file and its flavor.
+fn select_script_file(
+    stem: &Path,
+    extension: &str,
+    flavor: ScriptFlavor,
+    fallbacks: &[ScriptFlavor],
+) -> (PathBuf, ScriptFlavor) {
+    let native = stem.with_extension(extension);
+    if native.exists() {
+        return (native, flavor);
+    }
+    for fallback in fallbacks {
+        let path = stem.with_extension(fallback.extension());
+        if path.exists() {
+            tracing::warn!(
+                "{} does not exist, running {} instead",
+                native.display(),
+                path.display()
+            );
+            return (path, *fallback);
+        }
+    }
+    (native, flavor)
+}
+
+#[allow(missing_docs)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum BuildScriptError {
+    #[error("Could not write the build script: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Unknown build script interpreter `{0}`")]
+    #[diagnostic(help("use one of `bash`, `cmd`, `powershell`, `python` or `nushell`"))]
+    UnknownInterpreter(String),
+
+    #[error("The build script interpreter `{interpreter}` was not found in the build prefix ({})", prefix.display())]
+    #[diagnostic(help("add `{package}` to the build requirements of the recipe"))]
+    InterpreterNotFound {
+        interpreter: String,
+        package: String,
+        prefix: PathBuf,
+    },
+
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    ToolNotFound(#[from] ToolNotFound),
+
+    #[error("The build script did not finish within {timeout:?} and was killed after {elapsed:.1?}, the last lines of its output are:\n{tail}")]
+    #[diagnostic(help("the full output is in {}", log.display()))]
+    TimedOut {
+        timeout: Duration,
+        elapsed: Duration,
+        log: PathBuf,
+        tail: String,
+    },
+
+    #[error("The build script failed, the last lines of its output are:\n{tail}")]
+    #[diagnostic(help("the full output is in {}", log.display()))]
+    Failed { log: PathBuf, tail: String },
+
+    #[error("The build was cancelled")]
+    Cancelled,
+
+    #[error("{name} can not be cross-compiled for {host_platform} on {build_platform}")]
+    #[diagnostic(help("the recipe sets `build.native_only`, build it on {host_platform}"))]
+    NativeOnly {
+        name: String,
+        build_platform: Platform,
+        host_platform: Platform,
+    },
+}
+
+/// The interpreter that is selected with `build.script.interpreter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptInterpreter {
+    /// A bash script, also for Windows packages
+    Bash,
+    /// A `cmd.exe` script, also for unix packages
+    Cmd,
+    /// A PowerShell script (run with `pwsh` on unix)
+    PowerShell,
+    /// A python script that is run with the python of the build prefix
+    Python,
+    /// A nushell script that is run with the `nu` of the build prefix
+    Nushell,
+}
+
+impl ScriptInterpreter {
+    /// Parse the name of an interpreter in the recipe
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bash" => Some(ScriptInterpreter::Bash),
+            "cmd" | "cmd.exe" => Some(ScriptInterpreter::Cmd),
+            "powershell" | "pwsh" => Some(ScriptInterpreter::PowerShell),
+            "python" | "python3" => Some(ScriptInterpreter::Python),
+            "nu" | "nushell" => Some(ScriptInterpreter::Nushell),
+            _ => None,
+        }
+    }
+
+    /// The flavor of the main build script. `bash`, `cmd` and `powershell` replace the flavor of
+    /// the target platform, the other interpreters are run from a script of that flavor.
+    pub fn flavor(&self, platform_flavor: ScriptFlavor) -> ScriptFlavor {
+        match self {
+            ScriptInterpreter::Bash => ScriptFlavor::Bash,
+            ScriptInterpreter::Cmd => ScriptFlavor::CmdExe,
+            ScriptInterpreter::PowerShell => ScriptFlavor::PowerShell,
+            ScriptInterpreter::Python | ScriptInterpreter::Nushell => platform_flavor,
+        }
+    }
+
+    /// The file extension of scripts that are run by the interpreter itself (i.e. not by the
+    /// main build script)
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            ScriptInterpreter::Python => Some("py"),
+            ScriptInterpreter::Nushell => Some("nu"),
+            ScriptInterpreter::Bash | ScriptInterpreter::Cmd | ScriptInterpreter::PowerShell => {
+                None
+            }
+        }
+    }
+
+    /// Find the executable of the interpreter in the build prefix. Returns `None` for `bash`,
+    /// `cmd` and `powershell`, which are run like the scripts of their flavor.
+    pub fn executable(
+        &self,
+        build_prefix: &Path,
+        build_platform: &Platform,
+    ) -> Result<Option<PathBuf>, BuildScriptError> {
+        let Some((name, package, candidates)) = self.candidates(build_platform) else {
+            return Ok(None);
+        };
+        candidates
+            .iter()
+            .map(|candidate| build_prefix.join(candidate))
+            .find(|path| path.is_file())
+            .map(Some)
+            .ok_or_else(|| BuildScriptError::InterpreterNotFound {
+                interpreter: name.to_string(),
+                package: package.to_string(),
+                prefix: build_prefix.to_path_buf(),
+            })
+    }
+
+    /// The name of the executable of the interpreter, the package that provides it and the
+    /// locations in the build prefix where it is looked for. The interpreters of the script
+    /// flavors are not looked for.
+    fn candidates(
+        &self,
+        build_platform: &Platform,
+    ) -> Option<(&'static str, &'static str, &'static [&'static str])> {
+        match (self, build_platform.is_windows()) {
+            (
+                ScriptInterpreter::Bash | ScriptInterpreter::Cmd | ScriptInterpreter::PowerShell,
+                _,
+            ) => None,
+            (ScriptInterpreter::Python, false) => Some(("python", "python", &["bin/python"])),
+            (ScriptInterpreter::Python, true) => Some(("python", "python", &["python.exe"])),
+            (ScriptInterpreter::Nushell, false) => Some(("nu", "nushell", &["bin/nu"])),
+            (ScriptInterpreter::Nushell, true) => {
+                Some(("nu", "nushell", &["Library/bin/nu.exe", "Scripts/nu.exe"]))
+            }
+        }
+    }
+}
+
+/// The build script that is written for an output
+#[derive(Debug, Clone)]
+pub struct BuildScript {
+    /// The path of the main build script (`conda_build.sh` / `conda_build.bat`)
+    pub path: PathBuf,
+    /// The flavor of the main build script
+    pub flavor: ScriptFlavor,
+    /// The interpreter that was found for the script, instead of the default of its flavor
+    /// (the bash of a bash script on Windows)
+    pub program: Option<PathBuf>,
+}
+
+impl BuildScript {
+    /// Returns the interpreter and arguments that are used to execute the build script
+    pub fn command(&self, build_platform: &Platform) -> (String, Vec<OsString>) {
+        let (program, args) = self.flavor.interpreter(build_platform, &self.path);
+        match &self.program {
+            Some(found) => (found.to_string_lossy().to_string(), args),
+            None => (program, args),
+        }
+    }
+}
+
+/// Create the conda build script of an output
 pub fn get_conda_build_script(
     output: &Output,
     directories: &Directories,
-) -> Result<PathBuf, std::io::Error> {
+    tools: &Tools,
+) -> Result<BuildScript, BuildScriptError> {
+    let (path, flavor, _) = write_build_scripts(output, directories, true)?;
+
+    // bash is not part of Windows, the script runs with the first bash that is found. A
+    // PowerShell script runs with PowerShell 7 (`pwsh`) if it is installed.
+    let program = match (
+        flavor,
+        output.build_configuration.build_platform.is_windows(),
+    ) {
+        (ScriptFlavor::Bash, true) => {
+            let bash = find_bash(tools)?;
+            tracing::info!("Running the bash script with {}", bash.display());
+            Some(bash)
+        }
+        (ScriptFlavor::PowerShell, true) => tools
+            .find(Tool::Pwsh, "run the PowerShell build script")
+            .ok(),
+        _ => None,
+    };
+    Ok(BuildScript {
+        path,
+        flavor,
+        program,
+    })
+}
+
+/// The build script of an output as it is shown by `--render-only`, without the hash of its
+/// inputs: the main script, followed by the script for the interpreter of the recipe (if any).
+/// The scripts are written to the work directory of the output, but the build environment does
+/// not have to be installed: the interpreter is expected at its usual location in the build
+/// prefix.
+pub fn render_build_script(output: &Output) -> Result<String, BuildScriptError> {
+    let directories = &output.build_configuration.directories;
+    let (path, flavor, interpreted) = write_build_scripts(output, directories, false)?;
+    let header = flavor.inputs_header("");
+    let mut script = fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.starts_with(header.trim_end()))
+        .join("\n");
+    if let Some(interpreted) = interpreted {
+        script.push_str("\n\n");
+        script.push_str(&fs::read_to_string(interpreted)?);
+    }
+    Ok(script)
+}
+
+/// Write the build scripts of an output to the work directory, and return the path and the
+/// flavor of the main script, and the path of the script for the interpreter of the recipe.
+/// Without `installed`, the interpreter is not looked for in the build prefix.
+fn write_build_scripts(
+    output: &Output,
+    directories: &Directories,
+    installed: bool,
+) -> Result<(PathBuf, ScriptFlavor, Option<PathBuf>), BuildScriptError> {
     let recipe = &output.recipe;
 
     let script = recipe.build().script();
-    let default_extension = if output.build_configuration.target_platform.is_windows() {
-        "bat"
+    let interpreter = script
+        .interpreter()
+        .map(|name| {
+            ScriptInterpreter::from_name(name)
+                .ok_or_else(|| BuildScriptError::UnknownInterpreter(name.to_string()))
+        })
+        .transpose()?;
+    let platform_flavor = ScriptFlavor::from_platforms(
+        &output.build_configuration.target_platform,
+        &output.build_configuration.build_platform,
+    );
+    let mut flavor = interpreter.map_or(platform_flavor, |interpreter| {
+        interpreter.flavor(platform_flavor)
+    });
+    // fail before anything is written if the interpreter is not installed
+    let build_platform = &output.build_configuration.build_platform;
+    let executable = match interpreter {
+        Some(interpreter) if installed => {
+            interpreter.executable(&directories.build_prefix, build_platform)?
+        }
+        Some(interpreter) => interpreter
+            .candidates(build_platform)
+            .map(|(_, _, candidates)| directories.build_prefix.join(candidates[0])),
+        None => None,
+    };
+    let default_extension = interpreter
+        .and_then(|interpreter| interpreter.extension())
+        .unwrap_or(flavor.extension());
+    // Without an interpreter, a Windows build runs the PowerShell or the bash script of the
+    // recipe if it has no batch script
+    let fallbacks: &[ScriptFlavor] = if interpreter.is_none() && flavor == ScriptFlavor::CmdExe {
+        &[ScriptFlavor::PowerShell, ScriptFlavor::Bash]
     } else {
-        "sh"
+        &[]
     };
+    // Without an interpreter, the extension of a script file selects its flavor
+    let file_flavor = |path: &Path, flavor: ScriptFlavor| match interpreter {
+        Some(_) => flavor,
+        None => path
+            .extension()
+            .and_then(|extension| ScriptFlavor::from_extension(&extension.to_string_lossy()))
+            .unwrap_or(flavor),
+    };
+    // whether the script was read from a file of the recipe
+    let mut from_file = matches!(
+        script.contents(),
+        ScriptContent::Default | ScriptContent::Path(_)
+    );
     let script_content = match script.contents() {
         // No script was specified, so we try to read the default script. If the file cannot be
         // found we return an empty string.
         ScriptContent::Default => {
-            let recipe_file = directories
-                .recipe_dir
-                .join(Path::new("build").with_extension(default_extension));
+            let (recipe_file, selected) = select_script_file(
+                &directories.recipe_dir.join("build"),
+                default_extension,
+                flavor,
+                fallbacks,
+            );
+            flavor = selected;
             match std::fs::read_to_string(recipe_file) {
                 Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
                 Err(e) => {
-                    return Err(e);
+                    return Err(e.into());
                 }
                 Ok(content) => content,
             }
@@ -66,21 +631,29 @@ pub fn get_conda_build_script(
 
         // The scripts path was explicitly specified. If the file cannot be found we error out.
         ScriptContent::Path(path) => {
-            let path_with_ext = if path.extension().is_none() {
-                Cow::Owned(path.with_extension(default_extension))
+            let recipe_file = if path.extension().is_none() {
+                let (recipe_file, selected) = select_script_file(
+                    &directories.recipe_dir.join(path),
+                    default_extension,
+                    flavor,
+                    fallbacks,
+                );
+                flavor = selected;
+                recipe_file
             } else {
-                Cow::Borrowed(path.as_path())
+                flavor = file_flavor(path, flavor);
+                directories.recipe_dir.join(path)
             };
-            let recipe_file = directories.recipe_dir.join(path_with_ext);
             match std::fs::read_to_string(&recipe_file) {
                 Err(err) if err.kind() == ErrorKind::NotFound => {
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::NotFound,
                         format!("recipe file {:?} does not exist", recipe_file.display()),
-                    ));
+                    )
+                    .into());
                 }
                 Err(e) => {
-                    return Err(e);
+                    return Err(e.into());
                 }
                 Ok(content) => content,
             }
@@ -89,122 +662,894 @@ pub fn get_conda_build_script(
         // contents of the string. Try to read the file as a script but fall back to using the string
         // as the contents itself if the file is missing.
         ScriptContent::CommandOrPath(path) => {
-            let content =
-                if !path.contains('\n') && (path.ends_with(".bat") || path.ends_with(".sh")) {
-                    let recipe_file = directories.recipe_dir.join(Path::new(path));
-                    match std::fs::read_to_string(recipe_file) {
-                        Err(err) if err.kind() == ErrorKind::NotFound => None,
-                        Err(e) => {
-                            return Err(e);
-                        }
-                        Ok(content) => Some(content),
+            let script_extension = format!(".{default_extension}");
+            let is_script_file = [".bat", ".sh", ".ps1", script_extension.as_str()]
+                .iter()
+                .any(|extension| path.ends_with(extension));
+            let content = if !path.contains('\n') && is_script_file {
+                let recipe_file = directories.recipe_dir.join(Path::new(path));
+                match std::fs::read_to_string(&recipe_file) {
+                    Err(err) if err.kind() == ErrorKind::NotFound => None,
+                    Err(e) => {
+                        return Err(e.into());
                     }
-                } else {
-                    None
-                };
+                    Ok(content) => {
+                        flavor = file_flavor(&recipe_file, flavor);
+                        from_file = true;
+                        Some(content)
+                    }
+                }
+            } else {
+                None
+            };
             match content {
                 Some(content) => content,
                 None => path.to_owned(),
             }
         }
         ScriptContent::Commands(commands) => commands.iter().join("\n"),
-        ScriptContent::Command(command) => command.to_owned(),
+        ScriptContent::Command(command) | ScriptContent::Rendered(command) => command.to_owned(),
     };
 
-    if script.interpreter().is_some() {
-        // We don't support an interpreter yet
-        tracing::error!("build.script.interpreter is not supported yet");
-    }
+    // Only scripts that are written in the recipe can select their interpreter with a shebang
+    // line, and only if the recipe does not select one
+    let inline = interpreter.is_none() && !from_file;
 
-    if cfg!(unix) {
-        let build_env_script_path = directories.work_dir.join("build_env.sh");
-        let preamble =
-            BASH_PREAMBLE.replace("((script_path))", &build_env_script_path.to_string_lossy());
+    let build_env_script_path = directories
+        .work_dir
+        .join(Path::new("build_env").with_extension(flavor.extension()));
 
-        let mut file_out = File::create(&build_env_script_path)?;
-        write_env_script(output, "BUILD", &mut file_out, shell::Bash).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to write build env script: {}", e),
-            )
-        })?;
-        let full_script = format!("{}\n{}", preamble, script_content);
-        let build_script_path = directories.work_dir.join("conda_build.sh");
+    let files = match (interpreter.and_then(|i| i.extension()), executable) {
+        (Some(extension), Some(executable)) => BuildScriptFiles::with_interpreter(
+            flavor,
+            &directories.work_dir,
+            &build_env_script_path,
+            &script_content,
+            &executable,
+            extension,
+        ),
+        _ => BuildScriptFiles::new(
+            flavor,
+            &directories.work_dir,
+            &build_env_script_path,
+            &script_content,
+            inline,
+        ),
+    };
 
-        let mut build_script_file = File::create(&build_script_path)?;
-        build_script_file.write_all(full_script.as_bytes())?;
-        Ok(build_script_path)
-    } else {
-        let build_env_script_path = directories.work_dir.join("build_env.bat");
-        let preamble = format!(
-            "IF \"%CONDA_BUILD%\" == \"\" (\n    call {}\n)",
-            build_env_script_path.to_string_lossy()
+    // the scripts of an earlier run in the same work dir are kept if they were written for the
+    // same inputs, and written again otherwise
+    let inputs_hash = script_inputs_hash(output, directories, &script_content);
+    let (build_script_path, full_script) = files.main;
+    let interpreted_path = files.interpreted.as_ref().map(|(path, _)| path.clone());
+    let existing = [&build_env_script_path, &build_script_path].map(|path| read_inputs_hash(path));
+    let interpreted_exists = files
+        .interpreted
+        .as_ref()
+        .map_or(true, |(path, _)| path.exists());
+    if interpreted_exists
+        && existing
+            .iter()
+            .all(|hash| hash.as_deref() == Some(inputs_hash.as_str()))
+    {
+        tracing::info!(
+            "Reusing the build scripts in {:?}, their inputs did not change",
+            directories.work_dir
         );
-        let mut file_out = File::create(&build_env_script_path)?;
+        return Ok((build_script_path, flavor, interpreted_path));
+    }
+    if existing.iter().any(Option::is_some) {
+        tracing::info!(
+            "The environment scripts in {:?} were written for other inputs and are refreshed",
+            directories.work_dir
+        );
+    }
 
-        write_env_script(output, "BUILD", &mut file_out, shell::CmdExe).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to write build env script: {}", e),
-            )
-        })?;
+    let mut file_out = File::create(&build_env_script_path)?;
+    writeln!(file_out, "{}", flavor.inputs_header(&inputs_hash))?;
+    match flavor {
+        ScriptFlavor::Bash => write_env_script(output, "BUILD", &mut file_out, shell::Bash),
+        ScriptFlavor::CmdExe => write_env_script(output, "BUILD", &mut file_out, shell::CmdExe),
+        ScriptFlavor::PowerShell => {
+            write_env_script(output, "BUILD", &mut file_out, shell::PowerShell::default())
+        }
+    }
+    .map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Failed to write build env script: {}", e),
+        )
+    })?;
+
+    if let Some((path, content)) = &files.interpreted {
+        let mut file = File::create(path)?;
+        file.write_all(content.as_bytes())?;
+    }
+
+    let mut build_script_file = File::create(&build_script_path)?;
+    build_script_file.write_all(
+        flavor
+            .with_inputs_header(&full_script, &inputs_hash)
+            .as_bytes(),
+    )?;
+    Ok((build_script_path, flavor, interpreted_path))
+}
+
+/// The hash of everything that goes into the build scripts of an output: the script itself, the
+/// build section of the recipe, the values of the passed through variables, the variant, the
+/// resolved dependencies, the directories and the version of rattler-build
+fn script_inputs_hash(output: &Output, directories: &Directories, script_content: &str) -> String {
+    let passthrough = output
+        .recipe
+        .build()
+        .script()
+        .passthrough()
+        .iter()
+        .map(|name| (name, std::env::var(name).ok()))
+        .collect::<BTreeMap<_, _>>();
+    let inputs = serde_json::json!({
+        "script": script_content,
+        "passthrough": passthrough,
+        "build": output.recipe.build(),
+        "variant": output.build_configuration.variant,
+        "target_platform": output.build_configuration.target_platform,
+        "build_platform": output.build_configuration.build_platform,
+        "dependencies": output.finalized_dependencies,
+        "directories": directories,
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    hex::encode(Sha256::digest(inputs.to_string()))
+}
+
+/// Read the hash of the inputs from the header of the script at `path`
+fn read_inputs_hash(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    // the header follows the shebang of a script
+    content.lines().take(2).find_map(|line| {
+        line.strip_prefix("# ")
+            .or_else(|| line.strip_prefix(":: "))
+            .and_then(|line| line.strip_prefix(INPUTS_HEADER))
+            .map(|hash| hash.trim().to_string())
+    })
+}
+
+/// The interpreter line (e.g. `#!/usr/bin/env python`) at the start of a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shebang {
+    /// The full first line of the script, including the `#!`
+    pub line: String,
+    /// The program to run the script with. For `#!/usr/bin/env <program>` this is the bare
+    /// program name that is looked up in the `PATH` of the build environment.
+    pub program: String,
+    /// Additional arguments passed to the program
+    pub args: Vec<String>,
+}
+
+impl Shebang {
+    /// Parse the shebang of a script, returns `None` if the script does not start with one
+    pub fn parse(content: &str) -> Option<Self> {
+        let line = content.lines().next()?;
+        let mut parts = line.strip_prefix("#!")?.split_whitespace();
+        let mut program = parts.next()?.to_string();
+        let mut args = parts.map(str::to_string).collect::<Vec<_>>();
+
+        if program_name(&program) == "env" {
+            if args.is_empty() {
+                return None;
+            }
+            program = args.remove(0);
+        }
+
+        Some(Shebang {
+            line: line.to_string(),
+            program,
+            args,
+        })
+    }
+
+    /// The file name of the program (e.g. `python` for `/usr/bin/python`)
+    pub fn program_name(&self) -> &str {
+        program_name(&self.program)
+    }
+
+    /// Returns true if the shebang selects a POSIX shell
+    pub fn is_shell(&self) -> bool {
+        matches!(self.program_name(), "bash" | "sh")
+    }
+
+    /// The file extension for scripts run by this interpreter
+    pub fn extension(&self) -> &'static str {
+        let name = self.program_name();
+        if name.starts_with("python") {
+            "py"
+        } else if name.starts_with("perl") {
+            "pl"
+        } else if name == "Rscript" {
+            "R"
+        } else if name == "ruby" {
+            "rb"
+        } else if name == "node" {
+            "js"
+        } else if matches!(name, "pwsh" | "powershell") {
+            "ps1"
+        } else if matches!(name, "bash" | "sh") {
+            "sh"
+        } else {
+            "script"
+        }
+    }
+}
+
+fn program_name(program: &str) -> &str {
+    Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(program)
+}
+
+/// The files that make up the build script of an output
+#[derive(Debug)]
+struct BuildScriptFiles {
+    /// The main build script (`conda_build.sh` / `conda_build.bat`) and its contents
+    main: (PathBuf, String),
+    /// A script that is run by the interpreter selected with a shebang or with
+    /// `build.script.interpreter`, and its contents
+    interpreted: Option<(PathBuf, String)>,
+}
+
+/// The line of the main build script that runs the interpreted script
+fn interpreted_command(
+    flavor: ScriptFlavor,
+    program: &str,
+    args: &[String],
+    interpreted_path: &Path,
+) -> String {
+    let command = std::iter::once(program.to_string())
+        .chain(args.iter().cloned())
+        .chain(std::iter::once(format!(
+            "\"{}\"",
+            interpreted_path.to_string_lossy()
+        )))
+        .join(" ");
+    match flavor {
+        ScriptFlavor::Bash => command,
+        ScriptFlavor::CmdExe => format!("{}\nIF %ERRORLEVEL% NEQ 0 exit 1", command),
+        // the exit code is checked by `powershell_exit_checks`
+        ScriptFlavor::PowerShell => format!("& {}", command),
+    }
+}
+
+impl BuildScriptFiles {
+    fn new(
+        flavor: ScriptFlavor,
+        work_dir: &Path,
+        env_script_path: &Path,
+        script_content: &str,
+        inline: bool,
+    ) -> Self {
+        let main_path = work_dir.join(Path::new("conda_build").with_extension(flavor.extension()));
+
+        let shebang = if inline {
+            Shebang::parse(script_content)
+        } else {
+            None
+        };
+
+        match shebang {
+            None => BuildScriptFiles {
+                main: (
+                    main_path,
+                    flavor.render_script(env_script_path, script_content),
+                ),
+                interpreted: None,
+            },
+            // A shell script for a bash flavor build only needs the preamble, we keep the
+            // shebang on the very first line.
+            Some(shebang) if shebang.is_shell() && flavor == ScriptFlavor::Bash => {
+                let body = script_content
+                    .split_once('\n')
+                    .map(|(_, rest)| rest)
+                    .unwrap_or_default();
+                BuildScriptFiles {
+                    main: (
+                        main_path,
+                        format!(
+                            "{}\n{}",
+                            shebang.line,
+                            flavor.render_script(env_script_path, body)
+                        ),
+                    ),
+                    interpreted: None,
+                }
+            }
+            // Everything else is written verbatim to its own file and run from the main build
+            // script, so that it still runs inside of the activated build environment.
+            Some(shebang) => {
+                let interpreted_path = work_dir
+                    .join(Path::new("conda_build_script").with_extension(shebang.extension()));
+                let program = if flavor != ScriptFlavor::Bash {
+                    shebang.program_name()
+                } else {
+                    shebang.program.as_str()
+                };
+                let command =
+                    interpreted_command(flavor, program, &shebang.args, &interpreted_path);
+                BuildScriptFiles {
+                    main: (main_path, flavor.render_script(env_script_path, &command)),
+                    interpreted: Some((interpreted_path, script_content.to_string())),
+                }
+            }
+        }
+    }
+
+    /// The files for a script that is run by the `executable` of the interpreter selected with
+    /// `build.script.interpreter`. The script is written verbatim to `conda_build.<extension>`,
+    /// and the main build script sets up the build environment and runs it.
+    fn with_interpreter(
+        flavor: ScriptFlavor,
+        work_dir: &Path,
+        env_script_path: &Path,
+        script_content: &str,
+        executable: &Path,
+        extension: &str,
+    ) -> Self {
+        let main_path = work_dir.join(Path::new("conda_build").with_extension(flavor.extension()));
+        let interpreted_path = work_dir.join(Path::new("conda_build").with_extension(extension));
+        let program = format!("\"{}\"", executable.to_string_lossy());
+        let command = interpreted_command(flavor, &program, &[], &interpreted_path);
+        BuildScriptFiles {
+            main: (main_path, flavor.render_script(env_script_path, &command)),
+            interpreted: Some((interpreted_path, script_content.to_string())),
+        }
+    }
+}
+
+/// Spawns a process and replaces the given strings in the output with the given replacements.
+/// This is used to replace the host prefix with $PREFIX and the build prefix with $BUILD_PREFIX.
+/// The lines of stdout and stderr are also written to the log file at `log_path`, in the order
+/// in which they arrive. If the process runs longer than `timeout`, or the build is cancelled, it
+/// is killed together with all processes that it started.
+#[allow(clippy::too_many_arguments)]
+fn run_process_with_replacements(
+    command: &str,
+    cwd: &PathBuf,
+    args: &[OsString],
+    env: &[(String, String)],
+    replacements: &[(&str, &str)],
+    log_sender: Option<&Sender<LogLine>>,
+    log_path: &Path,
+    timeout: Option<Duration>,
+    cancellation: &CancellationToken,
+) -> miette::Result<()> {
+    let log = Arc::new(Mutex::new(BuildLog {
+        file: File::create(log_path).into_diagnostic()?,
+        tail: VecDeque::with_capacity(LOG_TAIL_LINES),
+    }));
+
+    let mut command = Command::new(command);
+    command
+        .current_dir(cwd)
+        .args(args)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // a process group of its own, so that Ctrl-C does not reach the script directly, and a
+    // timeout or a cancellation also kills the processes of the script
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    // suspended until it is in the job object of its process tree, so that every process that
+    // it starts is in the job as well
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        command.creation_flags(windows_sys::Win32::System::Threading::CREATE_SUSPENDED);
+    }
+    let start = Instant::now();
+    let mut child = command.spawn().expect("Failed to execute command");
+    let process_tree = ProcessTree::new(&child);
+
+    // Process the output line by line. The readers are threads of their own, so that they can
+    // be left behind if a process that escaped the process tree keeps the pipes open.
+    let forward = |output: Box<dyn Read + Send>, stream: LogStream| {
+        let mut forwarder = LogForwarder::new(log_sender, BuildPhase::Script);
+        let replacements = replacements
+            .iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect::<Vec<_>>();
+        let log = log.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(output).lines() {
+                if let Ok(line) = line {
+                    let filtered_line = replacements
+                        .iter()
+                        .fold(line, |acc, (from, to)| acc.replace(from, to));
+                    tracing::info!("{}", filtered_line);
+                    forwarder.send(stream, &filtered_line);
+                    log.lock().unwrap().push(&filtered_line);
+                } else {
+                    tracing::warn!("Error reading output: {:?}", line);
+                }
+            }
+            forwarder.finish();
+        })
+    };
+    let mut readers = Vec::new();
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(forward(Box::new(stderr), LogStream::Stderr));
+    }
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(forward(Box::new(stdout), LogStream::Stdout));
+    }
+
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("Failed to wait on child") {
+            break ScriptEnd::Exited(status);
+        }
+        if cancellation.is_cancelled() {
+            process_tree.kill(&mut child);
+            break ScriptEnd::Cancelled;
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            process_tree.kill(&mut child);
+            break ScriptEnd::TimedOut;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    // the output of a script that exited is read to the end, but the readers of a killed script
+    // only get a moment to read the rest
+    let grace = match status {
+        ScriptEnd::Exited(_) => None,
+        ScriptEnd::Cancelled | ScriptEnd::TimedOut => Some(READER_GRACE_PERIOD),
+    };
+    join_readers(readers, grace);
+
+    let tail = log.lock().unwrap().tail.iter().join("\n");
+    match status {
+        ScriptEnd::Exited(status) if status.success() => Ok(()),
+        ScriptEnd::Exited(_) => Err(BuildScriptError::Failed {
+            log: log_path.to_path_buf(),
+            tail,
+        }
+        .into()),
+        ScriptEnd::Cancelled => Err(BuildScriptError::Cancelled.into()),
+        ScriptEnd::TimedOut => Err(BuildScriptError::TimedOut {
+            timeout: timeout.unwrap_or_default(),
+            elapsed: start.elapsed(),
+            log: log_path.to_path_buf(),
+            tail,
+        }
+        .into()),
+    }
+}
+
+/// How the build script process ended
+enum ScriptEnd {
+    Exited(ExitStatus),
+    TimedOut,
+    Cancelled,
+}
+
+/// How long the output of a killed build script is still read
+const READER_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Wait until the `readers` have read the output to the end, or at most for `grace`. The readers
+/// that are still blocked after that (because a process that escaped keeps a pipe open) are
+/// left behind.
+fn join_readers(readers: Vec<JoinHandle<()>>, grace: Option<Duration>) {
+    if let Some(grace) = grace {
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline && !readers.iter().all(JoinHandle::is_finished) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    for reader in readers {
+        if grace.is_none() || reader.is_finished() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// The build script and all processes that it started: the process group of the script on
+/// unix, and a job object that the script is assigned to on Windows
+struct ProcessTree {
+    #[cfg(windows)]
+    job: windows_sys::Win32::Foundation::HANDLE,
+}
+
+impl ProcessTree {
+    /// The processes of `child`, which must have been spawned in a process group of its own on
+    /// unix
+    #[cfg(unix)]
+    fn new(_child: &Child) -> Self {
+        Self {}
+    }
+
+    /// The processes of `child`, which must have been spawned suspended. It is assigned to a new
+    /// job object before it is resumed, so the processes that it starts are in the job as well.
+    #[cfg(windows)]
+    fn new(child: &Child) -> Self {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW};
+
+        // SAFETY: the job has no security attributes and no name, and the handle of the child
+        // is valid until it is waited for
+        let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+        if job != 0 && unsafe { AssignProcessToJobObject(job, child.as_raw_handle() as _) } == 0 {
+            tracing::warn!("Could not assign the build script to a job object");
+        }
+        resume_process(child.id());
+        Self { job }
+    }
+
+    /// Kill all processes of the tree, or only `child` if that fails
+    fn kill(&self, child: &mut Child) {
+        #[cfg(unix)]
+        // SAFETY: `killpg` has no memory safety requirements
+        let killed = unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } == 0;
+        #[cfg(windows)]
+        // SAFETY: the job handle is owned by this tree and not closed yet
+        let killed = self.job != 0
+            && unsafe { windows_sys::Win32::System::JobObjects::TerminateJobObject(self.job, 1) }
+                != 0;
+        if !killed {
+            tracing::warn!(
+                "Could not kill the processes of the build script, only killing the script"
+            );
+            let _ = child.kill();
+        }
+        let _ = child.wait();
+    }
+}
+
+/// Resume the threads of the suspended process `process_id`
+#[cfg(windows)]
+fn resume_process(process_id: u32) {
+    use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+    };
+    use windows_sys::Win32::System::Threading::{OpenThread, ResumeThread, THREAD_SUSPEND_RESUME};
+
+    let mut resumed = false;
+    // SAFETY: the entry has the size that the snapshot functions expect, and the snapshot and the
+    // thread handles are closed after use
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+        if snapshot != INVALID_HANDLE_VALUE {
+            let mut entry: THREADENTRY32 = std::mem::zeroed();
+            entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+            let mut next = Thread32First(snapshot, &mut entry) != 0;
+            while next {
+                if entry.th32OwnerProcessID == process_id {
+                    let thread = OpenThread(THREAD_SUSPEND_RESUME, 0, entry.th32ThreadID);
+                    if thread != 0 {
+                        resumed |= ResumeThread(thread) != u32::MAX;
+                        CloseHandle(thread);
+                    }
+                }
+                next = Thread32Next(snapshot, &mut entry) != 0;
+            }
+            CloseHandle(snapshot);
+        }
+    }
+    if !resumed {
+        tracing::warn!("Could not resume the threads of the build script");
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        if self.job != 0 {
+            // SAFETY: the job handle is owned by this tree
+            unsafe { windows_sys::Win32::Foundation::CloseHandle(self.job) };
+        }
+    }
+}
+
+/// The number of lines of the build log that are shown when the build script fails
+const LOG_TAIL_LINES: usize = 20;
+
+/// The log file of the build script and its last lines
+struct BuildLog {
+    file: File,
+    tail: VecDeque<String>,
+}
+
+impl BuildLog {
+    fn push(&mut self, line: &str) {
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            tracing::warn!("Could not write to the build log: {}", e);
+        }
+        if self.tail.len() == LOG_TAIL_LINES {
+            self.tail.pop_front();
+        }
+        self.tail.push_back(line.to_string());
+    }
+}
+
+/// Fetch the sources of the output into its work directory. Returns the commits that were
+/// checked out for the git sources, by the index of the source.
+async fn fetch_output_sources(
+    output: &Output,
+    tool_configuration: &tool_configuration::Configuration,
+    tools: &Tools,
+) -> miette::Result<HashMap<usize, String>> {
+    if output.recipe.sources().is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let directories = &output.build_configuration.directories;
+    let _group = LogGroup::start(tool_configuration.ci_log_style, BuildPhase::Fetch);
+    LogForwarder::phase_started(tool_configuration.log_sender.as_ref(), BuildPhase::Fetch);
+
+    // the sources that were fetched to render the recipe are only copied
+    if let Some(fetched) = tool_configuration
+        .fetched_sources
+        .as_deref()
+        .filter(|fetched| fetched.sources == output.recipe.sources())
+    {
+        tracing::info!("Copying the sources that were fetched to render the recipe");
+        CopyDir::new(&fetched.directory, &directories.work_dir)
+            .use_gitignore(false)
+            .run()?;
+        return Ok(fetched.commits.clone());
+    }
+
+    let commits = fetch_sources(
+        output.recipe.sources(),
+        &directories.work_dir,
+        &directories.recipe_dir,
+        &directories.source_cache,
+        tool_configuration,
+        tools,
+    )
+    .await?;
+    Ok(commits)
+}
+
+/// The result of [`run_build`]
+#[derive(Debug, Clone)]
+pub enum BuildOutcome {
+    /// The packages were built and written to these paths (one per package format, the one of
+    /// `package_format` first)
+    Built(Vec<PathBuf>),
+    /// A package with the same name, version and build string already exists at this URL (see
+    /// [`tool_configuration::Configuration::skip_existing`])
+    Skipped(url::Url),
+}
+
+/// Run the build for the given output. This will fetch the sources, resolve the dependencies,
+/// and execute the build script. Returns the paths to the resulting packages, or the location of
+/// the existing package if the build was skipped.
+///
+/// The build directory is removed after a successful build (unless `no_clean` is set), but
+/// never after a failed one, so that the work directory and the prefixes can be inspected.
+///
+/// If a container image is configured, only the sources are fetched here and the rest of the
+/// build runs in the container.
+pub async fn run_build(
+    output: &Output,
+    tool_configuration: tool_configuration::Configuration,
+) -> miette::Result<BuildOutcome> {
+    if let Some(existing) = existing_package(output, &tool_configuration).await? {
+        let pkgs_dir = rattler::default_cache_dir()
+            .expect("Could not get default cache dir")
+            .join("pkgs");
+        claim_existing_package_files(output, &existing, &pkgs_dir, &tool_configuration)?;
+        let build_dir = &output.build_configuration.directories.build_dir;
+        if !tool_configuration.no_clean && build_dir.exists() {
+            fs::remove_dir_all(build_dir).into_diagnostic()?;
+        }
+        return Ok(BuildOutcome::Skipped(existing.url));
+    }
+
+    let build_configuration = &output.build_configuration;
+    if output.recipe.build().native_only() && build_configuration.cross_compilation() {
+        return Err(BuildScriptError::NativeOnly {
+            name: output.name().as_normalized().to_string(),
+            build_platform: build_configuration.build_platform,
+            host_platform: build_configuration.host_platform,
+        }
+        .into());
+    }
+
+    if let Some(container) = &tool_configuration.container {
+        let tools = Tools::new(Some(&output.build_configuration.directories.build_prefix));
+        let start = Instant::now();
+        let git_commits = fetch_output_sources(output, &tool_configuration, &tools).await?;
+        let fetch = start.elapsed();
+        let packages = container::run_build(output, &tool_configuration, container)?;
+        if let Some(package) = packages.first() {
+            claim_package_files(output, package, &tool_configuration)?;
+        }
+
+        // the dependencies were resolved and the tests ran in the container, the rest of the
+        // build is reported as one phase
+        if let Some(report_path) = &tool_configuration.build_report {
+            let timings = PhaseTimings {
+                fetch: Some(fetch),
+                build: Some(start.elapsed() - fetch),
+                ..Default::default()
+            };
+            let tests = if tool_configuration.no_test {
+                TestStatus::Skipped
+            } else {
+                TestStatus::Unknown
+            };
+            let report = BuildReport::new(output, &packages, timings, tests, &git_commits)
+                .into_diagnostic()?;
+            tool_configuration
+                .build_reports
+                .add(report, report_path)
+                .into_diagnostic()?;
+        }
+        return Ok(BuildOutcome::Built(packages));
+    }
+
+    let result = build_output(output, tool_configuration.clone(), true).await;
+    if tool_configuration.cancellation.is_cancelled() {
+        let build_dir = &output.build_configuration.directories.build_dir;
+        if !tool_configuration.no_clean && build_dir.exists() {
+            tracing::info!("Removing the build directory {}", build_dir.display());
+            fs::remove_dir_all(build_dir).into_diagnostic()?;
+        }
+        return Err(BuildScriptError::Cancelled.into());
+    }
+    if result.is_err() {
+        let directories = &output.build_configuration.directories;
+        tracing::error!(
+            "The build failed, the build directory is kept: {}",
+            directories.build_dir.display()
+        );
+        if let Some(hint) = debug_hint(directories) {
+            tracing::info!("{}", hint);
+        }
+    }
+    result.map(BuildOutcome::Built)
+}
 
-        let full_script = format!("{}\n{}", preamble, script_content);
-        let build_script_path = directories.work_dir.join("conda_build.bat");
+/// The existing package with the name, version and build string of `output`, if the
+/// configuration skips existing packages
+async fn existing_package(
+    output: &Output,
+    tool_configuration: &tool_configuration::Configuration,
+) -> miette::Result<Option<RepoDataRecord>> {
+    if tool_configuration.skip_existing == SkipExisting::Off {
+        return Ok(None);
+    }
+    // the packages of the output directory may not be indexed yet
+    index::index(
+        &output.build_configuration.directories.output_dir,
+        Some(&output.build_configuration.target_platform),
+    )
+    .into_diagnostic()?;
 
-        let mut build_script_file = File::create(&build_script_path)?;
-        build_script_file.write_all(full_script.as_bytes())?;
-        Ok(build_script_path)
+    let query = ChannelQuery::from_tool_configuration(tool_configuration).into_diagnostic()?;
+    let existing = find_existing(output, tool_configuration.skip_existing, &query)
+        .await
+        .into_diagnostic()?;
+    if let Some(existing) = &existing {
+        tracing::info!(
+            "{} is already built, skipping it ({})",
+            output.identifier().unwrap_or_default(),
+            existing.url
+        );
     }
+    Ok(existing)
 }
 
-/// Spawns a process and replaces the given strings in the output with the given replacements.
-/// This is used to replace the host prefix with $PREFIX and the build prefix with $BUILD_PREFIX
-fn run_process_with_replacements(
-    command: &str,
-    cwd: &PathBuf,
-    args: &[OsString],
-    replacements: &[(&str, &str)],
+/// Claim the files of an output that was built in a container from the `paths.json` of its
+/// package, so that the other outputs cannot package them again
+fn claim_package_files(
+    output: &Output,
+    package: &Path,
+    tool_configuration: &tool_configuration::Configuration,
 ) -> miette::Result<()> {
-    let mut child = Command::new(command)
-        .current_dir(cwd)
-        .args(args)
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to execute command");
+    let paths_json = PackageInspector::open(package)
+        .and_then(|package| package.paths_json())
+        .into_diagnostic()?;
+    claim_paths(output, &paths_json, tool_configuration)
+}
 
-    if let Some(ref mut stdout) = child.stdout {
-        let reader = BufReader::new(stdout);
+/// Claim the files of the `paths_json` for `output`
+fn claim_paths(
+    output: &Output,
+    paths_json: &PathsJson,
+    tool_configuration: &tool_configuration::Configuration,
+) -> miette::Result<()> {
+    tool_configuration.claimed_files.claim(
+        output.name().as_normalized(),
+        paths_json
+            .paths
+            .iter()
+            .map(|entry| entry.relative_path.as_path()),
+    )?;
+    Ok(())
+}
 
-        // Process the output line by line
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                let filtered_line = replacements
-                    .iter()
-                    .fold(line, |acc, (from, to)| acc.replace(from, to));
-                tracing::info!("{}", filtered_line);
-            } else {
-                tracing::warn!("Error reading output: {:?}", line);
-            }
+/// Claim the files of a skipped output from its existing package. The files of a package in a
+/// remote channel are only known if it is extracted in the package cache `pkgs_dir`: skipping
+/// an output never downloads its package.
+fn claim_existing_package_files(
+    output: &Output,
+    existing: &RepoDataRecord,
+    pkgs_dir: &Path,
+    tool_configuration: &tool_configuration::Configuration,
+) -> miette::Result<()> {
+    if existing.url.scheme() == "file" {
+        if let Ok(path) = existing.url.to_file_path() {
+            return claim_package_files(output, &path, tool_configuration);
+        }
+    }
+
+    let cache_key: CacheKey = (&existing.package_record).into();
+    match PathsJson::from_package_directory(pkgs_dir.join(cache_key.to_string())) {
+        Ok(paths_json) => claim_paths(output, &paths_json, tool_configuration),
+        Err(_) => {
+            tracing::debug!(
+                "The files of {} are not claimed, the package is not in the package cache",
+                existing.url
+            );
+            Ok(())
         }
     }
+}
+
+/// A command that enters the build environment of a failed build, if its scripts were written
+fn debug_hint(directories: &Directories) -> Option<String> {
+    let work_dir = directories.work_dir.display();
+    if directories.work_dir.join("build_env.sh").exists() {
+        Some(format!(
+            "To debug, run:\n  cd \"{work_dir}\" && source build_env.sh"
+        ))
+    } else if directories.work_dir.join("build_env.bat").exists() {
+        Some(format!(
+            "To debug, run:\n  cd /d \"{work_dir}\" && call build_env.bat"
+        ))
+    } else {
+        None
+    }
+}
 
-    let status = child.wait().expect("Failed to wait on child");
+/// Run the build for an output whose sources are already in its work directory (e.g. inside of
+/// the build container). Returns the paths to the resulting packages.
+pub async fn run_build_with_fetched_sources(
+    output: &Output,
+    tool_configuration: tool_configuration::Configuration,
+) -> miette::Result<Vec<PathBuf>> {
+    build_output(output, tool_configuration, false).await
+}
 
-    if !status.success() {
-        return Err(miette::miette!("Build failed"));
+/// Run `future`, unless the build is cancelled first
+async fn cancellable<T>(
+    cancellation: &CancellationToken,
+    future: impl Future<Output = miette::Result<T>>,
+) -> miette::Result<T> {
+    tokio::select! {
+        result = future => result,
+        _ = cancellation.cancelled() => Err(BuildScriptError::Cancelled.into()),
     }
+}
 
+/// Fail if the build was cancelled, before the next step starts
+fn check_cancelled(cancellation: &CancellationToken) -> miette::Result<()> {
+    if cancellation.is_cancelled() {
+        return Err(BuildScriptError::Cancelled.into());
+    }
     Ok(())
 }
 
-/// Run the build for the given output. This will fetch the sources, resolve the dependencies,
-/// and execute the build script. Returns the path to the resulting package.
-pub async fn run_build(
+async fn build_output(
     output: &Output,
     tool_configuration: tool_configuration::Configuration,
-) -> miette::Result<PathBuf> {
+    fetch: bool,
+) -> miette::Result<Vec<PathBuf>> {
     let directories = &output.build_configuration.directories;
 
     index::index(
@@ -217,30 +1562,42 @@ pub async fn run_build(
     let mut channels = vec![directories.output_dir.to_string_lossy().to_string()];
     channels.extend(output.build_configuration.channels.clone());
 
-    if !output.recipe.sources().is_empty() {
-        fetch_sources(
-            output.recipe.sources(),
-            &directories.work_dir,
-            &directories.recipe_dir,
-            &directories.output_dir,
-        )
-        .await
-        .into_diagnostic()?;
+    let log_style = tool_configuration.ci_log_style;
+    let log_sender = tool_configuration.log_sender.as_ref();
+    let tools = Tools::new(Some(&directories.build_prefix));
+
+    let mut timings = PhaseTimings::default();
+    let mut git_commits = HashMap::new();
+    let source_fetch = output.recipe.build().source_fetch();
+    if fetch && source_fetch == SourceFetch::BeforeSolve {
+        let start = Instant::now();
+        git_commits = fetch_output_sources(output, &tool_configuration, &tools).await?;
+        PhaseTimings::add(&mut timings.fetch, start.elapsed());
     }
 
+    let solve_start = Instant::now();
     let output = if output.finalized_dependencies.is_some() {
         tracing::info!("Using finalized dependencies");
 
         // The output already has the finalized dependencies, so we can just use it as-is
-        install_environments(output, tool_configuration.clone())
-            .await
-            .into_diagnostic()?;
+        let _group = LogGroup::start(log_style, BuildPhase::EnvInstall);
+        LogForwarder::phase_started(log_sender, BuildPhase::EnvInstall);
+        cancellable(&tool_configuration.cancellation, async {
+            install_environments(output, tool_configuration.clone())
+                .await
+                .into_diagnostic()
+        })
+        .await?;
         output.clone()
     } else {
-        let finalized_dependencies =
+        let _group = LogGroup::start(log_style, BuildPhase::Solve);
+        LogForwarder::phase_started(log_sender, BuildPhase::Solve);
+        let finalized_dependencies = cancellable(&tool_configuration.cancellation, async {
             resolve_dependencies(output, &channels, tool_configuration.clone())
                 .await
-                .into_diagnostic()?;
+                .into_diagnostic()
+        })
+        .await?;
 
         // The output with the resolved dependencies
         Output {
@@ -250,42 +1607,76 @@ pub async fn run_build(
         }
     };
 
-    let build_script = get_conda_build_script(&output, directories).into_diagnostic()?;
+    PhaseTimings::add(&mut timings.solve, solve_start.elapsed());
+
+    if fetch && source_fetch == SourceFetch::AfterSolve {
+        let start = Instant::now();
+        git_commits = fetch_output_sources(&output, &tool_configuration, &tools).await?;
+        PhaseTimings::add(&mut timings.fetch, start.elapsed());
+    }
+
+    // a reused environment may have been modified by an earlier build script
+    check_prefix(
+        &directories.build_prefix,
+        &output.build_configuration.build_platform,
+        &tool_configuration,
+    )
+    .await?;
+    check_prefix(
+        &directories.host_prefix,
+        &output.build_configuration.host_platform,
+        &tool_configuration,
+    )
+    .await?;
+
+    check_cancelled(&tool_configuration.cancellation)?;
+    let build_script = get_conda_build_script(&output, directories, &tools)?;
     tracing::info!("Work dir: {:?}", &directories.work_dir);
-    tracing::info!("Build script: {:?}", build_script);
+    tracing::info!("Build script: {:?}", build_script.path);
 
     let files_before = record_files(&directories.host_prefix).expect("Could not record files");
+    let script_start = Instant::now();
 
-    let (interpreter, args) = if cfg!(unix) {
-        (
-            "/bin/bash",
-            vec![OsString::from("-e"), build_script.as_os_str().to_owned()],
-        )
-    } else {
-        (
-            "cmd.exe",
-            vec![
-                OsString::from("/d"),
-                OsString::from("/c"),
-                build_script.as_os_str().to_owned(),
-            ],
-        )
-    };
-    run_process_with_replacements(
-        interpreter,
+    let (interpreter, args) = build_script.command(&output.build_configuration.build_platform);
+    tracing::info!("Build script interpreter: {}", interpreter);
+    let script_group = LogGroup::start(log_style, BuildPhase::Script);
+    LogForwarder::phase_started(log_sender, BuildPhase::Script);
+    let network = NetworkGuard::start(output.recipe.build().network())?;
+    let (interpreter, args) = network.command(&interpreter, &args);
+    let secrets = script_secrets(&output);
+    let env = network
+        .env()
+        .into_iter()
+        .chain(secrets.iter().cloned())
+        .collect::<Vec<_>>();
+    let host_prefix = directories.host_prefix.to_string_lossy();
+    let build_prefix = directories.build_prefix.to_string_lossy();
+    // the secrets are masked first, before they could be changed by another replacement
+    let replacements = secrets
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(_, value)| (value.as_str(), "***"))
+        .chain([
+            (host_prefix.as_ref(), "$PREFIX"),
+            (build_prefix.as_ref(), "$BUILD_PREFIX"),
+        ])
+        .collect::<Vec<_>>();
+    let result = run_process_with_replacements(
+        &interpreter,
         &directories.work_dir,
         &args,
-        &[
-            (
-                directories.host_prefix.to_string_lossy().as_ref(),
-                "$PREFIX",
-            ),
-            (
-                directories.build_prefix.to_string_lossy().as_ref(),
-                "$BUILD_PREFIX",
-            ),
-        ],
-    )?;
+        &env,
+        &replacements,
+        log_sender,
+        &directories.build_log(),
+        tool_configuration.build_timeout,
+        &tool_configuration.cancellation,
+    );
+    // the requests of the script can explain why it failed
+    network.finish()?;
+    result?;
+    drop(script_group);
+    PhaseTimings::add(&mut timings.build, script_start.elapsed());
 
     let files_after = record_files(&directories.host_prefix).expect("Could not record files");
 
@@ -293,48 +1684,79 @@ pub async fn run_build(
         .difference(&files_before)
         .cloned()
         .collect::<HashSet<_>>();
+    let difference = select_files(
+        &difference,
+        &directories.host_prefix,
+        output.recipe.build().files(),
+    )?;
+    tool_configuration.claimed_files.claim(
+        output.name().as_normalized(),
+        difference
+            .iter()
+            .filter_map(|file| file.strip_prefix(&directories.host_prefix).ok()),
+    )?;
 
-    let (result, paths_json) = package_conda(
+    let package_start = Instant::now();
+    let package_group = LogGroup::start(log_style, BuildPhase::Package);
+    LogForwarder::phase_started(log_sender, BuildPhase::Package);
+    let (packages, paths_json) = package_conda(
         &output,
         &difference,
         &directories.host_prefix,
         &directories.output_dir,
-        output.build_configuration.package_format,
-    )
-    .into_diagnostic()?;
+        &output.build_configuration.package_formats(),
+        &tools,
+    )?;
+    // the other formats contain the same files, so only the first package is tested
+    let result = &packages[0];
 
-    if let Some(package_content) = output.recipe.test().package_content() {
-        test::run_package_content_tests(
+    let package_content_tests = match output.recipe.test().package_content() {
+        Some(package_content) => test::run_package_content_tests(
             package_content,
             paths_json,
             &output.build_configuration.target_platform,
+            result,
         )
         .await
-        .into_diagnostic()?;
-    }
+        .into_diagnostic(),
+        None => Ok(()),
+    };
+    drop(package_group);
+    PhaseTimings::add(&mut timings.package, package_start.elapsed());
 
-    if !tool_configuration.no_clean {
-        fs::remove_dir_all(&directories.build_dir).into_diagnostic()?;
-    }
+    // a package that was written while the build was cancelled is not added to the index
+    check_cancelled(&tool_configuration.cancellation)?;
 
+    // the package is in the output directory now, so the index is updated even if its tests fail
     index::index(
         &directories.output_dir,
         Some(&output.build_configuration.target_platform),
     )
     .into_diagnostic()?;
+    package_content_tests?;
+
+    if tool_configuration.write_lock_file {
+        if let Some(lock_file) = write_lock_file(&output, result)? {
+            tracing::info!("Wrote the lock file {}", lock_file.display());
+        }
+    }
 
     let test_dir = directories.work_dir.join("test");
     fs::create_dir_all(&test_dir).into_diagnostic()?;
 
     tracing::info!("{}", output);
 
-    if tool_configuration.no_test {
+    let tests = if tool_configuration.no_test {
         tracing::info!("Skipping tests");
+        TestStatus::Skipped
     } else {
+        let test_start = Instant::now();
+        let _group = LogGroup::start(log_style, BuildPhase::Test);
+        LogForwarder::phase_started(log_sender, BuildPhase::Test);
         tracing::info!("Running tests");
 
         test::run_test(
-            &result,
+            result,
             &TestConfiguration {
                 test_prefix: test_dir.clone(),
                 target_platform: Some(output.build_configuration.target_platform),
@@ -344,11 +1766,874 @@ pub async fn run_build(
         )
         .await
         .into_diagnostic()?;
+        PhaseTimings::add(&mut timings.test, test_start.elapsed());
+        TestStatus::Passed
+    };
+
+    if let Some(report_path) = &tool_configuration.build_report {
+        let report =
+            BuildReport::new(&output, &packages, timings, tests, &git_commits).into_diagnostic()?;
+        tool_configuration
+            .build_reports
+            .add(report, report_path)
+            .into_diagnostic()?;
+        tracing::info!("Wrote the build report {}", report_path.display());
     }
 
     if !tool_configuration.no_clean {
         fs::remove_dir_all(&directories.build_dir).into_diagnostic()?;
     }
 
-    Ok(result)
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::str::FromStr;
+
+    use rattler::package_cache::CacheKey;
+    use rattler_conda_types::{
+        NoArchType, PackageName, PackageRecord, Platform, RepoDataRecord, VersionWithSource,
+    };
+    use rstest::rstest;
+
+    use super::{
+        claim_existing_package_files, find_bash, get_conda_build_script, powershell_exit_checks,
+        read_inputs_hash, run_build, select_script_file, BuildScriptError, BuildScriptFiles,
+        ScriptFlavor, ScriptInterpreter, Shebang, POWERSHELL_EXIT_CHECK,
+    };
+    use crate::metadata::{Directories, Output};
+    use crate::recipe::parser::{Script, ScriptContent};
+    use crate::render::resolved_dependencies::DependencyInfo;
+    use crate::tool_configuration::Configuration;
+    use crate::tools::Tools;
+
+    #[rstest]
+    #[case(Platform::Linux64, Platform::Linux64, ScriptFlavor::Bash, "/bin/bash")]
+    #[case(Platform::Linux64, Platform::Win64, ScriptFlavor::CmdExe, "wine")]
+    #[case(Platform::Win64, Platform::Linux64, ScriptFlavor::Bash, "bash")]
+    #[case(Platform::Win64, Platform::Win64, ScriptFlavor::CmdExe, "cmd.exe")]
+    fn test_script_flavor(
+        #[case] build_platform: Platform,
+        #[case] target_platform: Platform,
+        #[case] expected_flavor: ScriptFlavor,
+        #[case] expected_interpreter: &str,
+    ) {
+        let flavor = ScriptFlavor::from_platforms(&target_platform, &build_platform);
+        assert_eq!(flavor, expected_flavor);
+
+        let script_path = Path::new("work").join(format!("conda_build.{}", flavor.extension()));
+        let (interpreter, args) = flavor.interpreter(&build_platform, &script_path);
+        assert_eq!(interpreter, expected_interpreter);
+        assert_eq!(args.last(), Some(&OsString::from(script_path.as_os_str())));
+
+        let env_script = Path::new("work").join(format!("build_env.{}", flavor.extension()));
+        let script = flavor.render_script(&env_script, "echo hello");
+        assert!(script.ends_with("\necho hello"));
+        match flavor {
+            ScriptFlavor::Bash => {
+                assert_eq!(flavor.extension(), "sh");
+                assert!(script.contains(&format!("source {}", env_script.display())));
+                assert!(script.contains("set -x"));
+            }
+            ScriptFlavor::CmdExe => {
+                assert_eq!(flavor.extension(), "bat");
+                assert!(script.contains(&format!("call {}", env_script.display())));
+                assert!(!script.contains("set -x"));
+            }
+            ScriptFlavor::PowerShell => unreachable!("no platform has PowerShell scripts"),
+        }
+    }
+
+    #[test]
+    fn test_powershell_flavor() {
+        let flavor = ScriptFlavor::PowerShell;
+        assert_eq!(ScriptFlavor::from_extension("ps1"), Some(flavor));
+        assert_eq!(
+            ScriptInterpreter::from_name("pwsh").map(|i| i.flavor(ScriptFlavor::CmdExe)),
+            Some(flavor)
+        );
+
+        let env_script = Path::new("work/build_env.ps1");
+        let script = flavor.render_script(env_script, "cmake --build .");
+        assert!(script.contains(". \"work/build_env.ps1\""));
+        assert!(script.contains("$ErrorActionPreference = \"Stop\""));
+
+        let script_path = Path::new("work/conda_build.ps1");
+        let (program, args) = flavor.interpreter(&Platform::Win64, script_path);
+        assert_eq!(program, "powershell.exe");
+        assert_eq!(args.last(), Some(&OsString::from("work/conda_build.ps1")));
+        assert_eq!(
+            flavor.interpreter(&Platform::Linux64, script_path).0,
+            "pwsh"
+        );
+    }
+
+    #[test]
+    fn test_powershell_exit_checks() {
+        let script = "cmake -G Ninja `\n  ..\nif ($env:CI) {\n  ninja\n}\nelse {\n  make\n}\n# done\nninja install\n";
+        assert_eq!(
+            powershell_exit_checks(script),
+            format!(
+                "cmake -G Ninja `\n  ..\n{check}\nif ($env:CI) {{\n  ninja\n}}\nelse {{\n  make\n}}\n{check}\n# done\nninja install\n{check}\n",
+                check = POWERSHELL_EXIT_CHECK
+            )
+        );
+
+        // a failing program stops the script, also with Windows PowerShell
+        let tmp = tempfile::tempdir().unwrap();
+        let flavor = ScriptFlavor::PowerShell;
+        let (program, args) =
+            flavor.interpreter(&Platform::current(), &tmp.path().join("build.ps1"));
+        if which::which(&program).is_err() {
+            return;
+        }
+        let env_script = tmp.path().join("build_env.ps1");
+        fs_err::write(&env_script, "").unwrap();
+        let content =
+            format!("& \"{program}\" -NoProfile -Command \"exit 3\"\nWrite-Output after\n");
+        fs_err::write(
+            tmp.path().join("build.ps1"),
+            flavor.render_script(&env_script, &content),
+        )
+        .unwrap();
+        let output = std::process::Command::new(&program)
+            .args(&args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("after"));
+    }
+
+    #[test]
+    fn test_script_file_fallbacks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stem = tmp.path().join("build");
+        let fallbacks = [ScriptFlavor::PowerShell, ScriptFlavor::Bash];
+        let select = || select_script_file(&stem, "bat", ScriptFlavor::CmdExe, &fallbacks);
+
+        // without any script, the missing native script is read (and the script is empty)
+        assert_eq!(select(), (stem.with_extension("bat"), ScriptFlavor::CmdExe));
+
+        fs_err::write(stem.with_extension("sh"), "make install").unwrap();
+        assert_eq!(select(), (stem.with_extension("sh"), ScriptFlavor::Bash));
+        fs_err::write(stem.with_extension("ps1"), "make install").unwrap();
+        assert_eq!(
+            select(),
+            (stem.with_extension("ps1"), ScriptFlavor::PowerShell)
+        );
+        fs_err::write(stem.with_extension("bat"), "make install").unwrap();
+        assert_eq!(select(), (stem.with_extension("bat"), ScriptFlavor::CmdExe));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_bash() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let executable = |dir: &Path| {
+            fs_err::create_dir_all(dir).unwrap();
+            let path = dir.join("bash");
+            fs_err::write(&path, "").unwrap();
+            fs_err::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        let prefix = tmp.path().join("build_env");
+        let wsl = executable(&tmp.path().join("Windows\\System32"));
+
+        // the bash of WSL is skipped
+        let tools = Tools::with_path(Some(&prefix), Some(wsl.parent().unwrap().into()));
+        let err = find_bash(&tools).unwrap_err();
+        assert_eq!(
+            miette::Diagnostic::help(&err).unwrap().to_string(),
+            "add `m2-bash` to the build requirements or install it on your system"
+        );
+
+        // the bash of the build prefix (`m2-bash`) is found first
+        let bash = executable(&prefix.join("bin"));
+        let tools = Tools::with_path(Some(&prefix), Some(wsl.parent().unwrap().into()));
+        assert_eq!(find_bash(&tools).unwrap(), bash);
+    }
+
+    #[test]
+    fn test_noarch_uses_build_platform() {
+        assert_eq!(
+            ScriptFlavor::from_platforms(&Platform::NoArch, &Platform::Win64),
+            ScriptFlavor::CmdExe
+        );
+        assert_eq!(
+            ScriptFlavor::from_platforms(&Platform::NoArch, &Platform::OsxArm64),
+            ScriptFlavor::Bash
+        );
+    }
+
+    #[test]
+    fn test_shebang_parse() {
+        let shebang = Shebang::parse("#!/usr/bin/env python -u\nprint('hi')").unwrap();
+        assert_eq!(shebang.program, "python");
+        assert_eq!(shebang.args, vec!["-u".to_string()]);
+        assert_eq!(shebang.extension(), "py");
+
+        let shebang = Shebang::parse("#!/bin/bash\necho hi").unwrap();
+        assert_eq!(shebang.program, "/bin/bash");
+        assert!(shebang.is_shell());
+
+        assert_eq!(Shebang::parse("echo hi"), None);
+        assert_eq!(Shebang::parse("#!/usr/bin/env"), None);
+    }
+
+    #[test]
+    fn test_shebang_python() {
+        let work_dir = Path::new("work");
+        let env_script = work_dir.join("build_env.sh");
+        let content = "#!/usr/bin/env python\nprint('hello')";
+
+        let files = BuildScriptFiles::new(ScriptFlavor::Bash, work_dir, &env_script, content, true);
+        let (interpreted_path, interpreted) = files.interpreted.unwrap();
+        assert_eq!(interpreted_path, work_dir.join("conda_build_script.py"));
+        // the script is written verbatim, including the shebang
+        assert_eq!(interpreted, content);
+
+        let (main_path, main) = files.main;
+        assert_eq!(main_path, work_dir.join("conda_build.sh"));
+        assert!(main.contains(&format!("source {}", env_script.display())));
+        assert!(main.ends_with(&format!("python \"{}\"", interpreted_path.display())));
+
+        // a windows build runs the interpreter from the batch script
+        let env_script = work_dir.join("build_env.bat");
+        let files =
+            BuildScriptFiles::new(ScriptFlavor::CmdExe, work_dir, &env_script, content, true);
+        let (_, main) = files.main;
+        assert!(main.contains("python \""));
+        assert!(main.ends_with("IF %ERRORLEVEL% NEQ 0 exit 1"));
+    }
+
+    #[test]
+    fn test_shebang_bash() {
+        let work_dir = Path::new("work");
+        let env_script = work_dir.join("build_env.sh");
+        let content = "#!/bin/bash\necho hello";
+
+        let files = BuildScriptFiles::new(ScriptFlavor::Bash, work_dir, &env_script, content, true);
+        assert!(files.interpreted.is_none());
+        let (_, main) = files.main;
+        assert!(main.starts_with("#!/bin/bash\n"));
+        assert!(main.contains(&format!("source {}", env_script.display())));
+        assert!(main.ends_with("\necho hello"));
+        assert_eq!(main.matches("#!/bin/bash").count(), 1);
+    }
+
+    #[test]
+    fn test_interpreter_names() {
+        assert_eq!(
+            ScriptInterpreter::from_name("python"),
+            Some(ScriptInterpreter::Python)
+        );
+        assert_eq!(
+            ScriptInterpreter::from_name("nu"),
+            Some(ScriptInterpreter::Nushell)
+        );
+        assert_eq!(ScriptInterpreter::from_name("perl"), None);
+
+        // bash and cmd replace the flavor of the target platform
+        assert_eq!(
+            ScriptInterpreter::Bash.flavor(ScriptFlavor::CmdExe),
+            ScriptFlavor::Bash
+        );
+        assert_eq!(
+            ScriptInterpreter::Cmd.flavor(ScriptFlavor::Bash),
+            ScriptFlavor::CmdExe
+        );
+        assert_eq!(
+            ScriptInterpreter::Python.flavor(ScriptFlavor::CmdExe),
+            ScriptFlavor::CmdExe
+        );
+    }
+
+    #[test]
+    fn test_interpreter_executable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path();
+        fs_err::create_dir_all(prefix.join("bin")).unwrap();
+        fs_err::write(prefix.join("bin/python"), "").unwrap();
+        fs_err::write(prefix.join("python.exe"), "").unwrap();
+
+        let executable =
+            |interpreter: ScriptInterpreter, platform| interpreter.executable(prefix, &platform);
+        assert_eq!(
+            executable(ScriptInterpreter::Python, Platform::Linux64).unwrap(),
+            Some(prefix.join("bin/python"))
+        );
+        assert_eq!(
+            executable(ScriptInterpreter::Python, Platform::Win64).unwrap(),
+            Some(prefix.join("python.exe"))
+        );
+        assert_eq!(
+            executable(ScriptInterpreter::Bash, Platform::Linux64).unwrap(),
+            None
+        );
+
+        let err = executable(ScriptInterpreter::Nushell, Platform::Linux64).unwrap_err();
+        assert!(matches!(
+            &err,
+            BuildScriptError::InterpreterNotFound { package, .. } if package == "nushell"
+        ));
+        assert!(err
+            .to_string()
+            .contains("`nu` was not found in the build prefix"));
+    }
+
+    #[test]
+    fn test_python_interpreter() {
+        let work_dir = Path::new("work");
+        let env_script = work_dir.join("build_env.sh");
+        let python = Path::new("build_env").join("bin/python");
+        let content = "print('hello')";
+
+        let files = BuildScriptFiles::with_interpreter(
+            ScriptFlavor::Bash,
+            work_dir,
+            &env_script,
+            content,
+            &python,
+            "py",
+        );
+        let (interpreted_path, interpreted) = files.interpreted.unwrap();
+        assert_eq!(interpreted_path, work_dir.join("conda_build.py"));
+        assert_eq!(interpreted, content);
+
+        // the main script sets up the environment and runs the python of the build prefix
+        let (main_path, main) = files.main;
+        assert_eq!(main_path, work_dir.join("conda_build.sh"));
+        assert!(main.contains(&format!("source {}", env_script.display())));
+        assert!(main.ends_with(&format!(
+            "\"{}\" \"{}\"",
+            python.display(),
+            interpreted_path.display()
+        )));
+    }
+
+    #[test]
+    fn test_inputs_header() {
+        let script = "#!/bin/bash\necho hello";
+        let with_header = ScriptFlavor::Bash.with_inputs_header(script, "abc");
+        assert_eq!(
+            with_header,
+            "#!/bin/bash\n# rattler-build inputs: abc\necho hello"
+        );
+        assert_eq!(
+            ScriptFlavor::CmdExe.with_inputs_header("echo hello", "abc"),
+            ":: rattler-build inputs: abc\necho hello"
+        );
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("conda_build.sh");
+        fs_err::write(&path, with_header).unwrap();
+        assert_eq!(read_inputs_hash(&path), Some("abc".to_string()));
+        fs_err::write(&path, script).unwrap();
+        assert_eq!(read_inputs_hash(&path), None);
+    }
+
+    /// The rendered rich recipe, with its directories in `tmp`
+    fn test_output(tmp: &Path) -> (Output, Directories) {
+        let recipe = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/rendered_recipes/rich_recipe.yaml");
+        let mut output: Output =
+            serde_yaml::from_str(&fs_err::read_to_string(recipe).unwrap()).unwrap();
+        let directories = Directories {
+            recipe_dir: tmp.join("recipe"),
+            host_prefix: tmp.join("host_env"),
+            build_prefix: tmp.join("build_env"),
+            work_dir: tmp.join("work"),
+            build_dir: tmp.to_path_buf(),
+            output_dir: tmp.join("output"),
+            source_cache: tmp.join("src_cache"),
+        };
+        for dir in [
+            &directories.recipe_dir,
+            &directories.host_prefix,
+            &directories.build_prefix,
+            &directories.work_dir,
+        ] {
+            fs_err::create_dir_all(dir).unwrap();
+        }
+        output.build_configuration.directories = directories.clone();
+        (output, directories)
+    }
+
+    #[test]
+    fn test_claim_remote_package_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (output, _) = test_output(tmp.path());
+        let file_name = "rich-13.4.2-pyh4616a5c_0.conda".to_string();
+        let record = RepoDataRecord {
+            package_record: PackageRecord {
+                arch: None,
+                build: "pyh4616a5c_0".into(),
+                build_number: 0,
+                constrains: vec![],
+                depends: vec![],
+                features: None,
+                legacy_bz2_md5: None,
+                legacy_bz2_size: None,
+                license: None,
+                license_family: None,
+                md5: None,
+                name: PackageName::from_str("rich").unwrap(),
+                noarch: NoArchType::python(),
+                platform: None,
+                sha256: None,
+                size: None,
+                subdir: "noarch".into(),
+                timestamp: None,
+                track_features: vec![],
+                version: VersionWithSource::from_str("13.4.2").unwrap(),
+                purls: Default::default(),
+            },
+            // nothing listens on port 1, a download would fail
+            url: format!("http://127.0.0.1:1/noarch/{file_name}")
+                .parse()
+                .unwrap(),
+            channel: "http://127.0.0.1:1/".to_string(),
+            file_name,
+        };
+        let pkgs_dir = tmp.path().join("pkgs");
+        let file = Path::new("site-packages/rich/__init__.py");
+
+        // the package is not in the package cache, so its files are not claimed
+        let configuration = Configuration::default();
+        claim_existing_package_files(&output, &record, &pkgs_dir, &configuration).unwrap();
+        configuration.claimed_files.claim("other", [file]).unwrap();
+
+        // the files of a package in the package cache are claimed
+        let cache_key: CacheKey = (&record.package_record).into();
+        let info = pkgs_dir.join(cache_key.to_string()).join("info");
+        fs_err::create_dir_all(&info).unwrap();
+        fs_err::write(
+            info.join("paths.json"),
+            r#"{"paths_version": 1, "paths": [{"_path": "site-packages/rich/__init__.py", "path_type": "hardlink", "sha256": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", "size_in_bytes": 0}]}"#,
+        )
+        .unwrap();
+        let configuration = Configuration::default();
+        claim_existing_package_files(&output, &record, &pkgs_dir, &configuration).unwrap();
+        assert!(configuration.claimed_files.claim("other", [file]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_native_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (output, directories) = test_output(tmp.path());
+        let mut value = serde_yaml::to_value(&output).unwrap();
+        value["recipe"]["build"]["native_only"] = true.into();
+        value["build_configuration"]["build_platform"] = "linux-64".into();
+        value["build_configuration"]["host_platform"] = "linux-aarch64".into();
+        let mut output: Output = serde_yaml::from_value(value).unwrap();
+        output.build_configuration.directories = directories;
+        assert!(output.recipe.build().native_only());
+
+        let err = run_build(&output, Configuration::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "rich can not be cross-compiled for linux-aarch64 on linux-64"
+        );
+        // nothing was fetched or built
+        assert!(
+            fs_err::read_dir(&output.build_configuration.directories.work_dir)
+                .unwrap()
+                .next()
+                .is_none()
+        );
+    }
+
+    #[rstest]
+    #[case(Platform::NoArch, Platform::Linux64, ScriptFlavor::Bash)]
+    #[case(Platform::NoArch, Platform::Win64, ScriptFlavor::CmdExe)]
+    #[case(Platform::Linux64, Platform::Linux64, ScriptFlavor::Bash)]
+    #[case(Platform::Linux64, Platform::Win64, ScriptFlavor::Bash)]
+    #[case(Platform::Win64, Platform::Linux64, ScriptFlavor::CmdExe)]
+    #[case(Platform::Win64, Platform::Win64, ScriptFlavor::CmdExe)]
+    fn test_build_script_of_platforms(
+        #[case] target_platform: Platform,
+        #[case] build_platform: Platform,
+        #[case] expected_flavor: ScriptFlavor,
+    ) {
+        let tmp = tempfile::tempdir().unwrap();
+        let (output, directories) = test_output(tmp.path());
+        let host_platform = match target_platform {
+            Platform::NoArch => build_platform,
+            platform => platform,
+        };
+        let mut value = serde_yaml::to_value(&output).unwrap();
+        value["build_configuration"]["target_platform"] = target_platform.to_string().into();
+        value["build_configuration"]["host_platform"] = host_platform.to_string().into();
+        value["build_configuration"]["build_platform"] = build_platform.to_string().into();
+        let mut output: Output = serde_yaml::from_value(value).unwrap();
+        output.build_configuration.directories = directories.clone();
+
+        let script = get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        assert_eq!(script.flavor, expected_flavor);
+        assert_eq!(
+            script.path.extension().unwrap(),
+            expected_flavor.extension()
+        );
+
+        // the variables of the environment script are the ones of the script flavor
+        let env_script = fs_err::read_to_string(
+            directories
+                .work_dir
+                .join(format!("build_env.{}", expected_flavor.extension())),
+        )
+        .unwrap();
+        let windows_vars = expected_flavor == ScriptFlavor::CmdExe;
+        assert_eq!(env_script.contains("LIBRARY_PREFIX"), windows_vars);
+        assert_eq!(env_script.contains("PKG_CONFIG_PATH"), !windows_vars);
+    }
+
+    #[test]
+    fn test_stale_build_scripts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut output, directories) = test_output(tmp.path());
+
+        let script = get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        let env_script = directories
+            .work_dir
+            .join(format!("build_env.{}", script.flavor.extension()));
+        let hash = read_inputs_hash(&env_script).unwrap();
+        assert_eq!(read_inputs_hash(&script.path), Some(hash.clone()));
+
+        // the scripts of a run with the same inputs are kept
+        let edited = format!(
+            "{}\necho debugging",
+            fs_err::read_to_string(&script.path).unwrap()
+        );
+        fs_err::write(&script.path, &edited).unwrap();
+        get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        assert_eq!(fs_err::read_to_string(&script.path).unwrap(), edited);
+
+        // a changed dependency pin writes them again
+        output.finalized_dependencies.as_mut().unwrap().run.depends[0] = DependencyInfo::Raw {
+            spec: rattler_conda_types::MatchSpec::from_str("python >=3.12").unwrap(),
+        };
+        get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        assert!(!fs_err::read_to_string(&script.path)
+            .unwrap()
+            .contains("echo debugging"));
+        let new_hash = read_inputs_hash(&env_script).unwrap();
+        assert_ne!(new_hash, hash);
+        assert_eq!(read_inputs_hash(&script.path), Some(new_hash));
+    }
+
+    #[test]
+    fn test_render_build_script() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut output, directories) = test_output(tmp.path());
+        let script: Script =
+            serde_yaml::from_str("interpreter: python\ncontent: print('hello')").unwrap();
+        output.recipe.set_build_script(script);
+
+        // python is not installed in the build prefix, but the script can be rendered
+        let rendered = render_build_script(&output).unwrap();
+        assert!(rendered.contains("print('hello')"));
+        assert!(!rendered.contains(INPUTS_HEADER));
+        assert!(get_conda_build_script(
+            &output,
+            &output.build_configuration.directories,
+            &Tools::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_stale_passthrough_variables() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut output, directories) = test_output(tmp.path());
+        // only this test uses the variable
+        let name = "RATTLER_BUILD_TEST_STALE_PASSTHROUGH";
+        let script: Script = serde_yaml::from_str(&format!("env: [{name}]")).unwrap();
+        output.recipe.set_build_script(script);
+
+        std::env::set_var(name, "1");
+        let script = get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        let env_script = directories
+            .work_dir
+            .join(format!("build_env.{}", script.flavor.extension()));
+        let hash = read_inputs_hash(&env_script).unwrap();
+
+        std::env::set_var(name, "2");
+        get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        assert_ne!(read_inputs_hash(&env_script).unwrap(), hash);
+        std::env::remove_var(name);
+    }
+
+    #[test]
+    fn test_shebang_of_script_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut output, directories) = test_output(tmp.path());
+        let content = "#!/usr/bin/env python\nprint('hello')\n";
+        fs_err::write(directories.recipe_dir.join("build.sh"), content).unwrap();
+        let interpreted = directories.work_dir.join("conda_build_script.py");
+
+        // a script file of the recipe runs as a script of its flavor
+        output
+            .recipe
+            .set_build_script(ScriptContent::CommandOrPath("build.sh".to_string()));
+        let script = get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        assert_eq!(script.flavor, ScriptFlavor::Bash);
+        assert!(fs_err::read_to_string(&script.path)
+            .unwrap()
+            .contains("print('hello')"));
+        assert!(!interpreted.exists());
+
+        // a script in the recipe runs with the interpreter of its shebang
+        output
+            .recipe
+            .set_build_script(ScriptContent::CommandOrPath(content.to_string()));
+        get_conda_build_script(&output, &directories, &Tools::default()).unwrap();
+        assert_eq!(fs_err::read_to_string(&interpreted).unwrap(), content);
+    }
+
+    #[test]
+    fn test_no_shebang() {
+        let work_dir = Path::new("work");
+        let env_script = work_dir.join("build_env.sh");
+        let content = "echo hello\necho world";
+
+        let files = BuildScriptFiles::new(ScriptFlavor::Bash, work_dir, &env_script, content, true);
+        assert!(files.interpreted.is_none());
+        let (main_path, main) = files.main;
+        assert_eq!(main_path, work_dir.join("conda_build.sh"));
+        assert_eq!(main, ScriptFlavor::Bash.render_script(&env_script, content));
+
+        // scripts read from a file are never dispatched on their shebang
+        let content = "#!/usr/bin/env python\nprint('hello')";
+        let files =
+            BuildScriptFiles::new(ScriptFlavor::Bash, work_dir, &env_script, content, false);
+        assert!(files.interpreted.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stream_script_output() {
+        use crate::log_stream::LogStream;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path().join("prefix");
+        super::run_process_with_replacements(
+            "/bin/sh",
+            &tmp.path().to_path_buf(),
+            &[
+                OsString::from("-c"),
+                OsString::from(format!(
+                    "echo one; echo two {}; echo error >&2",
+                    prefix.display()
+                )),
+            ],
+            &[],
+            &[(prefix.to_string_lossy().as_ref(), "$PREFIX")],
+            Some(&sender),
+            &tmp.path().join("build.log"),
+            None,
+            &Default::default(),
+        )
+        .unwrap();
+        drop(sender);
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        while let Ok(line) = receiver.try_recv() {
+            match line.stream {
+                LogStream::Stdout => stdout.push(line.line),
+                LogStream::Stderr => stderr.push(line.line),
+                LogStream::Message => panic!("no lines were dropped"),
+            }
+        }
+        assert_eq!(stdout, vec!["one", "two $PREFIX"]);
+        assert_eq!(stderr, vec!["error"]);
+
+        // both streams end up in the log, with the replacements
+        let log = fs_err::read_to_string(tmp.path().join("build.log")).unwrap();
+        let mut lines = log.lines().collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(lines, vec!["error", "one", "two $PREFIX"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_script_shows_log_tail() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log_path = tmp.path().join("build.log");
+        let err = super::run_process_with_replacements(
+            "/bin/sh",
+            &tmp.path().to_path_buf(),
+            &[
+                OsString::from("-c"),
+                OsString::from("for i in $(seq 1 30); do echo line $i; done; exit 1"),
+            ],
+            &[],
+            &[],
+            None,
+            &log_path,
+            None,
+            &Default::default(),
+        )
+        .unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("line 30"));
+        assert!(message.contains("line 11"));
+        assert!(!message.contains("line 10\n"));
+        assert_eq!(
+            fs_err::read_to_string(&log_path).unwrap().lines().count(),
+            30
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn build_timeout() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join("marker");
+        let start = std::time::Instant::now();
+        let err = super::run_process_with_replacements(
+            "/bin/sh",
+            &tmp.path().to_path_buf(),
+            &[
+                OsString::from("-c"),
+                OsString::from(format!(
+                    "echo started; (sleep 2; touch {}) & sleep 30",
+                    marker.display()
+                )),
+            ],
+            &[],
+            &[],
+            Some(&sender),
+            &tmp.path().join("build.log"),
+            Some(std::time::Duration::from_millis(500)),
+            &Default::default(),
+        )
+        .unwrap_err();
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert!(err.to_string().contains("did not finish within 500ms"));
+        assert!(err.to_string().contains("started"));
+
+        // the output up to the kill was streamed
+        drop(sender);
+        assert_eq!(receiver.try_recv().unwrap().line, "started");
+
+        // the background process of the script was killed as well
+        std::thread::sleep(std::time::Duration::from_secs(3));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn debug_hint_for_failed_builds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let directories = crate::metadata::Directories {
+            recipe_dir: tmp.path().join("recipe"),
+            host_prefix: tmp.path().join("host_env"),
+            build_prefix: tmp.path().join("build_env"),
+            work_dir: tmp.path().join("work"),
+            build_dir: tmp.path().to_path_buf(),
+            output_dir: tmp.path().join("output"),
+            source_cache: tmp.path().join("src_cache"),
+        };
+        fs_err::create_dir_all(&directories.work_dir).unwrap();
+        // the build failed before the scripts were written
+        assert_eq!(super::debug_hint(&directories), None);
+
+        fs_err::write(directories.work_dir.join("build_env.sh"), "").unwrap();
+        let hint = super::debug_hint(&directories).unwrap();
+        assert!(hint.contains(&format!("cd \"{}\"", directories.work_dir.display())));
+        assert!(hint.ends_with("source build_env.sh"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancel_build_script() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cancellation = tokio_util::sync::CancellationToken::new();
+        let start = std::time::Instant::now();
+        let err = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                cancellation.cancel();
+            });
+            super::run_process_with_replacements(
+                "/bin/sh",
+                &tmp.path().to_path_buf(),
+                &[OsString::from("-c"), OsString::from("sleep 30")],
+                &[],
+                &[],
+                None,
+                &tmp.path().join("build.log"),
+                None,
+                &cancellation,
+            )
+            .unwrap_err()
+        });
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(err.to_string(), "The build was cancelled");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn escaped_processes_do_not_block_a_timeout() {
+        // `setsid` moves the process out of the process group, and it keeps stdout open
+        let tmp = tempfile::tempdir().unwrap();
+        let start = std::time::Instant::now();
+        let err = super::run_process_with_replacements(
+            "/bin/sh",
+            &tmp.path().to_path_buf(),
+            &[
+                OsString::from("-c"),
+                OsString::from("setsid sleep 30 & echo started; sleep 30"),
+            ],
+            &[],
+            &[],
+            None,
+            &tmp.path().join("build.log"),
+            Some(std::time::Duration::from_millis(500)),
+            &Default::default(),
+        )
+        .unwrap_err();
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert!(err.to_string().contains("did not finish within"), "{err}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mask_secrets() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        let tmp = tempfile::tempdir().unwrap();
+        super::run_process_with_replacements(
+            "/bin/sh",
+            &tmp.path().to_path_buf(),
+            &[
+                OsString::from("-c"),
+                OsString::from("echo key=$MY_LICENSE_KEY; echo $MY_LICENSE_KEY >&2"),
+            ],
+            &[("MY_LICENSE_KEY".to_string(), "hunter2".to_string())],
+            &[("hunter2", "***")],
+            Some(&sender),
+            &tmp.path().join("build.log"),
+            None,
+            &Default::default(),
+        )
+        .unwrap();
+        drop(sender);
+
+        let mut lines = Vec::new();
+        while let Ok(line) = receiver.try_recv() {
+            lines.push(line.line);
+        }
+        lines.sort();
+        assert_eq!(lines, vec!["***", "key=***"]);
+    }
 }
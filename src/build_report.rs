@@ -0,0 +1,417 @@
+//! A machine-readable report of the built packages (`--build-report`), for the tools that run
+//! rattler-build, e.g. in CI. The report is a JSON array with an entry per built package, which
+//! is rewritten after every build of the invocation. Fields are only added to the schema, an
+//! incompatible change increments [`SCHEMA_VERSION`].
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use fs_err as fs;
+use rattler_conda_types::Platform;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSecondsWithFrac};
+
+use crate::{metadata::Output, package_inspect::PackageInspector, recipe::parser::Source};
+
+/// The version of the schema of the report
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The wall-clock time of the phases of a build (in seconds). A phase that did not run is
+/// `null`.
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTimings {
+    /// Fetching the sources
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    pub fetch: Option<Duration>,
+    /// Resolving the dependencies and installing the build and host environments
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    pub solve: Option<Duration>,
+    /// Running the build script
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    pub build: Option<Duration>,
+    /// Creating the packages and running the package content tests
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    pub package: Option<Duration>,
+    /// Running the tests of the package
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    pub test: Option<Duration>,
+}
+
+impl PhaseTimings {
+    /// Add `duration` to the time of a phase (the sources are fetched in two steps, for example)
+    pub fn add(phase: &mut Option<Duration>, duration: Duration) {
+        *phase = Some(phase.unwrap_or_default() + duration);
+    }
+}
+
+/// A package file and its checksums
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageFile {
+    /// The path of the package
+    pub path: PathBuf,
+    /// The SHA256 checksum of the package (hex)
+    pub sha256: String,
+    /// The MD5 checksum of the package (hex)
+    pub md5: String,
+    /// The size of the package in bytes
+    pub size: u64,
+}
+
+impl PackageFile {
+    /// Compute the checksums of the package at `path`
+    pub fn new(path: &Path) -> Result<Self, io::Error> {
+        let sha256 = rattler_digest::compute_file_digest::<rattler_digest::Sha256>(path)?;
+        let md5 = rattler_digest::compute_file_digest::<rattler_digest::Md5>(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            sha256: format!("{sha256:x}"),
+            md5: format!("{md5:x}"),
+            size: fs::metadata(path)?.len(),
+        })
+    }
+}
+
+/// A package of the build or host environment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResolvedPackage {
+    /// The environment, `build` or `host`
+    pub env: String,
+    /// The name of the package
+    pub name: String,
+    /// The exact version of the package
+    pub version: String,
+    /// The build string of the package
+    pub build: String,
+    /// The channel of the package
+    pub channel: String,
+}
+
+/// A source of the build
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceReport {
+    /// A downloaded file
+    Url {
+        /// The URL (the first mirror)
+        url: String,
+        /// The SHA256 checksum that the download was verified with
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+        /// The MD5 checksum that the download was verified with
+        #[serde(skip_serializing_if = "Option::is_none")]
+        md5: Option<String>,
+    },
+    /// A git repository
+    Git {
+        /// The URL or the path of the repository
+        url: String,
+        /// The commit that was checked out (the revision of the recipe if the sources were
+        /// fetched before the build)
+        rev: String,
+    },
+    /// A local directory or file
+    Path {
+        /// The path of the source
+        path: PathBuf,
+    },
+}
+
+impl SourceReport {
+    /// The report of `source`. `commit` is the commit that was checked out for a git source.
+    pub fn new(source: &Source, commit: Option<&str>) -> Self {
+        match source {
+            Source::Url(url) => SourceReport::Url {
+                url: url.url().to_string(),
+                sha256: url.sha256().map(hex::encode),
+                md5: url.md5().map(hex::encode),
+            },
+            Source::Git(git) => SourceReport::Git {
+                url: git.url().to_string(),
+                rev: commit.unwrap_or(git.rev()).to_string(),
+            },
+            Source::Path(path) => SourceReport::Path {
+                path: path.path().clone(),
+            },
+        }
+    }
+}
+
+/// Whether the tests of the package ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestStatus {
+    /// The tests ran and passed
+    Passed,
+    /// The tests were skipped (`--no-test`)
+    Skipped,
+    /// The package was built (and tested) in a container, the result of its tests is not known
+    Unknown,
+}
+
+/// The report of a built package
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildReport {
+    /// The version of the schema of the report (see [`SCHEMA_VERSION`])
+    pub schema_version: u32,
+    /// The name of the package
+    pub name: String,
+    /// The version of the package
+    pub version: String,
+    /// The build string of the package
+    pub build_string: String,
+    /// The target platform of the build
+    pub target_platform: Platform,
+    /// The subdir of the package
+    pub subdir: String,
+    /// The package files, one per package format
+    pub packages: Vec<PackageFile>,
+    /// The packages of the build and host environments, `null` if they are not known (the
+    /// environments of a build in a container are resolved in the container)
+    pub dependencies: Option<Vec<ResolvedPackage>>,
+    /// The sources of the build
+    pub sources: Vec<SourceReport>,
+    /// How long the phases of the build took
+    pub timings: PhaseTimings,
+    /// Whether the tests ran
+    pub tests: TestStatus,
+}
+
+impl BuildReport {
+    /// The report of the build of `output` into the `packages`. The dependencies are only
+    /// reported if they are finalized. `git_commits` are the commits that were checked out for
+    /// the git sources, by the index of the source.
+    pub fn new(
+        output: &Output,
+        packages: &[PathBuf],
+        timings: PhaseTimings,
+        tests: TestStatus,
+        git_commits: &HashMap<usize, String>,
+    ) -> Result<Self, io::Error> {
+        let dependencies = output.finalized_dependencies.as_ref().map(|finalized| {
+            [("build", &finalized.build), ("host", &finalized.host)]
+                .into_iter()
+                .filter_map(|(env, resolved)| resolved.as_ref().map(|resolved| (env, resolved)))
+                .flat_map(|(env, resolved)| {
+                    resolved.resolved.iter().map(move |record| ResolvedPackage {
+                        env: env.to_string(),
+                        name: record.package_record.name.as_normalized().to_string(),
+                        version: record.package_record.version.to_string(),
+                        build: record.package_record.build.clone(),
+                        channel: record.channel.clone(),
+                    })
+                })
+                .collect()
+        });
+
+        // the subdir of the `index.json` of the package
+        let subdir = match packages.first() {
+            Some(package) => PackageInspector::open(package)
+                .and_then(|package| package.index_json())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                .subdir
+                .unwrap_or_else(|| output.build_configuration.target_platform.to_string()),
+            None => output.build_configuration.target_platform.to_string(),
+        };
+
+        Ok(Self {
+            schema_version: SCHEMA_VERSION,
+            name: output.name().as_normalized().to_string(),
+            version: output.version().to_string(),
+            build_string: output.build_string().unwrap_or_default().to_string(),
+            target_platform: output.build_configuration.target_platform,
+            subdir,
+            packages: packages
+                .iter()
+                .map(|package| PackageFile::new(package))
+                .collect::<Result<_, _>>()?,
+            dependencies,
+            sources: output
+                .recipe
+                .sources()
+                .iter()
+                .enumerate()
+                .map(|(index, source)| {
+                    SourceReport::new(source, git_commits.get(&index).map(String::as_str))
+                })
+                .collect(),
+            timings,
+            tests,
+        })
+    }
+}
+
+/// The reports of the packages that were built with a configuration
+#[derive(Debug, Clone, Default)]
+pub struct BuildReports(Arc<Mutex<Vec<BuildReport>>>);
+
+impl BuildReports {
+    /// Add the `report` and write all reports to `path`. The file is replaced at once, so that
+    /// it is never read half-written.
+    pub fn add(&self, report: BuildReport, path: &Path) -> Result<(), io::Error> {
+        let mut reports = self
+            .0
+            .lock()
+            .expect("the build reports lock is not poisoned");
+        reports.push(report);
+
+        let directory = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        fs::create_dir_all(directory)?;
+        let mut file = tempfile::NamedTempFile::new_in(directory)?;
+        serde_json::to_writer_pretty(&mut file, &*reports)?;
+        writeln!(file)?;
+        crate::source::cache::persist_file(file, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path, time::Duration};
+
+    use fs_err as fs;
+    use rattler_conda_types::Platform;
+    use rattler_package_streaming::write::{write_conda_package, CompressionLevel};
+
+    use super::{
+        BuildReport, BuildReports, PackageFile, PhaseTimings, SourceReport, TestStatus,
+        SCHEMA_VERSION,
+    };
+    use crate::metadata::Output;
+
+    #[test]
+    fn reports_of_an_invocation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let package = tmp.path().join("foo-1.0-h123_0.conda");
+        std::fs::write(&package, "package").unwrap();
+
+        let package_file = PackageFile::new(&package).unwrap();
+        assert_eq!(
+            package_file.sha256,
+            "bc4a71180870f7945155fbb02f4b0a2e3faa2a62d6d31b7039013055ed19869a"
+        );
+        assert_eq!(package_file.md5, "efe90a8e604a7c840e88d03a67f6b7d8");
+        assert_eq!(package_file.size, 7);
+
+        let mut timings = PhaseTimings::default();
+        PhaseTimings::add(&mut timings.fetch, Duration::from_millis(1500));
+        PhaseTimings::add(&mut timings.fetch, Duration::from_millis(500));
+        let report = BuildReport {
+            schema_version: SCHEMA_VERSION,
+            name: "foo".to_string(),
+            version: "1.0".to_string(),
+            build_string: "h123_0".to_string(),
+            target_platform: Platform::Linux64,
+            subdir: "linux-64".to_string(),
+            packages: vec![package_file],
+            dependencies: None,
+            sources: vec![SourceReport::Git {
+                url: "https://github.com/foo/foo.git".to_string(),
+                rev: "v1.0".to_string(),
+            }],
+            timings,
+            tests: TestStatus::Skipped,
+        };
+
+        let path = tmp.path().join("reports/build-report.json");
+        let reports = BuildReports::default();
+        reports.add(report.clone(), &path).unwrap();
+        reports.clone().add(report.clone(), &path).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let written = written.as_array().unwrap();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0]["schema_version"], 1);
+        assert_eq!(written[0]["timings"]["fetch"], 2.0);
+        assert!(written[0]["timings"]["test"].is_null());
+        assert_eq!(written[0]["tests"], "skipped");
+        assert_eq!(written[0]["sources"][0]["type"], "git");
+
+        let parsed: Vec<BuildReport> =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(parsed[1], report);
+    }
+
+    #[test]
+    fn report_of_an_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let recipe = fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/rendered_recipes/rich_recipe.yaml"),
+        )
+        .unwrap()
+        .replace(
+            "- url: https://pypi.io/packages/source/r/rich/rich-13.4.2.tar.gz\n    sha256: d653d6bccede5844304c605d5aac802c7cf9621efd700b46c7ec2b51ea914898",
+            "- url: https://github.com/Textualize/rich.git\n    rev: v13.4.2",
+        );
+        let mut output: Output = serde_yaml::from_str(&recipe).unwrap();
+        // the subdir is the one of the package
+        output.build_configuration.target_platform = Platform::Linux64;
+
+        let base = tmp.path().join("pkg");
+        let index = base.join("info/index.json");
+        fs::create_dir_all(index.parent().unwrap()).unwrap();
+        fs::write(
+            &index,
+            r#"{"name": "rich", "version": "13.4.2", "build": "pyh4616a5c_0", "build_number": 0, "subdir": "noarch", "depends": []}"#,
+        )
+        .unwrap();
+        let package = tmp.path().join("rich-13.4.2-pyh4616a5c_0.conda");
+        write_conda_package(
+            fs::File::create(&package).unwrap(),
+            &base,
+            &[index],
+            CompressionLevel::Default,
+            "rich-13.4.2-pyh4616a5c_0",
+            None,
+        )
+        .unwrap();
+
+        let commits = HashMap::from([(0, "3d7d8e2f".to_string())]);
+        let report = BuildReport::new(
+            &output,
+            &[package.clone()],
+            PhaseTimings::default(),
+            TestStatus::Passed,
+            &commits,
+        )
+        .unwrap();
+        assert_eq!(report.subdir, "noarch");
+        assert_eq!(
+            report.sources,
+            vec![SourceReport::Git {
+                url: "https://github.com/Textualize/rich.git".to_string(),
+                rev: "3d7d8e2f".to_string(),
+            }]
+        );
+        assert!(report
+            .dependencies
+            .unwrap()
+            .iter()
+            .any(|p| p.name == "python"));
+
+        // the environments of a build in a container are not known
+        output.finalized_dependencies = None;
+        let report = BuildReport::new(
+            &output,
+            &[package],
+            PhaseTimings::default(),
+            TestStatus::Unknown,
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(report.dependencies, None);
+        assert!(matches!(&report.sources[0], SourceReport::Git { rev, .. } if rev == "v13.4.2"));
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["dependencies"].is_null());
+        assert_eq!(json["tests"], "unknown");
+    }
+}
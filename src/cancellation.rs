@@ -0,0 +1,49 @@
+//! Cancelling a build with Ctrl-C (or SIGTERM).
+//!
+//! The first signal cancels the [`CancellationToken`] of the tool configuration: the build script
+//! is killed together with the processes that it started, running downloads are aborted, and the
+//! build directory is removed (unless it is kept). A second signal exits right away.
+
+use tokio_util::sync::CancellationToken;
+
+/// The exit code of a process that was interrupted by SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Cancel `token` on the first Ctrl-C (or SIGTERM, or a console break event on Windows), and exit
+/// the process on the second one. Has to be called inside of a tokio runtime.
+pub fn cancel_on_signals(token: CancellationToken) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = signal().await {
+                tracing::warn!("Could not listen for Ctrl-C: {}", e);
+                return;
+            }
+            if token.is_cancelled() {
+                std::process::exit(INTERRUPTED_EXIT_CODE);
+            }
+            tracing::warn!("Cancelling the build, press Ctrl-C again to exit immediately");
+            token.cancel();
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+async fn signal() -> std::io::Result<()> {
+    let mut ctrl_break = tokio::signal::windows::ctrl_break()?;
+    let mut ctrl_close = tokio::signal::windows::ctrl_close()?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = ctrl_break.recv() => Ok(()),
+        _ = ctrl_close.recv() => Ok(()),
+    }
+}
@@ -0,0 +1,470 @@
+//! Query channels for the packages they contain (e.g. to find out which builds of a package
+//! already exist in a channel).
+//!
+//! The solver loads the records of the build environments through a [`ChannelQuery`] as well,
+//! so every subdir is fetched with the same cache and only loaded once per [`ChannelQuery`].
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use indicatif::MultiProgress;
+use rattler_conda_types::{Channel, PackageName, Platform, RepoDataRecord};
+use rattler_networking::AuthenticatedClient;
+use rattler_repodata_gateway::{
+    fetch::{CacheAction, DownloadProgress, FetchRepoDataError, FetchRepoDataOptions},
+    sparse::SparseRepoData,
+};
+use url::Url;
+
+use crate::{
+    progress::{Progress, ProgressOutput, ProgressUnit},
+    tool_configuration,
+};
+
+/// Errors that can occur while querying a channel
+#[derive(Debug, thiserror::Error)]
+pub enum ChannelQueryError {
+    /// The repodata could not be fetched
+    #[error("failed to fetch repodata: {0}")]
+    Fetch(#[from] FetchRepoDataError),
+
+    /// The repodata could not be read or parsed
+    #[error("failed to read repodata: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The background task that parses the repodata was cancelled
+    #[error("loading the repodata was cancelled")]
+    Cancelled,
+
+    /// The `noarch` subdir, which every channel has, does not exist
+    #[error("the channel has no noarch subdir: {0}")]
+    MissingNoArch(Url),
+}
+
+/// The options to use when fetching repodata with the given tool configuration
+pub fn fetch_options(
+    tool_configuration: &tool_configuration::Configuration,
+) -> FetchRepoDataOptions {
+    FetchRepoDataOptions {
+        cache_action: if tool_configuration.offline {
+            CacheAction::ForceCacheOnly
+        } else {
+            CacheAction::CacheOrFetch
+        },
+        zstd_enabled: tool_configuration.use_zstd,
+        bz2_enabled: tool_configuration.use_bz2,
+        ..Default::default()
+    }
+}
+
+/// Answers "which records of package X exist in subdir Z of channel Y" and memoizes the
+/// loaded repodata.
+pub struct ChannelQuery {
+    client: AuthenticatedClient,
+    repodata_cache: PathBuf,
+    options: FetchRepoDataOptions,
+    progress: Option<(MultiProgress, ProgressOutput)>,
+    subdirs: Mutex<HashMap<Url, Option<Arc<SparseRepoData>>>>,
+    records: Mutex<HashMap<(Url, PackageName), Vec<RepoDataRecord>>>,
+}
+
+impl ChannelQuery {
+    /// Create a new query that stores the downloaded repodata in `repodata_cache`
+    pub fn new(client: AuthenticatedClient, repodata_cache: impl Into<PathBuf>) -> Self {
+        Self {
+            client,
+            repodata_cache: repodata_cache.into(),
+            options: FetchRepoDataOptions::default(),
+            progress: None,
+            subdirs: Mutex::default(),
+            records: Mutex::default(),
+        }
+    }
+
+    /// Create a new query from the tool configuration. The downloads are reported with the
+    /// progress of the tool configuration.
+    pub fn from_tool_configuration(
+        tool_configuration: &tool_configuration::Configuration,
+    ) -> Result<Self, std::io::Error> {
+        let cache_dir = rattler::default_cache_dir()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e.to_string()))?;
+        Ok(Self::new(
+            tool_configuration.client.clone(),
+            cache_dir.join("repodata"),
+        )
+        .with_options(fetch_options(tool_configuration))
+        .with_progress(
+            tool_configuration.multi_progress_indicator.clone(),
+            tool_configuration.progress,
+        ))
+    }
+
+    /// Use the given options when fetching repodata
+    pub fn with_options(mut self, options: FetchRepoDataOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Report the repodata downloads in `multi_progress`, as `output` says
+    pub fn with_progress(mut self, multi_progress: MultiProgress, output: ProgressOutput) -> Self {
+        self.progress = Some((multi_progress, output));
+        self
+    }
+
+    /// Returns all records of the package `name` in the `subdir` of the `channel`. A subdir that
+    /// does not exist in the channel has no records.
+    pub async fn existing_records(
+        &self,
+        channel: &Channel,
+        subdir: Platform,
+        name: &PackageName,
+    ) -> Result<Vec<RepoDataRecord>, ChannelQueryError> {
+        let subdir_url = channel.platform_url(subdir);
+        let key = (subdir_url.clone(), name.clone());
+        if let Some(records) = self.records.lock().unwrap().get(&key) {
+            return Ok(records.clone());
+        }
+
+        let records = match self.subdir(channel, subdir, &subdir_url).await? {
+            Some(repodata) => repodata.load_records(name)?,
+            None => Vec::new(),
+        };
+
+        self.records.lock().unwrap().insert(key, records.clone());
+        Ok(records)
+    }
+
+    /// Returns the records of `package_names` and of all the packages they (transitively)
+    /// depend on, one list per subdir in `subdirs`. The subdirs are loaded concurrently; only the
+    /// `noarch` subdir of a channel has to exist.
+    pub async fn load_records_recursive(
+        &self,
+        subdirs: &[(Channel, Platform)],
+        package_names: Vec<PackageName>,
+    ) -> Result<Vec<Vec<RepoDataRecord>>, ChannelQueryError> {
+        let repodatas =
+            futures::future::try_join_all(subdirs.iter().map(|(channel, subdir)| async move {
+                let subdir_url = channel.platform_url(*subdir);
+                match self.subdir(channel, *subdir, &subdir_url).await? {
+                    Some(repodata) => Ok(Some(repodata)),
+                    None if *subdir == Platform::NoArch => {
+                        Err(ChannelQueryError::MissingNoArch(subdir_url))
+                    }
+                    None => Ok(None),
+                }
+            }))
+            .await?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        tokio::task::spawn_blocking(move || {
+            SparseRepoData::load_records_recursive(
+                repodatas.iter().map(|repodata| repodata.as_ref()),
+                package_names,
+                None,
+            )
+        })
+        .await
+        .map_err(|_| ChannelQueryError::Cancelled)?
+        .map_err(ChannelQueryError::from)
+    }
+
+    /// Load (and memoize) the repodata of a single subdir
+    async fn subdir(
+        &self,
+        channel: &Channel,
+        subdir: Platform,
+        subdir_url: &Url,
+    ) -> Result<Option<Arc<SparseRepoData>>, ChannelQueryError> {
+        if let Some(repodata) = self.subdirs.lock().unwrap().get(subdir_url) {
+            return Ok(repodata.clone());
+        }
+
+        let repodata_path = if subdir_url.scheme() == "file" {
+            // local channels are read directly, they do not need to go through the cache
+            subdir_url
+                .to_file_path()
+                .ok()
+                .map(|path| path.join("repodata.json"))
+                .filter(|path| path.is_file())
+        } else {
+            let progress = self.progress.as_ref().map(|(multi_progress, output)| {
+                Arc::new(Progress::with_output(
+                    multi_progress,
+                    *output,
+                    format!("{}/{subdir}", friendly_channel_name(channel)),
+                    None,
+                    ProgressUnit::Bytes,
+                ))
+            });
+            let download_progress = progress.clone();
+            let result = rattler_repodata_gateway::fetch::fetch_repo_data(
+                subdir_url.clone(),
+                self.client.clone(),
+                self.repodata_cache.clone(),
+                self.options.clone(),
+                download_progress.map(|progress| {
+                    Box::new(move |DownloadProgress { bytes, .. }| progress.set_position(bytes))
+                        as Box<dyn FnMut(DownloadProgress) + Send + Sync>
+                }),
+            )
+            .await;
+            if let Some(progress) = &progress {
+                progress.finish();
+            }
+            match result {
+                Ok(result) => Some(result.repo_data_json_path),
+                Err(FetchRepoDataError::NotFound(_)) => None,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let repodata = match repodata_path {
+            Some(path) => Some(Arc::new(load_sparse(channel.clone(), subdir, path).await?)),
+            None => None,
+        };
+
+        self.subdirs
+            .lock()
+            .unwrap()
+            .insert(subdir_url.clone(), repodata.clone());
+        Ok(repodata)
+    }
+}
+
+/// Returns a friendly name for the specified channel
+fn friendly_channel_name(channel: &Channel) -> String {
+    channel
+        .name
+        .as_ref()
+        .map(String::from)
+        .unwrap_or_else(|| channel.canonical_name())
+}
+
+/// Parse the `repodata.json` in a blocking task
+async fn load_sparse(
+    channel: Channel,
+    subdir: Platform,
+    path: impl AsRef<Path>,
+) -> Result<SparseRepoData, ChannelQueryError> {
+    let path = path.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        SparseRepoData::new(channel, subdir.to_string(), path, None)
+    })
+    .await
+    .map_err(|_| ChannelQueryError::Cancelled)?
+    .map_err(ChannelQueryError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        path::Path,
+    };
+
+    use rattler_conda_types::{Channel, ChannelConfig, PackageName, Platform};
+    use rattler_networking::AuthenticatedClient;
+    use url::Url;
+
+    use super::{ChannelQuery, ChannelQueryError};
+
+    fn fixture_channel() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/channels/query-channel")
+    }
+
+    fn channel(url: &str) -> Channel {
+        Channel::from_str(url, &ChannelConfig::default()).unwrap()
+    }
+
+    /// Serve the fixture channel over HTTP, answering `404` for everything except the
+    /// `linux-64/repodata.json`
+    fn serve_fixture_channel() -> (Url, std::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let repodata =
+            fs_err::read_to_string(fixture_channel().join("linux-64/repodata.json")).unwrap();
+        let (requests_tx, requests_rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request_line).unwrap();
+                // consume the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let path = parts.next().unwrap_or_default().to_string();
+                let _ = requests_tx.send(format!("{method} {path}"));
+
+                let response = if path == "/linux-64/repodata.json" {
+                    let body = if method == "HEAD" {
+                        ""
+                    } else {
+                        repodata.as_str()
+                    };
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        repodata.len(),
+                        body
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (
+            Url::parse(&format!("http://127.0.0.1:{port}/")).unwrap(),
+            requests_rx,
+        )
+    }
+
+    #[tokio::test]
+    async fn local_channel() {
+        let cache = tempfile::tempdir().unwrap();
+        let query = ChannelQuery::new(AuthenticatedClient::default(), cache.path());
+        let channel = channel(
+            Url::from_directory_path(fixture_channel())
+                .unwrap()
+                .as_str(),
+        );
+
+        let name = PackageName::try_from("foo").unwrap();
+        let records = query
+            .existing_records(&channel, Platform::Linux64, &name)
+            .await
+            .unwrap();
+        let mut builds = records
+            .iter()
+            .map(|r| {
+                format!(
+                    "{}-{}",
+                    r.package_record.version, r.package_record.build_number
+                )
+            })
+            .collect::<Vec<_>>();
+        builds.sort();
+        assert_eq!(builds, vec!["1.0.0-0", "1.0.0-1", "2.0.0-0"]);
+
+        let other = PackageName::try_from("bar").unwrap();
+        let records = query
+            .existing_records(&channel, Platform::NoArch, &other)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+
+        // a subdir that does not exist has no records
+        let records = query
+            .existing_records(&channel, Platform::OsxArm64, &name)
+            .await
+            .unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_of_dependencies() {
+        let cache = tempfile::tempdir().unwrap();
+        let query = ChannelQuery::new(AuthenticatedClient::default(), cache.path());
+        let channel = channel(
+            Url::from_directory_path(fixture_channel())
+                .unwrap()
+                .as_str(),
+        );
+        let subdirs = [
+            (channel.clone(), Platform::Linux64),
+            (channel.clone(), Platform::NoArch),
+        ];
+
+        // `bar` depends on `baz`, `foo` is not needed
+        let records = query
+            .load_records_recursive(&subdirs, vec![PackageName::try_from("bar").unwrap()])
+            .await
+            .unwrap();
+        let names = records
+            .iter()
+            .map(|subdir| {
+                subdir
+                    .iter()
+                    .map(|r| r.package_record.name.as_normalized().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![vec!["baz"], vec!["bar"]]);
+
+        // only the noarch subdir has to exist
+        let records = query
+            .load_records_recursive(
+                &[
+                    (channel.clone(), Platform::OsxArm64),
+                    (channel.clone(), Platform::NoArch),
+                ],
+                vec![PackageName::try_from("bar").unwrap()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+
+        let linux_only = tempfile::tempdir().unwrap();
+        fs_err::create_dir_all(linux_only.path().join("linux-64")).unwrap();
+        fs_err::copy(
+            fixture_channel().join("linux-64/repodata.json"),
+            linux_only.path().join("linux-64/repodata.json"),
+        )
+        .unwrap();
+        let linux_only = self::channel(
+            Url::from_directory_path(linux_only.path())
+                .unwrap()
+                .as_str(),
+        );
+        let err = query
+            .load_records_recursive(
+                &[(linux_only, Platform::NoArch)],
+                vec![PackageName::try_from("foo").unwrap()],
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ChannelQueryError::MissingNoArch(_)));
+    }
+
+    #[tokio::test]
+    async fn http_channel_is_memoized() {
+        let (url, requests) = serve_fixture_channel();
+        let cache = tempfile::tempdir().unwrap();
+        let query = ChannelQuery::new(AuthenticatedClient::default(), cache.path());
+        let channel = channel(url.as_str());
+        let name = PackageName::try_from("foo").unwrap();
+
+        let records = query
+            .existing_records(&channel, Platform::Linux64, &name)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        let fetched = requests
+            .try_iter()
+            .filter(|r| r == "GET /linux-64/repodata.json")
+            .count();
+        assert_eq!(fetched, 1);
+
+        // the second query is answered without touching the network
+        let records = query
+            .existing_records(&channel, Platform::Linux64, &name)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(requests.try_iter().count(), 0);
+    }
+}
@@ -0,0 +1,206 @@
+//! Group the console output of the build phases for CI systems that can collapse log sections
+//! (GitHub Actions and GitLab CI).
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The style of the group markers written around each build phase
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CiLogStyle {
+    /// `::group::` / `::endgroup::` markers for GitHub Actions
+    Github,
+    /// `section_start` / `section_end` markers for GitLab CI
+    Gitlab,
+    /// No markers
+    #[default]
+    None,
+}
+
+impl CiLogStyle {
+    /// Detect the CI system from the environment variables it sets
+    pub fn detect() -> Self {
+        Self::detect_from(|key| std::env::var(key).ok())
+    }
+
+    fn detect_from(var: impl Fn(&str) -> Option<String>) -> Self {
+        if var("GITHUB_ACTIONS").as_deref() == Some("true") {
+            CiLogStyle::Github
+        } else if var("GITLAB_CI").is_some() {
+            CiLogStyle::Gitlab
+        } else {
+            CiLogStyle::None
+        }
+    }
+
+    /// The line that opens a group
+    fn start_marker(&self, name: &str, title: &str, timestamp: u64) -> Option<String> {
+        match self {
+            CiLogStyle::Github => Some(format!("::group::{title}")),
+            CiLogStyle::Gitlab => Some(format!(
+                "\x1b[0Ksection_start:{timestamp}:{name}[collapsed=true]\r\x1b[0K{title}"
+            )),
+            CiLogStyle::None => None,
+        }
+    }
+
+    /// The line that closes a group
+    fn end_marker(&self, name: &str, timestamp: u64) -> Option<String> {
+        match self {
+            CiLogStyle::Github => Some("::endgroup::".to_string()),
+            CiLogStyle::Gitlab => Some(format!("\x1b[0Ksection_end:{timestamp}:{name}\r\x1b[0K")),
+            CiLogStyle::None => None,
+        }
+    }
+}
+
+/// The phases of a build that are put in their own group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildPhase {
+    /// Fetching the sources
+    Fetch,
+    /// Resolving the dependencies
+    Solve,
+    /// Installing the build and host environments
+    EnvInstall,
+    /// Running the build script
+    Script,
+    /// Creating the package
+    Package,
+    /// Testing the package
+    Test,
+}
+
+impl BuildPhase {
+    fn id(&self) -> &'static str {
+        match self {
+            BuildPhase::Fetch => "fetch_sources",
+            BuildPhase::Solve => "resolve_dependencies",
+            BuildPhase::EnvInstall => "install_environments",
+            BuildPhase::Script => "build_script",
+            BuildPhase::Package => "packaging",
+            BuildPhase::Test => "tests",
+        }
+    }
+
+    /// The title of the phase (e.g. "Running build script")
+    pub fn title(&self) -> &'static str {
+        match self {
+            BuildPhase::Fetch => "Fetching sources",
+            BuildPhase::Solve => "Resolving dependencies",
+            BuildPhase::EnvInstall => "Installing environments",
+            BuildPhase::Script => "Running build script",
+            BuildPhase::Package => "Packaging",
+            BuildPhase::Test => "Running tests",
+        }
+    }
+}
+
+/// GitLab merges sections with the same name, so every group gets a unique one
+static GROUP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// An open group of log lines. The group is closed when this is dropped, so that errors which
+/// are reported after a failed phase end up outside of the (collapsed) group.
+#[must_use]
+pub struct LogGroup {
+    style: CiLogStyle,
+    name: String,
+}
+
+impl LogGroup {
+    /// Open a new group for the given build phase
+    pub fn start(style: CiLogStyle, phase: BuildPhase) -> Self {
+        let name = format!(
+            "{}_{}",
+            phase.id(),
+            GROUP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        if let Some(marker) = style.start_marker(&name, phase.title(), unix_timestamp()) {
+            tracing::info!("{}", marker);
+        }
+        Self { style, name }
+    }
+}
+
+impl Drop for LogGroup {
+    fn drop(&mut self) {
+        if let Some(marker) = self.style.end_marker(&self.name, unix_timestamp()) {
+            tracing::info!("{}", marker);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildPhase, CiLogStyle};
+
+    #[test]
+    fn detect_style() {
+        let detect = |vars: &[(&str, &str)]| {
+            CiLogStyle::detect_from(|key| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            })
+        };
+        assert_eq!(detect(&[("GITHUB_ACTIONS", "true")]), CiLogStyle::Github);
+        assert_eq!(detect(&[("GITLAB_CI", "true")]), CiLogStyle::Gitlab);
+        assert_eq!(detect(&[("GITHUB_ACTIONS", "false")]), CiLogStyle::None);
+        assert_eq!(detect(&[]), CiLogStyle::None);
+    }
+
+    #[test]
+    fn markers() {
+        let phase = BuildPhase::Script;
+
+        assert_eq!(
+            CiLogStyle::Github
+                .start_marker("build_script_0", phase.title(), 1700000000)
+                .unwrap(),
+            "::group::Running build script"
+        );
+        assert_eq!(
+            CiLogStyle::Github
+                .end_marker("build_script_0", 1700000010)
+                .unwrap(),
+            "::endgroup::"
+        );
+
+        assert_eq!(
+            CiLogStyle::Gitlab
+                .start_marker("build_script_0", phase.title(), 1700000000)
+                .unwrap(),
+            "\x1b[0Ksection_start:1700000000:build_script_0[collapsed=true]\r\x1b[0KRunning build script"
+        );
+        assert_eq!(
+            CiLogStyle::Gitlab
+                .end_marker("build_script_0", 1700000010)
+                .unwrap(),
+            "\x1b[0Ksection_end:1700000010:build_script_0\r\x1b[0K"
+        );
+
+        assert!(CiLogStyle::None
+            .start_marker("build_script_0", phase.title(), 0)
+            .is_none());
+        assert!(CiLogStyle::None.end_marker("build_script_0", 0).is_none());
+    }
+}
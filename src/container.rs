@@ -0,0 +1,422 @@
+//! Run the build of an output inside of a container image (e.g. to build against an old glibc).
+//!
+//! The recipe is rendered and the sources are fetched on the host. The dependencies are then
+//! resolved and installed, the build script is run and the package is created by a second
+//! `rattler-build` process inside the container, which gets the output directory, the recipe
+//! directory and the package cache bind-mounted at fixed paths.
+
+use std::{
+    fmt,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use clap::ValueEnum;
+use fs_err as fs;
+
+use crate::{
+    bandwidth,
+    metadata::{padded_host_prefix, Directories, Output},
+    tool_configuration,
+    tools::{Tool, Tools},
+};
+
+/// The output directory (and local channel) inside the container
+pub const CONTAINER_OUTPUT_DIR: &str = "/opt/rattler-build/output";
+
+/// The (read-only) recipe directory inside the container
+pub const CONTAINER_RECIPE_DIR: &str = "/opt/rattler-build/recipe";
+
+/// The value of `XDG_CACHE_HOME` inside the container. The rattler package cache of the host is
+/// mounted below it.
+pub const CONTAINER_CACHE_DIR: &str = "/opt/rattler-build/cache";
+
+/// The `rattler-build` executable inside the container
+pub const CONTAINER_EXECUTABLE: &str = "/opt/rattler-build/bin/rattler-build";
+
+/// The container runtime that runs the build
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerRuntime {
+    /// Docker
+    Docker,
+    /// Podman
+    Podman,
+}
+
+impl ContainerRuntime {
+    const fn tool(&self) -> Tool {
+        match self {
+            ContainerRuntime::Docker => Tool::Docker,
+            ContainerRuntime::Podman => Tool::Podman,
+        }
+    }
+}
+
+impl fmt::Display for ContainerRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.tool().executable())
+    }
+}
+
+/// The configuration of a containerized build
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ContainerConfig {
+    /// The image to run the build in (e.g. `quay.io/condaforge/linux-anvil-cos7-x86_64`)
+    pub image: String,
+    /// The container runtime. `docker` and then `podman` are tried if not set.
+    pub runtime: Option<ContainerRuntime>,
+    /// The `rattler-build` executable that is mounted into the container. Defaults to the
+    /// running executable, which has to be compatible with the image.
+    pub executable: Option<PathBuf>,
+}
+
+#[allow(missing_docs)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum ContainerError {
+    #[error("Could not find {runtime} to run the build in the container image `{image}`")]
+    #[diagnostic(help("install docker or podman, or build without a container image"))]
+    RuntimeNotFound { runtime: String, image: String },
+
+    #[error("`{runtime}` failed to run the container image `{image}` (exit code {code})")]
+    #[diagnostic(help(
+        "check that the container runtime is running, that the image can be pulled and that the \
+         `rattler-build` executable can run in the image"
+    ))]
+    RuntimeFailed {
+        runtime: ContainerRuntime,
+        image: String,
+        code: i32,
+    },
+
+    #[error("The build in the container image `{image}` failed")]
+    BuildFailed { image: String, code: Option<i32> },
+
+    #[error("The build in the container did not create the package {0}")]
+    PackageNotFound(PathBuf),
+
+    #[error("The build directory {0} is not inside of the output directory")]
+    BuildDirOutsideOutputDir(PathBuf),
+
+    #[error("Could not serialize the output for the container: {0}")]
+    Serialization(#[from] serde_yaml::Error),
+
+    #[error("Could not find the rattler package cache: {0}")]
+    Cache(String),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// The directories of the build as they are seen from inside the container
+pub fn container_directories(directories: &Directories) -> Result<Directories, ContainerError> {
+    let output_dir = PathBuf::from(CONTAINER_OUTPUT_DIR);
+    let build_dir = output_dir.join(
+        directories
+            .build_dir
+            .strip_prefix(&directories.output_dir)
+            .map_err(|_| ContainerError::BuildDirOutsideOutputDir(directories.build_dir.clone()))?,
+    );
+
+    Ok(Directories {
+        recipe_dir: PathBuf::from(CONTAINER_RECIPE_DIR),
+        host_prefix: padded_host_prefix(&build_dir),
+        build_prefix: build_dir.join("build_env"),
+        work_dir: build_dir.join("work"),
+        build_dir,
+        output_dir,
+        // the sources are fetched on the host
+        source_cache: directories.source_cache.clone(),
+    })
+}
+
+/// Find the configured container runtime, or the first one that is installed
+fn find_runtime(
+    container: &ContainerConfig,
+    tools: &Tools,
+) -> Result<(ContainerRuntime, PathBuf), ContainerError> {
+    let candidates = match container.runtime {
+        Some(runtime) => vec![runtime],
+        None => ContainerRuntime::value_variants().to_vec(),
+    };
+
+    for runtime in &candidates {
+        let reason = format!("run the build in `{}`", container.image);
+        if let Ok(executable) = tools.find(runtime.tool(), reason) {
+            return Ok((*runtime, executable));
+        }
+    }
+
+    Err(ContainerError::RuntimeNotFound {
+        runtime: candidates
+            .iter()
+            .map(|r| format!("`{r}`"))
+            .collect::<Vec<_>>()
+            .join(" or "),
+        image: container.image.clone(),
+    })
+}
+
+fn volume(host: &Path, container: &str, read_only: bool) -> String {
+    let suffix = if read_only { ":ro" } else { "" };
+    format!("{}:{}{}", host.display(), container, suffix)
+}
+
+/// Run the build of `output`, whose sources are already fetched, in the container image
+pub(crate) fn run_build(
+    output: &Output,
+    tool_configuration: &tool_configuration::Configuration,
+    container: &ContainerConfig,
+) -> Result<Vec<PathBuf>, ContainerError> {
+    let (runtime, runtime_executable) = find_runtime(container, &Tools::default())?;
+    let executable = match &container.executable {
+        Some(executable) => executable.clone(),
+        None => std::env::current_exe()?,
+    };
+
+    let directories = &output.build_configuration.directories;
+    let mut container_output = output.clone();
+    container_output.build_configuration.directories = container_directories(directories)?;
+    let output_file = directories.build_dir.join("container_output.yaml");
+    fs::write(&output_file, serde_yaml::to_string(&container_output)?)?;
+    let container_output_file = container_output
+        .build_configuration
+        .directories
+        .build_dir
+        .join("container_output.yaml");
+
+    let cache_dir =
+        rattler::default_cache_dir().map_err(|e| ContainerError::Cache(e.to_string()))?;
+    fs::create_dir_all(&cache_dir)?;
+
+    let mut command = Command::new(&runtime_executable);
+    command
+        .args(["run", "--rm"])
+        .arg("--volume")
+        .arg(volume(&directories.output_dir, CONTAINER_OUTPUT_DIR, false))
+        .arg("--volume")
+        .arg(volume(&directories.recipe_dir, CONTAINER_RECIPE_DIR, true))
+        .arg("--volume")
+        .arg(volume(
+            &cache_dir,
+            &format!("{CONTAINER_CACHE_DIR}/rattler/cache"),
+            false,
+        ))
+        .arg("--volume")
+        .arg(volume(&executable, CONTAINER_EXECUTABLE, true))
+        .arg("--env")
+        .arg(format!("XDG_CACHE_HOME={CONTAINER_CACHE_DIR}"))
+        .arg("--env")
+        .arg("HOME=/tmp");
+
+    if let Some(limit) = &tool_configuration.bandwidth_limit {
+        command.arg("--env").arg(format!(
+            "{}={}",
+            bandwidth::RATE_LIMIT_ENV,
+            limit.bytes_per_second()
+        ));
+    }
+
+    // the variables of the build script that come from the environment of rattler-build
+    let script = output.recipe.build().script();
+    for name in script.passthrough().iter().chain(script.secrets()) {
+        if std::env::var_os(name).is_some() {
+            command.arg("--env").arg(name);
+        }
+    }
+
+    // rootless podman already maps the root user of the container to the current user
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if runtime == ContainerRuntime::Docker {
+            let meta = fs::metadata(&directories.output_dir)?;
+            command
+                .arg("--user")
+                .arg(format!("{}:{}", meta.uid(), meta.gid()));
+        }
+    }
+
+    command
+        .arg(&container.image)
+        .arg(CONTAINER_EXECUTABLE)
+        .arg("build-in-container")
+        .arg("--output-file")
+        .arg(&container_output_file)
+        .arg("--recipe-dir")
+        .arg(CONTAINER_RECIPE_DIR)
+        .arg("--output-dir")
+        .arg(CONTAINER_OUTPUT_DIR);
+    if let Some(style) = tool_configuration.ci_log_style.to_possible_value() {
+        command.arg("--ci-log-style").arg(style.get_name());
+    }
+    if tool_configuration.no_test {
+        command.arg("--no-test");
+    }
+    if tool_configuration.no_clean {
+        command.arg("--keep-build");
+    }
+    if tool_configuration.offline {
+        command.arg("--offline");
+    }
+    if let Some(timeout) = tool_configuration.build_timeout {
+        command
+            .arg("--build-timeout")
+            .arg(timeout.as_secs().to_string());
+    }
+    if tool_configuration.write_lock_file {
+        command.arg("--write-lock-file");
+    }
+    if let Some(progress) = tool_configuration.progress.to_possible_value() {
+        command.arg("--progress").arg(progress.get_name());
+    }
+
+    tracing::info!(
+        "Running the build in `{}` with {}",
+        container.image,
+        runtime
+    );
+    let code = run_and_forward_output(command)?;
+    match code {
+        Some(0) => {}
+        // the exit codes of `docker run` and `podman run` for failures of the runtime itself,
+        // and for a command that cannot be invoked or found in the container
+        Some(code @ (125..=127)) => {
+            return Err(ContainerError::RuntimeFailed {
+                runtime,
+                image: container.image.clone(),
+                code,
+            })
+        }
+        code => {
+            return Err(ContainerError::BuildFailed {
+                image: container.image.clone(),
+                code,
+            })
+        }
+    }
+
+    let identifier = output
+        .identifier()
+        .ok_or_else(|| ContainerError::PackageNotFound(directories.output_dir.clone()))?;
+    output
+        .build_configuration
+        .package_formats()
+        .into_iter()
+        .map(|format| {
+            let package = directories
+                .output_dir
+                .join(output.build_configuration.target_platform.to_string())
+                .join(format!("{}{}", identifier, format.extension()));
+            package
+                .exists()
+                .then_some(package)
+                .ok_or_else(|| ContainerError::PackageNotFound(directories.output_dir.clone()))
+        })
+        .collect()
+}
+
+/// Run the command and pass every line of its output on to the log. Returns the exit code.
+fn run_and_forward_output(mut command: Command) -> Result<Option<i32>, std::io::Error> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stderr = child.stderr.take();
+    let forward_stderr = std::thread::spawn(move || {
+        if let Some(stderr) = stderr {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                tracing::info!("{}", line);
+            }
+        }
+    });
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            tracing::info!("{}", line);
+        }
+    }
+    let _ = forward_stderr.join();
+
+    Ok(child.wait()?.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, path::PathBuf};
+
+    use super::{
+        container_directories, find_runtime, ContainerConfig, ContainerError, ContainerRuntime,
+    };
+    use crate::{metadata::Directories, tools::Tools};
+
+    #[test]
+    fn translated_directories() {
+        let build_dir = PathBuf::from("/home/user/output/bld/rattler-build_foo_1700000000");
+        let host = Directories {
+            recipe_dir: PathBuf::from("/home/user/recipes/foo"),
+            host_prefix: build_dir.join("host_env_placehold"),
+            build_prefix: build_dir.join("build_env"),
+            work_dir: build_dir.join("work"),
+            build_dir,
+            output_dir: PathBuf::from("/home/user/output"),
+            source_cache: PathBuf::from("/home/user/.cache/rattler-build/src_cache"),
+        };
+
+        let container = container_directories(&host).unwrap();
+        let build_dir = PathBuf::from("/opt/rattler-build/output/bld/rattler-build_foo_1700000000");
+        assert_eq!(container.build_dir, build_dir);
+        assert_eq!(container.work_dir, build_dir.join("work"));
+        assert_eq!(container.build_prefix, build_dir.join("build_env"));
+        assert_eq!(
+            container.recipe_dir,
+            PathBuf::from("/opt/rattler-build/recipe")
+        );
+        assert_eq!(
+            container.output_dir,
+            PathBuf::from("/opt/rattler-build/output")
+        );
+        assert!(container
+            .host_prefix
+            .starts_with(build_dir.join("host_env")));
+        assert_eq!(container.host_prefix.as_os_str().len(), 255);
+
+        let outside = Directories {
+            output_dir: PathBuf::from("/somewhere/else"),
+            ..host
+        };
+        assert!(matches!(
+            container_directories(&outside),
+            Err(ContainerError::BuildDirOutsideOutputDir(_))
+        ));
+    }
+
+    #[test]
+    fn missing_runtime() {
+        let tools = Tools::with_path(None, Some(OsString::new()));
+        let config = ContainerConfig {
+            image: "quay.io/condaforge/linux-anvil-cos7-x86_64".to_string(),
+            runtime: None,
+            executable: None,
+        };
+        let err = find_runtime(&config, &tools).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not find `docker` or `podman` to run the build in the container image \
+             `quay.io/condaforge/linux-anvil-cos7-x86_64`"
+        );
+
+        let podman = ContainerConfig {
+            runtime: Some(ContainerRuntime::Podman),
+            ..config
+        };
+        assert!(matches!(
+            find_runtime(&podman, &tools),
+            Err(ContainerError::RuntimeNotFound { runtime, .. }) if runtime == "`podman`"
+        ));
+    }
+}
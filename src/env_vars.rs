@@ -143,7 +143,11 @@ pub fn language_vars(
 /// - LANG: Language (e.g. en_US.UTF-8)
 /// - LC_ALL: Language (e.g. en_US.UTF-8)
 /// - MAKEFLAGS: Make flags (e.g. -j4)
-pub fn os_vars(prefix: &Path, platform: &Platform) -> HashMap<String, String> {
+pub fn os_vars(
+    prefix: &Path,
+    platform: &Platform,
+    build_platform: &Platform,
+) -> HashMap<String, String> {
     let mut vars = HashMap::<String, String>::new();
 
     vars.insert(
@@ -170,9 +174,16 @@ pub fn os_vars(prefix: &Path, platform: &Platform) -> HashMap<String, String> {
     vars.insert("SHLIB_EXT".to_string(), shlib_ext);
     vars.insert("PATH".to_string(), env::var("PATH").unwrap_or_default());
 
-    if cfg!(target_family = "windows") {
+    // The build scripts are written for the target platform, so the shell specific variables
+    // follow it as well. The scripts of `noarch` packages are written for the build platform
+    // (like in `ScriptFlavor::from_platforms`).
+    let windows_flavor = match platform {
+        Platform::NoArch => build_platform.is_windows(),
+        platform => platform.is_windows(),
+    };
+    if windows_flavor {
         vars.extend(windows::env::default_env_vars(prefix, platform));
-    } else if cfg!(target_family = "unix") {
+    } else {
         vars.extend(unix::env::default_env_vars(prefix));
     }
 
@@ -191,6 +202,72 @@ macro_rules! insert {
     };
 }
 
+/// The target triple of the conda-forge compilers for `platform`
+fn compiler_triple(platform: &Platform) -> Option<&'static str> {
+    match platform {
+        Platform::Linux64 => Some("x86_64-conda-linux-gnu"),
+        Platform::LinuxAarch64 => Some("aarch64-conda-linux-gnu"),
+        Platform::LinuxPpc64le => Some("powerpc64le-conda-linux-gnu"),
+        Platform::Osx64 => Some("x86_64-apple-darwin13.4.0"),
+        Platform::OsxArm64 => Some("arm64-apple-darwin20.0.0"),
+        _ => None,
+    }
+}
+
+/// The CMake system name and processor of `platform`
+fn cmake_system(platform: &Platform) -> Option<(&'static str, &'static str)> {
+    match platform {
+        Platform::Linux64 => Some(("Linux", "x86_64")),
+        Platform::LinuxAarch64 => Some(("Linux", "aarch64")),
+        Platform::LinuxPpc64le => Some(("Linux", "ppc64le")),
+        Platform::Osx64 => Some(("Darwin", "x86_64")),
+        Platform::OsxArm64 => Some(("Darwin", "arm64")),
+        Platform::Win64 => Some(("Windows", "AMD64")),
+        Platform::WinArm64 => Some(("Windows", "ARM64")),
+        _ => None,
+    }
+}
+
+/// The compilers and the CMake arguments for a build on `build_platform` of packages for
+/// `host_platform`, none if the two are the same. The activation scripts of the compiler packages
+/// run after these are set, and override them.
+pub fn cross_compilation_vars(
+    build_platform: &Platform,
+    host_platform: &Platform,
+    host_prefix: &Path,
+) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    if build_platform == host_platform {
+        return vars;
+    }
+
+    if let Some(triple) = compiler_triple(host_platform) {
+        let (cc, cxx) = if host_platform.is_osx() {
+            ("clang", "clang++")
+        } else {
+            ("cc", "c++")
+        };
+        insert!(vars, "CC", format!("{triple}-{cc}"));
+        insert!(vars, "CXX", format!("{triple}-{cxx}"));
+    }
+
+    if let Some((system, processor)) = cmake_system(host_platform) {
+        let prefix = host_prefix.to_string_lossy();
+        let args = [
+            format!("-DCMAKE_SYSTEM_NAME={system}"),
+            format!("-DCMAKE_SYSTEM_PROCESSOR={processor}"),
+            format!("-DCMAKE_FIND_ROOT_PATH={prefix}"),
+            "-DCMAKE_FIND_ROOT_PATH_MODE_PROGRAM=NEVER".to_string(),
+            "-DCMAKE_FIND_ROOT_PATH_MODE_LIBRARY=ONLY".to_string(),
+            "-DCMAKE_FIND_ROOT_PATH_MODE_INCLUDE=ONLY".to_string(),
+            format!("-DCMAKE_INSTALL_PREFIX={prefix}"),
+        ];
+        insert!(vars, "CMAKE_ARGS", args.join(" "));
+    }
+
+    vars
+}
+
 /// Set environment variables that help to force color output.
 fn force_color_vars(platform: &Platform) -> HashMap<String, String> {
     let mut vars = HashMap::<String, String>::new();
@@ -318,6 +395,12 @@ pub fn vars(output: &Output, build_state: &str) -> HashMap<String, String> {
     );
     insert!(vars, "CONDA_BUILD_STATE", build_state);
 
+    vars.extend(cross_compilation_vars(
+        &output.build_configuration.build_platform,
+        &output.build_configuration.host_platform,
+        &directories.host_prefix,
+    ));
+
     vars.extend(language_vars(
         &directories.host_prefix,
         // Note: host_platform cannot be noarch
@@ -353,6 +436,26 @@ pub enum ScriptError {
     CreateActivation(#[from] ActivationError),
 }
 
+/// The secrets of the build script (`build.script.secrets`) and their values in the environment
+/// of rattler-build. Secrets are never written to the env script: the build script gets them in
+/// its environment, and their values are masked in the log.
+pub fn script_secrets(output: &Output) -> Vec<(String, String)> {
+    output
+        .recipe
+        .build()
+        .script()
+        .secrets()
+        .iter()
+        .filter_map(|name| match std::env::var(name) {
+            Ok(value) => Some((name.clone(), value)),
+            Err(_) => {
+                tracing::warn!("The secret `{}` of `build.script.secrets` is not set", name);
+                None
+            }
+        })
+        .collect()
+}
+
 /// Write a script that can be sourced to set the environment variables for the build process.
 /// The script will also activate the host and build prefixes.
 pub fn write_env_script<T: Shell + Clone>(
@@ -371,18 +474,29 @@ pub fn write_env_script<T: Shell + Clone>(
 
     let platform = output.build_configuration.target_platform;
 
-    let additional_os_vars = os_vars(&directories.host_prefix, &platform);
+    let additional_os_vars = os_vars(
+        &directories.host_prefix,
+        &platform,
+        &output.build_configuration.build_platform,
+    );
 
     for (k, v) in additional_os_vars {
         shell_type.set_env_var(&mut s, &k, &v)?;
     }
 
-    for (k, v) in output.recipe.build().script().env() {
+    let script = output.recipe.build().script();
+    for (k, v) in script.env() {
         shell_type.set_env_var(&mut s, k, v)?;
     }
 
-    if !output.recipe.build().script().secrets().is_empty() {
-        tracing::error!("Secrets are not supported yet");
+    for name in script.passthrough() {
+        match std::env::var(name) {
+            Ok(value) => shell_type.set_env_var(&mut s, name, &value)?,
+            Err(_) => tracing::warn!(
+                "The environment variable `{}` of `build.script.env` is not set",
+                name
+            ),
+        }
     }
 
     writeln!(out, "{}", s)?;
@@ -432,3 +546,30 @@ pub fn write_env_script<T: Shell + Clone>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use rattler_conda_types::Platform;
+
+    use super::cross_compilation_vars;
+
+    #[test]
+    fn cross_compilation() {
+        let prefix = Path::new("/host");
+        assert!(cross_compilation_vars(&Platform::Linux64, &Platform::Linux64, prefix).is_empty());
+
+        let vars = cross_compilation_vars(&Platform::Linux64, &Platform::LinuxAarch64, prefix);
+        assert_eq!(vars["CC"], "aarch64-conda-linux-gnu-cc");
+        assert_eq!(vars["CXX"], "aarch64-conda-linux-gnu-c++");
+        assert!(vars["CMAKE_ARGS"].starts_with(
+            "-DCMAKE_SYSTEM_NAME=Linux -DCMAKE_SYSTEM_PROCESSOR=aarch64 -DCMAKE_FIND_ROOT_PATH=/host "
+        ));
+
+        let vars = cross_compilation_vars(&Platform::Osx64, &Platform::OsxArm64, prefix);
+        assert_eq!(vars["CC"], "arm64-apple-darwin20.0.0-clang");
+        assert_eq!(vars["CXX"], "arm64-apple-darwin20.0.0-clang++");
+        assert!(vars["CMAKE_ARGS"].contains("-DCMAKE_SYSTEM_NAME=Darwin"));
+    }
+}
@@ -0,0 +1,281 @@
+//! Capabilities of the filesystems that a build writes to.
+//!
+//! Some filesystems (e.g. exFAT or SMB mounts) cannot create symlinks, or refuse to rename files.
+//! The output directory, the package cache and the root of the build directories are probed
+//! before the build starts. Renames fall back to a copy everywhere, and the build directories,
+//! whose prefixes contain symlinks, are moved to the temporary directory of the system if the
+//! output directory has no symlinks. Only if that is not possible either, the build fails early.
+
+use std::path::{Path, PathBuf};
+
+use fs_err as fs;
+
+/// What a filesystem supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether symlinks can be created
+    pub symlinks: bool,
+    /// Whether a file can be renamed
+    pub rename: bool,
+}
+
+/// Finds the capabilities of the filesystem of a directory
+pub trait Probe {
+    /// Probe the filesystem of `dir`, which is created if it does not exist yet. An error means
+    /// that nothing can be written to `dir`.
+    fn probe(&self, dir: &Path) -> Result<Capabilities, std::io::Error>;
+}
+
+/// Probes a filesystem by creating a symlink and renaming a file in a temporary directory
+#[derive(Debug, Default)]
+pub struct DirectoryProbe;
+
+impl Probe for DirectoryProbe {
+    fn probe(&self, dir: &Path) -> Result<Capabilities, std::io::Error> {
+        fs::create_dir_all(dir)?;
+        let tmp = tempfile::Builder::new()
+            .prefix(".rattler-build-probe")
+            .tempdir_in(dir)?;
+        let file = tmp.path().join("file");
+        fs::write(&file, "")?;
+
+        // creating symlinks on Windows needs privileges, rattler copies the files instead
+        #[cfg(unix)]
+        let symlinks = std::os::unix::fs::symlink(&file, tmp.path().join("link")).is_ok();
+        #[cfg(not(unix))]
+        let symlinks = true;
+        let rename = std::fs::rename(&file, tmp.path().join("renamed")).is_ok();
+
+        Ok(Capabilities { symlinks, rename })
+    }
+}
+
+#[allow(missing_docs)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum FilesystemError {
+    #[error("Cannot write to the {role} {}: {source}", dir.display())]
+    #[diagnostic(help("check that the directory is writable"))]
+    NotWritable {
+        role: &'static str,
+        dir: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("The filesystem of the {role} {} does not support symlinks", dir.display())]
+    #[diagnostic(help("{help}"))]
+    NoSymlinks {
+        role: &'static str,
+        dir: PathBuf,
+        help: &'static str,
+    },
+}
+
+fn probe_dir(
+    probe: &dyn Probe,
+    role: &'static str,
+    dir: &Path,
+) -> Result<Capabilities, FilesystemError> {
+    let capabilities = probe
+        .probe(dir)
+        .map_err(|source| FilesystemError::NotWritable {
+            role,
+            dir: dir.to_path_buf(),
+            source,
+        })?;
+    if !capabilities.rename {
+        tracing::warn!(
+            "The filesystem of the {} {} does not support renames, files are copied instead",
+            role,
+            dir.display()
+        );
+    }
+    Ok(capabilities)
+}
+
+/// Probe the `output_dir` and the `package_cache`, and return the directory in which the build
+/// directories are created: `bld` in the output directory, or a directory in the `temp_dir` if
+/// the output directory has no symlinks.
+pub fn check_build_roots(
+    probe: &dyn Probe,
+    output_dir: &Path,
+    package_cache: Option<&Path>,
+    temp_dir: &Path,
+) -> Result<PathBuf, FilesystemError> {
+    if let Some(package_cache) = package_cache {
+        // the packages that are extracted into the cache contain symlinks
+        if !probe_dir(probe, "package cache", package_cache)?.symlinks {
+            return Err(FilesystemError::NoSymlinks {
+                role: "package cache",
+                dir: package_cache.to_path_buf(),
+                help: "move the cache directory to a filesystem with symlinks (on Linux with `XDG_CACHE_HOME`)",
+            });
+        }
+    }
+
+    let output = probe_dir(probe, "output directory", output_dir)?;
+    let build_root = output_dir.join("bld");
+    if output.symlinks {
+        return Ok(build_root);
+    }
+
+    let fallback = temp_dir.join("rattler-build-bld");
+    match probe.probe(&fallback) {
+        Ok(capabilities) if capabilities.symlinks => {
+            tracing::warn!(
+                "The filesystem of the output directory {} does not support symlinks, which the build and host prefixes need. The build directories are created in {} instead.",
+                output_dir.display(),
+                fallback.display()
+            );
+            Ok(fallback)
+        }
+        _ => Err(FilesystemError::NoSymlinks {
+            role: "output directory",
+            dir: output_dir.to_path_buf(),
+            help: "select an output directory on a filesystem with symlinks (e.g. not exFAT or SMB) with `--output-dir`",
+        }),
+    }
+}
+
+/// Rename `from` to `to`, or copy it (and delete `from`) if the filesystem cannot rename it
+pub fn rename(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    let err = match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    tracing::debug!("Renaming failed, copying instead: {}", err);
+
+    let copied = if from.is_dir() {
+        let options = fs_extra::dir::CopyOptions::new().content_only(true);
+        fs::create_dir_all(to)
+            .and_then(|_| {
+                fs_extra::dir::copy(from, to, &options)
+                    .map(|_| ())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            })
+            .and_then(|_| fs::remove_dir_all(from))
+    } else {
+        fs::copy(from, to).and_then(|_| fs::remove_file(from))
+    };
+    // report the original error if copying fails as well
+    copied.map_err(|_| err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
+
+    use super::{check_build_roots, rename, Capabilities, FilesystemError, Probe};
+
+    /// A probe with fixed capabilities per directory. Missing directories are not writable.
+    struct FakeProbe(HashMap<PathBuf, Capabilities>);
+
+    impl Probe for FakeProbe {
+        fn probe(&self, dir: &Path) -> Result<Capabilities, std::io::Error> {
+            self.0.get(dir).copied().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read-only")
+            })
+        }
+    }
+
+    const FULL: Capabilities = Capabilities {
+        symlinks: true,
+        rename: true,
+    };
+    const EXFAT: Capabilities = Capabilities {
+        symlinks: false,
+        rename: false,
+    };
+
+    fn probe(dirs: &[(&str, Capabilities)]) -> FakeProbe {
+        FakeProbe(
+            dirs.iter()
+                .map(|(dir, capabilities)| (PathBuf::from(dir), *capabilities))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn build_roots() {
+        let check = |probe: &FakeProbe| {
+            check_build_roots(
+                probe,
+                Path::new("/output"),
+                Some(Path::new("/cache")),
+                Path::new("/tmp"),
+            )
+        };
+
+        let full = probe(&[("/output", FULL), ("/cache", FULL)]);
+        assert_eq!(check(&full).unwrap(), PathBuf::from("/output/bld"));
+
+        // the build directories move to the temporary directory
+        let exfat = probe(&[
+            ("/output", EXFAT),
+            ("/cache", FULL),
+            ("/tmp/rattler-build-bld", FULL),
+        ]);
+        assert_eq!(
+            check(&exfat).unwrap(),
+            PathBuf::from("/tmp/rattler-build-bld")
+        );
+
+        let no_fallback = probe(&[
+            ("/output", EXFAT),
+            ("/cache", FULL),
+            ("/tmp/rattler-build-bld", EXFAT),
+        ]);
+        assert!(matches!(
+            check(&no_fallback),
+            Err(FilesystemError::NoSymlinks {
+                role: "output directory",
+                ..
+            })
+        ));
+
+        let exfat_cache = probe(&[("/output", FULL), ("/cache", EXFAT)]);
+        assert!(matches!(
+            check(&exfat_cache),
+            Err(FilesystemError::NoSymlinks {
+                role: "package cache",
+                ..
+            })
+        ));
+
+        let read_only = probe(&[("/cache", FULL)]);
+        assert!(matches!(
+            check(&read_only),
+            Err(FilesystemError::NotWritable {
+                role: "output directory",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn directory_probe() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("output");
+        let capabilities = super::DirectoryProbe.probe(&dir).unwrap();
+        assert!(capabilities.rename);
+        assert!(capabilities.symlinks);
+        // the probe cleans up after itself
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn rename_files_and_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("dir/sub")).unwrap();
+        std::fs::write(tmp.path().join("dir/sub/file.txt"), "content").unwrap();
+
+        rename(&tmp.path().join("dir"), &tmp.path().join("renamed")).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("renamed/sub/file.txt")).unwrap(),
+            "content"
+        );
+        assert!(rename(&tmp.path().join("dir"), &tmp.path().join("other")).is_err());
+    }
+}
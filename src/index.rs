@@ -1,28 +1,24 @@
 //! Indexing of packages in a output folder to create up to date repodata.json files
 use rattler_conda_types::package::ArchiveType;
 use rattler_conda_types::package::IndexJson;
-use rattler_conda_types::package::PackageFile;
 use rattler_conda_types::ChannelInfo;
 use rattler_conda_types::PackageRecord;
 use rattler_conda_types::Platform;
 use rattler_conda_types::RepoData;
-use rattler_package_streaming::read;
-use rattler_package_streaming::seek;
 
-use fs_err::File;
-use std::ffi::OsStr;
-use std::io::Read;
+use crate::package_inspect::PackageInspector;
+use fs_err as fs;
+use fslock::LockFile;
+use std::collections::BTreeSet;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
-use walkdir::WalkDir;
 
-fn package_record_from_index_json<T: Read>(
+/// Create the repodata record of the package archive at `file` from its `index.json`
+pub(crate) fn package_record_from_index_json(
     file: &Path,
-    index_json_reader: &mut T,
+    index: IndexJson,
 ) -> Result<PackageRecord, std::io::Error> {
-    let index = IndexJson::from_reader(index_json_reader)?;
-
     let sha256_result = rattler_digest::compute_file_digest::<rattler_digest::Sha256>(file)?;
     let md5_result = rattler_digest::compute_file_digest::<rattler_digest::Md5>(file)?;
     let size = std::fs::metadata(file)?.len();
@@ -53,143 +49,223 @@ fn package_record_from_index_json<T: Read>(
     Ok(package_record)
 }
 
-fn package_record_from_tar_bz2(file: &Path) -> Result<PackageRecord, std::io::Error> {
-    let reader = std::fs::File::open(file)?;
-    let mut archive = read::stream_tar_bz2(reader);
-    for entry in archive.entries()?.flatten() {
-        let mut entry = entry;
-        let path = entry.path()?;
-        if path.as_os_str().eq("info/index.json") {
-            return package_record_from_index_json(file, &mut entry);
-        }
-    }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "No index.json found",
-    ))
+fn package_record(file: &Path) -> Result<PackageRecord, std::io::Error> {
+    let index = PackageInspector::open(file)
+        .and_then(|package| package.index_json())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    package_record_from_index_json(file, index)
 }
 
-fn package_record_from_conda(file: &Path) -> Result<PackageRecord, std::io::Error> {
-    let reader = std::fs::File::open(file)?;
-    let mut archive = seek::stream_conda_info(reader).expect("Could not open conda file");
-
-    for entry in archive.entries()?.flatten() {
-        let mut entry = entry;
-        let path = entry.path()?;
-        if path.as_os_str().eq("info/index.json") {
-            return package_record_from_index_json(file, &mut entry);
-        }
-    }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "No index.json found",
-    ))
-}
+/// The lock of a subdir, which is held while its `repodata.json` is written
+const LOCK_FILE: &str = ".repodata.lock";
 
 /// Create a new `repodata.json` for all packages in the given output folder. If `target_platform` is
-/// `Some`, only that specific subdir is indexed. Otherwise indexes all subdirs and creates a
-/// `repodata.json` for each.
+/// `Some`, only that subdir and `noarch` are indexed. Otherwise indexes all subdirs that contain
+/// packages and creates a `repodata.json` for each. Only the folders that are named after a
+/// platform are subdirs (and not e.g. the source cache in the output folder).
 pub fn index(
     output_folder: &Path,
     target_platform: Option<&Platform>,
 ) -> Result<(), std::io::Error> {
-    let entries = WalkDir::new(output_folder).into_iter();
-    let entries: Vec<(PathBuf, ArchiveType)> = entries
-        .filter_entry(|e| e.depth() <= 2)
-        .filter_map(|e| e.ok())
-        .filter_map(|e| {
-            ArchiveType::split_str(e.path().to_string_lossy().as_ref())
-                .map(|(p, t)| (PathBuf::from(format!("{}{}", p, t.extension())), t))
-        })
-        .collect();
-
-    // find all subdirs
-    let mut platforms = entries
-        .iter()
-        .filter_map(|(p, _)| {
-            p.parent()
-                .and_then(|parent| parent.file_name())
-                .and_then(|file_name| {
-                    let name = file_name.to_string_lossy().to_string();
-                    if name != "src_cache" {
-                        Some(name)
-                    } else {
-                        None
-                    }
-                })
-        })
-        .collect::<std::collections::HashSet<_>>();
-
-    // Always create noarch subdir
-    if !output_folder.join("noarch").exists() {
-        std::fs::create_dir(output_folder.join("noarch"))?;
-        platforms.insert("noarch".to_string());
+    let mut subdirs = BTreeSet::from(["noarch".to_string()]);
+    match target_platform {
+        Some(target_platform) => {
+            subdirs.insert(target_platform.to_string());
+        }
+        None => {
+            for entry in fs::read_dir(output_folder)? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                if name.parse::<Platform>().is_ok()
+                    && path.is_dir()
+                    && !package_files(&path)?.is_empty()
+                {
+                    subdirs.insert(name);
+                }
+            }
+        }
+    }
+
+    for subdir in subdirs {
+        let subdir_path = output_folder.join(&subdir);
+        fs::create_dir_all(&subdir_path)?;
+        index_subdir(&subdir_path, &subdir)?;
     }
 
-    // Create target platform dir if needed
-    if let Some(target_platform) = target_platform {
-        let platform_str = target_platform.to_string();
-        if !output_folder.join(&platform_str).exists() {
-            std::fs::create_dir(output_folder.join(&platform_str))?;
-            platforms.insert(platform_str);
+    Ok(())
+}
+
+/// The package archives in `dir`
+fn package_files(dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && archive_type(&path).is_some() {
+            files.push(path);
         }
     }
+    files.sort();
+    Ok(files)
+}
+
+/// The archive type of the package at `path`
+fn archive_type(path: &Path) -> Option<ArchiveType> {
+    ArchiveType::split_str(path.to_string_lossy().as_ref()).map(|(_, archive_type)| archive_type)
+}
+
+/// Write the `repodata.json` of the subdir at `path`. Concurrent builds that share the output
+/// folder wait for each other, and the file is replaced at once, so that it is never read
+/// half-written. The records of the previous `repodata.json` are reused for the packages that
+/// did not change since it was written.
+fn index_subdir(path: &Path, subdir: &str) -> Result<(), std::io::Error> {
+    let mut lock = LockFile::open(&path.join(LOCK_FILE))?;
+    if !lock.try_lock()? {
+        tracing::info!("Waiting for another build that indexes {}", path.display());
+        lock.lock()?;
+    }
+
+    let repodata_path = path.join("repodata.json");
+    let previous = fs::metadata(&repodata_path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| {
+            let content = fs::read_to_string(&repodata_path).ok()?;
+            let repodata = serde_json::from_str::<RepoData>(&content).ok()?;
+            Some((modified, repodata))
+        });
 
-    for platform in platforms {
-        if let Some(target_platform) = target_platform {
-            if platform != target_platform.to_string() {
-                if platform != "noarch" {
+    let mut repodata = RepoData {
+        info: Some(ChannelInfo {
+            subdir: subdir.to_string(),
+            base_url: None,
+        }),
+        packages: Default::default(),
+        conda_packages: Default::default(),
+        removed: Default::default(),
+        version: Some(1),
+    };
+
+    let mut reused = 0;
+    for file in package_files(path)? {
+        let file_name = file.file_name().unwrap().to_string_lossy().to_string();
+        let metadata = fs::metadata(&file)?;
+        let unchanged = previous.as_ref().and_then(|(written, previous)| {
+            let record = previous
+                .conda_packages
+                .get(&file_name)
+                .or_else(|| previous.packages.get(&file_name))?;
+            let modified = metadata.modified().ok()?;
+            (modified < *written && record.size == Some(metadata.len())).then(|| record.clone())
+        });
+        let record = match unchanged {
+            Some(record) => {
+                reused += 1;
+                record
+            }
+            None => match package_record(&file) {
+                Ok(record) => record,
+                Err(_) => {
+                    tracing::info!("Could not read package record from {:?}", file);
                     continue;
-                } else {
-                    // check that noarch is already indexed if it is not the target platform
-                    if output_folder.join("noarch/repodata.json").exists() {
-                        continue;
-                    }
                 }
-            }
-        }
-
-        let mut repodata = RepoData {
-            info: Some(ChannelInfo {
-                subdir: platform.clone(),
-                base_url: None,
-            }),
-            packages: Default::default(),
-            conda_packages: Default::default(),
-            removed: Default::default(),
-            version: Some(1),
+            },
         };
+        match archive_type(&file) {
+            Some(ArchiveType::TarBz2) => repodata.packages.insert(file_name, record),
+            _ => repodata.conda_packages.insert(file_name, record),
+        };
+    }
+    tracing::debug!(
+        "Indexed {} ({} of {} packages unchanged)",
+        path.display(),
+        reused,
+        repodata.packages.len() + repodata.conda_packages.len()
+    );
 
-        for (p, t) in entries.iter().filter_map(|(p, t)| {
-            p.parent().and_then(|parent| {
-                parent.file_name().and_then(|file_name| {
-                    if file_name == OsStr::new(&platform) {
-                        // If the file_name is the platform we're looking for, return Some((p, t))
-                        Some((p, t))
-                    } else {
-                        // Otherwise, we return None to filter out this item
-                        None
-                    }
-                })
-            })
-        }) {
-            let record = match t {
-                ArchiveType::TarBz2 => package_record_from_tar_bz2(p),
-                ArchiveType::Conda => package_record_from_conda(p),
-            };
-            let (Ok(record), Some(file_name)) = (record, p.file_name()) else {
-                tracing::info!("Could not read package record from {:?}", p);
-                continue;
-            };
-            repodata
-                .conda_packages
-                .insert(file_name.to_string_lossy().to_string(), record);
+    let mut file = tempfile::NamedTempFile::new_in(path)?;
+    file.write_all(serde_json::to_string_pretty(&repodata)?.as_bytes())?;
+    crate::source::cache::persist_file(file, &repodata_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use filetime::FileTime;
+    use rattler_conda_types::{Platform, RepoData};
+
+    use super::index;
+
+    fn read_repodata(path: &std::path::Path) -> RepoData {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn target_and_noarch_subdirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("osx-64")).unwrap();
+
+        index(tmp.path(), Some(&Platform::Linux64)).unwrap();
+        assert!(tmp.path().join("linux-64/repodata.json").exists());
+        assert!(tmp.path().join("noarch/repodata.json").exists());
+        assert!(!tmp.path().join("osx-64/repodata.json").exists());
+        let repodata = read_repodata(&tmp.path().join("linux-64/repodata.json"));
+        assert_eq!(repodata.info.unwrap().subdir, "linux-64");
+    }
+
+    #[test]
+    fn only_platform_subdirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        for dir in ["osx-64", "src_cache"] {
+            std::fs::create_dir(tmp.path().join(dir)).unwrap();
+            std::fs::write(tmp.path().join(dir).join("foo-1.0-0.tar.bz2"), "package").unwrap();
         }
-        let out_file = output_folder.join(platform).join("repodata.json");
-        File::create(&out_file)?.write_all(serde_json::to_string_pretty(&repodata)?.as_bytes())?;
+
+        index(tmp.path(), None).unwrap();
+        assert!(tmp.path().join("osx-64/repodata.json").exists());
+        assert!(tmp.path().join("noarch/repodata.json").exists());
+        assert!(!tmp.path().join("src_cache/repodata.json").exists());
     }
 
-    Ok(())
-}
+    #[test]
+    fn unchanged_packages_are_not_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let noarch = tmp.path().join("noarch");
+        std::fs::create_dir(&noarch).unwrap();
+
+        // not a valid archive, its record can only come from the previous repodata
+        let package = noarch.join("foo-1.0-0.tar.bz2");
+        std::fs::write(&package, "package").unwrap();
+        let past = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        filetime::set_file_mtime(&package, past).unwrap();
+
+        let record = serde_json::json!({
+            "name": "foo",
+            "version": "1.0",
+            "build": "0",
+            "build_number": 0,
+            "subdir": "noarch",
+            "depends": [],
+            "size": 7,
+        });
+        let previous = serde_json::json!({
+            "info": {"subdir": "noarch"},
+            "packages": {"foo-1.0-0.tar.bz2": record},
+            "packages.conda": {},
+            "removed": [],
+        });
+        std::fs::write(noarch.join("repodata.json"), previous.to_string()).unwrap();
 
-// TODO: write proper unit tests for above functions
+        index(tmp.path(), Some(&Platform::NoArch)).unwrap();
+        let repodata = read_repodata(&noarch.join("repodata.json"));
+        assert!(repodata.packages.contains_key("foo-1.0-0.tar.bz2"));
+
+        // a package that changed since is read again (and dropped, as it is not an archive)
+        std::fs::write(&package, "changed").unwrap();
+        filetime::set_file_mtime(&package, FileTime::now()).unwrap();
+        filetime::set_file_mtime(noarch.join("repodata.json"), past).unwrap();
+        index(tmp.path(), Some(&Platform::NoArch)).unwrap();
+        let repodata = read_repodata(&noarch.join("repodata.json"));
+        assert!(repodata.packages.is_empty());
+    }
+}
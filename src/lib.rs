@@ -2,14 +2,31 @@
 
 //! The library pieces of rattler-build
 
+pub mod bandwidth;
 pub mod build;
+pub mod build_report;
+pub mod cancellation;
+pub mod channel_query;
+pub mod ci_log;
+pub mod container;
+pub mod filesystem;
+pub mod log_stream;
 pub mod metadata;
+pub mod network;
+pub mod output_files;
+pub mod output_metadata;
+pub mod package_inspect;
+pub mod profiles;
+pub mod progress;
 pub mod recipe;
 pub mod render;
 pub mod selectors;
+pub mod skip_existing;
 pub mod source;
+pub mod summary;
 pub mod test;
 pub mod tool_configuration;
+pub mod tools;
 pub mod used_variables;
 pub mod variant_config;
 
@@ -7,10 +7,14 @@ use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 
+use crate::tools::{Tool, ToolNotFound, Tools};
+
 /// A linux shared object (ELF)
 pub struct SharedObject {
     /// Path to the shared object
     pub path: PathBuf,
+    /// The soname of the shared object (if any)
+    pub soname: Option<String>,
     /// Libraries that this shared object depends on
     pub libraries: HashSet<String>,
     /// RPATH entries
@@ -19,7 +23,7 @@ pub struct SharedObject {
     pub runpaths: Vec<String>,
 }
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, miette::Diagnostic, Debug)]
 pub enum RelinkError {
     #[error("non-absolute or non-normalized base path")]
     PathDiffFailed,
@@ -30,8 +34,9 @@ pub enum RelinkError {
     #[error("failed to run patchelf")]
     PatchElfFailed,
 
-    #[error("failed to find patchelf: please install patchelf on your system")]
-    PatchElfNotFound(#[from] which::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    PatchElfNotFound(#[from] ToolNotFound),
 
     #[error("failed to read or write elf file: {0}")]
     IoError(#[from] std::io::Error),
@@ -55,13 +60,13 @@ impl SharedObject {
     /// Create a new shared object from a path
     pub fn new(path: &Path) -> Result<Self, RelinkError> {
         let mut buffer = Vec::new();
-        let mut file = File::open(path).expect("Failed to open the DLL file");
-        file.read_to_end(&mut buffer)
-            .expect("Failed to read the DLL file");
-        let elf = Elf::parse(&buffer).expect("Failed to parse the ELF file");
+        let mut file = File::open(path)?;
+        file.read_to_end(&mut buffer)?;
+        let elf = Elf::parse(&buffer)?;
 
         Ok(Self {
             path: path.to_path_buf(),
+            soname: elf.soname.map(|s| s.to_string()),
             libraries: elf.libraries.iter().map(|s| s.to_string()).collect(),
             rpaths: elf.rpaths.iter().map(|s| s.to_string()).collect(),
             runpaths: elf.runpaths.iter().map(|s| s.to_string()).collect(),
@@ -71,7 +76,12 @@ impl SharedObject {
     /// find all RPATH and RUNPATH entries
     /// replace them with the encoded prefix
     /// if the prefix is not found, add it to the end of the list
-    pub fn relink(&self, prefix: &Path, encoded_prefix: &Path) -> Result<(), RelinkError> {
+    pub fn relink(
+        &self,
+        prefix: &Path,
+        encoded_prefix: &Path,
+        tools: &Tools,
+    ) -> Result<(), RelinkError> {
         let rpaths = self
             .rpaths
             .iter()
@@ -109,18 +119,26 @@ impl SharedObject {
         // keep only first unique item
         final_rpath = final_rpath.into_iter().unique().collect();
 
-        call_patchelf(&self.path, &final_rpath)?;
+        // binaries without an rpath are left untouched
+        if final_rpath.is_empty() {
+            return Ok(());
+        }
+
+        call_patchelf(&self.path, &final_rpath, tools)?;
 
         Ok(())
     }
 }
 
-fn call_patchelf(elf_path: &Path, new_rpath: &[PathBuf]) -> Result<(), RelinkError> {
+fn call_patchelf(elf_path: &Path, new_rpath: &[PathBuf], tools: &Tools) -> Result<(), RelinkError> {
     let new_rpath = new_rpath.iter().map(|p| p.to_string_lossy()).join(":");
 
     tracing::info!("patchelf for {:?}: {:?}", elf_path, new_rpath);
 
-    let patchelf_exe = which::which("patchelf")?;
+    let patchelf_exe = tools.find(
+        Tool::Patchelf,
+        format_args!("relink `{}`", elf_path.display()),
+    )?;
 
     let mut cmd = std::process::Command::new(patchelf_exe);
 
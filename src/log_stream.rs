@@ -0,0 +1,254 @@
+//! Streaming of the build log to library consumers (e.g. a web UI that shows the live output).
+//!
+//! If [`Configuration::log_sender`](crate::tool_configuration::Configuration::log_sender) is
+//! set, two kinds of lines are sent to it, in addition to the normal logging:
+//!
+//! - the start of every build phase (fetching the sources, resolving the dependencies,
+//!   installing the environments, running the build script, packaging and testing), as a
+//!   [`LogStream::Message`] with the title of the phase
+//! - every line of the standard output and error of the build script, with
+//!   [`BuildPhase::Script`]
+//!
+//! The log messages of rattler-build itself during the other phases (e.g. the downloads of the
+//! sources or the solved environments) and the output of the test commands are not streamed,
+//! they only go to the normal logging.
+//!
+//! The build never waits for the consumer: lines that do not fit into the channel are dropped
+//! and counted, and the consumer is told how many lines it missed as soon as there is room
+//! again.
+//!
+//! ```no_run
+//! # async fn build(output: rattler_build::metadata::Output) -> miette::Result<()> {
+//! use rattler_build::{log_stream::LogLine, tool_configuration::Configuration};
+//!
+//! let (sender, mut receiver) = tokio::sync::mpsc::channel::<LogLine>(1024);
+//! tokio::spawn(async move {
+//!     while let Some(line) = receiver.recv().await {
+//!         println!("[{}] {:?}: {}", line.phase.title(), line.stream, line.line);
+//!     }
+//! });
+//!
+//! let configuration = Configuration {
+//!     log_sender: Some(sender),
+//!     ..Default::default()
+//! };
+//! rattler_build::build::run_build(&output, configuration).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::ci_log::BuildPhase;
+
+/// Where a line of the log comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStream {
+    /// The standard output of a process
+    Stdout,
+    /// The standard error of a process
+    Stderr,
+    /// A message of rattler-build itself (e.g. the start of a phase, or dropped lines)
+    Message,
+}
+
+/// A single line of the build log
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    /// The text of the line, without the line break
+    pub line: String,
+    /// Where the line comes from
+    pub stream: LogStream,
+    /// The build phase that produced the line
+    pub phase: BuildPhase,
+    /// When the line was produced
+    pub timestamp: DateTime<Utc>,
+}
+
+impl LogLine {
+    fn new(line: impl Into<String>, stream: LogStream, phase: BuildPhase) -> Self {
+        Self {
+            line: line.into(),
+            stream,
+            phase,
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// Sends the lines of one phase (or one stream of a process) to the consumer without blocking
+pub struct LogForwarder {
+    sender: Option<Sender<LogLine>>,
+    phase: BuildPhase,
+    /// The lines that were dropped since the last line that was sent
+    pending_drops: usize,
+    /// All lines that were dropped
+    dropped: usize,
+}
+
+impl LogForwarder {
+    /// A forwarder for the lines of `phase`. Without a `sender`, all lines are discarded.
+    pub fn new(sender: Option<&Sender<LogLine>>, phase: BuildPhase) -> Self {
+        Self {
+            sender: sender.cloned(),
+            phase,
+            pending_drops: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Announce the start of the phase to the consumer
+    pub fn phase_started(sender: Option<&Sender<LogLine>>, phase: BuildPhase) {
+        Self::new(sender, phase).send(LogStream::Message, phase.title());
+    }
+
+    fn try_send(&mut self, line: LogLine) -> bool {
+        let Some(sender) = &self.sender else {
+            return false;
+        };
+        match sender.try_send(line) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Closed(_)) => {
+                // nobody is listening anymore
+                self.sender = None;
+                false
+            }
+        }
+    }
+
+    /// Send a line to the consumer, or count it as dropped if the channel is full
+    pub fn send(&mut self, stream: LogStream, line: &str) {
+        if self.sender.is_none() {
+            return;
+        }
+        if self.pending_drops > 0 {
+            let note = LogLine::new(
+                format!("[{} lines dropped]", self.pending_drops),
+                LogStream::Message,
+                self.phase,
+            );
+            if !self.try_send(note) {
+                self.count_drop();
+                return;
+            }
+            self.pending_drops = 0;
+        }
+        if !self.try_send(LogLine::new(line, stream, self.phase)) {
+            self.count_drop();
+        }
+    }
+
+    fn count_drop(&mut self) {
+        // lines for a closed channel are not dropped, they have no consumer
+        if self.sender.is_some() {
+            self.pending_drops += 1;
+            self.dropped += 1;
+        }
+    }
+
+    /// Tell the consumer about the last dropped lines (if there is room) and return how many
+    /// lines were dropped in total
+    pub fn finish(mut self) -> usize {
+        if self.pending_drops > 0 {
+            let note = LogLine::new(
+                format!("[{} lines dropped]", self.pending_drops),
+                LogStream::Message,
+                self.phase,
+            );
+            self.try_send(note);
+        }
+        if self.dropped > 0 {
+            tracing::warn!(
+                "{} lines of the build log were not streamed because the consumer was too slow",
+                self.dropped
+            );
+        }
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LogForwarder, LogStream};
+    use crate::ci_log::BuildPhase;
+
+    #[tokio::test]
+    async fn order_and_drops() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+        let producer = std::thread::spawn(move || {
+            let mut forwarder = LogForwarder::new(Some(&sender), BuildPhase::Script);
+            for i in 0..100 {
+                forwarder.send(LogStream::Stdout, &format!("line {i}"));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            forwarder.finish()
+        });
+
+        // a slow consumer
+        let mut received = Vec::new();
+        while let Some(line) = receiver.recv().await {
+            received.push(line);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let dropped = producer.join().unwrap();
+        assert!(dropped > 0);
+
+        let lines = received
+            .iter()
+            .filter(|line| line.stream == LogStream::Stdout)
+            .map(|line| {
+                line.line
+                    .trim_start_matches("line ")
+                    .parse::<usize>()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(lines.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(received.iter().all(|line| line.phase == BuildPhase::Script));
+
+        // every dropped line is accounted for, either directly or in a note
+        let noted = received
+            .iter()
+            .filter(|line| line.stream == LogStream::Message)
+            .map(|line| {
+                line.line
+                    .trim_start_matches('[')
+                    .trim_end_matches(" lines dropped]")
+                    .parse::<usize>()
+                    .unwrap()
+            })
+            .sum::<usize>();
+        assert_eq!(lines.len() + dropped, 100);
+        assert!(noted <= dropped);
+        assert!(noted > 0);
+    }
+
+    #[test]
+    fn phase_start() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+        LogForwarder::phase_started(Some(&sender), BuildPhase::Package);
+        let line = receiver.try_recv().unwrap();
+        assert_eq!(line.stream, LogStream::Message);
+        assert_eq!(line.phase, BuildPhase::Package);
+        assert_eq!(line.line, BuildPhase::Package.title());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn without_consumer() {
+        let mut forwarder = LogForwarder::new(None, BuildPhase::Script);
+        forwarder.send(LogStream::Stdout, "line");
+        assert_eq!(forwarder.finish(), 0);
+
+        // a closed channel does not count as dropped lines
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        drop(receiver);
+        let mut forwarder = LogForwarder::new(Some(&sender), BuildPhase::Script);
+        forwarder.send(LogStream::Stdout, "line");
+        assert_eq!(forwarder.finish(), 0);
+    }
+}
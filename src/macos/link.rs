@@ -6,6 +6,8 @@ use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use crate::tools::{Tool, ToolNotFound, Tools};
+
 /// A macOS dylib (Mach-O)
 pub struct Dylib {
     /// Path to the dylib
@@ -18,15 +20,14 @@ pub struct Dylib {
     pub libraries: Vec<PathBuf>,
 }
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, miette::Diagnostic, Debug)]
 pub enum RelinkError {
     #[error("failed to run install_name_tool")]
     InstallNameToolFailed,
 
-    #[error(
-        "failed to find install_name_tool: please install xcode / install_name_tool on your system"
-    )]
-    InstallNameToolNotFound(#[from] which::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    InstallNameToolNotFound(#[from] ToolNotFound),
 
     #[error("failed to read or write MachO file: {0}")]
     IoError(#[from] std::io::Error),
@@ -104,7 +105,13 @@ impl Dylib {
     /// * `dylib_path` - Path to the dylib to modify
     /// * `prefix` - The prefix of the file (usually a temporary directory)
     /// * `encoded_prefix` - The prefix of the file as encoded in the dylib at build time (e.g. the host prefix)
-    pub fn relink(&self, prefix: &Path, encoded_prefix: &Path) -> Result<(), RelinkError> {
+    /// * `tools` - Used to find `install_name_tool`
+    pub fn relink(
+        &self,
+        prefix: &Path,
+        encoded_prefix: &Path,
+        tools: &Tools,
+    ) -> Result<(), RelinkError> {
         let mut changes = DylibChanges::default();
         let mut modified = false;
         for rpath in &self.rpaths {
@@ -155,7 +162,7 @@ impl Dylib {
         }
 
         if modified {
-            install_name_tool(&self.path, &changes)?;
+            install_name_tool(&self.path, &changes, tools)?;
             codesign(&self.path)?;
         }
 
@@ -191,10 +198,17 @@ struct DylibChanges {
     change_dylib: Vec<(PathBuf, PathBuf)>,
 }
 
-fn install_name_tool(dylib_path: &Path, changes: &DylibChanges) -> Result<(), RelinkError> {
+fn install_name_tool(
+    dylib_path: &Path,
+    changes: &DylibChanges,
+    tools: &Tools,
+) -> Result<(), RelinkError> {
     tracing::info!("install_name_tool for {:?}: {:?}", dylib_path, changes);
 
-    let install_name_tool_exe = which::which("install_name_tool")?;
+    let install_name_tool_exe = tools.find(
+        Tool::InstallNameTool,
+        format_args!("relink `{}`", dylib_path.display()),
+    )?;
 
     let mut cmd = std::process::Command::new(install_name_tool_exe);
 
@@ -1,20 +1,23 @@
 //! This is the main entry point for the `rattler-build` binary.
 
-use clap::{arg, crate_version, Parser};
+use clap::{arg, crate_version, ArgGroup, Parser};
 
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use dunce::canonicalize;
 use fs_err as fs;
-use indicatif::MultiProgress;
+use indicatif::{HumanBytes, MultiProgress};
 use miette::IntoDiagnostic;
-use rattler_conda_types::{package::ArchiveType, Platform};
+use rattler_conda_types::Platform;
 use rattler_networking::AuthenticatedClient;
 use std::{
     collections::BTreeMap,
     env::current_dir,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::{self, FromStr},
+    sync::Arc,
+    time::Duration,
 };
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{
     filter::{Directive, ParseError},
     fmt,
@@ -23,14 +26,34 @@ use tracing_subscriber::{
 };
 
 use rattler_build::{
-    build::run_build,
+    bandwidth::BandwidthLimiter,
+    build::{render_build_script, run_build, run_build_with_fetched_sources, BuildOutcome},
+    cancellation::cancel_on_signals,
+    ci_log::CiLogStyle,
+    container::{ContainerConfig, ContainerRuntime, CONTAINER_OUTPUT_DIR},
+    filesystem::{check_build_roots, DirectoryProbe, FilesystemError},
     hash::HashInfo,
     metadata::{BuildConfiguration, Directories, PackageIdentifier},
-    recipe::{parser::Recipe, ParsingError},
+    output_metadata::output_metadata,
+    profiles::{self, ConfigFile, PackageFormat, Settings},
+    progress::ProgressOutput,
+    recipe::{
+        parser::{Recipe, SourceFetch},
+        ParsingError,
+    },
+    render::{
+        integrity::PrefixVerification,
+        lock::{apply_lock_files, LockFile, LockFileError},
+        resolved_dependencies::{finalize_outputs, recipe_requirements},
+    },
     selectors::SelectorConfig,
+    skip_existing::SkipExisting,
+    source::{cache, fetch_sources, FetchedSources},
+    summary::{dependency_names, BuildStatus, BuildSummary},
     test::{self, TestConfiguration},
     tool_configuration,
-    variant_config::VariantConfig,
+    tools::Tools,
+    variant_config::{variant_config_files, VariantConfig},
 };
 
 mod console_utils;
@@ -48,6 +71,13 @@ enum SubCommands {
 
     /// Rebuild a package
     Rebuild(RebuildOpts),
+
+    /// Continue a build inside of a container (started by `build --container-image`)
+    #[clap(hide = true)]
+    BuildInContainer(BuildInContainerOpts),
+
+    /// Remove old or least recently used entries from the source cache
+    CleanCache(CleanCacheOpts),
 }
 
 #[derive(Parser)]
@@ -60,12 +90,6 @@ struct App {
     verbose: Verbosity<InfoLevel>,
 }
 
-#[derive(clap::ValueEnum, Clone)]
-enum PackageFormat {
-    TarBz2,
-    Conda,
-}
-
 /// Common opts that are shared between [`Rebuild`] and [`Build`]` subcommands
 #[derive(Parser)]
 struct CommonOpts {
@@ -73,16 +97,147 @@ struct CommonOpts {
     #[clap(long, env = "CONDA_BLD_PATH")]
     output_dir: Option<PathBuf>,
 
-    /// Enable support for repodata.json.zst
-    #[clap(long, env = "RATTLER_ZSTD", default_value = "true", hide = true)]
-    use_zstd: bool,
+    /// Use the settings of this profile of the configuration file. Environment variables and
+    /// command line options override them.
+    #[clap(long)]
+    profile: Option<String>,
+
+    /// The configuration file with the profiles. Defaults to
+    /// `~/.config/rattler-build/config.yaml`.
+    #[clap(long, env = profiles::CONFIG_FILE_ENV)]
+    config_file: Option<PathBuf>,
+
+    /// Enable support for repodata.json.zst (env: RATTLER_ZSTD)
+    #[clap(long, hide = true)]
+    use_zstd: Option<bool>,
+
+    /// Enable support for repodata.json.bz2 (env: RATTLER_BZ2)
+    #[clap(long, hide = true)]
+    use_bz2: Option<bool>,
+
+    /// Only use repodata from the cache and never fetch it from the network (env:
+    /// RATTLER_OFFLINE)
+    #[clap(long)]
+    offline: bool,
+
+    /// Group the output of the build phases in collapsible sections for the given CI system.
+    /// Detected from the environment (`GITHUB_ACTIONS`, `GITLAB_CI`) by default.
+    #[clap(long, value_enum)]
+    ci_log_style: Option<CiLogStyle>,
+
+    /// Apply patches with the `patch` executable instead of the built-in implementation, for
+    /// patches that the latter does not support (env: RATTLER_BUILD_USE_PATCH_EXECUTABLE)
+    #[clap(long)]
+    use_patch_executable: bool,
+
+    /// The maximum number of sources that are downloaded at the same time. Defaults to 4 (env:
+    /// RATTLER_BUILD_SOURCE_FETCH_CONCURRENCY).
+    #[clap(long)]
+    source_fetch_concurrency: Option<usize>,
+
+    /// How often a failed source download is retried, with an exponential backoff. Defaults to
+    /// 3 (env: RATTLER_BUILD_DOWNLOAD_RETRIES).
+    #[clap(long)]
+    download_retries: Option<usize>,
+
+    /// The maximum total size of the (extracted) files of a single source, in bytes with an
+    /// optional `K`, `M` or `G` suffix. Defaults to 50 GB (env: RATTLER_BUILD_MAX_SOURCE_SIZE).
+    #[clap(long, value_parser = parse_cache_size)]
+    max_source_size: Option<u64>,
+
+    /// The maximum number of files of a single source. Defaults to 2000000 (env:
+    /// RATTLER_BUILD_MAX_SOURCE_FILES).
+    #[clap(long)]
+    max_source_files: Option<u64>,
+
+    /// Kill the build script, and all processes that it started, when it runs longer than this
+    /// (e.g. `90m` or `2h`, seconds without a unit)
+    #[clap(long, value_parser = parse_timeout)]
+    build_timeout: Option<u64>,
+
+    /// Write the resolved dependencies (with their URLs and hashes) to a lock file next to each
+    /// package (`<name>-<version>-<build>.lock.yaml`), for `--lock-file`
+    #[clap(long)]
+    write_lock_file: bool,
+
+    /// How the progress of downloads, extractions and installations is reported: progress bars
+    /// on a terminal and log lines otherwise (`auto`), always log lines (`log`) or not at all
+    /// (`off`)
+    #[clap(long, value_enum)]
+    progress: Option<ProgressOutput>,
+
+    /// The cache of the downloaded sources and git repositories, which is shared by all builds.
+    /// Defaults to `rattler-build/src_cache` in the cache directory of the platform (env:
+    /// RATTLER_BUILD_SOURCE_CACHE_DIR).
+    #[clap(long)]
+    source_cache_dir: Option<PathBuf>,
+}
+
+/// Parse a timeout with an optional unit (`s`, `m` or `h`) into seconds
+fn parse_timeout(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let (number, factor) = match trimmed.char_indices().last() {
+        Some((index, 's')) => (&trimmed[..index], 1),
+        Some((index, 'm')) => (&trimmed[..index], 60),
+        Some((index, 'h')) => (&trimmed[..index], 60 * 60),
+        _ => (trimmed, 1),
+    };
+    let seconds = number
+        .parse::<u64>()
+        .map_err(|_| format!("`{value}` is not a timeout like `90m` or `2h`"))?
+        * factor;
+    if seconds == 0 {
+        return Err("the timeout must be at least 1 second".to_string());
+    }
+    Ok(seconds)
+}
+
+impl CommonOpts {
+    /// The settings of the command line
+    fn cli_settings(&self) -> Settings {
+        Settings {
+            offline: self.offline.then_some(true),
+            use_zstd: self.use_zstd,
+            use_bz2: self.use_bz2,
+            ci_log_style: self.ci_log_style,
+            use_patch_executable: self.use_patch_executable.then_some(true),
+            source_fetch_concurrency: self.source_fetch_concurrency,
+            download_retries: self.download_retries,
+            max_source_size: self.max_source_size,
+            max_source_files: self.max_source_files,
+            build_timeout: self.build_timeout,
+            write_lock_file: self.write_lock_file.then_some(true),
+            progress: self.progress,
+            source_cache_dir: self.source_cache_dir.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The settings of the selected profile, the environment and the command line `cli`
+    fn layered_settings(&self, cli: Settings) -> miette::Result<Settings> {
+        let profile = self
+            .profile
+            .as_deref()
+            .map(|name| ConfigFile::load_profile(self.config_file.as_deref(), name))
+            .transpose()?;
+        let settings = Settings::layered(profile, cli)?;
+        tracing::debug!(
+            "Effective configuration: {}",
+            serde_json::to_string(&settings.effective()).into_diagnostic()?
+        );
+        Ok(settings)
+    }
+}
 
-    /// Enable support for repodata.json.bz2
-    #[clap(long, env = "RATTLER_BZ2", default_value = "true", hide = true)]
-    use_bz2: bool,
+/// The format of the rendered outputs of `--render-only`
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum RenderFormat {
+    Json,
+    Yaml,
 }
 
 #[derive(Parser)]
+#[command(group(ArgGroup::new("render").args(["render_only", "output_metadata_json"])))]
 struct BuildOpts {
     /// The recipe file or directory containing `recipe.yaml`. Defaults to the current directory.
     #[arg(short, long, default_value = ".")]
@@ -97,14 +252,48 @@ struct BuildOpts {
     #[arg(short = 'c', long)]
     channel: Option<Vec<String>>,
 
-    /// Variant configuration files for the build.
+    /// Variant configuration files for the build. A `variants.yaml` next to the recipe is loaded
+    /// first, the keys of these files override its keys.
     #[arg(short = 'm', long)]
     variant_config: Vec<PathBuf>,
 
-    /// Render the recipe files without executing the build.
+    /// Do not load the `variants.yaml` next to the recipe
+    #[arg(long)]
+    ignore_recipe_variants: bool,
+
+    /// Write a JSON report of the built packages (their checksums, dependencies, sources and the
+    /// timings of the build phases) to this file
+    #[arg(long)]
+    build_report: Option<PathBuf>,
+
+    /// Error out (instead of warning) when a key of the variant configuration is not used by
+    /// any output.
+    #[arg(long)]
+    strict_variants: bool,
+
+    /// Render the recipe files without executing the build, and print the rendered outputs (the
+    /// recipe, the build configuration and, with `--solve`, the finalized dependencies).
     #[arg(long)]
     render_only: bool,
 
+    /// The format of the rendered outputs of `--render-only`
+    #[arg(long, value_enum, default_value = "json", requires = "render_only")]
+    render_format: RenderFormat,
+
+    /// Write the rendered outputs of `--render-only` to this file instead of stdout
+    #[arg(long, requires = "render_only")]
+    render_output: Option<PathBuf>,
+
+    /// Print the metadata of all outputs and variants that would be built as JSON (see
+    /// `schemas/output_metadata_v1.json`) to stdout, without building them
+    #[arg(long, conflicts_with = "render_only")]
+    output_metadata_json: bool,
+
+    /// Resolve the build and host dependencies of the outputs for `--render-only` or
+    /// `--output-metadata-json`, so that the run requirements contain the run exports
+    #[arg(long, requires = "render")]
+    solve: bool,
+
     /// Keep intermediate build artifacts after the build.
     #[arg(long)]
     keep_build: bool,
@@ -115,8 +304,20 @@ struct BuildOpts {
 
     /// The package format to use for the build.
     /// Defaults to `.tar.bz2`.
-    #[arg(long, default_value = "tar-bz2")]
-    package_format: PackageFormat,
+    #[arg(long)]
+    package_format: Option<PackageFormat>,
+
+    /// The bzip2 compression level (1 to 9) of `.tar.bz2` packages. Defaults to 9.
+    #[arg(long)]
+    tar_bz2_compression_level: Option<u32>,
+
+    /// The zstd compression level (-7 to 22) of `.conda` packages. Defaults to 15.
+    #[arg(long, allow_hyphen_values = true)]
+    conda_compression_level: Option<i32>,
+
+    /// The number of threads that compress `.conda` packages. Defaults to 1.
+    #[arg(long)]
+    compression_threads: Option<u32>,
 
     /// Do not store the recipe in the final package
     #[arg(long)]
@@ -130,15 +331,165 @@ struct BuildOpts {
     #[arg(long, default_value = "false")]
     no_force_colors: bool,
 
+    /// Keep building the remaining outputs and variants when a build fails. Outputs that depend
+    /// on a failed output are not attempted. Exits with a non-zero status if any build failed.
+    #[arg(long, default_value = "false")]
+    continue_on_failure: bool,
+
+    /// Install the dependencies, run the build script and create the package in this container
+    /// image (with docker or podman). The recipe is rendered and the sources are fetched on the
+    /// host.
+    #[arg(long)]
+    container_image: Option<String>,
+
+    /// The container runtime for `--container-image`. Defaults to `docker`, then `podman`.
+    #[arg(long, value_enum, requires = "container_image")]
+    container_runtime: Option<ContainerRuntime>,
+
+    /// The `rattler-build` executable that runs in the container. Defaults to the running
+    /// executable, which has to be compatible with the image.
+    #[arg(long, requires = "container_image")]
+    container_executable: Option<PathBuf>,
+
+    /// Use this locally built package (`.conda` or `.tar.bz2`) instead of the packages of the
+    /// same name in the channels, even if they have the same version and build string. Can be
+    /// used multiple times.
+    #[arg(long, conflicts_with = "container_image")]
+    use_local_package: Vec<PathBuf>,
+
+    /// Skip the installation of the build and host environments when their dependencies did
+    /// not change since the last build. Requires `--keep-build` and `--no-build-id`, so that
+    /// the build directory of the last build is found again. Meant for iterating on the build
+    /// script, not for release builds.
+    #[arg(long)]
+    reuse_environments: bool,
+
+    /// Compare the files of the packages in the build and host prefixes with the hashes of
+    /// their records before the build script runs. `sample` checks the sizes of all files and
+    /// the hashes of some files of every package, `full` the hashes of all files.
+    #[arg(long, value_enum)]
+    verify_prefixes: Option<PrefixVerification>,
+
+    /// Link packages with modified files again from the package cache, instead of failing the
+    /// build. Requires `--verify-prefixes`.
+    #[arg(long, requires = "verify_prefixes")]
+    repair_prefixes: bool,
+
+    /// Do not build an output if a package with the same name, version and build string exists
+    /// in the output directory (`local`), or also in the channels (`all`)
+    #[arg(long, value_enum)]
+    skip_existing: Option<SkipExisting>,
+
+    /// Build all outputs, even those that `--skip-existing` (or the profile) would skip
+    #[arg(long, conflicts_with = "skip_existing")]
+    force: bool,
+
+    /// Install the dependencies of this lock file (written with `--write-lock-file`) instead of
+    /// resolving them, for the output of the same name and target platform. Fails if the
+    /// requirements of the recipe changed since the lock file was written. Can be used multiple
+    /// times.
+    #[arg(long)]
+    lock_file: Vec<PathBuf>,
+
+    /// Print the effective configuration (of the profile, the environment and the command line)
+    /// and exit
+    #[arg(long)]
+    show_config: bool,
+
     #[clap(flatten)]
     common: CommonOpts,
 }
 
+impl BuildOpts {
+    /// The settings of the command line
+    fn cli_settings(&self) -> Settings {
+        Settings {
+            channels: self.channel.clone(),
+            package_format: self.package_format,
+            tar_bz2_compression_level: self.tar_bz2_compression_level,
+            conda_compression_level: self.conda_compression_level,
+            compression_threads: self.compression_threads,
+            keep_build: self.keep_build.then_some(true),
+            no_build_id: self.no_build_id.then_some(true),
+            reuse_environments: self.reuse_environments.then_some(true),
+            verify_prefixes: self.verify_prefixes,
+            repair_prefixes: self.repair_prefixes.then_some(true),
+            skip_existing: if self.force {
+                Some(SkipExisting::Off)
+            } else {
+                self.skip_existing
+            },
+            no_include_recipe: self.no_include_recipe.then_some(true),
+            no_test: self.no_test.then_some(true),
+            no_force_colors: self.no_force_colors.then_some(true),
+            continue_on_failure: self.continue_on_failure.then_some(true),
+            strict_variants: self.strict_variants.then_some(true),
+            container_image: self.container_image.clone(),
+            container_runtime: self.container_runtime,
+            container_executable: self.container_executable.clone(),
+            use_local_package: (!self.use_local_package.is_empty())
+                .then(|| self.use_local_package.clone()),
+            ..self.common.cli_settings()
+        }
+    }
+}
+
 #[derive(Parser)]
 struct TestOpts {
     /// The package file to test
     #[arg(short, long)]
     package_file: PathBuf,
+
+    /// The channels from which the dependencies of the package and the test requirements are
+    /// installed. For more than one channel use it multiple times. Defaults to `conda-forge`
+    /// and `./output`.
+    #[arg(short = 'c', long)]
+    channel: Option<Vec<String>>,
+
+    /// The platform of the test environment. Defaults to the current platform.
+    #[arg(long)]
+    target_platform: Option<String>,
+
+    /// The directory of the test environment (it must not exist yet). Defaults to a temporary
+    /// directory.
+    #[arg(long)]
+    test_prefix: Option<PathBuf>,
+
+    /// Keep the test environment after the tests ran
+    #[arg(long)]
+    keep_test_prefix: bool,
+}
+
+#[derive(Parser)]
+struct CleanCacheOpts {
+    /// The source cache to clean. Defaults to `rattler-build/src_cache` in the cache directory
+    /// of the platform.
+    #[arg(long, env = "RATTLER_BUILD_SOURCE_CACHE_DIR")]
+    source_cache_dir: Option<PathBuf>,
+
+    /// Remove the entries that were not used for this many days
+    #[arg(long)]
+    max_age_days: Option<u64>,
+
+    /// Remove the least recently used entries until the cache is at most this large (in bytes,
+    /// with an optional `K`, `M` or `G` suffix for KiB, MiB and GiB)
+    #[arg(long, value_parser = parse_cache_size)]
+    max_size: Option<u64>,
+}
+
+/// Parse a size in bytes with an optional `K`, `M` or `G` suffix (of the cache or of a source)
+fn parse_cache_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let (number, factor) = match trimmed.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&trimmed[..index], 1024),
+        Some((index, 'M' | 'm')) => (&trimmed[..index], 1024 * 1024),
+        Some((index, 'G' | 'g')) => (&trimmed[..index], 1024 * 1024 * 1024),
+        _ => (trimmed, 1),
+    };
+    number
+        .parse::<u64>()
+        .map(|number| number * factor)
+        .map_err(|_| format!("`{value}` is not a size like `500M` or `10G`"))
 }
 
 #[derive(Parser)]
@@ -155,6 +506,28 @@ struct RebuildOpts {
     common: CommonOpts,
 }
 
+#[derive(Parser)]
+struct BuildInContainerOpts {
+    /// The serialized output with the directories as they are seen in the container
+    #[arg(long)]
+    output_file: PathBuf,
+
+    /// The recipe directory in the container
+    #[arg(long)]
+    recipe_dir: PathBuf,
+
+    /// Do not run tests after building
+    #[arg(long, default_value = "false")]
+    no_test: bool,
+
+    /// Keep intermediate build artifacts after the build.
+    #[arg(long)]
+    keep_build: bool,
+
+    #[clap(flatten)]
+    common: CommonOpts,
+}
+
 #[tokio::main]
 async fn main() -> miette::Result<()> {
     let args = App::parse();
@@ -171,33 +544,101 @@ async fn main() -> miette::Result<()> {
         )
         .init();
 
+    let cancellation = CancellationToken::new();
+    cancel_on_signals(cancellation.clone());
+
     match args.subcommand {
-        SubCommands::Build(args) => run_build_from_args(args, multi_progress).await,
+        SubCommands::Build(args) => run_build_from_args(args, multi_progress, cancellation).await,
         SubCommands::Test(args) => run_test_from_args(args).await,
-        SubCommands::Rebuild(args) => rebuild_from_args(args).await,
+        SubCommands::Rebuild(args) => rebuild_from_args(args, multi_progress, cancellation).await,
+        SubCommands::BuildInContainer(args) => {
+            build_in_container_from_args(args, multi_progress, cancellation).await
+        }
+        SubCommands::CleanCache(args) => clean_cache_from_args(args),
     }
 }
 
+fn clean_cache_from_args(args: CleanCacheOpts) -> miette::Result<()> {
+    if args.max_age_days.is_none() && args.max_size.is_none() {
+        return Err(miette::miette!(
+            "Nothing to clean, pass `--max-age-days` or `--max-size`"
+        ));
+    }
+    let cache_dir = args
+        .source_cache_dir
+        .unwrap_or_else(cache::default_source_cache_dir);
+    let max_age = args
+        .max_age_days
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let summary =
+        cache::clean_source_cache(&cache_dir, max_age, args.max_size).into_diagnostic()?;
+    tracing::info!(
+        "Removed {} entries ({}) from {}, {} are left",
+        summary.removed,
+        HumanBytes(summary.freed_bytes),
+        cache_dir.display(),
+        HumanBytes(summary.kept_bytes)
+    );
+    Ok(())
+}
+
 async fn run_test_from_args(args: TestOpts) -> miette::Result<()> {
     let package_file = canonicalize(args.package_file).into_diagnostic()?;
-    let test_prefix = PathBuf::from("test-prefix");
+    let target_platform = match args.target_platform {
+        Some(platform) => Platform::from_str(&platform).into_diagnostic()?,
+        None => Platform::current(),
+    };
+
+    // a fresh test environment, which is a temporary directory unless it is kept
+    let temp_prefix = tempfile::tempdir().into_diagnostic()?;
+    let test_prefix = match args.test_prefix {
+        Some(test_prefix) if test_prefix.exists() => {
+            return Err(miette::miette!(
+                "The test prefix {} exists already",
+                test_prefix.display()
+            ));
+        }
+        Some(test_prefix) => test_prefix,
+        None => temp_prefix.path().join("test-prefix"),
+    };
     fs::create_dir_all(&test_prefix).into_diagnostic()?;
 
     let test_options = TestConfiguration {
-        test_prefix,
-        target_platform: Some(Platform::current()),
-        keep_test_prefix: false,
-        channels: vec!["conda-forge".to_string(), "./output".to_string()],
+        test_prefix: test_prefix.clone(),
+        target_platform: Some(target_platform),
+        keep_test_prefix: args.keep_test_prefix,
+        channels: args
+            .channel
+            .unwrap_or_else(|| vec!["conda-forge".to_string(), "./output".to_string()]),
     };
 
     test::run_test(&package_file, &test_options)
         .await
         .into_diagnostic()?;
 
+    if args.keep_test_prefix {
+        // the temporary directory is not removed either
+        let _ = temp_prefix.into_path();
+        tracing::info!("Kept the test environment in {}", test_prefix.display());
+    }
+
     Ok(())
 }
 
-async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) -> miette::Result<()> {
+async fn run_build_from_args(
+    args: BuildOpts,
+    multi_progress: MultiProgress,
+    cancellation: CancellationToken,
+) -> miette::Result<()> {
+    let settings = args.common.layered_settings(args.cli_settings())?;
+    if args.show_config {
+        print!(
+            "{}",
+            serde_yaml::to_string(&settings.effective()).into_diagnostic()?
+        );
+        return Ok(());
+    }
+
     let recipe_path = canonicalize(&args.recipe);
     if let Err(e) = &recipe_path {
         match e.kind() {
@@ -251,6 +692,21 @@ async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) ->
         , output_dir.to_string_lossy()));
     }
 
+    // fail early if the filesystems cannot hold the outputs and the build directories
+    fs::create_dir_all(&output_dir).map_err(|source| FilesystemError::NotWritable {
+        role: "output directory",
+        dir: output_dir.clone(),
+        source,
+    })?;
+    let output_dir = canonicalize(output_dir).into_diagnostic()?;
+    let package_cache = rattler::default_cache_dir().ok();
+    let build_root = check_build_roots(
+        &DirectoryProbe,
+        &output_dir,
+        package_cache.as_deref(),
+        &std::env::temp_dir(),
+    )?;
+
     let recipe_text = fs::read_to_string(&recipe_path).into_diagnostic()?;
 
     let host_platform = if let Some(target_platform) = args.target_platform {
@@ -260,18 +716,110 @@ async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) ->
         Platform::current()
     };
 
-    let selector_config = SelectorConfig {
+    let mut selector_config = SelectorConfig {
         // We ignore noarch here
         target_platform: host_platform,
         hash: None,
         build_platform: Platform::current(),
         variant: BTreeMap::new(),
+        source_dir: None,
+        recipe_dir: recipe_path.parent().map(Path::to_path_buf),
+        sources_requested: Default::default(),
     };
 
-    let variant_config =
-        VariantConfig::from_files(&args.variant_config, &selector_config).into_diagnostic()?;
+    let mut tool_config = tool_configuration::Configuration {
+        client: AuthenticatedClient::default(),
+        multi_progress_indicator: multi_progress,
+        no_clean: settings.keep_build(),
+        no_test: settings.no_test(),
+        use_zstd: settings.use_zstd(),
+        use_bz2: settings.use_bz2(),
+        offline: settings.offline(),
+        ci_log_style: settings.ci_log_style.unwrap_or_else(CiLogStyle::detect),
+        source_limits: settings.source_limits(),
+        container: settings
+            .container_image
+            .clone()
+            .map(|image| ContainerConfig {
+                image,
+                runtime: settings.container_runtime,
+                executable: settings.container_executable.clone(),
+            }),
+        bandwidth_limit: BandwidthLimiter::from_env(),
+        source_fetch_concurrency: settings.source_fetch_concurrency(),
+        download_retries: settings.download_retries(),
+        use_patch_executable: settings.use_patch_executable(),
+        local_package_overrides: settings.local_packages().to_vec(),
+        reuse_environments: settings.reuse_environments(),
+        verify_prefixes: settings.verify_prefixes(),
+        repair_prefixes: settings.repair_prefixes(),
+        build_timeout: settings.build_timeout(),
+        source_cache_dir: settings.source_cache_dir(),
+        skip_existing: settings.skip_existing(),
+        write_lock_file: settings.write_lock_file(),
+        progress: settings.progress(),
+        build_report: args.build_report.clone(),
+        log_sender: None,
+        cancellation,
+        fetched_sources: None,
+        claimed_files: Default::default(),
+        run_exports_cache: Default::default(),
+        build_reports: Default::default(),
+    };
 
-    let outputs_and_variants = variant_config.find_variants(&recipe_text, &selector_config)?;
+    let lock_files = args
+        .lock_file
+        .iter()
+        .map(|path| Ok((path.clone(), LockFile::read(path)?)))
+        .collect::<Result<Vec<_>, LockFileError>>()?;
+
+    let variant_config_files = if args.ignore_recipe_variants {
+        args.variant_config.clone()
+    } else {
+        variant_config_files(&recipe_path, &args.variant_config)
+    };
+    let mut variant_config =
+        VariantConfig::from_files(&variant_config_files, &selector_config).into_diagnostic()?;
+    variant_config.strict = settings.strict_variants();
+
+    let mut outputs_and_variants = variant_config.find_variants(&recipe_text, &selector_config);
+
+    // Recipes that read files from their sources while rendering need the sources before the
+    // variants can be discovered. `load_file_from_source` and `load_from_file` record whether
+    // they needed them, and the variants are discovered again with the fetched sources. The
+    // builds with the same sources copy them into their work directory.
+    let _render_source_dir = if selector_config.sources_requested.get() {
+        let (sources, source_fetch) =
+            Recipe::sources_from_yaml(&recipe_text, selector_config.clone())?;
+        if source_fetch == SourceFetch::BeforeSolve {
+            tracing::info!("Fetching sources to render the recipe");
+            let render_source_dir = tempfile::tempdir().into_diagnostic()?;
+            let commits = fetch_sources(
+                &sources,
+                render_source_dir.path(),
+                recipe_path
+                    .parent()
+                    .expect("Could not get parent of recipe"),
+                &tool_config.source_cache_dir,
+                &tool_config,
+                &Tools::default(),
+            )
+            .await?;
+            selector_config.source_dir = Some(render_source_dir.path().to_path_buf());
+            tool_config.fetched_sources = Some(Arc::new(FetchedSources {
+                sources,
+                directory: render_source_dir.path().to_path_buf(),
+                commits,
+            }));
+            outputs_and_variants = variant_config.find_variants(&recipe_text, &selector_config);
+            Some(render_source_dir)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    let outputs_and_variants = outputs_and_variants?;
 
     tracing::info!("Found variants:\n");
     for discovered_output in &outputs_and_variants {
@@ -293,16 +841,21 @@ async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) ->
         tracing::info!("{}\n", table);
     }
 
-    let tool_config = tool_configuration::Configuration {
-        client: AuthenticatedClient::default(),
-        multi_progress_indicator: multi_progress,
-        no_clean: args.keep_build,
-        no_test: args.no_test,
-        use_zstd: args.common.use_zstd,
-        use_bz2: args.common.use_bz2,
-    };
+    // the outputs for `--render-only` and `--output-metadata-json` are never built, so their
+    // build directories are only temporary (and named without a build id)
+    let render = args.render_only || args.output_metadata_json;
+    let render_build_root = render
+        .then(tempfile::tempdir)
+        .transpose()
+        .into_diagnostic()?;
+    let build_root = render_build_root
+        .as_ref()
+        .map(|dir| dir.path().to_path_buf())
+        .unwrap_or(build_root);
+    let mut rendered_outputs = Vec::new();
 
     let mut subpackages = BTreeMap::new();
+    let mut summary = BuildSummary::new();
     for discovered_output in outputs_and_variants {
         let hash =
             HashInfo::from_variant(&discovered_output.used_vars, &discovered_output.noarch_type);
@@ -312,6 +865,9 @@ async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) ->
             hash: Some(hash.clone()),
             target_platform: selector_config.target_platform,
             build_platform: selector_config.build_platform,
+            source_dir: selector_config.source_dir.clone(),
+            recipe_dir: selector_config.recipe_dir.clone(),
+            sources_requested: selector_config.sources_requested.clone(),
         };
 
         let recipe = Recipe::from_node(&discovered_output.node, selector_config)
@@ -325,15 +881,54 @@ async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) ->
             );
             tracing::info!("Variant: {:#?}", discovered_output.used_vars);
             tracing::info!("Hash: {:#?}", recipe.build().string());
-            tracing::info!("Skip?: {}\n", recipe.build().skip());
-            continue;
+            tracing::info!("Skip?: {}", recipe.build().skip());
+            for requirement in recipe_requirements(recipe.requirements()) {
+                match &requirement.recipe_location {
+                    Some(location) => tracing::info!(
+                        "{} ({}): {}",
+                        requirement.location,
+                        location,
+                        requirement.dependency
+                    ),
+                    None => tracing::info!("{}: {}", requirement.location, requirement.dependency),
+                }
+            }
+            if !args.solve {
+                tracing::info!(
+                    "Run exports of the build and host dependencies are added when building\n"
+                );
+            }
         }
 
+        let identifier = format!(
+            "{}-{}-{}",
+            discovered_output.name, discovered_output.version, discovered_output.build_string
+        );
+
         if recipe.build().skip() {
             tracing::info!(
                 "Skipping build for variant: {:#?}",
                 discovered_output.used_vars
             );
+            summary.record(&discovered_output.name, identifier, BuildStatus::Skipped);
+            continue;
+        }
+
+        let failed_dependencies =
+            summary.failed_dependencies(dependency_names(&recipe).iter().map(String::as_str));
+        if !failed_dependencies.is_empty() {
+            tracing::warn!(
+                "Not building {} because its dependencies failed: {}",
+                identifier,
+                failed_dependencies.join(", ")
+            );
+            summary.record(
+                &discovered_output.name,
+                identifier,
+                BuildStatus::Blocked {
+                    failed_dependencies,
+                },
+            );
             continue;
         }
 
@@ -351,15 +946,12 @@ async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) ->
         );
 
         let name = recipe.package().name().clone();
-        // Add the channels from the args and by default always conda-forge
-        let channels = args
-            .channel
-            .clone()
-            .unwrap_or_else(|| vec!["conda-forge".to_string()]);
+        // Add the channels from the settings and by default always conda-forge
+        let channels = settings.channels();
 
         let timestamp = chrono::Utc::now();
 
-        let output = rattler_build::metadata::Output {
+        let mut output = rattler_build::metadata::Output {
             recipe,
             build_configuration: BuildConfiguration {
                 target_platform: discovered_output.target_platform,
@@ -371,30 +963,155 @@ async fn run_build_from_args(args: BuildOpts, multi_progress: MultiProgress) ->
                     name.as_normalized(),
                     &recipe_path,
                     &output_dir,
-                    args.no_build_id,
+                    &build_root,
+                    &tool_config.source_cache_dir,
+                    settings.no_build_id() || render,
                     &timestamp,
                 )
                 .into_diagnostic()?,
                 channels,
                 timestamp,
                 subpackages: subpackages.clone(),
-                package_format: match args.package_format {
-                    PackageFormat::TarBz2 => ArchiveType::TarBz2,
-                    PackageFormat::Conda => ArchiveType::Conda,
-                },
-                store_recipe: !args.no_include_recipe,
-                force_colors: !args.no_force_colors,
+                package_format: settings.package_format().into(),
+                additional_package_formats: settings.package_format().additional_archive_types(),
+                compression: settings.compression(),
+                store_recipe: !settings.no_include_recipe(),
+                force_colors: !settings.no_force_colors(),
             },
             finalized_dependencies: None,
         };
+        apply_lock_files(&mut output, &lock_files)?;
 
-        run_build(&output, tool_config.clone()).await?;
+        if render {
+            rendered_outputs.push(output);
+            continue;
+        }
+
+        match run_build(&output, tool_config.clone()).await {
+            Ok(BuildOutcome::Built(packages)) => {
+                summary.record(
+                    &discovered_output.name,
+                    identifier,
+                    BuildStatus::Success { packages },
+                );
+            }
+            Ok(BuildOutcome::Skipped(url)) => {
+                summary.record(
+                    &discovered_output.name,
+                    identifier,
+                    BuildStatus::Existing {
+                        url: url.to_string(),
+                    },
+                );
+            }
+            // a cancelled build stops all remaining builds
+            Err(err)
+                if settings.continue_on_failure() && !tool_config.cancellation.is_cancelled() =>
+            {
+                tracing::error!("Build of {} failed: {:?}", identifier, err);
+                summary.record(
+                    &discovered_output.name,
+                    identifier,
+                    BuildStatus::Failed {
+                        error: err.to_string(),
+                        build_dir: output.build_configuration.directories.build_dir.clone(),
+                    },
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    if args.render_only {
+        let outputs = if args.solve {
+            finalize_outputs(&rendered_outputs, &tool_config)
+                .await
+                .into_diagnostic()?
+        } else {
+            rendered_outputs
+        };
+        let outputs = outputs
+            .iter()
+            .map(|output| rendered_output(output, &build_root))
+            .collect::<miette::Result<Vec<_>>>()?;
+        let rendered = match args.render_format {
+            RenderFormat::Json => {
+                format!(
+                    "{}\n",
+                    serde_json::to_string_pretty(&outputs).into_diagnostic()?
+                )
+            }
+            RenderFormat::Yaml => serde_yaml::to_string(&outputs).into_diagnostic()?,
+        };
+        match &args.render_output {
+            Some(path) => fs::write(path, rendered).into_diagnostic()?,
+            None => print!("{rendered}"),
+        }
+        return Ok(());
+    }
+
+    if args.output_metadata_json {
+        let metadata = output_metadata(&rendered_outputs, args.solve.then_some(&tool_config))
+            .await
+            .into_diagnostic()?;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&metadata).into_diagnostic()?
+        );
+        return Ok(());
+    }
+
+    if settings.continue_on_failure() {
+        tracing::info!("Build summary:\n{}", summary.to_table());
+        if summary.has_failures() {
+            return Err(miette::miette!(
+                "{} of {} builds failed",
+                summary.failure_count(),
+                summary.entries().len()
+            ));
+        }
     }
 
     Ok(())
 }
 
-async fn rebuild_from_args(args: RebuildOpts) -> miette::Result<()> {
+/// An output as it is printed by `--render-only`: with the build script that would run, and
+/// without the build directories and the timestamp, which change with every run. The temporary
+/// build root in the script is replaced by `<build root>`.
+fn rendered_output(
+    output: &rattler_build::metadata::Output,
+    build_root: &Path,
+) -> miette::Result<serde_yaml::Value> {
+    let script = render_build_script(output)
+        .into_diagnostic()?
+        .replace(&*build_root.to_string_lossy(), "<build root>");
+    let mut value = serde_yaml::to_value(output).into_diagnostic()?;
+    if let Some(configuration) = value
+        .get_mut("build_configuration")
+        .and_then(serde_yaml::Value::as_mapping_mut)
+    {
+        configuration.remove("directories");
+        configuration.remove("timestamp");
+    }
+    if let Some(mapping) = value.as_mapping_mut() {
+        mapping.insert("build_script".into(), script.into());
+        // with `--solve`, the provenance is part of the finalized dependencies
+        if output.finalized_dependencies.is_none() {
+            let requirements = recipe_requirements(output.recipe.requirements());
+            mapping.insert(
+                "provenance".into(),
+                serde_yaml::to_value(requirements).into_diagnostic()?,
+            );
+        }
+    }
+    Ok(value)
+}
+
+async fn rebuild_from_args(
+    args: RebuildOpts,
+    multi_progress: MultiProgress,
+    cancellation: CancellationToken,
+) -> miette::Result<()> {
     tracing::info!("Rebuilding {}", args.package_file.to_string_lossy());
     // we extract the recipe folder from the package file (info/recipe/*)
     // and then run the rendered recipe with the same arguments as the original build
@@ -425,15 +1142,44 @@ async fn rebuild_from_args(args: RebuildOpts) -> miette::Result<()> {
     output.build_configuration.directories.output_dir =
         canonicalize(output_dir).into_diagnostic()?;
 
+    let settings = args.common.layered_settings(Settings {
+        no_test: args.no_test.then_some(true),
+        ..args.common.cli_settings()
+    })?;
     let tool_config = tool_configuration::Configuration {
         client: AuthenticatedClient::default(),
-        multi_progress_indicator: MultiProgress::new(),
+        multi_progress_indicator: multi_progress,
         no_clean: true,
-        no_test: args.no_test,
-        use_zstd: args.common.use_zstd,
-        use_bz2: args.common.use_bz2,
+        no_test: settings.no_test(),
+        use_zstd: settings.use_zstd(),
+        use_bz2: settings.use_bz2(),
+        offline: settings.offline(),
+        ci_log_style: settings.ci_log_style.unwrap_or_else(CiLogStyle::detect),
+        source_limits: settings.source_limits(),
+        container: None,
+        bandwidth_limit: BandwidthLimiter::from_env(),
+        source_fetch_concurrency: settings.source_fetch_concurrency(),
+        download_retries: settings.download_retries(),
+        use_patch_executable: settings.use_patch_executable(),
+        local_package_overrides: Vec::new(),
+        reuse_environments: false,
+        verify_prefixes: PrefixVerification::Off,
+        repair_prefixes: false,
+        build_timeout: settings.build_timeout(),
+        source_cache_dir: settings.source_cache_dir(),
+        skip_existing: SkipExisting::Off,
+        write_lock_file: settings.write_lock_file(),
+        progress: settings.progress(),
+        build_report: None,
+        log_sender: None,
+        cancellation,
+        fetched_sources: None,
+        claimed_files: Default::default(),
+        run_exports_cache: Default::default(),
+        build_reports: Default::default(),
     };
 
+    output.build_configuration.directories.source_cache = tool_config.source_cache_dir.clone();
     output
         .build_configuration
         .directories
@@ -445,6 +1191,65 @@ async fn rebuild_from_args(args: RebuildOpts) -> miette::Result<()> {
     Ok(())
 }
 
+async fn build_in_container_from_args(
+    args: BuildInContainerOpts,
+    multi_progress: MultiProgress,
+    cancellation: CancellationToken,
+) -> miette::Result<()> {
+    let serialized_output = fs::read_to_string(&args.output_file).into_diagnostic()?;
+    let mut output: rattler_build::metadata::Output =
+        serde_yaml::from_str(&serialized_output).into_diagnostic()?;
+
+    // the recipe and output directories are not serialized
+    output.build_configuration.directories.recipe_dir = args.recipe_dir;
+    output.build_configuration.directories.output_dir = args
+        .common
+        .output_dir
+        .unwrap_or_else(|| PathBuf::from(CONTAINER_OUTPUT_DIR));
+
+    let settings = args.common.layered_settings(Settings {
+        keep_build: args.keep_build.then_some(true),
+        no_test: args.no_test.then_some(true),
+        ..args.common.cli_settings()
+    })?;
+    let tool_config = tool_configuration::Configuration {
+        client: AuthenticatedClient::default(),
+        multi_progress_indicator: multi_progress,
+        no_clean: settings.keep_build(),
+        no_test: settings.no_test(),
+        use_zstd: settings.use_zstd(),
+        use_bz2: settings.use_bz2(),
+        offline: settings.offline(),
+        ci_log_style: settings.ci_log_style.unwrap_or_default(),
+        source_limits: settings.source_limits(),
+        container: None,
+        bandwidth_limit: BandwidthLimiter::from_env(),
+        source_fetch_concurrency: settings.source_fetch_concurrency(),
+        download_retries: settings.download_retries(),
+        use_patch_executable: settings.use_patch_executable(),
+        local_package_overrides: Vec::new(),
+        reuse_environments: false,
+        verify_prefixes: PrefixVerification::Off,
+        repair_prefixes: false,
+        build_timeout: settings.build_timeout(),
+        source_cache_dir: settings.source_cache_dir(),
+        skip_existing: SkipExisting::Off,
+        write_lock_file: settings.write_lock_file(),
+        progress: settings.progress(),
+        build_report: None,
+        log_sender: None,
+        cancellation,
+        fetched_sources: None,
+        claimed_files: Default::default(),
+        run_exports_cache: Default::default(),
+        build_reports: Default::default(),
+    };
+
+    run_build_with_fetched_sources(&output, tool_config).await?;
+
+    Ok(())
+}
+
 /// Constructs a default [`EnvFilter`] that is used when the user did not specify a custom RUST_LOG.
 pub fn get_default_env_filter(
     verbose: clap_verbosity_flag::LevelFilter,
@@ -55,10 +55,13 @@ pub struct Directories {
     /// The output directory or local channel directory
     #[serde(skip)]
     pub output_dir: PathBuf,
+    /// The cache of the downloaded sources and git repositories, which is shared by all builds
+    #[serde(skip)]
+    pub source_cache: PathBuf,
 }
 
 fn setup_build_dir(
-    output_dir: &Path,
+    build_root: &Path,
     name: &str,
     no_build_id: bool,
     timestamp: &DateTime<Utc>,
@@ -70,17 +73,40 @@ fn setup_build_dir(
     } else {
         format!("rattler-build_{}_{:?}", name, since_the_epoch)
     };
-    let path = output_dir.join("bld").join(dirname);
+    let path = build_root.join(dirname);
     fs::create_dir_all(path.join("work"))?;
     Ok(path)
 }
 
+/// The host prefix in `build_dir`, padded with `_placehold` to a length of 255 characters so that
+/// it can be replaced by longer prefixes at installation time
+pub(crate) fn padded_host_prefix(build_dir: &Path) -> PathBuf {
+    let placeholder_template = "_placehold";
+    let mut placeholder = String::new();
+    let placeholder_length: usize = 255;
+
+    while placeholder.len() < placeholder_length {
+        placeholder.push_str(placeholder_template);
+    }
+
+    let placeholder = placeholder
+        [0..placeholder_length - build_dir.join("host_env").as_os_str().len()]
+        .to_string();
+
+    build_dir.join(format!("host_env{}", placeholder))
+}
+
 impl Directories {
-    /// Create all directories needed for the building of a package
+    /// Create all directories needed for the building of a package. The build directory is
+    /// created in the `build_root` (usually `bld` in the output directory, see
+    /// [`crate::filesystem::check_build_roots`]). The sources are fetched into the
+    /// `source_cache`.
     pub fn create(
         name: &str,
         recipe_path: &Path,
         output_dir: &Path,
+        build_root: &Path,
+        source_cache: &Path,
         no_build_id: bool,
         timestamp: &DateTime<Utc>,
     ) -> Result<Directories, std::io::Error> {
@@ -89,7 +115,7 @@ impl Directories {
         }
         let output_dir = canonicalize(output_dir)?;
 
-        let build_dir = setup_build_dir(&output_dir, name, no_build_id, timestamp)
+        let build_dir = setup_build_dir(build_root, name, no_build_id, timestamp)
             .expect("Could not create build directory");
         let recipe_dir = recipe_path
             .parent()
@@ -101,19 +127,7 @@ impl Directories {
         let host_prefix = if cfg!(target_os = "windows") {
             build_dir.join("h_env")
         } else {
-            let placeholder_template = "_placehold";
-            let mut placeholder = String::new();
-            let placeholder_length: usize = 255;
-
-            while placeholder.len() < placeholder_length {
-                placeholder.push_str(placeholder_template);
-            }
-
-            let placeholder = placeholder
-                [0..placeholder_length - build_dir.join("host_env").as_os_str().len()]
-                .to_string();
-
-            build_dir.join(format!("host_env{}", placeholder))
+            padded_host_prefix(&build_dir)
         };
 
         let directories = Directories {
@@ -123,11 +137,18 @@ impl Directories {
             work_dir: build_dir.join("work"),
             recipe_dir,
             output_dir,
+            source_cache: source_cache.to_path_buf(),
         };
 
         Ok(directories)
     }
 
+    /// The log of the build script, next to the work directory. It stays in place when the
+    /// build fails (or with `--keep-build`), so that CI can upload it.
+    pub fn build_log(&self) -> PathBuf {
+        self.build_dir.join("build.log")
+    }
+
     /// create all directories
     pub fn recreate_directories(&self) -> Result<(), std::io::Error> {
         if self.build_dir.exists() {
@@ -175,6 +196,12 @@ pub struct BuildConfiguration {
     pub subpackages: BTreeMap<PackageName, PackageIdentifier>,
     /// Package format (.tar.bz2 or .conda)
     pub package_format: ArchiveType,
+    /// Package formats that are written in addition to `package_format`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_package_formats: Vec<ArchiveType>,
+    /// How the packages are compressed
+    #[serde(default, skip_serializing_if = "PackageCompression::is_default")]
+    pub compression: PackageCompression,
     /// Whether to store the recipe and build instructions in the final package or not
     #[serde(skip_serializing, default = "default_true")]
     pub store_recipe: bool,
@@ -184,9 +211,55 @@ pub struct BuildConfiguration {
 }
 
 impl BuildConfiguration {
-    /// true if the build is cross-compiling
+    /// true if the build is cross-compiling (the host platform differs from the build platform)
     pub fn cross_compilation(&self) -> bool {
-        self.target_platform != self.build_platform
+        self.host_platform != self.build_platform
+    }
+
+    /// All package formats that are written, `package_format` first
+    pub fn package_formats(&self) -> Vec<ArchiveType> {
+        let mut formats = vec![self.package_format];
+        for format in &self.additional_package_formats {
+            if !formats.contains(format) {
+                formats.push(*format);
+            }
+        }
+        formats
+    }
+}
+
+/// The compression of the packages. The defaults are the levels that
+/// `rattler_package_streaming` uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackageCompression {
+    /// The bzip2 level of `.tar.bz2` packages (1 to 9)
+    pub tar_bz2_level: u32,
+    /// The zstd level of `.conda` packages (-7 to 22)
+    pub conda_level: i32,
+    /// The number of threads that compress a `.conda` package
+    pub threads: u32,
+}
+
+impl PackageCompression {
+    /// The default bzip2 level
+    pub const DEFAULT_TAR_BZ2_LEVEL: u32 = 9;
+    /// The default zstd level
+    pub const DEFAULT_CONDA_LEVEL: i32 = 15;
+
+    /// true if the packages are compressed as they are by default
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Default for PackageCompression {
+    fn default() -> Self {
+        Self {
+            tar_bz2_level: Self::DEFAULT_TAR_BZ2_LEVEL,
+            conda_level: Self::DEFAULT_CONDA_LEVEL,
+            threads: 1,
+        }
     }
 }
 
@@ -283,12 +356,29 @@ impl Display for Output {
                 table
                     .load_preset(comfy_table::presets::UTF8_FULL_CONDENSED)
                     .apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS)
-                    .set_header(vec!["Name", "Spec"]);
-
-                finalized_dependencies.run.depends.iter().for_each(|d| {
-                    let rendered = d.render();
-                    table.add_row(rendered.splitn(2, ' ').collect::<Vec<&str>>());
-                });
+                    .set_header(vec!["Name", "Spec", "Origin"]);
+
+                let origins = finalized_dependencies
+                    .run
+                    .provenance
+                    .iter()
+                    .filter(|p| p.list == "run")
+                    .map(|p| p.to_string());
+                finalized_dependencies
+                    .run
+                    .depends
+                    .iter()
+                    .zip(origins.chain(std::iter::repeat(String::new())))
+                    .for_each(|(d, origin)| {
+                        let rendered = d.render();
+                        let mut row = rendered
+                            .splitn(2, ' ')
+                            .map(str::to_string)
+                            .collect::<Vec<_>>();
+                        row.resize(2, String::new());
+                        row.push(origin);
+                        table.add_row(row);
+                    });
 
                 writeln!(f, "{}\n", table)?;
             }
@@ -299,12 +389,29 @@ impl Display for Output {
                 table
                     .load_preset(comfy_table::presets::UTF8_FULL_CONDENSED)
                     .apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS)
-                    .set_header(vec!["Name", "Spec"]);
-
-                finalized_dependencies.run.constrains.iter().for_each(|d| {
-                    let rendered = d.render();
-                    table.add_row(rendered.splitn(2, ' ').collect::<Vec<&str>>());
-                });
+                    .set_header(vec!["Name", "Spec", "Origin"]);
+
+                let origins = finalized_dependencies
+                    .run
+                    .provenance
+                    .iter()
+                    .filter(|p| p.list == "run_constrained")
+                    .map(|p| p.to_string());
+                finalized_dependencies
+                    .run
+                    .constrains
+                    .iter()
+                    .zip(origins.chain(std::iter::repeat(String::new())))
+                    .for_each(|(d, origin)| {
+                        let rendered = d.render();
+                        let mut row = rendered
+                            .splitn(2, ' ')
+                            .map(str::to_string)
+                            .collect::<Vec<_>>();
+                        row.resize(2, String::new());
+                        row.push(origin);
+                        table.add_row(row);
+                    });
 
                 writeln!(f, "{}\n", table)?;
             }
@@ -360,6 +467,8 @@ mod test {
             "name",
             &tempdir.path().join("recipe"),
             &tempdir.path().join("output"),
+            &tempdir.path().join("output/bld"),
+            &tempdir.path().join("src_cache"),
             false,
             &chrono::Utc::now(),
         )
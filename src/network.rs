@@ -0,0 +1,347 @@
+//! The network policy of the build script (`build.network`).
+//!
+//! With `network: none`, the script runs in a network namespace without interfaces on Linux
+//! (with `unshare`), so every connection fails. Where that is not possible, and for the `warn`
+//! and `error` policies, the script gets a local proxy (`http_proxy` and `https_proxy`) that
+//! refuses all requests and records their destinations. This only detects the tools that honor
+//! the proxy variables, which most downloaders (`curl`, `wget`, `pip`, ...) do.
+
+use std::{
+    ffi::OsString,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use itertools::Itertools;
+
+use crate::recipe::parser::NetworkPolicy;
+
+/// The response of the sinkhole proxy to every request
+const REFUSED: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+#[allow(missing_docs)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum NetworkError {
+    #[error("Could not start the network sinkhole: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("The build script tried to access the network (`network: {policy}`):\n{attempts}")]
+    #[diagnostic(help("fetch the files as sources of the recipe instead"))]
+    Accessed {
+        policy: NetworkPolicy,
+        attempts: String,
+    },
+}
+
+/// A local proxy that refuses all requests and records their destinations
+struct Sinkhole {
+    address: SocketAddr,
+    attempts: Arc<Mutex<Vec<String>>>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Sinkhole {
+    fn start() -> Result<Self, std::io::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let attempts = attempts.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(mut stream) = stream else {
+                        continue;
+                    };
+                    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                    if let Some(destination) = destination(&stream) {
+                        attempts.lock().unwrap().push(destination);
+                    }
+                    let _ = stream.write_all(REFUSED);
+                }
+            })
+        };
+
+        Ok(Self {
+            address,
+            attempts,
+            stop,
+            thread,
+        })
+    }
+
+    /// The environment variables that route the requests of the script to the sinkhole
+    fn env(&self) -> Vec<(String, String)> {
+        let proxy = format!("http://{}", self.address);
+        ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"]
+            .into_iter()
+            .map(|name| (name.to_string(), proxy.clone()))
+            .chain(
+                ["no_proxy", "NO_PROXY"]
+                    .into_iter()
+                    .map(|name| (name.to_string(), String::new())),
+            )
+            .collect()
+    }
+
+    /// Stop the sinkhole and return the destinations of the refused requests
+    fn finish(self) -> Vec<String> {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake up the listener, which checks the stop flag after every connection
+        let _ = TcpStream::connect(self.address);
+        let _ = self.thread.join();
+        let attempts = self.attempts.lock().unwrap();
+        attempts.clone()
+    }
+}
+
+/// The destination of the proxy request on `stream`: the `host:port` of a `CONNECT` request,
+/// and the URL of any other request
+fn destination(stream: &TcpStream) -> Option<String> {
+    let mut request_line = String::new();
+    BufReader::new(stream).read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    Some(match method {
+        "CONNECT" => format!("https://{target}"),
+        _ => target.to_string(),
+    })
+}
+
+/// The `unshare` command that runs a program without network, if it is available
+#[cfg(target_os = "linux")]
+fn unshare_command() -> Option<Vec<OsString>> {
+    // without root, the network namespace needs a user namespace
+    [
+        vec!["unshare", "--net"],
+        vec!["unshare", "--user", "--map-root-user", "--net"],
+    ]
+    .into_iter()
+    .find(|command| {
+        Command::new(command[0])
+            .args(&command[1..])
+            .arg("true")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    })
+    .map(|command| command.into_iter().map(OsString::from).collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unshare_command() -> Option<Vec<OsString>> {
+    None
+}
+
+/// Enforces the network policy while the build script runs
+pub struct NetworkGuard {
+    policy: NetworkPolicy,
+    unshare: Option<Vec<OsString>>,
+    sinkhole: Option<Sinkhole>,
+}
+
+impl NetworkGuard {
+    /// Prepare the network `policy` for the build script
+    pub fn start(policy: NetworkPolicy) -> Result<Self, NetworkError> {
+        let unshare = match policy {
+            NetworkPolicy::None => {
+                let unshare = unshare_command();
+                if unshare.is_none() {
+                    tracing::warn!(
+                        "Cannot run the build script without network, only the requests through the proxy variables are blocked"
+                    );
+                }
+                unshare
+            }
+            _ => None,
+        };
+        let sinkhole = match policy {
+            NetworkPolicy::Allow => None,
+            _ if unshare.is_some() => None,
+            _ => Some(Sinkhole::start()?),
+        };
+        Ok(Self {
+            policy,
+            unshare,
+            sinkhole,
+        })
+    }
+
+    /// The command that runs `program` with `args` under the policy
+    pub fn command(&self, program: &str, args: &[OsString]) -> (String, Vec<OsString>) {
+        match &self.unshare {
+            Some(unshare) => (
+                unshare[0].to_string_lossy().into_owned(),
+                unshare[1..]
+                    .iter()
+                    .cloned()
+                    .chain(std::iter::once(OsString::from(program)))
+                    .chain(args.iter().cloned())
+                    .collect(),
+            ),
+            None => (program.to_string(), args.to_vec()),
+        }
+    }
+
+    /// The additional environment variables of the script
+    pub fn env(&self) -> Vec<(String, String)> {
+        self.sinkhole
+            .as_ref()
+            .map(|sinkhole| sinkhole.env())
+            .unwrap_or_default()
+    }
+
+    /// Report the requests of the script. They are an error unless the policy is `warn`.
+    pub fn finish(self) -> Result<(), NetworkError> {
+        let Some(sinkhole) = self.sinkhole else {
+            return Ok(());
+        };
+        let attempts = sinkhole.finish();
+        if attempts.is_empty() {
+            return Ok(());
+        }
+
+        let attempts = attempts
+            .iter()
+            .counts()
+            .into_iter()
+            .sorted()
+            .map(|(destination, count)| match count {
+                1 => format!(" - {destination}"),
+                count => format!(" - {destination} ({count} times)"),
+            })
+            .join("\n");
+        if self.policy == NetworkPolicy::Warn {
+            tracing::warn!(
+                "The build script tried to access the network:\n{}",
+                attempts
+            );
+            return Ok(());
+        }
+        Err(NetworkError::Accessed {
+            policy: self.policy,
+            attempts,
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        ffi::OsString,
+        io::{Read, Write},
+        net::TcpListener,
+        process::Command,
+        time::Duration,
+    };
+
+    use super::{NetworkError, NetworkGuard};
+    use crate::recipe::parser::NetworkPolicy;
+
+    /// Run a script that curls a local server under `policy`. Returns whether the script
+    /// succeeded, whether the server got the request, and the result of the guard.
+    fn curl_local_server(policy: NetworkPolicy) -> Option<(bool, bool, Result<(), NetworkError>)> {
+        if Command::new("curl").arg("--version").output().is_err() {
+            // curl is not installed
+            return None;
+        }
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.txt", server.local_addr().unwrap());
+        let guard = NetworkGuard::start(policy).unwrap();
+        let (program, args) = guard.command(
+            "/bin/bash",
+            &[
+                OsString::from("-c"),
+                OsString::from(format!("curl --fail --silent --max-time 5 {url}")),
+            ],
+        );
+        let mut child = Command::new(program)
+            .args(args)
+            .env_remove("http_proxy")
+            .env_remove("HTTP_PROXY")
+            .envs(guard.env())
+            .spawn()
+            .unwrap();
+
+        server.set_nonblocking(true).unwrap();
+        let mut received = false;
+        let success = loop {
+            if let Ok((mut stream, _)) = server.accept() {
+                stream.set_nonblocking(false).unwrap();
+                let _ = stream.read(&mut [0u8; 1024]);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                );
+                received = true;
+            }
+            if let Some(status) = child.try_wait().unwrap() {
+                break status.success();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        Some((success, received, guard.finish()))
+    }
+
+    #[test]
+    fn allow() {
+        let Some((success, received, result)) = curl_local_server(NetworkPolicy::Allow) else {
+            return;
+        };
+        assert!(success && received);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn warn() {
+        let Some((success, received, result)) = curl_local_server(NetworkPolicy::Warn) else {
+            return;
+        };
+        assert!(!success && !received);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn error() {
+        let Some((success, received, result)) = curl_local_server(NetworkPolicy::Error) else {
+            return;
+        };
+        assert!(!success && !received);
+        match result {
+            Err(NetworkError::Accessed { attempts, .. }) => {
+                assert!(attempts.starts_with(" - http://127.0.0.1:"));
+                assert!(attempts.ends_with("/file.txt"));
+            }
+            other => panic!("expected an error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn none() {
+        let Some((success, received, result)) = curl_local_server(NetworkPolicy::None) else {
+            return;
+        };
+        // the request fails in the namespace, or is refused by the proxy without one
+        assert!(!success && !received);
+        if cfg!(target_os = "linux") && super::unshare_command().is_some() {
+            assert!(result.is_ok());
+        } else {
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! The files of the outputs of a recipe. Every output packages the new files of its host prefix,
+//! or only the ones that match the globs of its `build.files`. A file can only belong to one
+//! output: the packages would overwrite each other's file when they are installed together.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use globset::{Glob, GlobSetBuilder};
+
+/// An error of the file selection of an output
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum OutputFilesError {
+    /// A glob of `build.files` is invalid
+    #[error("Invalid glob in `build.files`: {0}")]
+    InvalidGlob(#[from] globset::Error),
+
+    /// The file is packaged by two outputs
+    #[error("{} is packaged by both {first} and {second} (see `build.files`)", path.display())]
+    ClaimedTwice {
+        /// The path of the file, relative to the prefix
+        path: PathBuf,
+        /// The output that packaged the file first
+        first: String,
+        /// The output that packages the file again
+        second: String,
+    },
+}
+
+/// Select the new `files` of the `prefix` (absolute paths) that match one of the `globs`
+/// (relative to the prefix). Without globs, all files are selected.
+pub fn select_files(
+    files: &HashSet<PathBuf>,
+    prefix: &Path,
+    globs: &[String],
+) -> Result<HashSet<PathBuf>, OutputFilesError> {
+    if globs.is_empty() {
+        return Ok(files.clone());
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    let globset = builder.build()?;
+
+    let selected = files
+        .iter()
+        .filter(|file| {
+            file.strip_prefix(prefix)
+                .map_or(false, |relative| globset.is_match(relative))
+        })
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    for glob in globs {
+        let matcher = Glob::new(glob)?.compile_matcher();
+        if !selected.iter().any(|file| {
+            file.strip_prefix(prefix)
+                .map_or(false, |relative| matcher.is_match(relative))
+        }) {
+            tracing::warn!("The glob {} of `build.files` matches no new file", glob);
+        }
+    }
+
+    Ok(selected)
+}
+
+/// The files that the outputs of the current invocation have packaged. The variants of an output
+/// have the same files, only two different outputs may not.
+#[derive(Debug, Clone, Default)]
+pub struct ClaimedFiles(Arc<Mutex<HashMap<PathBuf, String>>>);
+
+impl ClaimedFiles {
+    /// Claim the `files` (relative to the prefix) for the output `name`. Fails on the first file
+    /// that another output claimed, and claims none of the files then.
+    pub fn claim<'a>(
+        &self,
+        name: &str,
+        files: impl IntoIterator<Item = &'a Path>,
+    ) -> Result<(), OutputFilesError> {
+        let mut claimed = self
+            .0
+            .lock()
+            .expect("the claimed files lock is not poisoned");
+        let files = files.into_iter().collect::<Vec<_>>();
+        for file in &files {
+            if let Some(first) = claimed.get(*file) {
+                if first != name {
+                    return Err(OutputFilesError::ClaimedTwice {
+                        path: file.to_path_buf(),
+                        first: first.clone(),
+                        second: name.to_string(),
+                    });
+                }
+            }
+        }
+        for file in files {
+            claimed.insert(file.to_path_buf(), name.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+
+    use super::{select_files, ClaimedFiles, OutputFilesError};
+
+    #[test]
+    fn selected_files() {
+        let prefix = Path::new("/prefix");
+        let files = ["lib/libfoo.so.1", "include/foo.h", "lib/pkgconfig/foo.pc"]
+            .iter()
+            .map(|file| prefix.join(file))
+            .collect::<HashSet<_>>();
+
+        assert_eq!(select_files(&files, prefix, &[]).unwrap(), files);
+
+        let selected = select_files(
+            &files,
+            prefix,
+            &["include/**".to_string(), "lib/pkgconfig/*".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            selected,
+            HashSet::from([
+                PathBuf::from("/prefix/include/foo.h"),
+                PathBuf::from("/prefix/lib/pkgconfig/foo.pc")
+            ])
+        );
+    }
+
+    #[test]
+    fn files_of_two_outputs() {
+        let claimed = ClaimedFiles::default();
+        claimed
+            .claim("libfoo", [Path::new("lib/libfoo.so.1")])
+            .unwrap();
+        // another variant of the same output
+        claimed
+            .claim("libfoo", [Path::new("lib/libfoo.so.1")])
+            .unwrap();
+
+        let err = claimed
+            .claim(
+                "libfoo-devel",
+                [Path::new("include/foo.h"), Path::new("lib/libfoo.so.1")],
+            )
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            OutputFilesError::ClaimedTwice { path, first, second }
+                if path == Path::new("lib/libfoo.so.1") && first == "libfoo" && second == "libfoo-devel"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "lib/libfoo.so.1 is packaged by both libfoo and libfoo-devel (see `build.files`)"
+        );
+
+        // nothing was claimed by the failed claim
+        claimed
+            .claim("foo-headers", [Path::new("include/foo.h")])
+            .unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+//! A stable, versioned description of the outputs of a recipe, for tools that plan builds.
+//!
+//! The shape of [`OutputMetadataV1`] is fixed by the JSON schema in
+//! `schemas/output_metadata_v1.json`. Fields are only ever added in a new version of the model
+//! (with a new schema), never changed or removed.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metadata::Output,
+    recipe::parser::Source,
+    render::resolved_dependencies::{finalize_outputs, ResolveError},
+    tool_configuration,
+};
+
+/// The JSON schema of a list of [`OutputMetadataV1`]
+pub const OUTPUT_METADATA_V1_SCHEMA: &str = include_str!("../schemas/output_metadata_v1.json");
+
+/// The metadata of one output (and variant) of a recipe, version 1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputMetadataV1 {
+    /// Always `1`
+    pub schema_version: u32,
+    /// The name of the package
+    pub name: String,
+    /// The version of the package
+    pub version: String,
+    /// The build string of the package
+    pub build_string: String,
+    /// The subdirectory of the channel (e.g. `linux-64` or `noarch`)
+    pub subdir: String,
+    /// The noarch type (`python` or `generic`), if any
+    pub noarch: Option<String>,
+    /// The variant of the output
+    pub variant: BTreeMap<String, String>,
+    /// The run requirements
+    pub run_dependencies: Vec<String>,
+    /// The run constraints
+    pub run_constraints: Vec<String>,
+    /// Whether the run requirements contain the run exports of the resolved build and host
+    /// dependencies, or are only the ones of the recipe
+    pub dependencies_resolved: bool,
+    /// The license of the package
+    pub license: Option<String>,
+    /// The URLs of the url and git sources
+    pub source_urls: Vec<String>,
+}
+
+impl From<&Output> for OutputMetadataV1 {
+    fn from(output: &Output) -> Self {
+        let recipe = &output.recipe;
+        let noarch = recipe.build().noarch();
+        let noarch = if noarch.is_python() {
+            Some("python".to_string())
+        } else if noarch.is_generic() {
+            Some("generic".to_string())
+        } else {
+            None
+        };
+
+        let (run_dependencies, run_constraints) = match &output.finalized_dependencies {
+            Some(finalized) => (
+                finalized.run.depends.iter().map(|d| d.render()).collect(),
+                finalized
+                    .run
+                    .constrains
+                    .iter()
+                    .map(|d| d.render())
+                    .collect(),
+            ),
+            None => (
+                recipe
+                    .requirements()
+                    .run()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+                recipe
+                    .requirements()
+                    .run_constrained()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            ),
+        };
+
+        let source_urls = recipe
+            .sources()
+            .iter()
+            .filter_map(|source| match source {
+                Source::Url(url) => Some(url.url().to_string()),
+                Source::Git(git) => Some(git.url().to_string()),
+                Source::Path(_) => None,
+            })
+            .collect();
+
+        OutputMetadataV1 {
+            schema_version: 1,
+            name: output.name().as_normalized().to_string(),
+            version: output.version().to_string(),
+            build_string: output.build_string().unwrap_or_default().to_string(),
+            subdir: output.build_configuration.target_platform.to_string(),
+            noarch,
+            variant: output.build_configuration.variant.clone(),
+            run_dependencies,
+            run_constraints,
+            dependencies_resolved: output.finalized_dependencies.is_some(),
+            license: recipe.about().license().map(ToString::to_string),
+            source_urls,
+        }
+    }
+}
+
+/// The metadata of all `outputs` (the outputs and variants of a recipe). With a
+/// `tool_configuration`, the dependencies of the outputs that are not finalized yet are resolved
+/// first, so that the run requirements contain the run exports.
+pub async fn output_metadata(
+    outputs: &[Output],
+    tool_configuration: Option<&tool_configuration::Configuration>,
+) -> Result<Vec<OutputMetadataV1>, ResolveError> {
+    let metadata = match tool_configuration {
+        Some(tool_configuration) => finalize_outputs(outputs, tool_configuration)
+            .await?
+            .iter()
+            .map(OutputMetadataV1::from)
+            .collect(),
+        None => outputs.iter().map(OutputMetadataV1::from).collect(),
+    };
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{OutputMetadataV1, OUTPUT_METADATA_V1_SCHEMA};
+    use crate::metadata::Output;
+
+    /// Check `value` against the parts of JSON schema that the output metadata schema uses
+    fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => vec![types.as_str().unwrap()],
+            };
+            let matches = types.iter().any(|ty| match *ty {
+                "object" => value.is_object(),
+                "array" => value.is_array(),
+                "string" => value.is_string(),
+                "integer" => value.is_u64() || value.is_i64(),
+                "boolean" => value.is_boolean(),
+                "null" => value.is_null(),
+                ty => panic!("unsupported type {ty} in the schema"),
+            });
+            if !matches {
+                return Err(format!("{path}: {value} is not of type {types:?}"));
+            }
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                return Err(format!("{path}: {value} is not one of {allowed:?}"));
+            }
+        }
+        if let Value::Object(object) = value {
+            for key in schema["required"].as_array().into_iter().flatten() {
+                if !object.contains_key(key.as_str().unwrap()) {
+                    return Err(format!("{path}: missing {key}"));
+                }
+            }
+            for (key, item) in object {
+                let item_path = format!("{path}.{key}");
+                match (
+                    schema.get("properties").and_then(|p| p.get(key)),
+                    schema.get("additionalProperties"),
+                ) {
+                    (Some(item_schema), _) => validate(item, item_schema, &item_path)?,
+                    (None, Some(Value::Bool(false))) => {
+                        return Err(format!("{item_path} is not in the schema"))
+                    }
+                    (None, Some(item_schema)) => validate(item, item_schema, &item_path)?,
+                    (None, None) => {}
+                }
+            }
+        }
+        if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+            for (index, item) in items.iter().enumerate() {
+                validate(item, item_schema, &format!("{path}[{index}]"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fixture(name: &str) -> Output {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/rendered_recipes")
+            .join(name);
+        serde_yaml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fixtures_match_the_schema() {
+        let schema: Value = serde_json::from_str(OUTPUT_METADATA_V1_SCHEMA).unwrap();
+
+        let rich = fixture("rich_recipe.yaml");
+        let mut unresolved = fixture("curl_recipe.yaml");
+        unresolved.finalized_dependencies = None;
+        let metadata = [&rich, &unresolved]
+            .into_iter()
+            .map(OutputMetadataV1::from)
+            .collect::<Vec<_>>();
+
+        let value = serde_json::to_value(&metadata).unwrap();
+        validate(&value, &schema, "$").unwrap();
+
+        // every field of the model is described by the schema and vice versa
+        let mut fields = value[0].as_object().unwrap().keys().collect::<Vec<_>>();
+        fields.sort();
+        let mut properties = schema["items"]["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>();
+        properties.sort();
+        assert_eq!(fields, properties);
+
+        let rich = &metadata[0];
+        assert_eq!(rich.name, "rich");
+        assert_eq!(rich.build_string, "pyh4616a5c_0");
+        assert_eq!(rich.subdir, "noarch");
+        assert_eq!(rich.noarch.as_deref(), Some("python"));
+        assert_eq!(rich.license.as_deref(), Some("MIT"));
+        assert!(rich.dependencies_resolved);
+        assert_eq!(
+            rich.source_urls,
+            ["https://pypi.io/packages/source/r/rich/rich-13.4.2.tar.gz"]
+        );
+
+        let curl = &metadata[1];
+        assert_eq!(curl.subdir, "osx-arm64");
+        assert_eq!(curl.noarch, None);
+        assert!(!curl.dependencies_resolved);
+    }
+
+    #[test]
+    fn schema_rejects_unknown_shapes() {
+        let schema: Value = serde_json::from_str(OUTPUT_METADATA_V1_SCHEMA).unwrap();
+        let metadata = OutputMetadataV1::from(&fixture("rich_recipe.yaml"));
+
+        let mut value = serde_json::to_value([&metadata]).unwrap();
+        value[0]["extra"] = Value::Bool(true);
+        assert!(validate(&value, &schema, "$").is_err());
+
+        let mut value = serde_json::to_value([&metadata]).unwrap();
+        value[0]["schema_version"] = Value::from(2);
+        assert!(validate(&value, &schema, "$").is_err());
+
+        let mut value = serde_json::to_value([&metadata]).unwrap();
+        value[0].as_object_mut().unwrap().remove("license");
+        assert!(validate(&value, &schema, "$").is_err());
+    }
+}
@@ -0,0 +1,561 @@
+//! Inspect built packages (`.conda` and `.tar.bz2`) without extracting them.
+//!
+//! The files of the `info/` folder are read into memory the first time they are needed. For
+//! `.conda` packages only the (small) info archive is read for this, the payload archive is only
+//! opened when the payload entries are listed or a payload file is extracted.
+
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use fs_err as fs;
+use once_cell::sync::OnceCell;
+use rattler_conda_types::{
+    package::{AboutJson, ArchiveType, IndexJson, LinkJson, NoArchLinks, PackageFile, PathsJson},
+    MatchSpec, ParseMatchSpecError,
+};
+use rattler_package_streaming::{read, seek, ExtractError};
+
+/// Errors that can occur while inspecting a package
+#[derive(Debug, thiserror::Error)]
+pub enum InspectError {
+    /// The file is neither a `.conda` nor a `.tar.bz2` file
+    #[error("{0} is not a package (expected a `.conda` or `.tar.bz2` file)")]
+    UnknownArchiveType(PathBuf),
+
+    /// The package could not be read
+    #[error("failed to read package: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// One of the archives of a `.conda` package could not be opened
+    #[error("failed to read package: {0}")]
+    Extract(#[from] ExtractError),
+
+    /// The requested file is not part of the package
+    #[error("{0} not found in package")]
+    FileNotFound(PathBuf),
+
+    /// The `info/link.json` of the package does not match its `info/index.json`
+    #[error("invalid info/link.json: {0}")]
+    InvalidLinkJson(String),
+
+    /// A `depends` or `constrains` entry of the `info/index.json` is not a valid match spec
+    #[error("invalid match spec in info/index.json: {0}")]
+    InvalidMatchSpec(#[from] ParseMatchSpecError),
+}
+
+/// A single file of the package payload (everything outside of `info/`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageEntry {
+    /// The path of the file relative to the root of the package
+    pub path: PathBuf,
+    /// The size of the file in bytes (`0` for symlinks)
+    pub size: u64,
+    /// The unix permissions of the file
+    pub mode: u32,
+    /// Whether the file is a symlink
+    pub is_symlink: bool,
+}
+
+/// Read only access to the metadata and the file list of a built package
+pub struct PackageInspector {
+    path: PathBuf,
+    archive_type: ArchiveType,
+    info: OnceCell<BTreeMap<PathBuf, Vec<u8>>>,
+    payload: OnceCell<Vec<PackageEntry>>,
+}
+
+impl PackageInspector {
+    /// Open the package at `path`. Nothing is read until the contents are accessed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, InspectError> {
+        let path = path.as_ref();
+        let archive_type = ArchiveType::try_from(path)
+            .ok_or_else(|| InspectError::UnknownArchiveType(path.to_path_buf()))?;
+        // fail early if the file does not exist
+        fs::metadata(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            archive_type,
+            info: OnceCell::new(),
+            payload: OnceCell::new(),
+        })
+    }
+
+    /// The path of the package file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The archive type of the package
+    pub fn archive_type(&self) -> ArchiveType {
+        self.archive_type
+    }
+
+    /// All files of the `info/` folder, keyed by their path relative to the root of the package
+    /// (e.g. `info/index.json`)
+    pub fn info_files(&self) -> Result<&BTreeMap<PathBuf, Vec<u8>>, InspectError> {
+        self.info.get_or_try_init(|| match self.archive_type {
+            ArchiveType::Conda => {
+                let archive = seek::stream_conda_info(fs::File::open(&self.path)?)?;
+                let (info, _) = scan(archive)?;
+                Ok(info)
+            }
+            ArchiveType::TarBz2 => {
+                let (info, payload) = self.scan_tar_bz2()?;
+                let _ = self.payload.set(payload);
+                Ok(info)
+            }
+        })
+    }
+
+    /// The contents of a single file of the `info/` folder (e.g. `info/about.json`)
+    pub fn info_file(&self, path: impl AsRef<Path>) -> Result<&[u8], InspectError> {
+        let path = path.as_ref();
+        self.info_files()?
+            .get(path)
+            .map(Vec::as_slice)
+            .ok_or_else(|| InspectError::FileNotFound(path.to_path_buf()))
+    }
+
+    /// Parse one of the standard metadata documents of the package
+    pub fn read_package_file<P: PackageFile>(&self) -> Result<P, InspectError> {
+        Ok(P::from_reader(self.info_file(P::package_path())?)?)
+    }
+
+    /// The `info/index.json` of the package
+    pub fn index_json(&self) -> Result<IndexJson, InspectError> {
+        self.read_package_file()
+    }
+
+    /// The `info/paths.json` of the package
+    pub fn paths_json(&self) -> Result<PathsJson, InspectError> {
+        self.read_package_file()
+    }
+
+    /// The `info/about.json` of the package
+    pub fn about_json(&self) -> Result<AboutJson, InspectError> {
+        self.read_package_file()
+    }
+
+    /// The run requirements of the package (`depends` of the `info/index.json`)
+    pub fn run_requirements(&self) -> Result<Vec<MatchSpec>, InspectError> {
+        parse_specs(&self.index_json()?.depends)
+    }
+
+    /// The run constraints of the package (`constrains` of the `info/index.json`). They restrict
+    /// the versions of the packages that are installed alongside it, without installing them.
+    pub fn run_constraints(&self) -> Result<Vec<MatchSpec>, InspectError> {
+        parse_specs(&self.index_json()?.constrains)
+    }
+
+    /// The `info/link.json` of the package, if it has one (only noarch packages do)
+    pub fn link_json(&self) -> Result<Option<LinkJson>, InspectError> {
+        match self.read_package_file() {
+            Ok(link_json) => Ok(Some(link_json)),
+            Err(InspectError::FileNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check that the package has an `info/link.json` exactly if it is a noarch package, and
+    /// that the noarch type and metadata version of the file are the ones conda expects
+    pub fn check_link_json(&self) -> Result<(), InspectError> {
+        let noarch = self.index_json()?.noarch;
+        let link_json = match (self.link_json()?, noarch.is_none()) {
+            (None, true) => return Ok(()),
+            (Some(_), true) => {
+                return Err(InspectError::InvalidLinkJson(
+                    "the package is not a noarch package".to_string(),
+                ))
+            }
+            (None, false) => {
+                return Err(InspectError::FileNotFound(LinkJson::package_path().into()))
+            }
+            (Some(link_json), false) => link_json,
+        };
+
+        if link_json.package_metadata_version != 1 {
+            return Err(InspectError::InvalidLinkJson(format!(
+                "unsupported package_metadata_version {}",
+                link_json.package_metadata_version
+            )));
+        }
+
+        let link_noarch = match link_json.noarch {
+            NoArchLinks::Python(_) => "python",
+            NoArchLinks::Generic => "generic",
+        };
+        let index_noarch = if noarch.is_python() {
+            "python"
+        } else {
+            "generic"
+        };
+        if link_noarch != index_noarch {
+            return Err(InspectError::InvalidLinkJson(format!(
+                "noarch type `{link_noarch}` does not match `{index_noarch}` of info/index.json"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The files of the package payload (directories are skipped)
+    pub fn payload_entries(&self) -> Result<impl Iterator<Item = &PackageEntry>, InspectError> {
+        let payload = self.payload.get_or_try_init(|| match self.archive_type {
+            ArchiveType::Conda => {
+                let archive = seek::stream_conda_content(fs::File::open(&self.path)?)?;
+                let (_, payload) = scan(archive)?;
+                Ok::<_, InspectError>(payload)
+            }
+            ArchiveType::TarBz2 => {
+                let (info, payload) = self.scan_tar_bz2()?;
+                let _ = self.info.set(info);
+                Ok(payload)
+            }
+        })?;
+        Ok(payload.iter())
+    }
+
+    /// Extract a single file of the package to `dest` (the path of the new file)
+    pub fn extract_file(
+        &self,
+        path: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> Result<(), InspectError> {
+        let (path, dest) = (path.as_ref(), dest.as_ref());
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // info files are already in memory (or small enough to be loaded)
+        if path.starts_with("info") {
+            return Ok(fs::write(dest, self.info_file(path)?)?);
+        }
+
+        let file = fs::File::open(&self.path)?;
+        let found = match self.archive_type {
+            ArchiveType::Conda => unpack_entry(seek::stream_conda_content(file)?, path, dest)?,
+            ArchiveType::TarBz2 => unpack_entry(read::stream_tar_bz2(file), path, dest)?,
+        };
+
+        if found {
+            Ok(())
+        } else {
+            Err(InspectError::FileNotFound(path.to_path_buf()))
+        }
+    }
+
+    /// `.tar.bz2` packages mix info and payload files, so both are collected in a single pass
+    fn scan_tar_bz2(
+        &self,
+    ) -> Result<(BTreeMap<PathBuf, Vec<u8>>, Vec<PackageEntry>), InspectError> {
+        let archive = read::stream_tar_bz2(fs::File::open(&self.path)?);
+        scan(archive)
+    }
+}
+
+fn parse_specs(specs: &[String]) -> Result<Vec<MatchSpec>, InspectError> {
+    Ok(specs
+        .iter()
+        .map(|spec| MatchSpec::from_str(spec))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Read all info files of a tar archive into memory and collect its payload entries
+fn scan(
+    mut archive: tar::Archive<impl Read>,
+) -> Result<(BTreeMap<PathBuf, Vec<u8>>, Vec<PackageEntry>), InspectError> {
+    let mut info = BTreeMap::new();
+    let mut payload = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        if entry_type.is_dir() {
+            continue;
+        }
+        let (size, mode) = (header.size()?, header.mode()?);
+
+        let path = entry.path()?.to_path_buf();
+        if path.starts_with("info") {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            info.insert(path, contents);
+        } else {
+            payload.push(PackageEntry {
+                path,
+                size,
+                mode,
+                is_symlink: entry_type.is_symlink(),
+            });
+        }
+    }
+
+    Ok((info, payload))
+}
+
+/// Unpack the entry at `path` of a tar archive to `dest`. Returns `false` if the archive does
+/// not contain the entry.
+fn unpack_entry(
+    mut archive: tar::Archive<impl Read>,
+    path: &Path,
+    dest: &Path,
+) -> Result<bool, InspectError> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == path {
+            entry.unpack(dest)?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use fs_err as fs;
+    use rattler_conda_types::package::{ArchiveType, NoArchLinks};
+    use rattler_package_streaming::write::{
+        write_conda_package, write_tar_bz2_package, CompressionLevel,
+    };
+
+    use super::{InspectError, PackageInspector};
+
+    /// Creates the files of a small package in `base` and returns their paths. The `extra_files`
+    /// are added to (or replace) the default files.
+    fn package_files(base: &Path, extra_files: &[(&str, &str)]) -> Vec<PathBuf> {
+        let mut files = vec![
+            (
+                "info/index.json",
+                r#"{"name": "inspect", "version": "1.2.3", "build": "h123_0", "build_number": 0, "subdir": "noarch", "depends": ["python"]}"#,
+            ),
+            (
+                "info/paths.json",
+                r#"{"paths": [{"_path": "share/inspect/data.txt", "path_type": "hardlink", "size_in_bytes": 11}], "paths_version": 1}"#,
+            ),
+            (
+                "info/about.json",
+                r#"{"summary": "A package to inspect", "license": "MIT"}"#,
+            ),
+            ("share/inspect/data.txt", "hello world"),
+        ];
+        for (path, contents) in extra_files {
+            files.retain(|(p, _)| p != path);
+            files.push((*path, *contents));
+        }
+
+        files
+            .iter()
+            .map(|(path, contents)| {
+                let path = base.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn create_package(
+        dir: &Path,
+        archive_type: ArchiveType,
+        extra_files: &[(&str, &str)],
+    ) -> PathBuf {
+        let base = dir.join("pkg");
+        let files = package_files(&base, extra_files);
+
+        #[cfg(unix)]
+        let files = {
+            let mut files = files;
+            let link = base.join("share/inspect/link.txt");
+            std::os::unix::fs::symlink("data.txt", &link).unwrap();
+            files.push(link);
+            files
+        };
+
+        let archive = dir.join(format!("inspect-1.2.3-h123_0{}", archive_type.extension()));
+        let writer = fs::File::create(&archive).unwrap();
+        match archive_type {
+            ArchiveType::TarBz2 => {
+                write_tar_bz2_package(writer, &base, &files, CompressionLevel::Default, None)
+                    .unwrap()
+            }
+            ArchiveType::Conda => write_conda_package(
+                writer,
+                &base,
+                &files,
+                CompressionLevel::Default,
+                "inspect-1.2.3-h123_0",
+                None,
+            )
+            .unwrap(),
+        }
+        archive
+    }
+
+    #[test]
+    fn round_trip() {
+        for archive_type in [ArchiveType::TarBz2, ArchiveType::Conda] {
+            let tmp = tempfile::tempdir().unwrap();
+            let package = create_package(tmp.path(), archive_type, &[]);
+            let inspector = PackageInspector::open(&package).unwrap();
+            assert_eq!(inspector.archive_type(), archive_type);
+
+            let index = inspector.index_json().unwrap();
+            assert_eq!(index.name.as_normalized(), "inspect");
+            assert_eq!(index.version.to_string(), "1.2.3");
+            assert_eq!(index.depends, vec!["python"]);
+
+            let paths = inspector.paths_json().unwrap();
+            assert_eq!(
+                paths.paths[0].relative_path,
+                PathBuf::from("share/inspect/data.txt")
+            );
+
+            let about = inspector.about_json().unwrap();
+            assert_eq!(about.summary.as_deref(), Some("A package to inspect"));
+
+            let entries = inspector.payload_entries().unwrap().collect::<Vec<_>>();
+            let data = entries
+                .iter()
+                .find(|e| e.path == Path::new("share/inspect/data.txt"))
+                .unwrap();
+            assert_eq!(data.size, 11);
+            assert!(!data.is_symlink);
+            assert!(entries.iter().all(|e| !e.path.starts_with("info")));
+            #[cfg(unix)]
+            {
+                let link = entries
+                    .iter()
+                    .find(|e| e.path == Path::new("share/inspect/link.txt"))
+                    .unwrap();
+                assert!(link.is_symlink);
+            }
+
+            let dest = tmp.path().join("extracted/data.txt");
+            inspector
+                .extract_file("share/inspect/data.txt", &dest)
+                .unwrap();
+            assert_eq!(fs::read_to_string(&dest).unwrap(), "hello world");
+
+            let dest = tmp.path().join("extracted/about.json");
+            inspector.extract_file("info/about.json", &dest).unwrap();
+            assert!(fs::read_to_string(&dest).unwrap().contains("MIT"));
+
+            assert!(matches!(
+                inspector.extract_file("does/not/exist", tmp.path().join("nope")),
+                Err(InspectError::FileNotFound(_))
+            ));
+            assert!(matches!(
+                inspector.info_file("info/recipe/recipe.yaml"),
+                Err(InspectError::FileNotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn link_json() {
+        const NOARCH_INDEX: &str = r#"{"name": "inspect", "version": "1.2.3", "build": "h123_0", "build_number": 0, "subdir": "noarch", "noarch": "python"}"#;
+        const PYTHON_LINK: &str = r#"{"noarch": {"entry_points": ["inspect = inspect.cli:main"], "type": "python"}, "package_metadata_version": 1}"#;
+        const GENERIC_LINK: &str =
+            r#"{"noarch": {"type": "generic"}, "package_metadata_version": 1}"#;
+
+        let check = |extra_files: &[(&str, &str)]| {
+            let tmp = tempfile::tempdir().unwrap();
+            let package = create_package(tmp.path(), ArchiveType::Conda, extra_files);
+            PackageInspector::open(package).unwrap().check_link_json()
+        };
+
+        // not a noarch package and no link.json
+        check(&[]).unwrap();
+        check(&[
+            ("info/index.json", NOARCH_INDEX),
+            ("info/link.json", PYTHON_LINK),
+        ])
+        .unwrap();
+
+        assert!(matches!(
+            check(&[("info/link.json", GENERIC_LINK)]),
+            Err(InspectError::InvalidLinkJson(_))
+        ));
+        assert!(matches!(
+            check(&[("info/index.json", NOARCH_INDEX)]),
+            Err(InspectError::FileNotFound(_))
+        ));
+        assert!(matches!(
+            check(&[
+                ("info/index.json", NOARCH_INDEX),
+                ("info/link.json", GENERIC_LINK)
+            ]),
+            Err(InspectError::InvalidLinkJson(_))
+        ));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let package = create_package(
+            tmp.path(),
+            ArchiveType::TarBz2,
+            &[
+                ("info/index.json", NOARCH_INDEX),
+                ("info/link.json", PYTHON_LINK),
+            ],
+        );
+        let link_json = PackageInspector::open(package)
+            .unwrap()
+            .link_json()
+            .unwrap()
+            .unwrap();
+        match link_json.noarch {
+            NoArchLinks::Python(python) => {
+                assert_eq!(
+                    python.entry_points[0].to_string(),
+                    "inspect = inspect.cli:main"
+                )
+            }
+            NoArchLinks::Generic => panic!("expected a noarch python link.json"),
+        }
+    }
+
+    #[test]
+    fn run_constraints() {
+        const INDEX: &str = r#"{"name": "inspect", "version": "1.2.3", "build": "h123_0", "build_number": 0, "subdir": "noarch", "depends": ["python >=3.8"], "constrains": ["cudatoolkit >=11.2,<12", "inspect-cli ==1.2.3 h123_0"]}"#;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let package = create_package(
+            tmp.path(),
+            ArchiveType::Conda,
+            &[("info/index.json", INDEX)],
+        );
+        let inspector = PackageInspector::open(package).unwrap();
+
+        let depends = inspector.run_requirements().unwrap();
+        assert_eq!(depends.len(), 1);
+        assert_eq!(depends[0].name.as_ref().unwrap().as_normalized(), "python");
+
+        let constrains = inspector.run_constraints().unwrap();
+        let names = constrains
+            .iter()
+            .map(|spec| spec.name.as_ref().unwrap().as_normalized())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["cudatoolkit", "inspect-cli"]);
+
+        // the default package has no constraints
+        let tmp = tempfile::tempdir().unwrap();
+        let package = create_package(tmp.path(), ArchiveType::TarBz2, &[]);
+        let inspector = PackageInspector::open(package).unwrap();
+        assert!(inspector.run_constraints().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_archive_type() {
+        assert!(matches!(
+            PackageInspector::open("foo-1.0-0.zip"),
+            Err(InspectError::UnknownArchiveType(_))
+        ));
+    }
+}
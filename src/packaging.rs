@@ -2,7 +2,7 @@ use fs_err as fs;
 use fs_err::File;
 use std::collections::HashSet;
 use std::io::{BufReader, Read, Write};
-use std::path::{Component, Path, PathBuf};
+use std::path::{Path, PathBuf};
 
 #[cfg(target_family = "unix")]
 use std::os::unix::prelude::OsStrExt;
@@ -11,12 +11,12 @@ use std::os::unix::prelude::OsStrExt;
 use std::os::unix::fs::symlink;
 
 use itertools::Itertools;
+use serde::Serialize;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 use rattler_conda_types::package::{
-    AboutJson, ArchiveType, FileMode, LinkJson, NoArchLinks, PathType, PathsEntry,
-    PrefixPlaceholder, PythonEntryPoints,
+    AboutJson, ArchiveType, EntryPoint, FileMode, PathType, PathsEntry, PrefixPlaceholder,
 };
 use rattler_conda_types::package::{IndexJson, PathsJson};
 use rattler_conda_types::{NoArchType, Platform};
@@ -27,9 +27,21 @@ use rattler_package_streaming::write::{
 
 use crate::macos;
 use crate::metadata::Output;
+use crate::recipe::parser::{DeduplicateFiles, LinkingCheckBehavior, SymlinkPolicy};
+use crate::tools::Tools;
 use crate::{linux, post};
+use noarch_python::NoarchPythonLayout;
 
-#[derive(Debug, thiserror::Error)]
+mod deduplicate;
+mod links;
+mod noarch_python;
+mod soname;
+mod xattrs;
+
+/// The name and version of the tool that created the package
+const TOOLS_VERSION: &str = concat!("rattler-build ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
 pub enum PackagingError {
     #[error("Serde error: {0}")]
     SerdeError(#[from] serde_yaml::Error),
@@ -59,16 +71,35 @@ pub enum PackagingError {
     VersionParseError(#[from] rattler_conda_types::ParseVersionError),
 
     #[error("Failed to relink ELF file: {0}")]
+    #[diagnostic(transparent)]
     LinuxRelinkError(#[from] linux::link::RelinkError),
 
     #[error("Failed to relink MachO file: {0}")]
+    #[diagnostic(transparent)]
     MacOSRelinkError(#[from] macos::link::RelinkError),
 
     #[error("Relink error: {0}")]
+    #[diagnostic(transparent)]
     RelinkError(#[from] crate::post::RelinkError),
 
     #[error(transparent)]
+    #[diagnostic(transparent)]
     SourceError(#[from] crate::source::SourceError),
+
+    #[error("{0}")]
+    MissingDsos(String),
+
+    #[error("Binaries still refer to the build directory after the relocation (see `build.dynamic_linking.build_reference_behavior`):\n{0}")]
+    BuildReferences(String),
+
+    #[error("Found symlinks to files of other packages (see `build.symlink_policy`):\n{0}")]
+    ForeignSymlinks(String),
+
+    #[error("The link {} points outside of the prefix, to {} (see `build.allow_external_links`)", path.display(), target.display())]
+    ExternalLink { path: PathBuf, target: PathBuf },
+
+    #[error("Found quarantined files, which the build downloaded without verifying them (see `build.xattr_policy`):\n{0}")]
+    QuarantinedFiles(String),
 }
 
 #[allow(unused_variables)]
@@ -165,11 +196,13 @@ fn create_prefix_placeholder(
 
 /// Create a `paths.json` file structure for the given paths.
 /// Paths should be given as absolute paths under the `path_prefix` directory.
-/// This function will also determine if the file is binary or text, and if it contains the prefix.
+/// This function will also determine if the file is binary or text, and if it contains the prefix
+/// (unless the file matches `ignore_prefix_files`).
 fn create_paths_json(
     paths: &HashSet<PathBuf>,
     path_prefix: &Path,
     encoded_prefix: &Path,
+    ignore_prefix_files: &globset::GlobSet,
 ) -> Result<PathsJson, PackagingError> {
     let mut paths_json = PathsJson {
         paths: Vec::new(),
@@ -209,7 +242,15 @@ fn create_paths_json(
                 paths_json.paths.push(path_entry);
             }
         } else if meta.is_file() {
-            let prefix_placeholder = create_prefix_placeholder(p, encoded_prefix)?;
+            let prefix_placeholder = if ignore_prefix_files.is_match(&relative_path) {
+                tracing::debug!(
+                    "Not detecting the prefix in {} (`build.ignore_prefix_files`)",
+                    relative_path.display()
+                );
+                None
+            } else {
+                create_prefix_placeholder(p, encoded_prefix)?
+            };
 
             let digest = compute_file_digest::<sha2::Sha256>(p)?;
 
@@ -222,15 +263,23 @@ fn create_paths_json(
                 size_in_bytes: Some(meta.len()),
             });
         } else if meta.file_type().is_symlink() {
-            let digest = compute_file_digest::<sha2::Sha256>(p)?;
+            // links to directories (and junctions) have no content of their own
+            let (sha256, size_in_bytes) = if p.is_dir() {
+                (None, None)
+            } else {
+                (
+                    Some(compute_file_digest::<sha2::Sha256>(p)?),
+                    Some(meta.len()),
+                )
+            };
 
             paths_json.paths.push(PathsEntry {
-                sha256: Some(digest),
+                sha256,
                 relative_path,
                 path_type: PathType::SoftLink,
                 prefix_placeholder: None,
                 no_link: false,
-                size_in_bytes: Some(meta.len()),
+                size_in_bytes,
             });
         }
     }
@@ -330,6 +379,15 @@ fn create_about_json(output: &Output) -> Result<String, PackagingError> {
         channels: output.build_configuration.channels.clone(),
     };
 
+    // record the tool that created the package, this is not part of the `AboutJson` type
+    let mut about_json = serde_json::to_value(about_json)?;
+    if let Some(about_map) = about_json.as_object_mut() {
+        about_map.insert(
+            "tools_version".to_string(),
+            serde_json::Value::from(TOOLS_VERSION),
+        );
+    }
+
     Ok(serde_json::to_string_pretty(&about_json)?)
 }
 
@@ -349,9 +407,10 @@ fn create_run_exports_json(output: &Output) -> Result<Option<String>, PackagingE
 }
 
 /// This function returns a HashSet of (recursively) all the files in the given directory.
+/// Symlinks and junctions to directories are recorded, but not traversed.
 pub fn record_files(directory: &PathBuf) -> Result<HashSet<PathBuf>, PackagingError> {
     let mut res = HashSet::new();
-    for entry in WalkDir::new(directory) {
+    for entry in WalkDir::new(directory).follow_links(false) {
         res.insert(entry?.path().to_owned());
     }
     Ok(res)
@@ -360,19 +419,17 @@ pub fn record_files(directory: &PathBuf) -> Result<HashSet<PathBuf>, PackagingEr
 /// This function copies the given file to the destination folder and
 /// transforms it on the way if needed.
 ///
-/// * For `noarch: python` packages, the "lib/pythonX.X" prefix is stripped so that only
-///   the "site-packages" part is kept. Additionally, any `__pycache__` directories or
-///  `.pyc` files are skipped.
-/// * For `noarch: python` packages, furthermore `bin` is replaced with `python-scripts`, and
-///   `Scripts` is replaced with `python-scripts` (on Windows only). All other files are included
-///   as-is.
-/// * Absolute symlinks are made relative so that they are easily relocatable.
+/// * For `noarch: python` packages, the files are mapped to the layout of the package (see
+///   [`NoarchPythonLayout::package_path`]). All other files are included as-is.
+/// * Absolute symlinks are made relative so that they are easily relocatable. On Windows, links
+///   (including junctions) must point into the prefix, unless they match `external_links`.
 fn write_to_dest(
     path: &Path,
     prefix: &Path,
     dest_folder: &Path,
     target_platform: &Platform,
-    noarch_type: &NoArchType,
+    noarch_python: Option<&NoarchPythonLayout>,
+    external_links: &globset::GlobSet,
 ) -> Result<Option<PathBuf>, PackagingError> {
     let path_rel = path.strip_prefix(prefix)?;
     let mut dest_path = dest_folder.join(path_rel);
@@ -398,64 +455,10 @@ fn write_to_dest(
         }
     }
 
-    if noarch_type.is_python() {
-        if ext == "pyc" {
-            return Ok(None); // skip .pyc files
-        }
-
-        // if any part of the path is __pycache__ skip it
-        if path_rel
-            .components()
-            .any(|c| c == Component::Normal("__pycache__".as_ref()))
-        {
-            return Ok(None);
-        }
-
-        if path_rel
-            .components()
-            .any(|c| c == Component::Normal("site-packages".as_ref()))
-        {
-            // check if site-packages is in the path and strip everything before it
-            let pat = std::path::Component::Normal("site-packages".as_ref());
-            let parts = path_rel.components();
-            let mut new_parts = Vec::new();
-            let mut found = false;
-            for part in parts {
-                if part == pat {
-                    found = true;
-                }
-                if found {
-                    new_parts.push(part);
-                }
-            }
-
-            dest_path = dest_folder.join(PathBuf::from_iter(new_parts));
-        } else if path_rel.starts_with("bin") || path_rel.starts_with("Scripts") {
-            // Replace bin with python-scripts. These should really be encoded
-            // as entrypoints but sometimes recipe authors forget or don't know
-            // how to do that. Maybe sometimes it's also not actually an
-            // entrypoint. The reason for this is that on Windows, the
-            // entrypoints are in `Scripts/...` folder, and on Unix they are in
-            // the `bin/...` folder. So we need to make sure that the
-            // entrypoints are in the right place.
-            let mut new_parts = path_rel.components().collect::<Vec<_>>();
-            new_parts[0] = Component::Normal("python-scripts".as_ref());
-
-            // on Windows, if the file ends with -script.py, remove the -script.py suffix
-            if let Some(Component::Normal(name)) = new_parts.last_mut() {
-                if let Some(name_str) = name.to_str() {
-                    if target_platform.is_windows() {
-                        if let Some(stripped_suffix) = name_str.strip_suffix("-script.py") {
-                            *name = stripped_suffix.as_ref();
-                        }
-                    }
-                }
-            }
-
-            dest_path = dest_folder.join(PathBuf::from_iter(new_parts));
-        } else {
-            // keep everything else as-is
-            dest_path = dest_folder.join(path_rel);
+    if let Some(layout) = noarch_python {
+        match layout.package_path(path_rel) {
+            Some(package_path) => dest_path = dest_folder.join(package_path),
+            None => return Ok(None),
         }
     }
 
@@ -487,51 +490,61 @@ fn write_to_dest(
             tracing::warn!("Could not read link at {:?}", path);
         }
 
-        #[cfg(target_family = "unix")]
-        fs::read_link(path).and_then(|target| {
-            if target.is_absolute() && target.starts_with(prefix) {
-                let rel_target = pathdiff::diff_paths(
-                    target,
-                    path.parent().ok_or(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Could not get parent directory",
-                    ))?,
-                )
-                .ok_or(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Could not get relative path",
-                ))?;
+        let allowed_outside = external_links.is_match(path_rel);
+        let link_target = links::link_target(path, prefix)?;
 
-                tracing::trace!(
-                    "Making symlink relative {:?} -> {:?}",
-                    dest_path,
-                    rel_target
-                );
-                symlink(&rel_target, &dest_path).map_err(|e| {
-                    tracing::error!(
-                        "Could not create symlink from {:?} to {:?}: {:?}",
-                        rel_target,
+        #[cfg(target_family = "unix")]
+        {
+            let target = fs::read_link(path)?;
+            let target = match link_target {
+                links::LinkTarget::Inside(rel_target) if target.is_absolute() => {
+                    tracing::trace!(
+                        "Making symlink relative {:?} -> {:?}",
                         dest_path,
-                        e
+                        rel_target
                     );
-                    e
-                })?;
-            } else {
-                if target.is_absolute() {
+                    rel_target
+                }
+                links::LinkTarget::Outside(_) if target.is_absolute() && !allowed_outside => {
                     tracing::warn!("Symlink {:?} points outside of the prefix", path);
+                    target
                 }
-                symlink(&target, &dest_path).map_err(|e| {
-                    tracing::error!(
-                        "Could not create symlink from {:?} to {:?}: {:?}",
+                _ => target,
+            };
+            symlink(&target, &dest_path).map_err(|e| {
+                tracing::error!(
+                    "Could not create symlink from {:?} to {:?}: {:?}",
+                    target,
+                    dest_path,
+                    e
+                );
+                e
+            })?;
+        }
+
+        #[cfg(target_family = "windows")]
+        {
+            // junctions cannot be relative, they are packaged as symlinks
+            let target = match link_target {
+                links::LinkTarget::Inside(rel_target) => rel_target,
+                links::LinkTarget::Outside(target) if allowed_outside => target,
+                links::LinkTarget::Outside(target) => {
+                    return Err(PackagingError::ExternalLink {
+                        path: path_rel.to_path_buf(),
                         target,
-                        dest_path,
-                        e
-                    );
+                    })
+                }
+            };
+            links::create_link(path, &target, &dest_path).map_err(|e| {
+                tracing::error!(
+                    "Could not create symlink from {:?} to {:?}: {:?}",
+                    target,
+                    dest_path,
                     e
-                })?;
-            }
-            Result::Ok(())
-        })?;
+                );
+                e
+            })?;
+        }
         Ok(Some(dest_path))
     } else if metadata.is_dir() {
         // skip directories for now
@@ -543,15 +556,43 @@ fn write_to_dest(
     }
 }
 
-/// This function creates a link.json file for the given output.
-fn create_link_json(output: &Output) -> Result<Option<String>, PackagingError> {
-    let noarch_links = PythonEntryPoints {
-        entry_points: output.recipe.build().python().entry_points().to_owned(),
+/// The version of the `info/link.json` format that conda understands
+const LINK_JSON_PACKAGE_METADATA_VERSION: u64 = 1;
+
+/// The `info/link.json` document. The fields are declared in sorted order because conda-build
+/// writes the file with sorted keys.
+#[derive(Serialize)]
+struct LinkJsonDocument<'a> {
+    noarch: NoArchDocument<'a>,
+    package_metadata_version: u64,
+}
+
+#[derive(Serialize)]
+struct NoArchDocument<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entry_points: Option<Vec<String>>,
+    #[serde(rename = "type")]
+    kind: &'a str,
+}
+
+/// This function creates the content of the `info/link.json` file. Only noarch packages have
+/// one, and only `noarch: python` packages have a (possibly empty) list of entry points.
+fn create_link_json(
+    noarch: &NoArchType,
+    entry_points: &[EntryPoint],
+) -> Result<Option<String>, PackagingError> {
+    let (kind, entry_points) = if noarch.is_none() {
+        return Ok(None);
+    } else if noarch.is_python() {
+        let entry_points = entry_points.iter().map(ToString::to_string).collect();
+        ("python", Some(entry_points))
+    } else {
+        ("generic", None)
     };
 
-    let link_json = LinkJson {
-        noarch: NoArchLinks::Python(noarch_links),
-        package_metadata_version: 1,
+    let link_json = LinkJsonDocument {
+        noarch: NoArchDocument { entry_points, kind },
+        package_metadata_version: LINK_JSON_PACKAGE_METADATA_VERSION,
     };
 
     Ok(Some(serde_json::to_string_pretty(&link_json)?))
@@ -773,27 +814,274 @@ fn write_recipe_folder(
     files.push(variant_config_file);
 
     // TODO(recipe): define how we want to render it exactly!
+    #[derive(Serialize)]
+    struct RenderedRecipe<'a> {
+        #[serde(flatten)]
+        output: &'a Output,
+        tools_version: &'a str,
+    }
+
     let rendered_recipe_file = recipe_folder.join("rendered_recipe.yaml");
     let mut rendered_recipe = File::create(&rendered_recipe_file)?;
-    rendered_recipe.write_all(serde_yaml::to_string(&output)?.as_bytes())?;
+    rendered_recipe.write_all(
+        serde_yaml::to_string(&RenderedRecipe {
+            output,
+            tools_version: TOOLS_VERSION,
+        })?
+        .as_bytes(),
+    )?;
     files.push(rendered_recipe_file);
 
     Ok(files)
 }
 
+/// Check that the binaries of the package can find all the shared libraries they need, and
+/// report the missing ones according to `build.dynamic_linking.missing_dso_behavior`.
+fn check_missing_dsos(
+    output: &Output,
+    tmp_files: &HashSet<PathBuf>,
+    tmp_dir_path: &Path,
+    prefix: &Path,
+) -> Result<(), PackagingError> {
+    let behavior = output
+        .recipe
+        .build()
+        .dynamic_linking()
+        .missing_dso_behavior();
+    if behavior == LinkingCheckBehavior::Ignore {
+        return Ok(());
+    }
+
+    let run_dependencies = output
+        .finalized_dependencies
+        .as_ref()
+        .ok_or(PackagingError::DependenciesNotFinalized)?
+        .run
+        .depends
+        .iter()
+        .filter_map(|dep| dep.spec().name.as_ref())
+        .map(|name| name.as_normalized().to_string())
+        .collect::<HashSet<_>>();
+
+    let providers = post::DsoProviders::from_prefix(prefix, &run_dependencies)?;
+    let report = post::check_missing_dsos(
+        tmp_files,
+        tmp_dir_path,
+        &providers,
+        &output.build_configuration.target_platform,
+    )?;
+
+    if !report.is_empty() {
+        if behavior == LinkingCheckBehavior::Error {
+            return Err(PackagingError::MissingDsos(report.to_string()));
+        }
+        tracing::warn!("{}", report);
+    }
+
+    Ok(())
+}
+
+/// The files that are relocated, all but the ones that `build.binary_relocation` skips
+fn binaries_to_relocate(
+    output: &Output,
+    tmp_files: &HashSet<PathBuf>,
+    tmp_dir_path: &Path,
+) -> Result<HashSet<PathBuf>, PackagingError> {
+    let mut skipped = globset::GlobSetBuilder::new();
+    for glob in output.recipe.build().binary_relocation().skipped_globs() {
+        skipped.add(globset::Glob::new(glob)?);
+    }
+    let skipped = skipped.build()?;
+
+    let mut relocated = HashSet::new();
+    for file in tmp_files {
+        let relative = file.strip_prefix(tmp_dir_path)?;
+        if skipped.is_match(relative) {
+            tracing::debug!(
+                "Not relocating {} (`build.binary_relocation`)",
+                relative.display()
+            );
+        } else {
+            relocated.insert(file.clone());
+        }
+    }
+    Ok(relocated)
+}
+
+/// Check that the relocated binaries no longer refer to the build directory
+fn check_build_references(
+    output: &Output,
+    relocated: &HashSet<PathBuf>,
+    tmp_dir_path: &Path,
+) -> Result<(), PackagingError> {
+    let behavior = output
+        .recipe
+        .build()
+        .dynamic_linking()
+        .build_reference_behavior();
+    if behavior == LinkingCheckBehavior::Ignore {
+        return Ok(());
+    }
+
+    let references = post::find_build_references(
+        relocated,
+        tmp_dir_path,
+        &output.build_configuration.directories.build_dir,
+        &output.build_configuration.target_platform,
+    )?;
+    if references.is_empty() {
+        return Ok(());
+    }
+
+    let report = references
+        .iter()
+        .flat_map(|(binary, references)| {
+            references
+                .iter()
+                .map(move |reference| format!("  - {}: {}", binary.display(), reference))
+        })
+        .join("\n");
+    if behavior == LinkingCheckBehavior::Error {
+        return Err(PackagingError::BuildReferences(report));
+    }
+    tracing::warn!(
+        "Binaries still refer to the build directory after the relocation:\n{}",
+        report
+    );
+    Ok(())
+}
+
+/// Apply `build.deduplicate_files` and return the duplicates that should be stored as hardlink
+/// entries, if there are any.
+fn find_duplicates(
+    output: &Output,
+    files: &[PathBuf],
+    tmp_dir_path: &Path,
+    package_format: ArchiveType,
+) -> Result<Option<deduplicate::Duplicates>, PackagingError> {
+    match (output.recipe.build().deduplicate_files(), package_format) {
+        (DeduplicateFiles::Off, _) => return Ok(None),
+        (DeduplicateFiles::CondaOnly, ArchiveType::TarBz2) => {
+            tracing::info!("Not deduplicating files of the .tar.bz2 package (`conda_only`)");
+            return Ok(None);
+        }
+        _ => {}
+    }
+
+    let duplicates = deduplicate::find_duplicates(files, tmp_dir_path)?;
+    if duplicates.is_empty() {
+        tracing::info!("No identical files found to deduplicate");
+        return Ok(None);
+    }
+
+    for (duplicate, original) in duplicates.links.iter().sorted() {
+        tracing::info!(
+            "  - {} (hardlink to {})",
+            duplicate.display(),
+            original.display()
+        );
+    }
+    tracing::info!(
+        deduplicated_files = duplicates.links.len(),
+        saved_bytes = duplicates.saved_bytes,
+        "Deduplicated {} files, saving {} bytes",
+        duplicates.links.len(),
+        duplicates.saved_bytes
+    );
+    Ok(Some(duplicates))
+}
+
+/// Apply `build.symlink_policy` to the new symlinks that point to files of other packages in the
+/// prefix. Returns the symlinks that should be replaced by a copy of their target.
+fn apply_symlink_policy(
+    output: &Output,
+    new_files: &HashSet<PathBuf>,
+    prefix: &Path,
+) -> Result<HashSet<PathBuf>, PackagingError> {
+    let has_symlinks = new_files.iter().any(|f| {
+        f.symlink_metadata()
+            .map(|m| m.is_symlink())
+            .unwrap_or(false)
+    });
+    if !has_symlinks {
+        return Ok(HashSet::new());
+    }
+
+    let owners = post::file_owners(prefix)?;
+    let foreign_links = post::find_foreign_symlinks(new_files, prefix, &owners)?;
+    if foreign_links.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    match output.recipe.build().symlink_policy() {
+        SymlinkPolicy::Keep => {
+            for link in &foreign_links {
+                tracing::warn!(
+                    "Packaging symlink to a file of another package: {}. \
+                     Set `build.symlink_policy: copy` to package the file content instead.",
+                    link
+                );
+            }
+            Ok(HashSet::new())
+        }
+        SymlinkPolicy::Copy => Ok(foreign_links
+            .iter()
+            .map(|link| prefix.join(&link.link))
+            .collect()),
+        SymlinkPolicy::Error => Err(PackagingError::ForeignSymlinks(
+            foreign_links
+                .iter()
+                .map(|link| format!(" - {}", link))
+                .join("\n"),
+        )),
+    }
+}
+
+/// Check the version symlink chains of the shared libraries in the package, and recreate the
+/// missing links with `build.fix_soname_links`
+fn check_soname_links(
+    output: &Output,
+    tmp_files: &mut HashSet<PathBuf>,
+    tmp_dir_path: &Path,
+) -> Result<(), PackagingError> {
+    let fix = output.recipe.build().fix_soname_links();
+    #[allow(unused_mut)]
+    let mut problems = soname::check_soname_chains(tmp_files, tmp_dir_path);
+    #[cfg(unix)]
+    if fix && problems.iter().any(|problem| problem.is_fixable()) {
+        soname::fix_soname_chains(&problems, tmp_files, tmp_dir_path)?;
+        problems = soname::check_soname_chains(tmp_files, tmp_dir_path);
+    }
+
+    for problem in &problems {
+        if fix || !problem.is_fixable() {
+            tracing::warn!("Broken shared library link chain: {}", problem);
+        } else {
+            tracing::warn!(
+                "Broken shared library link chain: {}. \
+                 Set `build.fix_soname_links: true` to create the link.",
+                problem
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Given an output and a set of new files, create a conda package.
 /// This function will copy all the files to a temporary directory and then
 /// create a conda package from that. Note that the output needs to have its
 /// dependencies finalized before calling this function.
 ///
-/// The `local_channel_dir` is the path to the local channel / output directory.
+/// The `local_channel_dir` is the path to the local channel / output directory. A package is
+/// written for every one of the `package_formats`, and their paths are returned in that order.
 pub fn package_conda(
     output: &Output,
     new_files: &HashSet<PathBuf>,
     prefix: &Path,
     local_channel_dir: &Path,
-    package_format: ArchiveType,
-) -> Result<(PathBuf, PathsJson), PackagingError> {
+    package_formats: &[ArchiveType],
+    tools: &Tools,
+) -> Result<(Vec<PathBuf>, PathsJson), PackagingError> {
     if output.finalized_dependencies.is_none() {
         return Err(PackagingError::DependenciesNotFinalized);
     }
@@ -801,7 +1089,16 @@ pub fn package_conda(
     let tmp_dir = TempDir::with_prefix(output.name().as_normalized())?;
     let tmp_dir_path = tmp_dir.path();
 
+    let symlinks_to_copy = apply_symlink_policy(output, new_files, prefix)?;
+    let mut external_links = globset::GlobSetBuilder::new();
+    for glob in output.recipe.build().allow_external_links() {
+        external_links.add(globset::Glob::new(glob)?);
+    }
+    let external_links = external_links.build()?;
+    let noarch_python = NoarchPythonLayout::new(output);
+
     let mut tmp_files = HashSet::new();
+    let mut with_xattrs = Vec::new();
     for f in new_files {
         let stripped = f.strip_prefix(prefix)?;
         // temporary measure to remove pyc files that are not supposed to be there
@@ -809,65 +1106,61 @@ pub fn package_conda(
             continue;
         }
 
-        if output.recipe.build().noarch().is_python() {
-            // we need to remove files in bin/ that are registered as entry points
-            if stripped.starts_with("bin") {
-                if let Some(name) = stripped.file_name() {
-                    if output
-                        .recipe
-                        .build()
-                        .python()
-                        .entry_points()
-                        .iter()
-                        .any(|ep| ep.command == name.to_string_lossy())
-                    {
-                        continue;
-                    }
-                }
-            }
-            // Windows
-            else if stripped.starts_with("Scripts") {
-                if let Some(name) = stripped.file_name() {
-                    if output
-                        .recipe
-                        .build()
-                        .python()
-                        .entry_points()
-                        .iter()
-                        .any(|ep| {
-                            format!("{}.exe", ep.command) == name.to_string_lossy()
-                                || format!("{}-script.py", ep.command) == name.to_string_lossy()
-                        })
-                    {
-                        continue;
-                    }
-                }
-            }
-        }
-
         if let Some(dest_file) = write_to_dest(
             f,
             prefix,
             tmp_dir_path,
             &output.build_configuration.target_platform,
-            output.recipe.build().noarch(),
+            noarch_python.as_ref(),
+            &external_links,
         )? {
+            if symlinks_to_copy.contains(f) {
+                // replace the link with the content of its target
+                if dest_file.symlink_metadata().is_ok() {
+                    fs::remove_file(&dest_file)?;
+                }
+                fs::copy(f, &dest_file)?;
+            }
+            let names = xattrs::list(f);
+            if !names.is_empty() {
+                xattrs::strip(&dest_file, &xattrs::list(&dest_file));
+                with_xattrs.push(xattrs::FileAttributes {
+                    path: stripped.to_path_buf(),
+                    names,
+                });
+            }
             tmp_files.insert(dest_file);
         }
     }
+    xattrs::report(&with_xattrs, output.recipe.build().xattr_policy())?;
+
+    let target_platform = &output.build_configuration.target_platform;
+    if target_platform.is_linux() || target_platform.is_osx() {
+        check_soname_links(output, &mut tmp_files, tmp_dir_path)?;
+    }
 
     tracing::info!("Copying done!");
 
     if output.build_configuration.target_platform != Platform::NoArch {
+        let relocated = binaries_to_relocate(output, &tmp_files, tmp_dir_path)?;
         post::relink(
-            &tmp_files,
+            &relocated,
             tmp_dir_path,
             prefix,
             &output.build_configuration.target_platform,
+            tools,
         )?;
+        check_build_references(output, &relocated, tmp_dir_path)?;
+    }
+
+    if output.build_configuration.target_platform != Platform::NoArch {
+        check_missing_dsos(output, &tmp_files, tmp_dir_path, prefix)?;
     }
 
     post::python(output.name(), output.version(), &tmp_files)?;
+    if let Some(layout) = &noarch_python {
+        layout.post_process(&tmp_files, tmp_dir_path, prefix)?;
+    }
 
     tracing::info!("Relink done!");
 
@@ -875,7 +1168,16 @@ pub fn package_conda(
     fs::create_dir_all(&info_folder)?;
 
     let mut paths_json = File::create(info_folder.join("paths.json"))?;
-    let paths_json_struct = create_paths_json(&tmp_files, tmp_dir_path, prefix)?;
+    let mut ignore_prefix_files = globset::GlobSetBuilder::new();
+    for glob in output.recipe.build().ignore_prefix_files().globs() {
+        ignore_prefix_files.add(globset::Glob::new(glob)?);
+    }
+    let paths_json_struct = create_paths_json(
+        &tmp_files,
+        tmp_dir_path,
+        prefix,
+        &ignore_prefix_files.build()?,
+    )?;
     paths_json.write_all(serde_json::to_string_pretty(&paths_json_struct)?.as_bytes())?;
     tmp_files.insert(info_folder.join("paths.json"));
 
@@ -913,12 +1215,13 @@ pub fn package_conda(
     let test_files = write_test_files(output, tmp_dir_path)?;
     tmp_files.extend(test_files);
 
-    if output.recipe.build().noarch().is_python() {
-        if let Some(link) = create_link_json(output)? {
-            let mut link_json = File::create(info_folder.join("link.json"))?;
-            link_json.write_all(link.as_bytes())?;
-            tmp_files.insert(info_folder.join("link.json"));
-        }
+    if let Some(link) = create_link_json(
+        output.recipe.build().noarch(),
+        output.recipe.build().python().entry_points(),
+    )? {
+        let mut link_json = File::create(info_folder.join("link.json"))?;
+        link_json.write_all(link.as_bytes())?;
+        tmp_files.insert(info_folder.join("link.json"));
     }
 
     // print sorted files
@@ -940,38 +1243,136 @@ pub fn package_conda(
     let identifier = output
         .identifier()
         .ok_or(PackagingError::BuildStringNotSet)?;
-    let out_path = output_folder.join(format!("{}{}", identifier, package_format.extension()));
-    let file = File::create(&out_path)?;
-
-    match package_format {
-        ArchiveType::TarBz2 => {
-            write_tar_bz2_package(
-                file,
-                tmp_dir_path,
-                &tmp_files.into_iter().collect::<Vec<_>>(),
-                CompressionLevel::Default,
-                Some(&output.build_configuration.timestamp),
-            )?;
-        }
-        ArchiveType::Conda => {
-            // This is safe because we're just putting it together before
-            write_conda_package(
-                file,
-                tmp_dir_path,
-                &tmp_files.into_iter().collect::<Vec<_>>(),
-                CompressionLevel::Default,
-                &identifier,
-                Some(&output.build_configuration.timestamp),
-            )?;
-        }
-    }
-
-    Ok((out_path, paths_json_struct))
+    let tmp_files = tmp_files.into_iter().collect::<Vec<_>>();
+    let timestamp = Some(&output.build_configuration.timestamp);
+    let compression = &output.build_configuration.compression;
+
+    let mut packages = Vec::new();
+    for &package_format in package_formats {
+        let out_path = output_folder.join(format!("{}{}", identifier, package_format.extension()));
+        let file = File::create(&out_path)?;
+        let duplicates = find_duplicates(output, &tmp_files, tmp_dir_path, package_format)?;
+
+        match (package_format, duplicates) {
+            (ArchiveType::TarBz2, Some(duplicates)) => {
+                deduplicate::write_tar_bz2_package(
+                    file,
+                    tmp_dir_path,
+                    &tmp_files,
+                    &duplicates,
+                    compression,
+                    timestamp,
+                )?;
+            }
+            (ArchiveType::Conda, Some(duplicates)) => {
+                deduplicate::write_conda_package(
+                    file,
+                    tmp_dir_path,
+                    &tmp_files,
+                    &duplicates,
+                    compression,
+                    &identifier,
+                    timestamp,
+                )?;
+            }
+            (ArchiveType::TarBz2, None) => {
+                write_tar_bz2_package(
+                    file,
+                    tmp_dir_path,
+                    &tmp_files,
+                    CompressionLevel::Numeric(compression.tar_bz2_level as i32),
+                    timestamp,
+                )?;
+            }
+            (ArchiveType::Conda, None) if compression.threads > 1 => {
+                deduplicate::write_conda_package(
+                    file,
+                    tmp_dir_path,
+                    &tmp_files,
+                    &deduplicate::Duplicates::default(),
+                    compression,
+                    &identifier,
+                    timestamp,
+                )?;
+            }
+            (ArchiveType::Conda, None) => {
+                // This is safe because we're just putting it together before
+                write_conda_package(
+                    file,
+                    tmp_dir_path,
+                    &tmp_files,
+                    CompressionLevel::Numeric(compression.conda_level),
+                    &identifier,
+                    timestamp,
+                )?;
+            }
+        }
+        tracing::info!("Wrote the package {}", out_path.display());
+        packages.push(out_path);
+    }
+
+    Ok((packages, paths_json_struct))
 }
 
 #[cfg(test)]
 mod test {
-    use super::create_prefix_placeholder;
+    use std::collections::HashSet;
+
+    use fs_err as fs;
+    use rattler::install::{link_package, InstallDriver, InstallOptions};
+    use std::str::FromStr;
+
+    use rattler_conda_types::package::{ArchiveType, EntryPoint, FileMode};
+    use rattler_conda_types::NoArchType;
+
+    use super::{
+        create_index_json, create_link_json, create_paths_json, create_prefix_placeholder,
+        deduplicate,
+    };
+    use crate::{
+        metadata::{Output, PackageCompression},
+        render::resolved_dependencies::DependencyInfo,
+    };
+
+    #[test]
+    fn index_json_constrains() {
+        let recipe = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/rendered_recipes/rich_recipe.yaml");
+        let mut output: Output =
+            serde_yaml::from_str(&fs::read_to_string(recipe).unwrap()).unwrap();
+
+        let spec = |s: &str| rattler_conda_types::MatchSpec::from_str(s).unwrap();
+        output
+            .finalized_dependencies
+            .as_mut()
+            .unwrap()
+            .run
+            .constrains = vec![
+            DependencyInfo::Raw {
+                spec: spec("cudatoolkit >=11.2,<12"),
+            },
+            DependencyInfo::PinSubpackage {
+                spec: spec("rich-cli >=13.4.2,<14.0a0"),
+            },
+            DependencyInfo::RunExport {
+                spec: spec("python_abi 3.10.* *_cp310"),
+                from: "host".to_string(),
+                source_package: "python".to_string(),
+            },
+        ];
+
+        let index_json: serde_json::Value =
+            serde_json::from_str(&create_index_json(&output).unwrap()).unwrap();
+        assert_eq!(
+            index_json["constrains"],
+            serde_json::json!([
+                "cudatoolkit >=11.2,<12",
+                "rich-cli >=13.4.2,<14.0a0",
+                "python_abi 3.10.* *_cp310"
+            ])
+        );
+        assert_eq!(index_json["depends"].as_array().unwrap().len(), 5);
+    }
 
     #[test]
     fn detect_prefix() {
@@ -981,4 +1382,237 @@ mod test {
 
         create_prefix_placeholder(&test_data, prefix).unwrap();
     }
+
+    #[test]
+    fn ignored_prefix_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = std::path::Path::new("/host_env_placehold_placehold");
+        fs::create_dir_all(tmp.path().join("lib/pkgconfig")).unwrap();
+        let files = HashSet::from([
+            tmp.path().join("lib/pkgconfig/foo.pc"),
+            tmp.path().join("lib/libfoo.so"),
+            tmp.path().join("lib/notes.txt"),
+        ]);
+        fs::write(
+            tmp.path().join("lib/pkgconfig/foo.pc"),
+            "prefix=/host_env_placehold_placehold\n",
+        )
+        .unwrap();
+        fs::write(
+            tmp.path().join("lib/libfoo.so"),
+            b"\x7fELF\0\0/host_env_placehold_placehold/lib\0\0",
+        )
+        .unwrap();
+        fs::write(
+            tmp.path().join("lib/notes.txt"),
+            "built in /host_env_placehold_placehold\n",
+        )
+        .unwrap();
+
+        let mut ignore = globset::GlobSetBuilder::new();
+        ignore.add(globset::Glob::new("lib/*.txt").unwrap());
+        let paths_json =
+            create_paths_json(&files, tmp.path(), prefix, &ignore.build().unwrap()).unwrap();
+
+        let placeholder = |path: &str| {
+            paths_json
+                .paths
+                .iter()
+                .find(|entry| entry.relative_path == std::path::Path::new(path))
+                .unwrap()
+                .prefix_placeholder
+                .as_ref()
+                .map(|p| (p.file_mode, p.placeholder.clone()))
+        };
+        let expected = |file_mode| Some((file_mode, prefix.to_string_lossy().to_string()));
+        assert_eq!(
+            placeholder("lib/pkgconfig/foo.pc"),
+            expected(FileMode::Text)
+        );
+        #[cfg(unix)]
+        assert_eq!(placeholder("lib/libfoo.so"), expected(FileMode::Binary));
+        assert_eq!(placeholder("lib/notes.txt"), None);
+    }
+
+    fn expected_link_json(name: &str) -> String {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/link_json")
+            .join(name);
+        fs::read_to_string(path).unwrap().trim_end().to_string()
+    }
+
+    #[test]
+    fn link_json() {
+        let entry_points = [
+            "flask = flask.cli:main",
+            "flask-admin = flask_admin.cli:run",
+        ]
+        .iter()
+        .map(|e| EntryPoint::from_str(e).unwrap())
+        .collect::<Vec<_>>();
+
+        let python = create_link_json(&NoArchType::python(), &entry_points).unwrap();
+        assert_eq!(
+            python.unwrap(),
+            expected_link_json("python_entry_points.json")
+        );
+
+        let python = create_link_json(&NoArchType::python(), &[]).unwrap();
+        assert_eq!(python.unwrap(), expected_link_json("python.json"));
+
+        // entry points are only installed for noarch python packages
+        let generic = create_link_json(&NoArchType::generic(), &entry_points).unwrap();
+        assert_eq!(generic.unwrap(), expected_link_json("generic.json"));
+
+        assert!(create_link_json(&NoArchType::none(), &entry_points)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn deduplicated_install_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let package_dir = tmp.path().join("package");
+
+        // 1 MB of pseudo random (incompressible) data
+        let mut state = 0x2545_f491_u32;
+        let data = (0..1_000_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+
+        let mut files = HashSet::new();
+        for name in ["share/a/data.bin", "share/b/data.bin"] {
+            let path = package_dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &data).unwrap();
+            files.insert(path);
+        }
+        let other = package_dir.join("share/other.txt");
+        fs::write(&other, "other").unwrap();
+        files.insert(other);
+
+        let paths_json = create_paths_json(&files, &package_dir, tmp.path()).unwrap();
+        fs::create_dir_all(package_dir.join("info")).unwrap();
+        fs::write(
+            package_dir.join("info/paths.json"),
+            serde_json::to_string(&paths_json).unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            package_dir.join("info/index.json"),
+            r#"{"name": "dedup", "version": "1.0", "build": "0", "build_number": 0}"#,
+        )
+        .unwrap();
+        files.insert(package_dir.join("info/paths.json"));
+        files.insert(package_dir.join("info/index.json"));
+        let files = files.into_iter().collect::<Vec<_>>();
+
+        let duplicates = deduplicate::find_duplicates(&files, &package_dir).unwrap();
+        assert_eq!(duplicates.links.len(), 1);
+        assert_eq!(
+            duplicates.links[std::path::Path::new("share/b/data.bin")],
+            std::path::Path::new("share/a/data.bin")
+        );
+        assert_eq!(duplicates.saved_bytes, 1_000_000);
+
+        for format in [ArchiveType::Conda, ArchiveType::TarBz2] {
+            let archive = tmp
+                .path()
+                .join(format!("dedup-1.0-0{}", format.extension()));
+            let file = fs::File::create(&archive).unwrap();
+            match format {
+                ArchiveType::Conda => deduplicate::write_conda_package(
+                    file,
+                    &package_dir,
+                    &files,
+                    &duplicates,
+                    &PackageCompression::default(),
+                    "dedup-1.0-0",
+                    None,
+                ),
+                ArchiveType::TarBz2 => deduplicate::write_tar_bz2_package(
+                    file,
+                    &package_dir,
+                    &files,
+                    &duplicates,
+                    &PackageCompression::default(),
+                    None,
+                ),
+            }
+            .unwrap();
+            // the data is only stored once
+            assert!(fs::metadata(&archive).unwrap().len() < 1_500_000);
+
+            let extracted = tmp.path().join(format!("extracted-{format:?}"));
+            rattler_package_streaming::fs::extract(&archive, &extracted).unwrap();
+
+            let prefix = tmp.path().join(format!("prefix-{format:?}"));
+            let installed = link_package(
+                &extracted,
+                &prefix,
+                &InstallDriver::default(),
+                InstallOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(installed.len(), 3);
+
+            for name in ["share/a/data.bin", "share/b/data.bin"] {
+                assert_eq!(fs::read(prefix.join(name)).unwrap(), data);
+            }
+            assert_eq!(
+                fs::read_to_string(prefix.join("share/other.txt")).unwrap(),
+                "other"
+            );
+        }
+    }
+
+    #[test]
+    fn multithreaded_conda_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let package_dir = tmp.path().join("package");
+        fs::create_dir_all(package_dir.join("info")).unwrap();
+        fs::create_dir_all(package_dir.join("share")).unwrap();
+        let text = "some compressible text\n".repeat(100_000);
+        fs::write(package_dir.join("share/text.txt"), &text).unwrap();
+        fs::write(
+            package_dir.join("info/index.json"),
+            r#"{"name": "text", "version": "1.0", "build": "0", "build_number": 0}"#,
+        )
+        .unwrap();
+        let files = vec![
+            package_dir.join("info/index.json"),
+            package_dir.join("share/text.txt"),
+        ];
+
+        let compression = PackageCompression {
+            conda_level: 3,
+            threads: 2,
+            ..Default::default()
+        };
+        let archive = tmp.path().join("text-1.0-0.conda");
+        deduplicate::write_conda_package(
+            fs::File::create(&archive).unwrap(),
+            &package_dir,
+            &files,
+            &deduplicate::Duplicates::default(),
+            &compression,
+            "text-1.0-0",
+            None,
+        )
+        .unwrap();
+        assert!(fs::metadata(&archive).unwrap().len() < 100_000);
+
+        let extracted = tmp.path().join("extracted");
+        rattler_package_streaming::fs::extract(&archive, &extracted).unwrap();
+        assert_eq!(
+            fs::read_to_string(extracted.join("share/text.txt")).unwrap(),
+            text
+        );
+    }
 }
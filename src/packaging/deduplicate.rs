@@ -0,0 +1,215 @@
+//! Deduplication of identical files in a package (`build.deduplicate_files`).
+//!
+//! The first file (in sorted order) of a group of identical files is stored as a regular tar
+//! entry, all others are stored as hardlink entries that point to it. Extracting such an archive
+//! recreates every path, so the installed environment looks the same as without deduplication.
+//!
+//! `rattler_package_streaming` does not write hardlink entries and compresses with a single
+//! thread, which is why the archives are assembled here when duplicates were found or when a
+//! `.conda` package is compressed with several threads.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use fs_err as fs;
+use rattler_digest::compute_file_digest;
+
+use crate::metadata::PackageCompression;
+
+/// The duplicated files of a package
+#[derive(Debug, Default)]
+pub(crate) struct Duplicates {
+    /// Maps the relative path of every duplicate to the relative path of the file it is
+    /// identical to
+    pub links: HashMap<PathBuf, PathBuf>,
+    /// The number of bytes that are not stored twice
+    pub saved_bytes: u64,
+}
+
+impl Duplicates {
+    /// Returns true if no duplicates were found
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+}
+
+/// Find identical regular files among `files`. Files in `info/` and empty files are never
+/// deduplicated.
+pub(crate) fn find_duplicates(files: &[PathBuf], base: &Path) -> Result<Duplicates, io::Error> {
+    // only files of the same size can be identical, so only those need to be hashed
+    let mut by_size = BTreeMap::<u64, Vec<PathBuf>>::new();
+    for file in files {
+        let relative = file.strip_prefix(base).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("{}: {e}", file.display()))
+        })?;
+        if relative.starts_with("info") {
+            continue;
+        }
+        let meta = fs::symlink_metadata(file)?;
+        if !meta.is_file() || meta.len() == 0 {
+            continue;
+        }
+        by_size
+            .entry(meta.len())
+            .or_default()
+            .push(relative.to_path_buf());
+    }
+
+    let mut duplicates = Duplicates::default();
+    for (size, mut candidates) in by_size {
+        if candidates.len() < 2 {
+            continue;
+        }
+        candidates.sort();
+
+        let mut by_hash = HashMap::new();
+        for candidate in candidates {
+            let digest = compute_file_digest::<sha2::Sha256>(base.join(&candidate))?;
+            match by_hash.get(&digest) {
+                Some(original) => {
+                    duplicates.links.insert(candidate, PathBuf::clone(original));
+                    duplicates.saved_bytes += size;
+                }
+                None => {
+                    by_hash.insert(digest, candidate);
+                }
+            }
+        }
+    }
+
+    Ok(duplicates)
+}
+
+/// Sort the paths like `rattler_package_streaming` does: the `info/` paths and the other paths
+/// are returned separately, each of them in sorted order.
+fn sort_paths(paths: &[PathBuf], base: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut relative = paths
+        .iter()
+        .filter_map(|p| p.strip_prefix(base).ok())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    relative.sort();
+    relative.into_iter().partition(|p| p.starts_with("info"))
+}
+
+fn append_path<W: Write>(
+    archive: &mut tar::Builder<W>,
+    base: &Path,
+    path: &Path,
+    duplicates: &Duplicates,
+    mtime: u64,
+) -> Result<(), io::Error> {
+    let full_path = base.join(path);
+    let meta = fs::symlink_metadata(&full_path)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&meta, tar::HeaderMode::Deterministic);
+    header.set_mtime(mtime);
+
+    if let Some(original) = duplicates.links.get(path) {
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        archive.append_link(&mut header, path, original)
+    } else if meta.file_type().is_symlink() {
+        let target = fs::read_link(&full_path)?;
+        archive.append_link(&mut header, path, target)
+    } else if meta.is_dir() {
+        archive.append_data(&mut header, path, io::empty())
+    } else {
+        archive.append_data(&mut header, path, fs::File::open(&full_path)?)
+    }
+}
+
+fn write_tar<W: Write>(
+    writer: W,
+    base: &Path,
+    paths: &[PathBuf],
+    duplicates: &Duplicates,
+    mtime: u64,
+) -> Result<W, io::Error> {
+    let mut archive = tar::Builder::new(writer);
+    archive.follow_symlinks(false);
+    for path in paths {
+        append_path(&mut archive, base, path, duplicates, mtime)?;
+    }
+    archive.into_inner()
+}
+
+fn mtime(timestamp: Option<&DateTime<Utc>>) -> u64 {
+    // 2023-01-01, the default of `rattler_package_streaming`
+    timestamp.map_or(1672531200, |t| t.timestamp().unsigned_abs())
+}
+
+/// Write a `.tar.bz2` package that stores the duplicates as hardlink entries
+pub(crate) fn write_tar_bz2_package<W: Write>(
+    writer: W,
+    base: &Path,
+    paths: &[PathBuf],
+    duplicates: &Duplicates,
+    compression: &PackageCompression,
+    timestamp: Option<&DateTime<Utc>>,
+) -> Result<(), io::Error> {
+    let (info_paths, other_paths) = sort_paths(paths, base);
+    let paths = info_paths
+        .into_iter()
+        .chain(other_paths)
+        .collect::<Vec<_>>();
+    let encoder =
+        bzip2::write::BzEncoder::new(writer, bzip2::Compression::new(compression.tar_bz2_level));
+    write_tar(encoder, base, &paths, duplicates, mtime(timestamp))?.finish()?;
+    Ok(())
+}
+
+/// Write a `.conda` package that stores the duplicates as hardlink entries. Duplicates are only
+/// searched outside of `info/`, so all links point into the same inner archive.
+pub(crate) fn write_conda_package<W: Write + Seek>(
+    writer: W,
+    base: &Path,
+    paths: &[PathBuf],
+    duplicates: &Duplicates,
+    compression: &PackageCompression,
+    out_name: &str,
+    timestamp: Option<&DateTime<Utc>>,
+) -> Result<(), io::Error> {
+    let to_io = |e: zip::result::ZipError| io::Error::new(io::ErrorKind::Other, e);
+
+    let mut options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored)
+        .large_file(true);
+    if let Some(time) = timestamp.and_then(|t| {
+        zip::DateTime::from_date_and_time(
+            t.year() as u16,
+            t.month() as u8,
+            t.day() as u8,
+            t.hour() as u8,
+            t.minute() as u8,
+            t.second() as u8,
+        )
+        .ok()
+    }) {
+        options = options.last_modified_time(time);
+    }
+
+    let (info_paths, other_paths) = sort_paths(paths, base);
+    let mtime = mtime(timestamp);
+    let mut outer = zip::ZipWriter::new(writer);
+
+    outer.start_file("metadata.json", options).map_err(to_io)?;
+    outer.write_all(br#"{"conda_pkg_format_version":2}"#)?;
+
+    for (archive_name, paths) in [
+        (format!("pkg-{out_name}.tar.zst"), other_paths),
+        (format!("info-{out_name}.tar.zst"), info_paths),
+    ] {
+        outer.start_file(archive_name, options).map_err(to_io)?;
+        let mut encoder = zstd::stream::write::Encoder::new(&mut outer, compression.conda_level)?;
+        if compression.threads > 1 {
+            encoder.multithread(compression.threads)?;
+        }
+        write_tar(encoder, base, &paths, duplicates, mtime)?.finish()?;
+    }
+
+    outer.finish().map_err(to_io)?;
+    Ok(())
+}
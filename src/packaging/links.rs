@@ -0,0 +1,286 @@
+//! Symlinks and junctions in the prefix.
+//!
+//! On Windows, build scripts and tools create junctions and directory symlinks in the host
+//! prefix. The standard library reports both (all name surrogate reparse points) as symlinks, so
+//! they are recorded and packaged as links and never traversed: a link into the prefix becomes
+//! a relative symlink in the package, and the files behind it are packaged once, under their
+//! real path. Links that point outside of the prefix are only packaged if they match one of the
+//! globs of `build.allow_external_links`.
+
+use std::path::{Component, Path, PathBuf};
+
+use fs_err as fs;
+
+/// The target of a link in the prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LinkTarget {
+    /// The target is in the prefix, relative to the directory of the link
+    Inside(PathBuf),
+    /// The target is outside of the prefix, as stored in the link
+    Outside(PathBuf),
+}
+
+/// Resolve the `.` and `..` components of `path` without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Read the target of the link at `path` and find out whether it points into `prefix`
+pub(crate) fn link_target(path: &Path, prefix: &Path) -> Result<LinkTarget, std::io::Error> {
+    // junctions store the target as a verbatim path (`\\?\C:\...`)
+    let target = dunce::simplified(&fs::read_link(path)?).to_path_buf();
+    let parent = path.parent().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::Other, "Could not get parent directory")
+    })?;
+
+    let absolute = normalize(&parent.join(&target));
+    if !absolute.starts_with(normalize(prefix)) {
+        return Ok(LinkTarget::Outside(target));
+    }
+    let relative = pathdiff::diff_paths(&absolute, normalize(parent)).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::Other, "Could not get relative path")
+    })?;
+    Ok(LinkTarget::Inside(relative))
+}
+
+/// Create a symlink at `dest` that points to `target`. On Windows, the link is a directory
+/// symlink if the original link (`source`) points to a directory.
+#[cfg(windows)]
+pub(crate) fn create_link(source: &Path, target: &Path, dest: &Path) -> Result<(), std::io::Error> {
+    if source.is_dir() {
+        std::os::windows::fs::symlink_dir(target, dest)
+    } else {
+        std::os::windows::fs::symlink_file(target, dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{link_target, normalize, LinkTarget};
+
+    #[test]
+    fn normalized_paths() {
+        assert_eq!(
+            normalize(Path::new("/prefix/lib/../bin/./tool")),
+            PathBuf::from("/prefix/bin/tool")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_targets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path().join("prefix");
+        fs_err::create_dir_all(prefix.join("lib/real")).unwrap();
+        fs_err::create_dir_all(prefix.join("bin")).unwrap();
+
+        let link = |name: &str, target: &Path| {
+            let path = prefix.join(name);
+            std::os::unix::fs::symlink(target, &path).unwrap();
+            link_target(&path, &prefix).unwrap()
+        };
+        assert_eq!(
+            link("lib/current", &prefix.join("lib/real")),
+            LinkTarget::Inside("real".into())
+        );
+        assert_eq!(
+            link("bin/lib", Path::new("../lib/real")),
+            LinkTarget::Inside("../lib/real".into())
+        );
+        assert_eq!(
+            link("bin/outside", Path::new("../../elsewhere")),
+            LinkTarget::Outside("../../elsewhere".into())
+        );
+        assert_eq!(
+            link("bin/system", Path::new("/usr/bin/env")),
+            LinkTarget::Outside("/usr/bin/env".into())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directory_links_in_paths_json() {
+        let tmp = tempfile::tempdir().unwrap();
+        let package = tmp.path();
+        fs_err::create_dir_all(package.join("lib/real")).unwrap();
+        fs_err::write(package.join("lib/real/data.txt"), "data").unwrap();
+        std::os::unix::fs::symlink("real", package.join("lib/current")).unwrap();
+
+        let files = ["lib/real/data.txt", "lib/current"]
+            .iter()
+            .map(|path| package.join(path))
+            .collect();
+        let paths_json =
+            crate::packaging::create_paths_json(&files, package, Path::new("/prefix")).unwrap();
+        let link = paths_json
+            .paths
+            .iter()
+            .find(|entry| entry.relative_path == Path::new("lib/current"))
+            .unwrap();
+        assert_eq!(
+            link.path_type,
+            rattler_conda_types::package::PathType::SoftLink
+        );
+        assert_eq!(link.sha256, None);
+        assert_eq!(link.size_in_bytes, None);
+    }
+
+    /// A prefix with a file, a junction and a file symlink (if the user may create symlinks)
+    #[cfg(windows)]
+    fn windows_prefix(root: &Path) -> (PathBuf, bool) {
+        let prefix = root.join("prefix");
+        fs_err::create_dir_all(prefix.join("Library/real")).unwrap();
+        fs_err::write(prefix.join("Library/real/data.txt"), "data").unwrap();
+
+        let status = std::process::Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(prefix.join("Library/junction"))
+            .arg(prefix.join("Library/real"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let symlinks = std::os::windows::fs::symlink_file(
+            "real\\data.txt",
+            prefix.join("Library/data-link.txt"),
+        )
+        .is_ok();
+        (prefix, symlinks)
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_links() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (prefix, symlinks) = windows_prefix(tmp.path());
+
+        // the junction is recorded, but not traversed
+        let recorded = crate::packaging::record_files(&prefix).unwrap();
+        assert!(recorded.contains(&prefix.join("Library/junction")));
+        assert!(!recorded.contains(&prefix.join("Library/junction/data.txt")));
+        let metadata = prefix.join("Library/junction").symlink_metadata().unwrap();
+        assert!(metadata.file_type().is_symlink());
+
+        assert_eq!(
+            link_target(&prefix.join("Library/junction"), &prefix).unwrap(),
+            LinkTarget::Inside("real".into())
+        );
+        if symlinks {
+            assert_eq!(
+                link_target(&prefix.join("Library/data-link.txt"), &prefix).unwrap(),
+                LinkTarget::Inside("real\\data.txt".into())
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_packaged_links() {
+        use rattler_conda_types::{package::PathType, NoArchType, Platform};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let (prefix, symlinks) = windows_prefix(tmp.path());
+        if !symlinks {
+            // packaging links needs the privilege to create symlinks
+            return;
+        }
+        let dest = tmp.path().join("package");
+        let allowed = globset::GlobSet::empty();
+
+        let mut packaged = std::collections::HashSet::new();
+        for file in crate::packaging::record_files(&prefix).unwrap() {
+            if let Some(path) = crate::packaging::write_to_dest(
+                &file,
+                &prefix,
+                &dest,
+                &Platform::Win64,
+                &NoArchType::none(),
+                &allowed,
+            )
+            .unwrap()
+            {
+                packaged.insert(path);
+            }
+        }
+
+        // both links are relative symlinks in the package
+        assert_eq!(
+            fs_err::read_link(dest.join("Library/junction")).unwrap(),
+            PathBuf::from("real")
+        );
+        assert_eq!(
+            fs_err::read_link(dest.join("Library/data-link.txt")).unwrap(),
+            PathBuf::from("real\\data.txt")
+        );
+
+        let paths_json = crate::packaging::create_paths_json(&packaged, &dest, &prefix).unwrap();
+        let entry = |path: &str| {
+            paths_json
+                .paths
+                .iter()
+                .find(|entry| entry.relative_path == Path::new(path))
+                .unwrap()
+        };
+        assert_eq!(entry("Library\\junction").path_type, PathType::SoftLink);
+        assert_eq!(entry("Library\\junction").sha256, None);
+        assert_eq!(
+            entry("Library\\data-link.txt").path_type,
+            PathType::SoftLink
+        );
+        assert_eq!(
+            entry("Library\\real\\data.txt").path_type,
+            PathType::HardLink
+        );
+
+        // a junction that points outside of the prefix is refused
+        let outside = tmp.path().join("outside");
+        fs_err::create_dir_all(&outside).unwrap();
+        let status = std::process::Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(prefix.join("Library/outside"))
+            .arg(&outside)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let result = crate::packaging::write_to_dest(
+            &prefix.join("Library/outside"),
+            &prefix,
+            &dest,
+            &Platform::Win64,
+            &NoArchType::none(),
+            &allowed,
+        );
+        assert!(matches!(
+            result,
+            Err(crate::packaging::PackagingError::ExternalLink { .. })
+        ));
+
+        let mut allowed = globset::GlobSetBuilder::new();
+        allowed.add(globset::Glob::new("Library/outside").unwrap());
+        assert!(crate::packaging::write_to_dest(
+            &prefix.join("Library/outside"),
+            &prefix,
+            &dest,
+            &Platform::Win64,
+            &NoArchType::none(),
+            &allowed.build().unwrap(),
+        )
+        .is_ok());
+    }
+}
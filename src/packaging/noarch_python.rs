@@ -0,0 +1,280 @@
+//! The layout of `noarch: python` packages.
+//!
+//! A `noarch: python` package is installed into any Python version, on any platform. conda maps
+//! its files when the package is installed: `site-packages/` goes into the site-packages folder
+//! of the Python of the environment, `python-scripts/` into `bin/` (or `Scripts/` on Windows), the
+//! entry points of `info/link.json` are created, and the bytecode is compiled for the installed
+//! Python. The package therefore contains no `lib/pythonX.Y/` folder, no scripts of the entry
+//! points and no `.pyc` files, which would only work with the Python of the build.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use fs_err as fs;
+use rattler_conda_types::package::EntryPoint;
+
+use crate::metadata::Output;
+
+/// The folder of the scripts in a `noarch: python` package
+const PYTHON_SCRIPTS: &str = "python-scripts";
+
+/// Maps the files of the host prefix to the layout of a `noarch: python` package
+pub(crate) struct NoarchPythonLayout<'a> {
+    /// Whether the build ran on Windows (the scripts are in `Scripts/` and end with `-script.py`)
+    windows: bool,
+    /// The entry points that conda creates when the package is installed
+    entry_points: &'a [EntryPoint],
+}
+
+impl<'a> NoarchPythonLayout<'a> {
+    /// The layout for `output`, if it is a `noarch: python` package
+    pub fn new(output: &'a Output) -> Option<Self> {
+        output.recipe.build().noarch().is_python().then(|| Self {
+            // the target platform is `noarch`, the files are laid out as on the build platform
+            windows: output.build_configuration.host_platform.is_windows(),
+            entry_points: output.recipe.build().python().entry_points(),
+        })
+    }
+
+    /// Whether `path` is the script of an entry point, which conda creates at install time
+    fn is_entry_point_script(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name().map(|name| name.to_string_lossy()) else {
+            return false;
+        };
+        if path.starts_with("bin") {
+            self.entry_points.iter().any(|ep| ep.command == name)
+        } else if path.starts_with("Scripts") {
+            self.entry_points.iter().any(|ep| {
+                format!("{}.exe", ep.command) == name || format!("{}-script.py", ep.command) == name
+            })
+        } else {
+            false
+        }
+    }
+
+    /// The path in the package of the file at `path` (relative to the prefix), or `None` if the
+    /// file is not packaged
+    pub fn package_path(&self, path: &Path) -> Option<PathBuf> {
+        if path.extension().unwrap_or_default() == "pyc"
+            || path
+                .components()
+                .any(|c| c == Component::Normal("__pycache__".as_ref()))
+            || self.is_entry_point_script(path)
+        {
+            return None;
+        }
+
+        // `lib/pythonX.Y/site-packages/...` (or `Lib/site-packages/...`) becomes
+        // `site-packages/...`
+        let site_packages = Component::Normal("site-packages".as_ref());
+        if let Some(position) = path.components().position(|c| c == site_packages) {
+            return Some(path.components().skip(position).collect());
+        }
+
+        // Scripts that are not entry points are installed as they are. Sometimes recipe authors
+        // forget to declare the entry points, or the script is not one.
+        if path.starts_with("bin") || path.starts_with("Scripts") {
+            let mut parts = path.components().collect::<Vec<_>>();
+            parts[0] = Component::Normal(PYTHON_SCRIPTS.as_ref());
+            if let Some(Component::Normal(name)) = parts.last_mut() {
+                if let Some(stripped) = name.to_str().and_then(|n| n.strip_suffix("-script.py")) {
+                    if self.windows {
+                        *name = stripped.as_ref();
+                    }
+                }
+            }
+            return Some(PathBuf::from_iter(parts));
+        }
+
+        Some(path.to_path_buf())
+    }
+
+    /// Make the scripts of the package independent of the Python version of the build, and warn
+    /// about files that only work with the Python version or the platform of the build
+    pub fn post_process(
+        &self,
+        files: &HashSet<PathBuf>,
+        tmp_dir_path: &Path,
+        prefix: &Path,
+    ) -> Result<(), io::Error> {
+        for file in files {
+            let Ok(relative) = file.strip_prefix(tmp_dir_path) else {
+                continue;
+            };
+            if relative.starts_with(PYTHON_SCRIPTS) && !self.windows {
+                rewrite_shebang(file, prefix)?;
+            } else if relative.starts_with("site-packages") {
+                let extension = relative.extension().unwrap_or_default();
+                if ["so", "pyd", "dylib", "dll"]
+                    .iter()
+                    .any(|e| extension == *e)
+                {
+                    tracing::warn!(
+                        "{} is a compiled module, which only works on the platform and with the Python version of the build",
+                        relative.display()
+                    );
+                }
+            } else if relative
+                .components()
+                .next()
+                .map_or(false, |c| c.as_os_str().to_string_lossy() == "lib")
+                && relative.components().nth(1).map_or(false, |c| {
+                    c.as_os_str().to_string_lossy().starts_with("python")
+                })
+            {
+                tracing::warn!(
+                    "{} is outside of site-packages, it is only found by the Python version of the build",
+                    relative.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replace the versioned interpreter (e.g. `$PREFIX/bin/python3.11`) in the first lines of the
+/// script with `$PREFIX/bin/python`. pip writes a `#!/bin/sh` wrapper that execs the interpreter
+/// when the prefix is long, which is why the first lines and not only the shebang are checked.
+fn rewrite_shebang(path: &Path, prefix: &Path) -> Result<(), io::Error> {
+    let Ok(content) = fs::read_to_string(path) else {
+        // not a text file
+        return Ok(());
+    };
+    if !content.starts_with("#!") {
+        return Ok(());
+    }
+
+    let interpreter = format!("{}/bin/python", prefix.display());
+    let mut rewritten = String::with_capacity(content.len());
+    let mut changed = false;
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        if index >= 3 {
+            rewritten.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(position) = rest.find(&interpreter) {
+            let end = position + interpreter.len();
+            let version = rest[end..]
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len() - end);
+            rewritten.push_str(&rest[..end]);
+            changed |= version > 0;
+            rest = &rest[end + version..];
+        }
+        rewritten.push_str(rest);
+    }
+
+    if changed {
+        tracing::info!(
+            "Using the unversioned Python interpreter in {}",
+            path.display()
+        );
+        fs::write(path, rewritten)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+
+    use rattler_conda_types::package::EntryPoint;
+
+    use super::{rewrite_shebang, NoarchPythonLayout};
+
+    #[test]
+    fn package_paths() {
+        let entry_points = [EntryPoint::from_str("flask = flask.cli:main").unwrap()];
+        let layout = NoarchPythonLayout {
+            windows: false,
+            entry_points: &entry_points,
+        };
+        let package_path = |path: &str| layout.package_path(Path::new(path));
+
+        assert_eq!(
+            package_path("lib/python3.11/site-packages/flask/app.py"),
+            Some(PathBuf::from("site-packages/flask/app.py"))
+        );
+        assert_eq!(
+            package_path("Lib/site-packages/flask/app.py"),
+            Some(PathBuf::from("site-packages/flask/app.py"))
+        );
+        assert_eq!(
+            package_path("lib/python3.11/site-packages/flask/__pycache__/app.cpython-311.pyc"),
+            None
+        );
+        assert_eq!(package_path("lib/python3.11/site-packages/old.pyc"), None);
+        // conda creates the scripts of the entry points
+        assert_eq!(package_path("bin/flask"), None);
+        assert_eq!(package_path("Scripts/flask.exe"), None);
+        assert_eq!(
+            package_path("bin/other"),
+            Some(PathBuf::from("python-scripts/other"))
+        );
+        assert_eq!(
+            package_path("share/doc/flask.txt"),
+            Some(PathBuf::from("share/doc/flask.txt"))
+        );
+
+        let windows = NoarchPythonLayout {
+            windows: true,
+            entry_points: &entry_points,
+        };
+        assert_eq!(
+            windows.package_path(Path::new("Scripts/flask-script.py")),
+            None
+        );
+        assert_eq!(
+            windows.package_path(Path::new("Scripts/other-script.py")),
+            Some(PathBuf::from("python-scripts/other"))
+        );
+    }
+
+    #[test]
+    fn unversioned_interpreter() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = Path::new("/host_env_placehold_placehold");
+
+        let script = tmp.path().join("tool");
+        std::fs::write(
+            &script,
+            "#!/host_env_placehold_placehold/bin/python3.11\nimport sys\n",
+        )
+        .unwrap();
+        rewrite_shebang(&script, prefix).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&script).unwrap(),
+            "#!/host_env_placehold_placehold/bin/python\nimport sys\n"
+        );
+
+        // the wrapper of pip for long prefixes
+        let wrapper = tmp.path().join("wrapper");
+        let content = "#!/bin/sh\n'''exec' \"/host_env_placehold_placehold/bin/python3.12\" \"$0\" \"$@\"\n' '''\nprint('python3.12')\n";
+        std::fs::write(&wrapper, content).unwrap();
+        rewrite_shebang(&wrapper, prefix).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&wrapper).unwrap(),
+            content.replacen("python3.12", "python", 1)
+        );
+
+        // other files are not touched
+        let layout = NoarchPythonLayout {
+            windows: false,
+            entry_points: &[],
+        };
+        let data = tmp.path().join("site-packages/data.txt");
+        std::fs::create_dir_all(data.parent().unwrap()).unwrap();
+        std::fs::write(&data, "#!/host_env_placehold_placehold/bin/python3.11\n").unwrap();
+        layout
+            .post_process(&HashSet::from([data.clone()]), tmp.path(), prefix)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&data).unwrap(),
+            "#!/host_env_placehold_placehold/bin/python3.11\n"
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Validation of the version symlink chains of shared libraries (`build.fix_soname_links`).
+//!
+//! A shared library is usually installed as a chain of links, e.g. `libfoo.so -> libfoo.so.1 ->
+//! libfoo.so.1.2.3` on Linux or `libfoo.dylib -> libfoo.1.dylib -> libfoo.1.2.3.dylib` on
+//! macOS. If a level is missing, or was packaged as a copy instead of a link, consumers of the
+//! package fail to link against it (or load two copies of the same library). The levels of a
+//! chain are the unversioned name, the major version and the full version, plus any other
+//! version that is in the package. A link may point to any higher level, as long as it resolves
+//! to the library.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use fs_err as fs;
+
+/// The naming scheme of a shared library
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LibraryKind {
+    /// `libfoo.so.1.2.3`
+    Elf,
+    /// `libfoo.1.2.3.dylib`
+    MachO,
+}
+
+/// The file name of a shared library, split into its parts
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LibraryName {
+    kind: LibraryKind,
+    /// The name without the extension and the version (e.g. `libfoo`)
+    base: String,
+    /// The components of the version (empty for the unversioned name)
+    version: Vec<String>,
+}
+
+impl LibraryName {
+    /// Parse the file name of a shared library, returns `None` for all other files
+    fn parse(file_name: &str) -> Option<Self> {
+        if !file_name.starts_with("lib") {
+            return None;
+        }
+        let is_version = |parts: &[&str]| {
+            parts
+                .iter()
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        };
+
+        for (index, _) in file_name.match_indices(".so") {
+            let (base, rest) = (&file_name[..index], &file_name[index + 3..]);
+            let version = match rest.strip_prefix('.') {
+                Some(version) => version.split('.').collect::<Vec<_>>(),
+                None if rest.is_empty() => Vec::new(),
+                None => continue,
+            };
+            if is_version(&version) {
+                return Some(LibraryName {
+                    kind: LibraryKind::Elf,
+                    base: base.to_string(),
+                    version: version.into_iter().map(str::to_string).collect(),
+                });
+            }
+        }
+
+        let mut parts = file_name.strip_suffix(".dylib")?.split('.');
+        let base = parts.next()?;
+        let version = parts.collect::<Vec<_>>();
+        is_version(&version).then(|| LibraryName {
+            kind: LibraryKind::MachO,
+            base: base.to_string(),
+            version: version.into_iter().map(str::to_string).collect(),
+        })
+    }
+
+    /// The file name of the library with another version
+    fn with_version(&self, version: &[String]) -> String {
+        match (self.kind, version.is_empty()) {
+            (LibraryKind::Elf, true) => format!("{}.so", self.base),
+            (LibraryKind::Elf, false) => format!("{}.so.{}", self.base, version.join(".")),
+            (LibraryKind::MachO, true) => format!("{}.dylib", self.base),
+            (LibraryKind::MachO, false) => format!("{}.{}.dylib", self.base, version.join(".")),
+        }
+    }
+}
+
+/// A problem with the version symlink chain of a shared library. All paths are relative to the
+/// root of the package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ChainProblem {
+    /// A level of the chain is missing, it should link to `target`
+    Missing { path: PathBuf, target: String },
+    /// A level of the chain is a copy of the library instead of a link to `target`
+    Copy { path: PathBuf, target: String },
+    /// The link does not resolve to a file
+    Dangling { path: PathBuf, target: PathBuf },
+}
+
+impl ChainProblem {
+    /// Whether the problem can be fixed by (re)creating the link
+    pub fn is_fixable(&self) -> bool {
+        !matches!(self, ChainProblem::Dangling { .. })
+    }
+}
+
+impl Display for ChainProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainProblem::Missing { path, target } => {
+                write!(f, "{} is missing (a link to {})", path.display(), target)
+            }
+            ChainProblem::Copy { path, target } => write!(
+                f,
+                "{} is a copy of the library instead of a link to {}",
+                path.display(),
+                target
+            ),
+            ChainProblem::Dangling { path, target } => write!(
+                f,
+                "{} points to {}, which does not exist",
+                path.display(),
+                target.display()
+            ),
+        }
+    }
+}
+
+/// A file of a chain
+struct Member {
+    version: Vec<String>,
+    path: PathBuf,
+    is_link: bool,
+}
+
+/// Find the problems of the shared library chains among `files` (absolute paths in `root`)
+pub(crate) fn check_soname_chains(files: &HashSet<PathBuf>, root: &Path) -> Vec<ChainProblem> {
+    // the libraries of every directory, by kind and base name
+    let mut groups: BTreeMap<(PathBuf, LibraryKind, String), (LibraryName, Vec<Member>)> =
+        BTreeMap::new();
+    for file in files {
+        let (Some(parent), Some(name)) = (file.parent(), file.file_name()) else {
+            continue;
+        };
+        let Some(library) = name.to_str().and_then(LibraryName::parse) else {
+            continue;
+        };
+        let Ok(metadata) = file.symlink_metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            continue;
+        }
+        let member = Member {
+            version: library.version.clone(),
+            path: file.clone(),
+            is_link: metadata.is_symlink(),
+        };
+        groups
+            .entry((parent.to_path_buf(), library.kind, library.base.clone()))
+            .or_insert_with(|| (library, Vec::new()))
+            .1
+            .push(member);
+    }
+
+    let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_path_buf();
+    let mut problems = Vec::new();
+    for ((dir, _, _), (library, mut members)) in groups {
+        if members.len() < 2 {
+            continue;
+        }
+        members.sort_by(|a, b| a.version.cmp(&b.version));
+
+        for member in members.iter().filter(|m| m.is_link) {
+            if !member.path.exists() {
+                problems.push(ChainProblem::Dangling {
+                    path: relative(&member.path),
+                    target: fs::read_link(&member.path).unwrap_or_default(),
+                });
+            }
+        }
+
+        // the library is the regular file with the longest version, other regular files of the
+        // chain are copies
+        let Some(real) = members
+            .iter()
+            .filter(|m| !m.is_link)
+            .max_by_key(|m| m.version.len())
+        else {
+            continue;
+        };
+        let full = real.version.clone();
+        let in_chain = |m: &&Member| full.starts_with(&m.version);
+        let Some(lowest) = members.iter().filter(in_chain).map(|m| m.version.len()).min() else {
+            continue;
+        };
+
+        // the levels from the highest to the lowest
+        let mut levels = members
+            .iter()
+            .filter(in_chain)
+            .map(|m| m.version.clone())
+            .chain([Vec::new(), full.iter().take(1).cloned().collect()])
+            .filter(|version| version.len() >= lowest)
+            .collect::<Vec<_>>();
+        levels.sort();
+        levels.dedup();
+        levels.reverse();
+
+        for pair in levels.windows(2) {
+            let (higher, level) = (&pair[0], &pair[1]);
+            let target = library.with_version(higher);
+            let path = dir.join(library.with_version(level));
+            match members.iter().find(|m| &m.version == level) {
+                None => problems.push(ChainProblem::Missing {
+                    path: relative(&path),
+                    target,
+                }),
+                Some(member) if !member.is_link => problems.push(ChainProblem::Copy {
+                    path: relative(&path),
+                    target,
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+    problems
+}
+
+/// Create the links of the fixable problems in `root` and add them to `files`. The problems have
+/// to be in the order of [`check_soname_chains`], so that every link is created after its target.
+#[cfg(unix)]
+pub(crate) fn fix_soname_chains(
+    problems: &[ChainProblem],
+    files: &mut HashSet<PathBuf>,
+    root: &Path,
+) -> Result<(), std::io::Error> {
+    for problem in problems {
+        let (ChainProblem::Missing { path, target } | ChainProblem::Copy { path, target }) = problem
+        else {
+            continue;
+        };
+        let path = root.join(path);
+        if path.symlink_metadata().is_ok() {
+            fs::remove_file(&path)?;
+        }
+        std::os::unix::fs::symlink(target, &path)?;
+        tracing::info!("Linked {} to {}", path.display(), target);
+        files.insert(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LibraryKind, LibraryName};
+
+    #[test]
+    fn library_names() {
+        let name = LibraryName::parse("libfoo.so.1.2.3").unwrap();
+        assert_eq!(name.kind, LibraryKind::Elf);
+        assert_eq!(name.base, "libfoo");
+        assert_eq!(name.version, ["1", "2", "3"]);
+        assert_eq!(name.with_version(&["1".to_string()]), "libfoo.so.1");
+        assert_eq!(name.with_version(&[]), "libfoo.so");
+
+        let name = LibraryName::parse("libfoo.1.2.dylib").unwrap();
+        assert_eq!(name.kind, LibraryKind::MachO);
+        assert_eq!(name.version, ["1", "2"]);
+        assert_eq!(name.with_version(&["1".to_string()]), "libfoo.1.dylib");
+
+        assert_eq!(LibraryName::parse("libfoo.so").unwrap().version.len(), 0);
+        assert_eq!(LibraryName::parse("libfoo.so.1a"), None);
+        assert_eq!(LibraryName::parse("libfoo.sources"), None);
+        assert_eq!(
+            LibraryName::parse("libfoo.sock.so.1").unwrap().base,
+            "libfoo.sock"
+        );
+        assert_eq!(LibraryName::parse("foo.so.1"), None);
+        assert_eq!(LibraryName::parse("libfoo.a"), None);
+    }
+
+    #[cfg(unix)]
+    mod chains {
+        use std::collections::HashSet;
+        use std::os::unix::fs::symlink;
+        use std::path::{Path, PathBuf};
+
+        use crate::packaging::soname::{check_soname_chains, fix_soname_chains, ChainProblem};
+
+        /// A `lib` directory with the given files; a `Some` target makes the file a link
+        fn layout(root: &Path, files: &[(&str, Option<&str>)]) -> HashSet<PathBuf> {
+            let lib = root.join("lib");
+            fs_err::create_dir_all(&lib).unwrap();
+            files
+                .iter()
+                .map(|(name, target)| {
+                    let path = lib.join(name);
+                    match target {
+                        Some(target) => symlink(target, &path).unwrap(),
+                        None => fs_err::write(&path, "library").unwrap(),
+                    }
+                    path
+                })
+                .collect()
+        }
+
+        #[test]
+        fn complete_chain() {
+            let tmp = tempfile::tempdir().unwrap();
+            let files = layout(
+                tmp.path(),
+                &[
+                    ("libfoo.so", Some("libfoo.so.1")),
+                    ("libfoo.so.1", Some("libfoo.so.1.2.3")),
+                    ("libfoo.so.1.2.3", None),
+                    // libtool links all levels to the library directly
+                    ("libbar.dylib", Some("libbar.1.0.0.dylib")),
+                    ("libbar.1.dylib", Some("libbar.1.0.0.dylib")),
+                    ("libbar.1.0.0.dylib", None),
+                    // a single library is not a chain
+                    ("libbaz.so.2", None),
+                ],
+            );
+            assert_eq!(check_soname_chains(&files, tmp.path()), vec![]);
+        }
+
+        #[test]
+        fn partial_chain() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut files = layout(
+                tmp.path(),
+                &[
+                    ("libfoo.so", Some("libfoo.so.1")),
+                    ("libfoo.so.1.2.3", None),
+                ],
+            );
+            let problems = check_soname_chains(&files, tmp.path());
+            assert_eq!(
+                problems,
+                vec![
+                    ChainProblem::Dangling {
+                        path: "lib/libfoo.so".into(),
+                        target: "libfoo.so.1".into(),
+                    },
+                    ChainProblem::Missing {
+                        path: "lib/libfoo.so.1".into(),
+                        target: "libfoo.so.1.2.3".into(),
+                    },
+                ]
+            );
+
+            // the missing level also repairs the dangling link
+            fix_soname_chains(&problems, &mut files, tmp.path()).unwrap();
+            assert!(files.contains(&tmp.path().join("lib/libfoo.so.1")));
+            assert_eq!(check_soname_chains(&files, tmp.path()), vec![]);
+            assert!(tmp.path().join("lib/libfoo.so").exists());
+        }
+
+        #[test]
+        fn copy_instead_of_link() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut files = layout(
+                tmp.path(),
+                &[
+                    ("libfoo.so", Some("libfoo.so.1")),
+                    ("libfoo.so.1", None),
+                    ("libfoo.so.1.2.3", None),
+                ],
+            );
+            let problems = check_soname_chains(&files, tmp.path());
+            assert_eq!(
+                problems,
+                vec![ChainProblem::Copy {
+                    path: "lib/libfoo.so.1".into(),
+                    target: "libfoo.so.1.2.3".into(),
+                }]
+            );
+
+            fix_soname_chains(&problems, &mut files, tmp.path()).unwrap();
+            let link = tmp.path().join("lib/libfoo.so.1");
+            assert!(link.symlink_metadata().unwrap().is_symlink());
+            assert_eq!(check_soname_chains(&files, tmp.path()), vec![]);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Extended attributes of the new files (`build.xattr_policy`).
+//!
+//! Conda archives cannot represent extended attributes, so they are never packaged: the copies
+//! in the packaging folder are stripped of them. The SELinux label (`security.selinux`) and the
+//! POSIX ACLs (the `system.` namespace) of a Linux file system are ignored, as they are set by
+//! the system and not by the build. macOS keeps ACLs outside of the extended attributes.
+
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+
+use super::PackagingError;
+use crate::recipe::parser::XattrPolicy;
+
+/// The attribute that macOS sets on downloaded files until they are verified
+pub(crate) const QUARANTINE: &str = "com.apple.quarantine";
+
+/// Whether the attribute `name` is set by the system (see the module documentation)
+fn is_ignored(name: &str) -> bool {
+    name == "security.selinux" || name.starts_with("system.")
+}
+
+/// A new file with extended attributes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileAttributes {
+    /// The path of the file, relative to the prefix
+    pub path: PathBuf,
+    /// The names of the attributes
+    pub names: Vec<String>,
+}
+
+/// The sorted names of the extended attributes of `path`, without the ignored ones. Symlinks are
+/// not followed. A file system that does not support extended attributes has none.
+#[cfg(unix)]
+pub(crate) fn list(path: &Path) -> Vec<String> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Vec::new();
+    }
+    match xattr::list(path) {
+        Ok(names) => names
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| !is_ignored(name))
+            .sorted()
+            .collect(),
+        Err(e) => {
+            tracing::debug!(
+                "Could not list the extended attributes of {:?}: {}",
+                path,
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn list(_path: &Path) -> Vec<String> {
+    Vec::new()
+}
+
+/// Remove the extended attributes `names` from `path`, as far as the permissions allow
+#[cfg(unix)]
+pub(crate) fn strip(path: &Path, names: &[String]) {
+    for name in names {
+        if let Err(e) = xattr::remove(path, name) {
+            tracing::debug!("Could not remove `{}` from {:?}: {}", name, path, e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn strip(_path: &Path, _names: &[String]) {}
+
+/// The `files` with the attributes that are not ignored, sorted by their path
+fn reported(files: &[FileAttributes]) -> Vec<FileAttributes> {
+    files
+        .iter()
+        .map(|f| FileAttributes {
+            path: f.path.clone(),
+            names: f
+                .names
+                .iter()
+                .filter(|name| !is_ignored(name))
+                .cloned()
+                .collect(),
+        })
+        .filter(|f| !f.names.is_empty())
+        .sorted_by(|a, b| a.path.cmp(&b.path))
+        .collect()
+}
+
+/// Warn about the new `files` with extended attributes (that are not ignored). With the strict
+/// policy, quarantined files are an error.
+pub(crate) fn report(files: &[FileAttributes], policy: XattrPolicy) -> Result<(), PackagingError> {
+    let files = reported(files);
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let files = files.iter();
+    let quarantined = files
+        .clone()
+        .filter(|f| f.names.iter().any(|name| name == QUARANTINE))
+        .map(|f| format!(" - {}", f.path.display()))
+        .collect::<Vec<_>>();
+    if policy == XattrPolicy::Strict && !quarantined.is_empty() {
+        return Err(PackagingError::QuarantinedFiles(quarantined.join("\n")));
+    }
+
+    tracing::warn!(
+        "Extended attributes are not packaged, but these files have them:\n{}",
+        files
+            .map(|f| format!(" - {} ({})", f.path.display(), f.names.join(", ")))
+            .join("\n")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{is_ignored, report, reported, FileAttributes, QUARANTINE};
+    use crate::{packaging::PackagingError, recipe::parser::XattrPolicy};
+
+    #[cfg(unix)]
+    #[test]
+    fn detect_and_strip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("file.txt");
+        fs_err::write(&file, "hello").unwrap();
+        // SELinux labels every file, so only look at the attributes that are set here
+        let ours = |path: &std::path::Path| {
+            super::list(path)
+                .into_iter()
+                .filter(|name| name.starts_with("user.") || name == QUARANTINE)
+                .collect::<Vec<_>>()
+        };
+        assert!(ours(&file).is_empty());
+
+        // Linux only allows arbitrary attributes in the `user.` namespace
+        let mut names = vec!["user.rattler-build.a", "user.rattler-build.b"];
+        if cfg!(target_os = "macos") {
+            names.insert(0, QUARANTINE);
+        }
+        for name in &names {
+            if xattr::set(&file, name, b"value").is_err() {
+                // the file system of the temporary directory does not support them
+                return;
+            }
+        }
+        assert_eq!(ours(&file), names);
+
+        super::strip(&file, &super::list(&file));
+        assert!(ours(&file).is_empty());
+    }
+
+    #[test]
+    fn system_attributes_are_ignored() {
+        for name in [
+            "security.selinux",
+            "system.posix_acl_access",
+            "system.nfs4_acl",
+        ] {
+            assert!(is_ignored(name));
+        }
+        for name in ["user.rattler-build", "security.capability", QUARANTINE] {
+            assert!(!is_ignored(name));
+        }
+
+        // a file with only ignored attributes is not reported
+        let files = vec![
+            FileAttributes {
+                path: PathBuf::from("share/data.txt"),
+                names: vec![
+                    "security.selinux".to_string(),
+                    "system.posix_acl_access".to_string(),
+                ],
+            },
+            FileAttributes {
+                path: PathBuf::from("bin/tool"),
+                names: vec!["security.selinux".to_string(), "user.origin".to_string()],
+            },
+        ];
+        assert_eq!(
+            reported(&files),
+            vec![FileAttributes {
+                path: PathBuf::from("bin/tool"),
+                names: vec!["user.origin".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn strict_policy_fails_for_quarantined_files() {
+        let files = vec![
+            FileAttributes {
+                path: PathBuf::from("lib/libfoo.dylib"),
+                names: vec![QUARANTINE.to_string()],
+            },
+            FileAttributes {
+                path: PathBuf::from("share/data.txt"),
+                names: vec!["security.selinux".to_string()],
+            },
+        ];
+
+        assert!(report(&files, XattrPolicy::Warn).is_ok());
+        assert!(report(&files[1..], XattrPolicy::Strict).is_ok());
+        match report(&files, XattrPolicy::Strict) {
+            Err(PackagingError::QuarantinedFiles(list)) => {
+                assert_eq!(list, " - lib/libfoo.dylib")
+            }
+            other => panic!("expected an error, got {other:?}"),
+        }
+    }
+}
@@ -1,30 +1,38 @@
 //! Functions to post-process packages after building
 //! This includes:
 //!
-//! - relinking of shared libraries to be relocatable
+//! - relinking of shared libraries to be relocatable, and finding the references to the build
+//!   directory that are left afterwards
 //! - checking for "overlinking" (i.e. linking to libraries that are not dependencies
 //!   of the package, or linking to system libraries that are not part of the allowed list)
+//! - checking that every shared library needed by a binary of the package can be found
+//!   in the package itself, its run dependencies or the system
 
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
     fs::{self, File},
     io::Read,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
-use rattler_conda_types::{PackageName, Platform};
+use rattler_conda_types::{PackageName, Platform, PrefixRecord};
 
-use crate::{linux::link::SharedObject, macos::link::Dylib, packaging::PackagingError};
+use crate::{
+    linux::link::SharedObject, macos::link::Dylib, packaging::PackagingError, tools::Tools,
+};
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
 pub enum RelinkError {
     #[error("Error reading file: {0}")]
     Io(#[from] std::io::Error),
 
     #[error("Error relinking shared object: {0}")]
+    #[diagnostic(transparent)]
     SharedObject(#[from] crate::linux::link::RelinkError),
 
     #[error("Error relinking dylib: {0}")]
+    #[diagnostic(transparent)]
     Dylib(#[from] crate::macos::link::RelinkError),
 }
 
@@ -51,6 +59,7 @@ pub fn relink(
     prefix: &Path,
     encoded_prefix: &Path,
     target_platform: &Platform,
+    tools: &Tools,
 ) -> Result<(), RelinkError> {
     for p in paths {
         let metadata = fs::symlink_metadata(p)?;
@@ -73,17 +82,80 @@ pub fn relink(
         if target_platform.is_linux() {
             if SharedObject::test_file(p)? {
                 let so = SharedObject::new(p)?;
-                so.relink(prefix, encoded_prefix)?;
+                so.relink(prefix, encoded_prefix, tools)?;
             }
         } else if target_platform.is_osx() && Dylib::test_file(p)? {
             let dylib = Dylib::new(p)?;
-            dylib.relink(prefix, encoded_prefix)?;
+            dylib.relink(prefix, encoded_prefix, tools)?;
         }
     }
 
     Ok(())
 }
 
+/// Find the references to `build_dir` that are left in the rpaths of the binaries in `paths`
+/// after the relocation (and in the install names of the libraries on macOS). They point into
+/// the prefixes or the work directory of the build, which are removed after the build.
+///
+/// Only ELF and Mach-O files are examined. The binaries are reported relative to
+/// `package_root`.
+pub fn find_build_references(
+    paths: &HashSet<PathBuf>,
+    package_root: &Path,
+    build_dir: &Path,
+    target_platform: &Platform,
+) -> Result<BTreeMap<PathBuf, Vec<String>>, RelinkError> {
+    let mut references = BTreeMap::new();
+    for p in paths {
+        let metadata = fs::symlink_metadata(p)?;
+        if metadata.is_symlink() || metadata.is_dir() {
+            continue;
+        }
+
+        let mut found = if target_platform.is_linux() && SharedObject::test_file(p)? {
+            let so = SharedObject::new(p)?;
+            so.rpaths
+                .iter()
+                .chain(so.runpaths.iter())
+                .flat_map(|r| r.split(':'))
+                .filter(|r| Path::new(r).starts_with(build_dir))
+                .map(|r| format!("rpath {r}"))
+                .collect::<Vec<_>>()
+        } else if target_platform.is_osx() && Dylib::test_file(p)? {
+            match Dylib::new(p) {
+                Ok(dylib) => dylib
+                    .rpaths
+                    .iter()
+                    .filter(|r| r.starts_with(build_dir))
+                    .map(|r| format!("rpath {}", r.display()))
+                    .chain(
+                        dylib
+                            .id
+                            .iter()
+                            .chain(dylib.libraries.iter())
+                            .filter(|l| l.starts_with(build_dir))
+                            .map(|l| format!("library {}", l.display())),
+                    )
+                    .collect(),
+                Err(crate::macos::link::RelinkError::FileTypeNotHandled) => Vec::new(),
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            Vec::new()
+        };
+
+        if !found.is_empty() {
+            found.sort();
+            let binary = p
+                .strip_prefix(package_root)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| p.clone());
+            references.insert(binary, found);
+        }
+    }
+    Ok(references)
+}
+
 /// Find any .dist-info/INSTALLER files and replace the contents with "conda"
 /// This is to prevent pip from trying to uninstall the package when it is installed with conda
 pub fn python(
@@ -121,3 +193,508 @@ pub fn python(
 
     Ok(())
 }
+
+/// Read the records of all packages installed in the prefix (from its `conda-meta` folder)
+pub(crate) fn prefix_records(prefix: &Path) -> Result<Vec<PrefixRecord>, std::io::Error> {
+    let conda_meta = prefix.join("conda-meta");
+    if !conda_meta.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut records = Vec::new();
+    for entry in fs::read_dir(conda_meta)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+        records.push(PrefixRecord::from_path(&path)?);
+    }
+    Ok(records)
+}
+
+/// Map every file (relative to the prefix) of the packages installed in the prefix to the name
+/// of the package that owns it
+pub fn file_owners(prefix: &Path) -> Result<HashMap<PathBuf, String>, std::io::Error> {
+    let mut owners = HashMap::new();
+    for record in prefix_records(prefix)? {
+        let name = record.repodata_record.package_record.name.as_normalized();
+        for file in &record.files {
+            owners.insert(file.clone(), name.to_string());
+        }
+    }
+    Ok(owners)
+}
+
+/// A new symlink that points to a file that is owned by another package in the prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignSymlink {
+    /// The path of the symlink, relative to the prefix
+    pub link: PathBuf,
+    /// The path of the target, relative to the prefix
+    pub target: PathBuf,
+    /// The name of the package that owns the target
+    pub owner: String,
+}
+
+impl fmt::Display for ForeignSymlink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} (owned by {})",
+            self.link.display(),
+            self.target.display(),
+            self.owner
+        )
+    }
+}
+
+/// Lexically resolve `.` and `..` components of a path
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            other => result.push(other),
+        }
+    }
+    result
+}
+
+/// Find the symlinks among `paths` whose (resolved) target is a file of one of the packages in
+/// `owners` (see [`file_owners`]).
+pub fn find_foreign_symlinks(
+    paths: &HashSet<PathBuf>,
+    prefix: &Path,
+    owners: &HashMap<PathBuf, String>,
+) -> Result<Vec<ForeignSymlink>, std::io::Error> {
+    let mut links = Vec::new();
+    for path in paths {
+        if !fs::symlink_metadata(path)?.is_symlink() {
+            continue;
+        }
+
+        let target = fs::read_link(path)?;
+        let target = match path.parent() {
+            Some(parent) if target.is_relative() => normalize_path(&parent.join(target)),
+            _ => normalize_path(&target),
+        };
+
+        let Ok(target) = target.strip_prefix(prefix) else {
+            continue;
+        };
+        if let Some(owner) = owners.get(target) {
+            links.push(ForeignSymlink {
+                link: path.strip_prefix(prefix).unwrap_or(path).to_path_buf(),
+                target: target.to_path_buf(),
+                owner: owner.clone(),
+            });
+        }
+    }
+    links.sort_by(|a, b| a.link.cmp(&b.link));
+    Ok(links)
+}
+
+/// Libraries that are expected to be provided by the system on Linux
+const LINUX_SYSTEM_LIBRARIES: [&str; 14] = [
+    "ld-linux*.so*",
+    "ld64.so*",
+    "linux-vdso.so*",
+    "libc.so*",
+    "libm.so*",
+    "libdl.so*",
+    "libpthread.so*",
+    "librt.so*",
+    "libutil.so*",
+    "libresolv.so*",
+    "libnsl.so*",
+    "libcrypt.so*",
+    "libanl.so*",
+    "libBrokenLocale.so*",
+];
+
+/// Returns true if the library is part of the platform (and thus does not need to be
+/// provided by a package)
+fn is_system_library(library: &str, target_platform: &Platform) -> bool {
+    if target_platform.is_osx() {
+        return library.starts_with("/usr/lib/") || library.starts_with("/System/Library/");
+    }
+
+    let file_name = library_file_name(library);
+    LINUX_SYSTEM_LIBRARIES.iter().any(|pattern| {
+        globset::Glob::new(pattern)
+            .map(|glob| glob.compile_matcher().is_match(&file_name))
+            .unwrap_or(false)
+    })
+}
+
+/// The file name of a needed library (e.g. `libfoo.dylib` for `@rpath/libfoo.dylib`)
+fn library_file_name(library: &str) -> String {
+    Path::new(library)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| library.to_string())
+}
+
+/// Returns true if the file name looks like a shared library
+fn is_shared_library_name(file_name: &str) -> bool {
+    file_name.ends_with(".so")
+        || file_name.contains(".so.")
+        || file_name.ends_with(".dylib")
+        || file_name.to_lowercase().ends_with(".dll")
+}
+
+/// A map from shared library names to the packages that provide them
+#[derive(Debug, Default, Clone)]
+pub struct DsoProviders {
+    libraries: HashMap<String, BTreeSet<String>>,
+    searched: BTreeSet<String>,
+}
+
+impl DsoProviders {
+    /// Collect the shared libraries of all packages in the `conda-meta` folder of the prefix
+    /// whose name is part of `packages` (usually the run dependencies of the package).
+    pub fn from_prefix(prefix: &Path, packages: &HashSet<String>) -> Result<Self, std::io::Error> {
+        let mut providers = Self::default();
+        for record in prefix_records(prefix)? {
+            let name = record.repodata_record.package_record.name.as_normalized();
+            if packages.contains(name) {
+                providers.add_package(name, &record.files);
+            }
+        }
+        Ok(providers)
+    }
+
+    /// Register the shared libraries among `files` as provided by `package`
+    pub fn add_package(&mut self, package: &str, files: &[PathBuf]) {
+        self.searched.insert(package.to_string());
+        for file in files {
+            if let Some(file_name) = file.file_name() {
+                let file_name = file_name.to_string_lossy();
+                if is_shared_library_name(&file_name) {
+                    self.add_library(package, &file_name);
+                }
+            }
+        }
+    }
+
+    /// Register a single library (file name or soname) as provided by `package`
+    pub fn add_library(&mut self, package: &str, library: &str) {
+        self.searched.insert(package.to_string());
+        self.libraries
+            .entry(library.to_string())
+            .or_default()
+            .insert(package.to_string());
+    }
+
+    /// Returns true if some package provides the library
+    fn provides(&self, library: &str) -> bool {
+        self.libraries.contains_key(library)
+            || self.libraries.contains_key(&library_file_name(library))
+    }
+}
+
+/// The shared libraries that could not be resolved, grouped by the binary that needs them
+#[derive(Debug, Default)]
+pub struct MissingDsoReport {
+    /// The binaries (relative to the package root) and the libraries they are missing
+    pub missing: BTreeMap<PathBuf, Vec<String>>,
+    /// The packages that were searched for the libraries
+    pub searched: Vec<String>,
+}
+
+impl MissingDsoReport {
+    /// Returns true if all needed libraries could be resolved
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+impl fmt::Display for MissingDsoReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Found binaries that need shared libraries that are not available:"
+        )?;
+        for (binary, libraries) in &self.missing {
+            writeln!(f, "  {}", binary.display())?;
+            for library in libraries {
+                writeln!(f, "    - {}", library)?;
+            }
+        }
+        write!(
+            f,
+            "Searched in the package itself, the system libraries and: {}",
+            if self.searched.is_empty() {
+                "(no run dependencies)".to_string()
+            } else {
+                self.searched.join(", ")
+            }
+        )
+    }
+}
+
+/// Check that all shared libraries needed by the binaries in `paths` can be found either in
+/// the package itself, in one of the `providers` (the run dependencies), or on the system.
+///
+/// Only ELF and Mach-O files are examined. The `package_root` is the directory that contains
+/// the files of the package and is used to report relative paths.
+pub fn check_missing_dsos(
+    paths: &HashSet<PathBuf>,
+    package_root: &Path,
+    providers: &DsoProviders,
+    target_platform: &Platform,
+) -> Result<MissingDsoReport, RelinkError> {
+    let mut own_libraries = HashSet::new();
+    let mut binaries = Vec::new();
+
+    for p in paths {
+        if let Some(file_name) = p.file_name() {
+            own_libraries.insert(file_name.to_string_lossy().to_string());
+        }
+
+        let metadata = fs::symlink_metadata(p)?;
+        if metadata.is_symlink() || metadata.is_dir() {
+            continue;
+        }
+
+        if target_platform.is_linux() {
+            if SharedObject::test_file(p)? {
+                let so = SharedObject::new(p)?;
+                if let Some(soname) = &so.soname {
+                    own_libraries.insert(soname.clone());
+                }
+                binaries.push((p.clone(), so.libraries.into_iter().collect::<Vec<_>>()));
+            }
+        } else if target_platform.is_osx() && Dylib::test_file(p)? {
+            match Dylib::new(p) {
+                Ok(dylib) => {
+                    if let Some(id) = &dylib.id {
+                        own_libraries.insert(library_file_name(&id.to_string_lossy()));
+                    }
+                    let libraries = dylib
+                        .libraries
+                        .iter()
+                        .map(|lib| lib.to_string_lossy().to_string())
+                        .collect();
+                    binaries.push((p.clone(), libraries));
+                }
+                Err(crate::macos::link::RelinkError::FileTypeNotHandled) => {
+                    tracing::info!("Skipping DSO check for {}", p.display());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    let mut report = MissingDsoReport {
+        searched: providers.searched.iter().cloned().collect(),
+        ..Default::default()
+    };
+
+    for (binary, libraries) in binaries {
+        let mut missing = libraries
+            .into_iter()
+            .filter(|lib| {
+                !own_libraries.contains(lib)
+                    && !own_libraries.contains(&library_file_name(lib))
+                    && !providers.provides(lib)
+                    && !is_system_library(lib, target_platform)
+            })
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            missing.sort();
+            let binary = binary
+                .strip_prefix(package_root)
+                .map(Path::to_path_buf)
+                .unwrap_or(binary);
+            report.missing.insert(binary, missing);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        path::{Path, PathBuf},
+    };
+
+    use rattler_conda_types::Platform;
+
+    use super::{
+        check_missing_dsos, file_owners, find_build_references, find_foreign_symlinks, DsoProviders,
+    };
+
+    /// Copy the fixture binaries into `lib/` of a fresh package root
+    fn package_with_fixtures(root: &Path) -> HashSet<PathBuf> {
+        let fixtures =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/binary_files/missing_dso");
+        let lib = root.join("lib");
+        fs_err::create_dir_all(&lib).unwrap();
+        ["plugin.so", "libfoo.so.3"]
+            .iter()
+            .map(|name| {
+                let dest = lib.join(name);
+                fs_err::copy(fixtures.join(name), &dest).unwrap();
+                dest
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_missing_soname() {
+        let tmp = tempfile::tempdir().unwrap();
+        let paths = package_with_fixtures(tmp.path());
+
+        let mut providers = DsoProviders::default();
+        providers.add_package("libbar", &[PathBuf::from("lib/libbar.so.1")]);
+
+        let report =
+            check_missing_dsos(&paths, tmp.path(), &providers, &Platform::Linux64).unwrap();
+
+        // `libc.so.6` is part of the system, `libfoo.so.2` is nowhere to be found
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(
+            report.missing.get(Path::new("lib/plugin.so")).unwrap(),
+            &vec!["libfoo.so.2".to_string()]
+        );
+        assert_eq!(report.searched, vec!["libbar".to_string()]);
+
+        let message = report.to_string();
+        assert!(message.contains("lib/plugin.so"));
+        assert!(message.contains("libfoo.so.2"));
+        assert!(message.contains("libbar"));
+    }
+
+    #[test]
+    fn run_dependency_provides_soname() {
+        let tmp = tempfile::tempdir().unwrap();
+        let paths = package_with_fixtures(tmp.path());
+
+        let mut providers = DsoProviders::default();
+        providers.add_package(
+            "libfoo",
+            &[
+                PathBuf::from("lib/libfoo.so.2"),
+                PathBuf::from("include/foo.h"),
+            ],
+        );
+
+        let report =
+            check_missing_dsos(&paths, tmp.path(), &providers, &Platform::Linux64).unwrap();
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn rpaths_into_the_build_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lib = tmp.path().join("lib");
+        fs_err::create_dir_all(&lib).unwrap();
+        fs_err::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/binary_files/build_references/librpath.so"),
+            lib.join("librpath.so"),
+        )
+        .unwrap();
+        fs_err::write(lib.join("notes.txt"), "/bld/rattler-build_test/work").unwrap();
+        let paths = HashSet::from([lib.join("librpath.so"), lib.join("notes.txt")]);
+
+        let references = find_build_references(
+            &paths,
+            tmp.path(),
+            Path::new("/bld/rattler-build_test"),
+            &Platform::Linux64,
+        )
+        .unwrap();
+        // `$ORIGIN/../lib` and `/opt/system/lib` are fine, and text files are not examined
+        assert_eq!(references.len(), 1);
+        assert_eq!(
+            references[Path::new("lib/librpath.so")],
+            vec!["rpath /bld/rattler-build_test/host_env_placehold/lib".to_string()]
+        );
+
+        let references = find_build_references(
+            &paths,
+            tmp.path(),
+            Path::new("/bld/rattler-build_other"),
+            &Platform::Linux64,
+        )
+        .unwrap();
+        assert!(references.is_empty());
+    }
+
+    /// Write a minimal `conda-meta` record for a package that owns `files`
+    fn install_fake_package(prefix: &Path, name: &str, files: &[&str]) {
+        let conda_meta = prefix.join("conda-meta");
+        fs_err::create_dir_all(&conda_meta).unwrap();
+        let record = serde_json::json!({
+            "name": name,
+            "version": "1.0.0",
+            "build": "h1234_0",
+            "build_number": 0,
+            "subdir": "linux-64",
+            "fn": format!("{name}-1.0.0-h1234_0.tar.bz2"),
+            "url": format!("https://conda.anaconda.org/conda-forge/linux-64/{name}-1.0.0-h1234_0.tar.bz2"),
+            "channel": "https://conda.anaconda.org/conda-forge/linux-64",
+            "files": files,
+            "paths_data": { "paths_version": 1, "paths": [] },
+        });
+        fs_err::write(
+            conda_meta.join(format!("{name}-1.0.0-h1234_0.json")),
+            serde_json::to_string(&record).unwrap(),
+        )
+        .unwrap();
+        for file in files {
+            let path = prefix.join(file);
+            fs_err::create_dir_all(path.parent().unwrap()).unwrap();
+            fs_err::write(path, name).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_into_dependencies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path();
+        install_fake_package(
+            prefix,
+            "python",
+            &["bin/python3.11", "lib/libpython3.11.so"],
+        );
+
+        let bin = prefix.join("bin");
+        let new_files = [
+            // relative link to a file of `python`
+            ("bin/python3", PathBuf::from("python3.11")),
+            // absolute link to a file of `python`
+            ("lib/libpython3.so", prefix.join("lib/libpython3.11.so")),
+            // link to a file of the package itself
+            ("bin/tool", PathBuf::from("tool-1.0")),
+        ];
+        fs_err::write(bin.join("tool-1.0"), "tool").unwrap();
+        let mut paths = HashSet::from([bin.join("tool-1.0")]);
+        for (link, target) in &new_files {
+            std::os::unix::fs::symlink(target, prefix.join(link)).unwrap();
+            paths.insert(prefix.join(link));
+        }
+
+        let owners = file_owners(prefix).unwrap();
+        assert_eq!(owners.get(Path::new("bin/python3.11")).unwrap(), "python");
+
+        let links = find_foreign_symlinks(&paths, prefix, &owners).unwrap();
+        let links = links.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            vec![
+                "bin/python3 -> bin/python3.11 (owned by python)",
+                "lib/libpython3.so -> lib/libpython3.11.so (owned by python)",
+            ]
+        );
+    }
+}
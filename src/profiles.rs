@@ -0,0 +1,795 @@
+//! Named profiles of build settings (`--profile`).
+//!
+//! The profiles are stored in a YAML configuration file:
+//!
+//! ```yaml
+//! profiles:
+//!   release:
+//!     channels: [conda-forge]
+//!     package_format: conda
+//!   debug:
+//!     keep_build: true
+//!     no_test: true
+//! ```
+//!
+//! The settings of a build are layered: the values of the selected profile are overridden by
+//! the environment variables, which are overridden by the command line options.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rattler_conda_types::package::ArchiveType;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ci_log::CiLogStyle,
+    container::ContainerRuntime,
+    metadata::PackageCompression,
+    progress::ProgressOutput,
+    render::integrity::PrefixVerification,
+    skip_existing::SkipExisting,
+    source::{cache::default_source_cache_dir, limits::SourceLimits},
+    tool_configuration::{DEFAULT_DOWNLOAD_RETRIES, DEFAULT_SOURCE_FETCH_CONCURRENCY},
+};
+
+/// The environment variable with the path of the configuration file
+pub const CONFIG_FILE_ENV: &str = "RATTLER_BUILD_CONFIG";
+
+#[allow(missing_docs)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum ProfileError {
+    #[error("Could not read the configuration file `{0}`: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("Could not parse the configuration file `{0}`: {1}")]
+    Parse(PathBuf, serde_yaml::Error),
+
+    #[error("There is no configuration file with the profile `{0}`")]
+    #[diagnostic(help("create `{1}` or pass the file with `--config-file`"))]
+    NoConfigFile(String, String),
+
+    #[error("The profile `{name}` is not defined in `{path}`")]
+    #[diagnostic(help("the defined profiles are: {available}"))]
+    UnknownProfile {
+        name: String,
+        path: PathBuf,
+        available: String,
+    },
+
+    #[error("Invalid value `{value}` of the environment variable {name}")]
+    InvalidEnv { name: &'static str, value: String },
+
+    #[error("Conflicting settings: {0}")]
+    Conflict(String),
+}
+
+/// The format of the built packages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageFormat {
+    /// `.tar.bz2`
+    TarBz2,
+    /// `.conda`
+    Conda,
+    /// `.tar.bz2` and `.conda`
+    Both,
+}
+
+/// The format of the main package (the one that is tested)
+impl From<PackageFormat> for ArchiveType {
+    fn from(format: PackageFormat) -> Self {
+        match format {
+            PackageFormat::TarBz2 | PackageFormat::Both => ArchiveType::TarBz2,
+            PackageFormat::Conda => ArchiveType::Conda,
+        }
+    }
+}
+
+impl PackageFormat {
+    /// The formats of the packages that are written next to the main package
+    pub fn additional_archive_types(self) -> Vec<ArchiveType> {
+        match self {
+            PackageFormat::Both => vec![ArchiveType::Conda],
+            PackageFormat::TarBz2 | PackageFormat::Conda => Vec::new(),
+        }
+    }
+}
+
+/// The settings of a build that a profile, the environment or the command line can set. Unset
+/// values are taken from the layer below, and finally from the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// The channels to get the dependencies from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<Vec<String>>,
+    /// The format of the built packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package_format: Option<PackageFormat>,
+    /// The bzip2 level of `.tar.bz2` packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tar_bz2_compression_level: Option<u32>,
+    /// The zstd level of `.conda` packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conda_compression_level: Option<i32>,
+    /// The number of threads that compress `.conda` packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression_threads: Option<u32>,
+    /// Keep the build directory after the build
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_build: Option<bool>,
+    /// Do not use a build id (timestamp) in the name of the build directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_build_id: Option<bool>,
+    /// Reuse the environments of the kept build directory if the dependencies did not change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reuse_environments: Option<bool>,
+    /// Check the files of the installed packages before the build script runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_prefixes: Option<PrefixVerification>,
+    /// Link modified packages again instead of failing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair_prefixes: Option<bool>,
+    /// Do not store the recipe in the package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_include_recipe: Option<bool>,
+    /// Do not run the tests after the build
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_test: Option<bool>,
+    /// Do not force colors in the output of the build script
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_force_colors: Option<bool>,
+    /// Keep building the other outputs when a build fails
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continue_on_failure: Option<bool>,
+    /// Fail when a key of the variant configuration is not used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_variants: Option<bool>,
+    /// Only use cached repodata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline: Option<bool>,
+    /// Use `repodata.json.zst`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_zstd: Option<bool>,
+    /// Use `repodata.json.bz2`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_bz2: Option<bool>,
+    /// The style of the group markers of the build phases
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ci_log_style: Option<CiLogStyle>,
+    /// Apply patches with the `patch` executable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_patch_executable: Option<bool>,
+    /// The maximum number of sources that are downloaded at the same time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_fetch_concurrency: Option<usize>,
+    /// How often a failed source download is retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_retries: Option<usize>,
+    /// The maximum total size of the files of a single source in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_source_size: Option<u64>,
+    /// The maximum number of files of a single source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_source_files: Option<u64>,
+    /// The container image to build in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_image: Option<String>,
+    /// The container runtime for the container image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_runtime: Option<ContainerRuntime>,
+    /// The `rattler-build` executable that runs in the container
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_executable: Option<PathBuf>,
+    /// Locally built packages that replace the packages of the same name in the channels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_local_package: Option<Vec<PathBuf>>,
+    /// Kill the build script after this many seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_timeout: Option<u64>,
+    /// The cache of the downloaded sources and git repositories
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_cache_dir: Option<PathBuf>,
+    /// Skip outputs whose package already exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_existing: Option<SkipExisting>,
+    /// Write a lock file with the resolved dependencies next to each package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_lock_file: Option<bool>,
+    /// How the progress of downloads, extractions and installations is reported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<ProgressOutput>,
+}
+
+impl Settings {
+    /// Read the settings from the environment variables
+    pub fn from_env() -> Result<Self, ProfileError> {
+        Self::from_env_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_env_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ProfileError> {
+        let bool_var = |name: &'static str| -> Result<Option<bool>, ProfileError> {
+            let Some(value) = var(name) else {
+                return Ok(None);
+            };
+            match value.trim().to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(Some(true)),
+                "0" | "false" | "no" | "off" | "" => Ok(Some(false)),
+                _ => Err(ProfileError::InvalidEnv { name, value }),
+            }
+        };
+        let number_var = |name: &'static str| -> Result<Option<usize>, ProfileError> {
+            var(name)
+                .map(|value| {
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| ProfileError::InvalidEnv { name, value })
+                })
+                .transpose()
+        };
+
+        Ok(Self {
+            offline: bool_var("RATTLER_OFFLINE")?,
+            use_zstd: bool_var("RATTLER_ZSTD")?,
+            use_bz2: bool_var("RATTLER_BZ2")?,
+            use_patch_executable: bool_var("RATTLER_BUILD_USE_PATCH_EXECUTABLE")?,
+            source_fetch_concurrency: number_var("RATTLER_BUILD_SOURCE_FETCH_CONCURRENCY")?,
+            download_retries: number_var("RATTLER_BUILD_DOWNLOAD_RETRIES")?,
+            max_source_size: number_var("RATTLER_BUILD_MAX_SOURCE_SIZE")?.map(|n| n as u64),
+            max_source_files: number_var("RATTLER_BUILD_MAX_SOURCE_FILES")?.map(|n| n as u64),
+            source_cache_dir: var("RATTLER_BUILD_SOURCE_CACHE_DIR").map(PathBuf::from),
+            ..Default::default()
+        })
+    }
+
+    /// Layer `other` on top of these settings: the values that are set in `other` win. Lists
+    /// are replaced, not extended.
+    pub fn merge(self, other: Settings) -> Settings {
+        Settings {
+            channels: other.channels.or(self.channels),
+            package_format: other.package_format.or(self.package_format),
+            tar_bz2_compression_level: other
+                .tar_bz2_compression_level
+                .or(self.tar_bz2_compression_level),
+            conda_compression_level: other
+                .conda_compression_level
+                .or(self.conda_compression_level),
+            compression_threads: other.compression_threads.or(self.compression_threads),
+            keep_build: other.keep_build.or(self.keep_build),
+            no_build_id: other.no_build_id.or(self.no_build_id),
+            reuse_environments: other.reuse_environments.or(self.reuse_environments),
+            verify_prefixes: other.verify_prefixes.or(self.verify_prefixes),
+            repair_prefixes: other.repair_prefixes.or(self.repair_prefixes),
+            no_include_recipe: other.no_include_recipe.or(self.no_include_recipe),
+            no_test: other.no_test.or(self.no_test),
+            no_force_colors: other.no_force_colors.or(self.no_force_colors),
+            continue_on_failure: other.continue_on_failure.or(self.continue_on_failure),
+            strict_variants: other.strict_variants.or(self.strict_variants),
+            offline: other.offline.or(self.offline),
+            use_zstd: other.use_zstd.or(self.use_zstd),
+            use_bz2: other.use_bz2.or(self.use_bz2),
+            ci_log_style: other.ci_log_style.or(self.ci_log_style),
+            use_patch_executable: other.use_patch_executable.or(self.use_patch_executable),
+            source_fetch_concurrency: other
+                .source_fetch_concurrency
+                .or(self.source_fetch_concurrency),
+            download_retries: other.download_retries.or(self.download_retries),
+            max_source_size: other.max_source_size.or(self.max_source_size),
+            max_source_files: other.max_source_files.or(self.max_source_files),
+            container_image: other.container_image.or(self.container_image),
+            container_runtime: other.container_runtime.or(self.container_runtime),
+            container_executable: other.container_executable.or(self.container_executable),
+            use_local_package: other.use_local_package.or(self.use_local_package),
+            build_timeout: other.build_timeout.or(self.build_timeout),
+            source_cache_dir: other.source_cache_dir.or(self.source_cache_dir),
+            skip_existing: other.skip_existing.or(self.skip_existing),
+            write_lock_file: other.write_lock_file.or(self.write_lock_file),
+            progress: other.progress.or(self.progress),
+        }
+    }
+
+    /// The settings of the `profile` (if any), layered below the environment variables and the
+    /// `cli` settings. The result is validated.
+    pub fn layered(profile: Option<Settings>, cli: Settings) -> Result<Settings, ProfileError> {
+        let settings = profile
+            .unwrap_or_default()
+            .merge(Self::from_env()?)
+            .merge(cli);
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Check for settings that cannot be combined, and for settings that require other ones
+    pub fn validate(&self) -> Result<(), ProfileError> {
+        self.check_conflicts()?;
+        if self.container_image.is_none()
+            && (self.container_runtime.is_some() || self.container_executable.is_some())
+        {
+            return Err(ProfileError::Conflict(
+                "`container_runtime` and `container_executable` require a `container_image`"
+                    .to_string(),
+            ));
+        }
+        if self.reuse_environments() && !(self.keep_build() && self.no_build_id()) {
+            return Err(ProfileError::Conflict(
+                "`reuse_environments` requires `keep_build` and `no_build_id`".to_string(),
+            ));
+        }
+        if self.repair_prefixes() && self.verify_prefixes() == PrefixVerification::Off {
+            return Err(ProfileError::Conflict(
+                "`repair_prefixes` requires `verify_prefixes`".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check for settings that cannot be combined. Unlike [`Settings::validate`], this does not
+    /// fail for settings that another layer can complete.
+    fn check_conflicts(&self) -> Result<(), ProfileError> {
+        let conflict = |message: &str| Err(ProfileError::Conflict(message.to_string()));
+        if self.container_image.is_some() && !self.local_packages().is_empty() {
+            return conflict("`use_local_package` cannot be combined with `container_image`");
+        }
+        if self.source_fetch_concurrency == Some(0) {
+            return conflict("`source_fetch_concurrency` must be at least 1");
+        }
+        if self.build_timeout == Some(0) {
+            return conflict("`build_timeout` must be at least 1 second");
+        }
+        if matches!(self.tar_bz2_compression_level, Some(level) if !(1..=9).contains(&level)) {
+            return conflict("`tar_bz2_compression_level` must be between 1 and 9");
+        }
+        if matches!(self.conda_compression_level, Some(level) if !(-7..=22).contains(&level)) {
+            return conflict("`conda_compression_level` must be between -7 and 22");
+        }
+        if self.compression_threads == Some(0) {
+            return conflict("`compression_threads` must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// The settings with the defaults filled in, as they are used by the build
+    pub fn effective(&self) -> Settings {
+        Settings {
+            channels: Some(self.channels()),
+            package_format: Some(self.package_format()),
+            tar_bz2_compression_level: Some(self.compression().tar_bz2_level),
+            conda_compression_level: Some(self.compression().conda_level),
+            compression_threads: Some(self.compression().threads),
+            keep_build: Some(self.keep_build()),
+            no_build_id: Some(self.no_build_id()),
+            reuse_environments: Some(self.reuse_environments()),
+            verify_prefixes: Some(self.verify_prefixes()),
+            repair_prefixes: Some(self.repair_prefixes()),
+            no_include_recipe: Some(self.no_include_recipe()),
+            no_test: Some(self.no_test()),
+            no_force_colors: Some(self.no_force_colors()),
+            continue_on_failure: Some(self.continue_on_failure()),
+            strict_variants: Some(self.strict_variants()),
+            offline: Some(self.offline()),
+            use_zstd: Some(self.use_zstd()),
+            use_bz2: Some(self.use_bz2()),
+            ci_log_style: Some(self.ci_log_style.unwrap_or_else(CiLogStyle::detect)),
+            use_patch_executable: Some(self.use_patch_executable()),
+            source_fetch_concurrency: Some(self.source_fetch_concurrency()),
+            download_retries: Some(self.download_retries()),
+            max_source_size: Some(self.source_limits().max_size),
+            max_source_files: Some(self.source_limits().max_files),
+            container_image: self.container_image.clone(),
+            container_runtime: self.container_runtime,
+            container_executable: self.container_executable.clone(),
+            use_local_package: Some(self.local_packages().to_vec()),
+            build_timeout: self.build_timeout,
+            source_cache_dir: Some(self.source_cache_dir()),
+            skip_existing: Some(self.skip_existing()),
+            write_lock_file: Some(self.write_lock_file()),
+            progress: Some(self.progress()),
+        }
+    }
+
+    /// The channels (`conda-forge` by default)
+    pub fn channels(&self) -> Vec<String> {
+        self.channels
+            .clone()
+            .unwrap_or_else(|| vec!["conda-forge".to_string()])
+    }
+
+    /// The package format (`.tar.bz2` by default)
+    pub fn package_format(&self) -> PackageFormat {
+        self.package_format.unwrap_or(PackageFormat::TarBz2)
+    }
+
+    /// The compression of the packages (the levels of `rattler_package_streaming` and a single
+    /// thread by default)
+    pub fn compression(&self) -> PackageCompression {
+        let default = PackageCompression::default();
+        PackageCompression {
+            tar_bz2_level: self
+                .tar_bz2_compression_level
+                .unwrap_or(default.tar_bz2_level),
+            conda_level: self.conda_compression_level.unwrap_or(default.conda_level),
+            threads: self.compression_threads.unwrap_or(default.threads),
+        }
+    }
+
+    /// Whether to keep the build directory
+    pub fn keep_build(&self) -> bool {
+        self.keep_build.unwrap_or_default()
+    }
+
+    /// Whether to create the build directory without a build id
+    pub fn no_build_id(&self) -> bool {
+        self.no_build_id.unwrap_or_default()
+    }
+
+    /// Whether to reuse the environments of the last build
+    pub fn reuse_environments(&self) -> bool {
+        self.reuse_environments.unwrap_or_default()
+    }
+
+    /// How to check the installed packages (not at all by default)
+    pub fn verify_prefixes(&self) -> PrefixVerification {
+        self.verify_prefixes.unwrap_or_default()
+    }
+
+    /// Whether to repair modified packages
+    pub fn repair_prefixes(&self) -> bool {
+        self.repair_prefixes.unwrap_or_default()
+    }
+
+    /// Whether to leave the recipe out of the package
+    pub fn no_include_recipe(&self) -> bool {
+        self.no_include_recipe.unwrap_or_default()
+    }
+
+    /// Whether to skip the tests
+    pub fn no_test(&self) -> bool {
+        self.no_test.unwrap_or_default()
+    }
+
+    /// Whether to not force colors in the build script
+    pub fn no_force_colors(&self) -> bool {
+        self.no_force_colors.unwrap_or_default()
+    }
+
+    /// Whether to keep building when a build fails
+    pub fn continue_on_failure(&self) -> bool {
+        self.continue_on_failure.unwrap_or_default()
+    }
+
+    /// Whether unused variant keys are an error
+    pub fn strict_variants(&self) -> bool {
+        self.strict_variants.unwrap_or_default()
+    }
+
+    /// Whether to only use cached repodata
+    pub fn offline(&self) -> bool {
+        self.offline.unwrap_or_default()
+    }
+
+    /// Whether to use `repodata.json.zst` (on by default)
+    pub fn use_zstd(&self) -> bool {
+        self.use_zstd.unwrap_or(true)
+    }
+
+    /// Whether to use `repodata.json.bz2` (on by default)
+    pub fn use_bz2(&self) -> bool {
+        self.use_bz2.unwrap_or(true)
+    }
+
+    /// Whether to apply patches with the `patch` executable
+    pub fn use_patch_executable(&self) -> bool {
+        self.use_patch_executable.unwrap_or_default()
+    }
+
+    /// The maximum number of concurrent source downloads
+    pub fn source_fetch_concurrency(&self) -> usize {
+        self.source_fetch_concurrency
+            .unwrap_or(DEFAULT_SOURCE_FETCH_CONCURRENCY)
+    }
+
+    /// The number of retries of a failed source download
+    pub fn download_retries(&self) -> usize {
+        self.download_retries.unwrap_or(DEFAULT_DOWNLOAD_RETRIES)
+    }
+
+    /// The limits of a single source (see [`SourceLimits`]), which the `limits` of a source in
+    /// the recipe override
+    pub fn source_limits(&self) -> SourceLimits {
+        let default = SourceLimits::default();
+        SourceLimits {
+            max_size: self.max_source_size.unwrap_or(default.max_size),
+            max_files: self.max_source_files.unwrap_or(default.max_files),
+        }
+    }
+
+    /// The local packages that replace channel packages
+    pub fn local_packages(&self) -> &[PathBuf] {
+        self.use_local_package.as_deref().unwrap_or_default()
+    }
+
+    /// The maximum run time of the build script (none by default)
+    pub fn build_timeout(&self) -> Option<Duration> {
+        self.build_timeout.map(Duration::from_secs)
+    }
+
+    /// The cache of the downloaded sources and git repositories (`rattler-build/src_cache` in the
+    /// cache directory of the platform by default)
+    pub fn source_cache_dir(&self) -> PathBuf {
+        self.source_cache_dir
+            .clone()
+            .unwrap_or_else(default_source_cache_dir)
+    }
+
+    /// Where to look for existing packages (nowhere by default)
+    pub fn skip_existing(&self) -> SkipExisting {
+        self.skip_existing.unwrap_or_default()
+    }
+
+    /// Whether to write lock files next to the packages
+    pub fn write_lock_file(&self) -> bool {
+        self.write_lock_file.unwrap_or_default()
+    }
+
+    /// How the progress is reported (progress bars on a terminal by default)
+    pub fn progress(&self) -> ProgressOutput {
+        self.progress.unwrap_or_default()
+    }
+}
+
+/// The configuration file with the profiles
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The profiles by name
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+impl ConfigFile {
+    /// The default location of the configuration file: `rattler-build/config.yaml` in
+    /// `$XDG_CONFIG_HOME` or `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("rattler-build").join("config.yaml"))
+    }
+
+    /// Read the configuration file at `path`
+    pub fn from_path(path: &Path) -> Result<Self, ProfileError> {
+        let content =
+            fs_err::read_to_string(path).map_err(|e| ProfileError::Io(path.to_path_buf(), e))?;
+        let config: ConfigFile = serde_yaml::from_str(&content)
+            .map_err(|e| ProfileError::Parse(path.to_path_buf(), e))?;
+        for (name, settings) in &config.profiles {
+            settings.check_conflicts().map_err(|e| match e {
+                ProfileError::Conflict(message) => {
+                    ProfileError::Conflict(format!("{message} (in the profile `{name}`)"))
+                }
+                e => e,
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Load the settings of the profile `name` from the configuration file at `path`, or at the
+    /// default location if no path is given
+    pub fn load_profile(path: Option<&Path>, name: &str) -> Result<Settings, ProfileError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => Self::default_path()
+                .filter(|path| path.is_file())
+                .ok_or_else(|| {
+                    ProfileError::NoConfigFile(
+                        name.to_string(),
+                        Self::default_path()
+                            .map(|path| path.display().to_string())
+                            .unwrap_or_else(|| "~/.config/rattler-build/config.yaml".to_string()),
+                    )
+                })?,
+        };
+        let mut config = Self::from_path(&path)?;
+        config
+            .profiles
+            .remove(name)
+            .ok_or_else(|| ProfileError::UnknownProfile {
+                name: name.to_string(),
+                available: config
+                    .profiles
+                    .keys()
+                    .map(|k| format!("`{k}`"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                path,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rattler_conda_types::package::ArchiveType;
+
+    use super::{ConfigFile, PackageFormat, ProfileError, Settings};
+
+    const CONFIG: &str = r#"
+profiles:
+  release:
+    channels: [conda-forge, bioconda]
+    package_format: conda
+    download_retries: 5
+    build_timeout: 7200
+  debug:
+    keep_build: true
+    no_test: true
+    offline: true
+"#;
+
+    #[test]
+    fn round_trip() {
+        let config: ConfigFile = serde_yaml::from_str(CONFIG).unwrap();
+        assert_eq!(config.profiles.len(), 2);
+        let release = &config.profiles["release"];
+        assert_eq!(release.package_format, Some(PackageFormat::Conda));
+        assert_eq!(release.keep_build, None);
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert_eq!(serde_yaml::from_str::<ConfigFile>(&yaml).unwrap(), config);
+
+        let effective = release.effective();
+        let yaml = serde_yaml::to_string(&effective).unwrap();
+        assert_eq!(serde_yaml::from_str::<Settings>(&yaml).unwrap(), effective);
+
+        // typos are not silently ignored
+        assert!(
+            serde_yaml::from_str::<ConfigFile>("profiles:\n  x:\n    keep_bulid: true").is_err()
+        );
+    }
+
+    #[test]
+    fn merge_precedence() {
+        let config: ConfigFile = serde_yaml::from_str(CONFIG).unwrap();
+        let profile = config.profiles["release"].clone();
+        let env = HashMap::from([
+            ("RATTLER_BUILD_DOWNLOAD_RETRIES", "8"),
+            ("RATTLER_BUILD_MAX_SOURCE_FILES", "1000"),
+            ("RATTLER_OFFLINE", "true"),
+            ("RATTLER_ZSTD", "false"),
+        ]);
+        let env = Settings::from_env_vars(|key| env.get(key).map(|v| v.to_string())).unwrap();
+        let cli = Settings {
+            channels: Some(vec!["robostack".to_string()]),
+            offline: Some(false),
+            keep_build: Some(true),
+            ..Default::default()
+        };
+
+        let settings = profile.merge(env).merge(cli);
+        // the command line wins over the environment and the profile
+        assert_eq!(settings.channels(), ["robostack"]);
+        assert!(!settings.offline());
+        assert!(settings.keep_build());
+        // the environment wins over the profile
+        assert_eq!(settings.download_retries(), 8);
+        assert!(!settings.use_zstd());
+        assert_eq!(settings.source_limits().max_files, 1000);
+        // values that only the profile sets are kept
+        assert_eq!(settings.package_format(), PackageFormat::Conda);
+        assert_eq!(
+            settings.build_timeout(),
+            Some(std::time::Duration::from_secs(7200))
+        );
+        // and everything else has the default value
+        assert!(settings.use_bz2());
+        assert_eq!(settings.source_fetch_concurrency(), 4);
+        assert_eq!(
+            settings.source_limits().max_size,
+            crate::source::limits::DEFAULT_MAX_SIZE
+        );
+        assert!(!settings.no_test());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let err = Settings::from_env_vars(|key| {
+            (key == "RATTLER_BUILD_SOURCE_FETCH_CONCURRENCY").then(|| "many".to_string())
+        })
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ProfileError::InvalidEnv {
+                name: "RATTLER_BUILD_SOURCE_FETCH_CONCURRENCY",
+                ..
+            }
+        ));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.yaml");
+        fs_err::write(
+            &path,
+            "profiles:\n  docker:\n    container_image: linux-anvil\n    use_local_package: [foo.conda]\n",
+        )
+        .unwrap();
+        let err = ConfigFile::load_profile(Some(&path), "docker").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Conflicting settings: `use_local_package` cannot be combined with `container_image` (in the profile `docker`)"
+        );
+
+        fs_err::write(&path, CONFIG).unwrap();
+        assert!(ConfigFile::load_profile(Some(&path), "release").is_ok());
+        let err = ConfigFile::load_profile(Some(&path), "nightly").unwrap_err();
+        match err {
+            ProfileError::UnknownProfile { available, .. } => {
+                assert_eq!(available, "`debug`, `release`")
+            }
+            err => panic!("unexpected error: {err}"),
+        }
+
+        // reusing environments is only possible with a kept build directory
+        let reuse = Settings {
+            reuse_environments: Some(true),
+            keep_build: Some(true),
+            ..Default::default()
+        };
+        assert!(reuse.validate().is_err());
+        let reuse = Settings {
+            no_build_id: Some(true),
+            ..reuse
+        };
+        assert!(reuse.validate().is_ok());
+
+        let repair = Settings {
+            repair_prefixes: Some(true),
+            ..Default::default()
+        };
+        assert!(repair.validate().is_err());
+        let repair = Settings {
+            verify_prefixes: Some(PrefixVerification::Sample),
+            ..repair
+        };
+        assert!(repair.validate().is_ok());
+
+        let timeout = Settings {
+            build_timeout: Some(0),
+            ..Default::default()
+        };
+        assert!(timeout.validate().is_err());
+
+        let compression = Settings {
+            conda_compression_level: Some(23),
+            ..Default::default()
+        };
+        assert!(compression.validate().is_err());
+        let compression = Settings {
+            conda_compression_level: Some(-7),
+            compression_threads: Some(0),
+            ..Default::default()
+        };
+        assert!(compression.validate().is_err());
+    }
+
+    #[test]
+    fn package_formats_and_compression() {
+        let settings: Settings = serde_yaml::from_str(
+            "package_format: both\nconda_compression_level: 3\ncompression_threads: 4\n",
+        )
+        .unwrap();
+        let format = settings.package_format();
+        assert_eq!(ArchiveType::from(format), ArchiveType::TarBz2);
+        assert_eq!(format.additional_archive_types(), [ArchiveType::Conda]);
+
+        let compression = settings.compression();
+        assert_eq!(compression.conda_level, 3);
+        assert_eq!(compression.threads, 4);
+        // the bzip2 level is the one of today
+        assert_eq!(compression.tar_bz2_level, 9);
+        assert!(Settings::default().compression().is_default());
+    }
+}
@@ -0,0 +1,298 @@
+//! Progress of downloads, extractions, copies and installations: progress bars on a terminal,
+//! and plain log lines when the progress bars are not drawn (e.g. in CI).
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+
+use crate::tool_configuration;
+
+/// How the progress is reported
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressOutput {
+    /// Progress bars on a terminal, log lines otherwise
+    #[default]
+    Auto,
+    /// Log lines
+    Log,
+    /// Neither progress bars nor log lines
+    Off,
+}
+
+/// What a progress counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressUnit {
+    /// Downloaded bytes
+    Bytes,
+    /// Extracted or copied files
+    Files,
+    /// Installed packages
+    Packages,
+}
+
+impl ProgressUnit {
+    fn format(self, count: u64) -> String {
+        match self {
+            ProgressUnit::Bytes => HumanBytes(count).to_string(),
+            ProgressUnit::Files => format!("{count} files"),
+            ProgressUnit::Packages => format!("{count} packages"),
+        }
+    }
+
+    fn format_of(self, count: u64, total: u64) -> String {
+        match self {
+            ProgressUnit::Bytes => format!("{} of {}", HumanBytes(count), HumanBytes(total)),
+            _ => format!("{count} of {}", self.format(total)),
+        }
+    }
+}
+
+/// How often the progress of something without a known total is logged
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The progress of something is logged at every quarter
+const LOG_STEP_PERCENT: u64 = 25;
+
+/// The state of the log lines of a progress
+struct LogState {
+    next_percent: u64,
+    last_log: Instant,
+}
+
+/// The progress of a single download, extraction, copy or installation. It is drawn as a
+/// progress bar (or a spinner, without a known total) of the multi-progress of the build, which
+/// the log output suspends. When the multi-progress is not drawn, a few log lines are written
+/// instead.
+pub struct Progress {
+    bar: ProgressBar,
+    label: String,
+    total: Option<u64>,
+    unit: ProgressUnit,
+    start: Instant,
+    log: Option<Mutex<LogState>>,
+}
+
+impl Progress {
+    /// The progress of `label`, with the multi-progress and the progress output of the
+    /// `tool_configuration`
+    pub fn new(
+        tool_configuration: &tool_configuration::Configuration,
+        label: impl Into<String>,
+        total: Option<u64>,
+        unit: ProgressUnit,
+    ) -> Self {
+        Self::with_output(
+            &tool_configuration.multi_progress_indicator,
+            tool_configuration.progress,
+            label,
+            total,
+            unit,
+        )
+    }
+
+    /// The progress of `label` in `multi_progress`, reported as `output` says
+    pub fn with_output(
+        multi_progress: &MultiProgress,
+        output: ProgressOutput,
+        label: impl Into<String>,
+        total: Option<u64>,
+        unit: ProgressUnit,
+    ) -> Self {
+        let label = label.into();
+        let draw_bars = output == ProgressOutput::Auto && !multi_progress.is_hidden();
+        let bar = if draw_bars {
+            let bar = match total {
+                Some(total) => ProgressBar::new(total).with_style(bar_style(unit)),
+                None => ProgressBar::new_spinner().with_style(spinner_style(unit)),
+            };
+            let bar = multi_progress.add(
+                bar.with_prefix(label.clone())
+                    .with_finish(indicatif::ProgressFinish::AndClear),
+            );
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar
+        } else {
+            ProgressBar::hidden()
+        };
+        if let Some(total) = total {
+            bar.set_length(total);
+        }
+
+        let log = (!draw_bars && output != ProgressOutput::Off).then(|| {
+            match total {
+                Some(total) => tracing::info!("{}: {}", label, unit.format(total)),
+                None => tracing::info!("{}: started", label),
+            }
+            Mutex::new(LogState {
+                next_percent: LOG_STEP_PERCENT,
+                last_log: Instant::now(),
+            })
+        });
+
+        Self {
+            bar,
+            label,
+            total,
+            unit,
+            start: Instant::now(),
+            log,
+        }
+    }
+
+    /// A progress that is not reported at all
+    pub fn hidden() -> Self {
+        Self::with_output(
+            &MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden()),
+            ProgressOutput::Off,
+            "",
+            None,
+            ProgressUnit::Files,
+        )
+    }
+
+    /// Use `style` for the progress bar instead of the default style of the unit
+    pub fn with_bar_style(self, style: ProgressStyle) -> Self {
+        if !self.bar.is_hidden() && self.total.is_some() {
+            self.bar.set_style(style);
+        }
+        self
+    }
+
+    /// The progress bar (hidden when log lines are written instead)
+    pub fn bar(&self) -> &ProgressBar {
+        &self.bar
+    }
+
+    /// Start at `position` (e.g. the bytes of a resumed download)
+    pub fn set_position(&self, position: u64) {
+        self.bar.set_position(position);
+    }
+
+    /// Show `message` next to the progress bar
+    pub fn set_message(&self, message: impl Into<std::borrow::Cow<'static, str>>) {
+        self.bar.set_message(message);
+    }
+
+    /// Advance the progress by `delta` units
+    pub fn inc(&self, delta: u64) {
+        self.bar.inc(delta);
+        let Some(log) = &self.log else {
+            return;
+        };
+        let mut log = log.lock().expect("the progress log lock is not poisoned");
+        let position = self.bar.position();
+        match self.total {
+            Some(total) if total > 0 => {
+                let percent = position.saturating_mul(100) / total;
+                if percent >= log.next_percent && percent < 100 {
+                    tracing::info!(
+                        "{}: {}% ({})",
+                        self.label,
+                        percent,
+                        self.unit.format_of(position, total)
+                    );
+                    log.next_percent = (percent / LOG_STEP_PERCENT + 1) * LOG_STEP_PERCENT;
+                }
+            }
+            _ => {
+                if log.last_log.elapsed() >= LOG_INTERVAL {
+                    tracing::info!("{}: {} so far", self.label, self.unit.format(position));
+                    log.last_log = Instant::now();
+                }
+            }
+        }
+    }
+
+    /// Finish the progress (and clear its progress bar)
+    pub fn finish(&self) {
+        let position = self.bar.position();
+        self.bar.finish_using_style();
+        if self.log.is_some() {
+            tracing::info!(
+                "{}: done, {} in {}",
+                self.label,
+                self.unit.format(position),
+                HumanDuration(self.start.elapsed())
+            );
+        }
+    }
+}
+
+/// The default style of a progress bar with a known total
+fn bar_style(unit: ProgressUnit) -> ProgressStyle {
+    let template = match unit {
+        ProgressUnit::Bytes => "{spinner:.green} {prefix:20!} [{elapsed_precise}] [{bar:40!.bright.yellow/dim.white}] {bytes:>10}/{total_bytes:10} {msg}",
+        ProgressUnit::Files | ProgressUnit::Packages => "{spinner:.green} {prefix:20!} [{elapsed_precise}] [{bar:40!.bright.yellow/dim.white}] {pos:>7}/{len:7} {msg}",
+    };
+    ProgressStyle::default_bar()
+        .template(template)
+        .expect("the progress bar template is valid")
+        .progress_chars("━━╾─")
+}
+
+/// The style of a spinner, for a progress without a known total
+fn spinner_style(unit: ProgressUnit) -> ProgressStyle {
+    let template = match unit {
+        ProgressUnit::Bytes => {
+            "{spinner:.green} {prefix:20!} [{elapsed_precise}] {bytes:>10} {msg}"
+        }
+        ProgressUnit::Files => {
+            "{spinner:.green} {prefix:20!} [{elapsed_precise}] {pos:>7} files {msg}"
+        }
+        ProgressUnit::Packages => {
+            "{spinner:.green} {prefix:20!} [{elapsed_precise}] {pos:>7} packages {msg}"
+        }
+    };
+    ProgressStyle::default_spinner()
+        .template(template)
+        .expect("the spinner template is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use indicatif::{MultiProgress, ProgressDrawTarget};
+
+    use super::{Progress, ProgressOutput, ProgressUnit};
+
+    #[test]
+    fn log_lines_without_a_terminal() {
+        let hidden = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let progress = Progress::with_output(
+            &hidden,
+            ProgressOutput::Auto,
+            "source.tar.gz",
+            Some(100),
+            ProgressUnit::Bytes,
+        );
+        assert!(progress.bar().is_hidden());
+        assert!(progress.log.is_some());
+
+        progress.inc(30);
+        progress.inc(30);
+        let next = progress.log.as_ref().unwrap().lock().unwrap().next_percent;
+        assert_eq!(next, 75);
+        progress.inc(40);
+        progress.finish();
+        assert_eq!(progress.bar().position(), 100);
+    }
+
+    #[test]
+    fn no_log_lines_when_off() {
+        let hidden = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let progress = Progress::with_output(
+            &hidden,
+            ProgressOutput::Off,
+            "work",
+            None,
+            ProgressUnit::Files,
+        );
+        assert!(progress.log.is_none());
+        progress.inc(3);
+        assert_eq!(progress.bar().position(), 3);
+    }
+}
@@ -596,6 +596,30 @@ impl Render<RenderedNode> for ScalarNode {
             return Ok(RenderedNode::Scalar(rendered));
         }
 
+        // A single expression that evaluates to a list (e.g. a list of `load_from_file`) becomes a
+        // sequence
+        if let Some(expression) = single_expression(self.as_str()) {
+            if let Ok(value) = jinja.eval(expression) {
+                if value.kind() == minijinja::value::ValueKind::Seq {
+                    let items = value
+                        .try_iter()
+                        .into_iter()
+                        .flatten()
+                        .map(|item| {
+                            RenderedNode::Scalar(RenderedScalarNode::new(
+                                *self.span(),
+                                item.to_string(),
+                            ))
+                        })
+                        .collect::<Vec<_>>();
+                    return Ok(RenderedNode::Sequence(RenderedSequenceNode::new(
+                        *self.span(),
+                        items,
+                    )));
+                }
+            }
+        }
+
         let rendered = jinja.render_str(self.as_str()).map_err(|err| {
             _partialerror!(
                 *self.span(),
@@ -613,6 +637,15 @@ impl Render<RenderedNode> for ScalarNode {
     }
 }
 
+/// The expression of a scalar that consists of a single `${{ ... }}`
+fn single_expression(scalar: &str) -> Option<&str> {
+    let expression = scalar.trim().strip_prefix("${{")?.strip_suffix("}}")?;
+    if expression.contains("${{") || expression.contains("}}") {
+        return None;
+    }
+    Some(expression)
+}
+
 impl Render<Option<RenderedNode>> for ScalarNode {
     fn render(
         &self,
@@ -623,7 +656,7 @@ impl Render<Option<RenderedNode>> for ScalarNode {
             _partialerror!(
                 *self.span(),
                 ErrorKind::JinjaRendering(err),
-                label = format!("Rendering error: {}", err.kind())
+                label = jinja_error_to_label(&err)
             )
         })?;
 
@@ -707,7 +740,16 @@ impl Render<RenderedSequenceNode> for SequenceNodeInternal {
     ) -> Result<RenderedSequenceNode, crate::recipe::error::PartialParsingError> {
         let mut rendered = Vec::new();
         match self {
-            SequenceNodeInternal::Simple(node) => rendered.push(node.render(jinja, name)?),
+            SequenceNodeInternal::Simple(node) => {
+                let rend: RenderedNode = node.render(jinja, name)?;
+
+                // a scalar item that renders to a list is merged into the sequence
+                if let (Node::Scalar(_), Some(rend)) = (node, rend.as_sequence()) {
+                    rendered.extend(rend.iter().cloned());
+                } else {
+                    rendered.push(rend);
+                }
+            }
             SequenceNodeInternal::Conditional(if_sel) => {
                 let if_res = if_sel.process(jinja)?;
                 if let Some(if_res) = if_res {
@@ -1,6 +1,6 @@
 //! Error types for the recipe parser.
 
-use std::{borrow::Cow, convert::Infallible, fmt, str::ParseBoolError};
+use std::{borrow::Cow, convert::Infallible, fmt, ops::Range, str::ParseBoolError};
 
 use miette::{Diagnostic, SourceOffset, SourceSpan};
 use thiserror::Error;
@@ -33,9 +33,14 @@ pub struct ParsingError {
 impl ParsingError {
     /// Turn a [`PartialParsingError`] into a [`ParsingError`] by adding the source string.
     pub fn from_partial(src: &str, err: PartialParsingError) -> Self {
+        let mut span = marker_span_to_span(src, err.span);
+        if let Some((value, offending)) = err.kind.offending_range() {
+            span = narrow_span(src, span, value, offending);
+        }
+
         Self {
             src: src.to_owned(),
-            span: marker_span_to_span(src, err.span),
+            span,
             label: err.label,
             help: err.help,
             kind: err.kind,
@@ -84,6 +89,10 @@ pub enum ErrorKind {
     #[diagnostic(code(error::invalid_sha256))]
     InvalidSha256,
 
+    /// Error when invalid BLAKE2b-256 hash.
+    #[diagnostic(code(error::invalid_blake2))]
+    InvalidBlake2,
+
     /// Error when there is a required missing field in a mapping.
     #[diagnostic(code(error::missing_field))]
     MissingField(Cow<'static, str>),
@@ -124,6 +133,36 @@ pub enum ErrorKind {
     #[diagnostic(code(error::entry_point_parsing))]
     EntryPointParsing(String),
 
+    /// Error when parsing a [`Version`](rattler_conda_types::Version).
+    #[diagnostic(code(error::version_parsing))]
+    VersionParsing(#[from] rattler_conda_types::ParseVersionError),
+
+    /// Error when a package name does not follow the conda naming rules.
+    #[diagnostic(code(error::invalid_package_name))]
+    InvalidPackageName {
+        /// The invalid name
+        name: String,
+        /// The rule that is violated
+        reason: &'static str,
+        /// The byte range of the offending characters in `name`
+        offending: Range<usize>,
+    },
+
+    /// Error when a version contains characters that conda does not allow.
+    #[diagnostic(code(error::invalid_version))]
+    InvalidVersion {
+        /// The invalid version
+        version: String,
+        /// The rule that is violated
+        reason: &'static str,
+        /// The byte range of the offending characters in `version`
+        offending: Range<usize>,
+    },
+
+    /// Error when the recipe requires a newer version of rattler-build than the running one.
+    #[diagnostic(code(error::min_tool_version))]
+    MinToolVersion(rattler_conda_types::Version),
+
     /// Generic unspecified error. If this is returned, the call site should
     /// be annotated with context, if possible.
     #[diagnostic(code(error::other))]
@@ -193,6 +232,7 @@ impl fmt::Display for ErrorKind {
             }
             ErrorKind::InvalidMd5 => write!(f, "invalid MD5 checksum."),
             ErrorKind::InvalidSha256 => write!(f, "invalid SHA256 checksum."),
+            ErrorKind::InvalidBlake2 => write!(f, "invalid BLAKE2b-256 checksum."),
             ErrorKind::InvalidField(s) => write!(f, "invalid field `{s}`."),
             ErrorKind::MissingField(s) => write!(f, "missing field `{s}`"),
             ErrorKind::JinjaRendering(err) => {
@@ -215,11 +255,40 @@ impl fmt::Display for ErrorKind {
             ErrorKind::EntryPointParsing(err) => {
                 write!(f, "failed to parse entry point: {}", err)
             }
+            ErrorKind::VersionParsing(err) => write!(f, "failed to parse version: {}", err),
+            ErrorKind::InvalidPackageName { name, reason, .. } => {
+                write!(f, "invalid package name `{}`: {}.", name, reason)
+            }
+            ErrorKind::InvalidVersion {
+                version, reason, ..
+            } => write!(f, "invalid version `{}`: {}.", version, reason),
+            ErrorKind::MinToolVersion(required) => write!(
+                f,
+                "this recipe requires rattler-build {} or newer, but this is rattler-build {}.",
+                required,
+                env!("CARGO_PKG_VERSION")
+            ),
             ErrorKind::Other => write!(f, "an unspecified error occurred."),
         }
     }
 }
 
+impl ErrorKind {
+    /// The value and the byte range of the characters in it that caused the error, if the error
+    /// is about specific characters.
+    fn offending_range(&self) -> Option<(&str, &Range<usize>)> {
+        match self {
+            ErrorKind::InvalidPackageName {
+                name, offending, ..
+            } => Some((name, offending)),
+            ErrorKind::InvalidVersion {
+                version, offending, ..
+            } => Some((version, offending)),
+            _ => None,
+        }
+    }
+}
+
 impl From<Infallible> for ErrorKind {
     fn from(_: Infallible) -> Self {
         Self::Other
@@ -343,6 +412,24 @@ pub(super) fn marker_to_span(src: &str, mark: marked_yaml::Marker) -> SourceSpan
     )
 }
 
+/// Narrow `span` down to the `offending` characters of `value`. This only works if the value is
+/// written literally (possibly quoted) at the start of the span, otherwise (e.g. for values that
+/// are rendered from a Jinja expression) the span is returned unchanged.
+fn narrow_span(src: &str, span: SourceSpan, value: &str, offending: &Range<usize>) -> SourceSpan {
+    let Some(text) = src.get(span.offset()..) else {
+        return span;
+    };
+    let quote = usize::from(text.starts_with(['"', '\'']));
+    if offending.is_empty() || !text[quote..].starts_with(value) {
+        return span;
+    }
+
+    SourceSpan::new(
+        (span.offset() + quote + offending.start).into(),
+        offending.len().into(),
+    )
+}
+
 /// Get the [`marked_yaml::Marker`] from a [`marked_yaml::LoadError`].
 pub(super) fn marker(err: &marked_yaml::LoadError) -> marked_yaml::Marker {
     use marked_yaml::LoadError::*;
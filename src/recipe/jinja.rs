@@ -1,27 +1,40 @@
 //! Module for types and functions related to miniJinja setup for recipes.
 
-use std::{collections::BTreeMap, str::FromStr};
-
-use minijinja::value::Object;
-use minijinja::{Environment, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use itertools::Itertools;
+use minijinja::value::{Object, StructObject};
+use minijinja::{Environment, UndefinedBehavior, Value};
 use rattler_conda_types::{PackageName, Version};
 
 pub use crate::render::pin::{Pin, PinExpression};
 pub use crate::selectors::SelectorConfig;
+use crate::selectors::SourcesRequested;
 
 /// A type that hold the miniJinja environment and context for Jinja template processing.
 #[derive(Debug, Clone)]
 pub struct Jinja<'a> {
     env: Environment<'a>,
     context: BTreeMap<String, Value>,
+    missing_keys: MissingKeys,
 }
 
 impl<'a> Jinja<'a> {
     /// Create a new Jinja instance with the given selector configuration.
     pub fn new(config: SelectorConfig) -> Self {
-        let env = set_jinja(&config);
+        let missing_keys = MissingKeys::default();
+        let env = set_jinja(&config, &missing_keys);
         let context = config.into_context();
-        Self { env, context }
+        Self {
+            env,
+            context,
+            missing_keys,
+        }
     }
 
     /// Get a reference to the miniJinja environment.
@@ -48,9 +61,19 @@ impl<'a> Jinja<'a> {
         &mut self.context
     }
 
+    /// Turn the use of undefined variables into rendering errors instead of silently
+    /// rendering them as empty strings.
+    pub fn with_strict_undefined(mut self) -> Self {
+        self.env.set_undefined_behavior(UndefinedBehavior::Strict);
+        self
+    }
+
     /// Render a template with the current context.
     pub fn render_str(&self, template: &str) -> Result<String, minijinja::Error> {
-        self.env.render_str(template, &self.context)
+        self.missing_keys.lock().unwrap().clear();
+        self.env
+            .render_str(template, &self.context)
+            .map_err(|err| self.explain_undefined(template, err))
     }
 
     /// Render, compile and evaluate a expr string with the current context.
@@ -59,16 +82,89 @@ impl<'a> Jinja<'a> {
         if expr.is_empty() {
             return Ok(Value::UNDEFINED);
         }
-        let expr = self.env.compile_expression(&expr)?;
-        expr.eval(self.context())
+        let compiled = self.env.compile_expression(&expr)?;
+        compiled
+            .eval(self.context())
+            .map_err(|err| self.explain_undefined(&format!("${{{{ {} }}}}", expr), err))
     }
+
+    /// Replace a bare "undefined value" error with one that names the undefined variable(s)
+    /// of the template and suggests close matches from the context.
+    fn explain_undefined(&self, template: &str, err: minijinja::Error) -> minijinja::Error {
+        if err.kind() != minijinja::ErrorKind::UndefinedError {
+            return err;
+        }
+
+        let missing_keys = std::mem::take(&mut *self.missing_keys.lock().unwrap());
+        if !missing_keys.is_empty() {
+            let detail = missing_keys.into_iter().unique().join(", ");
+            return minijinja::Error::new(minijinja::ErrorKind::UndefinedError, detail)
+                .with_source(err);
+        }
+
+        let mut undefined = crate::used_variables::variables_in_template(template)
+            .into_iter()
+            .filter(|var| !self.context.contains_key(var) && template.contains(var.as_str()))
+            .collect::<Vec<_>>();
+        if undefined.is_empty() {
+            return err;
+        }
+        undefined.sort();
+
+        let detail = undefined
+            .iter()
+            .map(|var| {
+                let matches = close_matches(var, self.context.keys());
+                if matches.is_empty() {
+                    format!("`{}` is undefined", var)
+                } else {
+                    format!(
+                        "`{}` is undefined (did you mean {}?)",
+                        var,
+                        matches.iter().map(|m| format!("`{}`", m)).join(", ")
+                    )
+                }
+            })
+            .join(", ");
+
+        minijinja::Error::new(minijinja::ErrorKind::UndefinedError, detail).with_source(err)
+    }
+}
+
+/// Returns the candidates that are within a small edit distance of `name`, closest first.
+fn close_matches<'c>(name: &str, candidates: impl Iterator<Item = &'c String>) -> Vec<&'c str> {
+    let max_distance = (name.len() / 3).max(1);
+    let mut matches = candidates
+        .map(|candidate| (levenshtein(name, candidate), candidate.as_str()))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+    matches.sort();
+    matches.into_iter().take(3).map(|(_, m)| m).collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            current[j + 1] = (previous[j] + cost)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
 }
 
 impl Default for Jinja<'_> {
     fn default() -> Self {
+        let missing_keys = MissingKeys::default();
         Self {
-            env: set_jinja(&SelectorConfig::default()),
+            env: set_jinja(&SelectorConfig::default(), &missing_keys),
             context: BTreeMap::new(),
+            missing_keys,
         }
     }
 }
@@ -132,7 +228,10 @@ fn jinja_pin_function(
     ))
 }
 
-fn set_jinja(config: &SelectorConfig) -> minijinja::Environment<'static> {
+fn set_jinja(
+    config: &SelectorConfig,
+    missing_keys: &MissingKeys,
+) -> minijinja::Environment<'static> {
     use rattler_conda_types::version_spec::VersionSpec;
     let mut env = minijinja::Environment::new();
 
@@ -230,6 +329,48 @@ fn set_jinja(config: &SelectorConfig) -> minijinja::Environment<'static> {
         jinja_pin_function(name, kwargs, "__PIN_COMPATIBLE")
     });
 
+    let source_dir = config.source_dir.clone();
+    let sources_requested = config.sources_requested.clone();
+    env.add_function("load_file_from_source", move |path: String| {
+        let Some(source_dir) = &source_dir else {
+            sources_requested.set();
+            return Err(minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                "`load_file_from_source` can only be used if the sources are fetched before the dependencies are resolved (`build.source_fetch: before_solve`)",
+            ));
+        };
+        let relative = std::path::Path::new(&path);
+        if relative.is_absolute()
+            || relative
+                .components()
+                .any(|c| c == std::path::Component::ParentDir)
+        {
+            return Err(minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("`load_file_from_source` only reads files inside the source, got `{path}`"),
+            ));
+        }
+        fs_err::read_to_string(source_dir.join(relative)).map_err(|e| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("Failed to load `{path}` from the source: {e}"),
+            )
+        })
+    });
+
+    let dirs = [config.source_dir.clone(), config.recipe_dir.clone()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let missing_keys = missing_keys.clone();
+    let sources_requested = config
+        .source_dir
+        .is_none()
+        .then(|| config.sources_requested.clone());
+    env.add_function("load_from_file", move |path: String| {
+        load_from_file(&path, &dirs, &missing_keys, sources_requested.as_ref())
+    });
+
     env.add_filter("version_to_buildstring", |s: String| {
         // we first split the string by whitespace and take the first part
         let s = s.split_whitespace().next().unwrap_or(&s);
@@ -243,6 +384,131 @@ fn set_jinja(config: &SelectorConfig) -> minijinja::Environment<'static> {
     env
 }
 
+/// The keys that were looked up in a file of `load_from_file` but are not defined in it. An
+/// undefined value error of the rendering is explained with them.
+type MissingKeys = Arc<Mutex<Vec<String>>>;
+
+/// Read the TOML, JSON or YAML file at `path`, from the first of the `dirs` that contains it. If
+/// none does, the file may be in the sources, which are requested if they are not fetched yet.
+fn load_from_file(
+    path: &str,
+    dirs: &[PathBuf],
+    missing_keys: &MissingKeys,
+    sources_requested: Option<&SourcesRequested>,
+) -> Result<Value, minijinja::Error> {
+    let error =
+        |detail: String| minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, detail);
+
+    let relative = Path::new(path);
+    if relative.is_absolute()
+        || relative
+            .components()
+            .any(|c| c == std::path::Component::ParentDir)
+    {
+        return Err(error(format!(
+            "`load_from_file` only reads files inside the recipe directory or the sources, got `{path}`"
+        )));
+    }
+    let Some(file) = dirs
+        .iter()
+        .map(|dir| dir.join(relative))
+        .find(|file| file.is_file())
+    else {
+        if let Some(sources_requested) = sources_requested {
+            sources_requested.set();
+        }
+        return Err(error(format!(
+            "`{path}` is neither in the recipe directory nor in the fetched sources"
+        )));
+    };
+    let content = fs_err::read_to_string(&file)
+        .map_err(|e| error(format!("Failed to load `{path}`: {e}")))?;
+
+    let extension = relative
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    let value: serde_json::Value = match extension.as_deref() {
+        Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+        Some("json") => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        _ => {
+            return Err(error(format!(
+                "`load_from_file` reads TOML, JSON and YAML files, use `load_file_from_source` to read `{path}` as text"
+            )))
+        }
+    }
+    .map_err(|e| error(format!("Failed to parse `{path}`: {e}")))?;
+
+    Ok(file_value(value, &Arc::from(path), "", missing_keys))
+}
+
+/// Convert the (part at `key` of the) `value` of a loaded `file` into a Jinja value
+fn file_value(
+    value: serde_json::Value,
+    file: &Arc<str>,
+    key: &str,
+    missing_keys: &MissingKeys,
+) -> Value {
+    match value {
+        serde_json::Value::Object(map) => Value::from_struct_object(FileMapping {
+            file: file.clone(),
+            key: key.to_string(),
+            map,
+            missing_keys: missing_keys.clone(),
+        }),
+        serde_json::Value::Array(items) => Value::from(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    file_value(item, file, &format!("{key}[{index}]"), missing_keys)
+                })
+                .collect::<Vec<_>>(),
+        ),
+        value => Value::from_serializable(&value),
+    }
+}
+
+/// A mapping of a file of `load_from_file` that records the lookups of missing keys
+struct FileMapping {
+    file: Arc<str>,
+    key: String,
+    map: serde_json::Map<String, serde_json::Value>,
+    missing_keys: MissingKeys,
+}
+
+impl StructObject for FileMapping {
+    fn get_field(&self, name: &str) -> Option<Value> {
+        let key = if self.key.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.key, name)
+        };
+        let Some(value) = self.map.get(name) else {
+            let matches = close_matches(name, self.map.keys());
+            let mut detail = format!("`{}` is not defined in `{}`", key, self.file);
+            if !matches.is_empty() {
+                detail += &format!(
+                    " (did you mean {}?)",
+                    matches.iter().map(|m| format!("`{}`", m)).join(", ")
+                );
+            }
+            self.missing_keys.lock().unwrap().push(detail);
+            return None;
+        };
+        Some(file_value(
+            value.clone(),
+            &self.file,
+            &key,
+            &self.missing_keys,
+        ))
+    }
+
+    fn fields(&self) -> Vec<Arc<str>> {
+        self.map.keys().map(|key| Arc::from(key.as_str())).collect()
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Env;
 impl std::fmt::Display for Env {
@@ -370,6 +636,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant: BTreeMap::new(),
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
 
         let jinja = Jinja::new(options);
@@ -395,6 +664,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant: BTreeMap::new(),
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
 
         let jinja = Jinja::new(options);
@@ -410,6 +682,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant,
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(options);
 
@@ -439,6 +714,9 @@ mod tests {
             build_platform: Platform::Linux32,
             variant,
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(options);
 
@@ -468,6 +746,9 @@ mod tests {
             build_platform: Platform::LinuxAarch64,
             variant,
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(options);
 
@@ -497,6 +778,9 @@ mod tests {
             build_platform: Platform::LinuxArmV6l,
             variant,
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(options);
 
@@ -528,6 +812,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant,
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(options);
 
@@ -550,6 +837,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant,
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(options);
 
@@ -581,6 +871,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant: Default::default(),
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(options);
 
@@ -617,4 +910,36 @@ mod tests {
                 .is_true());
         });
     }
+
+    #[test]
+    fn strict_undefined() {
+        let options = SelectorConfig {
+            target_platform: Platform::Linux64,
+            build_platform: Platform::Linux64,
+            variant: BTreeMap::from([("python".to_string(), "3.11".to_string())]),
+            hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
+        };
+
+        // by default undefined variables render as empty strings
+        let jinja = Jinja::new(options.clone());
+        assert_eq!(jinja.render_str("${{ pyhton }}").unwrap(), "");
+
+        let jinja = Jinja::new(options).with_strict_undefined();
+        assert_eq!(jinja.render_str("${{ python }}").unwrap(), "3.11");
+        // platform selectors of other platforms are defined (and false)
+        assert!(!jinja.eval("win or osx").unwrap().is_true());
+
+        let err = jinja.render_str("python ${{ pyhton }}").unwrap_err();
+        assert_eq!(err.kind(), minijinja::ErrorKind::UndefinedError);
+        assert_eq!(
+            err.detail(),
+            Some("`pyhton` is undefined (did you mean `python`?)")
+        );
+
+        let err = jinja.eval("completely_unknown and unix").unwrap_err();
+        assert_eq!(err.detail(), Some("`completely_unknown` is undefined"));
+    }
 }
@@ -2,13 +2,16 @@
 //!
 //! This phase parses YAML and [`SelectorConfig`] into a [`Recipe`], where
 //! if-selectors are handled and any jinja string is processed, resulting in a rendered recipe.
+use std::{path::PathBuf, str::FromStr};
+
 use minijinja::Value;
+use rattler_conda_types::Version;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     _partialerror,
     recipe::{
-        custom_yaml::{HasSpan, RenderedMappingNode, ScalarNode, TryConvertNode},
+        custom_yaml::{HasSpan, RenderedMappingNode, RenderedNode, ScalarNode, TryConvertNode},
         error::{ErrorKind, ParsingError, PartialParsingError},
         jinja::Jinja,
         Render,
@@ -27,18 +30,149 @@ mod test;
 
 pub use self::{
     about::About,
-    build::Build,
+    build::{
+        BinaryRelocation, Build, DeduplicateFiles, DynamicLinking, IgnorePrefixFiles,
+        LinkingCheckBehavior, NetworkPolicy, SourceFetch, SymlinkPolicy, XattrPolicy,
+    },
     output::find_outputs_from_src,
     package::{OutputPackage, Package},
     requirements::{
-        Compiler, Dependency, IgnoreRunExports, PinSubpackage, Requirements, RunExports,
+        Compiler, Dependency, IgnoreRunExports, PinSubpackage, RecipeLocation,
+        RequirementLocations, Requirements, RunExports,
     },
     script::{Script, ScriptContent},
-    source::{Checksum, GitSource, GitUrl, PathSource, Source, UrlSource},
+    source::{Checksum, GitSource, GitUrl, Limits, PathSource, Source, UrlSource},
     test::{PackageContent, Test},
 };
 
-use super::custom_yaml::Node;
+use super::custom_yaml::{MappingNode, Node};
+
+/// Render the values of the `context` section and add them to the jinja context. If `lenient`
+/// is set, values that fail to render are skipped (and stay undefined).
+fn add_context_values(
+    root_node: &MappingNode,
+    jinja: &mut Jinja,
+    lenient: bool,
+) -> Result<(), PartialParsingError> {
+    let Some(context) = root_node.get("context") else {
+        return Ok(());
+    };
+    let context = context.as_mapping().ok_or_else(|| {
+        _partialerror!(
+            *context.span(),
+            ErrorKind::ExpectedMapping,
+            help = "`context` must always be a mapping"
+        )
+    })?;
+
+    for (k, v) in context.iter() {
+        let val = v.as_scalar().ok_or_else(|| {
+            _partialerror!(
+                *v.span(),
+                ErrorKind::ExpectedScalar,
+                help = "`context` values must always be scalars"
+            )
+        })?;
+        let rendered: Option<ScalarNode> =
+            match val.render(jinja, &format!("context.{}", k.as_str())) {
+                Ok(rendered) => rendered,
+                Err(_) if lenient => continue,
+                Err(e) => return Err(e),
+            };
+
+        if let Some(rendered) = rendered {
+            jinja.context_mut().insert(
+                k.as_str().to_owned(),
+                Value::from_safe_string(rendered.as_str().to_string()),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The `version` value of the `context` section, if there is one.
+fn context_version(root_node: &MappingNode) -> Option<&ScalarNode> {
+    root_node
+        .get("context")?
+        .as_mapping()?
+        .get("version")?
+        .as_scalar()
+}
+
+/// Whether the raw `package.version` of the recipe refers to the `version` context variable.
+fn package_version_uses_context(root_node: &MappingNode) -> bool {
+    let Some(template) = root_node
+        .get("package")
+        .and_then(|p| p.as_mapping())
+        .and_then(|p| p.get("version"))
+        .and_then(|v| v.as_scalar())
+    else {
+        return false;
+    };
+
+    template.as_str().split("${{").skip(1).any(|expr| {
+        let expr = expr.split("}}").next().unwrap_or_default();
+        expr.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .any(|word| word == "version")
+    })
+}
+
+/// If `package.version` is rendered from the `version` context variable, an invalid version is
+/// reported where it is defined in the `context`, because that is where it has to be fixed.
+fn point_to_context_version(
+    root_node: &MappingNode,
+    mut err: PartialParsingError,
+) -> PartialParsingError {
+    if !matches!(
+        err.kind,
+        ErrorKind::VersionParsing(_) | ErrorKind::InvalidVersion { .. }
+    ) {
+        return err;
+    }
+
+    if let Some(context_version) = context_version(root_node) {
+        if package_version_uses_context(root_node) {
+            err.span = *context_version.span();
+            err.label = Some("`package.version` is rendered from this value".into());
+        }
+    }
+    err
+}
+
+/// Warn if the `context` defines a `version` that differs from the package version. Every
+/// `${{ version }}` in the recipe (e.g. in the source URL) would then refer to another version
+/// than the one of the package.
+fn check_context_version(root_node: &MappingNode, package: &Package) {
+    let Some(context_version) = context_version(root_node) else {
+        return;
+    };
+    if package_version_uses_context(root_node) {
+        return;
+    }
+
+    let context_version = context_version.as_str().trim().to_lowercase();
+    if context_version != package.version() {
+        tracing::warn!(
+            "`package.version` is `{}`, but `context.version` is `{}`: `${{{{ version }}}}` refers \
+             to the latter",
+            package.version(),
+            context_version
+        );
+    }
+}
+
+/// Resolve the relative local paths of the URL sources against the `recipe_dir` (or the current
+/// directory, if the recipe is not read from a file).
+fn resolve_relative_paths(sources: &mut [Source], recipe_dir: Option<PathBuf>) {
+    let current_dir = std::env::current_dir().unwrap_or_default();
+    let recipe_dir = current_dir.join(recipe_dir.unwrap_or_default());
+    for source in sources {
+        if let Source::Url(url) = source {
+            url.resolve_relative_paths(&recipe_dir);
+        }
+    }
+}
 
 /// A recipe that has been parsed and validated.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,6 +186,8 @@ pub struct Recipe {
     test: Test,
     #[serde(default, skip_serializing_if = "About::is_default")]
     about: About,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_tool_version: Option<Version>,
 }
 
 impl Recipe {
@@ -84,44 +220,29 @@ impl Recipe {
         jinja_opt: SelectorConfig,
     ) -> Result<Self, PartialParsingError> {
         let hash = jinja_opt.hash.clone();
+        let recipe_dir = jinja_opt.recipe_dir.clone();
         let mut jinja = Jinja::new(jinja_opt);
+        // Once the hash is known the variant is final, so every variable that the recipe refers
+        // to has to be defined (either in the variant or in the `context`).
+        if hash.is_some() {
+            jinja = jinja.with_strict_undefined();
+        }
 
         let root_node = root_node
             .as_mapping()
             .ok_or_else(|| _partialerror!(*root_node.span(), ErrorKind::ExpectedMapping,))?;
 
-        // add context values
-        if let Some(context) = root_node.get("context") {
-            let context = context.as_mapping().ok_or_else(|| {
-                _partialerror!(
-                    *context.span(),
-                    ErrorKind::ExpectedMapping,
-                    help = "`context` must always be a mapping"
-                )
-            })?;
-
-            for (k, v) in context.iter() {
-                let val = v.as_scalar().ok_or_else(|| {
-                    _partialerror!(
-                        *v.span(),
-                        ErrorKind::ExpectedScalar,
-                        help = "`context` values must always be scalars"
-                    )
-                })?;
-                let rendered: Option<ScalarNode> =
-                    val.render(&jinja, &format!("context.{}", k.as_str()))?;
-
-                if let Some(rendered) = rendered {
-                    jinja.context_mut().insert(
-                        k.as_str().to_owned(),
-                        Value::from_safe_string(rendered.as_str().to_string()),
-                    );
-                }
-            }
-        }
+        add_context_values(root_node, &mut jinja, false)?;
 
         let rendered_node: RenderedMappingNode = root_node.render(&jinja, "ROOT")?;
 
+        // Check the required tool version first, a newer recipe might contain fields that we
+        // don't know about yet.
+        let min_tool_version = rendered_node
+            .get("min_tool_version")
+            .map(parse_min_tool_version)
+            .transpose()?;
+
         let mut package = None;
         let mut build = Build::default();
         let mut source = Vec::new();
@@ -132,7 +253,13 @@ impl Recipe {
         for (key, value) in rendered_node.iter() {
             let key_str = key.as_str();
             match key_str {
-                "package" => package = Some(value.try_convert(key_str)?),
+                "package" => {
+                    package = Some(
+                        value
+                            .try_convert(key_str)
+                            .map_err(|err| point_to_context_version(root_node, err))?,
+                    )
+                }
                 "recipe" => {
                     return Err(_partialerror!(
                         *key.span(),
@@ -148,6 +275,7 @@ impl Recipe {
                 "about" => about = value.try_convert(key_str)?,
                 "context" => {}
                 "extra" => {}
+                "min_tool_version" => {}
                 invalid_key => {
                     return Err(_partialerror!(
                         *key.span(),
@@ -157,6 +285,8 @@ impl Recipe {
             }
         }
 
+        resolve_relative_paths(&mut source, recipe_dir);
+
         // Add hash to build.string if it is not set
         if build.string.is_none() {
             if let Some(hash) = hash {
@@ -164,6 +294,10 @@ impl Recipe {
             }
         }
 
+        if let Some(package) = &package {
+            check_context_version(root_node, package);
+        }
+
         let recipe = Recipe {
             package: package.ok_or_else(|| {
                 _partialerror!(
@@ -177,11 +311,74 @@ impl Recipe {
             requirements,
             test,
             about,
+            min_tool_version,
         };
 
         Ok(recipe)
     }
 
+    /// Render only the sources of a recipe from a YAML string, see [`Recipe::sources_from_node`].
+    pub fn sources_from_yaml(
+        yaml: &str,
+        jinja_opt: SelectorConfig,
+    ) -> Result<(Vec<Source>, SourceFetch), ParsingError> {
+        let yaml_root = Node::parse_yaml(0, yaml)?;
+
+        Self::sources_from_node(&yaml_root, jinja_opt)
+            .map_err(|err| ParsingError::from_partial(yaml, err))
+    }
+
+    /// Render only the `source` section and `build.source_fetch` of a recipe. This is used to
+    /// fetch the sources before the rest of the recipe can be rendered with
+    /// `load_file_from_source`. Context values that need the sources are left undefined.
+    pub fn sources_from_node(
+        root_node: &Node,
+        jinja_opt: SelectorConfig,
+    ) -> Result<(Vec<Source>, SourceFetch), PartialParsingError> {
+        let recipe_dir = jinja_opt.recipe_dir.clone();
+        let mut jinja = Jinja::new(jinja_opt);
+
+        let root_node = root_node
+            .as_mapping()
+            .ok_or_else(|| _partialerror!(*root_node.span(), ErrorKind::ExpectedMapping,))?;
+
+        add_context_values(root_node, &mut jinja, true)?;
+
+        let mut sources = match root_node.get("source") {
+            Some(source) => {
+                let rendered: RenderedNode = source.render(&jinja, "source")?;
+                rendered.try_convert("source")?
+            }
+            None => Vec::new(),
+        };
+        resolve_relative_paths(&mut sources, recipe_dir);
+
+        let source_fetch = match root_node
+            .get("build")
+            .and_then(|build| build.as_mapping())
+            .and_then(|build| build.get("source_fetch"))
+        {
+            Some(source_fetch) => {
+                let rendered: RenderedNode = source_fetch.render(&jinja, "source_fetch")?;
+                rendered.try_convert("source_fetch")?
+            }
+            None => SourceFetch::default(),
+        };
+
+        Ok((sources, source_fetch))
+    }
+
+    /// Replace the build script of the recipe, e.g. with [`ScriptContent::Rendered`] contents
+    /// to skip the discovery of the build script on the filesystem.
+    pub fn set_build_script(&mut self, script: impl Into<Script>) {
+        self.build.script = script.into();
+    }
+
+    /// The minimum version of rattler-build that is required by the recipe, if any.
+    pub const fn min_tool_version(&self) -> Option<&Version> {
+        self.min_tool_version.as_ref()
+    }
+
     /// Get the package information.
     pub const fn package(&self) -> &Package {
         &self.package
@@ -213,11 +410,38 @@ impl Recipe {
     }
 }
 
+/// Parse the `min_tool_version` field and make sure that the running rattler-build satisfies it.
+fn parse_min_tool_version(node: &RenderedNode) -> Result<Version, PartialParsingError> {
+    let raw: String = node.try_convert("min_tool_version")?;
+    let required = Version::from_str(raw.trim()).map_err(|err| {
+        _partialerror!(
+            *node.span(),
+            ErrorKind::VersionParsing(err),
+            help = "`min_tool_version` must be a plain version, e.g. `0.6.0`"
+        )
+    })?;
+
+    let current = Version::from_str(env!("CARGO_PKG_VERSION"))
+        .expect("the crate version is always a valid version");
+    if required > current {
+        return Err(_partialerror!(
+            *node.span(),
+            ErrorKind::MinToolVersion(required),
+            help = "update rattler-build to build this recipe"
+        ));
+    }
+
+    Ok(required)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use insta::assert_yaml_snapshot;
+    use rattler_conda_types::NoArchType;
 
-    use crate::assert_miette_snapshot;
+    use crate::{assert_miette_snapshot, hash::HashInfo};
 
     use super::*;
 
@@ -271,10 +495,347 @@ mod tests {
         assert_miette_snapshot!(err);
     }
 
+    #[test]
+    fn undefined_variable() {
+        let raw_recipe = r#"
+        context:
+          version: "0.1.0"
+
+        package:
+          name: test
+          version: ${{ verion }}
+        "#;
+
+        let selector_config = SelectorConfig {
+            hash: Some(HashInfo::from_variant(
+                &Default::default(),
+                &NoArchType::none(),
+            )),
+            ..SelectorConfig::default()
+        };
+        let err = Recipe::from_yaml(raw_recipe, selector_config).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::JinjaRendering(_)));
+        let label = err.label.unwrap_or_default();
+        assert!(
+            label.contains("`verion` is undefined (did you mean `version`?)"),
+            "unexpected label: {label}"
+        );
+    }
+
+    #[test]
+    fn min_tool_version() {
+        let recipe = |version: &str| {
+            format!(
+                r#"
+        min_tool_version: "{version}"
+
+        package:
+          name: test
+          version: 0.1.0
+        "#
+            )
+        };
+
+        let satisfied = Recipe::from_yaml(&recipe("0.1.0"), SelectorConfig::default()).unwrap();
+        assert_eq!(
+            satisfied.min_tool_version(),
+            Some(&Version::from_str("0.1.0").unwrap())
+        );
+
+        let current = recipe(env!("CARGO_PKG_VERSION"));
+        assert!(Recipe::from_yaml(&current, SelectorConfig::default()).is_ok());
+
+        let err = Recipe::from_yaml(&recipe("999.0.0"), SelectorConfig::default()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::MinToolVersion(_)));
+        let message = err.kind().to_string();
+        assert!(message.contains("999.0.0"));
+        assert!(message.contains(env!("CARGO_PKG_VERSION")));
+
+        let err = Recipe::from_yaml(&recipe(">=0.6.0"), SelectorConfig::default()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::VersionParsing(_)));
+
+        let without = Recipe::from_yaml(
+            "package:\n  name: test\n  version: 0.1.0\n",
+            SelectorConfig::default(),
+        )
+        .unwrap();
+        assert!(without.min_tool_version().is_none());
+    }
+
     #[test]
     fn jinja_sequence() {
         let recipe = include_str!("../../test-data/recipes/test-parsing/recipe_inline_jinja.yaml");
         let recipe = Recipe::from_yaml(recipe, SelectorConfig::default()).unwrap();
         assert_yaml_snapshot!(recipe);
     }
+
+    #[test]
+    fn script_interpreter() {
+        let raw_recipe = r#"
+        package:
+          name: test
+          version: 0.1.0
+        build:
+          script:
+            interpreter: python
+            content: print("hello")
+        "#;
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        assert_eq!(recipe.build().script().interpreter(), Some("python"));
+
+        let raw_recipe = r#"
+        package:
+          name: test
+          version: 0.1.0
+        build:
+          script:
+            shell: python
+        "#;
+        assert!(Recipe::from_yaml(raw_recipe, SelectorConfig::default()).is_err());
+    }
+
+    #[test]
+    fn version_from_source() {
+        let raw_recipe = r#"
+        context:
+          version: ${{ load_file_from_source("VERSION") | trim }}
+
+        package:
+          name: test
+          version: ${{ version }}
+
+        source:
+          path: ./src
+        "#;
+
+        let (sources, source_fetch) =
+            Recipe::sources_from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        assert_eq!(source_fetch, SourceFetch::BeforeSolve);
+        assert!(matches!(&sources[..], [Source::Path(_)]));
+
+        let source_dir = tempfile::tempdir().unwrap();
+        fs_err::write(source_dir.path().join("VERSION"), "1.2.3\n").unwrap();
+        let selector_config = SelectorConfig {
+            source_dir: Some(source_dir.path().to_path_buf()),
+            ..SelectorConfig::default()
+        };
+        let recipe = Recipe::from_yaml(raw_recipe, selector_config).unwrap();
+        assert_eq!(recipe.package().version(), "1.2.3");
+
+        // without fetched sources the function fails, and records that it needed them
+        let selector_config = SelectorConfig::default();
+        assert!(Recipe::from_yaml(raw_recipe, selector_config.clone()).is_err());
+        assert!(selector_config.sources_requested.get());
+
+        // a recipe that only mentions the function in a comment does not need the sources
+        let raw_recipe = r#"
+        # the version could be read with load_file_from_source("VERSION")
+        package:
+          name: test
+          version: 1.0.0
+        "#;
+        let selector_config = SelectorConfig::default();
+        Recipe::from_yaml(raw_recipe, selector_config.clone()).unwrap();
+        assert!(!selector_config.sources_requested.get());
+    }
+
+    /// A selector config for the final render of a recipe in `test-data/load_from_file`
+    fn load_from_file_config() -> SelectorConfig {
+        SelectorConfig {
+            hash: Some(HashInfo::from_variant(
+                &Default::default(),
+                &NoArchType::none(),
+            )),
+            recipe_dir: Some(
+                Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/load_from_file"),
+            ),
+            ..SelectorConfig::default()
+        }
+    }
+
+    #[test]
+    fn load_from_file() {
+        let raw_recipe = r#"
+        package:
+          name: ${{ load_from_file("package.json").name }}
+          version: ${{ load_from_file("pyproject.toml").project.version }}
+
+        requirements:
+          build:
+            - ${{ load_from_file("pyproject.toml")["build-system"].requires }}
+            - python
+          host: ${{ load_from_file("pyproject.toml").project.dependencies }}
+          run:
+            - nodejs ${{ load_from_file("package.json").engines.node }}
+        "#;
+
+        let selector_config = load_from_file_config();
+        let recipe = Recipe::from_yaml(raw_recipe, selector_config.clone()).unwrap();
+        // the files are all in the recipe directory
+        assert!(!selector_config.sources_requested.get());
+        assert_eq!(recipe.package().name().as_normalized(), "example-js");
+        assert_eq!(recipe.package().version(), "1.4.2");
+        let specs = |deps: &[Dependency]| deps.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            specs(recipe.requirements().build()),
+            ["setuptools >=61", "python"]
+        );
+        assert_eq!(
+            specs(recipe.requirements().host()),
+            ["numpy >=1.21", "requests"]
+        );
+        assert_eq!(specs(recipe.requirements().run()), ["nodejs >=18"]);
+    }
+
+    #[test]
+    fn load_from_file_errors() {
+        let recipe = |version: &str| {
+            format!(
+                r#"
+        package:
+          name: test
+          version: ${{{{ {version} }}}}
+        "#
+            )
+        };
+        let label = |version: &str| {
+            let err = Recipe::from_yaml(&recipe(version), load_from_file_config()).unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::JinjaRendering(_)));
+            err.label.unwrap_or_default()
+        };
+
+        assert!(
+            label(r#"load_from_file("pyproject.toml").project.verison"#).contains(
+                "`project.verison` is not defined in `pyproject.toml` (did you mean `version`?)"
+            )
+        );
+        assert!(label(r#"load_from_file("package.json").engines.python"#)
+            .contains("`engines.python` is not defined in `package.json`"));
+        assert!(label(r#"load_from_file("setup.cfg")"#)
+            .contains("is neither in the recipe directory nor in the fetched sources"));
+        assert!(label(r#"load_from_file("../pyproject.toml")"#)
+            .contains("only reads files inside the recipe directory or the sources"));
+    }
+
+    #[test]
+    fn source_fetch_after_solve() {
+        let raw_recipe = r#"
+        package:
+          name: test
+          version: 0.1.0
+
+        build:
+          source_fetch: after_solve
+        "#;
+
+        let (sources, source_fetch) =
+            Recipe::sources_from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        assert!(sources.is_empty());
+        assert_eq!(source_fetch, SourceFetch::AfterSolve);
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        assert_eq!(recipe.build().source_fetch(), SourceFetch::AfterSolve);
+    }
+
+    #[test]
+    fn ignore_prefix_files() {
+        let recipe = |build: &str| {
+            let raw_recipe = format!(
+                "package:\n  name: test\n  version: 0.1.0\nbuild:\n  ignore_prefix_files: {build}\n"
+            );
+            Recipe::from_yaml(&raw_recipe, SelectorConfig::default()).unwrap()
+        };
+
+        assert_eq!(recipe("true").build().ignore_prefix_files().globs(), ["**"]);
+        assert!(recipe("false").build().ignore_prefix_files().is_default());
+        assert_eq!(
+            recipe("[share/doc/**, lib/*.la]")
+                .build()
+                .ignore_prefix_files()
+                .globs(),
+            ["share/doc/**", "lib/*.la"]
+        );
+    }
+
+    #[test]
+    fn binary_relocation() {
+        let recipe = |build: &str| {
+            let raw_recipe = format!("package:\n  name: test\n  version: 0.1.0\nbuild:\n{build}");
+            Recipe::from_yaml(&raw_recipe, SelectorConfig::default()).unwrap()
+        };
+
+        let default = recipe("  number: 0\n");
+        assert!(default.build().binary_relocation().is_default());
+        assert!(default
+            .build()
+            .binary_relocation()
+            .skipped_globs()
+            .is_empty());
+        assert_eq!(
+            recipe("  binary_relocation: false\n")
+                .build()
+                .binary_relocation()
+                .skipped_globs(),
+            ["**"]
+        );
+
+        let skipped = recipe(
+            "  binary_relocation: [lib/libprebuilt.so]\n  dynamic_linking:\n    build_reference_behavior: error\n",
+        );
+        assert_eq!(
+            skipped.build().binary_relocation().skipped_globs(),
+            ["lib/libprebuilt.so"]
+        );
+        assert_eq!(
+            skipped.build().dynamic_linking().build_reference_behavior(),
+            LinkingCheckBehavior::Error
+        );
+    }
+
+    #[test]
+    fn output_files() {
+        let raw_recipe = r#"
+        package:
+          name: libfoo-devel
+          version: 0.1.0
+        build:
+          files:
+            - include/**
+            - lib/pkgconfig/foo.pc
+        "#;
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        assert_eq!(
+            recipe.build().files(),
+            ["include/**", "lib/pkgconfig/foo.pc"]
+        );
+
+        let raw_recipe = "package:\n  name: libfoo\n  version: 0.1.0\n";
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        assert!(recipe.build().files().is_empty());
+    }
+
+    #[test]
+    fn test_section() {
+        let recipe = |test: &str| {
+            let raw_recipe = format!("package:\n  name: test\n  version: 0.1.0\ntest:\n{test}");
+            Recipe::from_yaml(&raw_recipe, SelectorConfig::default()).unwrap()
+        };
+
+        // the imports are tested without any commands
+        let imports = recipe("  imports:\n    - bsdiff4\n");
+        assert!(!imports.test().is_empty());
+        assert_eq!(imports.test().imports(), ["bsdiff4"]);
+
+        let commands = recipe(
+            "  requires:\n    - pytest\n  files:\n    - tests/\n  source_files:\n    - test-data.txt\n  commands:\n    - pytest tests\n",
+        );
+        assert_eq!(commands.test().requires(), ["pytest"]);
+        assert_eq!(commands.test().files(), ["tests/"]);
+        assert_eq!(commands.test().source_files(), ["test-data.txt"]);
+        assert_eq!(commands.test().commands(), ["pytest tests"]);
+
+        assert!(recipe("  package_contents:\n    files:\n      - bin/foo\n")
+            .test()
+            .is_empty());
+    }
 }
@@ -43,6 +43,42 @@ pub struct Build {
     /// Python specific build configuration
     #[serde(default, skip_serializing_if = "Python::is_default")]
     pub(super) python: Python,
+    /// Settings for the checks of the shared libraries and executables in the package
+    #[serde(default, skip_serializing_if = "DynamicLinking::is_default")]
+    pub(super) dynamic_linking: DynamicLinking,
+    /// What to do with new symlinks that point to files of other packages
+    #[serde(default, skip_serializing_if = "SymlinkPolicy::is_default")]
+    pub(super) symlink_policy: SymlinkPolicy,
+    /// When the sources are fetched, relative to the dependency resolution
+    #[serde(default, skip_serializing_if = "SourceFetch::is_default")]
+    pub(super) source_fetch: SourceFetch,
+    /// Store identical files of the package only once
+    #[serde(default, skip_serializing_if = "DeduplicateFiles::is_default")]
+    pub(super) deduplicate_files: DeduplicateFiles,
+    /// What to do with new files that have extended attributes
+    #[serde(default, skip_serializing_if = "XattrPolicy::is_default")]
+    pub(super) xattr_policy: XattrPolicy,
+    /// Whether the build script may access the network
+    #[serde(default, skip_serializing_if = "NetworkPolicy::is_default")]
+    pub(super) network: NetworkPolicy,
+    /// Globs of the links in the package that may point outside of the prefix
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) allow_external_links: Vec<String>,
+    /// Recreate the missing links of the version symlink chains of shared libraries
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(super) fix_soname_links: bool,
+    /// The files in which the host prefix is not detected
+    #[serde(default, skip_serializing_if = "IgnorePrefixFiles::is_default")]
+    pub(super) ignore_prefix_files: IgnorePrefixFiles,
+    /// The shared libraries and executables whose rpaths and install names are made relative
+    #[serde(default, skip_serializing_if = "BinaryRelocation::is_default")]
+    pub(super) binary_relocation: BinaryRelocation,
+    /// Globs of the new files of the prefix that are packaged (all of them if empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) files: Vec<String>,
+    /// The package can not be cross-compiled, it is only built on its host platform
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(super) native_only: bool,
     // TODO: Add and parse the rest of the fields
 }
 
@@ -77,6 +113,66 @@ impl Build {
         &self.python
     }
 
+    /// Settings for the dynamic linking checks.
+    pub const fn dynamic_linking(&self) -> &DynamicLinking {
+        &self.dynamic_linking
+    }
+
+    /// Get the policy for symlinks to files of other packages.
+    pub const fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlink_policy
+    }
+
+    /// Get the point at which the sources are fetched.
+    pub const fn source_fetch(&self) -> SourceFetch {
+        self.source_fetch
+    }
+
+    /// Get the setting for the deduplication of identical files.
+    pub const fn deduplicate_files(&self) -> DeduplicateFiles {
+        self.deduplicate_files
+    }
+
+    /// Get the policy for new files with extended attributes.
+    pub const fn xattr_policy(&self) -> XattrPolicy {
+        self.xattr_policy
+    }
+
+    /// Get the network policy of the build script.
+    pub const fn network(&self) -> NetworkPolicy {
+        self.network
+    }
+
+    /// Get the globs of the links that may point outside of the prefix.
+    pub fn allow_external_links(&self) -> &[String] {
+        self.allow_external_links.as_slice()
+    }
+
+    /// Whether the missing links of shared library version chains are recreated.
+    pub const fn fix_soname_links(&self) -> bool {
+        self.fix_soname_links
+    }
+
+    /// Whether the package can only be built on its host platform.
+    pub const fn native_only(&self) -> bool {
+        self.native_only
+    }
+
+    /// Get the files in which the host prefix is not detected.
+    pub const fn ignore_prefix_files(&self) -> &IgnorePrefixFiles {
+        &self.ignore_prefix_files
+    }
+
+    /// Get the binaries that are relocated.
+    pub const fn binary_relocation(&self) -> &BinaryRelocation {
+        &self.binary_relocation
+    }
+
+    /// Get the globs of the new files of the prefix that are packaged.
+    pub fn files(&self) -> &[String] {
+        self.files.as_slice()
+    }
+
     /// Check if the build should be skipped.
     pub fn is_skip_build(&self) -> bool {
         self.skip()
@@ -116,6 +212,42 @@ impl TryConvertNode<Build> for RenderedMappingNode {
                 "python" => {
                     build.python = value.try_convert(key_str)?;
                 }
+                "dynamic_linking" => {
+                    build.dynamic_linking = value.try_convert(key_str)?;
+                }
+                "symlink_policy" => {
+                    build.symlink_policy = value.try_convert(key_str)?;
+                }
+                "source_fetch" => {
+                    build.source_fetch = value.try_convert(key_str)?;
+                }
+                "deduplicate_files" => {
+                    build.deduplicate_files = value.try_convert(key_str)?;
+                }
+                "xattr_policy" => {
+                    build.xattr_policy = value.try_convert(key_str)?;
+                }
+                "network" => {
+                    build.network = value.try_convert(key_str)?;
+                }
+                "allow_external_links" => {
+                    build.allow_external_links = value.try_convert(key_str)?;
+                }
+                "fix_soname_links" => {
+                    build.fix_soname_links = value.try_convert(key_str)?;
+                }
+                "ignore_prefix_files" => {
+                    build.ignore_prefix_files = value.try_convert(key_str)?;
+                }
+                "binary_relocation" => {
+                    build.binary_relocation = value.try_convert(key_str)?;
+                }
+                "native_only" => {
+                    build.native_only = value.try_convert(key_str)?;
+                }
+                "files" => {
+                    build.files = value.try_convert(key_str)?;
+                }
                 invalid => {
                     return Err(_partialerror!(
                         *key.span(),
@@ -181,6 +313,425 @@ impl TryConvertNode<Python> for RenderedMappingNode {
     }
 }
 
+/// What to do when one of the dynamic linking checks finds a problem
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkingCheckBehavior {
+    /// Do not report the problem at all
+    Ignore,
+    /// Report the problem as a warning
+    #[default]
+    Warn,
+    /// Fail the build
+    Error,
+}
+
+impl TryConvertNode<LinkingCheckBehavior> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<LinkingCheckBehavior, PartialParsingError> {
+        self.as_scalar()
+            .ok_or_else(|| _partialerror!(*self.span(), ErrorKind::ExpectedScalar))
+            .and_then(|s| s.try_convert(name))
+    }
+}
+
+impl TryConvertNode<LinkingCheckBehavior> for RenderedScalarNode {
+    fn try_convert(&self, name: &str) -> Result<LinkingCheckBehavior, PartialParsingError> {
+        match self.as_str() {
+            "ignore" => Ok(LinkingCheckBehavior::Ignore),
+            "warn" => Ok(LinkingCheckBehavior::Warn),
+            "error" => Ok(LinkingCheckBehavior::Error),
+            invalid => Err(_partialerror!(
+                *self.span(),
+                ErrorKind::InvalidField(invalid.to_owned().into()),
+                help = format!("expected `ignore`, `warn` or `error` for {name}"),
+            )),
+        }
+    }
+}
+
+/// What to do with new symlinks whose target is a file of another package (e.g. a
+/// `bin/python3 -> python3.11` link that points into the `python` package)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Package the symlink as-is (and warn about it)
+    #[default]
+    Keep,
+    /// Copy the content of the target into the package instead of the symlink
+    Copy,
+    /// Fail the build
+    Error,
+}
+
+impl SymlinkPolicy {
+    /// Returns true if this is the default policy.
+    pub fn is_default(&self) -> bool {
+        *self == SymlinkPolicy::default()
+    }
+}
+
+impl TryConvertNode<SymlinkPolicy> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<SymlinkPolicy, PartialParsingError> {
+        self.as_scalar()
+            .ok_or_else(|| _partialerror!(*self.span(), ErrorKind::ExpectedScalar))
+            .and_then(|s| s.try_convert(name))
+    }
+}
+
+impl TryConvertNode<SymlinkPolicy> for RenderedScalarNode {
+    fn try_convert(&self, name: &str) -> Result<SymlinkPolicy, PartialParsingError> {
+        match self.as_str() {
+            "keep" => Ok(SymlinkPolicy::Keep),
+            "copy" => Ok(SymlinkPolicy::Copy),
+            "error" => Ok(SymlinkPolicy::Error),
+            invalid => Err(_partialerror!(
+                *self.span(),
+                ErrorKind::InvalidField(invalid.to_owned().into()),
+                help = format!("expected `keep`, `copy` or `error` for {name}"),
+            )),
+        }
+    }
+}
+
+/// What to do with new files that have extended attributes (e.g. macOS quarantine flags or
+/// SELinux labels). The attributes are never packaged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XattrPolicy {
+    /// Warn about the files with extended attributes
+    #[default]
+    Warn,
+    /// Like `warn`, but fail the build if a file has the `com.apple.quarantine` attribute
+    Strict,
+}
+
+impl XattrPolicy {
+    /// Returns true if this is the default policy.
+    pub fn is_default(&self) -> bool {
+        *self == XattrPolicy::default()
+    }
+}
+
+impl TryConvertNode<XattrPolicy> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<XattrPolicy, PartialParsingError> {
+        self.as_scalar()
+            .ok_or_else(|| _partialerror!(*self.span(), ErrorKind::ExpectedScalar))
+            .and_then(|s| s.try_convert(name))
+    }
+}
+
+impl TryConvertNode<XattrPolicy> for RenderedScalarNode {
+    fn try_convert(&self, name: &str) -> Result<XattrPolicy, PartialParsingError> {
+        match self.as_str() {
+            "warn" => Ok(XattrPolicy::Warn),
+            "strict" => Ok(XattrPolicy::Strict),
+            invalid => Err(_partialerror!(
+                *self.span(),
+                ErrorKind::InvalidField(invalid.to_owned().into()),
+                help = format!("expected `warn` or `strict` for {name}"),
+            )),
+        }
+    }
+}
+
+/// Whether the build script may access the network. Downloads in the build script make a build
+/// depend on the state of the servers, so they should be sources of the recipe instead.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkPolicy {
+    /// The script may access the network
+    #[default]
+    Allow,
+    /// Refuse the requests of the script through the proxy variables and warn about them
+    Warn,
+    /// Like `warn`, but fail the build if the script made a request
+    Error,
+    /// Run the script without network. Where that is not possible, this is like `error`.
+    None,
+}
+
+impl NetworkPolicy {
+    /// Returns true if this is the default policy.
+    pub fn is_default(&self) -> bool {
+        *self == NetworkPolicy::default()
+    }
+}
+
+impl std::fmt::Display for NetworkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkPolicy::Allow => write!(f, "allow"),
+            NetworkPolicy::Warn => write!(f, "warn"),
+            NetworkPolicy::Error => write!(f, "error"),
+            NetworkPolicy::None => write!(f, "none"),
+        }
+    }
+}
+
+impl TryConvertNode<NetworkPolicy> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<NetworkPolicy, PartialParsingError> {
+        self.as_scalar()
+            .ok_or_else(|| _partialerror!(*self.span(), ErrorKind::ExpectedScalar))
+            .and_then(|s| s.try_convert(name))
+    }
+}
+
+impl TryConvertNode<NetworkPolicy> for RenderedScalarNode {
+    fn try_convert(&self, name: &str) -> Result<NetworkPolicy, PartialParsingError> {
+        match self.as_str() {
+            "allow" => Ok(NetworkPolicy::Allow),
+            "warn" => Ok(NetworkPolicy::Warn),
+            "error" => Ok(NetworkPolicy::Error),
+            "none" => Ok(NetworkPolicy::None),
+            invalid => Err(_partialerror!(
+                *self.span(),
+                ErrorKind::InvalidField(invalid.to_owned().into()),
+                help = format!("expected `allow`, `warn`, `error` or `none` for {name}"),
+            )),
+        }
+    }
+}
+
+/// When the sources are fetched
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFetch {
+    /// Fetch the sources before resolving the dependencies, so that the recipe can read files
+    /// from them with `load_file_from_source`
+    #[default]
+    BeforeSolve,
+    /// Fetch the sources after the dependencies are resolved, so that a failing solve does not
+    /// download anything
+    AfterSolve,
+}
+
+impl SourceFetch {
+    /// Returns true if this is the default.
+    pub fn is_default(&self) -> bool {
+        *self == SourceFetch::default()
+    }
+}
+
+impl TryConvertNode<SourceFetch> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<SourceFetch, PartialParsingError> {
+        self.as_scalar()
+            .ok_or_else(|| _partialerror!(*self.span(), ErrorKind::ExpectedScalar))
+            .and_then(|s| s.try_convert(name))
+    }
+}
+
+impl TryConvertNode<SourceFetch> for RenderedScalarNode {
+    fn try_convert(&self, name: &str) -> Result<SourceFetch, PartialParsingError> {
+        match self.as_str() {
+            "before_solve" => Ok(SourceFetch::BeforeSolve),
+            "after_solve" => Ok(SourceFetch::AfterSolve),
+            invalid => Err(_partialerror!(
+                *self.span(),
+                ErrorKind::InvalidField(invalid.to_owned().into()),
+                help = format!("expected `before_solve` or `after_solve` for {name}"),
+            )),
+        }
+    }
+}
+
+/// Whether identical files are stored once (with hardlink entries for the copies)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeduplicateFiles {
+    /// Store every file as a regular entry
+    #[default]
+    Off,
+    /// Deduplicate files in `.conda` and `.tar.bz2` packages
+    All,
+    /// Deduplicate files only in `.conda` packages, `.tar.bz2` packages keep regular entries
+    /// for older clients
+    CondaOnly,
+}
+
+impl DeduplicateFiles {
+    /// Returns true if this is the default.
+    pub fn is_default(&self) -> bool {
+        *self == DeduplicateFiles::default()
+    }
+}
+
+impl TryConvertNode<DeduplicateFiles> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<DeduplicateFiles, PartialParsingError> {
+        self.as_scalar()
+            .ok_or_else(|| _partialerror!(*self.span(), ErrorKind::ExpectedScalar))
+            .and_then(|s| s.try_convert(name))
+    }
+}
+
+impl TryConvertNode<DeduplicateFiles> for RenderedScalarNode {
+    fn try_convert(&self, name: &str) -> Result<DeduplicateFiles, PartialParsingError> {
+        match self.as_str() {
+            "false" | "off" => Ok(DeduplicateFiles::Off),
+            "true" | "all" => Ok(DeduplicateFiles::All),
+            "conda_only" => Ok(DeduplicateFiles::CondaOnly),
+            invalid => Err(_partialerror!(
+                *self.span(),
+                ErrorKind::InvalidField(invalid.to_owned().into()),
+                help = format!("expected `true`, `false` or `conda_only` for {name}"),
+            )),
+        }
+    }
+}
+
+/// The files of a package in which the host prefix is not detected. conda replaces the prefix
+/// of the build in the detected files with the prefix of the environment when the package is
+/// installed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IgnorePrefixFiles {
+    /// Do not detect the prefix in any file (`true`), or detect it in all files (`false`)
+    All(bool),
+    /// Do not detect the prefix in the files that match one of these globs
+    Paths(Vec<String>),
+}
+
+impl Default for IgnorePrefixFiles {
+    fn default() -> Self {
+        IgnorePrefixFiles::All(false)
+    }
+}
+
+impl IgnorePrefixFiles {
+    /// Returns true if the prefix is detected in all files.
+    pub fn is_default(&self) -> bool {
+        *self == IgnorePrefixFiles::default()
+    }
+
+    /// The globs of the files in which the prefix is not detected (`**` for all files)
+    pub fn globs(&self) -> Vec<&str> {
+        match self {
+            IgnorePrefixFiles::All(true) => vec!["**"],
+            IgnorePrefixFiles::All(false) => Vec::new(),
+            IgnorePrefixFiles::Paths(globs) => globs.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+impl TryConvertNode<IgnorePrefixFiles> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<IgnorePrefixFiles, PartialParsingError> {
+        match self {
+            RenderedNode::Scalar(scalar) if matches!(scalar.as_str(), "true" | "false") => {
+                scalar.try_convert(name).map(IgnorePrefixFiles::All)
+            }
+            _ => self.try_convert(name).map(IgnorePrefixFiles::Paths),
+        }
+    }
+}
+
+/// The shared libraries and executables of a package whose rpaths (and install names on macOS)
+/// are made relative, so that the package works in any prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BinaryRelocation {
+    /// Relocate all binaries (`true`) or none (`false`)
+    All(bool),
+    /// Relocate all binaries except the ones that match one of these globs
+    Skip(Vec<String>),
+}
+
+impl Default for BinaryRelocation {
+    fn default() -> Self {
+        BinaryRelocation::All(true)
+    }
+}
+
+impl BinaryRelocation {
+    /// Returns true if all binaries are relocated.
+    pub fn is_default(&self) -> bool {
+        *self == BinaryRelocation::default()
+    }
+
+    /// The globs of the binaries that are not relocated (`**` for all binaries)
+    pub fn skipped_globs(&self) -> Vec<&str> {
+        match self {
+            BinaryRelocation::All(true) => Vec::new(),
+            BinaryRelocation::All(false) => vec!["**"],
+            BinaryRelocation::Skip(globs) => globs.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+impl TryConvertNode<BinaryRelocation> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<BinaryRelocation, PartialParsingError> {
+        match self {
+            RenderedNode::Scalar(scalar) if matches!(scalar.as_str(), "true" | "false") => {
+                scalar.try_convert(name).map(BinaryRelocation::All)
+            }
+            _ => self.try_convert(name).map(BinaryRelocation::Skip),
+        }
+    }
+}
+
+/// Settings for the checks that are run on the shared libraries and executables of a package
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DynamicLinking {
+    /// What to do when a binary needs a shared library that is neither part of the package,
+    /// of its run dependencies nor of the system
+    #[serde(default)]
+    pub(super) missing_dso_behavior: LinkingCheckBehavior,
+    /// What to do when a relocated binary still refers to the build directory (e.g. with an
+    /// rpath into the host prefix)
+    #[serde(default)]
+    pub(super) build_reference_behavior: LinkingCheckBehavior,
+}
+
+impl DynamicLinking {
+    /// Get the behavior for missing shared libraries.
+    pub const fn missing_dso_behavior(&self) -> LinkingCheckBehavior {
+        self.missing_dso_behavior
+    }
+
+    /// Get the behavior for binaries that still refer to the build directory.
+    pub const fn build_reference_behavior(&self) -> LinkingCheckBehavior {
+        self.build_reference_behavior
+    }
+
+    /// Returns true if this is the default dynamic linking configuration.
+    pub fn is_default(&self) -> bool {
+        self.missing_dso_behavior == LinkingCheckBehavior::default()
+            && self.build_reference_behavior == LinkingCheckBehavior::default()
+    }
+}
+
+impl TryConvertNode<DynamicLinking> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<DynamicLinking, PartialParsingError> {
+        self.as_mapping()
+            .ok_or_else(|| _partialerror!(*self.span(), ErrorKind::ExpectedMapping))
+            .and_then(|m| m.try_convert(name))
+    }
+}
+
+impl TryConvertNode<DynamicLinking> for RenderedMappingNode {
+    fn try_convert(&self, _name: &str) -> Result<DynamicLinking, PartialParsingError> {
+        let mut dynamic_linking = DynamicLinking::default();
+
+        for (key, value) in self.iter() {
+            let key_str = key.as_str();
+            match key_str {
+                "missing_dso_behavior" => {
+                    dynamic_linking.missing_dso_behavior = value.try_convert(key_str)?;
+                }
+                "build_reference_behavior" => {
+                    dynamic_linking.build_reference_behavior = value.try_convert(key_str)?;
+                }
+                invalid => {
+                    return Err(_partialerror!(
+                        *key.span(),
+                        ErrorKind::InvalidField(invalid.to_string().into()),
+                    ));
+                }
+            }
+        }
+
+        Ok(dynamic_linking)
+    }
+}
+
 /// Run exports are applied to downstream packages that depend on this package.
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct RunExports {
@@ -293,3 +844,39 @@ impl TryConvertNode<EntryPoint> for RenderedScalarNode {
             .map_err(|err| _partialerror!(*self.span(), ErrorKind::EntryPointParsing(err),))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::recipe::{jinja::SelectorConfig, Recipe};
+
+    fn recipe(build: &str) -> String {
+        format!(
+            r#"
+        package:
+          name: test
+          version: 0.1.0
+        build:
+          {build}
+        "#
+        )
+    }
+
+    #[test]
+    fn native_only() {
+        let native =
+            Recipe::from_yaml(&recipe("native_only: true"), SelectorConfig::default()).unwrap();
+        assert!(native.build().native_only());
+        let yaml = serde_yaml::to_string(native.build()).unwrap();
+        assert!(yaml.contains("native_only: true"));
+
+        // cross-compilation is allowed by default, and not written to the rendered recipe
+        let default = Recipe::from_yaml(&recipe("number: 0"), SelectorConfig::default()).unwrap();
+        assert!(!default.build().native_only());
+        let yaml = serde_yaml::to_string(default.build()).unwrap();
+        assert!(!yaml.contains("native_only"));
+
+        assert!(
+            Recipe::from_yaml(&recipe("native_only: maybe"), SelectorConfig::default()).is_err()
+        );
+    }
+}
@@ -12,8 +12,15 @@ use crate::{
 };
 
 static DEEP_MERGE_KEYS: [&str; 4] = ["package", "about", "extra", "build"];
-static ALLOWED_KEYS_MULTI_OUTPUTS: [&str; 7] = [
-    "context", "recipe", "source", "build", "outputs", "about", "extra",
+static ALLOWED_KEYS_MULTI_OUTPUTS: [&str; 8] = [
+    "context",
+    "recipe",
+    "source",
+    "build",
+    "outputs",
+    "about",
+    "extra",
+    "min_tool_version",
 ];
 
 /// Retrieve all outputs from the recipe source (YAML)
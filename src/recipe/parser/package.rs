@@ -1,6 +1,6 @@
-use std::str::FromStr;
+use std::{ops::Range, str::FromStr};
 
-use rattler_conda_types::PackageName;
+use rattler_conda_types::{PackageName, Version};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -49,12 +49,13 @@ impl TryConvertNode<Package> for RenderedMappingNode {
         for (key, value) in self.iter() {
             let key_str = key.as_str();
             match key_str {
-                "name" => {
-                    name_val = value.try_convert(key_str)?;
+                "name" if !matches!(value, RenderedNode::Null(_)) => {
+                    name_val = Some(parse_package_name(value)?);
                 }
-                "version" => {
-                    version = value.try_convert(key_str)?;
+                "version" if !matches!(value, RenderedNode::Null(_)) => {
+                    version = Some(parse_version(value)?);
                 }
+                "name" | "version" => {}
                 invalid => {
                     return Err(_partialerror!(
                         *key.span(),
@@ -122,12 +123,13 @@ impl TryConvertNode<OutputPackage> for RenderedMappingNode {
         for (key, value) in self.iter() {
             let key_str = key.as_str();
             match key_str {
-                "name" => {
-                    name_val = value.try_convert(key_str)?;
+                "name" if !matches!(value, RenderedNode::Null(_)) => {
+                    name_val = Some(parse_package_name(value)?);
                 }
-                "version" => {
-                    version = value.try_convert(key_str)?;
+                "version" if !matches!(value, RenderedNode::Null(_)) => {
+                    version = Some(parse_version(value)?);
                 }
+                "name" | "version" => {}
                 invalid => {
                     return Err(_partialerror!(
                         *key.span(),
@@ -150,6 +152,120 @@ impl TryConvertNode<OutputPackage> for RenderedMappingNode {
     }
 }
 
+/// Parse a package name, which must follow the conda naming rules: only lowercase letters,
+/// digits, `-`, `_` and `.`, and not starting with `.` or `-`.
+fn parse_package_name(node: &RenderedNode) -> Result<PackageName, PartialParsingError> {
+    let scalar = node
+        .as_scalar()
+        .ok_or_else(|| _partialerror!(*node.span(), ErrorKind::ExpectedScalar))?;
+    let name = scalar.as_str();
+
+    let invalid = |reason: &'static str, offending: Range<usize>, help: String| {
+        _partialerror!(
+            *scalar.span(),
+            ErrorKind::InvalidPackageName {
+                name: name.to_string(),
+                reason,
+                offending,
+            },
+            help = help
+        )
+    };
+
+    if name.is_empty() {
+        return Err(invalid(
+            "package names cannot be empty",
+            0..0,
+            "set `name` to the name of the package".to_string(),
+        ));
+    }
+
+    let is_valid = |c: char| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_' | '.');
+    if let Some(offending) = char_range(name, |c| !is_valid(c)) {
+        let lowercase = name.to_lowercase();
+        let help = if lowercase.chars().all(is_valid) {
+            format!("package names are lowercase, use `{lowercase}` instead")
+        } else {
+            "replace the highlighted characters, e.g. with `-` or `_`".to_string()
+        };
+        return Err(invalid(
+            "package names may only contain lowercase letters, digits, `-`, `_` and `.`",
+            offending,
+            help,
+        ));
+    }
+
+    if name.starts_with(['.', '-']) {
+        return Err(invalid(
+            "package names cannot start with `.` or `-`",
+            0..1,
+            format!("remove the leading `{}`", &name[..1]),
+        ));
+    }
+
+    PackageName::from_str(name).map_err(|err| _partialerror!(*scalar.span(), ErrorKind::from(err)))
+}
+
+/// Parse a package version. Surrounding whitespace is trimmed and the version is lowercased, so
+/// that e.g. `1.2.3.POST1` becomes `1.2.3.post1`. Versions cannot contain `-` or whitespace and
+/// must be valid conda versions.
+fn parse_version(node: &RenderedNode) -> Result<String, PartialParsingError> {
+    let scalar = node
+        .as_scalar()
+        .ok_or_else(|| _partialerror!(*node.span(), ErrorKind::ExpectedScalar))?;
+    let value = scalar.as_str();
+    let trimmed = value.trim();
+    let trimmed_start = value.len() - value.trim_start().len();
+
+    let invalid = |reason: &'static str, offending: Range<usize>, help: &'static str| {
+        _partialerror!(
+            *scalar.span(),
+            ErrorKind::InvalidVersion {
+                version: value.to_string(),
+                reason,
+                offending,
+            },
+            help = help
+        )
+    };
+
+    if let Some(offending) = char_range(trimmed, |c| c == '-') {
+        let offending = offending.start + trimmed_start..offending.end + trimmed_start;
+        return Err(invalid(
+            "versions cannot contain `-`",
+            offending,
+            "use `_` or `.` instead of `-`, e.g. `1.2.3_1` instead of `1.2.3-1`",
+        ));
+    }
+    if let Some(offending) = char_range(trimmed, char::is_whitespace) {
+        let offending = offending.start + trimmed_start..offending.end + trimmed_start;
+        return Err(invalid(
+            "versions cannot contain whitespace",
+            offending,
+            "remove the whitespace from the version",
+        ));
+    }
+
+    let normalized = trimmed.to_lowercase();
+    Version::from_str(&normalized).map_err(|err| {
+        _partialerror!(
+            *scalar.span(),
+            ErrorKind::from(err),
+            help = "versions consist of an optional epoch (`1!`), dot separated components \
+                    and an optional local version (`+local`)"
+        )
+    })?;
+
+    Ok(normalized)
+}
+
+/// The byte range from the first to the last character of `value` that matches `pred`.
+fn char_range(value: &str, pred: impl Fn(char) -> bool) -> Option<Range<usize>> {
+    let start = value.find(&pred)?;
+    let (end, last) = value.char_indices().filter(|(_, c)| pred(*c)).last()?;
+    Some(start..end + last.len_utf8())
+}
+
 impl TryConvertNode<PackageName> for RenderedNode {
     fn try_convert(&self, name: &str) -> Result<PackageName, PartialParsingError> {
         self.as_scalar()
@@ -169,9 +285,13 @@ impl TryConvertNode<PackageName> for RenderedScalarNode {
 mod tests {
     use crate::{
         assert_miette_snapshot,
-        recipe::{jinja::SelectorConfig, Recipe},
+        recipe::{error::ErrorKind, jinja::SelectorConfig, Recipe},
     };
 
+    fn recipe(name: &str, version: &str) -> String {
+        format!("package:\n  name: {name}\n  version: {version}\n")
+    }
+
     #[test]
     fn missing_fields() {
         let raw_recipe = r#"
@@ -197,4 +317,103 @@ mod tests {
         let err = recipe.unwrap_err();
         assert_miette_snapshot!(err);
     }
+
+    #[test]
+    fn valid_names_and_versions() {
+        let cases = [
+            ("foo", "1.2.3", "1.2.3"),
+            ("foo-bar", "0.1", "0.1"),
+            ("foo_bar.baz", "2023.01.01", "2023.01.01"),
+            ("r-base", "4.3.1", "4.3.1"),
+            ("7zip", "b1513", "b1513"),
+            ("foo", "1!2.0", "1!2.0"),
+            ("foo", "1.0+local.1", "1.0+local.1"),
+            ("foo", "1.2.3.post1", "1.2.3.post1"),
+            ("foo", "1.2.3.POST1", "1.2.3.post1"),
+            ("foo", "\"1.10\"", "1.10"),
+        ];
+
+        for (name, version, expected) in cases {
+            let recipe = Recipe::from_yaml(&recipe(name, version), SelectorConfig::default())
+                .unwrap_or_else(|e| panic!("`{name}` `{version}` should be valid: {e}"));
+            assert_eq!(recipe.package().name().as_normalized(), name);
+            assert_eq!(recipe.package().version(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_names() {
+        // the name, and the highlighted part of it
+        let cases = [
+            ("Foo", "F"),
+            ("fooBAR", "BAR"),
+            ("foo bar", " "),
+            ("\"foo bar!\"", " bar!"),
+            (".foo", "."),
+            ("-foo", "-"),
+            ("foo/bar", "/"),
+        ];
+
+        for (name, highlighted) in cases {
+            let yaml = recipe(name, "1.0");
+            let err = Recipe::from_yaml(&yaml, SelectorConfig::default()).unwrap_err();
+            assert!(
+                matches!(err.kind(), ErrorKind::InvalidPackageName { .. }),
+                "`{name}`: {err}"
+            );
+            let span = &yaml[err.span.offset()..err.span.offset() + err.span.len()];
+            assert_eq!(span, highlighted, "`{name}`");
+        }
+    }
+
+    #[test]
+    fn invalid_versions() {
+        // the version, and the highlighted part of it (if the error is about specific characters)
+        let cases = [
+            ("1.2.3-1", Some("-")),
+            ("1.2-3-4", Some("-3-")),
+            ("\"1.2 3\"", Some(" ")),
+            ("x!1.0", None),
+        ];
+
+        for (version, highlighted) in cases {
+            let yaml = recipe("foo", version);
+            let err = Recipe::from_yaml(&yaml, SelectorConfig::default()).unwrap_err();
+            match highlighted {
+                Some(highlighted) => {
+                    assert!(
+                        matches!(err.kind(), ErrorKind::InvalidVersion { .. }),
+                        "`{version}`: {err}"
+                    );
+                    let span = &yaml[err.span.offset()..err.span.offset() + err.span.len()];
+                    assert_eq!(span, highlighted, "`{version}`");
+                }
+                None => assert!(
+                    matches!(err.kind(), ErrorKind::VersionParsing(_)),
+                    "`{version}`: {err}"
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_context_version() {
+        let raw_recipe = r#"
+        context:
+            version: 1.0-beta
+        package:
+            name: test
+            version: ${{ version }}
+        "#;
+
+        let err = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidVersion { .. }));
+        // the error points to the definition of the version in the context
+        let line = raw_recipe[..err.span.offset()].lines().count();
+        assert_eq!(line, 3);
+        assert_eq!(
+            err.label.as_deref(),
+            Some("`package.version` is rendered from this value")
+        );
+    }
 }
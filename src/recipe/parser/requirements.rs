@@ -1,6 +1,7 @@
 //! Parsing for the requirements section of the recipe.
 
 use indexmap::IndexSet;
+use marked_yaml::Span;
 use std::str::FromStr;
 
 use rattler_conda_types::{MatchSpec, PackageName};
@@ -55,6 +56,45 @@ pub struct Requirements {
     /// Ignore run-exports by name or from certain packages
     #[serde(default, skip_serializing_if = "IgnoreRunExports::is_empty")]
     pub ignore_run_exports: IgnoreRunExports,
+
+    /// The locations of the `run` and `run_constrained` entries in the recipe file. They are
+    /// not serialized, so a rendered recipe that is read again has no locations.
+    #[serde(skip)]
+    pub locations: RequirementLocations,
+}
+
+/// The location of an entry in the recipe file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecipeLocation {
+    /// The line, starting at 1
+    pub line: usize,
+    /// The column, starting at 1
+    pub column: usize,
+}
+
+impl RecipeLocation {
+    /// The start of the `span`, if it has one (nodes that were created while rendering don't)
+    pub fn from_span(span: &Span) -> Option<Self> {
+        span.start().map(|start| Self {
+            line: start.line(),
+            column: start.column(),
+        })
+    }
+}
+
+impl std::fmt::Display for RecipeLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// The locations of the run requirements in the recipe file, one per entry of the list
+#[derive(Debug, Default, Clone)]
+pub struct RequirementLocations {
+    /// The locations of the `run` entries
+    pub run: Vec<Option<RecipeLocation>>,
+    /// The locations of the `run_constrained` entries
+    pub run_constrained: Vec<Option<RecipeLocation>>,
 }
 
 impl Requirements {
@@ -88,6 +128,11 @@ impl Requirements {
         &self.ignore_run_exports
     }
 
+    /// Get the locations of the run requirements in the recipe file.
+    pub const fn locations(&self) -> &RequirementLocations {
+        &self.locations
+    }
+
     /// Get all requirements at build time (combines build and host requirements)
     pub fn build_time(&self) -> impl Iterator<Item = &Dependency> {
         self.build.iter().chain(self.host.iter())
@@ -133,14 +178,21 @@ impl TryConvertNode<Requirements> for RenderedMappingNode {
         let mut run_constrained = Vec::new();
         let mut run_exports = RunExports::default();
         let mut ignore_run_exports = IgnoreRunExports::default();
+        let mut locations = RequirementLocations::default();
 
         for (key, value) in self.iter() {
             let key_str = key.as_str();
             match key_str {
                 "build" => build = value.try_convert(key_str)?,
                 "host" => host = value.try_convert(key_str)?,
-                "run" => run = value.try_convert(key_str)?,
-                "run_constrained" => run_constrained = value.try_convert(key_str)?,
+                "run" => {
+                    run = value.try_convert(key_str)?;
+                    locations.run = dependency_locations(value);
+                }
+                "run_constrained" => {
+                    run_constrained = value.try_convert(key_str)?;
+                    locations.run_constrained = dependency_locations(value);
+                }
                 "run_exports" => {
                     run_exports = value.try_convert(key_str)?;
                 }
@@ -163,10 +215,21 @@ impl TryConvertNode<Requirements> for RenderedMappingNode {
             run_constrained,
             run_exports,
             ignore_run_exports,
+            locations,
         })
     }
 }
 
+/// The locations of the entries of a dependency list, in the order in which they are converted
+/// to [`Dependency`]s
+fn dependency_locations(node: &RenderedNode) -> Vec<Option<RecipeLocation>> {
+    match node {
+        RenderedNode::Scalar(scalar) => vec![RecipeLocation::from_span(scalar.span())],
+        RenderedNode::Sequence(seq) => seq.iter().flat_map(dependency_locations).collect(),
+        RenderedNode::Mapping(_) | RenderedNode::Null(_) => Vec::new(),
+    }
+}
+
 /// A pin subpackage is a special kind of dependency that is used to depend on
 /// another output (subpackage) of the same recipe. The pin is used to specify
 /// the version range to pin the subpackage to.
@@ -233,6 +296,29 @@ pub enum Dependency {
     Compiler(Compiler),
 }
 
+impl std::fmt::Display for Dependency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dependency::Spec(spec) => write!(f, "{spec}"),
+            Dependency::PinSubpackage(pin) => {
+                write!(
+                    f,
+                    "pin_subpackage({})",
+                    pin.pin_value().name.as_normalized()
+                )
+            }
+            Dependency::PinCompatible(pin) => {
+                write!(
+                    f,
+                    "pin_compatible({})",
+                    pin.pin_value().name.as_normalized()
+                )
+            }
+            Dependency::Compiler(compiler) => write!(f, "compiler({})", compiler.language()),
+        }
+    }
+}
+
 impl TryConvertNode<Vec<Dependency>> for RenderedNode {
     fn try_convert(&self, name: &str) -> Result<Vec<Dependency>, PartialParsingError> {
         match self {
@@ -616,4 +702,78 @@ mod test {
         let deserialized: Requirements = serde_yaml::from_str(&yaml).unwrap();
         insta::assert_yaml_snapshot!(deserialized);
     }
+
+    #[test]
+    fn run_constrained() {
+        let recipe = r#"
+        package:
+          name: foo
+          version: 1.0.0
+        requirements:
+          run_constrained:
+            - cudatoolkit >=11.2,<12
+            - ${{ pin_subpackage("foo-cli", exact=True) }}
+            - if: unix
+              then: libfoo ${{ "1.0" }}.*
+        "#;
+
+        let recipe = crate::recipe::Recipe::from_yaml(
+            recipe,
+            crate::recipe::jinja::SelectorConfig::default(),
+        )
+        .unwrap();
+        let constrained = recipe.requirements().run_constrained();
+        assert_eq!(constrained.len(), if cfg!(unix) { 3 } else { 2 });
+        assert!(
+            matches!(&constrained[0], Dependency::Spec(spec) if spec.to_string() == "cudatoolkit >=11.2,<12")
+        );
+        match &constrained[1] {
+            Dependency::PinSubpackage(pin) => {
+                assert_eq!(pin.pin_value().name.as_normalized(), "foo-cli");
+                assert!(pin.pin_value().exact);
+            }
+            other => panic!("expected a pin_subpackage, got {other}"),
+        }
+        if cfg!(unix) {
+            assert_eq!(constrained[2].to_string(), "libfoo 1.0.*");
+        }
+
+        // the rendered recipe keeps the pins
+        let yaml = serde_yaml::to_string(recipe.requirements()).unwrap();
+        let deserialized: Requirements = serde_yaml::from_str(&yaml).unwrap();
+        assert!(matches!(
+            deserialized.run_constrained()[1],
+            Dependency::PinSubpackage(_)
+        ));
+    }
+
+    #[test]
+    fn requirement_locations() {
+        let recipe = r#"
+        package:
+          name: foo
+          version: 1.0.0
+        requirements:
+          run:
+            - zlib
+            - if: unix
+              then: libfoo
+          run_constrained: bar <2
+        "#;
+
+        let recipe = crate::recipe::Recipe::from_yaml(
+            recipe,
+            crate::recipe::jinja::SelectorConfig::default(),
+        )
+        .unwrap();
+        let at = |line, column| Some(RecipeLocation { line, column });
+        let locations = recipe.requirements().locations();
+        if cfg!(unix) {
+            assert_eq!(locations.run, vec![at(7, 15), at(9, 21)]);
+        } else {
+            assert_eq!(locations.run, vec![at(7, 15)]);
+        }
+        assert_eq!(locations.run_constrained, vec![at(10, 28)]);
+        assert_eq!(locations.run[0].unwrap().to_string(), "line 7, column 15");
+    }
 }
@@ -16,6 +16,8 @@ pub struct Script {
     pub(super) interpreter: Option<String>,
     /// Environment variables to set in the build environment.
     pub(super) env: BTreeMap<String, String>,
+    /// Environment variables that are passed through from the environment of rattler-build.
+    pub(super) passthrough: Vec<String>,
     /// Environment variables to leak into the build environment from the host system that
     /// contain sensitve information. Use with care because this might make recipes no
     /// longer reproducible on other machines.
@@ -37,6 +39,13 @@ impl Serialize for Script {
             Path { file: &'a PathBuf },
         }
 
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum RawEnv<'a> {
+            Map(&'a BTreeMap<String, String>),
+            List(Vec<String>),
+        }
+
         #[derive(Serialize)]
         #[serde(untagged)]
         enum RawScript<'a> {
@@ -45,8 +54,8 @@ impl Serialize for Script {
             Object {
                 #[serde(skip_serializing_if = "Option::is_none")]
                 interpreter: Option<&'a String>,
-                #[serde(skip_serializing_if = "BTreeMap::is_empty")]
-                env: &'a BTreeMap<String, String>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                env: Option<RawEnv<'a>>,
                 #[serde(skip_serializing_if = "Vec::is_empty")]
                 secrets: &'a Vec<String>,
                 #[serde(skip_serializing_if = "Option::is_none", flatten)]
@@ -57,16 +66,34 @@ impl Serialize for Script {
         let raw_script = match &self.content {
             ScriptContent::CommandOrPath(content) => RawScript::CommandOrPath(content),
             ScriptContent::Commands(content)
-                if self.interpreter.is_none() && self.env.is_empty() && self.secrets.is_empty() =>
+                if self.interpreter.is_none()
+                    && self.env.is_empty()
+                    && self.passthrough.is_empty()
+                    && self.secrets.is_empty() =>
             {
                 RawScript::Commands(content)
             }
             _ => RawScript::Object {
                 interpreter: self.interpreter.as_ref(),
-                env: &self.env,
+                // the passed through variables need the list form
+                env: if !self.passthrough.is_empty() {
+                    Some(RawEnv::List(
+                        self.env
+                            .iter()
+                            .map(|(name, value)| format!("{name}={value}"))
+                            .chain(self.passthrough.iter().cloned())
+                            .collect(),
+                    ))
+                } else if !self.env.is_empty() {
+                    Some(RawEnv::Map(&self.env))
+                } else {
+                    None
+                },
                 secrets: &self.secrets,
                 content: match &self.content {
-                    ScriptContent::Command(content) => Some(RawScriptContent::Command { content }),
+                    ScriptContent::Command(content) | ScriptContent::Rendered(content) => {
+                        Some(RawScriptContent::Command { content })
+                    }
                     ScriptContent::Commands(content) => {
                         Some(RawScriptContent::Commands { content })
                     }
@@ -94,6 +121,13 @@ impl<'de> Deserialize<'de> for Script {
             Path { file: PathBuf },
         }
 
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawEnv {
+            Map(BTreeMap<String, String>),
+            List(Vec<String>),
+        }
+
         #[derive(Deserialize)]
         #[serde(untagged)]
         enum RawScript {
@@ -103,7 +137,7 @@ impl<'de> Deserialize<'de> for Script {
                 #[serde(default)]
                 interpreter: Option<String>,
                 #[serde(default)]
-                env: BTreeMap<String, String>,
+                env: Option<RawEnv>,
                 #[serde(default)]
                 secrets: Vec<String>,
                 content: Option<RawScriptContent>,
@@ -119,23 +153,51 @@ impl<'de> Deserialize<'de> for Script {
                 env,
                 secrets,
                 content,
-            } => Self {
-                interpreter,
-                env,
-                secrets,
-                content: match content {
-                    Some(RawScriptContent::Command { content }) => ScriptContent::Command(content),
-                    Some(RawScriptContent::Commands { content }) => {
-                        ScriptContent::Commands(content)
-                    }
-                    Some(RawScriptContent::Path { file }) => ScriptContent::Path(file),
-                    None => ScriptContent::Default,
-                },
-            },
+            } => {
+                let (env, passthrough) = match env {
+                    Some(RawEnv::Map(env)) => (env, Vec::new()),
+                    Some(RawEnv::List(entries)) => split_env_entries(entries),
+                    None => Default::default(),
+                };
+                Self {
+                    interpreter,
+                    env,
+                    passthrough,
+                    secrets,
+                    content: match content {
+                        Some(RawScriptContent::Command { content }) => {
+                            ScriptContent::Command(content)
+                        }
+                        Some(RawScriptContent::Commands { content }) => {
+                            ScriptContent::Commands(content)
+                        }
+                        Some(RawScriptContent::Path { file }) => ScriptContent::Path(file),
+                        None => ScriptContent::Default,
+                    },
+                }
+            }
         })
     }
 }
 
+/// Split the entries of an `env` list into the `NAME=value` literals and the names of the
+/// variables that are passed through
+fn split_env_entries(
+    entries: impl IntoIterator<Item = String>,
+) -> (BTreeMap<String, String>, Vec<String>) {
+    let mut env = BTreeMap::new();
+    let mut passthrough = Vec::new();
+    for entry in entries {
+        match entry.split_once('=') {
+            Some((name, value)) => {
+                env.insert(name.to_string(), value.to_string());
+            }
+            None => passthrough.push(entry),
+        }
+    }
+    (env, passthrough)
+}
+
 impl Script {
     /// Returns the interpreter to use to execute the script
     pub fn interpreter(&self) -> Option<&str> {
@@ -152,6 +214,12 @@ impl Script {
         &self.env
     }
 
+    /// Get the names of the environment variables that are passed through from the environment
+    /// of rattler-build.
+    pub fn passthrough(&self) -> &[String] {
+        self.passthrough.as_slice()
+    }
+
     /// Get the secrets environment variables.
     ///
     /// Environment variables to leak into the build environment from the host system that
@@ -169,6 +237,7 @@ impl Script {
         self.content.is_default()
             && self.interpreter.is_none()
             && self.env.is_empty()
+            && self.passthrough.is_empty()
             && self.secrets.is_empty()
     }
 }
@@ -178,6 +247,7 @@ impl From<ScriptContent> for Script {
         Self {
             interpreter: None,
             env: Default::default(),
+            passthrough: Default::default(),
             secrets: Default::default(),
             content: value,
         }
@@ -218,7 +288,7 @@ impl TryConvertNode<Script> for RenderedSequenceNode {
 impl TryConvertNode<Script> for RenderedMappingNode {
     fn try_convert(&self, name: &str) -> Result<Script, PartialParsingError> {
         let invalid = self.keys().find(|k| {
-            matches!(
+            !matches!(
                 k.as_str(),
                 "env" | "secrets" | "interpreter" | "content" | "file"
             )
@@ -232,11 +302,31 @@ impl TryConvertNode<Script> for RenderedMappingNode {
             ));
         }
 
-        let env = self
-            .get("env")
-            .map(|node| node.try_convert("env"))
-            .transpose()?
-            .unwrap_or_default();
+        let (env, passthrough) = match self.get("env") {
+            // a list of variable names to pass through, and `NAME=value` literals
+            Some(RenderedNode::Sequence(entries)) => {
+                let entries = entries
+                    .iter()
+                    .map(|entry| {
+                        let value: String = entry.try_convert("env")?;
+                        let name = value
+                            .split_once('=')
+                            .map_or(value.as_str(), |(name, _)| name);
+                        if name.is_empty() || name.contains(char::is_whitespace) {
+                            return Err(_partialerror!(
+                                *entry.span(),
+                                ErrorKind::InvalidField(value.clone().into()),
+                                help = "entries of `env` are variable names or `NAME=value`"
+                            ));
+                        }
+                        Ok(value)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                split_env_entries(entries)
+            }
+            Some(node) => (node.try_convert("env")?, Vec::new()),
+            None => Default::default(),
+        };
 
         let secrets = self
             .get("secrets")
@@ -280,6 +370,7 @@ impl TryConvertNode<Script> for RenderedMappingNode {
 
         Ok(Script {
             env,
+            passthrough,
             secrets,
             interpreter,
             content,
@@ -305,6 +396,10 @@ pub enum ScriptContent {
 
     /// The script is given as a string
     Command(String),
+
+    /// The fully rendered script contents, injected through the library API. The contents are
+    /// used as-is and are never looked up as a file.
+    Rendered(String),
 }
 
 impl ScriptContent {
@@ -313,3 +408,55 @@ impl ScriptContent {
         matches!(self, Self::Default)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::recipe::{jinja::SelectorConfig, parser::Script, Recipe};
+
+    #[test]
+    fn env_list() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        build:
+            script:
+                env:
+                    - MY_LICENSE_KEY
+                    - CARGO_NET_GIT_FETCH_WITH_CLI=true
+                secrets:
+                    - MY_TOKEN
+                content: echo hello
+        "#;
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        let script = recipe.build().script();
+        assert_eq!(script.passthrough(), ["MY_LICENSE_KEY"]);
+        assert_eq!(
+            script.env().get("CARGO_NET_GIT_FETCH_WITH_CLI"),
+            Some(&"true".to_string())
+        );
+        assert_eq!(script.secrets(), ["MY_TOKEN"]);
+
+        let deserialized: Script =
+            serde_yaml::from_str(&serde_yaml::to_string(script).unwrap()).unwrap();
+        assert_eq!(deserialized.passthrough(), script.passthrough());
+        assert_eq!(deserialized.env(), script.env());
+        assert_eq!(deserialized.secrets(), script.secrets());
+    }
+
+    #[test]
+    fn invalid_env_entry() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        build:
+            script:
+                env:
+                    - "=value"
+        "#;
+
+        assert!(Recipe::from_yaml(raw_recipe, SelectorConfig::default()).is_err());
+    }
+}
@@ -1,10 +1,15 @@
 //! Parse the source section of a recipe
 
-use std::{fmt, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 
 use rattler_digest::{serde::SerializableHash, Md5, Md5Hash, Sha256, Sha256Hash};
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use serde_with::{formats::PreferOne, serde_as, OneOrMany};
 use url::Url;
 
 use crate::{
@@ -15,6 +20,7 @@ use crate::{
         },
         error::{ErrorKind, PartialParsingError},
     },
+    source::auth::{env_references, is_credential_header},
 };
 
 /// Source information.
@@ -47,6 +53,15 @@ impl Source {
             Self::Path(path) => path.folder(),
         }
     }
+
+    /// Get the limits that override the global source limits.
+    pub fn limits(&self) -> Option<&Limits> {
+        match self {
+            Self::Git(git) => git.limits(),
+            Self::Url(url) => url.limits(),
+            Self::Path(path) => path.limits(),
+        }
+    }
 }
 
 impl TryConvertNode<Vec<Source>> for RenderedNode {
@@ -101,7 +116,8 @@ pub struct GitSource {
     /// Optionally a revision to checkout, defaults to `HEAD`
     #[serde(default)]
     rev: String,
-    /// Optionally a depth to clone the repository, defaults to `None`
+    /// Optionally a depth to clone the repository, defaults to `None` (the full history, like
+    /// `-1`)
     #[serde(skip_serializing_if = "Option::is_none")]
     depth: Option<i32>,
     /// Optionally patches to apply to the source code
@@ -111,8 +127,17 @@ pub struct GitSource {
     #[serde(skip_serializing_if = "Option::is_none")]
     folder: Option<PathBuf>,
     /// Optionally request the lfs pull in git source
-    #[serde(skip_serializing_if = "should_not_serialize_lfs")]
+    #[serde(default, skip_serializing_if = "should_not_serialize_lfs")]
     lfs: bool,
+    /// Whether to check out the submodules (recursively), defaults to `true`
+    #[serde(
+        default = "default_submodules",
+        skip_serializing_if = "should_not_serialize_submodules"
+    )]
+    submodules: bool,
+    /// Optionally override the global limits for this source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limits: Option<Limits>,
 }
 
 /// A helper method to skip serializing the lfs flag if it is false.
@@ -120,6 +145,16 @@ fn should_not_serialize_lfs(lfs: &bool) -> bool {
     !lfs
 }
 
+/// The submodules of a git source are checked out by default
+fn default_submodules() -> bool {
+    true
+}
+
+/// A helper method to skip serializing the submodules flag if it is true.
+fn should_not_serialize_submodules(submodules: &bool) -> bool {
+    *submodules
+}
+
 impl GitSource {
     #[cfg(test)]
     pub fn create(
@@ -129,6 +164,7 @@ impl GitSource {
         patches: Vec<PathBuf>,
         folder: Option<PathBuf>,
         lfs: bool,
+        submodules: bool,
     ) -> Self {
         Self {
             url,
@@ -137,6 +173,8 @@ impl GitSource {
             patches,
             folder,
             lfs,
+            submodules,
+            limits: None,
         }
     }
 
@@ -169,6 +207,23 @@ impl GitSource {
     pub const fn lfs(&self) -> bool {
         self.lfs
     }
+
+    /// Get true if the submodules are checked out.
+    pub const fn submodules(&self) -> bool {
+        self.submodules
+    }
+
+    /// Get the depth of a shallow clone, or `None` for the full history.
+    pub fn shallow_depth(&self) -> Option<u32> {
+        self.depth
+            .and_then(|depth| u32::try_from(depth).ok())
+            .filter(|depth| *depth > 0)
+    }
+
+    /// Get the limits of the git source.
+    pub const fn limits(&self) -> Option<&Limits> {
+        self.limits.as_ref()
+    }
 }
 
 impl TryConvertNode<GitSource> for RenderedMappingNode {
@@ -179,40 +234,34 @@ impl TryConvertNode<GitSource> for RenderedMappingNode {
         let mut patches = Vec::new();
         let mut folder = None;
         let mut lfs = false;
-
-        // TODO: is there a better place for this error?
-        // raising the error during parsing allows us to suggest fixes in future
-        // in case we build linting functionality on top
-        if self.contains_key("git_rev") {
-            if let Some((k, _)) = self.get_key_value("git_depth") {
-                return Err(_partialerror!(
-                    *k.span(),
-                    ErrorKind::InvalidField(k.as_str().to_owned().into()),
-                    help = "use of `git_depth` with `git_rev` is invalid"
-                ));
-            }
-        }
+        let mut submodules = true;
+        let mut limits = None;
 
         for (k, v) in self.iter() {
             match k.as_str() {
                 "git_url" => {
                     let url_str: String = v.try_convert("git_url")?;
-                    let url_ = Url::from_str(&url_str);
-                    match url_ {
-                        Ok(url_) => url = Some(GitUrl::Url(url_)),
-                        Err(err) => {
-                            tracing::warn!("invalid `git_url` `{url_str}`: {err}");
-                            tracing::warn!("attempting to parse as path");
-                            let path = PathBuf::from(url_str);
-                            url = Some(GitUrl::Path(path));
-                        }
-                    }
+                    // everything that is not a url (with a scheme of more than one letter, so
+                    // that `C:\repo` is a path) is a path relative to the recipe
+                    url = Some(match Url::from_str(&url_str) {
+                        Ok(url_) if url_.scheme().len() > 1 => GitUrl::Url(url_),
+                        _ => GitUrl::Path(PathBuf::from(url_str)),
+                    });
                 }
                 "git_rev" => {
                     rev = Some(v.try_convert("git_rev")?);
                 }
                 "git_depth" => {
-                    depth = Some(v.try_convert("git_depth")?);
+                    let git_depth: i32 = v.try_convert("git_depth")?;
+                    if git_depth == 0 || git_depth < -1 {
+                        return Err(_partialerror!(
+                            *v.span(),
+                            ErrorKind::Other,
+                            label = format!("invalid depth `{git_depth}`"),
+                            help = "`git_depth` is the number of commits to fetch, or `-1` for the full history"
+                        ));
+                    }
+                    depth = Some(git_depth);
                 }
                 "patches" => {
                     patches = v.try_convert("patches")?;
@@ -223,11 +272,17 @@ impl TryConvertNode<GitSource> for RenderedMappingNode {
                 "lfs" => {
                     lfs = v.try_convert("lfs")?;
                 }
+                "submodules" => {
+                    submodules = v.try_convert("submodules")?;
+                }
+                "limits" => {
+                    limits = Some(v.try_convert("limits")?);
+                }
                 _ => {
                     return Err(_partialerror!(
                         *k.span(),
                         ErrorKind::InvalidField(k.as_str().to_owned().into()),
-                        help = "valid fields for git `source` are `git_url`, `git_rev`, `git_depth`, `patches`, `lfs` and `folder`"
+                        help = "valid fields for git `source` are `git_url`, `git_rev`, `git_depth`, `patches`, `lfs`, `submodules`, `folder` and `limits`"
                     ))
                 }
             }
@@ -250,6 +305,8 @@ impl TryConvertNode<GitSource> for RenderedMappingNode {
             patches,
             folder,
             lfs,
+            submodules,
+            limits,
         })
     }
 }
@@ -278,8 +335,10 @@ impl fmt::Display for GitUrl {
 #[serde_as]
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UrlSource {
-    /// Url to the source code (usually a tar.gz or tar.bz2 etc. file)
-    url: Url,
+    /// Url to the source code (usually a tar.gz or tar.bz2 etc. file), or a list of mirrors
+    /// that are tried in order
+    #[serde_as(as = "OneOrMany<_, PreferOne>")]
+    url: Vec<Url>,
 
     /// Optionally a sha256 checksum to verify the downloaded file
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -291,6 +350,11 @@ pub struct UrlSource {
     #[serde_as(as = "Option<SerializableHash::<rattler_digest::Md5>>")]
     md5: Option<Md5Hash>,
 
+    /// Optionally a BLAKE2b-256 checksum to verify the downloaded file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<SerializableHash::<Blake2b256>>")]
+    blake2: Option<Blake2Hash>,
+
     /// Optionally a file name to rename the downloaded file (does not apply to archives)
     #[serde(skip_serializing_if = "Option::is_none")]
     file_name: Option<String>,
@@ -300,12 +364,37 @@ pub struct UrlSource {
     /// Optionally a folder name under the `work` directory to place the source code
     #[serde(skip_serializing_if = "Option::is_none")]
     folder: Option<PathBuf>,
+    /// Whether the url points to a conda package (`.conda` or `.tar.bz2`) that should be fully
+    /// extracted, including its `info/` folder.
+    #[serde(default, skip_serializing_if = "should_not_serialize_conda_package")]
+    conda_package: bool,
+    /// Optionally override the global limits for this source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limits: Option<Limits>,
+    /// Headers of the download request. Values can reference environment variables as
+    /// `${NAME}`, which are only replaced when the source is downloaded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    /// The relative local paths of the recipe (by their index in `url`), until they are resolved
+    /// against the recipe directory
+    #[serde(skip)]
+    relative_paths: Vec<(usize, PathBuf)>,
+}
+
+/// Helper method to skip serializing the conda_package flag if it is false.
+fn should_not_serialize_conda_package(conda_package: &bool) -> bool {
+    !*conda_package
 }
 
 impl UrlSource {
-    /// Get the url.
-    pub const fn url(&self) -> &Url {
-        &self.url
+    /// Get the url (the first mirror).
+    pub fn url(&self) -> &Url {
+        &self.url[0]
+    }
+
+    /// Get all mirrors of the url, in the order they are tried.
+    pub fn urls(&self) -> &[Url] {
+        self.url.as_slice()
     }
 
     /// Get the SHA256 checksum of the URL source.
@@ -318,6 +407,20 @@ impl UrlSource {
         self.md5.as_ref()
     }
 
+    /// Get the BLAKE2b-256 checksum of the URL source.
+    pub fn blake2(&self) -> Option<&Blake2Hash> {
+        self.blake2.as_ref()
+    }
+
+    /// Get the checksum that the download is verified with. SHA256 is preferred, then
+    /// BLAKE2b-256 and then MD5.
+    pub fn checksum(&self) -> Option<Checksum> {
+        self.sha256
+            .map(Checksum::Sha256)
+            .or_else(|| self.blake2.map(Checksum::Blake2))
+            .or_else(|| self.md5.map(Checksum::Md5))
+    }
+
     /// Get the patches of the URL source.
     pub fn patches(&self) -> &[PathBuf] {
         self.patches.as_slice()
@@ -332,21 +435,100 @@ impl UrlSource {
     pub const fn file_name(&self) -> Option<&String> {
         self.file_name.as_ref()
     }
+
+    /// Whether the URL source is a conda package that should be extracted as such.
+    pub const fn conda_package(&self) -> bool {
+        self.conda_package
+    }
+
+    /// Get the limits of the URL source.
+    pub const fn limits(&self) -> Option<&Limits> {
+        self.limits.as_ref()
+    }
+
+    /// Get the headers of the download request (with unexpanded environment variables).
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    /// Turn the relative local paths of `url` into `file://` urls in the `recipe_dir`.
+    pub(crate) fn resolve_relative_paths(&mut self, recipe_dir: &Path) {
+        for (index, path) in std::mem::take(&mut self.relative_paths) {
+            let path = normalize_path(&recipe_dir.join(path));
+            if let Ok(url) = Url::from_file_path(&path) {
+                self.url[index] = url;
+            }
+        }
+    }
+}
+
+/// Remove the `.` and `..` components of `path` (without resolving symlinks, the file might not
+/// exist)
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
 }
 
 impl TryConvertNode<UrlSource> for RenderedMappingNode {
     fn try_convert(&self, _name: &str) -> Result<UrlSource, PartialParsingError> {
-        let mut url = None;
+        let mut url: Option<Vec<Url>> = None;
+        let mut relative_paths = Vec::new();
         let mut sha256 = None;
         let mut md5 = None;
+        let mut blake2 = None;
         let mut patches = Vec::new();
         let mut folder = None;
         let mut file_name = None;
+        let mut conda_package = false;
+        let mut limits = None;
+        let mut headers = BTreeMap::new();
 
         for (key, value) in self.iter() {
             let key_str = key.as_str();
             match key_str {
-                "url" => url = value.try_convert(key_str)?,
+                "url" => {
+                    let entries: Vec<RenderedScalarNode> = value.try_convert(key_str)?;
+                    let mut urls = Vec::with_capacity(entries.len());
+                    for entry in entries {
+                        // like `git_url`, everything that is not a url (with a scheme of more
+                        // than one letter, so that `C:\src.tar.gz` is a path) is a local file
+                        match Url::parse(entry.as_str()) {
+                            Ok(url_) if url_.scheme().len() > 1 => urls.push(url_),
+                            Ok(_) | Err(url::ParseError::RelativeUrlWithoutBase) => {
+                                let path = PathBuf::from(entry.as_str());
+                                if path.is_absolute() {
+                                    urls.push(Url::from_file_path(&path).map_err(|_| {
+                                        _partialerror!(
+                                            *entry.span(),
+                                            ErrorKind::InvalidField("url".into()),
+                                            help = "expected a url or a path to a local file"
+                                        )
+                                    })?);
+                                } else {
+                                    relative_paths.push((urls.len(), path));
+                                    urls.push(Url::parse("file:///").expect("valid url"));
+                                }
+                            }
+                            Err(e) => {
+                                return Err(_partialerror!(
+                                    *entry.span(),
+                                    ErrorKind::InvalidField("url".into()),
+                                    help = format!("invalid url: {e}")
+                                ))
+                            }
+                        }
+                    }
+                    url = Some(urls);
+                }
                 "sha256" => {
                     let sha256_str: RenderedScalarNode = value.try_convert(key_str)?;
                     let sha256_out = rattler_digest::parse_digest_from_hex::<Sha256>(sha256_str.as_str()).ok_or_else(|| _partialerror!(*sha256_str.span(), ErrorKind::InvalidSha256))?;
@@ -357,32 +539,53 @@ impl TryConvertNode<UrlSource> for RenderedMappingNode {
                     let md5_out = rattler_digest::parse_digest_from_hex::<Md5>(md5_str.as_str()).ok_or_else(|| _partialerror!(*md5_str.span(), ErrorKind::InvalidMd5))?;
                     md5 = Some(md5_out);
                 }
+                "blake2" => {
+                    let blake2_str: RenderedScalarNode = value.try_convert(key_str)?;
+                    let blake2_out = rattler_digest::parse_digest_from_hex::<Blake2b256>(blake2_str.as_str()).ok_or_else(|| _partialerror!(*blake2_str.span(), ErrorKind::InvalidBlake2))?;
+                    blake2 = Some(blake2_out);
+                }
                 "file_name" => file_name = value.try_convert(key_str)?,
                 "patches" => patches = value.try_convert(key_str)?,
                 "folder" => folder = value.try_convert(key_str)?,
+                "conda_package" => conda_package = value.try_convert(key_str)?,
+                "limits" => limits = Some(value.try_convert(key_str)?),
+                "headers" => {
+                    let nodes: BTreeMap<String, RenderedScalarNode> = value.try_convert(key_str)?;
+                    for (name, value) in nodes {
+                        // credentials must not be written into the recipe
+                        if is_credential_header(&name) && env_references(value.as_str()).is_empty() {
+                            return Err(_partialerror!(
+                                *value.span(),
+                                ErrorKind::InvalidField(format!("headers.{name}").into()),
+                                help = format!("the value of `{name}` is a credential and must reference an environment variable, e.g. `Bearer ${{TOKEN}}`")
+                            ));
+                        }
+                        headers.insert(name, value.as_str().to_owned());
+                    }
+                }
                 invalid_key => {
                     return Err(_partialerror!(
                         *key.span(),
                         ErrorKind::InvalidField(invalid_key.to_owned().into()),
-                        help = "valid fields for URL `source` are `url`, `sha256`, `md5`, `patches`, `file_name` and `folder`"
+                        help = "valid fields for URL `source` are `url`, `sha256`, `md5`, `blake2`, `patches`, `file_name`, `folder`, `conda_package`, `limits` and `headers`"
                     ))
                 }
             }
         }
 
-        let url = url.ok_or_else(|| {
+        let url = url.filter(|url| !url.is_empty()).ok_or_else(|| {
             _partialerror!(
                 *self.span(),
                 ErrorKind::MissingField("url".into()),
-                help = "URL `source` must have a `url` field"
+                help = "URL `source` must have a `url` field (or a list of mirrors)"
             )
         })?;
 
-        if md5.is_none() && sha256.is_none() {
+        if md5.is_none() && sha256.is_none() && blake2.is_none() {
             return Err(_partialerror!(
                 *self.span(),
-                ErrorKind::MissingField("sha256 or md5".into()),
-                help = "URL `source` must have a `sha256` or `md5` checksum field"
+                ErrorKind::MissingField("sha256, md5 or blake2".into()),
+                help = "URL `source` must have a `sha256`, `md5` or `blake2` checksum field"
             ));
         }
 
@@ -390,9 +593,14 @@ impl TryConvertNode<UrlSource> for RenderedMappingNode {
             url,
             md5,
             sha256,
+            blake2,
             file_name,
             patches,
             folder,
+            conda_package,
+            limits,
+            headers,
+            relative_paths,
         })
     }
 }
@@ -405,7 +613,36 @@ pub enum Checksum {
     Sha256(#[serde_as(as = "SerializableHash::<rattler_digest::Sha256>")] Sha256Hash),
     /// A MD5 checksum
     Md5(#[serde_as(as = "SerializableHash::<rattler_digest::Md5>")] Md5Hash),
+    /// A BLAKE2b-256 checksum
+    Blake2(#[serde_as(as = "SerializableHash::<Blake2b256>")] Blake2Hash),
+}
+
+impl Checksum {
+    /// The name of the hash function
+    pub const fn algorithm(&self) -> &'static str {
+        match self {
+            Checksum::Sha256(_) => "sha256",
+            Checksum::Md5(_) => "md5",
+            Checksum::Blake2(_) => "blake2",
+        }
+    }
+
+    /// The checksum as a hex string
+    pub fn to_hex(&self) -> String {
+        match self {
+            Checksum::Sha256(value) => hex::encode(value),
+            Checksum::Md5(value) => hex::encode(value),
+            Checksum::Blake2(value) => hex::encode(value),
+        }
+    }
 }
+
+/// The BLAKE2b hash function with a 256 bit digest (`b2sum --length 256`)
+pub type Blake2b256 = blake2::Blake2b<blake2::digest::consts::U32>;
+
+/// A BLAKE2b-256 digest
+pub type Blake2Hash = blake2::digest::Output<Blake2b256>;
+
 /// A local path source. The source code will be copied to the `work`
 /// (or `work/<folder>` directory).
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -424,6 +661,15 @@ pub struct PathSource {
     /// Whether to use the `.gitignore` file in the source directory. Defaults to `true`.
     #[serde(skip_serializing_if = "should_not_serialize_use_gitignore")]
     use_gitignore: bool,
+    /// Globs of the files to copy (all files if empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    filter: Vec<String>,
+    /// Globs of the files and directories to skip
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<String>,
+    /// Optionally override the global limits for this source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limits: Option<Limits>,
 }
 
 /// Helper method to skip serializing the use_gitignore flag if it is true.
@@ -456,6 +702,21 @@ impl PathSource {
     pub const fn use_gitignore(&self) -> bool {
         self.use_gitignore
     }
+
+    /// Get the globs of the files to copy.
+    pub fn filter(&self) -> &[String] {
+        self.filter.as_slice()
+    }
+
+    /// Get the globs of the files and directories to skip.
+    pub fn exclude(&self) -> &[String] {
+        self.exclude.as_slice()
+    }
+
+    /// Get the limits of the path source.
+    pub const fn limits(&self) -> Option<&Limits> {
+        self.limits.as_ref()
+    }
 }
 
 impl TryConvertNode<PathSource> for RenderedMappingNode {
@@ -465,6 +726,9 @@ impl TryConvertNode<PathSource> for RenderedMappingNode {
         let mut folder = None;
         let mut use_gitignore = true;
         let mut file_name = None;
+        let mut filter = Vec::new();
+        let mut exclude = Vec::new();
+        let mut limits = None;
 
         for (key, value) in self.iter() {
             match key.as_str() {
@@ -473,11 +737,14 @@ impl TryConvertNode<PathSource> for RenderedMappingNode {
                 "folder" => folder = value.try_convert("folder")?,
                 "file_name" => file_name = value.try_convert("file_name")?,
                 "use_gitignore" => use_gitignore = value.try_convert("use_gitignore")?,
+                "filter" => filter = value.try_convert("filter")?,
+                "exclude" => exclude = value.try_convert("exclude")?,
+                "limits" => limits = Some(value.try_convert("limits")?),
                 invalid_key => {
                     return Err(_partialerror!(
                         *key.span(),
                         ErrorKind::InvalidField(invalid_key.to_string().into()),
-                        help = "valid fields for path `source` are `path`, `patches`, `folder`, `file_name` and `use_gitignore`"
+                        help = "valid fields for path `source` are `path`, `patches`, `folder`, `file_name`, `use_gitignore`, `filter`, `exclude` and `limits`"
                     ))
                 }
             }
@@ -497,6 +764,371 @@ impl TryConvertNode<PathSource> for RenderedMappingNode {
             folder,
             file_name,
             use_gitignore,
+            filter,
+            exclude,
+            limits,
         })
     }
 }
+
+/// Limits for the contents of a single source. Limits that are not set fall back to the global
+/// limits of the tool configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// The maximum total (uncompressed) size of all files in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_size: Option<u64>,
+    /// The maximum number of files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_files: Option<u64>,
+}
+
+impl Limits {
+    /// Get the maximum total size in bytes.
+    pub const fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// Get the maximum number of files.
+    pub const fn max_files(&self) -> Option<u64> {
+        self.max_files
+    }
+}
+
+impl TryConvertNode<Limits> for RenderedNode {
+    fn try_convert(&self, name: &str) -> Result<Limits, PartialParsingError> {
+        self.as_mapping()
+            .ok_or_else(|| {
+                _partialerror!(
+                    *self.span(),
+                    ErrorKind::ExpectedMapping,
+                    label = format!("expected a mapping for `{name}`")
+                )
+            })
+            .and_then(|m| m.try_convert(name))
+    }
+}
+
+impl TryConvertNode<Limits> for RenderedMappingNode {
+    fn try_convert(&self, _name: &str) -> Result<Limits, PartialParsingError> {
+        let mut limits = Limits::default();
+
+        for (key, value) in self.iter() {
+            match key.as_str() {
+                "max_size" => {
+                    let size: RenderedScalarNode = value.try_convert("max_size")?;
+                    limits.max_size = Some(parse_size(size.as_str()).ok_or_else(|| {
+                        _partialerror!(
+                            *size.span(),
+                            ErrorKind::Other,
+                            label = format!("invalid size `{}`", size.as_str()),
+                            help = "use a number of bytes or a number with a unit, e.g. `500 MB` or `2 GiB`"
+                        )
+                    })?);
+                }
+                "max_files" => limits.max_files = Some(value.try_convert("max_files")?),
+                invalid_key => {
+                    return Err(_partialerror!(
+                        *key.span(),
+                        ErrorKind::InvalidField(invalid_key.to_string().into()),
+                        help = "valid fields for `limits` are `max_size` and `max_files`"
+                    ))
+                }
+            }
+        }
+
+        Ok(limits)
+    }
+}
+
+/// Parse a size in bytes, optionally followed by a decimal (`KB`, `MB`, `GB`, `TB`) or binary
+/// (`KiB`, `MiB`, `GiB`, `TiB`) unit.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number.parse().ok()?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000_u64.pow(2),
+        "gb" => 1000_u64.pow(3),
+        "tb" => 1000_u64.pow(4),
+        "kib" => 1024,
+        "mib" => 1024_u64.pow(2),
+        "gib" => 1024_u64.pow(3),
+        "tib" => 1024_u64.pow(4),
+        _ => return None,
+    };
+
+    number.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+    use crate::recipe::{
+        jinja::SelectorConfig,
+        parser::{Checksum, GitUrl, Source},
+        Recipe,
+    };
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("10 MB"), Some(10_000_000));
+        assert_eq!(parse_size("2GiB"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1.5 GB"), None);
+        assert_eq!(parse_size("10 parsecs"), None);
+        assert_eq!(parse_size("99999999999 TB"), None);
+    }
+
+    #[test]
+    fn source_limits() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        source:
+            path: ./src
+            limits:
+                max_size: 1 GB
+                max_files: 1000
+        "#;
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        let Source::Path(source) = &recipe.sources()[0] else {
+            panic!("expected a path source");
+        };
+        let limits = source.limits().unwrap();
+        assert_eq!(limits.max_size(), Some(1_000_000_000));
+        assert_eq!(limits.max_files(), Some(1000));
+    }
+
+    #[test]
+    fn path_globs() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        source:
+            - path: ./src
+              filter:
+                - "**/*.py"
+                - pyproject.toml
+              exclude:
+                - node_modules
+                - tests/data
+            - path: ./other
+        "#;
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        let [Source::Path(filtered), Source::Path(other)] = recipe.sources() else {
+            panic!("expected two path sources");
+        };
+        assert_eq!(filtered.filter(), ["**/*.py", "pyproject.toml"]);
+        assert_eq!(filtered.exclude(), ["node_modules", "tests/data"]);
+        assert!(other.filter().is_empty());
+        assert!(other.exclude().is_empty());
+
+        let deserialized: Vec<Source> =
+            serde_yaml::from_str(&serde_yaml::to_string(recipe.sources()).unwrap()).unwrap();
+        assert_eq!(deserialized, recipe.sources());
+    }
+
+    #[test]
+    fn url_mirrors() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        source:
+            - url:
+                - https://downloads.sourceforge.net/project/foo/foo-1.0.tar.gz
+                - https://github.com/foo/foo/releases/download/1.0/foo-1.0.tar.gz
+              sha256: 5a022ff4c1d1de87232b1c70bde50afbb98212fd246be4a867d8737173cf1f8f
+            - url: https://example.com/bar-1.0.tar.gz
+              sha256: 5a022ff4c1d1de87232b1c70bde50afbb98212fd246be4a867d8737173cf1f8f
+        "#;
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        let [Source::Url(mirrors), Source::Url(single)] = recipe.sources() else {
+            panic!("expected two url sources");
+        };
+        assert_eq!(mirrors.urls().len(), 2);
+        assert_eq!(mirrors.url().host_str(), Some("downloads.sourceforge.net"));
+        assert_eq!(single.urls(), [single.url().clone()]);
+
+        // a single url is serialized as a string, and mirrors as a list
+        let serialized = serde_yaml::to_string(recipe.sources()).unwrap();
+        assert!(serialized.contains("url: https://example.com/bar-1.0.tar.gz"));
+        let deserialized: Vec<Source> = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, recipe.sources());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn url_local_paths() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        source:
+            - url: ../archives/foo-1.0.tar.gz
+              sha256: 5a022ff4c1d1de87232b1c70bde50afbb98212fd246be4a867d8737173cf1f8f
+            - url:
+                - /srv/mirror/bar-1.0.tar.gz
+                - https://example.com/bar-1.0.tar.gz
+              sha256: 5a022ff4c1d1de87232b1c70bde50afbb98212fd246be4a867d8737173cf1f8f
+        "#;
+
+        let config = SelectorConfig {
+            recipe_dir: Some("/home/me/recipes/test".into()),
+            ..SelectorConfig::default()
+        };
+        let recipe = Recipe::from_yaml(raw_recipe, config).unwrap();
+        let [Source::Url(relative), Source::Url(absolute)] = recipe.sources() else {
+            panic!("expected two url sources");
+        };
+        assert_eq!(
+            relative.url().as_str(),
+            "file:///home/me/recipes/archives/foo-1.0.tar.gz"
+        );
+        assert_eq!(absolute.url().as_str(), "file:///srv/mirror/bar-1.0.tar.gz");
+        assert_eq!(absolute.urls()[1].scheme(), "https");
+
+        // the rendered recipe contains the resolved urls
+        let deserialized: Vec<Source> =
+            serde_yaml::from_str(&serde_yaml::to_string(recipe.sources()).unwrap()).unwrap();
+        assert_eq!(deserialized, recipe.sources());
+    }
+
+    #[test]
+    fn url_headers() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        source:
+            url: https://artifactory.example.com/foo-1.0.tar.gz
+            sha256: 5a022ff4c1d1de87232b1c70bde50afbb98212fd246be4a867d8737173cf1f8f
+            headers:
+                Authorization: Bearer ${ARTIFACTORY_TOKEN}
+                Accept: application/octet-stream
+                X-GitHub-Api-Version: 2022-11-28
+        "#;
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        let Source::Url(source) = &recipe.sources()[0] else {
+            panic!("expected a url source");
+        };
+        assert_eq!(
+            source.headers()["Authorization"],
+            "Bearer ${ARTIFACTORY_TOKEN}"
+        );
+        assert_eq!(source.headers()["Accept"], "application/octet-stream");
+        // the versions of an API are no credentials
+        assert_eq!(source.headers()["X-GitHub-Api-Version"], "2022-11-28");
+
+        // a literal token is rejected
+        let literal = raw_recipe.replace("${ARTIFACTORY_TOKEN}", "abc123");
+        assert!(Recipe::from_yaml(&literal, SelectorConfig::default()).is_err());
+    }
+
+    #[test]
+    fn url_checksums() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        source:
+            - url: https://example.com/md5.tar.gz
+              md5: 6a5ed8ba2e4c2f9fb2d3e0e1a1b4b0a5
+            - url: https://example.com/blake2.tar.gz
+              blake2: 0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8
+            - url: https://example.com/both.tar.gz
+              md5: 6a5ed8ba2e4c2f9fb2d3e0e1a1b4b0a5
+              sha256: 5a022ff4c1d1de87232b1c70bde50afbb98212fd246be4a867d8737173cf1f8f
+        "#;
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        let [Source::Url(md5), Source::Url(blake2), Source::Url(both)] = recipe.sources() else {
+            panic!("expected three url sources");
+        };
+        assert!(matches!(md5.checksum(), Some(Checksum::Md5(_))));
+        assert_eq!(
+            blake2.checksum().map(|c| c.to_hex()).as_deref(),
+            Some("0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8")
+        );
+        // sha256 is preferred
+        assert!(matches!(both.checksum(), Some(Checksum::Sha256(_))));
+
+        let deserialized: Vec<Source> =
+            serde_yaml::from_str(&serde_yaml::to_string(recipe.sources()).unwrap()).unwrap();
+        assert_eq!(deserialized, recipe.sources());
+
+        let invalid = raw_recipe.replace("blake2: 0e57", "blake2: 0e");
+        assert!(Recipe::from_yaml(&invalid, SelectorConfig::default()).is_err());
+    }
+
+    #[test]
+    fn git_options() {
+        let raw_recipe = r#"
+        package:
+            name: test
+            version: 0.1.0
+        source:
+            - git_url: https://github.com/llvm/llvm-project
+              git_rev: llvmorg-17.0.6
+              git_depth: 1
+              submodules: false
+            - git_url: https://github.com/prefix-dev/rattler-build
+        "#;
+
+        let recipe = Recipe::from_yaml(raw_recipe, SelectorConfig::default()).unwrap();
+        let [Source::Git(shallow), Source::Git(full)] = recipe.sources() else {
+            panic!("expected two git sources");
+        };
+        assert_eq!(shallow.shallow_depth(), Some(1));
+        assert!(!shallow.submodules());
+        assert_eq!(full.shallow_depth(), None);
+        assert!(full.submodules());
+
+        let deserialized: Vec<Source> =
+            serde_yaml::from_str(&serde_yaml::to_string(recipe.sources()).unwrap()).unwrap();
+        assert_eq!(deserialized, recipe.sources());
+
+        let invalid = raw_recipe.replace("git_depth: 1", "git_depth: 0");
+        assert!(Recipe::from_yaml(&invalid, SelectorConfig::default()).is_err());
+    }
+
+    #[test]
+    fn git_locations() {
+        let urls = |location: &str| {
+            let raw_recipe = format!(
+                "package:\n  name: test\n  version: 0.1.0\nsource:\n  git_url: '{location}'\n"
+            );
+            let recipe = Recipe::from_yaml(&raw_recipe, SelectorConfig::default()).unwrap();
+            let [Source::Git(git)] = recipe.sources() else {
+                panic!("expected a git source");
+            };
+            git.url().clone()
+        };
+
+        assert!(matches!(
+            urls("https://github.com/prefix-dev/rattler-build"),
+            GitUrl::Url(_)
+        ));
+        assert!(
+            matches!(urls("file:///home/me/src/my-project"), GitUrl::Url(url) if url.scheme() == "file")
+        );
+        assert_eq!(urls("../my-project"), GitUrl::Path("../my-project".into()));
+        assert_eq!(
+            urls("C:\\src\\my-project"),
+            GitUrl::Path("C:\\src\\my-project".into())
+        );
+    }
+}
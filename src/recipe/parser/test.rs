@@ -57,6 +57,9 @@ pub struct PackageContent {
     /// check if include path contains the file, direct or glob?
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     includes: Vec<String>,
+    /// check that the package has a valid `info/link.json` (`true`) or none at all (`false`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link_json: Option<bool>,
 }
 
 impl PackageContent {
@@ -84,6 +87,11 @@ impl PackageContent {
     pub fn includes(&self) -> &[String] {
         &self.includes
     }
+
+    /// Get the expected presence of `info/link.json`, if it should be checked.
+    pub fn link_json(&self) -> Option<bool> {
+        self.link_json
+    }
 }
 
 impl TryConvertNode<PackageContent> for RenderedNode {
@@ -105,18 +113,20 @@ impl TryConvertNode<PackageContent> for RenderedMappingNode {
         let mut libs = vec![];
         let mut bins = vec![];
         let mut includes = vec![];
+        let mut link_json = None;
         for (key, value) in self.iter() {
             let key_str = key.as_str();
             match key_str {
                 "files" => files = value.try_convert(key_str)?,
                 "site_packages" => site_packages = value.try_convert(key_str)?,
-                "libs" => libs = value.try_convert(key_str)?,
-                "bins" => bins = value.try_convert(key_str)?,
-                "includes" => includes = value.try_convert(key_str)?,
+                "libs" | "lib" => libs = value.try_convert(key_str)?,
+                "bins" | "bin" => bins = value.try_convert(key_str)?,
+                "includes" | "include" => includes = value.try_convert(key_str)?,
+                "link_json" => link_json = Some(value.try_convert(key_str)?),
                 invalid => Err(_partialerror!(
                     *key.span(),
                     ErrorKind::InvalidField(invalid.to_string().into()),
-                    help = format!("expected fields for {name} is one of `files`, `site_packages`, `libs`, `bins`, `includes`")
+                    help = format!("expected fields for {name} is one of `files`, `site_packages`, `libs`, `bins`, `includes`, `link_json`")
                 ))?
             }
         }
@@ -126,6 +136,7 @@ impl TryConvertNode<PackageContent> for RenderedMappingNode {
             bins,
             libs,
             includes,
+            link_json,
         })
     }
 }
@@ -161,9 +172,14 @@ impl Test {
         self.files.as_slice()
     }
 
-    /// Check if there is not test commands to be run
+    /// Check if there are no tests to be run in a test environment (the package content tests
+    /// run on the package itself)
     pub fn is_empty(&self) -> bool {
-        self.commands.is_empty()
+        self.imports.is_empty()
+            && self.commands.is_empty()
+            && self.requires.is_empty()
+            && self.source_files.is_empty()
+            && self.files.is_empty()
     }
 }
 
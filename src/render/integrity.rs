@@ -0,0 +1,430 @@
+//! Verification of the packages that are installed in the build and host prefixes
+//! (`--verify-prefixes`).
+//!
+//! A build script can modify the files of its dependencies, e.g. patch a header in the host
+//! prefix. When the environments are reused, the next build silently uses the modified files.
+//! Before the build script runs, the files of every package are compared with the sizes and
+//! hashes in its `conda-meta` record. Modified or missing files fail the build, or the affected
+//! packages are linked again from the package cache (`--repair-prefixes`).
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use rattler::{
+    install::{link_package, InstallDriver, InstallOptions, PythonInfo},
+    package_cache::PackageCache,
+};
+use rattler_conda_types::{Platform, PrefixRecord};
+use serde::{Deserialize, Serialize};
+
+use crate::tool_configuration;
+
+/// How thoroughly the files of the installed packages are checked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefixVerification {
+    /// The prefixes are not checked
+    #[default]
+    Off,
+    /// All files must exist and have the recorded size, and the hashes of a random sample of
+    /// the files of every package are checked
+    Sample,
+    /// The hashes of all files are checked
+    Full,
+}
+
+/// The number of files per package whose hash is checked with [`PrefixVerification::Sample`]
+const SAMPLE_SIZE: usize = 8;
+
+/// What is wrong with an installed file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileProblem {
+    /// The file was removed
+    Missing,
+    /// The size or the hash of the file differs from its record
+    Modified,
+}
+
+/// An installed package with files that do not match its `conda-meta` record
+#[derive(Debug)]
+pub struct CorruptPackage {
+    /// The record of the package
+    pub record: PrefixRecord,
+    /// The files that do not match, relative to the prefix
+    pub files: Vec<(PathBuf, FileProblem)>,
+}
+
+impl CorruptPackage {
+    fn name(&self) -> String {
+        let record = &self.record.repodata_record.package_record;
+        format!(
+            "{}-{}-{}",
+            record.name.as_normalized(),
+            record.version,
+            record.build
+        )
+    }
+}
+
+#[allow(missing_docs)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum IntegrityError {
+    #[error("Could not verify the packages in {}: {source}", prefix.display())]
+    Io {
+        prefix: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("The installed files of {packages} in {} were modified", prefix.display())]
+    #[diagnostic(help(
+        "pass `--repair-prefixes` to link the packages again from the package cache, or remove the build directory"
+    ))]
+    Corrupt { prefix: PathBuf, packages: String },
+
+    #[error("Could not repair {package} in {}: {message}", prefix.display())]
+    Repair {
+        prefix: PathBuf,
+        package: String,
+        message: String,
+    },
+}
+
+/// Compare the files of all packages in `prefix` with their `conda-meta` records, and return the
+/// packages with missing or modified files
+pub fn verify_prefix(
+    prefix: &Path,
+    verification: PrefixVerification,
+) -> Result<Vec<CorruptPackage>, std::io::Error> {
+    if verification == PrefixVerification::Off {
+        return Ok(Vec::new());
+    }
+
+    let sample_state = RandomState::new();
+    let mut corrupt = Vec::new();
+    for record in crate::post::prefix_records(prefix)? {
+        let mut files = Vec::new();
+        let mut hashed = Vec::new();
+        for entry in &record.paths_data.paths {
+            let path = prefix.join(&entry.relative_path);
+            let metadata = match path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    files.push((entry.relative_path.clone(), FileProblem::Missing));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            // symlinks and directories are only checked for existence
+            if !metadata.is_file() {
+                continue;
+            }
+            if entry
+                .size_in_bytes
+                .map_or(false, |size| size != metadata.len())
+            {
+                files.push((entry.relative_path.clone(), FileProblem::Modified));
+            } else if let Some(sha256) = entry.sha256_in_prefix.or(entry.sha256) {
+                hashed.push((&entry.relative_path, path, sha256));
+            }
+        }
+
+        if verification == PrefixVerification::Sample && hashed.len() > SAMPLE_SIZE {
+            hashed.sort_by_cached_key(|(relative_path, _, _)| {
+                let mut hasher = sample_state.build_hasher();
+                relative_path.hash(&mut hasher);
+                hasher.finish()
+            });
+            hashed.truncate(SAMPLE_SIZE);
+        }
+        for (relative_path, path, sha256) in hashed {
+            if rattler_digest::compute_file_digest::<rattler_digest::Sha256>(&path)? != sha256 {
+                files.push((relative_path.clone(), FileProblem::Modified));
+            }
+        }
+
+        if !files.is_empty() {
+            files.sort();
+            corrupt.push(CorruptPackage { record, files });
+        }
+    }
+    Ok(corrupt)
+}
+
+/// Link the `packages` into `prefix` again, from their extracted directory or the package cache
+pub async fn repair_packages(
+    prefix: &Path,
+    platform: &Platform,
+    packages: &[CorruptPackage],
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<(), IntegrityError> {
+    let repair_error = |package: &CorruptPackage, message: String| IntegrityError::Repair {
+        prefix: prefix.to_path_buf(),
+        package: package.name(),
+        message,
+    };
+    let io_error = |source| IntegrityError::Io {
+        prefix: prefix.to_path_buf(),
+        source,
+    };
+
+    // noarch python packages are linked into the site-packages of the python of the prefix
+    let records = crate::post::prefix_records(prefix).map_err(io_error)?;
+    let python_info = records
+        .iter()
+        .map(|record| &record.repodata_record.package_record)
+        .find(|record| record.name.as_normalized() == "python")
+        .map(|python| PythonInfo::from_python_record(python, *platform))
+        .transpose()
+        .map_err(|e| IntegrityError::Io {
+            prefix: prefix.to_path_buf(),
+            source: std::io::Error::new(ErrorKind::InvalidData, e),
+        })?;
+
+    let cache_dir = rattler::default_cache_dir().map_err(|e| IntegrityError::Io {
+        prefix: prefix.to_path_buf(),
+        source: std::io::Error::new(ErrorKind::NotFound, e),
+    })?;
+    let package_cache = PackageCache::new(cache_dir.join("pkgs"));
+    let install_driver = InstallDriver::default();
+
+    for package in packages {
+        let repodata_record = &package.record.repodata_record;
+        let package_dir = match &package.record.extracted_package_dir {
+            Some(dir) if dir.is_dir() => dir.clone(),
+            _ => package_cache
+                .get_or_fetch_from_url(
+                    &repodata_record.package_record,
+                    repodata_record.url.clone(),
+                    tool_configuration.client.clone(),
+                )
+                .await
+                .map_err(|e| repair_error(package, e.to_string()))?,
+        };
+
+        // all files of the package are linked again
+        for entry in &package.record.paths_data.paths {
+            let path = prefix.join(&entry.relative_path);
+            if path.is_dir() && !path.is_symlink() {
+                continue;
+            }
+            match fs_err::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(io_error(e)),
+                _ => {}
+            }
+        }
+        link_package(
+            &package_dir,
+            prefix,
+            &install_driver,
+            InstallOptions {
+                platform: Some(*platform),
+                python_info: python_info.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|e| repair_error(package, e.to_string()))?;
+
+        tracing::info!(
+            "{} Linked {} again from {}",
+            console::style(console::Emoji("✔", "")).green(),
+            package.name(),
+            package_dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Verify the packages in `prefix` as configured in the `tool_configuration`, and either repair
+/// the packages with modified files or fail
+pub async fn check_prefix(
+    prefix: &Path,
+    platform: &Platform,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<(), IntegrityError> {
+    let verification = tool_configuration.verify_prefixes;
+    if verification == PrefixVerification::Off || !prefix.exists() {
+        return Ok(());
+    }
+
+    let corrupt = verify_prefix(prefix, verification).map_err(|source| IntegrityError::Io {
+        prefix: prefix.to_path_buf(),
+        source,
+    })?;
+    if corrupt.is_empty() {
+        tracing::info!(
+            "{} The installed packages in {:?} are unmodified",
+            console::style(console::Emoji("✔", "")).green(),
+            prefix
+        );
+        return Ok(());
+    }
+
+    for package in &corrupt {
+        let files = package
+            .files
+            .iter()
+            .map(|(path, problem)| {
+                let problem = match problem {
+                    FileProblem::Missing => "missing",
+                    FileProblem::Modified => "modified",
+                };
+                format!("  - {} ({problem})", path.display())
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        tracing::warn!(
+            "The files of {} in {:?} do not match the package:\n{}",
+            package.name(),
+            prefix,
+            files
+        );
+    }
+
+    if !tool_configuration.repair_prefixes {
+        return Err(IntegrityError::Corrupt {
+            prefix: prefix.to_path_buf(),
+            packages: corrupt
+                .iter()
+                .map(CorruptPackage::name)
+                .collect::<Vec<_>>()
+                .join(", "),
+        });
+    }
+    repair_packages(prefix, platform, &corrupt, tool_configuration).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use rattler_conda_types::Platform;
+    use sha2::{Digest, Sha256};
+
+    use super::{check_prefix, verify_prefix, FileProblem, IntegrityError, PrefixVerification};
+    use crate::tool_configuration::Configuration;
+
+    const FILES: [(&str, &str); 3] = [
+        ("lib/libfoo.so", "the foo library"),
+        ("include/foo.h", "int foo();"),
+        ("share/foo/data.txt", "some data"),
+    ];
+
+    /// A prefix with `libfoo` installed, and the extracted package of `libfoo`
+    fn installed_package(root: &Path) -> PathBuf {
+        let package_dir = root.join("pkgs/libfoo-1.0.0-h1234_0");
+        let prefix = root.join("prefix");
+        let paths = FILES
+            .iter()
+            .map(|(path, content)| {
+                serde_json::json!({
+                    "_path": path,
+                    "path_type": "hardlink",
+                    "sha256": hex::encode(Sha256::digest(content)),
+                    "size_in_bytes": content.len(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for dir in [&package_dir, &prefix] {
+            for (path, content) in FILES {
+                fs_err::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+                fs_err::write(dir.join(path), content).unwrap();
+            }
+        }
+        fs_err::create_dir_all(package_dir.join("info")).unwrap();
+        let index = serde_json::json!({
+            "name": "libfoo",
+            "version": "1.0.0",
+            "build": "h1234_0",
+            "build_number": 0,
+            "subdir": "linux-64",
+            "depends": [],
+        });
+        fs_err::write(package_dir.join("info/index.json"), index.to_string()).unwrap();
+        let paths_json = serde_json::json!({ "paths_version": 1, "paths": paths });
+        fs_err::write(package_dir.join("info/paths.json"), paths_json.to_string()).unwrap();
+
+        fs_err::create_dir_all(prefix.join("conda-meta")).unwrap();
+        let record = serde_json::json!({
+            "name": "libfoo",
+            "version": "1.0.0",
+            "build": "h1234_0",
+            "build_number": 0,
+            "subdir": "linux-64",
+            "fn": "libfoo-1.0.0-h1234_0.conda",
+            "url": "https://conda.anaconda.org/conda-forge/linux-64/libfoo-1.0.0-h1234_0.conda",
+            "channel": "https://conda.anaconda.org/conda-forge/linux-64",
+            "extracted_package_dir": package_dir,
+            "files": FILES.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            "paths_data": { "paths_version": 1, "paths": paths },
+        });
+        fs_err::write(
+            prefix.join("conda-meta/libfoo-1.0.0-h1234_0.json"),
+            record.to_string(),
+        )
+        .unwrap();
+        prefix
+    }
+
+    #[test]
+    fn detect_modified_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = installed_package(tmp.path());
+        for verification in [PrefixVerification::Sample, PrefixVerification::Full] {
+            assert!(verify_prefix(&prefix, verification).unwrap().is_empty());
+        }
+
+        // the same size, but other content
+        fs_err::write(prefix.join("lib/libfoo.so"), "the bar library").unwrap();
+        fs_err::write(prefix.join("include/foo.h"), "int foo(int);").unwrap();
+        fs_err::remove_file(prefix.join("share/foo/data.txt")).unwrap();
+
+        let corrupt = verify_prefix(&prefix, PrefixVerification::Full).unwrap();
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!(corrupt[0].name(), "libfoo-1.0.0-h1234_0");
+        assert_eq!(
+            corrupt[0].files,
+            [
+                (PathBuf::from("include/foo.h"), FileProblem::Modified),
+                (PathBuf::from("lib/libfoo.so"), FileProblem::Modified),
+                (PathBuf::from("share/foo/data.txt"), FileProblem::Missing),
+            ]
+        );
+        assert!(verify_prefix(&prefix, PrefixVerification::Off)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn fail_or_repair() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = installed_package(tmp.path());
+        fs_err::write(prefix.join("include/foo.h"), "#error patched by a build").unwrap();
+
+        let mut configuration = Configuration {
+            verify_prefixes: PrefixVerification::Sample,
+            ..Default::default()
+        };
+        let result = check_prefix(&prefix, &Platform::current(), &configuration).await;
+        assert!(
+            matches!(result, Err(IntegrityError::Corrupt { packages, .. }) if packages == "libfoo-1.0.0-h1234_0")
+        );
+
+        configuration.repair_prefixes = true;
+        check_prefix(&prefix, &Platform::current(), &configuration)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs_err::read_to_string(prefix.join("include/foo.h")).unwrap(),
+            "int foo();"
+        );
+        assert!(verify_prefix(&prefix, PrefixVerification::Full)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+//! Locally built packages that replace the packages of the same name in the channels
+//! (`--use-local-package`).
+//!
+//! An override is added to the candidates of every solve, and all channel packages with the same
+//! name are removed, so the solver has to pick the override (or fail) even if a channel contains
+//! a package with the same version and build string.
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use rattler::package_cache::CacheKey;
+use rattler_conda_types::{
+    package::ArchiveIdentifier, MatchSpec, PackageName, Platform, RepoDataRecord,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    index::package_record_from_index_json,
+    package_inspect::{InspectError, PackageInspector},
+};
+
+/// Errors when reading a local package override
+#[derive(Debug, Error)]
+pub enum LocalPackageError {
+    #[error("Could not read the local package `{0}`: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("Could not inspect the local package `{0}`: {1}")]
+    Inspect(PathBuf, InspectError),
+
+    #[error("`{0}` and `{1}` are both overrides for the package `{2}`")]
+    Duplicate(PathBuf, PathBuf, String),
+}
+
+/// A locally built package that replaces the packages of the same name in the channels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalPackageOverride {
+    /// The path of the package archive
+    pub path: PathBuf,
+    /// The record of the package, with a `file://` URL of the archive
+    pub record: RepoDataRecord,
+}
+
+impl LocalPackageOverride {
+    /// Read the metadata of the package archive at `path`
+    pub fn from_path(path: &Path) -> Result<Self, LocalPackageError> {
+        let io_error = |e| LocalPackageError::Io(path.to_path_buf(), e);
+        let path = dunce::canonicalize(path).map_err(io_error)?;
+
+        let index_json = PackageInspector::open(&path)
+            .and_then(|package| package.index_json())
+            .map_err(|e| LocalPackageError::Inspect(path.clone(), e))?;
+        let package_record = package_record_from_index_json(&path, index_json).map_err(io_error)?;
+
+        let url = Url::from_file_path(&path).expect("canonical paths are absolute");
+        let channel = path
+            .parent()
+            .and_then(|dir| Url::from_directory_path(dir).ok())
+            .map(|url| url.to_string())
+            .unwrap_or_default();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Ok(Self {
+            path,
+            record: RepoDataRecord {
+                package_record,
+                file_name,
+                url,
+                channel,
+            },
+        })
+    }
+
+    /// Read all overrides. There can only be one override per package name.
+    pub fn from_paths(paths: &[PathBuf]) -> Result<Vec<Self>, LocalPackageError> {
+        let mut overrides: Vec<Self> = Vec::with_capacity(paths.len());
+        for path in paths {
+            let new = Self::from_path(path)?;
+            if let Some(existing) = overrides.iter().find(|o| o.name() == new.name()) {
+                return Err(LocalPackageError::Duplicate(
+                    existing.path.clone(),
+                    new.path,
+                    new.name().as_normalized().to_string(),
+                ));
+            }
+            overrides.push(new);
+        }
+        Ok(overrides)
+    }
+
+    /// The name of the package
+    pub fn name(&self) -> &PackageName {
+        &self.record.package_record.name
+    }
+
+    /// Whether the package can be installed in an environment for `platform`
+    pub fn is_for_platform(&self, platform: &Platform) -> bool {
+        let subdir = &self.record.package_record.subdir;
+        *subdir == platform.to_string() || *subdir == Platform::NoArch.to_string()
+    }
+
+    /// The names of the dependencies of the package. They have to be loaded from the channels in
+    /// addition to the dependencies of the packages that the override replaces.
+    pub fn dependency_names(&self) -> impl Iterator<Item = PackageName> + '_ {
+        self.record
+            .package_record
+            .depends
+            .iter()
+            .filter_map(|dep| MatchSpec::from_str(dep).ok()?.name)
+    }
+
+    /// Remove the package from the package cache if it is cached. The cache is keyed by name,
+    /// version and build string, so a cached channel package with the same key would otherwise
+    /// be installed instead of the override.
+    pub fn remove_from_cache(&self, cache_dir: &Path) -> Result<(), std::io::Error> {
+        let Some(identifier) = ArchiveIdentifier::try_from_path(&self.path) else {
+            return Ok(());
+        };
+        let cached = cache_dir
+            .join("pkgs")
+            .join(CacheKey::from(identifier).to_string());
+        if cached.exists() {
+            tracing::debug!("Removing {:?} from the package cache", cached);
+            fs_err::remove_dir_all(cached)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the records of the packages that have an override for `platform` with the overrides
+pub fn apply_overrides(
+    repodatas: &mut Vec<Vec<RepoDataRecord>>,
+    overrides: &[LocalPackageOverride],
+    platform: &Platform,
+) {
+    let overrides = overrides
+        .iter()
+        .filter(|o| o.is_for_platform(platform))
+        .collect::<Vec<_>>();
+    if overrides.is_empty() {
+        return;
+    }
+
+    for records in repodatas.iter_mut() {
+        records.retain(|record| {
+            !overrides
+                .iter()
+                .any(|o| o.name() == &record.package_record.name)
+        });
+    }
+    repodatas.push(overrides.iter().map(|o| o.record.clone()).collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rattler_conda_types::{
+        MatchSpec, NoArchType, PackageName, PackageRecord, Platform, RepoDataRecord,
+        VersionWithSource,
+    };
+    use rattler_solve::{resolvo::Solver, SolverImpl, SolverTask};
+
+    use super::{apply_overrides, LocalPackageOverride};
+
+    fn record(name: &str, version: &str, build: &str, channel: &str) -> RepoDataRecord {
+        let file_name = format!("{name}-{version}-{build}.conda");
+        RepoDataRecord {
+            package_record: PackageRecord {
+                arch: None,
+                build: build.into(),
+                build_number: 0,
+                constrains: vec![],
+                depends: vec![],
+                features: None,
+                legacy_bz2_md5: None,
+                legacy_bz2_size: None,
+                license: None,
+                license_family: None,
+                md5: None,
+                name: PackageName::from_str(name).unwrap(),
+                noarch: NoArchType::none(),
+                platform: None,
+                sha256: None,
+                size: None,
+                subdir: "linux-64".into(),
+                timestamp: None,
+                track_features: vec![],
+                version: VersionWithSource::from_str(version).unwrap(),
+                purls: Default::default(),
+            },
+            url: format!("{channel}linux-64/{file_name}").parse().unwrap(),
+            channel: channel.to_string(),
+            file_name,
+        }
+    }
+
+    fn solve(repodatas: &[Vec<RepoDataRecord>], spec: &str) -> Vec<RepoDataRecord> {
+        Solver
+            .solve(SolverTask {
+                available_packages: repodatas,
+                locked_packages: Vec::new(),
+                pinned_packages: Vec::new(),
+                virtual_packages: Vec::new(),
+                specs: vec![MatchSpec::from_str(spec).unwrap()],
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn override_shadows_channel_package() {
+        let channel = "https://conda.anaconda.org/conda-forge/";
+        let mut repodatas = vec![vec![
+            record("foo", "1.0", "h123_0", channel),
+            record("foo", "2.0", "h123_0", channel),
+            record("bar", "1.0", "0", channel),
+        ]];
+        // without the override, the newest channel package is used
+        assert_eq!(
+            solve(&repodatas, "foo")[0].url.as_str(),
+            "https://conda.anaconda.org/conda-forge/linux-64/foo-2.0-h123_0.conda"
+        );
+
+        // the override has the same version and build string as a channel package
+        let local = LocalPackageOverride {
+            path: "/local/linux-64/foo-1.0-h123_0.conda".into(),
+            record: record("foo", "1.0", "h123_0", "file:///local/"),
+        };
+        apply_overrides(&mut repodatas, &[local.clone()], &Platform::Linux64);
+        assert_eq!(repodatas.iter().flatten().count(), 2);
+
+        let solved = solve(&repodatas, "foo");
+        assert_eq!(solved.len(), 1);
+        assert_eq!(
+            solved[0].url.as_str(),
+            "file:///local/linux-64/foo-1.0-h123_0.conda"
+        );
+
+        // overrides for other platforms are ignored
+        let mut other = vec![vec![record("foo", "2.0", "h123_0", channel)]];
+        apply_overrides(&mut other, &[local], &Platform::Win64);
+        assert_eq!(other.iter().flatten().count(), 1);
+        assert_eq!(solve(&other, "foo")[0].channel, channel);
+    }
+}
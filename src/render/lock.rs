@@ -0,0 +1,280 @@
+//! Lock files with the finalized dependencies of a built package (`--write-lock-file` and
+//! `--lock-file`).
+//!
+//! A lock file is written next to the package (`<name>-<version>-<build>.lock.yaml`). It stores
+//! the exact records (with their URLs and hashes) of the build and host environments and the
+//! final run requirements, together with the requirements of the recipe they were resolved from.
+//! A later build with the lock file installs the same packages instead of resolving the
+//! dependencies again, and fails if the recipe no longer matches the lock file.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use rattler_conda_types::{package::ArchiveType, Platform};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::resolved_dependencies::FinalizedDependencies;
+use crate::{metadata::Output, recipe::parser::Dependency};
+
+/// Errors when writing, reading or applying a lock file
+#[allow(missing_docs)]
+#[derive(Debug, Error, miette::Diagnostic)]
+pub enum LockFileError {
+    #[error("Could not access the lock file `{}`: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Could not parse the lock file `{}`: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+
+    #[error("Could not serialize the lock file `{}`: {source}", path.display())]
+    Serialize {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+
+    #[error("The output `{name}` does not match the lock file `{}` anymore:\n{diff}", path.display())]
+    #[diagnostic(help("build without `--lock-file` to resolve the dependencies again"))]
+    Outdated {
+        name: String,
+        path: PathBuf,
+        diff: String,
+    },
+}
+
+/// The contents of a lock file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockFile {
+    /// The version of rattler-build that wrote the lock file
+    pub rattler_build_version: String,
+    /// The name of the package
+    pub name: String,
+    /// The version of the package
+    pub version: String,
+    /// The build string of the package
+    pub build_string: String,
+    /// The target platform of the package
+    pub target_platform: Platform,
+    /// The requirements of the recipe (`build`, `host`, `run` and `run_constrained`) that the
+    /// dependencies were resolved from
+    pub requirements: BTreeMap<String, Vec<String>>,
+    /// The resolved dependencies
+    pub finalized_dependencies: FinalizedDependencies,
+}
+
+/// The requirements of the recipe of `output`, by list
+fn recipe_requirements(output: &Output) -> BTreeMap<String, Vec<String>> {
+    let requirements = output.recipe.requirements();
+    let render = |deps: &[Dependency]| deps.iter().map(ToString::to_string).collect();
+    BTreeMap::from([
+        ("build".to_string(), render(requirements.build())),
+        ("host".to_string(), render(requirements.host())),
+        ("run".to_string(), render(requirements.run())),
+        (
+            "run_constrained".to_string(),
+            render(requirements.run_constrained()),
+        ),
+    ])
+}
+
+impl LockFile {
+    /// The lock file of an output with finalized dependencies
+    pub fn from_output(output: &Output) -> Option<Self> {
+        Some(Self {
+            rattler_build_version: env!("CARGO_PKG_VERSION").to_string(),
+            name: output.name().as_normalized().to_string(),
+            version: output.version().to_string(),
+            build_string: output.build_string()?.to_string(),
+            target_platform: output.build_configuration.target_platform,
+            requirements: recipe_requirements(output),
+            finalized_dependencies: output.finalized_dependencies.clone()?,
+        })
+    }
+
+    /// Read the lock file at `path`
+    pub fn read(path: &Path) -> Result<Self, LockFileError> {
+        let content = fs_err::read_to_string(path).map_err(|source| LockFileError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        serde_yaml::from_str(&content).map_err(|source| LockFileError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// The differences between the lock file and `output`, one line per difference (`-` for
+    /// the lock file, `+` for the output)
+    pub fn diff(&self, output: &Output) -> Vec<String> {
+        let mut diff = Vec::new();
+        let mut compare = |field: &str, locked: &str, current: &str| {
+            if locked != current {
+                diff.push(format!("- {field}: {locked}"));
+                diff.push(format!("+ {field}: {current}"));
+            }
+        };
+        compare("version", &self.version, output.version());
+        compare(
+            "build_string",
+            &self.build_string,
+            output.build_string().unwrap_or_default(),
+        );
+
+        for (list, current) in recipe_requirements(output) {
+            let locked = self.requirements.get(&list).cloned().unwrap_or_default();
+            for removed in locked.iter().filter(|dep| !current.contains(dep)) {
+                diff.push(format!("- requirements.{list}: {removed}"));
+            }
+            for added in current.iter().filter(|dep| !locked.contains(dep)) {
+                diff.push(format!("+ requirements.{list}: {added}"));
+            }
+        }
+        diff
+    }
+}
+
+/// The path of the lock file of the package at `package`
+pub fn lock_file_path(package: &Path) -> PathBuf {
+    let package = package.to_string_lossy();
+    let base = ArchiveType::split_str(&package)
+        .map(|(base, _)| base)
+        .unwrap_or(package.as_ref());
+    PathBuf::from(format!("{base}.lock.yaml"))
+}
+
+/// Write the lock file of `output` next to its `package`, and return its path
+pub fn write_lock_file(output: &Output, package: &Path) -> Result<Option<PathBuf>, LockFileError> {
+    let Some(lock_file) = LockFile::from_output(output) else {
+        return Ok(None);
+    };
+    let path = lock_file_path(package);
+    let content = serde_yaml::to_string(&lock_file).map_err(|source| LockFileError::Serialize {
+        path: path.clone(),
+        source,
+    })?;
+    fs_err::write(&path, content).map_err(|source| LockFileError::Io {
+        path: path.clone(),
+        source,
+    })?;
+    Ok(Some(path))
+}
+
+/// Use the finalized dependencies of the lock file for `output`, if one of the `lock_files` is
+/// for its name and target platform. Fails if none of those lock files matches the output.
+/// Returns whether a lock file was applied.
+pub fn apply_lock_files(
+    output: &mut Output,
+    lock_files: &[(PathBuf, LockFile)],
+) -> Result<bool, LockFileError> {
+    let candidates = lock_files
+        .iter()
+        .filter(|(_, lock)| {
+            lock.name == output.name().as_normalized()
+                && lock.target_platform == output.build_configuration.target_platform
+        })
+        .map(|(path, lock)| (path, lock, lock.diff(output)))
+        .collect::<Vec<_>>();
+
+    if let Some((path, lock, _)) = candidates.iter().find(|(_, _, diff)| diff.is_empty()) {
+        tracing::info!("Using the dependencies of the lock file {}", path.display());
+        output.finalized_dependencies = Some(lock.finalized_dependencies.clone());
+        return Ok(true);
+    }
+    match candidates.into_iter().next() {
+        Some((path, _, diff)) => Err(LockFileError::Outdated {
+            name: output.name().as_normalized().to_string(),
+            path: path.clone(),
+            diff: diff.join("\n"),
+        }),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{apply_lock_files, lock_file_path, write_lock_file, LockFile, LockFileError};
+    use crate::metadata::Output;
+
+    fn rich_output() -> Output {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/rendered_recipes/rich_recipe.yaml");
+        serde_yaml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn lock_file_paths() {
+        assert_eq!(
+            lock_file_path(Path::new("output/noarch/rich-13.4.2-pyh4616a5c_0.tar.bz2")),
+            PathBuf::from("output/noarch/rich-13.4.2-pyh4616a5c_0.lock.yaml")
+        );
+        assert_eq!(
+            lock_file_path(Path::new("output/linux-64/foo-1.0-h123_0.conda")),
+            PathBuf::from("output/linux-64/foo-1.0-h123_0.lock.yaml")
+        );
+    }
+
+    #[test]
+    fn reuse_lock_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = rich_output();
+        let package = tmp.path().join("rich-13.4.2-pyh4616a5c_0.tar.bz2");
+        let path = write_lock_file(&output, &package).unwrap().unwrap();
+        let lock_files = vec![(path.clone(), LockFile::read(&path).unwrap())];
+
+        let mut unresolved = Output {
+            finalized_dependencies: None,
+            ..output.clone()
+        };
+        assert!(apply_lock_files(&mut unresolved, &lock_files).unwrap());
+        assert_eq!(
+            serde_yaml::to_string(&unresolved.finalized_dependencies).unwrap(),
+            serde_yaml::to_string(&output.finalized_dependencies).unwrap()
+        );
+
+        // a lock file of another package does not apply
+        let mut other = lock_files[0].1.clone();
+        other.name = "other".to_string();
+        let mut unresolved = Output {
+            finalized_dependencies: None,
+            ..output
+        };
+        assert!(!apply_lock_files(&mut unresolved, &[(path, other)]).unwrap());
+        assert!(unresolved.finalized_dependencies.is_none());
+    }
+
+    #[test]
+    fn changed_requirements() {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = rich_output();
+        let path = write_lock_file(&output, &tmp.path().join("rich-13.4.2-pyh4616a5c_0.conda"))
+            .unwrap()
+            .unwrap();
+        // the lock file was written for an older version of the recipe
+        let mut lock_file = LockFile::read(&path).unwrap();
+        lock_file.requirements.get_mut("run").unwrap()[0] = "markdown-it-py >=1".to_string();
+
+        let mut changed = Output {
+            finalized_dependencies: None,
+            ..output
+        };
+        let err = apply_lock_files(&mut changed, &[(path, lock_file)]).unwrap_err();
+        let LockFileError::Outdated { diff, .. } = err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(
+            diff,
+            "- requirements.run: markdown-it-py >=1\n+ requirements.run: markdown-it-py >=2.2.0"
+        );
+        assert!(changed.finalized_dependencies.is_none());
+    }
+}
@@ -1,6 +1,10 @@
 #![allow(missing_docs)]
 //! Render the dependencies to a final recipe
 
+pub mod integrity;
+pub mod local_packages;
+pub mod lock;
 pub mod pin;
 pub mod resolved_dependencies;
+pub mod reuse;
 pub mod solver;
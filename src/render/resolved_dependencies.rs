@@ -2,8 +2,9 @@ use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
     fs,
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{Arc, Mutex},
 };
 
 use crate::{
@@ -11,6 +12,7 @@ use crate::{
     tool_configuration,
 };
 use indicatif::HumanBytes;
+use itertools::Itertools;
 use rattler::package_cache::CacheKey;
 use rattler_conda_types::{
     package::{PackageFile, RunExportsJson},
@@ -20,8 +22,12 @@ use rattler_conda_types::{version_spec::ParseVersionSpecError, PackageRecord};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use super::{pin::PinError, solver::create_environment};
-use crate::recipe::parser::Dependency;
+use super::{
+    local_packages::{LocalPackageError, LocalPackageOverride},
+    pin::PinError,
+    solver::create_environment,
+};
+use crate::recipe::parser::{Dependency, IgnoreRunExports, RecipeLocation, Requirements};
 use crate::render::solver::install_packages;
 use serde_with::{serde_as, DisplayFromStr};
 
@@ -95,20 +101,197 @@ impl DependencyInfo {
     }
 }
 
+/// Where an entry of the final run requirements comes from. Recipe locations refer to the
+/// rendered recipe (after the `if` selectors are applied), e.g. `requirements.run[0]`, the
+/// position in the recipe file is the `recipe_location` of the [`DependencyProvenance`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "origin", rename_all = "snake_case")]
+pub enum DependencyOrigin {
+    /// Written in the recipe at `location`
+    Recipe { location: String },
+    /// Written in the recipe at `location`, with the version of the `variant`
+    Variant { location: String, variant: String },
+    /// A `pin_subpackage` in the recipe at `location`
+    PinSubpackage { location: String },
+    /// A `pin_compatible` in the recipe at `location`
+    PinCompatible { location: String },
+    /// A `compiler` in the recipe at `location`
+    Compiler { location: String },
+    /// A run export of `package` from the `env` (`build` or `host`) environment
+    RunExport { env: String, package: String },
+}
+
+impl Display for DependencyOrigin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyOrigin::Recipe { location } => write!(f, "{location}"),
+            DependencyOrigin::Variant { location, variant } => {
+                write!(f, "{location} (variant {variant})")
+            }
+            DependencyOrigin::PinSubpackage { location } => {
+                write!(f, "{location} (pin_subpackage)")
+            }
+            DependencyOrigin::PinCompatible { location } => {
+                write!(f, "{location} (pin_compatible)")
+            }
+            DependencyOrigin::Compiler { location } => write!(f, "{location} (compiler)"),
+            DependencyOrigin::RunExport { env, package } => {
+                write!(f, "run export of {package} ({env})")
+            }
+        }
+    }
+}
+
+/// An entry of the final run requirements and where it comes from
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DependencyProvenance {
+    #[serde_as(as = "DisplayFromStr")]
+    pub spec: MatchSpec,
+    /// The list of the entry, `run` or `run_constrained`
+    pub list: String,
+    #[serde(flatten)]
+    pub origin: DependencyOrigin,
+    /// The position of the entry in the recipe file, if it is written in the recipe
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipe_location: Option<RecipeLocation>,
+}
+
+impl Display for DependencyProvenance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.recipe_location {
+            Some(location) => write!(f, "{} at {}", self.origin, location),
+            None => write!(f, "{}", self.origin),
+        }
+    }
+}
+
+/// An entry of the run requirements of a recipe whose dependencies are not resolved yet, for
+/// `--render-only`. The run exports are only known once the environments are resolved.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecipeRequirement {
+    /// The entry as written in the recipe (after the selectors are applied)
+    pub dependency: String,
+    /// The list of the entry, `run` or `run_constrained`
+    pub list: String,
+    /// The location of the entry in the rendered recipe, e.g. `requirements.run[0]`
+    pub location: String,
+    /// The position of the entry in the recipe file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipe_location: Option<RecipeLocation>,
+}
+
+/// The `run` and `run_constrained` entries of the `requirements` and where they are written
+pub fn recipe_requirements(requirements: &Requirements) -> Vec<RecipeRequirement> {
+    let locations = requirements.locations();
+    [
+        ("run", requirements.run(), &locations.run),
+        (
+            "run_constrained",
+            requirements.run_constrained(),
+            &locations.run_constrained,
+        ),
+    ]
+    .into_iter()
+    .flat_map(|(list, dependencies, locations)| {
+        dependencies
+            .iter()
+            .enumerate()
+            .map(move |(index, dependency)| RecipeRequirement {
+                dependency: dependency.to_string(),
+                list: list.to_string(),
+                location: format!("requirements.{list}[{index}]"),
+                recipe_location: locations.get(index).copied().flatten(),
+            })
+    })
+    .collect()
+}
+
+/// The provenance of the entries of a final run requirements `list`, of which the first
+/// `from_recipe` entries are the ones of the recipe, written at `locations` in the recipe file
+fn provenance(
+    dependencies: &[DependencyInfo],
+    list: &str,
+    from_recipe: usize,
+    locations: &[Option<RecipeLocation>],
+) -> Vec<DependencyProvenance> {
+    dependencies
+        .iter()
+        .enumerate()
+        .map(|(index, dependency)| {
+            let location = if index < from_recipe {
+                format!("requirements.{list}[{index}]")
+            } else {
+                format!("requirements.{list}")
+            };
+            let origin = match dependency {
+                DependencyInfo::Raw { .. } => DependencyOrigin::Recipe { location },
+                DependencyInfo::Variant { variant, .. } => DependencyOrigin::Variant {
+                    location,
+                    variant: variant.clone(),
+                },
+                DependencyInfo::PinSubpackage { .. } => {
+                    DependencyOrigin::PinSubpackage { location }
+                }
+                DependencyInfo::PinCompatible { .. } => {
+                    DependencyOrigin::PinCompatible { location }
+                }
+                DependencyInfo::Compiler { .. } => DependencyOrigin::Compiler { location },
+                DependencyInfo::RunExport {
+                    from,
+                    source_package,
+                    ..
+                } => DependencyOrigin::RunExport {
+                    env: from.clone(),
+                    package: source_package.clone(),
+                },
+            };
+            let recipe_location = if index < from_recipe {
+                locations.get(index).copied().flatten()
+            } else {
+                None
+            };
+            DependencyProvenance {
+                spec: dependency.spec().clone(),
+                list: list.to_string(),
+                origin,
+                recipe_location,
+            }
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FinalizedRunDependencies {
     pub depends: Vec<DependencyInfo>,
     pub constrains: Vec<DependencyInfo>,
     pub run_exports: Option<RunExportsJson>,
+    /// Where every entry of `depends` and `constrains` comes from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provenance: Vec<DependencyProvenance>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResolvedDependencies {
     pub specs: Vec<DependencyInfo>,
     pub resolved: Vec<RepoDataRecord>,
+    #[serde(serialize_with = "serialize_sorted_run_exports")]
     pub run_exports: HashMap<PackageName, RunExportsJson>,
 }
 
+/// Serialize the run exports sorted by the package name, so that a rendered output does not
+/// change between runs
+fn serialize_sorted_run_exports<S: serde::Serializer>(
+    run_exports: &HashMap<PackageName, RunExportsJson>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        run_exports
+            .iter()
+            .sorted_by(|(a, _), (b, _)| a.as_normalized().cmp(b.as_normalized())),
+    )
+}
+
 fn short_channel(channel: &str) -> String {
     if channel.contains('/') {
         channel
@@ -198,6 +381,22 @@ pub struct FinalizedDependencies {
     pub build: Option<ResolvedDependencies>,
     pub host: Option<ResolvedDependencies>,
     pub run: FinalizedRunDependencies,
+    /// The local packages (`--use-local-package`) that were installed in the build or host
+    /// environment instead of the channel packages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub local_package_overrides: Vec<LocalPackageOverride>,
+}
+
+impl FinalizedDependencies {
+    /// Explain why the package depends on (or is constrained by) `name`: the entries of the final
+    /// run requirements for `name` and where they come from
+    pub fn explain_dependency(&self, name: &PackageName) -> Vec<&DependencyProvenance> {
+        self.run
+            .provenance
+            .iter()
+            .filter(|p| p.spec.name.as_ref() == Some(name))
+            .collect()
+    }
 }
 
 #[derive(Error, Debug)]
@@ -220,6 +419,9 @@ pub enum ResolveError {
     #[error("Could not parse match spec: {0}")]
     MatchSpecParseError(#[from] rattler_conda_types::ParseMatchSpecError),
 
+    #[error(transparent)]
+    LocalPackage(#[from] LocalPackageError),
+
     #[error("Could not parse build string matcher: {0}")]
     StringMatcherParseError(#[from] rattler_conda_types::StringMatcherParseError),
 
@@ -377,9 +579,69 @@ pub fn apply_variant(
         .collect()
 }
 
+/// The run exports `specs` of the package `name` in the `env` (`build` or `host`) environment,
+/// without the ones of the packages that the recipe ignores (`ignore_run_exports.by_name`)
+fn run_export_specs(
+    name: &PackageName,
+    env: &str,
+    specs: &[String],
+    ignore_run_exports: &IgnoreRunExports,
+) -> Result<Vec<DependencyInfo>, ResolveError> {
+    let mut run_exports = Vec::new();
+    for spec in specs {
+        let spec = MatchSpec::from_str(spec)?;
+        if spec.name.as_ref().map_or(false, |exported| {
+            ignore_run_exports.by_name().contains(exported)
+        }) {
+            tracing::info!(
+                "Ignoring the run export {} of {} ({})",
+                spec,
+                name.as_normalized(),
+                env
+            );
+            continue;
+        }
+        tracing::info!(
+            "Adding the run export {} of {} ({})",
+            spec,
+            name.as_normalized(),
+            env
+        );
+        run_exports.push(DependencyInfo::RunExport {
+            spec,
+            from: env.to_string(),
+            source_package: name.as_normalized().to_string(),
+        });
+    }
+    Ok(run_exports)
+}
+
+/// The run exports of the packages that were resolved, keyed by the directory of the package in
+/// the package cache. The outputs and variants of a recipe mostly resolve the same packages, so
+/// the `run_exports.json` of a package is only read once per invocation.
+#[derive(Debug, Clone, Default)]
+pub struct RunExportsCache(Arc<Mutex<HashMap<PathBuf, Option<RunExportsJson>>>>);
+
+impl RunExportsCache {
+    /// The run exports of the extracted package in `package_dir`, or `None` if it has none. A
+    /// package that is not extracted yet is not cached.
+    pub fn get(&self, package_dir: &Path) -> Option<RunExportsJson> {
+        if !package_dir.is_dir() {
+            return None;
+        }
+        self.0
+            .lock()
+            .unwrap()
+            .entry(package_dir.to_path_buf())
+            .or_insert_with(|| RunExportsJson::from_package_directory(package_dir).ok())
+            .clone()
+    }
+}
+
 fn collect_run_exports_from_env(
     env: &[RepoDataRecord],
     cache_dir: &Path,
+    run_exports_cache: &RunExportsCache,
     filter: impl Fn(&RepoDataRecord) -> bool,
 ) -> Result<HashMap<PackageName, RunExportsJson>, std::io::Error> {
     let mut run_exports = HashMap::new();
@@ -390,8 +652,7 @@ fn collect_run_exports_from_env(
 
         let cache_key: CacheKey = Into::into(&pkg.package_record);
         let pkc = cache_dir.join(cache_key.to_string());
-        let rex = RunExportsJson::from_package_directory(pkc).ok();
-        if let Some(rex) = rex {
+        if let Some(rex) = run_exports_cache.get(&pkc) {
             run_exports.insert(pkg.package_record.name.clone(), rex);
         }
     }
@@ -434,6 +695,37 @@ pub async fn install_environments(
     Ok(())
 }
 
+/// Resolve the dependencies of every output that are not finalized yet, with the output
+/// directory as the first channel (if it has been indexed), like a build does. Nothing is
+/// written to the output directory.
+pub async fn finalize_outputs(
+    outputs: &[Output],
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<Vec<Output>, ResolveError> {
+    let mut finalized = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        if output.finalized_dependencies.is_some() {
+            finalized.push(output.clone());
+            continue;
+        }
+        // the outputs are only rendered, so the output directory is not indexed: it is only used
+        // as a channel if an earlier build wrote its `repodata.json`
+        let output_dir = &output.build_configuration.directories.output_dir;
+        let mut channels = Vec::new();
+        if output_dir.join("noarch").join("repodata.json").is_file() {
+            channels.push(output_dir.to_string_lossy().to_string());
+        }
+        channels.extend(output.build_configuration.channels.iter().cloned());
+        let finalized_dependencies =
+            resolve_dependencies(output, &channels, tool_configuration.clone()).await?;
+        finalized.push(Output {
+            finalized_dependencies: Some(finalized_dependencies),
+            ..output.clone()
+        });
+    }
+    Ok(finalized)
+}
+
 /// This function resolves the dependencies of a recipe.
 /// To do this, we have to run a couple of steps:
 ///
@@ -452,6 +744,7 @@ pub async fn resolve_dependencies(
     let pkgs_dir = cache_dir.join("pkgs");
 
     let reqs = &output.recipe.requirements();
+    let ignore_run_exports = reqs.ignore_run_exports();
     let mut compatibility_specs = HashMap::new();
 
     let build_env = if !reqs.build.is_empty() {
@@ -473,19 +766,19 @@ pub async fn resolve_dependencies(
         .await
         .map_err(ResolveError::from)?;
 
-        let run_exports = collect_run_exports_from_env(&env, &pkgs_dir, |rec| {
-            let res = match_specs
-                .iter()
-                .any(|m| Some(&rec.package_record.name) == m.name.as_ref());
-
-            let ignore_run_exports_from = output
-                .recipe
-                .requirements()
-                .ignore_run_exports()
-                .from_package();
-
-            res && !ignore_run_exports_from.contains(&rec.package_record.name)
-        })
+        let run_exports = collect_run_exports_from_env(
+            &env,
+            &pkgs_dir,
+            &tool_configuration.run_exports_cache,
+            |rec| {
+                match_specs
+                    .iter()
+                    .any(|m| Some(&rec.package_record.name) == m.name.as_ref())
+                    && !ignore_run_exports
+                        .from_package()
+                        .contains(&rec.package_record.name)
+            },
+        )
         .map_err(ResolveError::CouldNotCollectRunExports)?;
 
         env.iter().for_each(|r| {
@@ -514,17 +807,7 @@ pub async fn resolve_dependencies(
                        env: &str,
                        specs: &[String]|
      -> Result<Vec<DependencyInfo>, ResolveError> {
-        let mut cloned = Vec::new();
-        for spec in specs {
-            let spec = MatchSpec::from_str(spec)?;
-            let dep = DependencyInfo::RunExport {
-                spec,
-                from: env.to_string(),
-                source_package: name.as_normalized().to_string(),
-            };
-            cloned.push(dep);
-        }
-        Ok(cloned)
+        run_export_specs(name, env, specs, ignore_run_exports)
     };
 
     // add the run exports of the build environment
@@ -547,11 +830,19 @@ pub async fn resolve_dependencies(
         .await
         .map_err(ResolveError::from)?;
 
-        let run_exports = collect_run_exports_from_env(&env, &pkgs_dir, |rec| {
-            match_specs
-                .iter()
-                .any(|m| Some(&rec.package_record.name) == m.name.as_ref())
-        })
+        let run_exports = collect_run_exports_from_env(
+            &env,
+            &pkgs_dir,
+            &tool_configuration.run_exports_cache,
+            |rec| {
+                match_specs
+                    .iter()
+                    .any(|m| Some(&rec.package_record.name) == m.name.as_ref())
+                    && !ignore_run_exports
+                        .from_package()
+                        .contains(&rec.package_record.name)
+            },
+        )
         .map_err(ResolveError::CouldNotCollectRunExports)?;
 
         env.iter().for_each(|r| {
@@ -607,6 +898,7 @@ pub async fn resolve_dependencies(
         depends,
         constrains,
         run_exports,
+        provenance: Vec::new(),
     };
 
     // Propagate run exports from host env to run env
@@ -657,10 +949,33 @@ pub async fn resolve_dependencies(
         }
     }
 
+    let locations = reqs.locations();
+    run_specs.provenance = provenance(&run_specs.depends, "run", reqs.run.len(), &locations.run);
+    run_specs.provenance.extend(provenance(
+        &run_specs.constrains,
+        "run_constrained",
+        reqs.run_constrained.len(),
+        &locations.run_constrained,
+    ));
+
+    // Record which local packages were used, so that the rendered recipe shows where they came
+    // from
+    let local_package_overrides =
+        LocalPackageOverride::from_paths(&tool_configuration.local_package_overrides)?
+            .into_iter()
+            .filter(|local| {
+                [&build_env, &host_env]
+                    .into_iter()
+                    .flatten()
+                    .any(|env| env.resolved.iter().any(|r| r.url == local.record.url))
+            })
+            .collect();
+
     Ok(FinalizedDependencies {
         build: build_env,
         host: host_env,
         run: run_specs,
+        local_package_overrides,
     })
 }
 
@@ -701,4 +1016,209 @@ mod tests {
         assert!(matches!(dep_info[3], DependencyInfo::PinSubpackage { .. }));
         assert!(matches!(dep_info[4], DependencyInfo::PinCompatible { .. }));
     }
+
+    #[test]
+    fn ignored_run_exports() {
+        let recipe = crate::recipe::Recipe::from_yaml(
+            r#"
+            package:
+              name: test
+              version: 0.1.0
+            requirements:
+              ignore_run_exports:
+                by_name:
+                  - libgcc-ng
+            "#,
+            crate::recipe::jinja::SelectorConfig::default(),
+        )
+        .unwrap();
+        let ignore_run_exports = recipe.requirements().ignore_run_exports();
+
+        let specs = run_export_specs(
+            &PackageName::from_str("gcc_impl_linux-64").unwrap(),
+            "build",
+            &["libgcc-ng >=12".to_string(), "libgomp >=12".to_string()],
+            ignore_run_exports,
+        )
+        .unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(
+            specs[0].render(),
+            "libgomp >=12 (RE of [build: gcc_impl_linux-64])"
+        );
+    }
+
+    #[test]
+    fn test_dependency_provenance() {
+        let spec = |s: &str| MatchSpec::from_str(s).unwrap();
+        // the run requirements of the recipe, followed by the run exports of the environments
+        let depends = vec![
+            DependencyInfo::Raw {
+                spec: spec("zlib >=1.2"),
+            },
+            DependencyInfo::Variant {
+                spec: spec("python 3.11.*"),
+                variant: "3.11".to_string(),
+            },
+            DependencyInfo::PinSubpackage {
+                spec: spec("libfoo ==1.0 h123_0"),
+            },
+            DependencyInfo::PinCompatible {
+                spec: spec("numpy >=1.26,<2"),
+            },
+            DependencyInfo::RunExport {
+                spec: spec("libstdcxx-ng >=12"),
+                from: "build".to_string(),
+                source_package: "gxx_impl_linux-64".to_string(),
+            },
+            DependencyInfo::RunExport {
+                spec: spec("zlib >=1.2.13,<1.3.0a0"),
+                from: "host".to_string(),
+                source_package: "zlib".to_string(),
+            },
+        ];
+        let constrains = vec![
+            DependencyInfo::Raw {
+                spec: spec("bar <2"),
+            },
+            DependencyInfo::RunExport {
+                spec: spec("python_abi 3.11.* *_cp311"),
+                from: "host".to_string(),
+                source_package: "python".to_string(),
+            },
+        ];
+
+        let at = |line| Some(RecipeLocation { line, column: 7 });
+        let mut provenance =
+            super::provenance(&depends, "run", 4, &[at(12), at(13), at(14), at(15)]);
+        provenance.extend(super::provenance(
+            &constrains,
+            "run_constrained",
+            1,
+            &[at(17)],
+        ));
+        insta::assert_yaml_snapshot!(provenance);
+
+        let finalized = FinalizedDependencies {
+            build: None,
+            host: None,
+            run: FinalizedRunDependencies {
+                depends,
+                constrains,
+                run_exports: None,
+                provenance,
+            },
+            local_package_overrides: Vec::new(),
+        };
+        let zlib = finalized.explain_dependency(&PackageName::from_str("zlib").unwrap());
+        assert_eq!(zlib.len(), 2);
+        assert_eq!(
+            zlib[0].origin,
+            DependencyOrigin::Recipe {
+                location: "requirements.run[0]".to_string()
+            }
+        );
+        assert_eq!(
+            zlib[0].to_string(),
+            "requirements.run[0] at line 12, column 7".to_string()
+        );
+        assert_eq!(
+            zlib[1].origin.to_string(),
+            "run export of zlib (host)".to_string()
+        );
+        assert!(finalized
+            .explain_dependency(&PackageName::from_str("baz").unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn run_constrained_with_pins() {
+        let test_data_dir =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/rendered_recipes");
+        let output: Output = serde_yaml::from_str(
+            &fs::read_to_string(test_data_dir.join("rich_recipe.yaml")).unwrap(),
+        )
+        .unwrap();
+        let mut build_configuration = output.build_configuration.clone();
+        build_configuration
+            .variant
+            .insert("cuda-version".to_string(), "11.8".to_string());
+
+        let recipe = crate::recipe::Recipe::from_yaml(
+            r#"
+            package:
+              name: rich-cli
+              version: 13.4.2
+            requirements:
+              run_constrained:
+                - cudatoolkit >=11.2,<12
+                - cuda-version
+                - ${{ pin_subpackage("rich", exact=True) }}
+            "#,
+            crate::recipe::jinja::SelectorConfig::default(),
+        )
+        .unwrap();
+
+        let constrains = apply_variant(
+            recipe.requirements().run_constrained(),
+            &build_configuration,
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(constrains.len(), 3);
+        assert!(matches!(constrains[0], DependencyInfo::Raw { .. }));
+        assert!(
+            matches!(&constrains[1], DependencyInfo::Variant { variant, .. } if variant == "11.8")
+        );
+        assert!(matches!(
+            constrains[2],
+            DependencyInfo::PinSubpackage { .. }
+        ));
+
+        let pinned = constrains[2].spec();
+        assert_eq!(pinned.name.as_ref().unwrap().as_normalized(), "rich");
+        assert_eq!(
+            pinned.build.as_ref().map(|build| build.to_string()),
+            Some("pyh4616a5c_0".to_string())
+        );
+
+        // a pin on an output that the recipe does not have is an error
+        let recipe = crate::recipe::Recipe::from_yaml(
+            r#"
+            package:
+              name: rich-cli
+              version: 13.4.2
+            requirements:
+              run_constrained:
+                - ${{ pin_subpackage("not-an-output") }}
+            "#,
+            crate::recipe::jinja::SelectorConfig::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            apply_variant(
+                recipe.requirements().run_constrained(),
+                &build_configuration,
+                &HashMap::new(),
+            ),
+            Err(ResolveError::SubpackageNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn run_exports_are_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_dir = dir.path().join("libfoo-1.0-h123_0");
+        fs::create_dir_all(package_dir.join("info")).unwrap();
+        let run_exports_json = package_dir.join("info").join("run_exports.json");
+        fs::write(&run_exports_json, r#"{"weak": ["libfoo >=1.0"]}"#).unwrap();
+
+        let cache = RunExportsCache::default();
+        assert!(cache.get(&dir.path().join("libbar-1.0-h123_0")).is_none());
+        assert_eq!(cache.get(&package_dir).unwrap().weak, vec!["libfoo >=1.0"]);
+
+        // the run exports of a package are only read once
+        fs::remove_file(&run_exports_json).unwrap();
+        assert_eq!(cache.get(&package_dir).unwrap().weak, vec!["libfoo >=1.0"]);
+    }
 }
@@ -0,0 +1,198 @@
+//! Reuse of the build and host environments between builds (`--reuse-environments`).
+//!
+//! After a prefix is installed, a marker with the fingerprint of the installed packages and the
+//! list of the installed files is written to the root of the prefix (not to `conda-meta`, where
+//! conda reads every `.json` file as the record of a package). When the next build installs the
+//! same packages into the prefix, the installation is skipped and only the files that the
+//! previous build script added to the prefix are removed.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use rattler_conda_types::{Platform, RepoDataRecord};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+/// The location of the marker in the prefix
+pub(crate) const MARKER: &str = ".rattler-build-environment.json";
+
+/// The content of the marker file
+#[derive(Debug, Serialize, Deserialize)]
+struct EnvironmentMarker {
+    /// The fingerprint of the installed packages
+    fingerprint: String,
+    /// The files and directories of the prefix after the installation, relative to the prefix
+    files: Vec<PathBuf>,
+}
+
+/// The fingerprint of the packages that are installed into a prefix for `platform`
+pub fn fingerprint(packages: &[RepoDataRecord], platform: &Platform) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(platform.as_str());
+    for package in packages
+        .iter()
+        .map(|record| {
+            let record_hash = record
+                .package_record
+                .sha256
+                .map(hex::encode)
+                .or_else(|| record.package_record.md5.map(hex::encode))
+                .unwrap_or_default();
+            format!("{} {}", record.url, record_hash)
+        })
+        .sorted()
+    {
+        hasher.update(b"\n");
+        hasher.update(package);
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn read_marker(prefix: &Path) -> Option<EnvironmentMarker> {
+    let content = match fs::read_to_string(prefix.join(MARKER)) {
+        Ok(content) => content,
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                tracing::warn!("Could not read the environment marker: {}", e);
+            }
+            return None;
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(marker) => Some(marker),
+        Err(e) => {
+            tracing::warn!("The environment marker in {:?} is corrupt: {}", prefix, e);
+            None
+        }
+    }
+}
+
+/// Prepare the reuse of the environment in `prefix`. If its marker has the `fingerprint`, the
+/// files that were added after the installation are removed and `true` is returned. Otherwise
+/// (no marker, a corrupt marker or other packages), the prefix is emptied so that it can be
+/// installed again.
+pub fn reuse_prefix(prefix: &Path, fingerprint: &str) -> Result<bool, std::io::Error> {
+    match read_marker(prefix) {
+        Some(marker) if marker.fingerprint == fingerprint => {
+            let installed = marker.files.into_iter().collect::<HashSet<_>>();
+            remove_added_files(prefix, &installed)?;
+            return Ok(true);
+        }
+        Some(_) => tracing::info!(
+            "The dependencies of {:?} changed, installing the environment again",
+            prefix
+        ),
+        None => {}
+    }
+
+    if prefix.exists() {
+        fs::remove_dir_all(prefix)?;
+    }
+    fs::create_dir_all(prefix)?;
+    Ok(false)
+}
+
+/// Remove everything in `prefix` that is not in the `installed` files
+fn remove_added_files(prefix: &Path, installed: &HashSet<PathBuf>) -> Result<(), std::io::Error> {
+    let mut entries = WalkDir::new(prefix).min_depth(1).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(prefix)
+            .expect("walkdir yields paths in the prefix");
+        if installed.contains(relative) || relative == Path::new(MARKER) {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            fs::remove_dir_all(entry.path())?;
+            entries.skip_current_dir();
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the marker of the freshly installed environment in `prefix`
+pub fn write_marker(prefix: &Path, fingerprint: &str) -> Result<(), std::io::Error> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(prefix).min_depth(1) {
+        let entry = entry?;
+        files.push(
+            entry
+                .path()
+                .strip_prefix(prefix)
+                .expect("walkdir yields paths in the prefix")
+                .to_path_buf(),
+        );
+    }
+    let marker = EnvironmentMarker {
+        fingerprint: fingerprint.to_string(),
+        files,
+    };
+
+    fs::create_dir_all(prefix)?;
+    fs::write(prefix.join(MARKER), serde_json::to_string(&marker)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{reuse_prefix, write_marker, MARKER};
+
+    /// A prefix with an installed file, and a file and a directory that the build script added
+    fn used_prefix(fingerprint: &str) -> tempfile::TempDir {
+        let prefix = tempfile::tempdir().unwrap();
+        fs::create_dir_all(prefix.path().join("lib")).unwrap();
+        fs::write(prefix.path().join("lib/libz.so"), "zlib").unwrap();
+        write_marker(prefix.path(), fingerprint).unwrap();
+
+        fs::write(prefix.path().join("lib/libfoo.so"), "foo").unwrap();
+        fs::create_dir_all(prefix.path().join("include/foo")).unwrap();
+        fs::write(prefix.path().join("include/foo/foo.h"), "").unwrap();
+        prefix
+    }
+
+    fn exists(prefix: &Path, path: &str) -> bool {
+        prefix.join(path).exists()
+    }
+
+    #[test]
+    fn matching_fingerprint() {
+        let prefix = used_prefix("abc");
+        assert!(reuse_prefix(prefix.path(), "abc").unwrap());
+        assert!(exists(prefix.path(), "lib/libz.so"));
+        assert!(exists(prefix.path(), MARKER));
+        assert!(!exists(prefix.path(), "conda-meta"));
+        assert!(!exists(prefix.path(), "lib/libfoo.so"));
+        assert!(!exists(prefix.path(), "include"));
+    }
+
+    #[test]
+    fn other_fingerprint() {
+        let prefix = used_prefix("abc");
+        assert!(!reuse_prefix(prefix.path(), "def").unwrap());
+        assert_eq!(fs::read_dir(prefix.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn missing_or_corrupt_marker() {
+        let prefix = used_prefix("abc");
+        fs::write(prefix.path().join(MARKER), "{\"fingerprint\": ").unwrap();
+        assert!(!reuse_prefix(prefix.path(), "abc").unwrap());
+        assert_eq!(fs::read_dir(prefix.path()).unwrap().count(), 0);
+
+        fs::write(prefix.path().join("file.txt"), "").unwrap();
+        assert!(!reuse_prefix(prefix.path(), "abc").unwrap());
+        assert!(!exists(prefix.path(), "file.txt"));
+    }
+}
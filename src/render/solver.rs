@@ -2,25 +2,20 @@ use anyhow::Context;
 use comfy_table::Table;
 use futures::{stream, stream::FuturesUnordered, FutureExt, StreamExt, TryFutureExt, TryStreamExt};
 
-use indicatif::{style::TemplateError, HumanBytes, ProgressBar, ProgressState, ProgressStyle};
+use indicatif::{style::TemplateError, HumanBytes, ProgressBar, ProgressStyle};
 use rattler::{
     install::{link_package, InstallDriver, InstallOptions, Transaction, TransactionOperation},
     package_cache::PackageCache,
 };
 use rattler_conda_types::{
-    Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, Platform, PrefixRecord,
-    RepoDataRecord,
+    package::ArchiveType, Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, Platform,
+    PrefixRecord, RepoDataRecord,
 };
 use rattler_networking::AuthenticatedClient;
-use rattler_repodata_gateway::fetch::{
-    CacheResult, DownloadProgress, FetchRepoDataError, FetchRepoDataOptions,
-};
-use rattler_repodata_gateway::sparse::SparseRepoData;
 use rattler_solve::{resolvo::Solver, SolverImpl, SolverTask};
 
 use std::{
     borrow::Cow,
-    fmt::Write,
     future::ready,
     io::ErrorKind,
     path::{Path, PathBuf},
@@ -28,7 +23,16 @@ use std::{
 };
 use tokio::task::JoinHandle;
 
-use crate::tool_configuration;
+use super::{
+    local_packages::{apply_overrides, LocalPackageOverride},
+    reuse,
+};
+use crate::{
+    bandwidth::BandwidthLimiter,
+    channel_query::ChannelQuery,
+    progress::{Progress, ProgressOutput, ProgressUnit},
+    tool_configuration,
+};
 
 fn print_as_table(packages: &Vec<RepoDataRecord>) {
     let mut table = Table::new();
@@ -111,42 +115,21 @@ pub async fn create_environment(
         .await
         .context("failed to determine currently installed packages")?;
 
-    // For each channel/subdirectory combination, download and cache the `repodata.json` that should
-    // be available from the corresponding Url. The code below also displays a nice CLI progress-bar
-    // to give users some more information about what is going on.
-
-    let repodata_cache_path = cache_dir.join("repodata");
-    let channel_and_platform_len = channel_urls.len();
-    let repodata_download_client = tool_configuration.client.clone();
-    let sparse_repo_datas = futures::stream::iter(channel_urls)
-        .map(move |(channel, platform)| {
-            let repodata_cache = repodata_cache_path.clone();
-            let download_client = repodata_download_client.clone();
-            async move {
-                fetch_repo_data_records_with_progress(
-                    channel,
-                    platform,
-                    &repodata_cache,
-                    download_client.clone(),
-                    tool_configuration.multi_progress_indicator.clone(),
-                    platform != Platform::NoArch,
-                )
-                .await
-            }
-        })
-        .buffer_unordered(channel_and_platform_len)
-        .collect::<Vec<_>>()
-        .await
-        // Collect into another iterator where we extract the first erroneous result
-        .into_iter()
-        .filter_map(Result::transpose)
-        .collect::<Result<Vec<_>, _>>()?;
+    let overrides = LocalPackageOverride::from_paths(&tool_configuration.local_package_overrides)?;
 
     // Get the package names from the matchspecs so we can only load the package records that we need.
-    let package_names = specs.iter().filter_map(|spec| spec.name.clone());
-    let repodatas = wrap_in_progress("parsing repodata", move || {
-        SparseRepoData::load_records_recursive(&sparse_repo_datas, package_names, None)
-    })??;
+    let package_names = specs
+        .iter()
+        .filter_map(|spec| spec.name.clone())
+        .chain(overrides.iter().flat_map(|o| o.dependency_names()))
+        .collect::<Vec<_>>();
+
+    // For each channel/subdirectory combination, download and cache the `repodata.json` that should
+    // be available from the corresponding Url, and load the records that we need from it.
+    let mut repodatas = ChannelQuery::from_tool_configuration(tool_configuration)?
+        .load_records_recursive(&channel_urls, package_names)
+        .await?;
+    apply_overrides(&mut repodatas, &overrides, target_platform);
 
     // Determine virtual packages of the system. These packages define the capabilities of the
     // system. Some packages depend on these virtual packages to indicate compatibility with the
@@ -178,6 +161,17 @@ pub async fn create_environment(
     // we need to apply to our environment to bring it up to date.
     let required_packages = wrap_in_progress("solving", move || Solver.solve(solver_task))??;
 
+    for local in &overrides {
+        if required_packages.iter().any(|r| r.url == local.record.url) {
+            tracing::info!(
+                "Using the local package {} for `{}`",
+                local.path.display(),
+                local.name().as_normalized()
+            );
+            local.remove_from_cache(&cache_dir)?;
+        }
+    }
+
     install_packages(
         &required_packages,
         target_platform,
@@ -197,6 +191,20 @@ pub async fn install_packages(
     cache_dir: &Path,
     tool_configuration: &tool_configuration::Configuration,
 ) -> anyhow::Result<()> {
+    // environments are only reused when the build directory is kept as well
+    let fingerprint = (tool_configuration.reuse_environments && tool_configuration.no_clean)
+        .then(|| reuse::fingerprint(required_packages, target_platform));
+    if let Some(fingerprint) = &fingerprint {
+        if reuse::reuse_prefix(target_prefix, fingerprint)? {
+            tracing::info!(
+                "{} Reusing the environment in {:?}, the dependencies are unchanged",
+                console::style(console::Emoji("✔", "")).green(),
+                target_prefix
+            );
+            return Ok(());
+        }
+    }
+
     let installed_packages = vec![];
     // Construct a transaction to
     let transaction = Transaction::from_current_and_desired(
@@ -215,6 +223,8 @@ pub async fn install_packages(
             cache_dir,
             tool_configuration.client.clone(),
             tool_configuration.multi_progress_indicator.clone(),
+            tool_configuration.progress,
+            tool_configuration.bandwidth_limit.clone(),
         )
         .await?;
         tracing::info!(
@@ -228,6 +238,10 @@ pub async fn install_packages(
         );
     }
 
+    if let Some(fingerprint) = &fingerprint {
+        reuse::write_marker(target_prefix, fingerprint)?;
+    }
+
     Ok(())
 }
 
@@ -238,6 +252,8 @@ async fn execute_transaction(
     cache_dir: &Path,
     download_client: AuthenticatedClient,
     multi_progress: indicatif::MultiProgress,
+    progress: ProgressOutput,
+    bandwidth_limit: Option<BandwidthLimiter>,
 ) -> anyhow::Result<()> {
     // Open the package cache
     let package_cache = PackageCache::new(cache_dir.join("pkgs"));
@@ -259,9 +275,14 @@ async fn execute_transaction(
         .filter(|op| op.record_to_install().is_some())
         .count();
     let download_pb = if total_packages_to_download > 0 {
+        let style = if bandwidth_limit.is_some() {
+            limited_progress_style()?
+        } else {
+            default_progress_style()?
+        };
         let pb = multi_progress.add(
             indicatif::ProgressBar::new(total_packages_to_download as u64)
-                .with_style(default_progress_style()?)
+                .with_style(style)
                 .with_finish(indicatif::ProgressFinish::WithMessage("Done!".into()))
                 .with_prefix("downloading"),
         );
@@ -271,15 +292,17 @@ async fn execute_transaction(
         None
     };
 
-    // Create a progress bar to track all operations.
+    // Create a progress bar to track all operations (or log lines without progress bars).
     let total_operations = transaction.operations.len();
-    let link_pb = multi_progress.add(
-        indicatif::ProgressBar::new(total_operations as u64)
-            .with_style(default_progress_style()?)
-            .with_finish(indicatif::ProgressFinish::WithMessage("Done!".into()))
-            .with_prefix("linking"),
-    );
-    link_pb.enable_steady_tick(Duration::from_millis(100));
+    let link_pb = Progress::with_output(
+        &multi_progress,
+        progress,
+        format!("linking into {}", target_prefix.display()),
+        Some(total_operations as u64),
+        ProgressUnit::Packages,
+    )
+    .with_bar_style(default_progress_style()?);
+    link_pb.bar().set_prefix("linking");
 
     // Perform all transactions operations in parallel.
     stream::iter(transaction.operations)
@@ -291,6 +314,7 @@ async fn execute_transaction(
             let download_pb = download_pb.as_ref();
             let link_pb = &link_pb;
             let install_options = &install_options;
+            let bandwidth_limit = bandwidth_limit.as_ref();
             async move {
                 execute_operation(
                     target_prefix,
@@ -301,11 +325,13 @@ async fn execute_transaction(
                     link_pb,
                     op,
                     install_options,
+                    bandwidth_limit,
                 )
                 .await
             }
         })
         .await?;
+    link_pb.finish();
 
     Ok(())
 }
@@ -319,9 +345,10 @@ async fn execute_operation(
     package_cache: &PackageCache,
     install_driver: &InstallDriver,
     download_pb: Option<&ProgressBar>,
-    link_pb: &ProgressBar,
+    link_pb: &Progress,
     op: TransactionOperation<PrefixRecord, RepoDataRecord>,
     install_options: &InstallOptions,
+    bandwidth_limit: Option<&BandwidthLimiter>,
 ) -> anyhow::Result<()> {
     // Determine the package to install
     let install_record = op.record_to_install();
@@ -337,13 +364,28 @@ async fn execute_operation(
     // Create a future to download the package
     let cached_package_dir_fut = if let Some(install_record) = install_record {
         async {
-            // Make sure the package is available in the package cache.
-            let result = package_cache
-                .get_or_fetch_from_url(
-                    &install_record.package_record,
-                    install_record.url.clone(),
-                    download_client.clone(),
-                )
+            // Make sure the package is available in the package cache. Local packages (e.g. of
+            // the output directory) are not downloaded, so they are not limited.
+            let limit = bandwidth_limit.filter(|_| install_record.url.scheme() != "file");
+            let fetch = match limit {
+                Some(limiter) => {
+                    let (client, url) = (download_client.clone(), install_record.url.clone());
+                    let (limiter, pb) = (limiter.clone(), download_pb.cloned());
+                    package_cache
+                        .get_or_fetch(&install_record.package_record, move |destination| {
+                            download_package(client, url, destination, limiter, pb)
+                        })
+                        .left_future()
+                }
+                None => package_cache
+                    .get_or_fetch_from_url(
+                        &install_record.package_record,
+                        install_record.url.clone(),
+                        download_client.clone(),
+                    )
+                    .right_future(),
+            };
+            let result = fetch
                 .map_ok(|cache_dir| Some((install_record.clone(), cache_dir)))
                 .map_err(anyhow::Error::from)
                 .await;
@@ -380,9 +422,65 @@ async fn execute_operation(
 
     // Increment the link progress bar since we finished a step!
     link_pb.inc(1);
-    if link_pb.length() == Some(link_pb.position()) {
-        link_pb.set_style(finished_progress_style()?);
+    let bar = link_pb.bar();
+    if bar.length() == Some(bar.position()) {
+        bar.set_style(finished_progress_style()?);
+    }
+
+    Ok(())
+}
+
+/// Errors of a package download with a bandwidth limit
+#[derive(Debug, thiserror::Error)]
+enum PackageDownloadError {
+    #[error("{0} is not a conda package")]
+    UnknownArchiveType(url::Url),
+
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Extract(#[from] rattler_package_streaming::ExtractError),
+
+    #[error("the extraction of the package failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// Download the package at `url` at most at the rate allowed by `limiter` and extract it to
+/// `destination`. The package is downloaded to a temporary file first, so that the extraction
+/// does not hold back the download.
+async fn download_package(
+    client: AuthenticatedClient,
+    url: url::Url,
+    destination: PathBuf,
+    limiter: BandwidthLimiter,
+    progress_bar: Option<ProgressBar>,
+) -> Result<(), PackageDownloadError> {
+    use std::io::{Seek, Write};
+
+    let archive_type = ArchiveType::try_from(Path::new(url.path()))
+        .ok_or_else(|| PackageDownloadError::UnknownArchiveType(url.clone()))?;
+
+    let response = client.get(url).send().await?.error_for_status()?;
+    let mut file = tempfile::tempfile()?;
+    let chunks = limiter.throttle(response.bytes_stream());
+    futures::pin_mut!(chunks);
+    while let Some(chunk) = chunks.next().await {
+        file.write_all(&chunk?)?;
+        if let Some(pb) = &progress_bar {
+            pb.set_message(limiter.rate_message());
+        }
     }
+    file.rewind()?;
+
+    tokio::task::spawn_blocking(move || match archive_type {
+        ArchiveType::TarBz2 => rattler_package_streaming::read::extract_tar_bz2(file, &destination),
+        ArchiveType::Conda => rattler_package_streaming::read::extract_conda(file, &destination),
+    })
+    .await??;
 
     Ok(())
 }
@@ -503,124 +601,6 @@ fn wrap_in_progress<T, F: FnOnce() -> T>(
     Ok(result)
 }
 
-/// Given a channel and platform, download and cache the `repodata.json` for it. This function
-/// reports its progress via a CLI progressbar.
-async fn fetch_repo_data_records_with_progress(
-    channel: Channel,
-    platform: Platform,
-    repodata_cache: &Path,
-    client: AuthenticatedClient,
-    multi_progress: indicatif::MultiProgress,
-    allow_not_found: bool,
-) -> anyhow::Result<Option<SparseRepoData>> {
-    // Create a progress bar
-    let progress_bar = multi_progress.add(
-        indicatif::ProgressBar::new(1)
-            .with_finish(indicatif::ProgressFinish::AndLeave)
-            .with_prefix(format!("{}/{platform}", friendly_channel_name(&channel)))
-            .with_style(default_bytes_style()?),
-    );
-    progress_bar.enable_steady_tick(Duration::from_millis(100));
-
-    let download_progress_bar = progress_bar.clone();
-    // Download the repodata.json
-    let result = rattler_repodata_gateway::fetch::fetch_repo_data(
-        channel.platform_url(platform),
-        client,
-        repodata_cache.to_path_buf(),
-        FetchRepoDataOptions {
-            ..Default::default()
-        },
-        Some(Box::new(move |DownloadProgress { total, bytes }| {
-            download_progress_bar.set_length(total.unwrap_or(bytes));
-            download_progress_bar.set_position(bytes);
-        })),
-    )
-    .await;
-
-    // Error out if an error occurred, but also update the progress bar
-    let result = match result {
-        Err(e) => {
-            if matches!(e, FetchRepoDataError::NotFound(_)) && allow_not_found {
-                progress_bar.set_style(errored_progress_style()?);
-                progress_bar.finish_with_message("Not Found");
-                return Ok(None);
-            }
-            progress_bar.set_style(errored_progress_style()?);
-            progress_bar.finish_with_message("404 not found");
-            return Err(e.into());
-        }
-        Ok(result) => result,
-    };
-
-    // Notify that we are deserializing
-    progress_bar.set_style(deserializing_progress_style()?);
-    progress_bar.set_message("Deserializing..");
-
-    // Deserialize the data. This is a hefty blocking operation so we spawn it as a tokio blocking
-    // task.
-    let repo_data_json_path = result.repo_data_json_path.clone();
-    match tokio::task::spawn_blocking(move || {
-        SparseRepoData::new(channel, platform.to_string(), repo_data_json_path, None)
-    })
-    .await
-    {
-        Ok(Ok(repodata)) => {
-            let is_cache_hit = matches!(
-                result.cache_result,
-                CacheResult::CacheHit | CacheResult::CacheHitAfterFetch
-            );
-            progress_bar.set_style(finished_progress_style()?);
-            progress_bar.finish_with_message(if is_cache_hit { "Using cache" } else { "Done" });
-            Ok(Some(repodata))
-        }
-        Ok(Err(err)) => {
-            progress_bar.set_style(errored_progress_style()?);
-            progress_bar.finish_with_message(format!("Error: {:?}", err));
-            Err(err.into())
-        }
-        Err(err) => match err.try_into_panic() {
-            Ok(panic) => {
-                std::panic::resume_unwind(panic);
-            }
-            Err(_) => {
-                progress_bar.set_style(errored_progress_style()?);
-                progress_bar.finish_with_message("Canceled...");
-                // Since the task was cancelled most likely the whole async stack is being cancelled.
-                Err(anyhow::anyhow!("canceled"))
-            }
-        },
-    }
-}
-
-/// Returns a friendly name for the specified channel.
-fn friendly_channel_name(channel: &Channel) -> String {
-    channel
-        .name
-        .as_ref()
-        .map(String::from)
-        .unwrap_or_else(|| channel.canonical_name())
-}
-
-/// Returns the style to use for a progressbar that is currently in progress.
-fn default_bytes_style() -> Result<indicatif::ProgressStyle, TemplateError> {
-    Ok(indicatif::ProgressStyle::default_bar()
-            .template("{spinner:.green} {prefix:20!} [{elapsed_precise}] [{bar:40!.bright.yellow/dim.white}] {bytes:>8} @ {smoothed_bytes_per_sec:8}")?
-            .progress_chars("━━╾─")
-            .with_key(
-                "smoothed_bytes_per_sec",
-                |s: &ProgressState, w: &mut dyn Write| match (s.pos(), s.elapsed().as_millis()) {
-                    (pos, elapsed_ms) if elapsed_ms > 0 => {
-                        // TODO: log with tracing?
-                        _ = write!(w, "{}/s", HumanBytes((pos as f64 * 1000_f64 / elapsed_ms as f64) as u64));
-                    }
-                    _ => {
-                        _ = write!(w, "-");
-                    },
-                },
-            ))
-}
-
 /// Returns the style to use for a progressbar that is currently in progress.
 fn default_progress_style() -> Result<indicatif::ProgressStyle, TemplateError> {
     Ok(indicatif::ProgressStyle::default_bar()
@@ -628,11 +608,12 @@ fn default_progress_style() -> Result<indicatif::ProgressStyle, TemplateError> {
             .progress_chars("━━╾─"))
 }
 
-/// Returns the style to use for a progressbar that is in Deserializing state.
-fn deserializing_progress_style() -> Result<indicatif::ProgressStyle, TemplateError> {
+/// Returns the style to use for the download progressbar when the bandwidth is limited. The
+/// message shows the current and the allowed rate.
+fn limited_progress_style() -> Result<indicatif::ProgressStyle, TemplateError> {
     Ok(indicatif::ProgressStyle::default_bar()
-        .template("{spinner:.green} {prefix:20!} [{elapsed_precise}] {wide_msg}")?
-        .progress_chars("━━╾─"))
+            .template("{spinner:.green} {prefix:20!} [{elapsed_precise}] [{bar:40!.bright.yellow/dim.white}] {pos:>7}/{len:7} {msg}")?
+            .progress_chars("━━╾─"))
 }
 
 /// Returns the style to use for a progressbar that is finished.
@@ -645,16 +626,6 @@ fn finished_progress_style() -> Result<indicatif::ProgressStyle, TemplateError>
         .progress_chars("━━╾─"))
 }
 
-/// Returns the style to use for a progressbar that is in error state.
-fn errored_progress_style() -> Result<indicatif::ProgressStyle, TemplateError> {
-    Ok(indicatif::ProgressStyle::default_bar()
-        .template(&format!(
-            "{} {{prefix:20!}} [{{elapsed_precise}}] {{msg:.bold.red}}",
-            console::style(console::Emoji("❌", " ")).red()
-        ))?
-        .progress_chars("━━╾─"))
-}
-
 /// Returns the style to use for a progressbar that is indeterminate and simply shows a spinner.
 fn long_running_progress_style() -> Result<indicatif::ProgressStyle, TemplateError> {
     ProgressStyle::with_template("{spinner:.green} {msg}")
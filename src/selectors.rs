@@ -1,6 +1,13 @@
 //! Contains the selector config, which is used to render the recipe.
 
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use crate::{hash::HashInfo, recipe::jinja::Env};
 
@@ -18,6 +25,31 @@ pub struct SelectorConfig {
     pub hash: Option<HashInfo>,
     /// The variant config
     pub variant: BTreeMap<String, String>,
+    /// The directory with the already fetched sources that `load_file_from_source` and
+    /// `load_from_file` read from
+    pub source_dir: Option<PathBuf>,
+    /// The directory of the recipe that `load_from_file` reads from
+    pub recipe_dir: Option<PathBuf>,
+    /// Set while rendering if the recipe reads a file from its sources, but no `source_dir` is
+    /// set. It is shared by all clones of this config.
+    pub sources_requested: SourcesRequested,
+}
+
+/// Whether `load_file_from_source` or `load_from_file` needed the sources of the recipe while
+/// they were not fetched yet
+#[derive(Clone, Debug, Default)]
+pub struct SourcesRequested(Arc<AtomicBool>);
+
+impl SourcesRequested {
+    /// Record that the sources were needed
+    pub fn set(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the sources were needed since this flag was created
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
 impl SelectorConfig {
@@ -30,6 +62,17 @@ impl SelectorConfig {
             Value::from_safe_string(self.target_platform.to_string()),
         );
 
+        // Define all platform and architecture selectors up front so that e.g. `win` is `false`
+        // (and not undefined) when rendering for linux.
+        for platform in Platform::all() {
+            if let Some(only_platform) = platform.only_platform() {
+                context.insert(only_platform.to_string(), Value::from(false));
+            }
+            if let Some(arch) = platform.arch() {
+                context.insert(arch.to_string(), Value::from(false));
+            }
+        }
+
         if let Some(platform) = self.target_platform.only_platform() {
             context.insert(
                 platform.to_string(),
@@ -85,6 +128,9 @@ impl Default for SelectorConfig {
             build_platform: Platform::current(),
             hash: None,
             variant: Default::default(),
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         }
     }
 }
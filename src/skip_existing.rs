@@ -0,0 +1,157 @@
+//! Skip the build of outputs whose package already exists (`--skip-existing`).
+
+use rattler_conda_types::{Channel, ChannelConfig, RepoDataRecord};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    channel_query::{ChannelQuery, ChannelQueryError},
+    metadata::Output,
+};
+
+/// Where to look for an existing package with the same name, version and build string
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SkipExisting {
+    /// Always build
+    #[default]
+    Off,
+    /// Skip the build if the package is in the output directory
+    Local,
+    /// Skip the build if the package is in the output directory or in one of the channels
+    All,
+}
+
+/// Find a package with the name, version and build string of `output` in the subdir of its
+/// target platform (`noarch` for noarch packages): in the (indexed) output directory, and with
+/// [`SkipExisting::All`] also in the channels of the build.
+pub async fn find_existing(
+    output: &Output,
+    skip_existing: SkipExisting,
+    query: &ChannelQuery,
+) -> Result<Option<RepoDataRecord>, ChannelQueryError> {
+    let Some(build_string) = output.build_string() else {
+        return Ok(None);
+    };
+    if skip_existing == SkipExisting::Off {
+        return Ok(None);
+    }
+
+    let output_dir = &output.build_configuration.directories.output_dir;
+    let mut channels = Url::from_directory_path(output_dir)
+        .ok()
+        .map(|url| url.to_string())
+        .into_iter()
+        .collect::<Vec<_>>();
+    if skip_existing == SkipExisting::All {
+        channels.extend(output.build_configuration.channels.iter().cloned());
+    }
+
+    let subdir = output.build_configuration.target_platform;
+    for channel in channels {
+        let channel = match Channel::from_str(&channel, &ChannelConfig::default()) {
+            Ok(channel) => channel,
+            Err(e) => {
+                tracing::warn!(
+                    "Could not check the channel {} for the package: {}",
+                    channel,
+                    e
+                );
+                continue;
+            }
+        };
+        let existing = query
+            .existing_records(&channel, subdir, output.name())
+            .await?
+            .into_iter()
+            .find(|record| {
+                record.package_record.build == build_string
+                    && record.package_record.version.to_string() == output.version()
+            });
+        if existing.is_some() {
+            return Ok(existing);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use rattler_networking::AuthenticatedClient;
+
+    use super::{find_existing, SkipExisting};
+    use crate::{channel_query::ChannelQuery, metadata::Output};
+
+    /// The noarch `rich` output, with its output directory in `output_dir`
+    fn rich_output(output_dir: &std::path::Path) -> Output {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/rendered_recipes/rich_recipe.yaml");
+        let mut output: Output =
+            serde_yaml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        output.build_configuration.directories.output_dir = output_dir.to_path_buf();
+        output.build_configuration.channels = Vec::new();
+        output
+    }
+
+    fn write_repodata(output_dir: &std::path::Path, build: &str) {
+        fs_err::create_dir_all(output_dir.join("noarch")).unwrap();
+        let repodata = serde_json::json!({
+            "info": { "subdir": "noarch" },
+            "packages": {
+                format!("rich-13.4.2-{build}.tar.bz2"): {
+                    "build": build,
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "rich",
+                    "noarch": "python",
+                    "subdir": "noarch",
+                    "version": "13.4.2"
+                }
+            }
+        });
+        fs_err::write(
+            output_dir.join("noarch/repodata.json"),
+            repodata.to_string(),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_local_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = rich_output(tmp.path());
+        let query = ChannelQuery::new(AuthenticatedClient::default(), tmp.path().join("cache"));
+
+        // nothing was built yet
+        assert!(find_existing(&output, SkipExisting::Local, &query)
+            .await
+            .unwrap()
+            .is_none());
+
+        write_repodata(tmp.path(), "pyh4616a5c_0");
+        let query = ChannelQuery::new(AuthenticatedClient::default(), tmp.path().join("cache"));
+        let existing = find_existing(&output, SkipExisting::Local, &query)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(existing.file_name, "rich-13.4.2-pyh4616a5c_0.tar.bz2");
+
+        // `--force` builds anyway
+        assert!(find_existing(&output, SkipExisting::Off, &query)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn other_build_string_is_built() {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = rich_output(tmp.path());
+        write_repodata(tmp.path(), "pyh0000000_0");
+        let query = ChannelQuery::new(AuthenticatedClient::default(), tmp.path().join("cache"));
+        assert!(find_existing(&output, SkipExisting::All, &query)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,385 @@
+//! Credentials of url sources: the `headers` of the recipe (with values that reference
+//! environment variables), the rattler authentication storage that is also used for channels,
+//! and `~/.netrc`.
+//!
+//! The credentials of a source are only sent to the origin (scheme, host and port) of its url.
+//! When the server redirects to another origin (e.g. to a presigned S3 url), the request to the
+//! new location is sent without them.
+
+use std::path::PathBuf;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use url::Url;
+
+use super::SourceError;
+use crate::recipe::parser::UrlSource;
+
+/// Headers whose values are credentials, and must reference an environment variable in the
+/// recipe
+const CREDENTIAL_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-jfrog-art-api",
+];
+
+/// Parts of header names that mark their values as credentials (e.g. `PRIVATE-TOKEN` or
+/// `X-Api-Key`). `api` alone is not one of them, `X-GitHub-Api-Version` is no credential.
+const CREDENTIAL_HEADER_PARTS: [&str; 3] = ["token", "key", "secret"];
+
+/// Whether the value of the header `name` is a credential
+pub(crate) fn is_credential_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    CREDENTIAL_HEADERS.contains(&name.as_str())
+        || name
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .any(|part| CREDENTIAL_HEADER_PARTS.contains(&part))
+}
+
+/// The parts of a header value: literal text and references to environment variables
+/// (`${NAME}`)
+#[derive(Debug, PartialEq, Eq)]
+enum ValuePart<'a> {
+    Literal(&'a str),
+    Variable(&'a str),
+}
+
+fn value_parts(value: &str) -> Vec<ValuePart<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start + 2..].find('}') else {
+            break;
+        };
+        if start > 0 {
+            parts.push(ValuePart::Literal(&rest[..start]));
+        }
+        parts.push(ValuePart::Variable(&rest[start + 2..start + 2 + end]));
+        rest = &rest[start + 2 + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(ValuePart::Literal(rest));
+    }
+    parts
+}
+
+/// The names of the environment variables that the header `value` references
+pub(crate) fn env_references(value: &str) -> Vec<&str> {
+    value_parts(value)
+        .into_iter()
+        .filter_map(|part| match part {
+            ValuePart::Variable(name) => Some(name),
+            ValuePart::Literal(_) => None,
+        })
+        .collect()
+}
+
+/// The header `value` with the environment variables replaced by their values from `lookup`
+fn expand_value(
+    header: &str,
+    value: &str,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<String, SourceError> {
+    let mut expanded = String::new();
+    for part in value_parts(value) {
+        match part {
+            ValuePart::Literal(text) => expanded.push_str(text),
+            ValuePart::Variable(name) => {
+                let value = lookup(name).ok_or_else(|| SourceError::MissingHeaderVariable {
+                    header: header.to_string(),
+                    variable: name.to_string(),
+                })?;
+                expanded.push_str(&value);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// The origin (scheme, host and port) of a url, which credentials are bound to
+fn origin(url: &Url) -> (String, Option<String>, Option<u16>) {
+    (
+        url.scheme().to_string(),
+        url.host_str().map(str::to_string),
+        url.port_or_known_default(),
+    )
+}
+
+/// The credentials of the download of one url of a source
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SourceCredentials {
+    origin: Option<(String, Option<String>, Option<u16>)>,
+    headers: HeaderMap,
+}
+
+impl SourceCredentials {
+    /// The credentials for `url` of `source`: the headers of the recipe, and, if they do not
+    /// set `Authorization`, the credentials of the authentication storage or of `~/.netrc` for
+    /// the host of the url
+    pub fn for_url(source: &UrlSource, url: &Url) -> Result<Self, SourceError> {
+        Self::with_lookups(
+            source,
+            url,
+            |name| std::env::var(name).ok(),
+            storage_authorization,
+            |host| {
+                let content = std::fs::read_to_string(netrc_path()?).ok()?;
+                Netrc::parse(&content).authorization(host)
+            },
+        )
+    }
+
+    fn with_lookups(
+        source: &UrlSource,
+        url: &Url,
+        env: impl Fn(&str) -> Option<String>,
+        storage: impl Fn(&Url) -> Option<String>,
+        netrc: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, SourceError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in source.headers() {
+            let value = expand_value(name, value, &env)?;
+            let invalid = |e: String| SourceError::InvalidHeader {
+                header: name.clone(),
+                reason: e,
+            };
+            let name = HeaderName::try_from(name.as_str()).map_err(|e| invalid(e.to_string()))?;
+            let mut value = HeaderValue::try_from(value).map_err(|e| invalid(e.to_string()))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+
+        if !headers.contains_key(AUTHORIZATION) {
+            let authorization = storage(url).or_else(|| url.host_str().and_then(&netrc));
+            if let Some(authorization) = authorization {
+                if let Ok(mut value) = HeaderValue::try_from(authorization) {
+                    value.set_sensitive(true);
+                    headers.insert(AUTHORIZATION, value);
+                }
+            }
+        }
+
+        Ok(Self {
+            origin: Some(origin(url)),
+            headers,
+        })
+    }
+
+    /// Whether there are credentials at all
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// The headers to send with a request to `url`: the credentials if `url` has the origin of
+    /// the source url, and none after a redirect to another origin
+    pub fn headers_for(&self, url: &Url) -> HeaderMap {
+        if self.origin.as_ref() == Some(&origin(url)) {
+            self.headers.clone()
+        } else {
+            HeaderMap::new()
+        }
+    }
+}
+
+/// The `Authorization` header for `url` from the rattler authentication storage
+fn storage_authorization(url: &Url) -> Option<String> {
+    use rattler_networking::Authentication;
+
+    let storage =
+        rattler_networking::AuthenticationStorage::new("rattler", &PathBuf::from("~/.rattler"));
+    let (_, authentication) = storage.get_by_url(url.clone()).ok()?;
+    match authentication? {
+        Authentication::BearerToken(token) => Some(format!("Bearer {token}")),
+        Authentication::BasicHTTP { username, password } => {
+            Some(basic_authorization(&username, &password))
+        }
+        // conda tokens are part of the path of channel urls, which does not apply to sources
+        Authentication::CondaToken(_) => None,
+    }
+}
+
+fn basic_authorization(login: &str, password: &str) -> String {
+    use base64::Engine;
+    let credentials =
+        base64::engine::general_purpose::STANDARD.encode(format!("{login}:{password}"));
+    format!("Basic {credentials}")
+}
+
+/// The netrc file: `$NETRC`, or `.netrc` (`_netrc` on Windows) in the home directory
+fn netrc_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("NETRC") {
+        return Some(PathBuf::from(path));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    let name = if cfg!(windows) { "_netrc" } else { ".netrc" };
+    Some(PathBuf::from(home).join(name))
+}
+
+/// The machines and their logins of a netrc file
+#[derive(Debug, Default)]
+struct Netrc {
+    /// The login and password of each machine (`None` for `default`)
+    machines: Vec<(Option<String>, String, String)>,
+}
+
+impl Netrc {
+    fn parse(content: &str) -> Self {
+        let mut machines = Vec::new();
+        let mut current: Option<(Option<String>, String, String)> = None;
+        let mut tokens = content
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(str::split_whitespace);
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" | "default" => {
+                    machines.extend(current.take());
+                    let machine = if token == "machine" {
+                        tokens.next().map(str::to_string)
+                    } else {
+                        None
+                    };
+                    current = Some((machine, String::new(), String::new()));
+                }
+                "login" => {
+                    if let (Some(entry), Some(login)) = (current.as_mut(), tokens.next()) {
+                        entry.1 = login.to_string();
+                    }
+                }
+                "password" => {
+                    if let (Some(entry), Some(password)) = (current.as_mut(), tokens.next()) {
+                        entry.2 = password.to_string();
+                    }
+                }
+                "account" => {
+                    tokens.next();
+                }
+                // macro definitions end at an empty line, which the tokens do not show, so the
+                // rest of the file is skipped
+                "macdef" => break,
+                _ => {}
+            }
+        }
+        machines.extend(current);
+        Self { machines }
+    }
+
+    /// The basic `Authorization` header for `host` (or of the `default` entry)
+    fn authorization(&self, host: &str) -> Option<String> {
+        self.machines
+            .iter()
+            .find(|(machine, _, _)| machine.as_deref() == Some(host))
+            .or_else(|| {
+                self.machines
+                    .iter()
+                    .find(|(machine, _, _)| machine.is_none())
+            })
+            .map(|(_, login, password)| basic_authorization(login, password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{env_references, is_credential_header, Netrc, SourceCredentials};
+    use crate::{recipe::parser::UrlSource, source::SourceError};
+
+    fn source(headers: &[(&str, &str)]) -> UrlSource {
+        let mut yaml = format!(
+            "url: https://artifactory.example.com/pkg-1.0.tar.gz\nsha256: {}\n",
+            "0".repeat(64)
+        );
+        if !headers.is_empty() {
+            yaml.push_str("headers:\n");
+            for (name, value) in headers {
+                yaml.push_str(&format!("  {name}: \"{value}\"\n"));
+            }
+        }
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn credential_headers() {
+        assert!(is_credential_header("Authorization"));
+        assert!(is_credential_header("X-JFrog-Art-Api"));
+        assert!(is_credential_header("PRIVATE-TOKEN"));
+        assert!(is_credential_header("X-Api-Key"));
+        assert!(!is_credential_header("X-GitHub-Api-Version"));
+        assert!(!is_credential_header("Accept"));
+        assert!(!is_credential_header("User-Agent"));
+
+        assert_eq!(env_references("Bearer ${TOKEN}"), ["TOKEN"]);
+        assert_eq!(env_references("${USER}:${PASSWORD}"), ["USER", "PASSWORD"]);
+        assert!(env_references("Bearer abc").is_empty());
+        assert!(env_references("Bearer ${unclosed").is_empty());
+    }
+
+    #[test]
+    fn expand_recipe_headers() {
+        let url = Url::parse("https://artifactory.example.com/pkg-1.0.tar.gz").unwrap();
+        let source = source(&[
+            ("Authorization", "Bearer ${TOKEN}"),
+            ("Accept", "application/octet-stream"),
+        ]);
+        let env = |name: &str| (name == "TOKEN").then(|| "secret".to_string());
+        let credentials = SourceCredentials::with_lookups(
+            &source,
+            &url,
+            env,
+            |_| Some("Bearer storage".to_string()),
+            |_| None,
+        )
+        .unwrap();
+        let headers = credentials.headers_for(&url);
+        assert_eq!(headers["authorization"], "Bearer secret");
+        assert_eq!(headers["accept"], "application/octet-stream");
+
+        // the header of the recipe wins over the authentication storage, and a missing
+        // variable is an error
+        let err = SourceCredentials::with_lookups(&source, &url, |_| None, |_| None, |_| None)
+            .unwrap_err();
+        assert!(
+            matches!(err, SourceError::MissingHeaderVariable { ref variable, .. } if variable == "TOKEN")
+        );
+    }
+
+    #[test]
+    fn credentials_are_not_sent_to_other_origins() {
+        let url =
+            Url::parse("https://github.com/org/repo/releases/download/v1/pkg.tar.gz").unwrap();
+        let credentials = SourceCredentials::with_lookups(
+            &source(&[]),
+            &url,
+            |_| None,
+            |_| None,
+            |host| (host == "github.com").then(|| "Basic abc".to_string()),
+        )
+        .unwrap();
+        assert_eq!(credentials.headers_for(&url)["authorization"], "Basic abc");
+
+        let redirect =
+            Url::parse("https://objects.s3.amazonaws.com/pkg.tar.gz?X-Amz-Signature=1").unwrap();
+        assert!(credentials.headers_for(&redirect).is_empty());
+        let other_port = Url::parse("https://github.com:8443/pkg.tar.gz").unwrap();
+        assert!(credentials.headers_for(&other_port).is_empty());
+    }
+
+    #[test]
+    fn parse_netrc() {
+        let netrc = Netrc::parse(
+            "# credentials\nmachine artifactory.example.com\n  login user\n  password pass\n\
+             machine other.example.com login other password secret account acc\n\
+             default login anonymous password guest\n",
+        );
+        assert_eq!(
+            netrc.authorization("artifactory.example.com").as_deref(),
+            Some("Basic dXNlcjpwYXNz")
+        );
+        assert_eq!(
+            netrc.authorization("unknown.example.com").as_deref(),
+            Some("Basic YW5vbnltb3VzOmd1ZXN0")
+        );
+        assert_eq!(netrc.machines.len(), 3);
+    }
+}
@@ -0,0 +1,386 @@
+//! Helpers to keep the source cache consistent when a process is interrupted.
+//!
+//! Everything that ends up in the cache is first written to a `*.tmp` file (or directory) next
+//! to its final location, and only renamed into place once it is complete. Leftovers of
+//! interrupted runs are removed by [`sweep_orphaned_tmp_files`]. Downloads are written to a
+//! `*.partial` file with a fixed name instead, so that the next attempt can resume them. On
+//! filesystems that cannot rename files, they are copied into place.
+//!
+//! The cache is shared by all builds (of any recipe and output directory). A build locks an
+//! entry (a download by its checksum, or a git repository by its url) with [`lock_entry`] while
+//! it fetches or uses it, so that concurrent builds wait for each other instead of writing the
+//! same files. [`clean_source_cache`] removes entries that were not used for a while, or the
+//! least recently used ones when the cache gets too large.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use filetime::FileTime;
+use fs_err as fs;
+use tempfile::{NamedTempFile, TempDir};
+
+/// Temporary files that are older than this are considered to be left over from an interrupted
+/// run and are deleted.
+pub const ORPHANED_TMP_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+const TMP_SUFFIX: &str = ".tmp";
+
+const PARTIAL_SUFFIX: &str = ".partial";
+
+const LOCK_SUFFIX: &str = ".lock";
+
+/// The default location of the source cache: `rattler-build/src_cache` in the cache directory
+/// of the platform (e.g. `~/.cache` on Linux)
+pub fn default_source_cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("rattler-build")
+        .join("src_cache")
+}
+
+/// A lock on an entry of the cache, which is released when it is dropped
+pub struct EntryLock(fslock::LockFile);
+
+/// Lock the entry `name` of the `cache_dir`, and wait until other processes release it
+pub fn lock_entry(cache_dir: &Path, name: &str) -> Result<EntryLock, std::io::Error> {
+    fs::create_dir_all(cache_dir)?;
+    let mut lock = fslock::LockFile::open(&cache_dir.join(format!("{name}{LOCK_SUFFIX}")))?;
+    if !lock.try_lock()? {
+        tracing::info!("Waiting for another build that uses the source cache entry {name}");
+        lock.lock()?;
+    }
+    Ok(EntryLock(lock))
+}
+
+/// Record that the cache entry at `path` is used, for [`clean_source_cache`]
+pub fn mark_used(path: &Path) -> Result<(), std::io::Error> {
+    filetime::set_file_mtime(path, FileTime::now())
+}
+
+/// Create a new temporary file in `cache_dir` that can later be moved to its final location
+/// with [`persist_file`].
+pub fn tmp_file(cache_dir: &Path, name: &str) -> Result<NamedTempFile, std::io::Error> {
+    tempfile::Builder::new()
+        .prefix(&format!("{name}."))
+        .suffix(TMP_SUFFIX)
+        .tempfile_in(cache_dir)
+}
+
+/// The file that a download of `name` is written to until it is complete. It is kept when the
+/// download fails, so that it can be resumed.
+pub fn partial_file(cache_dir: &Path, name: &str) -> PathBuf {
+    cache_dir.join(format!("{name}{PARTIAL_SUFFIX}"))
+}
+
+/// Create a new temporary directory in `cache_dir` that can later be moved to its final
+/// location with [`persist_dir`].
+pub fn tmp_dir(cache_dir: &Path, name: &str) -> Result<TempDir, std::io::Error> {
+    tempfile::Builder::new()
+        .prefix(&format!("{name}."))
+        .suffix(TMP_SUFFIX)
+        .tempdir_in(cache_dir)
+}
+
+/// Flush the temporary file to disk and atomically move it to `dest`.
+pub fn persist_file(mut tmp: NamedTempFile, dest: &Path) -> Result<(), std::io::Error> {
+    tmp.flush()?;
+    tmp.as_file().sync_all()?;
+    if let Err(e) = tmp.persist(dest) {
+        // the filesystem cannot rename, the temporary file is deleted after copying it
+        crate::filesystem::rename(e.file.path(), dest)?;
+    }
+    sync_parent_dir(dest)
+}
+
+/// Flush the complete `partial` download to disk and atomically move it to `dest`.
+pub fn persist_partial(partial: &Path, dest: &Path) -> Result<(), std::io::Error> {
+    fs::File::open(partial)?.sync_all()?;
+    crate::filesystem::rename(partial, dest)?;
+    sync_parent_dir(dest)
+}
+
+/// Atomically move the temporary directory to `dest`. An existing directory at `dest` is
+/// replaced.
+pub fn persist_dir(tmp: TempDir, dest: &Path) -> Result<(), std::io::Error> {
+    if dest.exists() {
+        fs::remove_dir_all(dest)?;
+    }
+    crate::filesystem::rename(tmp.path(), dest)?;
+    // the directory has been moved, make sure it is not deleted when `tmp` is dropped
+    let _ = tmp.into_path();
+    sync_parent_dir(dest)
+}
+
+/// Make sure that a rename in the parent directory of `path` is written to disk.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// Delete all `*.tmp` files and directories (and `*.partial` downloads) in `cache_dir` that were
+/// last modified more than `max_age` ago. A partial download is kept while a build holds the
+/// lock of its entry. Returns the number of deleted entries.
+pub fn sweep_orphaned_tmp_files(
+    cache_dir: &Path,
+    max_age: Duration,
+) -> Result<usize, std::io::Error> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if !file_name.ends_with(TMP_SUFFIX) && !file_name.ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        // a stalled download of a running build may not have been written to for a while
+        let _lock = match file_name.strip_suffix(PARTIAL_SUFFIX) {
+            Some(name) => {
+                let mut lock =
+                    fslock::LockFile::open(&cache_dir.join(format!("{name}{LOCK_SUFFIX}")))?;
+                if !lock.try_lock()? {
+                    continue;
+                }
+                Some(lock)
+            }
+            None => None,
+        };
+
+        tracing::info!(
+            "Removing orphaned temporary file {}",
+            entry.path().display()
+        );
+        if metadata.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// What [`clean_source_cache`] removed and kept
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupSummary {
+    /// The number of removed entries
+    pub removed: usize,
+    /// The size of the removed entries in bytes
+    pub freed_bytes: u64,
+    /// The size of the remaining entries in bytes
+    pub kept_bytes: u64,
+}
+
+/// The size of a file, or of all files in a directory
+fn entry_size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Remove the entries of the source cache at `cache_dir` that were not used for `max_age`, and
+/// then the least recently used entries until the cache is at most `max_size` bytes large.
+/// Entries that a running build has locked are kept, as are the lock files themselves.
+pub fn clean_source_cache(
+    cache_dir: &Path,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+) -> Result<CleanupSummary, std::io::Error> {
+    let mut summary = CleanupSummary::default();
+    if !cache_dir.is_dir() {
+        return Ok(summary);
+    }
+    summary.removed += sweep_orphaned_tmp_files(cache_dir, ORPHANED_TMP_MAX_AGE)?;
+
+    let now = SystemTime::now();
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.ends_with(LOCK_SUFFIX) || file_name.ends_with(TMP_SUFFIX) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        entries.push((age, entry_size(&entry.path()), file_name, entry.path()));
+    }
+    // the oldest entries first
+    entries.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut total = entries.iter().map(|(_, size, _, _)| size).sum::<u64>();
+    for (age, size, file_name, path) in entries {
+        let expired = max_age.map_or(false, |max_age| age > max_age);
+        let too_large = max_size.map_or(false, |max_size| total > max_size);
+        if !expired && !too_large {
+            continue;
+        }
+
+        // a partial download has the lock of the download
+        let name = file_name.trim_end_matches(PARTIAL_SUFFIX);
+        let mut lock = fslock::LockFile::open(&cache_dir.join(format!("{name}{LOCK_SUFFIX}")))?;
+        if !lock.try_lock()? {
+            tracing::info!("Keeping {}, it is used by a running build", path.display());
+            continue;
+        }
+        tracing::info!("Removing {} from the source cache", path.display());
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+        summary.removed += 1;
+        summary.freed_bytes += size;
+        total -= size;
+    }
+    summary.kept_bytes = total;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use fs_err as fs;
+
+    use super::{
+        clean_source_cache, lock_entry, partial_file, persist_dir, persist_file,
+        sweep_orphaned_tmp_files, tmp_dir, tmp_file, CleanupSummary,
+    };
+
+    #[test]
+    fn interrupted_download_is_swept() {
+        let cache = tempfile::tempdir().unwrap();
+
+        // simulate a process that was killed while writing: the tmp file is left behind
+        // truncated and never renamed
+        let mut tmp = tmp_file(cache.path(), "example_12345678.tar.gz").unwrap();
+        tmp.write_all(b"only half of the cont").unwrap();
+        let (_, orphan) = tmp.keep().unwrap();
+        let truncated = fs::OpenOptions::new().write(true).open(&orphan).unwrap();
+        truncated.set_len(4).unwrap();
+
+        // the final file was never created, so it cannot be picked up as valid cache entry
+        assert!(!cache.path().join("example_12345678.tar.gz").exists());
+
+        // recent tmp files may belong to a concurrent run and are kept
+        assert_eq!(
+            sweep_orphaned_tmp_files(cache.path(), Duration::from_secs(60 * 60)).unwrap(),
+            0
+        );
+        assert!(orphan.exists());
+
+        assert_eq!(
+            sweep_orphaned_tmp_files(cache.path(), Duration::ZERO).unwrap(),
+            1
+        );
+        assert!(!orphan.exists());
+
+        // a partial download is kept while its entry is locked
+        let partial = partial_file(cache.path(), "example_12345678.tar.gz");
+        fs::write(&partial, b"the full").unwrap();
+        let lock = lock_entry(cache.path(), "example_12345678.tar.gz").unwrap();
+        assert_eq!(
+            sweep_orphaned_tmp_files(cache.path(), Duration::ZERO).unwrap(),
+            0
+        );
+        drop(lock);
+        assert_eq!(
+            sweep_orphaned_tmp_files(cache.path(), Duration::ZERO).unwrap(),
+            1
+        );
+        assert!(!partial.exists());
+        fs::remove_file(cache.path().join("example_12345678.tar.gz.lock")).unwrap();
+
+        // a new download succeeds and ends up under the final name
+        let mut tmp = tmp_file(cache.path(), "example_12345678.tar.gz").unwrap();
+        tmp.write_all(b"the full content").unwrap();
+        let dest = cache.path().join("example_12345678.tar.gz");
+        persist_file(tmp, &dest).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "the full content");
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn persist_dir_replaces_existing() {
+        let cache = tempfile::tempdir().unwrap();
+        let dest = cache.path().join("repo");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("old"), "old").unwrap();
+
+        let tmp = tmp_dir(cache.path(), "repo").unwrap();
+        fs::write(tmp.path().join("new"), "new").unwrap();
+        persist_dir(tmp, &dest).unwrap();
+
+        assert!(dest.join("new").exists());
+        assert!(!dest.join("old").exists());
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn clean_old_and_large_entries() {
+        let cache = tempfile::tempdir().unwrap();
+        let entry = |name: &str, size: usize, age_secs: u64| {
+            let path = cache.path().join(name);
+            fs::write(&path, vec![0u8; size]).unwrap();
+            let modified = std::time::SystemTime::now() - Duration::from_secs(age_secs);
+            filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(modified))
+                .unwrap();
+            path
+        };
+        let old = entry("old_12345678.tar.gz", 100, 90 * 24 * 60 * 60);
+        let older = entry("older_12345678.tar.gz", 300, 10 * 60 * 60);
+        let recent = entry("recent_12345678.tar.gz", 200, 60);
+
+        // only the entries that were not used for a month
+        let summary = clean_source_cache(
+            cache.path(),
+            Some(Duration::from_secs(30 * 24 * 60 * 60)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            CleanupSummary {
+                removed: 1,
+                freed_bytes: 100,
+                kept_bytes: 500,
+            }
+        );
+        assert!(!old.exists());
+
+        // the least recently used entries, unless a build holds them
+        let lock = lock_entry(cache.path(), "older_12345678.tar.gz").unwrap();
+        let summary = clean_source_cache(cache.path(), None, Some(250)).unwrap();
+        assert_eq!(summary.removed, 1);
+        assert!(older.exists() && !recent.exists());
+        drop(lock);
+
+        let summary = clean_source_cache(cache.path(), None, Some(250)).unwrap();
+        assert_eq!(summary.kept_bytes, 0);
+        assert!(!older.exists());
+    }
+}
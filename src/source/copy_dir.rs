@@ -10,7 +10,11 @@ use fs_err::create_dir_all;
 use fs_extra::dir::CopyOptions;
 use ignore::WalkBuilder;
 
-use super::SourceError;
+use super::{
+    limits::{LimitTracker, SourceLimits},
+    SourceError,
+};
+use crate::progress::Progress;
 
 /// The copy_dir function accepts additionally a list of globs to ignore or include in the copy process.
 /// It uses the `ignore` crate to read the `.gitignore` file in the source directory and uses the globs
@@ -32,6 +36,8 @@ pub(crate) struct CopyDir<'a> {
     use_git_global: bool,
     hidden: bool,
     copy_options: CopyOptions,
+    limits: Option<(&'a str, SourceLimits)>,
+    progress: Option<&'a Progress>,
 }
 
 impl<'a> CopyDir<'a> {
@@ -45,6 +51,8 @@ impl<'a> CopyDir<'a> {
             use_git_global: false,
             hidden: false,
             copy_options: CopyOptions::new(),
+            limits: None,
+            progress: None,
         }
     }
 
@@ -97,6 +105,17 @@ impl<'a> CopyDir<'a> {
         self
     }
 
+    /// Only copy the files that match one of the `include` globs (all files if there are none),
+    /// and skip the files and directories that match one of the `exclude` globs. The globs are
+    /// matched against the paths relative to the source directory, after the `.gitignore`.
+    pub fn with_globs<I, E>(self, include: I, exclude: E) -> Self
+    where
+        I: IntoIterator<Item = &'a str>,
+        E: IntoIterator<Item = &'a str>,
+    {
+        self.with_include_globs(include).with_exclude_globs(exclude)
+    }
+
     pub fn use_gitignore(mut self, b: bool) -> Self {
         self.use_gitignore = b;
         self
@@ -134,6 +153,19 @@ impl<'a> CopyDir<'a> {
         self
     }
 
+    /// Abort the copy when the files of the source `name` exceed the `limits`. The files that
+    /// were already copied are removed again.
+    pub fn with_limits(mut self, name: &'a str, limits: SourceLimits) -> Self {
+        self.limits = Some((name, limits));
+        self
+    }
+
+    /// Count the copied files in `progress`
+    pub fn with_progress(mut self, progress: &'a Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
     pub fn run(self) -> Result<CopyDirResult<'a>, SourceError> {
         // Create the to path because we're going to copy the contents only
         create_dir_all(self.to_path)?;
@@ -151,11 +183,33 @@ impl<'a> CopyDir<'a> {
             exclude_globs: make_glob_match_map(self.exclude_globs)?,
         };
 
+        let mut tracker = self
+            .limits
+            .map(|(name, limits)| LimitTracker::new(name, limits));
+        let mut created = Vec::new();
+
+        // excluded directories are skipped as a whole, without walking into them
+        let mut excluded_dirs = globset::GlobSetBuilder::new();
+        for glob in result.exclude_globs().keys() {
+            excluded_dirs.add(glob.g.clone());
+        }
+        let excluded_dirs = excluded_dirs.build()?;
+        let root = self.from_path.to_path_buf();
+
         let copied_pathes = WalkBuilder::new(self.from_path)
             // disregard global gitignore
             .git_global(self.use_git_global)
             .git_ignore(self.use_gitignore)
             .hidden(self.hidden)
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
+                !is_dir
+                    || entry
+                        .path()
+                        .strip_prefix(&root)
+                        .map(|path| !excluded_dirs.is_match(path))
+                        .unwrap_or(true)
+            })
             .build()
             .filter_map(|entry| {
                 let entry = match entry {
@@ -224,8 +278,14 @@ impl<'a> CopyDir<'a> {
                 if path.is_dir() {
                     // create the empty dir
                     create_dir_all(&dest_path)?;
+                    created.push(dest_path.clone());
                     Ok(Some(dest_path))
                 } else {
+                    if let Some(tracker) = tracker.as_mut() {
+                        tracker.add_file()?;
+                        tracker.add_bytes(path.symlink_metadata()?.len())?;
+                    }
+
                     // create dir if parent does not exist
                     if let Some(parent) = dest_path.parent() {
                         if !parent.exists() {
@@ -250,6 +310,10 @@ impl<'a> CopyDir<'a> {
                         fs_extra::file::copy(path, &dest_path, &file_options)
                             .map_err(SourceError::FileSystemError)?;
                     }
+                    created.push(dest_path.clone());
+                    if let Some(progress) = self.progress {
+                        progress.inc(1);
+                    }
 
                     tracing::info!(
                         "Copied {} to {}",
@@ -260,13 +324,45 @@ impl<'a> CopyDir<'a> {
                 }
             })
             .filter_map(|res| res.transpose())
-            .collect::<Result<Vec<_>, SourceError>>()?;
+            .collect::<Result<Vec<_>, SourceError>>();
+
+        let copied_pathes = match copied_pathes {
+            Ok(copied_pathes) => copied_pathes,
+            Err(e @ SourceError::LimitExceeded { .. }) => {
+                remove_created(&created, self.to_path);
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
 
         result.copied_pathes = copied_pathes;
         Ok(result)
     }
 }
 
+/// Remove the files and directories that were copied to `to_path` (and the parent directories
+/// that are empty afterwards).
+fn remove_created(created: &[PathBuf], to_path: &Path) {
+    for path in created.iter().rev() {
+        let removed = match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => fs_err::remove_dir(path),
+            Ok(_) => fs_err::remove_file(path),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = removed {
+            tracing::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir == to_path || !dir.starts_with(to_path) || fs_err::remove_dir(dir).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+}
+
 pub(crate) struct CopyDirResult<'a> {
     copied_pathes: Vec<PathBuf>,
     include_globs: HashMap<Glob<'a>, Match>,
@@ -467,6 +563,75 @@ mod test {
         );
     }
 
+    #[test]
+    fn copydir_with_globs() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let dir = tmp_dir.path().join("src");
+
+        // src/lib.py
+        // src/data/large.bin
+        // src/docs/index.md
+        // src/node_modules/pkg/index.js
+        // src/build/ (gitignored)
+        for sub_dir in ["data", "docs", "node_modules/pkg", "build"] {
+            fs::create_dir_all(dir.join(sub_dir)).unwrap();
+        }
+        fs::write(dir.join("lib.py"), "").unwrap();
+        fs::write(dir.join("data/large.bin"), "").unwrap();
+        fs::write(dir.join("docs/index.md"), "").unwrap();
+        fs::write(dir.join("node_modules/pkg/index.js"), "").unwrap();
+        fs::write(dir.join("build/lib.py"), "").unwrap();
+        fs::write(dir.join(".gitignore"), "build/\n").unwrap();
+        fs::create_dir(dir.join(".git")).unwrap();
+
+        let copied = |include: &[&str], exclude: &[&str]| {
+            let dest_dir = tempfile::TempDir::new().unwrap();
+            let copy_dir = super::CopyDir::new(&dir, dest_dir.path())
+                .use_gitignore(true)
+                .with_globs(include.iter().copied(), exclude.iter().copied())
+                .run()
+                .unwrap();
+            let mut copied = copy_dir
+                .copied_pathes()
+                .iter()
+                .map(|p| {
+                    p.strip_prefix(dest_dir.path())
+                        .unwrap()
+                        .to_string_lossy()
+                        .replace('\\', "/")
+                })
+                // hidden files are copied as well
+                .filter(|p| !p.starts_with(".git"))
+                .collect::<Vec<_>>();
+            copied.sort();
+            copied
+        };
+
+        // no include globs copy everything that is not gitignored
+        assert_eq!(
+            copied(&[], &[]),
+            [
+                "data/large.bin",
+                "docs/index.md",
+                "lib.py",
+                "node_modules/pkg/index.js"
+            ]
+        );
+
+        // excluded directories are skipped with all their contents
+        assert_eq!(
+            copied(&[], &["node_modules", "data"]),
+            ["docs/index.md", "lib.py"]
+        );
+
+        // the parent directories of included files are created
+        assert_eq!(copied(&["**/*.md"], &[]), ["docs/index.md"]);
+
+        // the globs cannot include gitignored files
+        assert_eq!(copied(&["*.py"], &[]), ["lib.py"]);
+        assert_eq!(copied(&["*.py", "**/*.js"], &["node_modules"]), ["lib.py"]);
+    }
+
     #[test]
     fn copydir_with_broken_symlink() {
         #[cfg(windows)]
@@ -506,4 +671,49 @@ mod test {
             std::path::PathBuf::from("/does/not/exist")
         );
     }
+
+    #[test]
+    fn copydir_with_limits() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let dir = tmp_dir.path().join("test_copy_dir");
+
+        for i in 0..10 {
+            let sub_dir = dir.join(format!("dir_{i}"));
+            fs::create_dir_all(&sub_dir).unwrap();
+            fs::write(sub_dir.join("file.txt"), "content").unwrap();
+        }
+
+        let limits = crate::source::limits::SourceLimits {
+            max_files: 5,
+            ..Default::default()
+        };
+        let dest_dir = tmp_dir.path().join("dest");
+        fs::create_dir_all(&dest_dir).unwrap();
+        fs::write(dest_dir.join("existing.txt"), "keep me").unwrap();
+
+        let result = super::CopyDir::new(&dir, &dest_dir)
+            .with_limits("./test_copy_dir", limits)
+            .run();
+        assert!(matches!(
+            result,
+            Err(crate::source::SourceError::LimitExceeded { .. })
+        ));
+
+        // only the partial copy is removed
+        let remaining = fs::read_dir(&dest_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, vec!["existing.txt"]);
+
+        let limits = crate::source::limits::SourceLimits {
+            max_size: 10 * "content".len() as u64,
+            max_files: 10,
+        };
+        let copy_dir = super::CopyDir::new(&dir, &dest_dir)
+            .with_limits("./test_copy_dir", limits)
+            .run()
+            .unwrap();
+        assert_eq!(copy_dir.copied_pathes().len(), 10);
+    }
 }
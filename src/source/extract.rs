@@ -0,0 +1,1191 @@
+//! In-process extraction of (compressed) tar and zip archives, so that sources can be fetched on
+//! systems without a `tar` executable.
+//!
+//! If all entries of an archive are inside a single top-level folder (e.g. `pkg-1.0/`), the
+//! result is the same as `tar -xf <archive> --strip-components=1 --preserve-permissions`: the
+//! folder is removed from the path of every entry. Archives with files at the top level or with
+//! several top-level folders are extracted as they are. The permissions (in particular the
+//! executable bits) are kept on unix.
+
+use std::{
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use fs_err as fs;
+
+use super::{limits::LimitTracker, SourceError};
+use crate::progress::Progress;
+
+/// The compression of a tar archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+/// The format of a source archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ArchiveFormat {
+    Tar(Compression),
+    Zip,
+}
+
+/// The offset and the value of the magic bytes of (ustar and GNU) tar headers
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+impl ArchiveFormat {
+    /// The format of an archive from its file name
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        let file_name = file_name.to_lowercase();
+        let has_suffix = |suffixes: &[&str]| suffixes.iter().any(|s| file_name.ends_with(s));
+
+        if has_suffix(&[".tar.gz", ".tgz"]) {
+            Some(Self::Tar(Compression::Gzip))
+        } else if has_suffix(&[".tar.bz2", ".tbz2"]) {
+            Some(Self::Tar(Compression::Bzip2))
+        } else if has_suffix(&[".tar.xz", ".txz"]) {
+            Some(Self::Tar(Compression::Xz))
+        } else if has_suffix(&[".tar.zst", ".tzst"]) {
+            Some(Self::Tar(Compression::Zstd))
+        } else if has_suffix(&[".tar"]) {
+            Some(Self::Tar(Compression::None))
+        } else if has_suffix(&[".zip"]) {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+
+    /// The format of an archive from its first bytes. Compressed files are only recognized as
+    /// archives if they contain a tar archive.
+    pub fn sniff(path: &Path) -> Result<Option<Self>, io::Error> {
+        let mut head = Vec::new();
+        fs::File::open(path)?.take(512).read_to_end(&mut head)?;
+
+        let compression = if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            return Ok(Some(Self::Zip));
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if head.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        };
+
+        // look for the magic bytes of the first tar header
+        let mut tar_head = Vec::new();
+        let read = tar_reader(path, compression)
+            .and_then(|reader| reader.take(512).read_to_end(&mut tar_head));
+        let is_tar = read.is_ok()
+            && tar_head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len())
+                == Some(TAR_MAGIC);
+        Ok(is_tar.then_some(Self::Tar(compression)))
+    }
+
+    /// The format of an archive from its file name or, if the file name has no extension that
+    /// could name a format (e.g. `download` or `pkg-1.2.3`), from its contents. Files with other
+    /// extensions (e.g. `.whl` or `.jar`) are not archives, even if they are zip files.
+    pub fn detect(path: &Path) -> Result<Option<Self>, io::Error> {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(format) = Self::from_file_name(&file_name) {
+            return Ok(Some(format));
+        }
+
+        let has_extension = path.extension().map_or(false, |ext| {
+            ext.to_string_lossy()
+                .chars()
+                .any(|c| c.is_ascii_alphabetic())
+        });
+        if has_extension {
+            Ok(None)
+        } else {
+            Self::sniff(path)
+        }
+    }
+}
+
+/// The top-level folder that contains all entries of an archive, if there is one. Paths are
+/// passed with `/` as separator, and the names of folders end with `/`.
+fn top_level_folder<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let mut top_level = None;
+    for name in names {
+        let name = name.trim_start_matches("./");
+        if name.is_empty() || name == "." {
+            // the archive root itself
+            continue;
+        }
+        match name.split_once('/') {
+            // a file at the top level
+            None => return None,
+            Some((first, _)) if top_level.map_or(false, |t| t != first) => return None,
+            Some((first, _)) => top_level = Some(first),
+        }
+    }
+    top_level
+}
+
+/// Whether the `top_level` folder of `archive` is stripped, which is logged
+fn strip_top_level(archive: &Path, top_level: Option<&str>) -> bool {
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    match top_level {
+        Some(top_level) => tracing::info!(
+            "All entries of {} are in `{}/`, extracting its contents",
+            name,
+            top_level
+        ),
+        None => tracing::info!(
+            "{} has no single top-level folder, extracting it as it is",
+            name
+        ),
+    }
+    top_level.is_some()
+}
+
+/// Whether a tar entry only carries metadata for other entries (e.g. the `pax_global_header`
+/// of archives created by `git archive`)
+fn is_metadata(entry_type: tar::EntryType) -> bool {
+    entry_type.is_pax_global_extensions()
+        || entry_type.is_pax_local_extensions()
+        || entry_type.is_gnu_longname()
+        || entry_type.is_gnu_longlink()
+}
+
+/// Open the tar archive at `archive` with the decompressor for `compression`
+pub(crate) fn tar_reader(
+    archive: &Path,
+    compression: Compression,
+) -> Result<Box<dyn Read>, io::Error> {
+    let file = fs::File::open(archive)?;
+    Ok(match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+        Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(file)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(file)?),
+    })
+}
+
+/// The path of an archive entry relative to the target directory, optionally without its first
+/// component. Returns `None` for the top-level folder itself when stripping (which
+/// `tar --strip-components=1` skips as well), and for the archive root.
+fn entry_path(path: &Path, strip_top_level: bool) -> Result<Option<PathBuf>, io::Error> {
+    let mut components = path
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir | Component::RootDir));
+    if strip_top_level && matches!(components.next(), Some(Component::ParentDir)) {
+        return Err(outside_of_target());
+    }
+
+    let mut relative = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            _ => return Err(outside_of_target()),
+        }
+    }
+
+    Ok((!relative.as_os_str().is_empty()).then_some(relative))
+}
+
+fn outside_of_target() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the entry points outside of the extraction directory",
+    )
+}
+
+/// Check that the symlink at `dest` with the target `link_target` stays inside `root` (which must
+/// be canonical). The parent of `dest` is resolved on the filesystem, because it can be a
+/// symlink itself, and the target may only go up (`..`) before it names any folder, so that it
+/// cannot go up from a symlink either.
+fn check_link_target(root: &Path, dest: &Path, link_target: &Path) -> Result<(), io::Error> {
+    let mut resolved = match dest.parent() {
+        Some(parent) => fs::canonicalize(parent)?,
+        None => root.to_path_buf(),
+    };
+    let mut named = false;
+    for component in link_target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if !named && resolved != root && resolved.starts_with(root) => {
+                resolved.pop();
+            }
+            Component::Normal(part) if resolved.starts_with(root) => {
+                named = true;
+                resolved.push(part);
+            }
+            _ => return Err(link_outside_of_target(link_target)),
+        }
+    }
+    Ok(())
+}
+
+fn link_outside_of_target(link_target: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "the symlink to `{}` points outside of the extraction directory",
+            link_target.display()
+        ),
+    )
+}
+
+/// The entries of an archive that are handled after all entries are unpacked, with their paths
+/// relative to the target directory
+#[derive(Default)]
+struct Unpacked {
+    /// The permissions of the directories, which are applied at the end so that read-only
+    /// directories can still be filled
+    directories: Vec<(PathBuf, u32)>,
+    /// The targets of the symlinks
+    links: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Unpack the entries of `tar` into `target_directory` as they are, counting the files and their
+/// sizes in `tracker` and the entries in `progress`. Symlinks that point outside of
+/// `target_directory` are rejected.
+fn unpack_tar_entries<R: Read>(
+    archive: &Path,
+    tar: &mut tar::Archive<R>,
+    target_directory: &Path,
+    tracker: &mut LimitTracker,
+    progress: &Progress,
+) -> Result<Unpacked, SourceError> {
+    let read_error = |e: io::Error| {
+        SourceError::ExtractionError(format!("Failed to read {}: {}", archive.display(), e))
+    };
+
+    tar.set_preserve_permissions(true);
+    tar.set_preserve_mtime(true);
+    tar.set_overwrite(true);
+
+    let root = fs::canonicalize(target_directory)?;
+    let mut unpacked = Unpacked::default();
+    for entry in tar.entries().map_err(read_error)? {
+        let mut entry = entry.map_err(read_error)?;
+        if is_metadata(entry.header().entry_type()) {
+            continue;
+        }
+        progress.inc(1);
+        let path = entry.path().map_err(read_error)?.into_owned();
+        let entry_error = |e: io::Error| {
+            SourceError::ExtractionError(format!(
+                "Failed to extract `{}` from {}: {}",
+                path.display(),
+                archive.display(),
+                e
+            ))
+        };
+
+        let relative = match entry_path(&path, false).map_err(entry_error)? {
+            Some(relative) => relative,
+            None => continue,
+        };
+        let dest = target_directory.join(&relative);
+        let entry_type = entry.header().entry_type();
+
+        if entry_type.is_dir() {
+            fs::create_dir_all(&dest).map_err(entry_error)?;
+            let mode = entry.header().mode().map_err(entry_error)?;
+            unpacked.directories.push((relative, mode));
+            continue;
+        }
+
+        // the size of the data of an entry is exactly what is unpacked, so the limits are
+        // checked before anything is written
+        tracker.add_file()?;
+        tracker.add_bytes(entry.size())?;
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(entry_error)?;
+        }
+
+        if entry_type.is_hard_link() {
+            // the target of a hard link is another entry of the archive
+            let link_name = entry
+                .link_name()
+                .map_err(entry_error)?
+                .ok_or_else(|| {
+                    entry_error(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "hard link without a target",
+                    ))
+                })?
+                .into_owned();
+            let link_target = entry_path(&link_name, false)
+                .map_err(entry_error)?
+                .ok_or_else(|| entry_error(outside_of_target()))?;
+            if dest.symlink_metadata().is_ok() {
+                fs::remove_file(&dest).map_err(entry_error)?;
+            }
+            fs::hard_link(target_directory.join(link_target), &dest).map_err(entry_error)?;
+            continue;
+        }
+
+        if entry_type.is_symlink() {
+            // a symlink to a folder outside would let the following entries write there
+            let link_target = entry
+                .link_name()
+                .map_err(entry_error)?
+                .ok_or_else(|| {
+                    entry_error(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "symlink without a target",
+                    ))
+                })?
+                .into_owned();
+            check_link_target(&root, &dest, &link_target).map_err(entry_error)?;
+            unpacked.links.push((relative, link_target));
+        }
+        entry.unpack(&dest).map_err(entry_error)?;
+    }
+
+    Ok(unpacked)
+}
+
+/// The name of the only entry of `directory`, if that entry is a folder
+fn only_folder(directory: &Path) -> Result<Option<String>, io::Error> {
+    let mut entries = fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    if entries.len() != 1 || !entries[0].file_type()?.is_dir() {
+        return Ok(None);
+    }
+    Ok(Some(
+        entries.remove(0).file_name().to_string_lossy().into_owned(),
+    ))
+}
+
+/// A staging folder in `target_directory` to extract an archive into. It is removed with
+/// everything in it when the extraction fails, e.g. because a limit is exceeded, so that no
+/// partially extracted files are left behind.
+fn staging_directory(target_directory: &Path) -> Result<tempfile::TempDir, io::Error> {
+    fs::create_dir_all(target_directory)?;
+    tempfile::Builder::new()
+        .prefix(".extract")
+        .tempdir_in(target_directory)
+}
+
+/// Move the contents of `source` into `target_directory`, merging the folders that exist in
+/// both and replacing everything else
+fn move_contents(source: &Path, target_directory: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(target_directory)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let dest = target_directory.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        match dest.symlink_metadata() {
+            Ok(existing) if is_dir && existing.is_dir() => {
+                move_contents(&entry.path(), &dest)?;
+                continue;
+            }
+            Ok(existing) if existing.is_dir() => fs::remove_dir_all(&dest)?,
+            Ok(_) => fs::remove_file(&dest)?,
+            Err(_) => {}
+        }
+        fs::rename(entry.path(), dest)?;
+    }
+    Ok(())
+}
+
+/// Unpack the tar archive at `archive` into `target_directory`. The top-level folder is stripped
+/// if it contains all entries. The extracted files are counted in `tracker`, which fails as soon
+/// as a limit is exceeded, and the entries in `progress`.
+pub(crate) fn unpack_tar(
+    archive: &Path,
+    compression: Compression,
+    target_directory: &Path,
+    tracker: &mut LimitTracker,
+    progress: &Progress,
+) -> Result<(), SourceError> {
+    let read_error = |e: io::Error| {
+        SourceError::ExtractionError(format!("Failed to read {}: {}", archive.display(), e))
+    };
+    let extract_error = |e: io::Error| {
+        SourceError::ExtractionError(format!("Failed to extract {}: {}", archive.display(), e))
+    };
+
+    // whether the top-level folder is stripped is only known after all entries are read, so the
+    // archive is unpacked into a staging folder instead of decompressing it twice
+    let staging = staging_directory(target_directory).map_err(extract_error)?;
+
+    let mut tar = tar::Archive::new(tar_reader(archive, compression).map_err(read_error)?);
+    let unpacked = unpack_tar_entries(archive, &mut tar, staging.path(), tracker, progress)?;
+
+    let top_level = only_folder(staging.path()).map_err(extract_error)?;
+    strip_top_level(archive, top_level.as_deref());
+    let root = match &top_level {
+        Some(folder) => {
+            // the symlinks must not point out of the folder that is stripped either
+            let root = fs::canonicalize(staging.path().join(folder)).map_err(extract_error)?;
+            for (relative, link_target) in &unpacked.links {
+                check_link_target(&root, &staging.path().join(relative), link_target).map_err(
+                    |e| {
+                        SourceError::ExtractionError(format!(
+                            "Failed to extract `{}` from {}: {}",
+                            relative.display(),
+                            archive.display(),
+                            e
+                        ))
+                    },
+                )?;
+            }
+            root
+        }
+        None => staging.path().to_path_buf(),
+    };
+    move_contents(&root, target_directory).map_err(extract_error)?;
+
+    let directories = unpacked
+        .directories
+        .into_iter()
+        .filter_map(|(relative, mode)| {
+            let relative = match &top_level {
+                // the top-level folder itself is skipped, like `tar --strip-components=1` does
+                Some(folder) => relative.strip_prefix(folder).ok()?.to_path_buf(),
+                None => relative,
+            };
+            (!relative.as_os_str().is_empty()).then(|| (target_directory.join(relative), mode))
+        })
+        .collect();
+    set_directory_permissions(directories)
+}
+
+/// Unpack the conda package at `archive` (`.conda` or `.tar.bz2`) into `target_directory`,
+/// keeping its full layout. The inner archives of a `.conda` package are unpacked while they are
+/// streamed out of it, so their contents are counted in `tracker` as well.
+pub(crate) fn unpack_conda_package(
+    archive: &Path,
+    target_directory: &Path,
+    tracker: &mut LimitTracker,
+    progress: &Progress,
+) -> Result<(), SourceError> {
+    let read_error = |e: io::Error| {
+        SourceError::ExtractionError(format!("Failed to read {}: {}", archive.display(), e))
+    };
+    let zip_error = |e: zip::result::ZipError| {
+        SourceError::ExtractionError(format!("Failed to read {}: {}", archive.display(), e))
+    };
+    let extract_error = |e: io::Error| {
+        SourceError::ExtractionError(format!("Failed to extract {}: {}", archive.display(), e))
+    };
+
+    let staging = staging_directory(target_directory).map_err(extract_error)?;
+    let is_conda = archive
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .ends_with(".conda");
+
+    let mut directories = Vec::new();
+    if is_conda {
+        let mut zip = zip::ZipArchive::new(fs::File::open(archive)?).map_err(zip_error)?;
+        for index in 0..zip.len() {
+            let entry = zip.by_index(index).map_err(zip_error)?;
+            // `metadata.json` only describes the format of the package
+            if !entry.name().ends_with(".tar.zst") {
+                continue;
+            }
+            let decoder = zstd::stream::read::Decoder::new(entry).map_err(read_error)?;
+            let mut tar = tar::Archive::new(decoder);
+            let unpacked =
+                unpack_tar_entries(archive, &mut tar, staging.path(), tracker, progress)?;
+            directories.extend(unpacked.directories);
+        }
+    } else {
+        let mut tar =
+            tar::Archive::new(tar_reader(archive, Compression::Bzip2).map_err(read_error)?);
+        directories =
+            unpack_tar_entries(archive, &mut tar, staging.path(), tracker, progress)?.directories;
+    }
+    move_contents(staging.path(), target_directory).map_err(extract_error)?;
+
+    set_directory_permissions(
+        directories
+            .into_iter()
+            .map(|(relative, mode)| (target_directory.join(relative), mode))
+            .collect(),
+    )
+}
+
+/// Apply the permissions of the extracted directories, innermost first
+fn set_directory_permissions(directories: Vec<(PathBuf, u32)>) -> Result<(), SourceError> {
+    #[cfg(unix)]
+    for (directory, mode) in directories.into_iter().rev() {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&directory, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    drop(directories);
+
+    Ok(())
+}
+
+/// Unpack the zip archive at `archive` into `target_directory`. The top-level folder is
+/// stripped if it contains all entries, and unix permissions (and symlinks) are restored from
+/// the external attributes of the entries. The extracted files are counted in `tracker`, which
+/// fails as soon as a limit is exceeded, and the entries in `progress`.
+pub(crate) fn unpack_zip(
+    archive: &Path,
+    target_directory: &Path,
+    tracker: &mut LimitTracker,
+    progress: &Progress,
+) -> Result<(), SourceError> {
+    let read_error = |e: zip::result::ZipError| {
+        SourceError::ExtractionError(format!("Failed to read {}: {}", archive.display(), e))
+    };
+    let extract_error = |e: io::Error| {
+        SourceError::ExtractionError(format!("Failed to extract {}: {}", archive.display(), e))
+    };
+
+    let mut zip = zip::ZipArchive::new(fs::File::open(archive)?).map_err(read_error)?;
+
+    // zips that were created on Windows can use `\` as separator
+    let names = zip
+        .file_names()
+        .map(|name| name.replace('\\', "/"))
+        .collect::<Vec<_>>();
+    let strip = strip_top_level(archive, top_level_folder(names.iter().map(String::as_str)));
+
+    let staging = staging_directory(target_directory).map_err(extract_error)?;
+    #[cfg(unix)]
+    let root = fs::canonicalize(staging.path())?;
+    let mut directories = Vec::new();
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index).map_err(read_error)?;
+        progress.inc(1);
+        let name = entry.name().replace('\\', "/");
+        let entry_error = |e: io::Error| {
+            SourceError::ExtractionError(format!(
+                "Failed to extract `{}` from {}: {}",
+                name,
+                archive.display(),
+                e
+            ))
+        };
+
+        let relative = match entry_path(Path::new(&name), strip).map_err(entry_error)? {
+            Some(relative) => relative,
+            None => continue,
+        };
+        let dest = staging.path().join(&relative);
+        let mode = entry.unix_mode();
+
+        if name.ends_with('/') {
+            fs::create_dir_all(&dest).map_err(entry_error)?;
+            if let Some(mode) = mode {
+                directories.push((relative, mode));
+            }
+            continue;
+        }
+
+        tracker.add_file()?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(entry_error)?;
+        }
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(&dest).map_err(entry_error)?;
+        }
+
+        #[cfg(unix)]
+        {
+            const S_IFMT: u32 = 0o170000;
+            const S_IFLNK: u32 = 0o120000;
+            if mode.map_or(false, |mode| mode & S_IFMT == S_IFLNK) {
+                // the contents of a symlink entry are the target of the link
+                let mut link_target = String::new();
+                entry
+                    .read_to_string(&mut link_target)
+                    .map_err(entry_error)?;
+                check_link_target(&root, &dest, Path::new(&link_target)).map_err(entry_error)?;
+                std::os::unix::fs::symlink(link_target, &dest).map_err(entry_error)?;
+                continue;
+            }
+        }
+
+        // the sizes in the headers of a zip archive can be wrong, so the bytes that come out of
+        // the decompressor are counted
+        let mut file = fs::File::create(&dest).map_err(entry_error)?;
+        tracker.copy(&mut entry, &mut file, entry_error)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = mode {
+                fs::set_permissions(&dest, std::fs::Permissions::from_mode(mode & 0o7777))
+                    .map_err(entry_error)?;
+            }
+        }
+    }
+    move_contents(staging.path(), target_directory).map_err(extract_error)?;
+
+    set_directory_permissions(
+        directories
+            .into_iter()
+            .map(|(relative, mode)| (target_directory.join(relative), mode))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::Path;
+
+    use fs_err as fs;
+
+    use super::{top_level_folder, unpack_tar, unpack_zip, ArchiveFormat, Compression};
+    use crate::{
+        progress::Progress,
+        source::{
+            limits::{LimitTracker, SourceLimits},
+            SourceError,
+        },
+    };
+
+    fn tracker() -> LimitTracker {
+        LimitTracker::new("archive", SourceLimits::default())
+    }
+
+    fn header(entry_type: tar::EntryType, size: u64, mode: u32) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(mode);
+        header
+    }
+
+    /// Writes a tar archive with a single top-level folder (and the global header of
+    /// `git archive`) and returns the finished writer
+    fn write_source_tar<W: Write>(writer: W) -> W {
+        let mut builder = tar::Builder::new(writer);
+        let comment = b"52 comment=0123456789abcdef0123456789abcdef01234567\n";
+        builder
+            .append_data(
+                &mut header(tar::EntryType::XGlobalHeader, comment.len() as u64, 0o644),
+                "pax_global_header",
+                &comment[..],
+            )
+            .unwrap();
+        let dir = header(tar::EntryType::Directory, 0, 0o755);
+        builder
+            .append_data(&mut dir.clone(), "pkg-1.0/", std::io::empty())
+            .unwrap();
+        builder
+            .append_data(&mut dir.clone(), "pkg-1.0/bin/", std::io::empty())
+            .unwrap();
+
+        let script = b"#!/bin/sh\necho hello\n";
+        builder
+            .append_data(
+                &mut header(tar::EntryType::Regular, script.len() as u64, 0o755),
+                "pkg-1.0/bin/hello",
+                &script[..],
+            )
+            .unwrap();
+        builder
+            .append_data(
+                &mut header(tar::EntryType::Regular, 6, 0o644),
+                "pkg-1.0/README",
+                &b"readme"[..],
+            )
+            .unwrap();
+        builder
+            .append_link(
+                &mut header(tar::EntryType::Link, 0, 0o644),
+                "pkg-1.0/README.copy",
+                "pkg-1.0/README",
+            )
+            .unwrap();
+        builder
+            .append_link(
+                &mut header(tar::EntryType::Symlink, 0, 0o777),
+                "pkg-1.0/bin/hello-link",
+                "hello",
+            )
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn check_extracted(dest: &Path) {
+        assert!(!dest.join("pkg-1.0").exists());
+        // the staging folder is removed
+        assert_eq!(fs::read_dir(dest).unwrap().count(), 3);
+        assert_eq!(fs::read_to_string(dest.join("README")).unwrap(), "readme");
+        assert_eq!(
+            fs::read_to_string(dest.join("README.copy")).unwrap(),
+            "readme"
+        );
+        assert!(dest.join("bin/hello").is_file());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &str| fs::metadata(dest.join(p)).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode("bin/hello"), 0o755);
+            assert_eq!(mode("README"), 0o644);
+            assert_eq!(
+                fs::read_link(dest.join("bin/hello-link")).unwrap(),
+                Path::new("hello")
+            );
+        }
+    }
+
+    #[test]
+    fn extract_compressed_tarballs() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let tar_gz = tmp.path().join("pkg-1.0.tar.gz");
+        write_source_tar(flate2::write::GzEncoder::new(
+            fs::File::create(&tar_gz).unwrap(),
+            flate2::Compression::default(),
+        ))
+        .finish()
+        .unwrap();
+
+        let tar_bz2 = tmp.path().join("pkg-1.0.tar.bz2");
+        write_source_tar(bzip2::write::BzEncoder::new(
+            fs::File::create(&tar_bz2).unwrap(),
+            bzip2::Compression::default(),
+        ))
+        .finish()
+        .unwrap();
+
+        let tar_xz = tmp.path().join("pkg-1.0.tar.xz");
+        write_source_tar(xz2::write::XzEncoder::new(
+            fs::File::create(&tar_xz).unwrap(),
+            6,
+        ))
+        .finish()
+        .unwrap();
+
+        let tar_zst = tmp.path().join("pkg-1.0.tzst");
+        write_source_tar(
+            zstd::stream::write::Encoder::new(fs::File::create(&tar_zst).unwrap(), 3).unwrap(),
+        )
+        .finish()
+        .unwrap();
+
+        let tar = tmp.path().join("pkg-1.0.tar");
+        write_source_tar(fs::File::create(&tar).unwrap());
+
+        let archives = [
+            (tar_gz, Compression::Gzip),
+            (tar_bz2, Compression::Bzip2),
+            (tar_xz, Compression::Xz),
+            (tar_zst, Compression::Zstd),
+            (tar, Compression::None),
+        ];
+        for (i, (archive, compression)) in archives.into_iter().enumerate() {
+            // the same archive without a useful file name is recognized by its contents
+            let download = tmp.path().join(format!("download_{i}"));
+            fs::copy(&archive, &download).unwrap();
+
+            for archive in [archive, download] {
+                assert_eq!(
+                    ArchiveFormat::detect(&archive).unwrap(),
+                    Some(ArchiveFormat::Tar(compression))
+                );
+                let dest = tmp.path().join("work").join(archive.file_name().unwrap());
+                fs::create_dir_all(&dest).unwrap();
+                unpack_tar(
+                    &archive,
+                    compression,
+                    &dest,
+                    &mut tracker(),
+                    &Progress::hidden(),
+                )
+                .unwrap();
+                check_extracted(&dest);
+                assert!(!dest.join("pax_global_header").exists());
+            }
+        }
+    }
+
+    #[test]
+    fn detect_archive_format() {
+        let format = ArchiveFormat::from_file_name;
+        assert_eq!(
+            format("x.tar.zst"),
+            Some(ArchiveFormat::Tar(Compression::Zstd))
+        );
+        assert_eq!(format("x.TGZ"), Some(ArchiveFormat::Tar(Compression::Gzip)));
+        assert_eq!(
+            format("x.tbz2"),
+            Some(ArchiveFormat::Tar(Compression::Bzip2))
+        );
+        assert_eq!(format("x.txz"), Some(ArchiveFormat::Tar(Compression::Xz)));
+        assert_eq!(format("v1.2.3.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("x.tar.gz.sig"), None);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let zip = tmp.path().join("download");
+        write_zip(&zip, &[("proj/README", "readme", 0o644)]);
+        assert_eq!(
+            ArchiveFormat::detect(&zip).unwrap(),
+            Some(ArchiveFormat::Zip)
+        );
+
+        // files with an extension are never sniffed
+        let wheel = tmp.path().join("pkg-1.0-py3-none-any.whl");
+        fs::copy(&zip, &wheel).unwrap();
+        assert_eq!(ArchiveFormat::detect(&wheel).unwrap(), None);
+        assert_eq!(
+            ArchiveFormat::sniff(&wheel).unwrap(),
+            Some(ArchiveFormat::Zip)
+        );
+
+        // a compressed file that is not a tar archive
+        let gz = tmp.path().join("data-1.0");
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&gz).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&[b'x'; 1024]).unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(ArchiveFormat::detect(&gz).unwrap(), None);
+
+        let text = tmp.path().join("script");
+        fs::write(&text, "#!/bin/sh\n").unwrap();
+        assert_eq!(ArchiveFormat::detect(&text).unwrap(), None);
+    }
+
+    /// Writes an uncompressed tar archive with the given files
+    fn write_tar(path: &Path, files: &[(&str, &str)]) {
+        let mut builder = tar::Builder::new(fs::File::create(path).unwrap());
+        for (name, contents) in files {
+            builder
+                .append_data(
+                    &mut header(tar::EntryType::Regular, contents.len() as u64, 0o644),
+                    name,
+                    contents.as_bytes(),
+                )
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn extract_flat_tarball() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("tool-1.0-win64.tar");
+        write_tar(
+            &archive,
+            &[("tool.exe", "binary"), ("lib/tool.dll", "library")],
+        );
+
+        let dest = tmp.path().join("work");
+        unpack_tar(
+            &archive,
+            Compression::None,
+            &dest,
+            &mut tracker(),
+            &Progress::hidden(),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(dest.join("tool.exe")).unwrap(), "binary");
+        assert_eq!(
+            fs::read_to_string(dest.join("lib/tool.dll")).unwrap(),
+            "library"
+        );
+    }
+
+    #[test]
+    fn extract_tarball_with_multiple_top_level_folders() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("multi.tar");
+        write_tar(
+            &archive,
+            &[
+                ("./src/main.c", "int main() {}"),
+                ("./docs/index.md", "docs"),
+            ],
+        );
+
+        let dest = tmp.path().join("work");
+        unpack_tar(
+            &archive,
+            Compression::None,
+            &dest,
+            &mut tracker(),
+            &Progress::hidden(),
+        )
+        .unwrap();
+        assert!(dest.join("src/main.c").is_file());
+        assert!(dest.join("docs/index.md").is_file());
+    }
+
+    #[test]
+    fn entries_outside_of_the_target_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("evil.tar");
+
+        // `tar::Builder` refuses to write such a path, so the header is filled in manually
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        let mut evil = header(tar::EntryType::Regular, 4, 0o644);
+        let name = b"pkg/../../evil";
+        evil.as_old_mut().name[..name.len()].copy_from_slice(name);
+        evil.set_cksum();
+        builder.append(&evil, &b"evil"[..]).unwrap();
+        builder.into_inner().unwrap();
+
+        let dest = tmp.path().join("work");
+        let err = unpack_tar(
+            &archive,
+            Compression::None,
+            &dest,
+            &mut tracker(),
+            &Progress::hidden(),
+        )
+        .unwrap_err();
+        match err {
+            SourceError::ExtractionError(msg) => assert!(msg.contains("pkg/../../evil"), "{msg}"),
+            _ => panic!("expected an extraction error, got {err:?}"),
+        }
+        assert!(!tmp.path().join("evil").exists());
+    }
+
+    /// Writes a tar archive with the given symlinks (`(name, target)`), followed by a file
+    fn write_links_tar(path: &Path, links: &[(&str, &str)], file: &str) {
+        let mut builder = tar::Builder::new(fs::File::create(path).unwrap());
+        for (name, target) in links {
+            builder
+                .append_link(&mut header(tar::EntryType::Symlink, 0, 0o777), name, target)
+                .unwrap();
+        }
+        builder
+            .append_data(
+                &mut header(tar::EntryType::Regular, 4, 0o644),
+                file,
+                &b"file"[..],
+            )
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_outside_of_the_target_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        let absolute = outside.to_string_lossy().into_owned();
+
+        let cases = [
+            // the following entry would be written through the link
+            (vec![("a", absolute.as_str())], "a/x"),
+            (vec![("a", "../outside")], "a/x"),
+            // inside the archive, but outside of the top-level folder that is stripped
+            (vec![("pkg/a", "../outside")], "pkg/README"),
+            // `b` is the parent of the target directory, because `a` is a link to `.`
+            (vec![("a", "."), ("b", "a/..")], "README"),
+        ];
+        for (i, (links, file)) in cases.into_iter().enumerate() {
+            let archive = tmp.path().join(format!("evil_{i}.tar"));
+            write_links_tar(&archive, &links, file);
+            let dest = tmp.path().join(format!("work_{i}"));
+            let err = unpack_tar(
+                &archive,
+                Compression::None,
+                &dest,
+                &mut tracker(),
+                &Progress::hidden(),
+            )
+            .unwrap_err();
+            match err {
+                SourceError::ExtractionError(msg) => {
+                    assert!(
+                        msg.contains("points outside of the extraction directory"),
+                        "{msg}"
+                    )
+                }
+                _ => panic!("expected an extraction error, got {err:?}"),
+            }
+        }
+        assert!(!outside.join("x").exists());
+
+        // links to other entries of the archive are fine
+        let archive = tmp.path().join("links.tar");
+        write_links_tar(&archive, &[("pkg/bin/tool", "../lib/tool")], "pkg/lib/tool");
+        let dest = tmp.path().join("work");
+        unpack_tar(
+            &archive,
+            Compression::None,
+            &dest,
+            &mut tracker(),
+            &Progress::hidden(),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(dest.join("bin/tool")).unwrap(), "file");
+    }
+
+    /// Writes a zip archive with the given files (`(name, contents, unix permissions)`)
+    fn write_zip(path: &Path, files: &[(&str, &str, u32)]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, contents, mode) in files {
+            let options = zip::write::FileOptions::default().unix_permissions(*mode);
+            if name.ends_with('/') {
+                zip.add_directory(*name, options).unwrap();
+            } else {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(contents.as_bytes()).unwrap();
+            }
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn top_level_folders() {
+        assert_eq!(
+            top_level_folder(["proj-1.0/", "proj-1.0/src/main.c"]),
+            Some("proj-1.0")
+        );
+        assert_eq!(top_level_folder(["./", "./proj/a", "proj/b"]), Some("proj"));
+        assert_eq!(top_level_folder(["proj/a", "other/b"]), None);
+        assert_eq!(top_level_folder(["proj/a", "README"]), None);
+        assert_eq!(top_level_folder(["README"]), None);
+        assert_eq!(top_level_folder(Vec::<&str>::new()), None);
+    }
+
+    #[test]
+    fn extract_zip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("v1.2.3.zip");
+        write_zip(
+            &archive,
+            &[
+                ("proj-1.2.3/", "", 0o755),
+                ("proj-1.2.3/bin/run", "#!/bin/sh\n", 0o755),
+                ("proj-1.2.3/README", "readme", 0o644),
+            ],
+        );
+
+        let dest = tmp.path().join("work");
+        unpack_zip(&archive, &dest, &mut tracker(), &Progress::hidden()).unwrap();
+        assert!(!dest.join("proj-1.2.3").exists());
+        assert_eq!(fs::read_to_string(dest.join("README")).unwrap(), "readme");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &str| fs::metadata(dest.join(p)).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode("bin/run"), 0o755);
+            assert_eq!(mode("README"), 0o644);
+        }
+    }
+
+    #[test]
+    fn extract_zip_with_multiple_top_level_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("multi.zip");
+        write_zip(
+            &archive,
+            &[
+                ("src/main.c", "int main() {}", 0o644),
+                ("README", "readme", 0o644),
+            ],
+        );
+
+        let dest = tmp.path().join("work");
+        unpack_zip(&archive, &dest, &mut tracker(), &Progress::hidden()).unwrap();
+        assert!(dest.join("src/main.c").is_file());
+        assert!(dest.join("README").is_file());
+    }
+
+    #[test]
+    fn extract_zip_with_backslashes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("windows.zip");
+        write_zip(
+            &archive,
+            &[
+                ("proj\\src\\main.c", "int main() {}", 0o644),
+                ("proj\\README", "readme", 0o644),
+            ],
+        );
+
+        let dest = tmp.path().join("work");
+        unpack_zip(&archive, &dest, &mut tracker(), &Progress::hidden()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("src/main.c")).unwrap(),
+            "int main() {}"
+        );
+        assert!(dest.join("README").is_file());
+    }
+
+    #[test]
+    fn zip_entries_outside_of_the_target_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("evil.zip");
+        write_zip(&archive, &[("../evil", "evil", 0o644)]);
+
+        let err = unpack_zip(
+            &archive,
+            &tmp.path().join("work"),
+            &mut tracker(),
+            &Progress::hidden(),
+        )
+        .unwrap_err();
+        match err {
+            SourceError::ExtractionError(msg) => assert!(msg.contains("../evil"), "{msg}"),
+            _ => panic!("expected an extraction error, got {err:?}"),
+        }
+        assert!(!tmp.path().join("evil").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zip_symlinks_outside_of_the_target_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        let absolute = outside.to_string_lossy().into_owned();
+
+        // `pkg/` is stripped, so `../outside` points next to the target directory
+        for (i, target) in [absolute.as_str(), "../outside"].into_iter().enumerate() {
+            let archive = tmp.path().join(format!("evil_{i}.zip"));
+            let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+            let options = zip::write::FileOptions::default();
+            zip.add_symlink("pkg/a", target, options).unwrap();
+            zip.start_file("pkg/a/x", options).unwrap();
+            zip.write_all(b"evil").unwrap();
+            zip.finish().unwrap();
+
+            let dest = tmp.path().join(format!("work_{i}"));
+            let err = unpack_zip(&archive, &dest, &mut tracker(), &Progress::hidden()).unwrap_err();
+            match err {
+                SourceError::ExtractionError(msg) => {
+                    assert!(
+                        msg.contains("points outside of the extraction directory"),
+                        "{msg}"
+                    )
+                }
+                _ => panic!("expected an extraction error, got {err:?}"),
+            }
+        }
+        assert!(!outside.join("x").exists());
+
+        // links to other entries of the archive are fine
+        let archive = tmp.path().join("links.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        let options = zip::write::FileOptions::default();
+        zip.add_symlink("pkg/README.link", "README", options)
+            .unwrap();
+        zip.start_file("pkg/README", options).unwrap();
+        zip.write_all(b"readme").unwrap();
+        zip.finish().unwrap();
+        let dest = tmp.path().join("work");
+        unpack_zip(&archive, &dest, &mut tracker(), &Progress::hidden()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("README.link")).unwrap(),
+            "readme"
+        );
+    }
+}
@@ -5,43 +5,195 @@ use std::{
     process::Command,
 };
 
-use fs_extra::dir::remove;
-use itertools::Itertools;
-
 use crate::recipe::parser::{GitSource, GitUrl};
+use crate::tools::{Tool, Tools};
+
+use super::{cache, SourceError};
 
-use super::SourceError;
-
-type RepoPath<'a> = &'a Path;
-
-/// Fetch the given repository using the host `git` executable.
-pub fn fetch_repo(repo_path: RepoPath, refspecs: &[String]) -> Result<(), SourceError> {
-    // might break on some platforms due to auth and ssh
-    // especially ssh with password
-    let refspecs_str = refspecs.iter().join(" ");
-    let cd = std::env::current_dir();
-    _ = std::env::set_current_dir(repo_path);
-    let output = Command::new("git")
-        .args(["fetch", "origin", refspecs_str.as_str()])
-        .output()
-        .map_err(|_err| SourceError::ValidationFailed)?;
-    _ = cd.map(std::env::set_current_dir);
+/// Run `git` with `args` in the `repo` and return its output
+fn run_git(git: &Path, repo: &Path, args: &[&str]) -> Result<String, SourceError> {
+    let output = Command::new(git).current_dir(repo).args(args).output()?;
     if !output.status.success() {
-        tracing::debug!("Repository fetch for refs {:?} failed!", refspecs);
-        return Err(SourceError::GitErrorStr(
-            "failed to git fetch refs from origin",
-        ));
+        return Err(SourceError::GitError(format!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
     }
-    tracing::debug!("Repository fetched successfully!");
-    Ok(())
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The commit of `rev` in the `repo`, if it exists
+fn resolve_commit(git: &Path, repo: &Path, rev: &str) -> Option<String> {
+    run_git(
+        git,
+        repo,
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{rev}^{{commit}}"),
+        ],
+    )
+    .ok()
+}
+
+/// Whether the `repo` is a shallow clone
+fn is_shallow(repo: &Path) -> bool {
+    repo.join(".git").join("shallow").exists()
+}
+
+/// Fetch `rev` (a branch, a tag or a commit) from the origin of the `repo` and return its
+/// commit. With a `depth`, only that many commits of its history are fetched. If the origin
+/// refuses to serve the revision directly (e.g. a commit that is not the tip of a branch, or an
+/// abbreviated hash), the whole repository is fetched instead. A shallow repository is deepened
+/// to the full history if no `depth` is given.
+pub fn fetch_rev(
+    git: &Path,
+    repo: &Path,
+    rev: &str,
+    depth: Option<u32>,
+) -> Result<String, SourceError> {
+    let depth_arg = depth.map(|depth| format!("--depth={depth}"));
+    let mut args = vec!["fetch", "--quiet", "--force"];
+    match &depth_arg {
+        Some(depth) => args.push(depth),
+        None if is_shallow(repo) => args.push("--unshallow"),
+        None => {}
+    }
+    args.extend(["origin", rev]);
+    match run_git(git, repo, &args) {
+        Ok(_) => {
+            if let Some(commit) = resolve_commit(git, repo, "FETCH_HEAD") {
+                return Ok(commit);
+            }
+        }
+        Err(e) => tracing::warn!(
+            "Could not fetch `{}` directly, fetching the whole repository: {}",
+            rev,
+            e
+        ),
+    }
+
+    let mut args = vec!["fetch", "--quiet", "--force", "--tags"];
+    if is_shallow(repo) {
+        args.push("--unshallow");
+    }
+    args.extend(["origin", "+refs/heads/*:refs/remotes/origin/*"]);
+    run_git(git, repo, &args)?;
+    resolve_commit(git, repo, rev)
+        .or_else(|| resolve_commit(git, repo, &format!("origin/{rev}")))
+        .ok_or_else(|| {
+            SourceError::GitError(format!(
+                "`{rev}` is not a branch, tag or commit of the repository"
+            ))
+        })
+}
+
+/// Check out the revision of the `source` in the `repo`, and update (or remove) its submodules.
+/// Returns the commit that was checked out.
+fn checkout(git: &Path, repo: &Path, source: &GitSource) -> Result<String, SourceError> {
+    let rev = match source.rev().trim() {
+        "" => "HEAD",
+        rev => rev,
+    };
+    let commit = fetch_rev(git, repo, rev, source.shallow_depth())?;
+    run_git(git, repo, &["checkout", "--quiet", "--force", &commit])?;
+    run_git(git, repo, &["clean", "-ffdx", "--quiet"])?;
+
+    // the cached repository is copied as a whole, so the submodules of an earlier build with
+    // `submodules: true` have to be removed
+    if source.submodules() {
+        run_git(
+            git,
+            repo,
+            &["submodule", "update", "--init", "--recursive", "--force"],
+        )?;
+    } else if repo.join(".gitmodules").exists() {
+        run_git(
+            git,
+            repo,
+            &["submodule", "deinit", "--all", "--force", "--quiet"],
+        )?;
+    }
+
+    tracing::info!("Checked out reference: '{}' ({})", rev, commit);
+    Ok(commit)
+}
+
+/// The repository that a git source is fetched from
+struct Remote {
+    /// The url of the `origin` remote
+    url: String,
+    /// The name of the repository (the last part of its location)
+    name: String,
+}
+
+impl Remote {
+    /// The remote of `url`. Paths are resolved against the `recipe_dir`.
+    fn new(url: &GitUrl, recipe_dir: &Path) -> Result<Self, SourceError> {
+        let path = match url {
+            GitUrl::Url(url) if url.scheme() == "file" => url.to_file_path().map_err(|_| {
+                SourceError::GitError(format!("`{url}` is not a valid path of a local repository"))
+            })?,
+            GitUrl::Url(url) => {
+                let name = url
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .filter(|name| !name.is_empty())
+                    .ok_or(SourceError::GitErrorStr("failed to get filename from url"))?;
+                return Ok(Self {
+                    url: url.to_string(),
+                    name: name.to_string(),
+                });
+            }
+            GitUrl::Path(path) => recipe_dir.join(path),
+        };
+
+        // git doesn't support UNC paths, hence we can't use std::fs::canonicalize
+        let path = dunce::canonicalize(&path).map_err(|e| {
+            SourceError::GitError(format!(
+                "the local repository {} does not exist: {e}",
+                path.display()
+            ))
+        })?;
+        // a path to the `.git` folder of a repository is named after the repository
+        let name = path
+            .components()
+            .rev()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .find(|name| name != ".git")
+            .ok_or(SourceError::GitErrorStr("failed to get filename from path"))?;
+        Ok(Self {
+            url: format!("file://{}", path.to_string_lossy()),
+            name,
+        })
+    }
+
+    /// The name of the repository in the cache. It contains a hash of the location, so that
+    /// repositories with the same name do not share a cache entry.
+    fn cache_name(&self) -> String {
+        let hash = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(self.url.as_bytes()));
+        format!("{}_{}", self.name, &hash[..8])
+    }
+}
+
+/// The name of the repository of `source` in the cache, e.g. to lock it with
+/// [`cache::lock_entry`]
+pub(crate) fn cache_name(source: &GitSource, recipe_dir: &Path) -> Result<String, SourceError> {
+    Ok(Remote::new(source.url(), recipe_dir)?.cache_name())
 }
 
 /// Fetch the git repository specified by the given source and place it in the cache directory.
+/// The repository can be a url or a local repository (a path relative to the `recipe_dir`, or a
+/// `file://` url). It is kept in the cache and only fetched again for the next build. Returns the
+/// path of the repository in the cache and the commit that was checked out.
 pub fn git_src(
     source: &GitSource,
     cache_dir: &Path,
     recipe_dir: &Path,
-) -> Result<PathBuf, SourceError> {
+    tools: &Tools,
+) -> Result<(PathBuf, String), SourceError> {
     tracing::info!(
         "git source: ({:?}) cache_dir: ({}) recipe_dir: ({})",
         source,
@@ -49,193 +201,404 @@ pub fn git_src(
         recipe_dir.display()
     );
 
-    // test if git is available locally as we fetch the git from PATH,
-    // user can always override git path
-    if !Command::new("git")
-        .arg("--version")
-        .output()?
-        .status
-        .success()
-    {
-        return Err(SourceError::GitErrorStr(
-            "`git` command not found in `PATH`",
-        ));
+    let git = tools.find(
+        Tool::Git,
+        format_args!("fetch the git source `{}`", source.url()),
+    )?;
+
+    let remote = Remote::new(source.url(), recipe_dir)?;
+    let filename = remote.cache_name();
+
+    fs_err::create_dir_all(cache_dir)?;
+    let cache_path = cache_dir.join(&filename);
+
+    let reuse_cache = cache_path.join(".git").is_dir();
+    let commit = if reuse_cache {
+        run_git(
+            &git,
+            &cache_path,
+            &["remote", "set-url", "origin", &remote.url],
+        )?;
+        let commit = checkout(&git, &cache_path, source)?;
+        cache::mark_used(&cache_path)?;
+        commit
+    } else {
+        // set up the repository in a temporary directory so that an interrupted fetch is never
+        // mistaken for a complete one
+        let tmp_clone = cache::tmp_dir(cache_dir, &filename)?;
+        run_git(&git, tmp_clone.path(), &["init", "--quiet"])?;
+        run_git(
+            &git,
+            tmp_clone.path(),
+            &["remote", "add", "origin", &remote.url],
+        )?;
+        let commit = checkout(&git, tmp_clone.path(), source)?;
+        cache::persist_dir(tmp_clone, &cache_path)?;
+        commit
+    };
+
+    if source.lfs() || uses_lfs(&git, &cache_path)? {
+        git_lfs_pull(&git, &cache_path, tools)?;
     }
 
-    if (source.rev().is_empty() || source.rev().eq("HEAD"))
-        // depth == -1, fetches the entire git history
-        && source.depth().map(|s| s != -1).unwrap_or_default()
-    {
-        return Err(SourceError::GitErrorStr(
-            "use of `git_rev` with `git_depth` is invalid",
-        ));
+    Ok((cache_path, commit))
+}
+
+/// Whether one of the `.gitattributes` files of the checked out revision of the `repo` stores
+/// files in Git LFS
+fn uses_lfs(git: &Path, repo: &Path) -> Result<bool, SourceError> {
+    let attributes = run_git(
+        git,
+        repo,
+        &["ls-files", "--", ".gitattributes", "*/.gitattributes"],
+    )?;
+    for path in attributes.lines() {
+        let content = fs_err::read_to_string(repo.join(path))?;
+        if content
+            .lines()
+            .any(|line| line.split_whitespace().any(|attr| attr == "filter=lfs"))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Replace the pointer files of the checked out revision of the `repo` with their Git LFS
+/// objects. The objects are stored in `.git/lfs` of the cached repository, so they are only
+/// downloaded once.
+fn git_lfs_pull(git: &Path, repo: &Path, tools: &Tools) -> Result<(), SourceError> {
+    let git_lfs = tools
+        .find(Tool::GitLfs, "fetch the Git LFS files of the git source")
+        .map_err(|_| {
+            SourceError::GitErrorStr(
+                "the repository stores files in Git LFS, but `git-lfs` was not found (add `git-lfs` to the build requirements or install it on your system)",
+            )
+        })?;
+
+    // `git-lfs` runs `git` itself, which may only be available in the build prefix
+    let path = std::env::join_paths(
+        git.parent().into_iter().map(Path::to_path_buf).chain(
+            std::env::var_os("PATH")
+                .iter()
+                .flat_map(std::env::split_paths),
+        ),
+    )
+    .map_err(|e| SourceError::GitError(e.to_string()))?;
+
+    let output = Command::new(git_lfs)
+        .current_dir(repo)
+        .env("PATH", path)
+        .arg("pull")
+        .output()?;
+    if !output.status.success() {
+        return Err(SourceError::GitError(format!(
+            "`git lfs pull` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
     }
 
-    let filename = match &source.url() {
-        GitUrl::Url(url) => (|| Some(url.path_segments()?.last()?.to_string()))()
-            .ok_or_else(|| SourceError::GitErrorStr("failed to get filename from url"))?,
-        GitUrl::Path(path) => recipe_dir
-            .join(path)
-            .canonicalize()?
-            .file_name()
-            .expect("unreachable, canonicalized paths shouldn't end with ..")
-            .to_string_lossy()
-            .to_string(),
+    tracing::info!("Fetched the Git LFS files of {}", repo.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use crate::{
+        recipe::parser::{GitSource, GitUrl},
+        source::{git_source::git_src, SourceError},
+        tools::{Tool, Tools},
     };
 
-    let cache_name = PathBuf::from(filename);
-    let cache_path = cache_dir.join(cache_name);
-
-    // Initialize or clone the repository depending on the source's git_url.
-    match &source.url() {
-        GitUrl::Url(_) => {
-            // If the cache_path exists, initialize the repo and fetch the specified revision.
-            if cache_path.exists() {
-                fetch_repo(&cache_path, &[source.rev().to_string()])?;
-            } else {
-                let mut command = Command::new("git");
-                command.args(["clone", "--recursive", source.url().to_string().as_str()]);
-                command.arg(cache_path.as_os_str());
-                if let Some(depth) = source.depth() {
-                    command.args(["--depth", depth.to_string().as_str()]);
-                }
-                let output = command
-                    .output()
-                    .map_err(|_e| SourceError::GitErrorStr("Failed to execute clone command"))?;
-                if !output.status.success() {
-                    return Err(SourceError::GitErrorStr("Git clone failed for source"));
-                }
-                if source.rev() == "HEAD" || source.rev().trim().is_empty() {
-                    // If the source is a path and the revision is HEAD, return the path to avoid git actions.
-                    return Ok(PathBuf::from(&cache_path));
-                }
-            }
-        }
-        GitUrl::Path(path) => {
-            if cache_path.exists() {
-                // Remove old cache so it can be overwritten.
-                if let Err(remove_error) = remove(&cache_path) {
-                    tracing::error!("Failed to remove old cache directory: {}", remove_error);
-                    return Err(SourceError::FileSystemError(remove_error));
-                }
-            }
-            // git doesn't support UNC paths, hence we can't use std::fs::canonicalize
-            let path = dunce::canonicalize(path).map_err(|e| {
-                tracing::error!("Path not found on system: {}", e);
-                SourceError::GitError(format!("{}: Path not found on system", e))
-            })?;
-
-            let path = path.to_string_lossy();
-            let mut command = Command::new("git");
-            command
-                .arg("clone")
-                .arg("--recursive")
-                .arg(format!("file://{}/.git", path).as_str())
-                .arg(cache_path.as_os_str());
-            if let Some(depth) = source.depth() {
-                command.args(["--depth", depth.to_string().as_str()]);
-            }
-            let output = command
-                .output()
-                .map_err(|_| SourceError::ValidationFailed)?;
-            if !output.status.success() {
-                tracing::error!("Command failed: {:?}", command);
-                return Err(SourceError::GitErrorStr(
-                    "failed to execute clone from file",
-                ));
-            }
+    /// Run `git` in `dir` and return its output
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .current_dir(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args([
+                "-c",
+                "commit.gpgsign=false",
+                "-c",
+                "protocol.file.allow=always",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?} failed: {output:?}");
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
 
-            if source.rev() == "HEAD" || source.rev().trim().is_empty() {
-                // If the source is a path and the revision is HEAD, return the path to avoid git actions.
-                return Ok(PathBuf::from(&cache_path));
+    /// A repository with the commits `1`, `2` (tagged `v2`) and `3` of the file `version`
+    fn upstream(dir: &Path) -> url::Url {
+        std::fs::create_dir_all(dir).unwrap();
+        git(dir, &["init", "--quiet"]);
+        for version in ["1", "2", "3"] {
+            std::fs::write(dir.join("version"), version).unwrap();
+            git(dir, &["add", "version"]);
+            git(dir, &["commit", "--quiet", "-m", version]);
+            if version == "2" {
+                git(dir, &["tag", "-a", "v2", "-m", "v2"]);
             }
         }
+        url::Url::from_file_path(dir).unwrap()
     }
 
-    // Resolve the reference and set the head to the specified revision.
-    // let ref_git = format!("refs/remotes/origin/{}", source.git_rev.to_string());
-    // let reference = match repo.find_reference(&ref_git) {
-    let output = Command::new("git")
-        .current_dir(&cache_path)
-        .args(["rev-parse", source.rev()])
-        .output()
-        .map_err(|_| SourceError::GitErrorStr("git rev-parse failed"))?;
-    if !output.status.success() {
-        tracing::error!("Command failed: \"git\" \"rev-parse\" \"{}\"", source.rev());
-        return Err(SourceError::GitErrorStr("failed to get valid hash for rev"));
+    fn source(url: &url::Url, rev: &str, depth: Option<i32>, submodules: bool) -> GitSource {
+        GitSource::create(
+            GitUrl::Url(url.clone()),
+            rev.to_owned(),
+            depth,
+            vec![],
+            None,
+            false,
+            submodules,
+        )
     }
-    let ref_git = String::from_utf8(output.stdout)
-        .map_err(|_| SourceError::GitErrorStr("failed to parse git rev as utf-8"))?;
-    tracing::info!("cache_path = {}", cache_path.display());
 
-    let mut command = Command::new("git");
-    command
-        .current_dir(&cache_path)
-        .arg("checkout")
-        .arg(ref_git.as_str().trim());
+    #[test]
+    fn shallow_and_full_clones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let url = upstream(&tmp.path().join("upstream"));
+        let cache_dir = tmp.path().join("cache");
+        let fetch = |rev: &str, depth: Option<i32>| {
+            git_src(
+                &source(&url, rev, depth, true),
+                &cache_dir,
+                tmp.path(),
+                &Tools::default(),
+            )
+            .unwrap()
+        };
 
-    let output = command
-        .output()
-        .map_err(|_| SourceError::GitErrorStr("failed to execute git checkout"))?;
+        let (repo, _) = fetch("v2", Some(1));
+        assert_eq!(std::fs::read_to_string(repo.join("version")).unwrap(), "2");
+        assert!(repo.join(".git/shallow").exists());
+        assert_eq!(git(&repo, &["rev-list", "--count", "HEAD"]), "1");
 
-    if !output.status.success() {
-        tracing::error!("Command failed: {:?}", command);
-        return Err(SourceError::GitErrorStr("failed to checkout for ref"));
-    }
+        // switching to the full history deepens the cached repository
+        let (repo, _) = fetch("HEAD", None);
+        assert_eq!(std::fs::read_to_string(repo.join("version")).unwrap(), "3");
+        assert!(!repo.join(".git/shallow").exists());
+        assert_eq!(git(&repo, &["rev-list", "--count", "HEAD"]), "3");
 
-    let output = Command::new("git")
-        .current_dir(&cache_path)
-        .args(["reset", "--hard"])
-        .output()
-        .map_err(|_| SourceError::GitErrorStr("failed to execute git reset"))?;
+        // an abbreviated hash cannot be fetched directly, so the whole repository is fetched
+        let first = git(
+            &tmp.path().join("upstream"),
+            &["rev-parse", "--short", "HEAD~2"],
+        );
+        let (repo, commit) = fetch(&first, Some(1));
+        assert_eq!(std::fs::read_to_string(repo.join("version")).unwrap(), "1");
+        // the full hash of the commit is returned
+        assert_eq!(
+            commit,
+            git(&tmp.path().join("upstream"), &["rev-parse", "HEAD~2"])
+        );
+    }
 
-    if !output.status.success() {
-        tracing::error!("Command failed: \"git\" \"reset\" \"--hard\"");
-        return Err(SourceError::GitErrorStr("failed to git reset"));
+    /// Tools with a `git` in `bin` that allows submodules with `file://` urls (which git refuses
+    /// by default). The option is passed on the command line, because the environment of the
+    /// test process is shared by the tests that run in parallel.
+    fn tools_allowing_file_submodules(bin: &Path) -> Tools {
+        let git = which::which("git").unwrap();
+        std::fs::create_dir_all(bin).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let wrapper = bin.join("git");
+            std::fs::write(
+                &wrapper,
+                format!(
+                    "#!/bin/sh\nexec \"{}\" -c protocol.file.allow=always \"$@\"\n",
+                    git.display()
+                ),
+            )
+            .unwrap();
+            std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        #[cfg(windows)]
+        std::fs::write(
+            bin.join("git.bat"),
+            format!(
+                "@\"{}\" -c protocol.file.allow=always %*\r\n",
+                git.display()
+            ),
+        )
+        .unwrap();
+        Tools::with_path(None, Some(bin.as_os_str().to_owned()))
     }
 
-    // only do lfs pull if a requirement!
-    if source.lfs() {
-        git_lfs_pull()?;
+    #[test]
+    fn submodules() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tools = tools_allowing_file_submodules(&tmp.path().join("bin"));
+        let library = upstream(&tmp.path().join("library"));
+        let app = tmp.path().join("app");
+        upstream(&app);
+        git(
+            &app,
+            &["submodule", "--quiet", "add", library.as_str(), "library"],
+        );
+        git(&app, &["commit", "--quiet", "-m", "add the library"]);
+        let url = url::Url::from_file_path(&app).unwrap();
+        let cache_dir = tmp.path().join("cache");
+
+        let repo = git_src(
+            &source(&url, "HEAD", None, true),
+            &cache_dir,
+            tmp.path(),
+            &tools,
+        )
+        .unwrap()
+        .0;
+        assert!(repo.join("library/version").is_file());
+
+        // the submodule checked out by the first fetch is removed from the cached repository
+        let repo = git_src(
+            &source(&url, "HEAD", None, false),
+            &cache_dir,
+            tmp.path(),
+            &tools,
+        )
+        .unwrap()
+        .0;
+        assert!(repo.join("library").is_dir());
+        assert!(!repo.join("library/version").exists());
     }
 
-    tracing::info!("Checked out reference: '{}'", &source.rev());
+    #[test]
+    fn local_repositories() {
+        let tmp = tempfile::tempdir().unwrap();
+        upstream(&tmp.path().join("a/project"));
+        let other = upstream(&tmp.path().join("b/project"));
+        std::fs::write(tmp.path().join("b/project/version"), "4").unwrap();
+        git(
+            &tmp.path().join("b/project"),
+            &["commit", "--quiet", "-am", "4"],
+        );
 
-    Ok(cache_path)
-}
+        let recipe_dir = tmp.path().join("recipe");
+        std::fs::create_dir_all(&recipe_dir).unwrap();
+        let cache_dir = tmp.path().join("cache");
+        let fetch = |url: GitUrl, rev: &str| {
+            let source = GitSource::create(url, rev.to_owned(), None, vec![], None, false, true);
+            git_src(&source, &cache_dir, &recipe_dir, &Tools::default())
+                .unwrap()
+                .0
+        };
 
-fn git_lfs_pull() -> Result<(), SourceError> {
-    // verify lfs install
-    let mut command = Command::new("git");
-    command.args(["lfs", "install"]);
-    let output = command
-        .output()
-        .map_err(|_| SourceError::GitErrorStr("failed to execute command"))?;
-    if !output.status.success() {
-        return Err(SourceError::GitErrorStr(
-            "git-lfs not installed, but required",
+        // a path relative to the recipe, and a `file://` url of a repository with the same name
+        let relative = fetch(GitUrl::Path("../a/project".into()), "v2");
+        assert_eq!(
+            std::fs::read_to_string(relative.join("version")).unwrap(),
+            "2"
+        );
+        let file_url = fetch(GitUrl::Url(other), "HEAD");
+        assert_eq!(
+            std::fs::read_to_string(file_url.join("version")).unwrap(),
+            "4"
+        );
+        assert_ne!(relative, file_url);
+
+        // the same repository has the same cache entry, however it is spelled
+        let absolute = fetch(GitUrl::Path(tmp.path().join("a/project")), "HEAD");
+        assert_eq!(absolute, relative);
+        assert_eq!(
+            std::fs::read_to_string(absolute.join("version")).unwrap(),
+            "3"
+        );
+
+        let missing = GitSource::create(
+            GitUrl::Path("../c/project".into()),
+            String::new(),
+            None,
+            vec![],
+            None,
+            false,
+            true,
+        );
+        assert!(matches!(
+            git_src(&missing, &cache_dir, &recipe_dir, &Tools::default()),
+            Err(SourceError::GitError(_))
         ));
     }
 
-    // git lfs pull
-    let mut command = Command::new("git");
-    command.args(["lfs", "pull"]);
-    let output = command
-        .output()
-        .map_err(|_| SourceError::GitErrorStr("failed to execute command"))?;
-    if !output.status.success() {
-        return Err(SourceError::GitErrorStr("`git lfs pull` failed!"));
+    #[test]
+    fn lfs() {
+        if Tools::default().find(Tool::GitLfs, "test").is_err() {
+            // git-lfs is not installed
+            return;
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("upstream");
+        let url = upstream(&dir);
+        git(&dir, &["lfs", "install", "--local"]);
+        git(&dir, &["lfs", "track", "*.bin"]);
+        std::fs::write(dir.join("data.bin"), "large binary data").unwrap();
+        git(&dir, &["add", ".gitattributes", "data.bin"]);
+        git(&dir, &["commit", "--quiet", "-m", "add data"]);
+
+        // the `.gitattributes` enable Git LFS without `lfs: true`
+        let cache_dir = tmp.path().join("cache");
+        let repo = git_src(
+            &source(&url, "HEAD", None, true),
+            &cache_dir,
+            tmp.path(),
+            &Tools::default(),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(
+            std::fs::read_to_string(repo.join("data.bin")).unwrap(),
+            "large binary data"
+        );
+        assert!(repo.join(".git/lfs/objects").is_dir());
     }
 
-    Ok(())
-}
+    #[cfg(unix)]
+    #[test]
+    fn lfs_without_git_lfs() {
+        let host_git = Tools::default().find(Tool::Git, "test").unwrap();
+        if Tools::default().find(Tool::GitLfs, "test").is_ok() {
+            // the pointer file is only checked out where git-lfs is not installed
+            return;
+        }
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        recipe::parser::{GitSource, GitUrl},
-        source::git_source::git_src,
-    };
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("upstream");
+        let url = upstream(&dir);
+        std::fs::write(
+            dir.join(".gitattributes"),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("data.bin"),
+            "version https://git-lfs.github.com/spec/v1\noid sha256:0000000000000000000000000000000000000000000000000000000000000000\nsize 17\n",
+        )
+        .unwrap();
+        git(&dir, &["add", ".gitattributes", "data.bin"]);
+        git(&dir, &["commit", "--quiet", "-m", "add data"]);
+
+        // only `git` is available
+        let bin = tmp.path().join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::os::unix::fs::symlink(host_git, bin.join("git")).unwrap();
+        let tools = Tools::with_path(None, Some(bin.into_os_string()));
+
+        let err = git_src(
+            &source(&url, "HEAD", None, true),
+            &tmp.path().join("cache"),
+            tmp.path(),
+            &tools,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, SourceError::GitErrorStr(msg) if msg.contains("`git-lfs` was not found"))
+        );
+    }
 
     #[tracing_test::traced_test]
     #[test]
@@ -255,6 +618,7 @@ mod tests {
                     vec![],
                     None,
                     false,
+                    true,
                 ),
                 "rattler-build",
             ),
@@ -270,6 +634,7 @@ mod tests {
                     vec![],
                     None,
                     false,
+                    true,
                 ),
                 "rattler-build",
             ),
@@ -285,6 +650,7 @@ mod tests {
                     vec![],
                     None,
                     false,
+                    true,
                 ),
                 "rattler-build",
             ),
@@ -296,23 +662,27 @@ mod tests {
                     vec![],
                     None,
                     false,
+                    true,
                 ),
                 "rattler-build",
             ),
         ];
         for (source, repo_name) in cases {
-            let path = git_src(
+            let (path, _) = git_src(
                 &source,
                 cache_dir.as_ref(),
                 // TODO: this test assumes current dir is the root folder of the project which may
                 // not be necessary for local runs.
                 std::env::current_dir().unwrap().as_ref(),
+                &Tools::default(),
             )
             .unwrap();
-            assert_eq!(
-                path.to_string_lossy(),
-                cache_dir.join(repo_name).to_string_lossy()
-            );
+            assert_eq!(path.parent().unwrap(), cache_dir);
+            assert!(path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(&format!("{repo_name}_")));
         }
     }
 }
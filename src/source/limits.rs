@@ -0,0 +1,349 @@
+//! Limits on the number of files and the total size of a single source. They protect the build
+//! machine against archive bombs and accidental copies of huge directories.
+//!
+//! Archives are checked while they are extracted, so that the extraction stops as soon as a
+//! limit is exceeded. The bytes that come out of the decompressor are counted instead of
+//! trusting the sizes recorded in zip headers, and the inner archives of `.conda` packages are
+//! counted as well.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use super::SourceError;
+use crate::recipe::parser::Limits;
+
+/// The default maximum total size of a single source (50 GB)
+pub const DEFAULT_MAX_SIZE: u64 = 50 * 1000 * 1000 * 1000;
+
+/// The default maximum number of files of a single source
+pub const DEFAULT_MAX_FILES: u64 = 2_000_000;
+
+/// The limits that are enforced when extracting or copying a source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SourceLimits {
+    /// The maximum total (uncompressed) size of all files in bytes
+    pub max_size: u64,
+    /// The maximum number of files
+    pub max_files: u64,
+}
+
+impl Default for SourceLimits {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+}
+
+impl SourceLimits {
+    /// Apply the limits that are set in the recipe for a single source
+    pub fn with_overrides(self, limits: Option<&Limits>) -> Self {
+        let Some(limits) = limits else {
+            return self;
+        };
+        Self {
+            max_size: limits.max_size().unwrap_or(self.max_size),
+            max_files: limits.max_files().unwrap_or(self.max_files),
+        }
+    }
+}
+
+/// The limit that was hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    /// The maximum total size in bytes (`limits.max_size`)
+    MaxSize(u64),
+    /// The maximum number of files (`limits.max_files`)
+    MaxFiles(u64),
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKind::MaxSize(bytes) => write!(f, "maximum size of {bytes} bytes (`max_size`)"),
+            LimitKind::MaxFiles(files) => {
+                write!(f, "maximum number of {files} files (`max_files`)")
+            }
+        }
+    }
+}
+
+/// Counts the files and bytes of a source and fails as soon as one of the limits is exceeded
+pub(crate) struct LimitTracker {
+    name: String,
+    limits: SourceLimits,
+    size: u64,
+    files: u64,
+}
+
+impl LimitTracker {
+    pub fn new(name: impl Into<String>, limits: SourceLimits) -> Self {
+        Self {
+            name: name.into(),
+            limits,
+            size: 0,
+            files: 0,
+        }
+    }
+
+    fn exceeded(&self, limit: LimitKind) -> SourceError {
+        SourceError::LimitExceeded {
+            name: self.name.clone(),
+            limit,
+        }
+    }
+
+    /// Count a new file
+    pub fn add_file(&mut self) -> Result<(), SourceError> {
+        self.files += 1;
+        if self.files > self.limits.max_files {
+            return Err(self.exceeded(LimitKind::MaxFiles(self.limits.max_files)));
+        }
+        Ok(())
+    }
+
+    /// Count `bytes` more bytes of file contents
+    pub fn add_bytes(&mut self, bytes: u64) -> Result<(), SourceError> {
+        self.size = self.size.saturating_add(bytes);
+        if self.size > self.limits.max_size {
+            return Err(self.exceeded(LimitKind::MaxSize(self.limits.max_size)));
+        }
+        Ok(())
+    }
+
+    /// Copy `reader` to `writer` and count the bytes, stopping as soon as the size limit is hit.
+    /// Errors while reading or writing are mapped with `io_error`.
+    pub fn copy(
+        &mut self,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        io_error: impl Fn(io::Error) -> SourceError,
+    ) -> Result<(), SourceError> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buf).map_err(&io_error)?;
+            if read == 0 {
+                return Ok(());
+            }
+            self.add_bytes(read as u64)?;
+            writer.write_all(&buf[..read]).map_err(&io_error)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        path::Path,
+    };
+
+    use fs_err as fs;
+
+    use super::{LimitKind, LimitTracker, SourceLimits};
+    use crate::{
+        progress::Progress,
+        source::{
+            extract::{unpack_conda_package, unpack_tar, unpack_zip, Compression},
+            SourceError,
+        },
+    };
+
+    const ZEROS: u64 = 10 * 1024 * 1024;
+
+    /// Extracts `archive` into a new folder next to it, with the `limits` for a source `name`
+    fn extract(archive: &Path, name: &str, limits: SourceLimits) -> Result<(), SourceError> {
+        let dest = tempfile::tempdir_in(archive.parent().unwrap()).unwrap();
+        extract_to(archive, dest.path(), name, limits)
+    }
+
+    /// Extracts `archive` into `dest`, with the `limits` for a source `name`
+    fn extract_to(
+        archive: &Path,
+        dest: &Path,
+        name: &str,
+        limits: SourceLimits,
+    ) -> Result<(), SourceError> {
+        let mut tracker = LimitTracker::new(name, limits);
+        let progress = Progress::hidden();
+        let file_name = archive.file_name().unwrap().to_string_lossy();
+        if file_name.ends_with(".zip") {
+            unpack_zip(archive, dest, &mut tracker, &progress)
+        } else if file_name.ends_with(".conda") {
+            unpack_conda_package(archive, dest, &mut tracker, &progress)
+        } else if file_name.ends_with(".tar.gz") {
+            unpack_tar(archive, Compression::Gzip, dest, &mut tracker, &progress)
+        } else {
+            unpack_tar(archive, Compression::None, dest, &mut tracker, &progress)
+        }
+    }
+
+    /// Writes a tar archive with `count` files of one byte to `writer`
+    fn write_files_tar<W: Write>(writer: W, count: usize) -> W {
+        let mut builder = tar::Builder::new(writer);
+        for i in 0..count {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("files/file_{i}"), &b"x"[..])
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Writes a tar archive with a single file of `ZEROS` zeros to `writer`
+    fn write_zeros_tar<W: Write>(writer: W) -> W {
+        let mut builder = tar::Builder::new(writer);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(ZEROS);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "zeros", std::io::repeat(0).take(ZEROS))
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn high_ratio_archives() {
+        let tmp = tempfile::tempdir().unwrap();
+        let limits = SourceLimits {
+            max_size: 1024 * 1024,
+            ..SourceLimits::default()
+        };
+
+        // 10 MiB of zeros compress to a few KiB
+        let tar_gz = tmp.path().join("bomb.tar.gz");
+        write_zeros_tar(flate2::write::GzEncoder::new(
+            fs::File::create(&tar_gz).unwrap(),
+            flate2::Compression::best(),
+        ))
+        .finish()
+        .unwrap();
+        assert!(fs::metadata(&tar_gz).unwrap().len() < 100 * 1024);
+
+        let zip_path = tmp.path().join("bomb.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        zip.start_file("zeros", zip::write::FileOptions::default())
+            .unwrap();
+        std::io::copy(&mut std::io::repeat(0).take(ZEROS), &mut zip).unwrap();
+        zip.finish().unwrap().flush().unwrap();
+
+        // the inner archive of a `.conda` package is stored as it is, but it is compressed itself
+        let conda = tmp.path().join("bomb-1.0-0.conda");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&conda).unwrap());
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("metadata.json", stored).unwrap();
+        zip.write_all(br#"{"conda_pkg_format_version": 2}"#)
+            .unwrap();
+        zip.start_file("pkg-bomb-1.0-0.tar.zst", stored).unwrap();
+        let encoder = write_zeros_tar(zstd::stream::write::Encoder::new(&mut zip, 19).unwrap());
+        encoder.finish().unwrap();
+        zip.finish().unwrap().flush().unwrap();
+        assert!(fs::metadata(&conda).unwrap().len() < 100 * 1024);
+
+        for archive in [&tar_gz, &zip_path, &conda] {
+            let err = extract(archive, "https://example.com/bomb", limits).unwrap_err();
+            assert!(matches!(
+                err,
+                SourceError::LimitExceeded {
+                    limit: LimitKind::MaxSize(1048576),
+                    ..
+                }
+            ));
+            assert_eq!(
+                err.to_string(),
+                "Source `https://example.com/bomb` exceeds the maximum size of 1048576 bytes (`max_size`)"
+            );
+
+            // the default limits are generous enough
+            extract(archive, "bomb", SourceLimits::default()).unwrap();
+        }
+    }
+
+    #[test]
+    fn too_many_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("many.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        for i in 0..10 {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("file_{i}"), &b"x"[..])
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let limits = SourceLimits {
+            max_files: 5,
+            ..SourceLimits::default()
+        };
+        let err = extract(&archive, "many", limits).unwrap_err();
+        assert!(matches!(
+            err,
+            SourceError::LimitExceeded {
+                limit: LimitKind::MaxFiles(5),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn failed_extractions_leave_nothing_behind() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let zip_path = tmp.path().join("many.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        for i in 0..10 {
+            zip.start_file(
+                format!("files/file_{i}"),
+                zip::write::FileOptions::default(),
+            )
+            .unwrap();
+            zip.write_all(b"x").unwrap();
+        }
+        zip.finish().unwrap().flush().unwrap();
+
+        let conda = tmp.path().join("many-1.0-0.conda");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&conda).unwrap());
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("metadata.json", stored).unwrap();
+        zip.write_all(br#"{"conda_pkg_format_version": 2}"#)
+            .unwrap();
+        zip.start_file("pkg-many-1.0-0.tar.zst", stored).unwrap();
+        let encoder = write_files_tar(zstd::stream::write::Encoder::new(&mut zip, 3).unwrap(), 10);
+        encoder.finish().unwrap();
+        zip.finish().unwrap().flush().unwrap();
+
+        let limits = SourceLimits {
+            max_files: 5,
+            ..SourceLimits::default()
+        };
+        for archive in [&zip_path, &conda] {
+            let dest = tmp.path().join("dest");
+            let err = extract_to(archive, &dest, "many", limits).unwrap_err();
+            assert!(matches!(
+                err,
+                SourceError::LimitExceeded {
+                    limit: LimitKind::MaxFiles(5),
+                    ..
+                }
+            ));
+            // the files that were extracted before the limit was hit are removed again
+            assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+
+            extract_to(archive, &dest, "many", SourceLimits::default()).unwrap();
+            assert_eq!(fs::read_dir(dest.join("files")).unwrap().count(), 10);
+            fs::remove_dir_all(&dest).unwrap();
+        }
+    }
+}
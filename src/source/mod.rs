@@ -1,20 +1,30 @@
 //! Module for fetching sources and applying patches
 
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf, StripPrefixError},
-    process::Command,
 };
 
+use crate::progress::{Progress, ProgressUnit};
 use crate::recipe::parser::Source;
+use crate::tool_configuration;
+use crate::tools::{ToolNotFound, Tools};
+use extract::ArchiveFormat;
 use fs_err as fs;
+use futures::{StreamExt, TryStreamExt};
+use limits::{LimitKind, LimitTracker, SourceLimits};
 
+pub(crate) mod auth;
+pub mod cache;
 pub mod copy_dir;
+mod extract;
 pub mod git_source;
+pub mod limits;
 pub mod patch;
 pub mod url_source;
 
 #[allow(missing_docs)]
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
 pub enum SourceError {
     #[error("IO Error: {0}")]
     Io(#[from] std::io::Error),
@@ -31,17 +41,22 @@ pub enum SourceError {
     #[error("StripPrefixError Error: {0}")]
     StripPrefixError(#[from] StripPrefixError),
 
-    #[error("Download could not be validated with checksum!")]
-    ValidationFailed,
+    #[error("Checksum validation failed for {} ({size} bytes): expected {algorithm} {expected}, got {actual}", path.display())]
+    #[diagnostic(help("update the checksum in the recipe if the new file is expected"))]
+    ValidationFailed {
+        path: PathBuf,
+        algorithm: &'static str,
+        expected: String,
+        actual: String,
+        size: u64,
+    },
 
     #[error("File not found: {0}")]
     FileNotFound(PathBuf),
 
-    #[error("Could not find `patch` executable")]
-    PatchNotFound,
-
-    #[error("Could not find `tar` executable")]
-    TarNotFound,
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    ToolNotFound(#[from] ToolNotFound),
 
     #[error("Failed to apply patch: {0}")]
     PatchFailed(String),
@@ -69,39 +84,123 @@ pub enum SourceError {
 
     #[error("No checksum found for url: {0}")]
     NoChecksum(url::Url),
+
+    #[error("Source `{name}` exceeds the {limit}")]
+    LimitExceeded { name: String, limit: LimitKind },
+
+    #[error("All mirrors of the source failed:\n{0}")]
+    AllMirrorsFailed(String),
+
+    #[error("Failed to fetch source from `{url}`: {error}")]
+    FetchFailed {
+        url: url::Url,
+        error: Box<SourceError>,
+    },
+
+    #[error("Fetching the sources was cancelled")]
+    Cancelled,
+
+    #[error("The header `{header}` of the source references the environment variable `{variable}`, which is not set")]
+    MissingHeaderVariable { header: String, variable: String },
+
+    #[error("The header `{header}` of the source is invalid: {reason}")]
+    InvalidHeader { header: String, reason: String },
+
+    #[error("The server refused the download of `{url}` ({status})")]
+    #[diagnostic(help("{help}"))]
+    Unauthorized {
+        url: url::Url,
+        status: reqwest::StatusCode,
+        help: &'static str,
+    },
 }
 
-/// Fetches all sources in a list of sources and applies specified patches
+/// Sources that were already fetched (and patched) into a directory, e.g. to render a recipe that
+/// reads files from its sources. The builds with the same sources copy this directory instead
+/// of fetching the sources again.
+#[derive(Debug, Clone)]
+pub struct FetchedSources {
+    /// The sources that were fetched
+    pub sources: Vec<Source>,
+    /// The directory that the sources were fetched into
+    pub directory: PathBuf,
+    /// The commits that were checked out for the git sources, by the index of the source
+    pub commits: HashMap<usize, String>,
+}
+
+/// Fetches all sources in a list of sources and applies specified patches. The patches of a
+/// source are applied in its destination folder (the `folder` of the source in the work
+/// directory), so their paths are relative to the root of the source. The source limits of
+/// the `tool_configuration` apply to every single source, unless they are overridden in the
+/// recipe. External tools (`git`, and `patch` if the configuration asks for it) are looked up
+/// with `tools`.
+///
+/// The downloads and git repositories are kept in the shared `source_cache`.
+///
+/// The URL sources are downloaded concurrently (at most `source_fetch_concurrency` at a time)
+/// before any source is copied or extracted, and all sources are then put into the work
+/// directory one after the other, in the order of the recipe.
+///
+/// Returns the commits that were checked out for the git sources, by the index of the source.
 pub async fn fetch_sources(
     sources: &[Source],
     work_dir: &Path,
     recipe_dir: &Path,
-    cache_dir: &Path,
-) -> Result<(), SourceError> {
-    let cache_src = cache_dir.join("src_cache");
-    fs::create_dir_all(&cache_src)?;
-
-    for src in sources {
+    source_cache: &Path,
+    tool_configuration: &tool_configuration::Configuration,
+    tools: &Tools,
+) -> Result<HashMap<usize, String>, SourceError> {
+    fs::create_dir_all(source_cache)?;
+    cache::sweep_orphaned_tmp_files(source_cache, cache::ORPHANED_TMP_MAX_AGE)?;
+
+    // dropping the downloads aborts them; a partial file is resumed by the next build
+    let mut downloads = tokio::select! {
+        downloads = download_url_sources(sources, source_cache, tool_configuration) => downloads?,
+        _ = tool_configuration.cancellation.cancelled() => return Err(SourceError::Cancelled),
+    };
+
+    let mut commits = HashMap::new();
+    for (index, src) in sources.iter().enumerate() {
+        let limits = tool_configuration
+            .source_limits
+            .with_overrides(src.limits());
         match &src {
             Source::Git(src) => {
                 tracing::info!("Fetching source from git repo: {}", src.url());
-                let result = git_source::git_src(src, &cache_src, recipe_dir)?;
+                // another build must not check out another revision until it is copied
+                let _lock =
+                    cache::lock_entry(source_cache, &git_source::cache_name(src, recipe_dir)?)?;
+                let (result, commit) = git_source::git_src(src, source_cache, recipe_dir, tools)?;
+                commits.insert(index, commit);
                 let dest_dir = if let Some(folder) = src.folder() {
                     work_dir.join(folder)
                 } else {
                     work_dir.to_path_buf()
                 };
+                let name = src.url().to_string();
+                let progress =
+                    Progress::new(tool_configuration, "copying", None, ProgressUnit::Files);
                 crate::source::copy_dir::CopyDir::new(&result, &dest_dir)
                     .use_gitignore(false)
+                    .with_limits(&name, limits)
+                    .with_progress(&progress)
                     .run()?;
+                progress.finish();
                 if !src.patches().is_empty() {
-                    patch::apply_patches(src.patches(), work_dir, recipe_dir)?;
+                    patch::apply_patches(
+                        src.patches(),
+                        &dest_dir,
+                        recipe_dir,
+                        tools,
+                        tool_configuration.use_patch_executable,
+                    )?;
                 }
             }
             Source::Url(src) => {
-                tracing::info!("Fetching source from URL: {}", src.url());
-                let res = url_source::url_src(src, &cache_src).await?;
-                let mut dest_dir = if let Some(folder) = src.folder() {
+                let res = downloads
+                    .remove(&index)
+                    .expect("all URL sources are downloaded");
+                let dest_dir = if let Some(folder) = src.folder() {
                     work_dir.join(folder)
                 } else {
                     work_dir.to_path_buf()
@@ -112,33 +211,48 @@ pub async fn fetch_sources(
                     fs::create_dir_all(&dest_dir)?;
                 }
 
-                const KNOWN_ARCHIVE_EXTENSIONS: [&str; 5] =
-                    ["tar", "tar.gz", "tar.xz", "tar.bz2", "zip"];
-                if KNOWN_ARCHIVE_EXTENSIONS.iter().any(|ext| {
-                    res.file_name()
-                        .unwrap_or_default()
-                        .to_string_lossy()
-                        .ends_with(ext)
-                }) {
-                    extract(&res, &dest_dir)?;
+                let name = src.url().to_string();
+                let res_file_name = res.file_name().unwrap_or_default().to_string_lossy();
+                if res_file_name.ends_with(".conda")
+                    || (src.conda_package() && res_file_name.ends_with(".tar.bz2"))
+                {
+                    let mut tracker = LimitTracker::new(&name, limits);
+                    extract_conda_package(
+                        &res,
+                        &dest_dir,
+                        src.conda_package(),
+                        &mut tracker,
+                        tool_configuration,
+                    )?;
+                    tracing::info!("Extracted conda package to {:?}", dest_dir);
+                } else if let Some(format) = ArchiveFormat::detect(&res)? {
+                    let mut tracker = LimitTracker::new(&name, limits);
+                    extract(&res, format, &dest_dir, &mut tracker, tool_configuration)?;
                     tracing::info!("Extracted to {:?}", dest_dir);
                 } else {
-                    if let Some(file_name) = src.file_name() {
-                        dest_dir = dest_dir.join(file_name);
+                    let dest_file = if let Some(file_name) = src.file_name() {
+                        dest_dir.join(file_name)
                     } else {
-                        dest_dir = dest_dir.join(res.file_name().ok_or_else(|| {
+                        dest_dir.join(res.file_name().ok_or_else(|| {
                             SourceError::UnknownError(format!(
                                 "Failed to get filename for `{}`",
                                 res.display()
                             ))
-                        })?);
-                    }
-                    fs::copy(&res, &dest_dir)?;
-                    tracing::info!("Downloaded to {:?}", dest_dir);
+                        })?)
+                    };
+                    check_file(&res, &name, limits)?;
+                    fs::copy(&res, &dest_file)?;
+                    tracing::info!("Downloaded to {:?}", dest_file);
                 }
 
                 if !src.patches().is_empty() {
-                    patch::apply_patches(src.patches(), work_dir, recipe_dir)?;
+                    patch::apply_patches(
+                        src.patches(),
+                        &dest_dir,
+                        recipe_dir,
+                        tools,
+                        tool_configuration.use_patch_executable,
+                    )?;
                 }
             }
             Source::Path(src) => {
@@ -160,10 +274,20 @@ pub async fn fetch_sources(
                 }
 
                 // check if the source path is a directory
+                let name = src.path().display().to_string();
                 if src_path.is_dir() {
+                    let progress =
+                        Progress::new(tool_configuration, "copying", None, ProgressUnit::Files);
                     copy_dir::CopyDir::new(&src_path, &dest_dir)
                         .use_gitignore(src.use_gitignore())
+                        .with_globs(
+                            src.filter().iter().map(String::as_str),
+                            src.exclude().iter().map(String::as_str),
+                        )
+                        .with_limits(&name, limits)
+                        .with_progress(&progress)
                         .run()?;
+                    progress.finish();
                 } else if let Some(file_name) = src
                     .file_name()
                     .cloned()
@@ -174,41 +298,393 @@ pub async fn fetch_sources(
                         src_path,
                         dest_dir.join(&file_name)
                     );
+                    check_file(&src_path, &name, limits)?;
                     fs::copy(&src_path, &dest_dir.join(file_name))?;
                 } else {
                     return Err(SourceError::FileNotFound(src_path));
                 }
 
                 if !src.patches().is_empty() {
-                    patch::apply_patches(src.patches(), work_dir, recipe_dir)?;
+                    patch::apply_patches(
+                        src.patches(),
+                        &dest_dir,
+                        recipe_dir,
+                        tools,
+                        tool_configuration.use_patch_executable,
+                    )?;
                 }
             }
         }
     }
-    Ok(())
+    Ok(commits)
 }
 
-/// Extracts a tar archive to the specified target directory
-fn extract(archive: &Path, target_directory: &Path) -> Result<std::process::Output, SourceError> {
-    let tar_exe = which::which("tar").map_err(|_| SourceError::TarNotFound)?;
+/// Download (or find in the cache) the URL sources, returning the downloaded files by the index
+/// of the source. The first failing download cancels the other ones.
+async fn download_url_sources(
+    sources: &[Source],
+    cache_src: &Path,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<HashMap<usize, PathBuf>, SourceError> {
+    let downloads = sources
+        .iter()
+        .enumerate()
+        .filter_map(|(index, src)| match src {
+            Source::Url(src) => Some((index, src)),
+            _ => None,
+        })
+        .map(|(index, src)| async move {
+            tracing::info!("Fetching source from URL: {}", src.url());
+            url_source::url_src(src, cache_src, tool_configuration)
+                .await
+                .map(|path| (index, path))
+                .map_err(|error| match error {
+                    // the path of a missing local file says everything
+                    SourceError::FileNotFound(_) => error,
+                    error => SourceError::FetchFailed {
+                        url: src.url().clone(),
+                        error: Box::new(error),
+                    },
+                })
+        });
+
+    futures::stream::iter(downloads)
+        .buffer_unordered(tool_configuration.source_fetch_concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+/// Check that a single file that is copied as-is stays within the size limit
+fn check_file(path: &Path, name: &str, limits: SourceLimits) -> Result<(), SourceError> {
+    let mut tracker = LimitTracker::new(name, limits);
+    tracker.add_file()?;
+    tracker.add_bytes(fs::metadata(path)?.len())
+}
+
+/// The progress of extracting `archive`
+fn extraction_progress(
+    archive: &Path,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Progress {
+    let label = archive
+        .file_name()
+        .map(|name| format!("extracting {}", name.to_string_lossy()))
+        .unwrap_or_default();
+    Progress::new(tool_configuration, label, None, ProgressUnit::Files)
+}
+
+/// Extracts a tar or zip archive to the specified target directory, stripping its top-level
+/// folder if it contains all entries. The extracted files are checked against the limits of the
+/// `tracker` and counted in a progress of the `tool_configuration`.
+fn extract(
+    archive: &Path,
+    format: ArchiveFormat,
+    target_directory: &Path,
+    tracker: &mut LimitTracker,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<(), SourceError> {
+    let progress = extraction_progress(archive, tool_configuration);
+    let result = match format {
+        ArchiveFormat::Tar(compression) => {
+            extract::unpack_tar(archive, compression, target_directory, tracker, &progress)
+        }
+        ArchiveFormat::Zip => extract::unpack_zip(archive, target_directory, tracker, &progress),
+    };
+    progress.finish();
+    result
+}
 
-    let output = Command::new(tar_exe)
-        .arg("-xf")
-        .arg(archive.as_os_str())
-        .arg("--preserve-permissions")
-        .arg("--strip-components=1")
-        .arg("-C")
-        .arg(target_directory.as_os_str())
-        .output()?;
+/// Extracts a conda package (`.conda` or `.tar.bz2`) to the specified target directory.
+///
+/// Unlike [`extract`], this keeps the full package layout (including the `info/` folder). If
+/// `require_index_json` is set, the extracted contents must contain `info/index.json`. The
+/// extracted files are checked against the limits of the `tracker`.
+fn extract_conda_package(
+    archive: &Path,
+    target_directory: &Path,
+    require_index_json: bool,
+    tracker: &mut LimitTracker,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<(), SourceError> {
+    let progress = extraction_progress(archive, tool_configuration);
+    let result = extract::unpack_conda_package(archive, target_directory, tracker, &progress);
+    progress.finish();
+    result?;
 
-    if !output.status.success() {
+    if require_index_json && !target_directory.join("info/index.json").is_file() {
         return Err(SourceError::ExtractionError(format!(
-            "Failed to extract archive: {}.\nStdout: {}\nStderr: {}",
-            archive.display(),
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
+            "{} is not a conda package (no `info/index.json` found after extraction)",
+            archive.display()
         )));
     }
 
-    Ok(output)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use fs_err as fs;
+    use rattler_package_streaming::write::{
+        write_conda_package, write_tar_bz2_package, CompressionLevel,
+    };
+
+    use super::{
+        extract_conda_package, fetch_sources, limits::LimitTracker, SourceError, SourceLimits,
+    };
+    use crate::{
+        recipe::parser::Recipe, selectors::SelectorConfig, tool_configuration::Configuration,
+        tools::Tools,
+    };
+
+    /// Extracts the conda package at `archive` with the default limits
+    fn extract_package(
+        archive: &Path,
+        dest: &Path,
+        require_index_json: bool,
+    ) -> Result<(), SourceError> {
+        let mut tracker = LimitTracker::new("package", SourceLimits::default());
+        extract_conda_package(
+            archive,
+            dest,
+            require_index_json,
+            &mut tracker,
+            &Configuration::default(),
+        )
+    }
+
+    /// Creates the files of a tiny package in `base` and returns their paths
+    fn package_files(base: &Path, with_index_json: bool) -> Vec<PathBuf> {
+        let mut files = vec![base.join("lib/hello.txt")];
+        if with_index_json {
+            files.push(base.join("info/index.json"));
+        }
+        for file in &files {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+        }
+        fs::write(&files[0], "hello").unwrap();
+        if with_index_json {
+            fs::write(&files[1], r#"{"name": "hello", "version": "1.0"}"#).unwrap();
+        }
+        files
+    }
+
+    #[test]
+    fn extract_tar_bz2_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("pkg");
+        let files = package_files(&base, true);
+
+        let archive = tmp.path().join("hello-1.0-0.tar.bz2");
+        write_tar_bz2_package(
+            fs::File::create(&archive).unwrap(),
+            &base,
+            &files,
+            CompressionLevel::Default,
+            None,
+        )
+        .unwrap();
+
+        let dest = tmp.path().join("work");
+        extract_package(&archive, &dest, true).unwrap();
+        assert!(dest.join("info/index.json").is_file());
+        assert_eq!(
+            fs::read_to_string(dest.join("lib/hello.txt")).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn extract_conda_format_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("pkg");
+        let files = package_files(&base, true);
+
+        let archive = tmp.path().join("hello-1.0-0.conda");
+        write_conda_package(
+            fs::File::create(&archive).unwrap(),
+            &base,
+            &files,
+            CompressionLevel::Default,
+            "hello-1.0-0",
+            None,
+        )
+        .unwrap();
+
+        let dest = tmp.path().join("work");
+        extract_package(&archive, &dest, true).unwrap();
+        assert!(dest.join("info/index.json").is_file());
+        assert_eq!(
+            fs::read_to_string(dest.join("lib/hello.txt")).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn plain_tarball_is_not_a_conda_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("pkg");
+        let files = package_files(&base, false);
+
+        let archive = tmp.path().join("hello-1.0.tar.bz2");
+        write_tar_bz2_package(
+            fs::File::create(&archive).unwrap(),
+            &base,
+            &files,
+            CompressionLevel::Default,
+            None,
+        )
+        .unwrap();
+
+        let dest = tmp.path().join("work");
+        let err = extract_package(&archive, &dest, true).unwrap_err();
+        assert!(matches!(err, SourceError::ExtractionError(_)));
+
+        // without the `conda_package` flag the contents are accepted as they are
+        let dest = tmp.path().join("work-unchecked");
+        extract_package(&archive, &dest, false).unwrap();
+        assert!(dest.join("lib/hello.txt").is_file());
+    }
+
+    #[tokio::test]
+    async fn url_sources_are_fetched_concurrently() {
+        use sha2::{Digest, Sha256};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let recipe_dir = tmp.path().join("recipe");
+        fs::create_dir_all(&recipe_dir).unwrap();
+        let mut urls = Vec::new();
+        for name in ["one", "two", "three"] {
+            let path = tmp.path().join("downloads").join(format!("{name}.txt"));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, name).unwrap();
+            urls.push((
+                url::Url::from_file_path(&path).unwrap(),
+                hex::encode(Sha256::digest(name)),
+            ));
+        }
+
+        let recipe = |broken: bool| {
+            let mut sources = String::new();
+            for (index, (url, sha256)) in urls.iter().enumerate() {
+                let sha256 = if broken && index == 1 {
+                    "0".repeat(64)
+                } else {
+                    sha256.clone()
+                };
+                sources.push_str(&format!(
+                    "  - url: {url}\n    sha256: {sha256}\n    folder: shared\n"
+                ));
+            }
+            Recipe::from_yaml(
+                &format!("package:\n  name: test\n  version: 1.0.0\nsource:\n{sources}"),
+                SelectorConfig::default(),
+            )
+            .unwrap()
+        };
+
+        for concurrency in [1, 3] {
+            let configuration = Configuration {
+                source_fetch_concurrency: concurrency,
+                ..Default::default()
+            };
+            let work_dir = tmp.path().join(format!("work-{concurrency}"));
+            fetch_sources(
+                recipe(false).sources(),
+                &work_dir,
+                &recipe_dir,
+                &tmp.path().join("cache"),
+                &configuration,
+                &Tools::default(),
+            )
+            .await
+            .unwrap();
+            for name in ["one", "two", "three"] {
+                assert_eq!(
+                    fs::read_to_string(work_dir.join("shared").join(format!("{name}.txt")))
+                        .unwrap(),
+                    name
+                );
+            }
+        }
+
+        let err = fetch_sources(
+            recipe(true).sources(),
+            &tmp.path().join("work-broken"),
+            &recipe_dir,
+            &tmp.path().join("cache"),
+            &Configuration::default(),
+            &Tools::default(),
+        )
+        .await
+        .unwrap_err();
+        match err {
+            SourceError::FetchFailed { url, error } => {
+                assert_eq!(url, urls[1].0);
+                assert!(matches!(*error, SourceError::ValidationFailed { .. }));
+            }
+            err => panic!("unexpected error: {err}"),
+        }
+        // nothing is extracted or copied when a download fails
+        assert!(!tmp.path().join("work-broken").exists());
+    }
+
+    #[tokio::test]
+    async fn patches_are_applied_in_the_source_folder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let recipe_dir = tmp.path().join("recipe");
+        for source in ["first", "second"] {
+            fs::create_dir_all(recipe_dir.join(source)).unwrap();
+            fs::write(recipe_dir.join(source).join("hello.txt"), "hello\n").unwrap();
+            fs::write(
+                recipe_dir.join(format!("{source}.patch")),
+                format!(
+                    "--- a/hello.txt\n+++ b/hello.txt\n@@ -1 +1 @@\n-hello\n+hello from {source}\n"
+                ),
+            )
+            .unwrap();
+        }
+
+        let recipe = Recipe::from_yaml(
+            r#"
+            package:
+              name: test
+              version: 1.0.0
+            source:
+              - path: ./first
+                folder: first
+                patches:
+                  - first.patch
+              - path: ./second
+                folder: nested/second
+                patches:
+                  - second.patch
+            "#,
+            SelectorConfig::default(),
+        )
+        .unwrap();
+
+        let work_dir = tmp.path().join("work");
+        fetch_sources(
+            recipe.sources(),
+            &work_dir,
+            &recipe_dir,
+            &tmp.path().join("cache"),
+            &Configuration::default(),
+            &Tools::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(work_dir.join("first/hello.txt")).unwrap(),
+            "hello from first\n"
+        );
+        assert_eq!(
+            fs::read_to_string(work_dir.join("nested/second/hello.txt")).unwrap(),
+            "hello from second\n"
+        );
+        assert!(!work_dir.join("hello.txt").exists());
+    }
 }
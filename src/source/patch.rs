@@ -1,39 +1,610 @@
 //! Functions for applying patches to a work directory.
+//!
+//! Patches are unified diffs (as created by `git diff` or `diff -u`). They are applied like
+//! `patch -p1` would, but without an external tool: the first component of the paths in the
+//! patch is stripped, and hunks are matched ignoring the line endings, so that a patch with `LF`
+//! line endings applies to a source checkout with `CRLF` line endings (and vice versa). The line
+//! endings of the patched file are kept. All files of a patch are checked before the first one is
+//! written, so a patch that does not apply leaves the work directory untouched.
+
 use std::{
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     process::Command,
 };
 
+use fs_err as fs;
+
 use super::SourceError;
+use crate::tools::{Tool, Tools};
 
-/// Applies all patches in a list of patches to the specified work directory
-/// Currently only supports patching with the `patch` command.
+/// Applies all patches in a list of patches to the specified work directory. If
+/// `use_patch_executable` is set, the patches are applied with the `patch` executable instead of
+/// the built-in implementation.
 pub(crate) fn apply_patches(
     patches: &[PathBuf],
     work_dir: &Path,
     recipe_dir: &Path,
+    tools: &Tools,
+    use_patch_executable: bool,
 ) -> Result<(), SourceError> {
     for patch in patches {
         let patch = recipe_dir.join(patch);
+        if use_patch_executable {
+            apply_with_executable(&patch, work_dir, tools)?;
+        } else {
+            apply_patch(&patch, work_dir)?;
+        }
+    }
+    Ok(())
+}
 
-        let patch_exe = which::which("patch").map_err(|_| SourceError::PatchNotFound)?;
-
-        let output = Command::new(patch_exe)
-            .arg("-p1")
-            .arg("-i")
-            .arg(String::from(patch.to_string_lossy()))
-            .arg("-d")
-            .arg(String::from(work_dir.to_string_lossy()))
-            .output()?;
-
-        if !output.status.success() {
-            tracing::error!("Failed to apply patch: {}", patch.to_string_lossy());
-            tracing::error!("Stdout: {}", String::from_utf8_lossy(&output.stdout));
-            tracing::error!("Stderr: {}", String::from_utf8_lossy(&output.stderr));
-            return Err(SourceError::PatchFailed(
-                patch.to_string_lossy().to_string(),
-            ));
+/// Applies a single patch with the `patch` executable
+fn apply_with_executable(patch: &Path, work_dir: &Path, tools: &Tools) -> Result<(), SourceError> {
+    let patch_exe = tools.find(
+        Tool::Patch,
+        format_args!("apply the patch `{}`", patch.display()),
+    )?;
+
+    let output = Command::new(patch_exe)
+        .arg("-p1")
+        .arg("-i")
+        .arg(String::from(patch.to_string_lossy()))
+        .arg("-d")
+        .arg(String::from(work_dir.to_string_lossy()))
+        .output()?;
+
+    if !output.status.success() {
+        tracing::error!("Failed to apply patch: {}", patch.to_string_lossy());
+        tracing::error!("Stdout: {}", String::from_utf8_lossy(&output.stdout));
+        tracing::error!("Stderr: {}", String::from_utf8_lossy(&output.stderr));
+        return Err(SourceError::PatchFailed(
+            patch.to_string_lossy().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Applies a single patch with the built-in implementation
+fn apply_patch(patch: &Path, work_dir: &Path) -> Result<(), SourceError> {
+    let failed =
+        |reason: String| SourceError::PatchFailed(format!("{}: {}", patch.display(), reason));
+
+    let content = fs::read(patch)?;
+    let file_patches = parse_patch(&content).map_err(failed)?;
+
+    // the new contents of all files (`None` for deleted files), in the order of the patch
+    let mut changes: Vec<(PathBuf, Option<Vec<u8>>)> = Vec::new();
+    for file_patch in &file_patches {
+        let (path, new_content) =
+            apply_file_patch(file_patch, work_dir, &changes).map_err(failed)?;
+        match changes.iter_mut().find(|(p, _)| *p == path) {
+            Some(change) => change.1 = new_content,
+            None => changes.push((path, new_content)),
+        }
+    }
+
+    for (path, content) in changes {
+        let path = work_dir.join(path);
+        match content {
+            Some(content) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, content)?;
+            }
+            None => fs::remove_file(&path)?,
         }
     }
     Ok(())
 }
+
+/// The changes of a patch to a single file
+#[derive(Debug)]
+struct FilePatch<'a> {
+    /// The path before the change, `None` if the file is created
+    old: Option<PathBuf>,
+    /// The path after the change, `None` if the file is deleted
+    new: Option<PathBuf>,
+    /// Whether the added lines have `CRLF` line endings (used for created files)
+    crlf: bool,
+    hunks: Vec<Hunk<'a>>,
+}
+
+/// A single `@@ -a,b +c,d @@` section of a [`FilePatch`]
+#[derive(Debug)]
+struct Hunk<'a> {
+    /// The `@@ ... @@` line, to name the hunk in errors
+    header: String,
+    /// The first line of the hunk in the old file (1-based, 0 for an empty file)
+    old_start: usize,
+    /// The lines of the hunk, without the line endings
+    lines: Vec<HunkLine<'a>>,
+    /// Whether the old file does not end with a newline after this hunk
+    old_no_newline: bool,
+    /// Whether the new file does not end with a newline after this hunk
+    new_no_newline: bool,
+}
+
+#[derive(Debug)]
+enum HunkLine<'a> {
+    Context(&'a [u8]),
+    Remove(&'a [u8]),
+    Add(&'a [u8]),
+}
+
+impl<'a> Hunk<'a> {
+    /// The lines that the hunk expects in the old file
+    fn old_lines(&self) -> Vec<&'a [u8]> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(l) | HunkLine::Remove(l) => Some(*l),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Git extended headers of changes that cannot be expressed as hunks
+const UNSUPPORTED_CHANGES: [&[u8]; 4] = [
+    b"rename from ",
+    b"copy from ",
+    b"GIT binary patch",
+    b"Binary files ",
+];
+
+/// Parse a unified diff. Everything outside of the `---`/`+++` headers and their hunks (e.g.
+/// the commit message of a `git format-patch` patch) is ignored.
+fn parse_patch(content: &[u8]) -> Result<Vec<FilePatch<'_>>, String> {
+    let (lines, _) = split_lines(content);
+    let mut file_patches = Vec::new();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let line = trim_cr(lines[i]);
+        if let Some(header) = UNSUPPORTED_CHANGES.iter().find(|h| line.starts_with(h)) {
+            return Err(format!(
+                "the patch contains a change that is not supported (`{}`), use \
+                 `--use-patch-executable` to apply it with the `patch` executable",
+                String::from_utf8_lossy(header).trim()
+            ));
+        }
+
+        let next = lines.get(i + 1).map(|l| trim_cr(l));
+        match (
+            line.strip_prefix(b"--- "),
+            next.and_then(|l| l.strip_prefix(b"+++ ")),
+        ) {
+            (Some(old), Some(new)) => {
+                let mut file_patch = FilePatch {
+                    old: parse_path(old),
+                    new: parse_path(new),
+                    crlf: false,
+                    hunks: Vec::new(),
+                };
+                i += 2;
+                while lines.get(i).is_some_and(|l| l.starts_with(b"@@ ")) {
+                    i = parse_hunk(&lines, i, &mut file_patch)?;
+                }
+                file_patches.push(file_patch);
+            }
+            _ => i += 1,
+        }
+    }
+
+    if file_patches.is_empty() {
+        return Err("the patch does not contain any changes".to_string());
+    }
+    Ok(file_patches)
+}
+
+/// Parse the hunk that starts at `lines[start]` into `file_patch` and return the index of the
+/// line after it
+fn parse_hunk<'a>(
+    lines: &[&'a [u8]],
+    start: usize,
+    file_patch: &mut FilePatch<'a>,
+) -> Result<usize, String> {
+    let header = String::from_utf8_lossy(trim_cr(lines[start])).to_string();
+    let invalid = || format!("invalid hunk header `{header}`");
+
+    let mut ranges = header.trim_start_matches("@@ ").split(' ');
+    let parse_range = |range: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let range = range?.strip_prefix(sign)?;
+        let (start, len) = range.split_once(',').unwrap_or((range, "1"));
+        Some((start.parse().ok()?, len.parse().ok()?))
+    };
+    let (old_start, mut old_remaining) = parse_range(ranges.next(), '-').ok_or_else(invalid)?;
+    let (_, mut new_remaining) = parse_range(ranges.next(), '+').ok_or_else(invalid)?;
+
+    let mut hunk = Hunk {
+        header: header.clone(),
+        old_start,
+        lines: Vec::new(),
+        old_no_newline: false,
+        new_no_newline: false,
+    };
+
+    let mut i = start + 1;
+    while i < lines.len() {
+        let raw = lines[i];
+        let line = trim_cr(raw);
+        // `\ No newline at end of file` refers to the line before it
+        if line.starts_with(b"\\") {
+            match hunk.lines.last() {
+                Some(HunkLine::Context(_)) => {
+                    hunk.old_no_newline = true;
+                    hunk.new_no_newline = true;
+                }
+                Some(HunkLine::Remove(_)) => hunk.old_no_newline = true,
+                Some(HunkLine::Add(_)) => hunk.new_no_newline = true,
+                None => return Err(format!("unexpected `{}`", String::from_utf8_lossy(line))),
+            }
+            i += 1;
+            continue;
+        }
+        if old_remaining == 0 && new_remaining == 0 {
+            break;
+        }
+
+        // some editors strip the trailing space of empty context lines
+        let (kind, content) = line.split_first().unwrap_or((&b' ', b"".as_slice()));
+        match kind {
+            b' ' if old_remaining > 0 && new_remaining > 0 => {
+                old_remaining -= 1;
+                new_remaining -= 1;
+                hunk.lines.push(HunkLine::Context(content));
+            }
+            b'-' if old_remaining > 0 => {
+                old_remaining -= 1;
+                hunk.lines.push(HunkLine::Remove(content));
+            }
+            b'+' if new_remaining > 0 => {
+                new_remaining -= 1;
+                file_patch.crlf |= raw.ends_with(b"\r");
+                hunk.lines.push(HunkLine::Add(content));
+            }
+            _ => break,
+        }
+        i += 1;
+    }
+
+    if old_remaining > 0 || new_remaining > 0 {
+        return Err(format!(
+            "hunk #{} (`{}`) is incomplete",
+            file_patch.hunks.len() + 1,
+            header
+        ));
+    }
+    file_patch.hunks.push(hunk);
+    Ok(i)
+}
+
+/// Parse the path of a `---` or `+++` line, `None` for `/dev/null`
+fn parse_path(raw: &[u8]) -> Option<PathBuf> {
+    let raw = String::from_utf8_lossy(raw);
+    // the path may be followed by a tab and a timestamp
+    let path = raw.split('\t').next().unwrap_or_default().trim();
+    let path = path.trim_matches('"');
+    (path != "/dev/null").then(|| PathBuf::from(path))
+}
+
+/// Strip the first component of a path in the patch (like `patch -p1`). Paths without a
+/// directory are kept as they are.
+fn strip_path(path: &Path) -> Result<PathBuf, String> {
+    let mut components = path.components().peekable();
+    if path.components().count() > 1 {
+        components.next();
+    }
+
+    let stripped = components.as_path().to_path_buf();
+    if stripped
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "the path `{}` points outside of the work directory",
+            path.display()
+        ));
+    }
+    Ok(stripped)
+}
+
+/// Apply the hunks of a single file and return the (stripped) path with its new content, or
+/// `None` if the file is deleted. `changes` are the files that were changed by the patch before.
+fn apply_file_patch(
+    file_patch: &FilePatch,
+    work_dir: &Path,
+    changes: &[(PathBuf, Option<Vec<u8>>)],
+) -> Result<(PathBuf, Option<Vec<u8>>), String> {
+    let current = |path: &Path| -> Result<Option<Vec<u8>>, String> {
+        if let Some((_, content)) = changes.iter().find(|(p, _)| p == path) {
+            return Ok(content.clone());
+        }
+        let full_path = work_dir.join(path);
+        if !full_path.is_file() {
+            return Ok(None);
+        }
+        fs::read(&full_path).map(Some).map_err(|e| e.to_string())
+    };
+
+    let old = file_patch.old.as_deref().map(strip_path).transpose()?;
+    let new = file_patch.new.as_deref().map(strip_path).transpose()?;
+
+    let (path, original) = match (old, new) {
+        (None, None) => return Err("a file is neither created nor changed".to_string()),
+        (None, Some(new)) => match current(&new)? {
+            Some(content) if !content.is_empty() => {
+                return Err(format!(
+                    "the patch creates `{}`, but it already exists",
+                    new.display()
+                ))
+            }
+            _ => (new, Vec::new()),
+        },
+        (Some(old), new) => {
+            let candidates = std::iter::once(old.clone()).chain(new);
+            let mut found = None;
+            for candidate in candidates {
+                if let Some(content) = current(&candidate)? {
+                    found = Some((candidate, content));
+                    break;
+                }
+            }
+            found.ok_or_else(|| format!("the patched file `{}` does not exist", old.display()))?
+        }
+    };
+
+    let crlf = if original.is_empty() {
+        file_patch.crlf
+    } else {
+        original.windows(2).any(|w| w == b"\r\n")
+    };
+    let patched = apply_hunks(&original, &file_patch.hunks, crlf).map_err(|hunk| {
+        format!(
+            "hunk #{} (`{}`) does not apply to `{}`",
+            hunk + 1,
+            file_patch.hunks[hunk].header,
+            path.display()
+        )
+    })?;
+
+    if file_patch.new.is_none() {
+        if !patched.is_empty() {
+            return Err(format!(
+                "the patch deletes `{}`, but does not remove all of its content",
+                path.display()
+            ));
+        }
+        return Ok((path, None));
+    }
+    Ok((path, Some(patched)))
+}
+
+/// Apply hunks to the content of a file. Hunks are searched near the position given in their
+/// header, so that they still apply if lines were added or removed before them. Returns the
+/// index of the first hunk that does not apply on failure.
+fn apply_hunks(original: &[u8], hunks: &[Hunk], crlf: bool) -> Result<Vec<u8>, usize> {
+    let (lines, mut final_newline) = split_lines(original);
+    let mut result: Vec<(&[u8], bool)> = Vec::new();
+    let mut pos = 0;
+    let mut offset = 0isize;
+
+    for (index, hunk) in hunks.iter().enumerate() {
+        let old_lines = hunk.old_lines();
+        // a hunk without old lines is inserted after the line `old_start`
+        let base = if old_lines.is_empty() {
+            hunk.old_start as isize
+        } else {
+            hunk.old_start.saturating_sub(1) as isize
+        };
+        let expected = (base + offset).clamp(pos as isize, lines.len() as isize) as usize;
+        let at = find_lines(&lines, &old_lines, expected, pos).ok_or(index)?;
+
+        result.extend(lines[pos..at].iter().map(|l| (*l, false)));
+        // context lines are kept as they are in the file (including their line ending)
+        let mut old_index = at;
+        for line in &hunk.lines {
+            match line {
+                HunkLine::Context(_) => {
+                    result.push((lines[old_index], false));
+                    old_index += 1;
+                }
+                HunkLine::Remove(_) => old_index += 1,
+                HunkLine::Add(l) => result.push((l, true)),
+            }
+        }
+
+        pos = at + old_lines.len();
+        offset = at as isize - base;
+        if pos == lines.len() {
+            if hunk.new_no_newline {
+                final_newline = false;
+            } else if hunk.old_no_newline {
+                final_newline = true;
+            }
+        }
+    }
+    result.extend(lines[pos..].iter().map(|l| (*l, false)));
+
+    let mut patched = Vec::with_capacity(original.len());
+    for (i, (line, added)) in result.iter().enumerate() {
+        patched.extend_from_slice(line);
+        if i + 1 < result.len() || final_newline {
+            if *added && crlf {
+                patched.push(b'\r');
+            }
+            patched.push(b'\n');
+        }
+    }
+    Ok(patched)
+}
+
+/// Find the position of `needle` in `lines` closest to `expected`, but not before `min`
+fn find_lines(lines: &[&[u8]], needle: &[&[u8]], expected: usize, min: usize) -> Option<usize> {
+    let matches_at = |at: usize| {
+        at >= min
+            && at + needle.len() <= lines.len()
+            && lines[at..at + needle.len()]
+                .iter()
+                .zip(needle)
+                .all(|(line, expected)| trim_cr(line) == *expected)
+    };
+
+    (0..=lines.len()).find_map(|distance| {
+        [
+            expected.checked_add(distance),
+            expected.checked_sub(distance),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|at| matches_at(*at))
+    })
+}
+
+/// Split content into lines (without the `\n`) and return whether it ends with a newline
+fn split_lines(content: &[u8]) -> (Vec<&[u8]>, bool) {
+    if content.is_empty() {
+        return (Vec::new(), true);
+    }
+    let mut lines: Vec<&[u8]> = content.split(|b| *b == b'\n').collect();
+    let final_newline = content.ends_with(b"\n");
+    if final_newline {
+        lines.pop();
+    }
+    (lines, final_newline)
+}
+
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use fs_err as fs;
+
+    use super::apply_patches;
+    use crate::tools::Tools;
+
+    const LIB_C: &str = "int add(int a, int b) {\n    return a + b;\n}\n\nint sub(int a, int b) {\n    return a - b;\n}\n";
+
+    fn work_dir(line_ending: &str, lib_c: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content.replace('\n', line_ending)).unwrap();
+        };
+        write("README.md", "# Project\n\nSome text.\nMore text.\n");
+        write("obsolete.txt", "remove me\n");
+        write("src/lib.c", lib_c);
+        dir
+    }
+
+    fn apply(work_dir: &Path, patch: &str) -> Result<(), crate::source::SourceError> {
+        let patches_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/patches");
+        apply_patches(
+            &[PathBuf::from(patch)],
+            work_dir,
+            &patches_dir,
+            &Tools::default(),
+            false,
+        )
+    }
+
+    fn read(work_dir: &Path, path: &str) -> String {
+        fs::read_to_string(work_dir.join(path)).unwrap()
+    }
+
+    #[test]
+    fn multi_file_patch() {
+        let dir = work_dir("\n", LIB_C);
+        apply(dir.path(), "multi_file.patch").unwrap();
+
+        assert_eq!(
+            read(dir.path(), "README.md"),
+            "# Project\n\nSome patched text.\nMore text.\n"
+        );
+        assert_eq!(
+            read(dir.path(), "new_file.txt"),
+            "a new file\nwith two lines\n"
+        );
+        assert!(!dir.path().join("obsolete.txt").exists());
+        assert_eq!(
+            read(dir.path(), "src/lib.c"),
+            "#include \"lib.h\"\n\n/* added */\n\nint add(int a, int b) {\n    return a + b;\n}\n\n\
+             int sub(int a, int b) {\n    int result = a - b;\n    return result;\n}\n"
+        );
+    }
+
+    #[test]
+    fn crlf_checkout() {
+        let dir = work_dir("\r\n", LIB_C);
+        apply(dir.path(), "multi_file.patch").unwrap();
+
+        // the line endings of the patched files are kept, created files use the ones of the patch
+        assert_eq!(
+            read(dir.path(), "README.md"),
+            "# Project\r\n\r\nSome patched text.\r\nMore text.\r\n"
+        );
+        assert_eq!(
+            read(dir.path(), "new_file.txt"),
+            "a new file\nwith two lines\n"
+        );
+        assert!(read(dir.path(), "src/lib.c").starts_with("#include \"lib.h\"\r\n\r\n"));
+    }
+
+    #[test]
+    fn hunks_with_offset() {
+        let lib_c = format!("// license header\n// more license\n\n{LIB_C}");
+        let dir = work_dir("\n", &lib_c);
+        apply(dir.path(), "multi_file.patch").unwrap();
+
+        assert!(read(dir.path(), "src/lib.c")
+            .starts_with("// license header\n// more license\n\n#include \"lib.h\"\n"));
+        assert!(read(dir.path(), "src/lib.c").contains("    int result = a - b;\n"));
+    }
+
+    #[test]
+    fn failing_hunk() {
+        let dir = work_dir("\n", &LIB_C.replace("a - b", "b - a"));
+        let err = apply(dir.path(), "multi_file.patch").unwrap_err();
+
+        assert!(
+            err.to_string().ends_with(
+                "hunk #2 (`@@ -5,3 +9,4 @@ int add(int a, int b) {`) does not apply to `src/lib.c`"
+            ),
+            "{err}"
+        );
+        // nothing was changed
+        assert_eq!(
+            read(dir.path(), "README.md"),
+            "# Project\n\nSome text.\nMore text.\n"
+        );
+        assert!(dir.path().join("obsolete.txt").exists());
+        assert!(!dir.path().join("new_file.txt").exists());
+    }
+
+    #[test]
+    fn missing_newline_at_end() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), "a\nb").unwrap();
+        fs::write(
+            dir.path().join("fix.patch"),
+            "--- a/file.txt\n+++ b/file.txt\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n",
+        )
+        .unwrap();
+
+        apply_patches(
+            &[PathBuf::from("fix.patch")],
+            dir.path(),
+            dir.path(),
+            &Tools::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(read(dir.path(), "file.txt"), "a\nc\n");
+    }
+}
@@ -2,38 +2,47 @@
 
 use std::{
     fs,
-    io::Cursor,
+    io::{ErrorKind, Write},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use crate::recipe::parser::{Checksum, UrlSource};
+use crate::{
+    bandwidth::BandwidthLimiter,
+    progress::{Progress, ProgressUnit},
+    recipe::parser::{Blake2b256, Checksum, UrlSource},
+    tool_configuration,
+};
+use futures::{Stream, StreamExt};
+use itertools::Itertools;
 use rattler_digest::compute_file_digest;
+use reqwest::StatusCode;
 
-use super::SourceError;
-
-fn validate_checksum(path: &Path, checksum: &Checksum) -> bool {
-    match checksum {
-        Checksum::Sha256(value) => {
-            let digest =
-                compute_file_digest::<sha2::Sha256>(path).expect("Could not compute SHA256");
-            let computed_sha = hex::encode(digest);
-            let checksum_sha = hex::encode(value);
-            if !computed_sha.eq(&checksum_sha) {
-                tracing::error!(
-                    "SHA256 values of downloaded file not matching!\nDownloaded = {}, should be {}",
-                    computed_sha,
-                    checksum_sha
-                );
-                false
-            } else {
-                tracing::info!("Validated SHA256 values of the downloaded file!");
-                true
-            }
-        }
-        Checksum::Md5(_value) => {
-            todo!("MD5 not implemented yet!");
-        }
+use super::{auth::SourceCredentials, cache, SourceError};
+
+/// Verify that the file at `path` has the `checksum`
+fn validate_checksum(path: &Path, checksum: &Checksum) -> Result<(), SourceError> {
+    let actual = match checksum {
+        Checksum::Sha256(_) => hex::encode(compute_file_digest::<sha2::Sha256>(path)?),
+        Checksum::Md5(_) => hex::encode(compute_file_digest::<rattler_digest::Md5>(path)?),
+        Checksum::Blake2(_) => hex::encode(compute_file_digest::<Blake2b256>(path)?),
+    };
+    let expected = checksum.to_hex();
+    if actual != expected {
+        return Err(SourceError::ValidationFailed {
+            path: path.to_path_buf(),
+            algorithm: checksum.algorithm(),
+            expected,
+            actual,
+            size: fs::metadata(path)?.len(),
+        });
     }
+    tracing::info!(
+        "Validated the {} checksum of {}",
+        checksum.algorithm(),
+        path.display()
+    );
+    Ok(())
 }
 
 fn split_filename(filename: &str) -> (String, String) {
@@ -65,73 +74,389 @@ fn split_filename(filename: &str) -> (String, String) {
 fn cache_name_from_url(url: &url::Url, checksum: &Checksum) -> Option<String> {
     let filename = url.path_segments()?.last()?;
     let (stem, extension) = split_filename(filename);
-    let checksum = match checksum {
-        Checksum::Sha256(value) => hex::encode(value),
-        Checksum::Md5(value) => hex::encode(value),
-    };
+    let checksum = checksum.to_hex();
     Some(format!("{}_{}{}", stem, &checksum[0..8], extension))
 }
 
-pub(crate) async fn url_src(source: &UrlSource, cache_dir: &Path) -> Result<PathBuf, SourceError> {
-    // convert sha256 or md5 to Checksum
-    let checksum = if let Some(sha256) = source.sha256() {
-        Checksum::Sha256(*sha256)
-    } else if let Some(md5) = source.md5() {
-        Checksum::Md5(*md5)
-    } else {
-        return Err(SourceError::NoChecksum(source.url().clone()));
+/// The style of the progress bar of a source download
+fn download_progress_style() -> indicatif::ProgressStyle {
+    indicatif::ProgressStyle::default_bar()
+        .template("{spinner:.green} {prefix:20!} [{elapsed_precise}] [{bar:40!.bright.yellow/dim.white}] {bytes:>10}/{total_bytes:10} {msg}")
+        .expect("the progress bar template is valid")
+        .progress_chars("━━╾─")
+}
+
+/// The most redirects that are followed for one request
+const MAX_REDIRECTS: usize = 10;
+
+/// Request `url` from byte `offset` on, and follow the redirects. The `credentials` are only
+/// sent to the origin of `url`, not to the locations that it redirects to on other hosts.
+async fn send(
+    client: &reqwest::Client,
+    url: &url::Url,
+    credentials: &SourceCredentials,
+    offset: u64,
+) -> Result<reqwest::Response, SourceError> {
+    let mut location = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = client
+            .get(location.clone())
+            .headers(credentials.headers_for(&location));
+        if offset > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+        }
+        let response = request.send().await?;
+        let next = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|next| next.to_str().ok());
+        match next {
+            Some(next) if response.status().is_redirection() => {
+                location = location.join(next).map_err(|e| {
+                    SourceError::UnknownError(format!(
+                        "Invalid redirect from {location} to {next}: {e}"
+                    ))
+                })?;
+            }
+            _ => return Ok(response),
+        }
+    }
+    Err(SourceError::UnknownError(format!(
+        "Too many redirects for {url}"
+    )))
+}
+
+/// Write the downloaded `chunks` to `writer`, at most at the rate allowed by `limiter`
+async fn write_chunks<B: AsRef<[u8]>>(
+    chunks: impl Stream<Item = Result<B, reqwest::Error>>,
+    writer: &mut impl Write,
+    limiter: Option<&BandwidthLimiter>,
+    progress: &Progress,
+) -> Result<(), SourceError> {
+    let chunks = match limiter {
+        Some(limiter) => limiter.throttle(chunks).left_stream(),
+        None => chunks.right_stream(),
     };
+    futures::pin_mut!(chunks);
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        writer.write_all(chunk.as_ref())?;
+        progress.inc(chunk.as_ref().len() as u64);
+        if let Some(limiter) = limiter {
+            progress.set_message(limiter.rate_message());
+        }
+    }
+    Ok(())
+}
+
+/// Fetch the url `source` into the `cache_dir`. The mirrors of the source are tried in order,
+/// and the next one is used if a mirror cannot be reached, responds with an error or serves a
+/// file with the wrong checksum.
+pub(crate) async fn url_src(
+    source: &UrlSource,
+    cache_dir: &Path,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<PathBuf, SourceError> {
+    let checksum = source
+        .checksum()
+        .ok_or_else(|| SourceError::NoChecksum(source.url().clone()))?;
 
-    if source.url().scheme() == "file" {
-        let local_path = source.url().to_file_path().map_err(|_| {
-            SourceError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid local file path",
-            ))
-        })?;
+    // The file can come from any mirror, so a cached file is found by its checksum. The cache
+    // names only differ if the mirrors use different file names.
+    let cache_names = source
+        .urls()
+        .iter()
+        .filter_map(|url| cache_name_from_url(url, &checksum))
+        .unique()
+        .map(|name| cache_dir.join(name))
+        .collect::<Vec<_>>();
+    let cache_name = cache_names.first().ok_or(SourceError::UnknownErrorStr(
+        "Failed to build cache name from url",
+    ))?;
 
-        if !local_path.is_file() {
-            return Err(SourceError::FileNotFound(local_path));
+    // other builds that fetch the same file wait until it is in the cache
+    let lock_name = cache_name
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let lock_dir = cache_dir.to_path_buf();
+    let _lock = tokio::task::spawn_blocking(move || cache::lock_entry(&lock_dir, &lock_name))
+        .await
+        .map_err(|e| SourceError::UnknownError(e.to_string()))??;
+
+    for cached in cache_names.iter().filter(|name| name.is_file()) {
+        match validate_checksum(cached, &checksum) {
+            Ok(()) => {
+                tracing::info!("Found valid source cache file.");
+                cache::mark_used(cached)?;
+                return Ok(cached.clone());
+            }
+            Err(e) => {
+                // a truncated or corrupted earlier download
+                tracing::warn!("Removing the invalid source cache file: {}", e);
+                fs::remove_file(cached)?;
+            }
         }
+    }
 
-        if let Some(sha256) = source.sha256() {
-            if !validate_checksum(&local_path, &Checksum::Sha256(*sha256)) {
-                return Err(SourceError::ValidationFailed);
+    let mut attempts = Vec::new();
+    for url in source.urls() {
+        let result = if url.scheme() == "file" {
+            local_src(url, cache_dir, cache_name, &checksum)
+        } else {
+            match SourceCredentials::for_url(source, url) {
+                Ok(credentials) => {
+                    download_src(
+                        url,
+                        &credentials,
+                        cache_dir,
+                        cache_name,
+                        &checksum,
+                        tool_configuration,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
             }
-        } else if let Some(md5) = source.md5() {
-            if !validate_checksum(&local_path, &Checksum::Md5(*md5)) {
-                return Err(SourceError::ValidationFailed);
+        };
+        match result {
+            Ok(path) => return Ok(path),
+            Err(e) if source.urls().len() == 1 => return Err(e),
+            Err(e) => {
+                tracing::warn!("Fetching {} failed: {}", url, e);
+                attempts.push(format!(" - {url}: {e}"));
             }
         }
-        tracing::info!("Using local source file.");
-        return Ok(local_path);
     }
+    Err(SourceError::AllMirrorsFailed(attempts.join("\n")))
+}
 
-    let cache_name = PathBuf::from(cache_name_from_url(source.url(), &checksum).ok_or(
-        SourceError::UnknownErrorStr("Failed to build cache name from url"),
-    )?);
-    let cache_name = cache_dir.join(cache_name);
+/// Copy the local file of the `file://` url to `cache_name` in the `cache_dir`, if its checksum
+/// matches
+fn local_src(
+    url: &url::Url,
+    cache_dir: &Path,
+    cache_name: &Path,
+    checksum: &Checksum,
+) -> Result<PathBuf, SourceError> {
+    let local_path = url.to_file_path().map_err(|_| {
+        SourceError::Io(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Invalid local file path",
+        ))
+    })?;
 
-    let metadata = fs::metadata(&cache_name);
-    if metadata.is_ok() && metadata?.is_file() && validate_checksum(&cache_name, &checksum) {
-        tracing::info!("Found valid source cache file.");
-        return Ok(cache_name.clone());
+    if !local_path.is_file() {
+        return Err(SourceError::FileNotFound(local_path));
     }
 
-    let response = reqwest::get(source.url().clone()).await?;
+    validate_checksum(&local_path, checksum)?;
+    tracing::info!("Using local source file {}", local_path.display());
+
+    let file_name = cache_name
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let partial = cache::partial_file(cache_dir, &file_name);
+    fs::copy(&local_path, &partial)?;
+    cache::persist_partial(&partial, cache_name)?;
+
+    Ok(cache_name.to_path_buf())
+}
+
+/// Download `url` (with the `credentials` of the source) to `cache_name` in the `cache_dir`,
+/// retrying failed attempts
+async fn download_src(
+    url: &url::Url,
+    credentials: &SourceCredentials,
+    cache_dir: &Path,
+    cache_name: &Path,
+    checksum: &Checksum,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<PathBuf, SourceError> {
+    let file_name = cache_name
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let partial = cache::partial_file(cache_dir, &file_name);
+    let policy = RetryPolicy {
+        max_retries: tool_configuration.download_retries,
+        initial_backoff: INITIAL_BACKOFF,
+    };
+    download_with_retries(
+        url,
+        credentials,
+        &partial,
+        checksum,
+        policy,
+        tool_configuration,
+    )
+    .await?;
+    cache::persist_partial(&partial, cache_name)?;
+
+    Ok(cache_name.to_path_buf())
+}
+
+/// The wait before the first retry of a failed download
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
 
-    let mut file = std::fs::File::create(&cache_name)?;
+/// The longest wait between two attempts of a download
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
 
-    let mut content = Cursor::new(response.bytes().await?);
-    std::io::copy(&mut content, &mut file)?;
+/// How often and after which wait a failed download is retried
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    /// The number of retries after the first attempt
+    max_retries: usize,
+    /// The wait before the first retry, which doubles for every further retry
+    initial_backoff: Duration,
+}
 
-    if !validate_checksum(&cache_name, &checksum) {
-        tracing::error!("Checksum validation failed!");
-        fs::remove_file(&cache_name)?;
-        return Err(SourceError::ValidationFailed);
+impl RetryPolicy {
+    /// The wait before the `retry`th retry (starting at 1)
+    fn backoff(&self, retry: usize) -> Duration {
+        let factor = 1u32 << retry.saturating_sub(1).min(16);
+        self.initial_backoff.saturating_mul(factor).min(MAX_BACKOFF)
     }
+}
 
-    Ok(cache_name)
+/// Whether a failed download can succeed when it is tried again
+fn is_retryable(error: &SourceError) -> bool {
+    match error {
+        SourceError::Url(e) => match e.status() {
+            Some(status) => {
+                status.is_server_error()
+                    || status == StatusCode::REQUEST_TIMEOUT
+                    || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            None => {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body() || e.is_decode()
+            }
+        },
+        SourceError::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::TimedOut
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
+
+/// Download `url` to the `partial` file until its checksum matches. Retryable errors are retried
+/// according to the `policy`, and the download resumes where the previous attempt stopped. A
+/// resumed download with a wrong checksum is downloaded again from the start.
+async fn download_with_retries(
+    url: &url::Url,
+    credentials: &SourceCredentials,
+    partial: &Path,
+    checksum: &Checksum,
+    policy: RetryPolicy,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<(), SourceError> {
+    // redirects are followed by `send`, which decides which headers are sent along
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let mut retry = 0;
+    loop {
+        match download(&client, url, credentials, partial, tool_configuration).await {
+            Ok(resumed) => {
+                let Err(e) = validate_checksum(partial, checksum) else {
+                    return Ok(());
+                };
+                fs::remove_file(partial)?;
+                if !resumed {
+                    return Err(e);
+                }
+                tracing::warn!(
+                    "The resumed download of {} is corrupt, downloading it again",
+                    url
+                );
+            }
+            Err(e) if retry < policy.max_retries && is_retryable(&e) => {
+                retry += 1;
+                let backoff = policy.backoff(retry);
+                tracing::warn!(
+                    "Downloading {} failed: {}. Retrying in {:?} ({}/{})",
+                    url,
+                    e,
+                    backoff,
+                    retry,
+                    policy.max_retries
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Download `url` to the `partial` file. If the file exists, only the rest of it is requested,
+/// and the response is appended if the server supports range requests. Returns whether the
+/// download was resumed.
+async fn download(
+    client: &reqwest::Client,
+    url: &url::Url,
+    credentials: &SourceCredentials,
+    partial: &Path,
+    tool_configuration: &tool_configuration::Configuration,
+) -> Result<bool, SourceError> {
+    let mut offset = fs::metadata(partial).map(|m| m.len()).unwrap_or(0);
+    let response = loop {
+        let response = send(client, url, credentials, offset).await?;
+        if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // the partial file is at least as long as the file on the server
+            fs::remove_file(partial)?;
+            offset = 0;
+            continue;
+        }
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(SourceError::Unauthorized {
+                url: url.clone(),
+                status,
+                help: if credentials.is_empty() {
+                    "the source needs credentials: set `headers` in the recipe (e.g. `Authorization: Bearer ${TOKEN}`), or add the host to the authentication storage or `~/.netrc`"
+                } else {
+                    "check that the credentials of the source (its `headers`, the authentication storage or `~/.netrc`) are valid and allow the download"
+                },
+            });
+        }
+        break response.error_for_status()?;
+    };
+
+    let resumed = offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+    let mut file = if resumed {
+        tracing::info!("Resuming the download of {} after {} bytes", url, offset);
+        fs::OpenOptions::new().append(true).open(partial)?
+    } else {
+        offset = 0;
+        fs::File::create(partial)?
+    };
+
+    let file_name = partial
+        .file_stem()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    // without a content length, a spinner shows the downloaded bytes
+    let total = response.content_length().map(|length| offset + length);
+    let progress = Progress::new(tool_configuration, file_name, total, ProgressUnit::Bytes)
+        .with_bar_style(download_progress_style());
+    progress.set_position(offset);
+    let result = write_chunks(
+        response.bytes_stream(),
+        &mut file,
+        tool_configuration.bandwidth_limit.as_ref(),
+        &progress,
+    )
+    .await;
+    progress.finish();
+    file.flush()?;
+
+    result.map(|()| resumed)
 }
 
 #[cfg(test)]
@@ -141,6 +466,455 @@ mod tests {
     use sha2::Sha256;
     use url::Url;
 
+    #[tokio::test]
+    async fn rate_limited_download() {
+        use std::io::{Read, Write};
+        use std::time::Instant;
+
+        let data = (0..200 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/source.tar.gz", listener.local_addr().unwrap());
+        let body = data.clone();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        let limiter = BandwidthLimiter::parse("100K").unwrap();
+        let response = reqwest::get(url).await.unwrap();
+        let mut downloaded = Vec::new();
+        let start = Instant::now();
+        write_chunks(
+            response.bytes_stream(),
+            &mut downloaded,
+            Some(&limiter),
+            &Progress::hidden(),
+        )
+        .await
+        .unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(downloaded, data);
+        // 200 KiB at 100 KiB/s, minus the initial burst of 25 KiB
+        assert!(elapsed >= Duration::from_millis(1600), "{elapsed:?}");
+    }
+
+    /// Serve `connections` HTTP connections on a local port. `respond` gets the index of the
+    /// connection and the (lowercase) request head, and returns the raw response. Joining the
+    /// returned handle yields the request heads.
+    fn serve(
+        connections: usize,
+        respond: impl Fn(usize, &str) -> Vec<u8> + Send + 'static,
+    ) -> (Url, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/source.tar.gz", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for index in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                stream.write_all(&respond(index, &request)).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (Url::parse(&url).unwrap(), handle)
+    }
+
+    fn response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response =
+            format!("HTTP/1.1 {status}\r\nConnection: close\r\n{headers}\r\n").into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// The start of the requested range, if the request has a `Range` header
+    fn range_start(request: &str) -> Option<usize> {
+        let range = request.split("range: bytes=").nth(1)?;
+        range.split('-').next()?.parse().ok()
+    }
+
+    /// Respond to range requests with the rest of `data`, and with all of it otherwise
+    fn serve_range(data: &[u8], request: &str) -> Vec<u8> {
+        match range_start(request) {
+            Some(start) => response(
+                "206 Partial Content",
+                &format!(
+                    "Content-Range: bytes {start}-{}/{}\r\nContent-Length: {}\r\n",
+                    data.len() - 1,
+                    data.len(),
+                    data.len() - start
+                ),
+                &data[start..],
+            ),
+            None => response(
+                "200 OK",
+                &format!("Content-Length: {}\r\n", data.len()),
+                data,
+            ),
+        }
+    }
+
+    fn test_data() -> (Vec<u8>, Checksum) {
+        let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let checksum = Checksum::Sha256(<Sha256 as sha2::Digest>::digest(&data));
+        (data, checksum)
+    }
+
+    const TEST_POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 2,
+        initial_backoff: Duration::from_millis(10),
+    };
+
+    #[tokio::test]
+    async fn interrupted_download_is_resumed() {
+        let (data, checksum) = test_data();
+        let body = data.clone();
+        let (url, server) = serve(2, move |index, request| {
+            if index == 0 {
+                // announce the full length, but close the connection in the middle
+                response(
+                    "200 OK",
+                    &format!("Content-Length: {}\r\n", body.len()),
+                    &body[..20_000],
+                )
+            } else {
+                serve_range(&body, request)
+            }
+        });
+
+        let cache = tempfile::tempdir().unwrap();
+        let partial = cache::partial_file(cache.path(), "source.tar.gz");
+        download_with_retries(
+            &url,
+            &SourceCredentials::default(),
+            &partial,
+            &checksum,
+            TEST_POLICY,
+            &tool_configuration::Configuration::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&partial).unwrap(), data);
+
+        let requests = server.join().unwrap();
+        assert_eq!(range_start(&requests[0]), None);
+        assert!(range_start(&requests[1]).is_some_and(|start| start > 0));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (data, checksum) = test_data();
+        let body = data.clone();
+        let (url, server) = serve(3, move |index, request| match index {
+            0 => response("503 Service Unavailable", "Content-Length: 0\r\n", b""),
+            1 => response("502 Bad Gateway", "Content-Length: 0\r\n", b""),
+            _ => serve_range(&body, request),
+        });
+
+        let cache = tempfile::tempdir().unwrap();
+        let partial = cache::partial_file(cache.path(), "source.tar.gz");
+        download_with_retries(
+            &url,
+            &SourceCredentials::default(),
+            &partial,
+            &checksum,
+            TEST_POLICY,
+            &tool_configuration::Configuration::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&partial).unwrap(), data);
+        assert_eq!(server.join().unwrap().len(), 3);
+
+        assert_eq!(TEST_POLICY.backoff(1), Duration::from_millis(10));
+        assert_eq!(TEST_POLICY.backoff(3), Duration::from_millis(40));
+        assert_eq!(TEST_POLICY.backoff(40), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (_, checksum) = test_data();
+        let (url, server) = serve(1, |_, _| {
+            response("404 Not Found", "Content-Length: 0\r\n", b"")
+        });
+
+        let cache = tempfile::tempdir().unwrap();
+        let partial = cache::partial_file(cache.path(), "source.tar.gz");
+        let err = download_with_retries(
+            &url,
+            &SourceCredentials::default(),
+            &partial,
+            &checksum,
+            TEST_POLICY,
+            &tool_configuration::Configuration::default(),
+        )
+        .await
+        .unwrap_err();
+        match err {
+            SourceError::Url(e) => assert_eq!(e.status(), Some(reqwest::StatusCode::NOT_FOUND)),
+            err => panic!("unexpected error: {err}"),
+        }
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupt_partial_download_is_restarted() {
+        let (data, checksum) = test_data();
+        let body = data.clone();
+        let (url, server) = serve(2, move |_, request| serve_range(&body, request));
+
+        // a partial download from an earlier run that does not match the file on the server
+        let cache = tempfile::tempdir().unwrap();
+        let partial = cache::partial_file(cache.path(), "source.tar.gz");
+        fs::write(&partial, vec![0u8; 20_000]).unwrap();
+
+        download_with_retries(
+            &url,
+            &SourceCredentials::default(),
+            &partial,
+            &checksum,
+            RetryPolicy {
+                max_retries: 0,
+                ..TEST_POLICY
+            },
+            &tool_configuration::Configuration::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&partial).unwrap(), data);
+
+        let requests = server.join().unwrap();
+        assert_eq!(range_start(&requests[0]), Some(20_000));
+        assert_eq!(range_start(&requests[1]), None);
+    }
+
+    fn mirrored_source(urls: &[&Url], checksum: &Checksum) -> UrlSource {
+        let Checksum::Sha256(sha256) = checksum else {
+            unreachable!("the test data has a sha256 checksum")
+        };
+        let urls = urls.iter().map(|url| format!("\"{url}\"")).join(", ");
+        serde_yaml::from_str(&format!(
+            "{{url: [{urls}], sha256: {}}}",
+            hex::encode(sha256)
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn credentials_are_not_forwarded_on_redirects() {
+        let (data, checksum) = test_data();
+        let Checksum::Sha256(sha256) = &checksum else {
+            unreachable!("the test data has a sha256 checksum")
+        };
+        let body = data.clone();
+        let (storage, storage_server) = serve(1, move |_, request| serve_range(&body, request));
+        let (origin, origin_server) = serve(1, move |_, _| {
+            response(
+                "302 Found",
+                &format!("Location: {storage}?signature=1\r\nContent-Length: 0\r\n"),
+                b"",
+            )
+        });
+
+        std::env::set_var("URL_SOURCE_TEST_TOKEN", "secret");
+        let source: UrlSource = serde_yaml::from_str(&format!(
+            "{{url: \"{origin}\", sha256: {}, headers: {{Authorization: \"Bearer ${{URL_SOURCE_TEST_TOKEN}}\"}}}}",
+            hex::encode(sha256)
+        ))
+        .unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let path = url_src(&source, cache.path(), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(fs::read(path).unwrap(), data);
+
+        let origin_requests = origin_server.join().unwrap();
+        assert!(origin_requests[0].contains("authorization: bearer secret"));
+        let storage_requests = storage_server.join().unwrap();
+        assert!(storage_requests[0].contains("?signature=1"));
+        assert!(!storage_requests[0].contains("authorization"));
+    }
+
+    #[tokio::test]
+    async fn refused_downloads_are_reported() {
+        let (_, checksum) = test_data();
+        let (url, _) = serve(1, |_, _| {
+            response("401 Unauthorized", "Content-Length: 0\r\n", b"")
+        });
+        let source = mirrored_source(&[&url], &checksum);
+
+        let cache = tempfile::tempdir().unwrap();
+        let configuration = tool_configuration::Configuration {
+            download_retries: 0,
+            ..Default::default()
+        };
+        match url_src(&source, cache.path(), &configuration).await {
+            Err(SourceError::Unauthorized { status, .. }) => {
+                assert_eq!(status, StatusCode::UNAUTHORIZED)
+            }
+            other => panic!("expected an authentication error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn mirrors_are_tried_in_order() {
+        let (data, checksum) = test_data();
+        let body = data.clone();
+        let (missing, _) = serve(1, |_, _| {
+            response("404 Not Found", "Content-Length: 0\r\n", b"")
+        });
+        let (corrupt, _) = serve(1, |_, _| {
+            response("200 OK", "Content-Length: 5\r\n", b"wrong")
+        });
+        let (working, _) = serve(1, move |_, request| serve_range(&body, request));
+        let source = mirrored_source(&[&missing, &corrupt, &working], &checksum);
+
+        let cache = tempfile::tempdir().unwrap();
+        let configuration = tool_configuration::Configuration {
+            download_retries: 0,
+            ..Default::default()
+        };
+        let path = url_src(&source, cache.path(), &configuration)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        // the cached file is found by its checksum, even if the first mirror is back
+        let source = mirrored_source(&[&working, &missing], &checksum);
+        assert_eq!(
+            url_src(&source, cache.path(), &configuration)
+                .await
+                .unwrap(),
+            path
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_mirrors_are_listed() {
+        let (_, checksum) = test_data();
+        let (missing, _) = serve(1, |_, _| {
+            response("404 Not Found", "Content-Length: 0\r\n", b"")
+        });
+        let (corrupt, _) = serve(1, |_, _| {
+            response("200 OK", "Content-Length: 5\r\n", b"wrong")
+        });
+        let source = mirrored_source(&[&missing, &corrupt], &checksum);
+
+        let cache = tempfile::tempdir().unwrap();
+        let configuration = tool_configuration::Configuration {
+            download_retries: 0,
+            ..Default::default()
+        };
+        match url_src(&source, cache.path(), &configuration).await {
+            Err(SourceError::AllMirrorsFailed(attempts)) => {
+                let attempts = attempts.lines().collect::<Vec<_>>();
+                assert_eq!(attempts.len(), 2);
+                assert!(attempts[0].contains(missing.as_str()) && attempts[0].contains("404"));
+                assert!(attempts[1].starts_with(&format!(" - {corrupt}: ")));
+                assert!(attempts[1].contains("checksum"));
+            }
+            other => panic!("expected an error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn local_files_are_copied_to_the_cache() {
+        let (data, checksum) = test_data();
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("foo-1.0.tar.gz");
+        fs::write(&local, &data).unwrap();
+        let url = Url::from_file_path(&local).unwrap();
+        let source = mirrored_source(&[&url], &checksum);
+
+        let cache = tempfile::tempdir().unwrap();
+        let path = url_src(&source, cache.path(), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(path.parent(), Some(cache.path()));
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        // a missing file is reported with its path
+        let missing = Url::from_file_path(dir.path().join("missing.tar.gz")).unwrap();
+        let source = mirrored_source(&[&missing], &checksum);
+        match url_src(&source, cache.path(), &Default::default()).await {
+            Err(SourceError::FileNotFound(path)) => {
+                assert_eq!(path, dir.path().join("missing.tar.gz"))
+            }
+            other => panic!("expected a missing file, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_cache_file_is_downloaded_again() {
+        let (data, checksum) = test_data();
+        let body = data.clone();
+        let (url, server) = serve(1, move |_, request| serve_range(&body, request));
+        let source = mirrored_source(&[&url], &checksum);
+
+        // a truncated download of an earlier run
+        let cache = tempfile::tempdir().unwrap();
+        let cache_name = cache
+            .path()
+            .join(cache_name_from_url(&url, &checksum).unwrap());
+        fs::write(&cache_name, &data[..1000]).unwrap();
+
+        let path = url_src(&source, cache.path(), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(path, cache_name);
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn md5_and_blake2_checksums() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), b"rattler-build").unwrap();
+
+        let md5 = <rattler_digest::Md5 as sha2::Digest>::digest(b"rattler-build");
+        let blake2 = <Blake2b256 as sha2::Digest>::digest(b"rattler-build");
+        assert!(validate_checksum(file.path(), &Checksum::Md5(md5)).is_ok());
+        assert!(validate_checksum(file.path(), &Checksum::Blake2(blake2)).is_ok());
+
+        let wrong = <Blake2b256 as sha2::Digest>::digest(b"something else");
+        match validate_checksum(file.path(), &Checksum::Blake2(wrong)) {
+            Err(e @ SourceError::ValidationFailed { .. }) => {
+                let message = e.to_string();
+                assert!(message.contains(&format!("expected blake2 {}", hex::encode(wrong))));
+                assert!(message.contains(&format!("got {}", hex::encode(blake2))));
+                assert!(message.contains("(13 bytes)"));
+            }
+            other => panic!("expected a validation error, got {other:?}"),
+        }
+    }
+
     #[test]
     fn test_split_filename() {
         let test_cases = vec![
@@ -0,0 +1,268 @@
+//! Keeps track of the outcome of every output that is built in a single invocation, so that a
+//! failure in one variant does not have to abort the remaining builds.
+
+use std::{collections::HashSet, path::PathBuf};
+
+use crate::recipe::parser::{Dependency, Recipe};
+
+/// The outcome of building a single output
+#[derive(Debug, Clone)]
+pub enum BuildStatus {
+    /// The output was built successfully and the packages were written to the given paths
+    Success {
+        /// The paths to the package files (one per package format)
+        packages: Vec<PathBuf>,
+    },
+    /// The build of the output failed
+    Failed {
+        /// The error message of the failure
+        error: String,
+        /// The build directory that was kept around for inspection
+        build_dir: PathBuf,
+    },
+    /// The output was skipped (e.g. because of `build.skip`)
+    Skipped,
+    /// The output was not built because its package already exists (`--skip-existing`)
+    Existing {
+        /// The URL of the existing package
+        url: String,
+    },
+    /// The output was not attempted because some of its dependencies failed to build
+    Blocked {
+        /// The names of the outputs that failed and that this output depends on
+        failed_dependencies: Vec<String>,
+    },
+}
+
+impl BuildStatus {
+    /// Returns true if the output was not built because of a failure (either its own or one
+    /// of its dependencies)
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            BuildStatus::Failed { .. } | BuildStatus::Blocked { .. }
+        )
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            BuildStatus::Success { .. } => "success",
+            BuildStatus::Failed { .. } => "failed",
+            BuildStatus::Skipped => "skipped",
+            BuildStatus::Existing { .. } => "exists",
+            BuildStatus::Blocked { .. } => "blocked",
+        }
+    }
+}
+
+/// A single entry of the [`BuildSummary`]
+#[derive(Debug, Clone)]
+pub struct BuildSummaryEntry {
+    /// The (normalized) package name of the output
+    pub name: String,
+    /// The `name-version-build_string` identifier of the output
+    pub identifier: String,
+    /// The outcome of the build
+    pub status: BuildStatus,
+}
+
+/// The aggregated outcome of all outputs built in one session
+#[derive(Debug, Clone, Default)]
+pub struct BuildSummary {
+    entries: Vec<BuildSummaryEntry>,
+}
+
+impl BuildSummary {
+    /// Create an empty summary
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the outcome of an output
+    pub fn record(
+        &mut self,
+        name: impl Into<String>,
+        identifier: impl Into<String>,
+        status: BuildStatus,
+    ) {
+        self.entries.push(BuildSummaryEntry {
+            name: name.into(),
+            identifier: identifier.into(),
+            status,
+        });
+    }
+
+    /// All recorded entries, in the order they were recorded
+    pub fn entries(&self) -> &[BuildSummaryEntry] {
+        &self.entries
+    }
+
+    /// Returns the subset of `dependencies` that failed (or were blocked) in this session
+    pub fn failed_dependencies<'a>(
+        &self,
+        dependencies: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<String> {
+        let failed = self
+            .entries
+            .iter()
+            .filter(|entry| entry.status.is_failure())
+            .map(|entry| entry.name.as_str())
+            .collect::<HashSet<_>>();
+
+        let mut result = dependencies
+            .into_iter()
+            .filter(|dep| failed.contains(dep))
+            .map(|dep| dep.to_string())
+            .collect::<Vec<_>>();
+        result.sort();
+        result.dedup();
+        result
+    }
+
+    /// Returns true if any of the recorded outputs failed or was blocked
+    pub fn has_failures(&self) -> bool {
+        self.entries.iter().any(|entry| entry.status.is_failure())
+    }
+
+    /// The number of failed or blocked outputs
+    pub fn failure_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.status.is_failure())
+            .count()
+    }
+
+    /// Render the summary as a table
+    pub fn to_table(&self) -> comfy_table::Table {
+        let mut table = comfy_table::Table::new();
+        table
+            .load_preset(comfy_table::presets::UTF8_FULL_CONDENSED)
+            .apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS)
+            .set_header(vec!["Package", "Status", "Details"]);
+
+        for entry in &self.entries {
+            let details = match &entry.status {
+                BuildStatus::Success { packages } => packages
+                    .iter()
+                    .map(|package| package.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                BuildStatus::Failed { error, build_dir } => {
+                    format!("{}\nsee {}", error, build_dir.display())
+                }
+                BuildStatus::Skipped => String::new(),
+                BuildStatus::Existing { url } => url.clone(),
+                BuildStatus::Blocked {
+                    failed_dependencies,
+                } => format!("depends on {}", failed_dependencies.join(", ")),
+            };
+            table.add_row(vec![
+                entry.identifier.clone(),
+                entry.status.label().to_string(),
+                details,
+            ]);
+        }
+        table
+    }
+}
+
+/// The names of all packages that the given recipe depends on (at build or run time, including
+/// `pin_subpackage` dependencies).
+pub fn dependency_names(recipe: &Recipe) -> HashSet<String> {
+    let requirements = recipe.requirements();
+    requirements
+        .all()
+        .chain(requirements.run_exports().all())
+        .filter_map(|dep| match dep {
+            Dependency::Spec(spec) => spec
+                .name
+                .as_ref()
+                .map(|name| name.as_normalized().to_string()),
+            Dependency::PinSubpackage(pin) => {
+                Some(pin.pin_value().name.as_normalized().to_string())
+            }
+            Dependency::PinCompatible(pin) => {
+                Some(pin.pin_value().name.as_normalized().to_string())
+            }
+            Dependency::Compiler(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rattler_conda_types::Platform;
+
+    use super::{dependency_names, BuildStatus, BuildSummary};
+    use crate::{
+        recipe::parser::{find_outputs_from_src, Recipe},
+        selectors::SelectorConfig,
+    };
+
+    #[test]
+    fn middle_output_failure_blocks_dependents() {
+        let test_data_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let recipe_text =
+            std::fs::read_to_string(test_data_dir.join("recipes/continue-on-failure/recipe.yaml"))
+                .unwrap();
+        let selector_config = SelectorConfig {
+            target_platform: Platform::Linux64,
+            build_platform: Platform::Linux64,
+            variant: Default::default(),
+            hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
+        };
+        let outputs = find_outputs_from_src(&recipe_text).unwrap();
+        let recipes = outputs
+            .iter()
+            .map(|node| Recipe::from_node(node, selector_config.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(recipes.len(), 3);
+
+        let mut summary = BuildSummary::new();
+        for recipe in &recipes {
+            let name = recipe.package().name().as_normalized().to_string();
+            let dependencies = dependency_names(recipe);
+            let failed = summary.failed_dependencies(dependencies.iter().map(String::as_str));
+            let status = if !failed.is_empty() {
+                BuildStatus::Blocked {
+                    failed_dependencies: failed,
+                }
+            } else if name == "cof-b" {
+                BuildStatus::Failed {
+                    error: "Build failed".to_string(),
+                    build_dir: PathBuf::from("bld/rattler-build_cof-b"),
+                }
+            } else {
+                BuildStatus::Success {
+                    packages: vec![PathBuf::from(format!("{name}-0.1.0-0.tar.bz2"))],
+                }
+            };
+            summary.record(name.clone(), name, status);
+        }
+
+        let statuses = summary
+            .entries()
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.status.label()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                ("cof-a", "success"),
+                ("cof-b", "failed"),
+                ("cof-c", "blocked")
+            ]
+        );
+        assert!(summary.has_failures());
+        assert_eq!(summary.failure_count(), 2);
+
+        let table = summary.to_table().to_string();
+        assert!(table.contains("depends on cof-b"));
+        assert!(table.contains("bld/rattler-build_cof-b"));
+    }
+}
@@ -9,7 +9,7 @@
 
 use std::{
     fs::{self},
-    io::{Read, Write},
+    io::Write,
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -18,16 +18,22 @@ use dunce::canonicalize;
 use indicatif::MultiProgress;
 use rattler::package_cache::CacheKey;
 use rattler_conda_types::{
-    package::{ArchiveIdentifier, ArchiveType, PathsJson},
-    MatchSpec, Platform,
+    package::{ArchiveIdentifier, PathsJson},
+    MatchSpec, Platform, RepoDataRecord,
 };
 use rattler_networking::AuthenticatedClient;
 use rattler_shell::{
     activation::{ActivationError, ActivationVariables, Activator},
-    shell::{Shell, ShellEnum, ShellScript},
+    shell::{Shell, ShellScript},
 };
 
-use crate::{env_vars, index, render::solver::create_environment, tool_configuration};
+use crate::{
+    build::ScriptFlavor,
+    env_vars, index,
+    package_inspect::{InspectError, PackageInspector},
+    render::solver::create_environment,
+    tool_configuration,
+};
 
 #[allow(missing_docs)]
 #[derive(thiserror::Error, Debug)]
@@ -47,6 +53,12 @@ pub enum TestError {
     #[error("failed to run test")]
     TestFailed,
 
+    #[error("The test command `{command}` failed with exit code {}", .code.map_or("unknown".to_string(), |code| code.to_string()))]
+    CommandFailed { command: String, code: Option<i32> },
+
+    #[error("Failed to copy the test files: {0}")]
+    CopyTestFiles(#[from] fs_extra::error::Error),
+
     #[error("Failed to read package: {0}")]
     PackageRead(#[from] std::io::Error),
 
@@ -70,6 +82,12 @@ pub enum TestError {
 
     #[error("Archive type not supported")]
     ArchiveTypeNotSupported,
+
+    #[error("Failed to inspect package: {0}")]
+    PackageInspect(#[from] InspectError),
+
+    #[error("The test environment violates the run constraints of the package: {0}")]
+    RunConstraintViolated(String),
 }
 
 #[derive(Debug)]
@@ -78,9 +96,16 @@ enum Tests {
     Python(PathBuf),
 }
 
+/// The environment variable with the file to which bash writes the command that failed
+const FAILED_COMMAND_FILE: &str = "RATTLER_BUILD_FAILED_COMMAND";
+
+/// Run `cmd` in the activated `environment`, with the interpreter of the script `flavor` (see
+/// [`ScriptFlavor::interpreter`]). If it fails, the error contains the command that failed (with
+/// bash) or the `description` of `cmd`.
 fn run_in_environment(
-    shell: ShellEnum,
+    flavor: ScriptFlavor,
     cmd: String,
+    description: &str,
     cwd: &Path,
     environment: &Path,
 ) -> Result<(), TestError> {
@@ -97,6 +122,7 @@ fn run_in_environment(
         path_modification_behavior: Default::default(),
     };
 
+    let shell = flavor.shell();
     let activator = Activator::from_path(environment, shell.clone(), Platform::current())?;
     let script = activator.activation(av)?;
 
@@ -107,7 +133,7 @@ fn run_in_environment(
 
     let mut additional_script = ShellScript::new(shell.clone(), Platform::current());
 
-    let os_vars = env_vars::os_vars(environment, &Platform::current());
+    let os_vars = env_vars::os_vars(environment, &Platform::current(), &Platform::current());
     for (key, val) in os_vars {
         if key == "PATH" {
             continue;
@@ -119,28 +145,35 @@ fn run_in_environment(
 
     writeln!(tmpfile, "{}", additional_script.contents)?;
     writeln!(tmpfile, "{}", script.script)?;
-    writeln!(tmpfile, "set -x")?;
+    if flavor == ScriptFlavor::Bash {
+        writeln!(
+            tmpfile,
+            "trap 'printf \"%s\" \"$BASH_COMMAND\" > \"${FAILED_COMMAND_FILE}\"' ERR"
+        )?;
+        writeln!(tmpfile, "set -x")?;
+    }
     writeln!(tmpfile, "{}", cmd)?;
 
+    let failed_command = tempfile::NamedTempFile::new()?;
     let tmpfile_path = tmpfile.into_temp_path();
-    let executable = shell.executable();
-    let status = match shell {
-        ShellEnum::Bash(_) => std::process::Command::new(executable)
-            .arg("-e")
-            .arg(&tmpfile_path)
-            .current_dir(cwd)
-            .status()?,
-        ShellEnum::CmdExe(_) => std::process::Command::new(executable)
-            .arg("/d")
-            .arg("/c")
-            .arg(&tmpfile_path)
-            .current_dir(cwd)
-            .status()?,
-        _ => todo!("No shells implemented beyond cmd.exe and bash"),
-    };
+    let (interpreter, args) = flavor.interpreter(&Platform::current(), &tmpfile_path);
+    let status = std::process::Command::new(interpreter)
+        .args(args)
+        .env(FAILED_COMMAND_FILE, failed_command.path())
+        .current_dir(cwd)
+        .status()?;
 
     if !status.success() {
-        return Err(TestError::TestFailed);
+        let command = fs::read_to_string(failed_command.path()).unwrap_or_default();
+        let command = if command.trim().is_empty() {
+            description.to_string()
+        } else {
+            command.trim().to_string()
+        };
+        return Err(TestError::CommandFailed {
+            command,
+            code: status.code(),
+        });
     }
 
     Ok(())
@@ -148,19 +181,24 @@ fn run_in_environment(
 
 impl Tests {
     fn run(&self, environment: &Path, cwd: &Path) -> Result<(), TestError> {
-        let default_shell = ShellEnum::default();
+        // the tests run on the current platform, like a build script that is built for it
+        let platform_flavor =
+            ScriptFlavor::from_platforms(&Platform::current(), &Platform::current());
 
         match self {
             Tests::Commands(path) => {
                 let contents = fs::read_to_string(path)?;
-                let is_path_ext =
-                    |ext: &str| path.extension().map(|s| s.eq(ext)).unwrap_or_default();
-                if Platform::current().is_windows() && is_path_ext("bat") {
-                    tracing::info!("Testing commands:");
-                    run_in_environment(default_shell, contents, cwd, environment)
-                } else if Platform::current().is_unix() && is_path_ext("sh") {
+                let flavor = path.extension().and_then(|extension| {
+                    ScriptFlavor::from_extension(&extension.to_string_lossy())
+                });
+                let description = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                // the commands of the other platforms are skipped
+                if flavor == Some(platform_flavor) {
                     tracing::info!("Testing commands:");
-                    run_in_environment(default_shell, contents, cwd, environment)
+                    run_in_environment(platform_flavor, contents, &description, cwd, environment)
                 } else {
                     Ok(())
                 }
@@ -168,12 +206,8 @@ impl Tests {
             Tests::Python(path) => {
                 let imports = fs::read_to_string(path)?;
                 tracing::info!("Testing Python imports:\n{imports}");
-                run_in_environment(
-                    default_shell,
-                    format!("python {}", path.to_string_lossy()),
-                    cwd,
-                    environment,
-                )
+                let command = format!("python {}", path.to_string_lossy());
+                run_in_environment(platform_flavor, command.clone(), &command, cwd, environment)
             }
         }
     }
@@ -210,50 +244,6 @@ async fn tests_from_folder(pkg: &Path) -> Result<(PathBuf, Vec<Tests>), TestErro
     Ok((test_folder, tests))
 }
 
-fn file_from_tar_bz2(archive_path: &Path, find_path: &Path) -> Result<String, std::io::Error> {
-    let reader = std::fs::File::open(archive_path)?;
-    let mut archive = rattler_package_streaming::read::stream_tar_bz2(reader);
-
-    for entry in archive.entries()? {
-        let mut entry = entry?;
-        let path = entry.path()?;
-        if path == find_path {
-            let mut contents = String::new();
-            entry.read_to_string(&mut contents)?;
-            return Ok(contents);
-        }
-    }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::NotFound,
-        format!("{:?} not found in {:?}", find_path, archive_path),
-    ))
-}
-
-fn file_from_conda(archive_path: &Path, find_path: &Path) -> Result<String, std::io::Error> {
-    let reader = std::fs::File::open(archive_path)?;
-
-    let mut archive = if find_path.starts_with("info") {
-        rattler_package_streaming::seek::stream_conda_info(reader)
-            .expect("Could not open conda file")
-    } else {
-        todo!("Not implemented yet");
-    };
-
-    for entry in archive.entries()? {
-        let mut entry = entry?;
-        let path = entry.path()?;
-        if path == find_path {
-            let mut contents = String::new();
-            entry.read_to_string(&mut contents)?;
-            return Ok(contents);
-        }
-    }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::NotFound,
-        format!("{:?} not found in {:?}", find_path, archive_path),
-    ))
-}
-
 /// The configuration for a test
 #[derive(Default, Debug)]
 pub struct TestConfiguration {
@@ -289,12 +279,35 @@ pub struct TestConfiguration {
 /// # Returns
 ///
 /// * `Ok(())` if the test was successful
-/// * `Err(TestError::TestFailed)` if the test failed
+/// * `Err(TestError::CommandFailed)` if a test command failed
 pub async fn run_test(package_file: &Path, config: &TestConfiguration) -> Result<(), TestError> {
+    let package = PackageInspector::open(package_file).map_err(|e| match e {
+        InspectError::UnknownArchiveType(_) => TestError::ArchiveTypeNotSupported,
+        e => TestError::PackageInspect(e),
+    })?;
+    if !has_tests(package.info_files()?.keys()) {
+        tracing::info!(
+            "No tests found in {} (there is no info/test/run_test.*)",
+            package_file.display()
+        );
+        return Ok(());
+    }
+
     let tmp_repo = tempfile::tempdir()?;
     let target_platform = config.target_platform.unwrap_or_else(Platform::current);
+    // the test environment is created for the target platform, which noarch packages do not have
+    let environment_platform = if target_platform == Platform::NoArch {
+        Platform::current()
+    } else {
+        target_platform
+    };
 
-    let subdir = tmp_repo.path().join(target_platform.to_string());
+    // the package is indexed in its own subdir (e.g. `noarch`)
+    let subdir = package
+        .index_json()?
+        .subdir
+        .unwrap_or_else(|| target_platform.to_string());
+    let subdir = tmp_repo.path().join(subdir);
     std::fs::create_dir_all(&subdir)?;
 
     std::fs::copy(
@@ -306,33 +319,21 @@ pub async fn run_test(package_file: &Path, config: &TestConfiguration) -> Result
         ),
     )?;
 
-    let archive_type =
-        ArchiveType::try_from(package_file).ok_or(TestError::ArchiveTypeNotSupported)?;
-    let test_dep_json = PathBuf::from("info/test/test_time_dependencies.json");
-    let test_dependencies = match archive_type {
-        ArchiveType::TarBz2 => file_from_tar_bz2(package_file, &test_dep_json),
-        ArchiveType::Conda => file_from_conda(package_file, &test_dep_json),
-    };
-
-    let mut dependencies: Vec<MatchSpec> = match test_dependencies {
-        Ok(contents) => {
-            let test_deps: Vec<String> = serde_json::from_str(&contents)?;
-            test_deps
-                .iter()
-                .map(|s| MatchSpec::from_str(s))
-                .collect::<Result<Vec<_>, _>>()?
-        }
-        Err(error) => {
-            if error.kind() == std::io::ErrorKind::NotFound {
-                Vec::new()
-            } else {
-                return Err(TestError::TestFailed);
+    let mut dependencies: Vec<MatchSpec> =
+        match package.info_file("info/test/test_time_dependencies.json") {
+            Ok(contents) => {
+                let test_deps: Vec<String> = serde_json::from_slice(contents)?;
+                test_deps
+                    .iter()
+                    .map(|s| MatchSpec::from_str(s))
+                    .collect::<Result<Vec<_>, _>>()?
             }
-        }
-    };
+            Err(InspectError::FileNotFound(_)) => Vec::new(),
+            Err(_) => return Err(TestError::TestFailed),
+        };
 
-    // index the temporary channel
-    index::index(tmp_repo.path(), Some(&target_platform))?;
+    // index the temporary channel (the subdir of the package and the one of the environment)
+    index::index(tmp_repo.path(), Some(&environment_platform))?;
 
     let cache_dir = rattler::default_cache_dir()?;
 
@@ -363,15 +364,16 @@ pub async fn run_test(package_file: &Path, config: &TestConfiguration) -> Result
 
     tracing::info!("Creating test environment in {:?}", prefix);
 
-    create_environment(
+    let installed = create_environment(
         &dependencies,
-        &Platform::current(),
+        &environment_platform,
         &prefix,
         &config.channels,
         &global_configuration,
     )
     .await
     .map_err(TestError::TestEnvironmentSetup)?;
+    check_run_constraints(&package.run_constraints()?, &installed)?;
 
     let cache_key = CacheKey::from(pkg);
     let dir = cache_dir.join("pkgs").join(cache_key.to_string());
@@ -379,8 +381,19 @@ pub async fn run_test(package_file: &Path, config: &TestConfiguration) -> Result
     tracing::info!("Collecting tests from {:?}", dir);
     let (test_folder, tests) = tests_from_folder(&dir).await?;
 
+    // the tests run in a copy of the test files (including the `files` and `source_files` of the
+    // recipe), so that they do not modify the package cache
+    let test_work_dir = tempfile::tempdir()?;
+    if test_folder.exists() {
+        fs_extra::dir::copy(
+            &test_folder,
+            test_work_dir.path(),
+            &fs_extra::dir::CopyOptions::new().content_only(true),
+        )?;
+    }
+
     for test in tests {
-        test.run(&prefix, &test_folder)?;
+        test.run(&prefix, test_work_dir.path())?;
     }
 
     tracing::info!(
@@ -388,243 +401,431 @@ pub async fn run_test(package_file: &Path, config: &TestConfiguration) -> Result
         console::style(console::Emoji("✔", "")).green()
     );
 
-    fs::remove_dir_all(prefix)?;
+    if !config.keep_test_prefix {
+        fs::remove_dir_all(prefix)?;
+    }
 
     Ok(())
 }
 
-/// <!-- TODO: better desc. --> Run package content tests.
+/// Whether the info files of a package contain a test script
+fn has_tests<'a>(info_files: impl IntoIterator<Item = &'a PathBuf>) -> bool {
+    info_files.into_iter().any(|path| {
+        path.parent() == Some(Path::new("info/test"))
+            && path.file_stem().map_or(false, |stem| stem == "run_test")
+    })
+}
+
+/// Check that the packages of the test environment satisfy the `constrains` of the tested
+/// package. Constraints on packages that are not installed are fulfilled.
+fn check_run_constraints(
+    constraints: &[MatchSpec],
+    installed: &[RepoDataRecord],
+) -> Result<(), TestError> {
+    let violations = constraints
+        .iter()
+        .flat_map(|spec| {
+            installed
+                .iter()
+                .filter(|record| spec.name.as_ref() == Some(&record.package_record.name))
+                .filter(|record| !spec.matches(&record.package_record))
+                .map(move |record| {
+                    format!(
+                        "{} {} {} does not match `{}`",
+                        record.package_record.name.as_normalized(),
+                        record.package_record.version,
+                        record.package_record.build,
+                        spec
+                    )
+                })
+        })
+        .collect::<Vec<_>>();
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(TestError::RunConstraintViolated(violations.join(", ")))
+    }
+}
+
+/// Run the package content tests: every entry must match a path of the package. The `bins`,
+/// `libs` and `includes` are names that are expanded to the paths of the `target_platform`
+/// (e.g. `libs: [foo]` matches `lib/libfoo.so.1.2` on Linux and `Library/bin/foo.dll` on Windows).
 /// # Arguments
 ///
 /// * `package_content` : The package content test format struct ref.
+/// * `package` : The path of the built package, used to check its `info/link.json`.
 ///
 /// # Returns
 ///
 /// * `Ok(())` if the test was successful
-/// * `Err(TestError::TestFailed)` if the test failed
+/// * `Err(TestError::PackageContentTestFailed)` with the entries that matched nothing
 pub async fn run_package_content_tests(
     package_content: &crate::recipe::parser::PackageContent,
     paths_json: PathsJson,
     target_platform: &Platform,
+    package: &Path,
 ) -> Result<(), TestError> {
-    // files globset
-    let mut file_globs = vec![];
-    for file_path in package_content.files() {
-        file_globs.push((file_path, globset::Glob::new(file_path)?.compile_matcher()));
+    if let Some(expected) = package_content.link_json() {
+        let inspector = PackageInspector::open(package)?;
+        if expected {
+            inspector.check_link_json()?;
+            if inspector.link_json()?.is_none() {
+                return Err(TestError::PackageContentTestFailedStr(
+                    "Expected the package to contain info/link.json",
+                ));
+            }
+        } else if inspector.link_json()?.is_some() {
+            return Err(TestError::PackageContentTestFailedStr(
+                "Expected the package to not contain info/link.json",
+            ));
+        }
     }
 
-    // site packages
-    let site_package_path = globset::Glob::new("**/site-packages/**")?.compile_matcher();
-    let mut site_packages = vec![];
-    for sp in package_content.site_packages() {
-        let mut s = String::new();
-        s.extend(sp.split('.').flat_map(|s| [s, "/"]));
-        s.push_str("/__init__.py");
-        site_packages.push((sp, s));
-    }
+    let paths = paths_json
+        .paths
+        .iter()
+        .map(|entry| entry.relative_path.as_path())
+        .collect::<Vec<_>>();
+    check_paths(package_content, &paths, target_platform)
+}
 
-    // binaries
-    let binary_dir = if target_platform.is_windows() {
-        "**/Library/bin/**"
-    } else {
-        "**/bin/**"
-    };
-    let binary_dir = globset::Glob::new(binary_dir)?.compile_matcher();
-    let mut binary_names = package_content
-        .bins()
+/// Check that the `paths` of a package fulfill the files, site packages, binaries, libraries
+/// and includes of the `package_content` tests
+fn check_paths(
+    package_content: &crate::recipe::parser::PackageContent,
+    paths: &[&Path],
+    target_platform: &Platform,
+) -> Result<(), TestError> {
+    let checks = content_checks(package_content, target_platform)?;
+    let failures = checks
         .iter()
-        .map(|bin| {
-            if target_platform.is_windows() {
-                bin.to_owned() + ".exe"
-            } else {
-                bin.to_owned()
+        .filter(|check| !paths.iter().any(|path| check.globs.is_match(path)))
+        .map(|check| {
+            let nearby = nearby_paths(&check.patterns[0], paths, 3);
+            let mut failure = format!(
+                "{} `{}` not found in the package contents (patterns: {})",
+                check.kind,
+                check.entry,
+                check.patterns.join(", ")
+            );
+            if !nearby.is_empty() {
+                failure.push_str(&format!(", nearby paths: {}", nearby.join(", ")));
             }
+            failure
         })
         .collect::<Vec<_>>();
 
-    // libraries
-    let library_dir = if target_platform.is_windows() {
-        "Library"
+    if failures.is_empty() {
+        Ok(())
     } else {
-        "lib"
-    };
-    let mut libraries = vec![];
+        Err(TestError::PackageContentTestFailed(failures.join("\n")))
+    }
+}
+
+/// An entry of the package content tests, which is fulfilled if a path of the package matches
+/// one of its patterns
+struct ContentCheck<'a> {
+    kind: &'static str,
+    entry: &'a str,
+    patterns: Vec<String>,
+    globs: globset::GlobSet,
+}
+
+/// The checks of the `package_content` tests, with the patterns of the layout of the
+/// `target_platform` (noarch packages have the unix layout)
+fn content_checks<'a>(
+    package_content: &'a crate::recipe::parser::PackageContent,
+    target_platform: &Platform,
+) -> Result<Vec<ContentCheck<'a>>, TestError> {
+    let windows = target_platform.is_windows();
+    let mut checks = Vec::new();
+    let mut add =
+        |kind: &'static str, entry: &'a String, patterns: Vec<String>| -> Result<(), TestError> {
+            let mut builder = globset::GlobSetBuilder::new();
+            for pattern in &patterns {
+                builder.add(globset::Glob::new(pattern)?);
+            }
+            checks.push(ContentCheck {
+                kind,
+                entry,
+                patterns,
+                globs: builder.build()?,
+            });
+            Ok(())
+        };
+
+    for file in package_content.files() {
+        // a directory is found if it contains a file
+        add(
+            "file",
+            file,
+            vec![file.clone(), format!("{}/**", file.trim_end_matches('/'))],
+        )?;
+    }
+
+    for site_package in package_content.site_packages() {
+        let module = site_package.replace('.', "/");
+        add(
+            "site package",
+            site_package,
+            vec![
+                format!("**/site-packages/{module}/__init__.py"),
+                format!("**/site-packages/{module}.py"),
+            ],
+        )?;
+    }
+
+    for bin in package_content.bins() {
+        let patterns = if windows {
+            vec![
+                format!("Library/bin/{bin}.exe"),
+                format!("Library/bin/{bin}.bat"),
+                format!("Scripts/{bin}.exe"),
+            ]
+        } else {
+            vec![format!("bin/{bin}")]
+        };
+        add("binary", bin, patterns)?;
+    }
+
     for lib in package_content.libs() {
-        if target_platform.is_windows() {
-            libraries.push((
-                lib,
-                globset::Glob::new(format!("**/{library_dir}/lib/{lib}.dll").as_str())?
-                    .compile_matcher(),
-                globset::Glob::new(format!("**/{library_dir}/bin/{lib}.lib").as_str())?
-                    .compile_matcher(),
-            ));
+        let patterns = if windows {
+            vec![
+                format!("Library/bin/{lib}.dll"),
+                format!("Library/lib/{lib}.lib"),
+            ]
         } else if target_platform.is_osx() {
-            libraries.push((
-                lib,
-                globset::Glob::new(format!("**/{library_dir}/{lib}.dylib").as_str())?
-                    .compile_matcher(),
-                globset::Glob::new(format!("**/{library_dir}/{lib}.a").as_str())?.compile_matcher(),
-            ));
-        } else if target_platform.is_unix() {
-            libraries.push((
-                lib,
-                globset::Glob::new(format!("**/{library_dir}/{lib}.so").as_str())?
-                    .compile_matcher(),
-                globset::Glob::new(format!("**/{library_dir}/{lib}.a").as_str())?.compile_matcher(),
-            ));
+            vec![
+                format!("lib/lib{lib}.dylib"),
+                format!("lib/lib{lib}.*.dylib"),
+                format!("lib/lib{lib}.a"),
+            ]
         } else {
-            return Err(TestError::PackageContentTestFailedStr(
-                "Package test on target not supported.",
-            ));
-        }
+            vec![
+                format!("lib/lib{lib}.so"),
+                format!("lib/lib{lib}.so.*"),
+                format!("lib/lib{lib}.a"),
+            ]
+        };
+        add("library", lib, patterns)?;
     }
 
-    // includes
-    let include_path = if target_platform.is_windows() {
-        "**/Library/include/**"
-    } else {
-        "**/include/**"
-    };
-    let include_path = globset::Glob::new(include_path)?.compile_matcher();
-    let mut includes = vec![];
     for include in package_content.includes() {
-        includes.push((
-            include,
-            globset::Glob::new(include.as_str())?.compile_matcher(),
-        ));
+        let include_dir = if windows {
+            "Library/include"
+        } else {
+            "include"
+        };
+        add("include", include, vec![format!("{include_dir}/{include}")])?;
     }
 
-    for path in &paths_json.paths {
-        // check if all site_packages present
-        if !site_packages.is_empty() && site_package_path.is_match(&path.relative_path) {
-            let mut s = None;
-            for (i, sp) in site_packages.iter().enumerate() {
-                // this checks for exact component level match
-                if path.relative_path.ends_with(&sp.1) {
-                    s = Some(i);
-                    break;
-                }
-            }
-            if let Some(i) = s {
-                // can panic, but panic here is unreachable
-                site_packages.swap_remove(i);
-            }
-        }
+    Ok(checks)
+}
 
-        // check if all file globs have a match
-        if !file_globs.is_empty() {
-            let mut s = None;
-            for (i, (_, fm)) in file_globs.iter().enumerate() {
-                if fm.is_match(&path.relative_path) {
-                    s = Some(i);
-                    break;
-                }
-            }
-            if let Some(i) = s {
-                // can panic, but panic here is unreachable
-                file_globs.swap_remove(i);
-            }
-        }
+/// Up to `count` of the `paths` that share the longest beginning with the literal part of
+/// `pattern` (before its first wildcard), to point out typos
+fn nearby_paths(pattern: &str, paths: &[&Path], count: usize) -> Vec<String> {
+    let literal = pattern
+        .split(|c| matches!(c, '*' | '?' | '[' | '{'))
+        .next()
+        .unwrap_or_default();
+    let mut scored = paths
+        .iter()
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .map(|path| {
+            let common = path
+                .chars()
+                .zip(literal.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            (common, path)
+        })
+        .filter(|(common, _)| *common > 0)
+        .collect::<Vec<_>>();
+    // the longest common beginning first, shorter paths first among them
+    scored.sort_by(|(a, path_a), (b, path_b)| {
+        b.cmp(a)
+            .then(path_a.len().cmp(&path_b.len()))
+            .then(path_a.cmp(path_b))
+    });
+    scored
+        .into_iter()
+        .take(count)
+        .map(|(_, path)| path)
+        .collect()
+}
 
-        // check if all includes have a match
-        if !includes.is_empty() && include_path.is_match(&path.relative_path) {
-            let mut s = None;
-            for (i, inc) in includes.iter().enumerate() {
-                if inc.1.is_match(&path.relative_path) {
-                    s = Some(i);
-                    break;
-                }
-            }
-            if let Some(i) = s {
-                // can panic, but panic here is unreachable
-                includes.swap_remove(i);
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
 
-        // check if for all all, either a static or dynamic library have a match
-        if !libraries.is_empty() {
-            let mut s = None;
-            for (i, l) in libraries.iter().enumerate() {
-                if l.1.is_match(&path.relative_path) || l.2.is_match(&path.relative_path) {
-                    s = Some(i);
-                    break;
-                }
-            }
-            if let Some(i) = s {
-                // can panic, but panic here is unreachable
-                libraries.swap_remove(i);
-            }
-        }
+    use rattler_conda_types::{
+        MatchSpec, NoArchType, PackageName, PackageRecord, Platform, RepoDataRecord,
+        VersionWithSource,
+    };
 
-        // check if all binaries have a match
-        if !binary_names.is_empty() && binary_dir.is_match(&path.relative_path) {
-            let mut s = None;
-            for (i, b) in binary_names.iter().enumerate() {
-                // the matches component-wise as b is single level,
-                // it just matches the last component
-                if path.relative_path.ends_with(b) {
-                    s = Some(i);
-                    break;
-                }
-            }
-            if let Some(i) = s {
-                // can panic, but panic here is unreachable
-                binary_names.swap_remove(i);
-            }
+    use super::{
+        check_paths, check_run_constraints, has_tests, nearby_paths, run_in_environment, TestError,
+    };
+    use crate::build::ScriptFlavor;
+    use crate::recipe::{jinja::SelectorConfig, Recipe};
+
+    fn record(name: &str, version: &str, build: &str) -> RepoDataRecord {
+        let file_name = format!("{name}-{version}-{build}.conda");
+        RepoDataRecord {
+            package_record: PackageRecord {
+                arch: None,
+                build: build.into(),
+                build_number: 0,
+                constrains: vec![],
+                depends: vec![],
+                features: None,
+                legacy_bz2_md5: None,
+                legacy_bz2_size: None,
+                license: None,
+                license_family: None,
+                md5: None,
+                name: PackageName::from_str(name).unwrap(),
+                noarch: NoArchType::none(),
+                platform: None,
+                sha256: None,
+                size: None,
+                subdir: "linux-64".into(),
+                timestamp: None,
+                track_features: vec![],
+                version: VersionWithSource::from_str(version).unwrap(),
+                purls: Default::default(),
+            },
+            url: format!("https://example.com/linux-64/{file_name}")
+                .parse()
+                .unwrap(),
+            channel: "https://example.com/".to_string(),
+            file_name,
         }
     }
-    let mut error = String::new();
-    if !file_globs.is_empty() {
-        error.push_str(&format!(
-            "Some file glob matches not found in package contents.\n{:?}",
-            file_globs
-                .into_iter()
-                .map(|s| s.0)
-                .collect::<Vec<&String>>()
-        ));
-    }
-    if !site_packages.is_empty() {
-        if !error.is_empty() {
-            error.push('\n');
-        }
-        error.push_str(&format!(
-            "Some site packages not found in package contents.\n{:?}",
-            site_packages
-                .into_iter()
-                .map(|s| s.0)
-                .collect::<Vec<&String>>()
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_command() {
+        let environment = tempfile::tempdir().unwrap();
+        let err = run_in_environment(
+            ScriptFlavor::Bash,
+            "true\nfalse\necho unreachable".to_string(),
+            "run_test.sh",
+            environment.path(),
+            environment.path(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            &err,
+            TestError::CommandFailed { command, code: Some(1) } if command == "false"
         ));
+        assert_eq!(
+            err.to_string(),
+            "The test command `false` failed with exit code 1"
+        );
     }
-    if !includes.is_empty() {
-        if !error.is_empty() {
-            error.push('\n');
+
+    #[test]
+    fn run_constraints() {
+        let specs = |specs: &[&str]| {
+            specs
+                .iter()
+                .map(|spec| MatchSpec::from_str(spec).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let installed = vec![
+            record("cudatoolkit", "12.1", "h123_0"),
+            record("zlib", "1.2.13", "h0"),
+        ];
+
+        // a constraint on a package that is not installed is fulfilled
+        assert!(check_run_constraints(&specs(&["zlib >=1.2", "libfoo <2"]), &installed).is_ok());
+
+        match check_run_constraints(&specs(&["cudatoolkit >=11.2,<12", "libfoo <2"]), &installed) {
+            Err(TestError::RunConstraintViolated(violations)) => assert_eq!(
+                violations,
+                "cudatoolkit 12.1 h123_0 does not match `cudatoolkit >=11.2,<12`"
+            ),
+            other => panic!("expected a violated constraint, got {other:?}"),
         }
-        error.push_str(&format!(
-            "Some includes not found in package contents.\n{:?}",
-            includes.into_iter().map(|s| s.0).collect::<Vec<&String>>()
-        ));
     }
-    if !libraries.is_empty() {
-        if !error.is_empty() {
-            error.push('\n');
-        }
-        error.push_str(&format!(
-            "Some libraries not found in package contents.\n{:?}",
-            libraries.into_iter().map(|s| s.0).collect::<Vec<&String>>()
-        ));
+
+    #[test]
+    fn packaged_tests() {
+        let info_files = |files: &[&str]| files.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert!(has_tests(&info_files(&[
+            "info/index.json",
+            "info/test/run_test.sh"
+        ])));
+        assert!(has_tests(&info_files(&["info/test/run_test.py"])));
+        // the test requirements and files alone are not tests
+        assert!(!has_tests(&info_files(&[
+            "info/index.json",
+            "info/test/test_time_dependencies.json",
+            "info/test/tests/run_test.sh"
+        ])));
     }
-    if !binary_names.is_empty() {
-        if !error.is_empty() {
-            error.push('\n');
-        }
-        error.push_str(&format!(
-            "Some binaries not found in package contents.\n{:?}",
-            binary_names
-        ));
+
+    #[test]
+    fn package_contents() {
+        let recipe = Recipe::from_yaml(
+            r#"
+            package:
+              name: test
+              version: 0.1.0
+            test:
+              package_contents:
+                lib: foo
+                bin: foo
+                include: foo/*.h
+                site_packages: foo.api
+                files:
+                  - share/foo
+            "#,
+            SelectorConfig::default(),
+        )
+        .unwrap();
+        let package_content = recipe.test().package_content().unwrap();
+
+        let linux = [
+            "lib/libfoo.so.1.2.3",
+            "bin/foo",
+            "include/foo/foo.h",
+            "lib/python3.11/site-packages/foo/api/__init__.py",
+            "share/foo/data.txt",
+        ]
+        .map(Path::new);
+        check_paths(package_content, &linux, &Platform::Linux64).unwrap();
+
+        let windows = [
+            "Library/bin/foo.dll",
+            "Library/bin/foo.exe",
+            "Library/include/foo/foo.h",
+            "Lib/site-packages/foo/api.py",
+            "share/foo/data.txt",
+        ]
+        .map(Path::new);
+        check_paths(package_content, &windows, &Platform::Win64).unwrap();
+
+        let err = check_paths(package_content, &linux, &Platform::OsxArm64)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("library `foo` not found in the package contents (patterns: lib/libfoo.dylib, lib/libfoo.*.dylib, lib/libfoo.a), nearby paths: lib/libfoo.so.1.2.3"));
+        assert!(!err.contains("binary `foo`"));
     }
-    if error.is_empty() {
-        Ok(())
-    } else {
-        Err(TestError::PackageContentTestFailed(error))
+
+    #[test]
+    fn nearby() {
+        let paths = ["include/foo.h", "include/bar.h", "share/foo.txt"].map(Path::new);
+        assert_eq!(nearby_paths("include/fooo.h", &paths, 1), ["include/foo.h"]);
+        assert_eq!(
+            nearby_paths("include/*.hpp", &paths, 3),
+            ["include/bar.h", "include/foo.h"]
+        );
+        assert!(nearby_paths("etc/*", &paths, 3).is_empty());
     }
 }
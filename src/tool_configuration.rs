@@ -1,17 +1,43 @@
 //! Configuration for the rattler-build tool
 //! This is useful when using rattler-build as a library
 
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use rattler_networking::AuthenticatedClient;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-/// Global configuration for the build
-#[derive(Clone)]
+use crate::{
+    bandwidth::BandwidthLimiter,
+    build_report::BuildReports,
+    ci_log::CiLogStyle,
+    container::ContainerConfig,
+    log_stream::LogLine,
+    output_files::ClaimedFiles,
+    progress::ProgressOutput,
+    render::{integrity::PrefixVerification, resolved_dependencies::RunExportsCache},
+    skip_existing::SkipExisting,
+    source::{cache::default_source_cache_dir, limits::SourceLimits, FetchedSources},
+};
+
+/// The default number of URL sources that are downloaded at the same time
+pub const DEFAULT_SOURCE_FETCH_CONCURRENCY: usize = 4;
+
+/// The default number of retries of a failed source download
+pub const DEFAULT_DOWNLOAD_RETRIES: usize = 3;
+
+/// Global configuration for the build. The progress indicator, the download client, the log
+/// sender, the cancellation token, the fetched sources, the claimed files, the run exports cache
+/// and the build reports are not serialized; they get their default value when the configuration is deserialized.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Configuration {
     /// If set to a value, a progress bar will be shown
+    #[serde(skip)]
     pub multi_progress_indicator: indicatif::MultiProgress,
 
     /// The authenticated reqwest download client to use
+    #[serde(skip)]
     pub client: AuthenticatedClient,
 
     /// Set this to true if you want to keep the build folder after the build is done
@@ -25,6 +51,99 @@ pub struct Configuration {
 
     /// Whether to use bzip2
     pub use_bz2: bool,
+
+    /// Whether to only use cached repodata and never access the network for it
+    pub offline: bool,
+
+    /// The style of the markers that group the output of the build phases on CI systems
+    pub ci_log_style: CiLogStyle,
+
+    /// The limits for the size and number of files of every source (can be overridden per source
+    /// in the recipe)
+    pub source_limits: SourceLimits,
+
+    /// If set, the dependencies are installed, and the build script and the packaging are run
+    /// in this container image
+    pub container: Option<ContainerConfig>,
+
+    /// If set, the sum of all source and package downloads is limited to this rate
+    pub bandwidth_limit: Option<BandwidthLimiter>,
+
+    /// The maximum number of URL sources of a recipe that are downloaded at the same time
+    pub source_fetch_concurrency: usize,
+
+    /// How often a source download is retried after a transient error (e.g. a connection reset
+    /// or a 503 response)
+    pub download_retries: usize,
+
+    /// Apply the patches of the sources with the `patch` executable instead of the built-in
+    /// implementation
+    pub use_patch_executable: bool,
+
+    /// Locally built packages that replace the packages of the same name in the channels when
+    /// solving the build and host environments
+    pub local_package_overrides: Vec<PathBuf>,
+
+    /// Skip the installation of the build and host environments if the kept build directory of
+    /// an earlier build has the same packages installed. Only has an effect with `no_clean`.
+    pub reuse_environments: bool,
+
+    /// Compare the files of the packages in the build and host prefixes with their records
+    /// before the build script runs
+    pub verify_prefixes: PrefixVerification,
+
+    /// Link the packages with modified files again instead of failing the build (see
+    /// `verify_prefixes`)
+    pub repair_prefixes: bool,
+
+    /// If set, the build script (and all processes that it started) is killed when it runs
+    /// longer than this
+    pub build_timeout: Option<Duration>,
+
+    /// The cache of the downloaded sources and git repositories, which is shared by all builds
+    pub source_cache_dir: PathBuf,
+
+    /// Skip the build of an output if a package with the same name, version and build string
+    /// exists in the output directory (or in the channels)
+    pub skip_existing: SkipExisting,
+
+    /// Write the resolved dependencies of each output to a lock file next to its package (see
+    /// [`crate::render::lock`])
+    pub write_lock_file: bool,
+
+    /// How the progress of downloads, extractions and installations is reported
+    pub progress: ProgressOutput,
+
+    /// If set, a JSON report of the built packages is written to this file (see
+    /// [`crate::build_report`])
+    pub build_report: Option<PathBuf>,
+
+    /// If set, the start of every build phase and the output of the build script are also sent
+    /// to this channel (see [`crate::log_stream`])
+    #[serde(skip)]
+    pub log_sender: Option<tokio::sync::mpsc::Sender<LogLine>>,
+
+    /// Cancelled to stop the build, e.g. on Ctrl-C (see [`crate::cancellation`])
+    #[serde(skip)]
+    pub cancellation: CancellationToken,
+
+    /// The sources that were already fetched to render the recipe, which the builds with the
+    /// same sources copy into their work directory
+    #[serde(skip)]
+    pub fetched_sources: Option<Arc<FetchedSources>>,
+
+    /// The files that the outputs built with this configuration have packaged (see
+    /// [`crate::output_files`])
+    #[serde(skip)]
+    pub claimed_files: ClaimedFiles,
+
+    /// The run exports of the packages that the outputs built with this configuration resolved
+    #[serde(skip)]
+    pub run_exports_cache: RunExportsCache,
+
+    /// The reports of the packages that were built with this configuration
+    #[serde(skip)]
+    pub build_reports: BuildReports,
 }
 
 impl Default for Configuration {
@@ -45,6 +164,71 @@ impl Default for Configuration {
             no_test: false,
             use_zstd: true,
             use_bz2: true,
+            offline: false,
+            ci_log_style: CiLogStyle::None,
+            source_limits: SourceLimits::default(),
+            container: None,
+            bandwidth_limit: BandwidthLimiter::from_env(),
+            source_fetch_concurrency: DEFAULT_SOURCE_FETCH_CONCURRENCY,
+            download_retries: DEFAULT_DOWNLOAD_RETRIES,
+            use_patch_executable: false,
+            local_package_overrides: Vec::new(),
+            reuse_environments: false,
+            verify_prefixes: PrefixVerification::Off,
+            repair_prefixes: false,
+            build_timeout: None,
+            source_cache_dir: default_source_cache_dir(),
+            skip_existing: SkipExisting::Off,
+            write_lock_file: false,
+            progress: ProgressOutput::Auto,
+            build_report: None,
+            log_sender: None,
+            cancellation: CancellationToken::new(),
+            fetched_sources: None,
+            claimed_files: ClaimedFiles::default(),
+            run_exports_cache: RunExportsCache::default(),
+            build_reports: BuildReports::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Configuration;
+    use crate::{bandwidth::BandwidthLimiter, ci_log::CiLogStyle, container::ContainerConfig};
+
+    #[test]
+    fn round_trip() {
+        let configuration = Configuration {
+            no_clean: true,
+            offline: true,
+            ci_log_style: CiLogStyle::Gitlab,
+            container: Some(ContainerConfig {
+                image: "quay.io/condaforge/linux-anvil-cos7-x86_64".to_string(),
+                runtime: None,
+                executable: Some("/usr/local/bin/rattler-build".into()),
+            }),
+            bandwidth_limit: BandwidthLimiter::parse("2M").ok(),
+            download_retries: 7,
+            local_package_overrides: vec!["output/linux-64/foo-1.0-0.conda".into()],
+            ..Default::default()
+        };
+
+        let yaml = serde_yaml::to_string(&configuration).unwrap();
+        let parsed: Configuration = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(serde_yaml::to_string(&parsed).unwrap(), yaml);
+        assert_eq!(parsed.container, configuration.container);
+        assert_eq!(
+            parsed.bandwidth_limit.map(|l| l.bytes_per_second()),
+            Some(2 * 1024 * 1024)
+        );
+
+        // missing fields get their default value
+        let parsed: Configuration = serde_yaml::from_str("offline: true").unwrap();
+        assert!(parsed.offline);
+        assert_eq!(
+            parsed.source_fetch_concurrency,
+            super::DEFAULT_SOURCE_FETCH_CONCURRENCY
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Discovery of the external tools that rattler-build runs (e.g. `patch`, `git` or the `bash`
+//! that runs the build script on Windows).
+//!
+//! Tools are searched in the build prefix first, so that a recipe can provide them with its build
+//! requirements, and then in `PATH`. Found tools are cached for the lifetime of a [`Tools`]
+//! instance, which lives as long as a single build. Missing tools are not cached because the
+//! build prefix may only be installed later on.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use itertools::Itertools;
+
+/// An external tool that rattler-build needs for some of its steps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tool {
+    /// `patch`, to apply the patches of a source
+    Patch,
+    /// `git`, to fetch git sources
+    Git,
+    /// `git-lfs`, to fetch the Git LFS files of git sources
+    GitLfs,
+    /// `patchelf`, to relink ELF files
+    Patchelf,
+    /// `install_name_tool`, to relink Mach-O files
+    InstallNameTool,
+    /// `docker`, to run builds in a container
+    Docker,
+    /// `podman`, to run builds in a container
+    Podman,
+    /// `bash`, to run bash build scripts on Windows
+    Bash,
+    /// `pwsh` (PowerShell 7), to run PowerShell build scripts on Windows
+    Pwsh,
+}
+
+impl Tool {
+    /// The name of the executable
+    pub const fn executable(&self) -> &'static str {
+        match self {
+            Tool::Patch => "patch",
+            Tool::Git => "git",
+            Tool::GitLfs => "git-lfs",
+            Tool::Patchelf => "patchelf",
+            Tool::InstallNameTool => "install_name_tool",
+            Tool::Docker => "docker",
+            Tool::Podman => "podman",
+            Tool::Bash => "bash",
+            Tool::Pwsh => "pwsh",
+        }
+    }
+
+    /// The conda packages that provide the tool
+    pub const fn packages(&self) -> &'static [&'static str] {
+        match self {
+            Tool::Patch => &["patch", "m2-patch"],
+            Tool::Git => &["git"],
+            Tool::GitLfs => &["git-lfs"],
+            Tool::Patchelf => &["patchelf"],
+            Tool::InstallNameTool => &["cctools"],
+            Tool::Bash => &["m2-bash"],
+            Tool::Pwsh => &["powershell"],
+            Tool::Docker | Tool::Podman => &[],
+        }
+    }
+}
+
+impl fmt::Display for Tool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.executable())
+    }
+}
+
+/// The error that is returned when a tool is neither in the build prefix nor in `PATH`
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+#[error("Could not find the `{tool}` executable, which is needed to {reason}")]
+#[diagnostic(help("{help}"))]
+pub struct ToolNotFound {
+    /// The tool that was not found
+    pub tool: Tool,
+    /// Why the tool was needed (e.g. "apply the patch `fix.patch`")
+    pub reason: String,
+    help: String,
+}
+
+impl ToolNotFound {
+    fn new(tool: Tool, reason: String) -> Self {
+        let help = if tool.packages().is_empty() {
+            format!("install `{tool}` on your system")
+        } else {
+            let packages = tool
+                .packages()
+                .iter()
+                .map(|p| format!("`{p}`"))
+                .join(" or ");
+            format!("add {packages} to the build requirements or install it on your system")
+        };
+        Self { tool, reason, help }
+    }
+}
+
+/// Finds and caches the external tools of a single build
+#[derive(Debug)]
+pub struct Tools {
+    search_path: Vec<PathBuf>,
+    cache: Mutex<HashMap<Tool, PathBuf>>,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Tools {
+    /// Search the tools in the `build_prefix` (if given) and then in `PATH`
+    pub fn new(build_prefix: Option<&Path>) -> Self {
+        Self::with_path(build_prefix, std::env::var_os("PATH"))
+    }
+
+    /// Search the tools in the `build_prefix` (if given) and then in the given `PATH` value
+    pub fn with_path(build_prefix: Option<&Path>, path: Option<OsString>) -> Self {
+        let mut search_path = build_prefix.map(prefix_bin_dirs).unwrap_or_default();
+        if let Some(path) = path {
+            search_path.extend(std::env::split_paths(&path));
+        }
+        Self {
+            search_path,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Find the executable of `tool`. The `reason` completes the sentence "... which is needed
+    /// to" in the error message.
+    pub fn find(&self, tool: Tool, reason: impl fmt::Display) -> Result<PathBuf, ToolNotFound> {
+        self.find_accepted(tool, reason, |_| true)
+    }
+
+    /// Find the first executable of `tool` that is `accept`ed, e.g. to skip the `bash` of WSL.
+    /// The found executable is cached like the one of [`Tools::find`].
+    pub fn find_accepted(
+        &self,
+        tool: Tool,
+        reason: impl fmt::Display,
+        accept: impl Fn(&Path) -> bool,
+    ) -> Result<PathBuf, ToolNotFound> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(found) = cache.get(&tool) {
+            return Ok(found.clone());
+        }
+
+        let found = self
+            .find_all(tool)
+            .into_iter()
+            .find(|path| accept(path))
+            .ok_or_else(|| ToolNotFound::new(tool, reason.to_string()))?;
+
+        tracing::debug!("Using `{}` from {}", tool, found.display());
+        cache.insert(tool, found.clone());
+        Ok(found)
+    }
+
+    /// All the executables of `tool`, in the order in which they are searched (like
+    /// `which -a`). The result is not cached.
+    pub fn find_all(&self, tool: Tool) -> Vec<PathBuf> {
+        std::env::join_paths(&self.search_path)
+            .ok()
+            .and_then(|paths| which::which_in_all(tool.executable(), Some(paths), ".").ok())
+            .map(|found| found.collect())
+            .unwrap_or_default()
+    }
+}
+
+/// The folders of a prefix that contain executables
+fn prefix_bin_dirs(prefix: &Path) -> Vec<PathBuf> {
+    if cfg!(windows) {
+        vec![
+            prefix.join("Library/bin"),
+            prefix.join("Library/usr/bin"),
+            prefix.join("Scripts"),
+            prefix.join("bin"),
+            prefix.to_path_buf(),
+        ]
+    } else {
+        vec![prefix.join("bin")]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use fs_err as fs;
+
+    use super::{Tool, Tools};
+
+    #[cfg(unix)]
+    fn fake_executable(dir: &std::path::Path, name: &str) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn missing_tool() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tools = Tools::with_path(Some(tmp.path()), Some(OsString::new()));
+
+        let err = tools
+            .find(Tool::Patch, "apply the patch `fix.patch`")
+            .unwrap_err();
+        assert_eq!(err.tool, Tool::Patch);
+        assert_eq!(
+            err.to_string(),
+            "Could not find the `patch` executable, which is needed to apply the patch `fix.patch`"
+        );
+        let help = miette::Diagnostic::help(&err).unwrap().to_string();
+        assert_eq!(
+            help,
+            "add `patch` or `m2-patch` to the build requirements or install it on your system"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn build_prefix_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path().join("build_env");
+        let path_dir = tmp.path().join("path");
+        let in_prefix = fake_executable(&prefix.join("bin"), "patch");
+        let in_path = fake_executable(&path_dir, "patch");
+        let git_in_path = fake_executable(&path_dir, "git");
+
+        let tools = Tools::with_path(Some(&prefix), Some(path_dir.clone().into_os_string()));
+        assert_eq!(tools.find(Tool::Patch, "patch").unwrap(), in_prefix);
+        assert_eq!(tools.find(Tool::Git, "clone").unwrap(), git_in_path);
+
+        let without_prefix = Tools::with_path(None, Some(path_dir.into_os_string()));
+        assert_eq!(without_prefix.find(Tool::Patch, "patch").unwrap(), in_path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejected_executables_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path().join("build_env");
+        let path_dir = tmp.path().join("path");
+        let in_prefix = fake_executable(&prefix.join("bin"), "bash");
+        let in_path = fake_executable(&path_dir, "bash");
+
+        let tools = Tools::with_path(Some(&prefix), Some(path_dir.into_os_string()));
+        assert_eq!(
+            tools.find_all(Tool::Bash),
+            vec![in_prefix.clone(), in_path.clone()]
+        );
+        assert_eq!(
+            tools
+                .find_accepted(Tool::Bash, "run the build script", |path| path != in_prefix)
+                .unwrap(),
+            in_path
+        );
+        assert!(Tools::with_path(Some(&prefix), None)
+            .find_accepted(Tool::Bash, "run the build script", |_| false)
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn results_are_cached() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git = fake_executable(tmp.path(), "git");
+
+        let tools = Tools::with_path(None, Some(tmp.path().as_os_str().to_owned()));
+        assert_eq!(tools.find(Tool::Git, "clone").unwrap(), git);
+
+        fs::remove_file(&git).unwrap();
+        assert_eq!(tools.find(Tool::Git, "clone").unwrap(), git);
+        assert!(
+            Tools::with_path(None, Some(tmp.path().as_os_str().to_owned()))
+                .find(Tool::Git, "clone")
+                .is_err()
+        );
+    }
+}
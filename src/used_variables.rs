@@ -85,6 +85,16 @@ fn extract_variable_from_expression(expr: &Expr, variables: &mut HashSet<String>
     }
 }
 
+/// Find all variables that are referenced in a single template string. Templates that fail to
+/// parse are treated as not referencing any variables.
+pub(crate) fn variables_in_template(template: &str) -> HashSet<String> {
+    let mut variables = HashSet::new();
+    if let Ok(ast) = parse(template, "template.yaml") {
+        extract_variables(&ast, &mut variables);
+    }
+    variables
+}
+
 /// This recursively finds all `if/then/else` expressions in a YAML node
 fn find_all_selectors<'a>(node: &'a Node, selectors: &mut HashSet<&'a ScalarNode>) {
     use crate::recipe::custom_yaml::SequenceNodeInternal;
@@ -2,7 +2,7 @@
 
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use indexmap::IndexSet;
@@ -27,6 +27,30 @@ use crate::{
 };
 use petgraph::{algo::toposort, graph::DiGraph};
 
+/// The variant configuration next to the recipe, which is loaded for every build of the recipe
+pub const RECIPE_VARIANT_CONFIG: &str = "variants.yaml";
+
+/// The variant configuration files of a build of the recipe at `recipe_path`: the
+/// [`RECIPE_VARIANT_CONFIG`] next to the recipe (if it exists), followed by the `files` (which
+/// override its keys)
+pub fn variant_config_files(recipe_path: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+    let recipe_config = recipe_path
+        .parent()
+        .map(|dir| dir.join(RECIPE_VARIANT_CONFIG))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            // the file is not loaded twice if it is also passed explicitly
+            let canonical = path.canonicalize().ok();
+            !files
+                .iter()
+                .any(|file| file.canonicalize().ok() == canonical)
+        });
+    recipe_config
+        .into_iter()
+        .chain(files.iter().cloned())
+        .collect()
+}
+
 #[allow(missing_docs)]
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct DiscoveredOutput {
@@ -151,6 +175,10 @@ pub struct VariantConfig {
     #[serde_as(deserialize_as = "BTreeMap<_, OneOrMany<_, PreferOne>>")]
     #[serde(flatten)]
     pub variants: BTreeMap<String, Vec<String>>,
+
+    /// Report variant keys that are not used by any output as an error instead of a warning.
+    #[serde(skip)]
+    pub strict: bool,
 }
 
 #[allow(missing_docs)]
@@ -300,6 +328,17 @@ impl VariantConfig {
         Ok(())
     }
 
+    /// Returns the variant keys that are not part of `used_vars`. The `target_platform` and
+    /// `build_platform` keys are always inserted and therefore never reported.
+    pub fn unused_keys(&self, used_vars: &HashSet<String>) -> Vec<String> {
+        self.variants
+            .keys()
+            .filter(|key| !matches!(key.as_str(), "target_platform" | "build_platform"))
+            .filter(|key| !used_vars.contains(*key))
+            .cloned()
+            .collect()
+    }
+
     /// This function returns all possible combinations of variants for the given set of used
     /// variables.
     pub fn combinations(
@@ -515,6 +554,17 @@ impl VariantConfig {
         all_variables.insert("target_platform".to_string());
         all_variables.insert("channel_targets".to_string());
 
+        let unused_keys = self.unused_keys(&all_variables);
+        if !unused_keys.is_empty() {
+            if self.strict {
+                return Err(VariantError::UnusedVariantKeys(unused_keys.join(", ")));
+            }
+            tracing::warn!(
+                "The following variant keys are not used by any output: {}",
+                unused_keys.join(", ")
+            );
+        }
+
         let combinations = self.combinations(&all_variables)?;
 
         // Then find all used variables from the each output recipe
@@ -762,6 +812,9 @@ pub enum VariantError {
 
     #[error("Found a cycle in the recipe outputs: {0}")]
     CycleInRecipeOutputs(String),
+
+    #[error("Variant keys are not used by any output: {0}")]
+    UnusedVariantKeys(String),
 }
 
 fn find_combinations(
@@ -804,6 +857,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant: Default::default(),
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(selector_config);
 
@@ -816,6 +872,9 @@ mod tests {
             build_platform: Platform::Win64,
             variant: Default::default(),
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
         let jinja = Jinja::new(selector_config);
 
@@ -833,6 +892,9 @@ mod tests {
             build_platform: Platform::Linux64,
             variant: Default::default(),
             hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
         };
 
         let variant = VariantConfig::from_files(&vec![yaml_file], &selector_config).unwrap();
@@ -842,6 +904,41 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_recipe_variant_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let recipe_path = tmp.path().join("recipe.yaml");
+        let extra = tmp.path().join("extra.yaml");
+
+        assert_eq!(
+            variant_config_files(&recipe_path, &[extra.clone()]),
+            [extra.clone()]
+        );
+
+        let recipe_config = tmp.path().join(RECIPE_VARIANT_CONFIG);
+        std::fs::write(&recipe_config, "python:\n  - \"3.11\"\n").unwrap();
+        std::fs::write(&extra, "python:\n  - \"3.12\"\n").unwrap();
+        let files = variant_config_files(&recipe_path, &[extra.clone()]);
+        assert_eq!(files, [recipe_config.clone(), extra.clone()]);
+        assert_eq!(
+            variant_config_files(&recipe_path, &[recipe_config.clone()]),
+            [recipe_config]
+        );
+
+        // the explicit file overrides the keys of the one next to the recipe
+        let selector_config = SelectorConfig {
+            target_platform: Platform::Linux64,
+            build_platform: Platform::Linux64,
+            variant: Default::default(),
+            hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
+        };
+        let config = VariantConfig::from_files(&files, &selector_config).unwrap();
+        assert_eq!(config.variants["python"], ["3.12"]);
+    }
+
     #[test]
     fn test_variant_combinations() {
         let mut variants = BTreeMap::new();
@@ -854,6 +951,7 @@ mod tests {
             variants,
             zip_keys: Some(zip_keys),
             pin_run_as_build: None,
+            strict: false,
         };
 
         let combinations = config.combinations(&used_vars).unwrap();
@@ -880,4 +978,47 @@ mod tests {
         let combinations = config.combinations(&used_vars).unwrap();
         assert_eq!(combinations.len(), 2 * 2 * 3);
     }
+
+    #[test]
+    fn test_unused_variant_keys() {
+        let recipe = r#"
+        package:
+          name: test
+          version: 0.1.0
+        requirements:
+          host:
+            - python
+        "#;
+        let selector_config = SelectorConfig {
+            target_platform: Platform::Linux64,
+            build_platform: Platform::Linux64,
+            variant: Default::default(),
+            hash: None,
+            source_dir: None,
+            recipe_dir: None,
+            sources_requested: Default::default(),
+        };
+
+        let mut config = VariantConfig::default();
+        config
+            .variants
+            .insert("python".to_string(), vec!["3.11".to_string()]);
+        config
+            .variants
+            .insert("pyhton".to_string(), vec!["3.12".to_string()]);
+        config
+            .variants
+            .insert("target_platform".to_string(), vec!["linux-64".to_string()]);
+
+        let used_vars = HashSet::from(["python".to_string()]);
+        assert_eq!(config.unused_keys(&used_vars), vec!["pyhton".to_string()]);
+
+        // only warns by default
+        let outputs = config.find_variants(recipe, &selector_config).unwrap();
+        assert_eq!(outputs.len(), 1);
+
+        config.strict = true;
+        let err = config.find_variants(recipe, &selector_config).unwrap_err();
+        assert!(matches!(err, VariantError::UnusedVariantKeys(keys) if keys == "pyhton"));
+    }
 }